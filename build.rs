@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // protoc is vendored, so building doesn't need it installed
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::configure()
+        .bytes(["."])
+        .compile_protos(&["proto/minikeyvalue.proto"], &["proto"])?;
+    Ok(())
+}
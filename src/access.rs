@@ -0,0 +1,107 @@
+use axum::extract::ConnectInfo;
+use axum::http::{header, HeaderMap, Method, StatusCode, Uri};
+use std::{net::SocketAddr, time::Duration};
+
+/// Target of the access log lines, so they can be told apart from the logs of the server.
+const TARGET: &str = "access";
+
+/// Middleware logging a line per request served, with the client address, method, path, status,
+/// bytes of the response body, duration and redirect target, e.g.
+/// `127.0.0.1:50432 "GET /wehave" 307 0 1.2ms "http://localhost:3001/sv03/d2VoYXZl"`.
+/// Bytes are `-` for streamed bodies without a Content-Length, the target `-` for other responses.
+pub(crate) async fn log_requests(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        // The public listener binds [::], IPv4 clients are logged without their IPv6 mapping
+        .map(|ConnectInfo(addr)| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let started_at = std::time::Instant::now();
+
+    let response = next.run(request).await;
+    log::info!(
+        target: TARGET,
+        "{}",
+        line(
+            client,
+            &method,
+            &uri,
+            response.status(),
+            response.headers(),
+            started_at.elapsed()
+        )
+    );
+    response
+}
+
+/// Returns the access log line of a request.
+fn line(
+    client: Option<SocketAddr>,
+    method: &Method,
+    uri: &Uri,
+    status: StatusCode,
+    headers: &HeaderMap,
+    duration: Duration,
+) -> String {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("-")
+    };
+    format!(
+        "{} \"{} {}\" {} {} {:.1}ms \"{}\"",
+        client.map_or_else(|| "-".to_string(), |client| client.to_string()),
+        method,
+        uri.path(),
+        status.as_u16(),
+        header(header::CONTENT_LENGTH),
+        duration.as_secs_f64() * 1000.0,
+        header(header::LOCATION),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, "0".parse().unwrap());
+        headers.insert(
+            header::LOCATION,
+            "http://localhost:3001/sv03/d2VoYXZl".parse().unwrap(),
+        );
+        let client = Some("127.0.0.1:50432".parse().unwrap());
+        let uri: Uri = "/wehave?proxy=0".parse().unwrap();
+        assert_eq!(
+            line(
+                client,
+                &Method::GET,
+                &uri,
+                StatusCode::TEMPORARY_REDIRECT,
+                &headers,
+                Duration::from_micros(1200)
+            ),
+            "127.0.0.1:50432 \"GET /wehave\" 307 0 1.2ms \"http://localhost:3001/sv03/d2VoYXZl\""
+        );
+
+        let uri: Uri = "/stream".parse().unwrap();
+        assert_eq!(
+            line(
+                None,
+                &Method::PUT,
+                &uri,
+                StatusCode::CREATED,
+                &HeaderMap::new(),
+                Duration::from_millis(3)
+            ),
+            "- \"PUT /stream\" 201 - 3.0ms \"-\""
+        );
+    }
+}
@@ -0,0 +1,692 @@
+use axum::http::StatusCode;
+use log::{error, info};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{checksum, drain, hashring, record, report, tasks, volume_keys};
+
+/// Default number of keys returned in a page.
+pub(crate) const DEFAULT_PAGE_LIMIT: usize = 1000;
+
+/// Default window in seconds for keys about to expire.
+const DEFAULT_EXPIRING_WITHIN: u64 = 3600;
+
+/// Axum state for admin requests.
+pub(crate) struct AppAdminState {
+    pub(crate) leveldb: Arc<record::LevelDb>,
+    pub(crate) scheduler: Arc<tasks::Scheduler>,
+    pub(crate) hashring: Arc<RwLock<hashring::Ring>>,
+    pub(crate) drains: Arc<drain::Drains>,
+}
+
+/// Query parameters for paginated listings.
+/// The start key is inclusive, next is the start key of the following page.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct PageParams {
+    pub(crate) start: Option<String>,
+    pub(crate) limit: Option<usize>,
+}
+
+/// Struct representing a page of keys. Next is empty on the last page.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct KeyPage {
+    pub(crate) keys: Vec<String>,
+    pub(crate) next: String,
+}
+
+/// Query parameters filtering listings by key prefix.
+#[derive(Debug, Deserialize)]
+pub(crate) struct PrefixParams {
+    prefix: Option<String>,
+}
+
+/// Struct representing a live object, enough to tell whether two clusters store the same value.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct LiveObject {
+    pub(crate) key: String,
+    pub(crate) hash: String,
+    /// Algorithm of the hash, None from servers listing MD5 hashes only.
+    #[serde(default)]
+    pub(crate) hash_algorithm: Option<checksum::Algorithm>,
+    pub(crate) size: u64,
+}
+
+/// Struct representing a page of live objects. Next is empty on the last page.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct ObjectPage {
+    pub(crate) objects: Vec<LiveObject>,
+    pub(crate) next: String,
+}
+
+/// Struct representing a soft-deleted record in the recycle bin.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct DeletedRecord {
+    key: String,
+    hash: String,
+    volumes: Vec<String>,
+    size: u64,
+    deleted_at: Option<u64>,
+}
+
+/// Struct representing a page of soft-deleted records. Next is empty on the last page.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct DeletedPage {
+    records: Vec<DeletedRecord>,
+    next: String,
+}
+
+/// Struct representing the volumes of the default hash ring.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct VolumeList {
+    volumes: Vec<String>,
+}
+
+/// Struct representing a volume to add to the default hash ring.
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewVolume {
+    volume: String,
+}
+
+/// Query parameters for the expiry listing.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExpiringParams {
+    within: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Creates the router for the admin endpoints.
+pub(crate) fn router(state: Arc<AppAdminState>) -> axum::Router {
+    axum::Router::new()
+        .route(
+            "/admin/volumes",
+            axum::routing::get(handle_list_volumes).post(handle_add_volume),
+        )
+        .route(
+            "/admin/volumes/:volume",
+            axum::routing::delete(handle_remove_volume),
+        )
+        .route(
+            "/admin/volumes/:volume/keys",
+            axum::routing::get(handle_list_volume_keys),
+        )
+        .route(
+            "/admin/volumes/:volume/drain",
+            axum::routing::get(handle_drain_status).post(handle_drain_volume),
+        )
+        .route("/admin/objects", axum::routing::get(handle_list_objects))
+        .route("/admin/report", axum::routing::get(handle_report))
+        .route("/admin/expiring", axum::routing::get(handle_list_expiring))
+        .route("/admin/deleted", axum::routing::get(handle_list_deleted))
+        .route("/admin/tasks", axum::routing::get(handle_list_tasks))
+        .route(
+            "/admin/tasks/:name/pause",
+            axum::routing::post(handle_pause_task),
+        )
+        .route(
+            "/admin/tasks/:name/resume",
+            axum::routing::post(handle_resume_task),
+        )
+        .route(
+            "/admin/tasks/:name/run",
+            axum::routing::post(handle_run_task),
+        )
+        .with_state(state)
+}
+
+/// Sorts the items by key and returns the page starting at the start key with at most limit items,
+/// along with the key of the first item of the following page, empty on the last page.
+pub(crate) fn paginate<T>(
+    mut items: Vec<T>,
+    key_of: impl Fn(&T) -> &str,
+    params: &PageParams,
+) -> (Vec<T>, String) {
+    items.sort_unstable_by(|a, b| key_of(a).cmp(key_of(b)));
+
+    let start = params.start.as_deref().unwrap_or_default();
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).max(1);
+
+    let mut items = items
+        .into_iter()
+        .filter(|item| key_of(item) >= start)
+        .take(limit + 1)
+        .collect::<Vec<T>>();
+    let next = if items.len() > limit {
+        items
+            .pop()
+            .map(|item| key_of(&item).to_string())
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    (items, next)
+}
+
+/// Runs a blocking scan of the leveldb off the async runtime and returns its result as JSON.
+/// Returns 200 with the result as JSON
+/// Returns 500 if the scan fails
+pub(crate) async fn scan_to_json<T: Serialize + Send + 'static>(
+    name: &str,
+    scan: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    match tokio::task::spawn_blocking(scan).await {
+        Ok(Ok(result)) => axum::Json(result).into_response(),
+        Ok(Err(e)) => {
+            error!("{}: failed to scan leveldb: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            error!("{}: scan task failed: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Handles GET requests listing the volumes of the default hash ring.
+async fn handle_list_volumes(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::Json<VolumeList> {
+    let volumes = state.hashring.read().volumes().to_vec();
+    axum::Json(VolumeList { volumes })
+}
+
+/// Handles POST requests adding a volume to the default hash ring, e.g. `{"volume": "localhost:3006"}`,
+/// or `{"volume": "https://localhost:3006"}` for a volume serving HTTPS.
+/// New writes are placed on the new ring, existing values stay on their volumes until rebalanced.
+/// Returns 201 with the volumes of the ring as JSON
+/// Returns 400 if the volume is empty or has a path
+/// Returns 409 if the ring already has the volume
+async fn handle_add_volume(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+    axum::Json(new_volume): axum::Json<NewVolume>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let volume = new_volume.volume.trim().trim_end_matches('/').to_string();
+    let address = match volume.split_once("://") {
+        Some(("http" | "https", address)) => address,
+        Some(_) => "",
+        None => &volume,
+    };
+    if address.is_empty() || address.contains('/') {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let mut hashring = state.hashring.write();
+    if !hashring.add_volume(volume.clone()) {
+        return StatusCode::CONFLICT.into_response();
+    }
+    info!("admin: added volume {} to the ring", volume);
+    let volumes = hashring.volumes().to_vec();
+    (StatusCode::CREATED, axum::Json(VolumeList { volumes })).into_response()
+}
+
+/// Handles DELETE requests removing a volume from the default hash ring, e.g. to drain it.
+/// New writes avoid the volume, existing values are still read from it until rebalanced.
+/// Returns 204 if the volume is removed
+/// Returns 404 if the ring doesn't have the volume
+/// Returns 409 if the ring would have fewer volumes than replicas
+async fn handle_remove_volume(
+    axum::extract::Path(volume): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> StatusCode {
+    let mut hashring = state.hashring.write();
+    if !hashring.volumes().contains(&volume) {
+        return StatusCode::NOT_FOUND;
+    }
+    if hashring.volumes().len() <= hashring.replicas() {
+        return StatusCode::CONFLICT;
+    }
+    hashring.remove_volume(&volume);
+    info!("admin: removed volume {} from the ring", volume);
+    StatusCode::NO_CONTENT
+}
+
+/// Handles POST requests draining a volume before removing its hardware. The volume is removed from the
+/// default hash ring, so new writes avoid it while reads keep using it, then the replicas it holds are moved
+/// in the background to other volumes of the ring and their records updated, see `drain::Drains`.
+/// A volume already removed from the ring can be drained again, e.g. after a drain left values on it.
+/// Returns 202 with the status of the drain as JSON
+/// Returns 404 if the ring doesn't have the volume and it was never drained
+/// Returns 409 if the volume is being drained, or the ring would have fewer volumes than replicas
+async fn handle_drain_volume(
+    axum::extract::Path(volume): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    {
+        let mut hashring = state.hashring.write();
+        if hashring.volumes().contains(&volume) {
+            if hashring.volumes().len() <= hashring.replicas() {
+                return StatusCode::CONFLICT.into_response();
+            }
+            hashring.remove_volume(&volume);
+            info!("admin: removed volume {} from the ring to drain it", volume);
+        } else if state.drains.status(&volume).is_none() {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    }
+    match state.drains.start(&volume) {
+        Some(status) => (StatusCode::ACCEPTED, axum::Json(status)).into_response(),
+        None => StatusCode::CONFLICT.into_response(),
+    }
+}
+
+/// Handles GET requests returning the progress of the last drain of a volume.
+/// Returns 200 with the status of the drain as JSON
+/// Returns 404 if the volume was never drained
+async fn handle_drain_status(
+    axum::extract::Path(volume): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> Result<axum::Json<drain::DrainStatus>, StatusCode> {
+    state
+        .drains
+        .status(&volume)
+        .map(axum::Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Handles GET requests listing the keys with a replica, a part or a shard in a volume, read from the index
+/// of the keys of every volume kept with the records, see `volume_keys`.
+/// The volume matches records with or without a subvolume, e.g. `localhost:3001` or `localhost:3001/sv01`.
+/// Returns 200 with a page of keys as JSON
+/// Returns 500 for internal server error
+async fn handle_list_volume_keys(
+    axum::extract::Path(volume): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+    axum::extract::Query(params): axum::extract::Query<PageParams>,
+) -> axum::response::Response {
+    let leveldb = state.leveldb.clone();
+    scan_to_json("list_volume_keys", move || {
+        let keys = volume_keys::keys(leveldb.store(), &volume)?;
+        let (keys, next) = paginate(keys, |key| key.as_str(), &params);
+        Ok(KeyPage { keys, next })
+    })
+    .await
+}
+
+/// Handles GET requests listing the live objects with their hash and size, e.g. to mirror a cluster.
+/// The `prefix` query parameter filters the keys, `start` and `limit` paginate them.
+/// Returns 200 with a page of objects as JSON
+/// Returns 500 for internal server error
+async fn handle_list_objects(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+    axum::extract::Query(prefix): axum::extract::Query<PrefixParams>,
+    axum::extract::Query(params): axum::extract::Query<PageParams>,
+) -> axum::response::Response {
+    let leveldb = state.leveldb.clone();
+    scan_to_json("list_objects", move || {
+        let prefix = prefix.prefix.unwrap_or_default();
+        let mut objects = Vec::new();
+        let now = record::unix_now();
+        leveldb.for_each_record(|record| {
+            if record.is_live(now) && record.key().starts_with(&prefix) {
+                objects.push(LiveObject {
+                    key: record.key().to_string(),
+                    hash: record.hash().to_string(),
+                    hash_algorithm: Some(record.hash_algorithm()),
+                    size: record.size(),
+                });
+            }
+            Ok(())
+        })?;
+        let (objects, next) = paginate(objects, |object| object.key.as_str(), &params);
+        Ok(ObjectPage { objects, next })
+    })
+    .await
+}
+
+/// Handles GET requests reporting the objects and logical bytes per volume and subvolume.
+/// Returns 200 with the distribution report as JSON
+/// Returns 500 for internal server error
+async fn handle_report(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::response::Response {
+    let leveldb = state.leveldb.clone();
+    scan_to_json("report", move || report::DistributionReport::scan(&leveldb)).await
+}
+
+/// Handles GET requests listing the keys past their expiry or expiring within a window.
+/// The `within` query parameter sets the window in seconds, `limit` the keys listed per set.
+/// Returns 200 with the expiry report as JSON
+/// Returns 500 for internal server error
+async fn handle_list_expiring(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+    axum::extract::Query(params): axum::extract::Query<ExpiringParams>,
+) -> axum::response::Response {
+    let leveldb = state.leveldb.clone();
+    let within = params.within.unwrap_or(DEFAULT_EXPIRING_WITHIN);
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    scan_to_json("list_expiring", move || {
+        report::ExpiryReport::scan(&leveldb, record::unix_now(), within, limit)
+    })
+    .await
+}
+
+/// Handles GET requests listing the soft-deleted records, the recycle bin of the undelete endpoint.
+/// The `prefix` query parameter filters the keys, `start` and `limit` paginate them.
+/// Returns 200 with a page of deleted records as JSON
+/// Returns 500 for internal server error
+async fn handle_list_deleted(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+    axum::extract::Query(prefix): axum::extract::Query<PrefixParams>,
+    axum::extract::Query(params): axum::extract::Query<PageParams>,
+) -> axum::response::Response {
+    let leveldb = state.leveldb.clone();
+    scan_to_json("list_deleted", move || {
+        let prefix = prefix.prefix.unwrap_or_default();
+        let mut records = Vec::new();
+        leveldb.for_each_record(|record| {
+            if record.deleted() == record::Deleted::Soft && record.key().starts_with(&prefix) {
+                records.push(DeletedRecord {
+                    key: record.key().to_string(),
+                    hash: record.hash().to_string(),
+                    volumes: record.read_volumes().to_vec(),
+                    size: record.size(),
+                    deleted_at: record.deleted_at(),
+                });
+            }
+            Ok(())
+        })?;
+        let (records, next) = paginate(records, |record| record.key.as_str(), &params);
+        Ok(DeletedPage { records, next })
+    })
+    .await
+}
+
+/// Handles GET requests listing the status of the background tasks.
+async fn handle_list_tasks(
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::Json<Vec<tasks::TaskStatus>> {
+    axum::Json(state.scheduler.statuses())
+}
+
+/// Returns the status of a task after an admin action.
+/// Returns 200 with the task status as JSON
+/// Returns 404 if the task does not exist
+fn task_response(state: &AppAdminState, name: &str, found: bool) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    match state.scheduler.status(name) {
+        Some(status) if found => axum::Json(status).into_response(),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Handles POST requests pausing the scheduled runs of a background task.
+async fn handle_pause_task(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::response::Response {
+    let found = state.scheduler.pause(&name);
+    task_response(&state, &name, found)
+}
+
+/// Handles POST requests resuming the scheduled runs of a background task.
+async fn handle_resume_task(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::response::Response {
+    let found = state.scheduler.resume(&name);
+    task_response(&state, &name, found)
+}
+
+/// Handles POST requests triggering a run of a background task, even if paused.
+async fn handle_run_task(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppAdminState>>,
+) -> axum::response::Response {
+    let found = state.scheduler.trigger(&name);
+    task_response(&state, &name, found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn test_paginate() {
+        let all = keys(&["c", "a", "d", "b", "e"]);
+        let page = |start: Option<&str>, limit| {
+            let params = PageParams {
+                start: start.map(String::from),
+                limit,
+            };
+            paginate(all.clone(), |key| key.as_str(), &params)
+        };
+
+        assert_eq!(page(None, Some(2)), (keys(&["a", "b"]), "c".to_string()));
+        assert_eq!(
+            page(Some("c"), Some(3)),
+            (keys(&["c", "d", "e"]), String::new())
+        );
+        assert_eq!(
+            page(None, None),
+            (keys(&["a", "b", "c", "d", "e"]), String::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_volume_keys() -> anyhow::Result<()> {
+        let cluster = crate::testkit::TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in ["a", "b", "c"] {
+            client.put(cluster.key_url(key)).body(key).send().await?;
+        }
+
+        let mut listed = Vec::new();
+        for volume in cluster.volume_addrs() {
+            let url = format!("{}/admin/volumes/{}/keys", cluster.url(), volume);
+            let page: serde_json::Value = client.get(url).send().await?.json().await?;
+            for key in page["keys"].as_array().unwrap() {
+                listed.push(key.as_str().unwrap().to_string());
+            }
+        }
+        listed.sort();
+        listed.dedup();
+        assert_eq!(listed, keys(&["a", "b", "c"]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_volume() -> anyhow::Result<()> {
+        let cluster = crate::testkit::TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = format!("{}/admin/volumes", cluster.url());
+        client
+            .put(cluster.key_url("before"))
+            .body("onyou")
+            .send()
+            .await?;
+
+        let volumes: VolumeList = client.get(&url).send().await?.json().await?;
+        assert_eq!(volumes.volumes, cluster.volume_addrs());
+
+        let res = client
+            .post(&url)
+            .json(&serde_json::json!({"volume": cluster.volume_addrs()[0]}))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let removed = &cluster.volume_addrs()[2];
+        let res = client.delete(format!("{}/{}", url, removed)).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.delete(format!("{}/{}", url, removed)).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client
+            .delete(format!("{}/{}", url, cluster.volume_addrs()[1]))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // Values written before the change are still read from their volumes
+        let res = client.get(cluster.key_url("before")).send().await?;
+        assert_eq!(res.text().await?, "onyou");
+
+        client
+            .put(cluster.key_url("after"))
+            .body("onyou")
+            .send()
+            .await?;
+        assert!(cluster
+            .volume(2)
+            .paths()
+            .iter()
+            .all(|path| !path.ends_with(&record::get_remote_path("after"))));
+
+        let res = client
+            .post(&url)
+            .json(&serde_json::json!({"volume": removed}))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let volumes: VolumeList = res.json().await?;
+        assert_eq!(volumes.volumes.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_volume() -> anyhow::Result<()> {
+        let cluster = crate::testkit::TestCluster::start(4, 2).await?;
+        let client = reqwest::Client::new();
+        // The first key, soft deleted, is on the drained volume whatever the ports of the volumes
+        let hashring = crate::hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+        let deleted = (20..)
+            .map(|i| format!("key-{}", i))
+            .find(|key| {
+                hashring
+                    .get_volume(key)
+                    .iter()
+                    .any(|volume| volume.starts_with(&cluster.volume_addrs()[0]))
+            })
+            .unwrap();
+        let keys: Vec<String> = std::iter::once(deleted)
+            .chain((0..20).map(|i| format!("key-{}", i)))
+            .collect();
+        for key in keys.iter() {
+            client
+                .put(cluster.key_url(key))
+                .body(key.clone())
+                .send()
+                .await?;
+        }
+        client.delete(cluster.key_url(&keys[0])).send().await?;
+        assert!(!cluster.volume(0).is_empty());
+
+        let drained = &cluster.volume_addrs()[0];
+        let url = format!("{}/admin/volumes/{}/drain", cluster.url(), drained);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.post(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+        let status = loop {
+            let status: drain::DrainStatus = client.get(&url).send().await?.json().await?;
+            if status.state != drain::DrainState::Running {
+                break status;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(status.state, drain::DrainState::Drained);
+        assert!(status.keys > 0);
+        assert_eq!(status.moved, status.keys);
+        assert_eq!(status.failed, 0);
+
+        // Values, the soft deleted one included, moved to the other volumes of the ring
+        assert!(cluster.volume(0).is_empty());
+        let volumes: VolumeList = client
+            .get(format!("{}/admin/volumes", cluster.url()))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert!(!volumes.volumes.contains(drained));
+        for key in keys.iter().skip(1) {
+            let res = client.get(cluster.key_url(key)).send().await?;
+            assert_eq!(res.text().await?, *key);
+        }
+        let res = client
+            .post(format!("{}/{}?undelete", cluster.url(), keys[0]))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(cluster.key_url(&keys[0])).send().await?;
+        assert_eq!(res.text().await?, keys[0]);
+        let page: serde_json::Value = client
+            .get(format!("{}/admin/volumes/{}/keys", cluster.url(), drained))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(page["keys"], serde_json::json!([]));
+
+        let res = client
+            .post(format!(
+                "{}/admin/volumes/unknown:3001/drain",
+                cluster.url()
+            ))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client
+            .post(format!(
+                "{}/admin/volumes/{}/drain",
+                cluster.url(),
+                cluster.volume_addrs()[1]
+            ))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let res = client
+            .post(format!(
+                "{}/admin/volumes/{}/drain",
+                cluster.url(),
+                cluster.volume_addrs()[2]
+            ))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_deleted() -> anyhow::Result<()> {
+        let cluster = crate::testkit::TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in ["bin-a", "bin-b", "keep-c", "bin-d"] {
+            client.put(cluster.key_url(key)).body(key).send().await?;
+        }
+        for key in ["bin-a", "keep-c", "bin-d"] {
+            client.delete(cluster.key_url(key)).send().await?;
+        }
+
+        let url = format!("{}/admin/deleted?prefix=bin-&limit=1", cluster.url());
+        let page: serde_json::Value = client.get(url).send().await?.json().await?;
+        assert_eq!(page["records"].as_array().unwrap().len(), 1);
+        assert_eq!(page["records"][0]["key"], "bin-a");
+        assert_eq!(page["records"][0]["hash"], checksum::md5_hex(b"bin-a"));
+        assert!(page["records"][0]["deleted_at"].as_u64().is_some());
+        assert_eq!(page["next"], "bin-d");
+
+        let url = format!("{}/admin/deleted?prefix=bin-&start=bin-d", cluster.url());
+        let page: serde_json::Value = client.get(url).send().await?.json().await?;
+        assert_eq!(page["records"].as_array().unwrap().len(), 1);
+        assert_eq!(page["records"][0]["key"], "bin-d");
+        assert_eq!(page["next"], "");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::Context;
+use axum::http::{header, Method, StatusCode};
+use std::{collections::HashMap, path::Path, str::FromStr, sync::Arc};
+
+/// Paths served without a token, so probes and the nginx `auth_request` hook don't need one.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/presign/verify"];
+
+/// Access granted to a token. Write tokens can read too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// GET and HEAD of keys and /metrics.
+    Read,
+    /// Every request, including PUT, POST, DELETE and /admin.
+    Write,
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            _ => anyhow::bail!("unknown scope: {}, expected read or write", s),
+        }
+    }
+}
+
+/// Struct representing the bearer tokens accepted by the server and their scopes.
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    scopes: HashMap<String, Scope>,
+}
+
+impl Tokens {
+    /// Accepts the token with the scope, a token added twice keeps its widest scope.
+    pub fn add(&mut self, token: String, scope: Scope) {
+        let current = self.scopes.entry(token).or_insert(scope);
+        *current = (*current).max(scope);
+    }
+
+    /// Reads a token file, one `<scope> <token>` per line, e.g. `read 7f3a...`.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read token file {}", path.display()))?;
+        let mut tokens = Self::default();
+        tokens
+            .extend_from_str(&content)
+            .with_context(|| format!("invalid token file {}", path.display()))?;
+        Ok(tokens)
+    }
+
+    /// Adds the tokens of the content of a token file.
+    fn extend_from_str(&mut self, content: &str) -> anyhow::Result<()> {
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (scope, token) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("line {}: expected <scope> <token>", number + 1))?;
+            let scope = scope
+                .parse()
+                .with_context(|| format!("line {}", number + 1))?;
+            self.add(token.trim().to_string(), scope);
+        }
+        Ok(())
+    }
+
+    /// Returns true if no token is accepted.
+    pub fn is_empty(&self) -> bool {
+        self.scopes.is_empty()
+    }
+
+    /// Returns the scope of a token, None if the token isn't accepted.
+    pub(crate) fn scope(&self, token: &str) -> Option<Scope> {
+        self.scopes.get(token).copied()
+    }
+
+    /// Returns the scope of the bearer token of the Authorization header,
+    /// None if the header is missing or the token isn't accepted.
+    pub(crate) fn bearer_scope(&self, headers: &axum::http::HeaderMap) -> Option<Scope> {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.scope(token.trim()))
+    }
+}
+
+/// Returns the scope a request needs. /admin needs write access, even to read.
+fn required_scope(method: &Method, path: &str) -> Scope {
+    if path == "/admin" || path.starts_with("/admin/") {
+        return Scope::Write;
+    }
+    match *method {
+        Method::GET | Method::HEAD => Scope::Read,
+        _ => Scope::Write,
+    }
+}
+
+/// Middleware rejecting the requests without a bearer token of the scope they need.
+/// Returns 401 if the Authorization header is missing or the token isn't accepted
+/// Returns 403 if the token only has read access and the request writes
+pub(crate) async fn require_token(
+    axum::extract::State(tokens): axum::extract::State<Arc<Tokens>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let path = request.uri().path();
+    if EXEMPT_PATHS.contains(&path) {
+        return next.run(request).await;
+    }
+    let required = required_scope(request.method(), path);
+    match tokens.bearer_scope(request.headers()) {
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+        Some(scope) if scope < required => StatusCode::FORBIDDEN.into_response(),
+        Some(_) => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_file() -> anyhow::Result<()> {
+        let mut tokens = Tokens::default();
+        tokens.extend_from_str(
+            "# readers\nread reader-token\n\nwrite writer-token\nread writer-token\n",
+        )?;
+        assert_eq!(tokens.scope("reader-token"), Some(Scope::Read));
+        assert_eq!(tokens.scope("writer-token"), Some(Scope::Write));
+        assert_eq!(tokens.scope("unknown"), None);
+
+        assert!(tokens.extend_from_str("admin token").is_err());
+        assert!(tokens.extend_from_str("read").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_scope() {
+        assert_eq!(required_scope(&Method::GET, "/key"), Scope::Read);
+        assert_eq!(required_scope(&Method::HEAD, "/key"), Scope::Read);
+        assert_eq!(required_scope(&Method::PUT, "/key"), Scope::Write);
+        assert_eq!(required_scope(&Method::DELETE, "/key"), Scope::Write);
+        assert_eq!(required_scope(&Method::GET, "/admin/report"), Scope::Write);
+        assert_eq!(required_scope(&Method::GET, "/administrator"), Scope::Read);
+    }
+}
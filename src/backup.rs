@@ -0,0 +1,417 @@
+use anyhow::Context;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::sync::Arc;
+
+use crate::{checksum, dedup, hashring, record, server};
+
+/// Struct representing a line of a metadata dump, one JSON object per live record.
+/// The blob of the record is stored in the blobs archive under the key as its path.
+/// Lines of `export_records` are read too, their volumes are ignored and their records not live skipped.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct BackupRecord {
+    pub(crate) key: String,
+    #[serde(default)]
+    pub(crate) hash: String,
+    pub(crate) size: u64,
+    #[serde(default)]
+    pub(crate) placement: Option<String>,
+    #[serde(default)]
+    pub(crate) expires_at: Option<u64>,
+    #[serde(default)]
+    pub(crate) content_disposition: Option<String>,
+    #[serde(default)]
+    pub(crate) content_type: Option<String>,
+    #[serde(default)]
+    pub(crate) checksums: Vec<(checksum::Algorithm, String)>,
+    #[serde(default)]
+    pub(crate) deleted: Option<record::Deleted>,
+    #[serde(default)]
+    pub(crate) created_at: Option<u64>,
+    #[serde(default)]
+    pub(crate) updated_at: Option<u64>,
+    #[serde(default)]
+    pub(crate) replicas: Option<usize>,
+    #[serde(default)]
+    pub(crate) refcount: Option<u64>,
+    #[serde(default)]
+    pub(crate) hash_algorithm: Option<checksum::Algorithm>,
+}
+
+impl BackupRecord {
+    /// Checks the value against the size, hash and checksums of the dump.
+    fn verify(&self, value: &[u8]) -> anyhow::Result<()> {
+        if value.len() as u64 != self.size {
+            anyhow::bail!(
+                "size mismatch, expected: {} got: {}",
+                self.size,
+                value.len()
+            );
+        }
+        let hash_algorithm = self.hash_algorithm.unwrap_or(checksum::Algorithm::Md5);
+        if !self.hash.is_empty() && hash_algorithm.compute_hex(value) != self.hash {
+            anyhow::bail!("{:?} mismatch, expected: {}", hash_algorithm, self.hash);
+        }
+        for (algorithm, expected) in self.checksums.iter() {
+            if !algorithm.matches(expected, &algorithm.compute(value)) {
+                anyhow::bail!("{:?} mismatch, expected: {}", algorithm, expected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts the dump line into a live leveldb record stored in the given volumes.
+    /// The times the key was written are kept if the dump has them.
+    fn into_record(self, read_volumes: Vec<String>) -> record::Record {
+        let record = record::Record::new(record::Deleted::No, self.hash, read_volumes)
+            .with_hash_algorithm(self.hash_algorithm.unwrap_or(checksum::Algorithm::Md5))
+            .with_checksums(self.checksums)
+            .with_content_disposition(self.content_disposition)
+            .with_content_type(self.content_type)
+            .with_expires_at(self.expires_at)
+            .with_placement(self.placement)
+            .with_replicas(self.replicas)
+            .with_refcount(self.refcount)
+            .with_size(self.size);
+        match (self.created_at, self.updated_at) {
+            (Some(created_at), Some(updated_at)) => record.with_timestamps(created_at, updated_at),
+            _ => record,
+        }
+    }
+}
+
+/// Reads a metadata dump, one JSON record per line, indexed by key.
+pub(crate) fn read_metadata(reader: impl BufRead) -> anyhow::Result<HashMap<String, BackupRecord>> {
+    let mut records = HashMap::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: BackupRecord = serde_json::from_str(&line)
+            .with_context(|| format!("invalid metadata at line {}", number + 1))?;
+        if record
+            .deleted
+            .is_some_and(|deleted| deleted != record::Deleted::No)
+        {
+            continue;
+        }
+        records.insert(record.key.clone(), record);
+    }
+    Ok(records)
+}
+
+/// Writes every record of the leveldb as a JSON line, deleted records and the parts of multipart
+/// uploads included, with every field of the record. Returns the number of records written.
+pub(crate) fn export_records(
+    leveldb: &record::LevelDb,
+    mut writer: impl Write,
+) -> anyhow::Result<u64> {
+    let mut exported = 0;
+    leveldb.for_each_record(|record| {
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+        exported += 1;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(exported)
+}
+
+/// Writes the records of an export, one JSON record per line, into an empty leveldb.
+/// The references of the records to their deduplicated blobs are counted again.
+/// Returns the number of records written.
+pub(crate) async fn import_records(
+    leveldb: &record::LevelDb,
+    reader: impl BufRead,
+) -> anyhow::Result<u64> {
+    let mut existing = 0;
+    leveldb.for_each_record(|_| {
+        existing += 1;
+        Ok(())
+    })?;
+    if existing > 0 {
+        anyhow::bail!("the leveldb already holds {} records", existing);
+    }
+
+    let mut imported = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: record::Record = serde_json::from_str(&line)
+            .with_context(|| format!("invalid record at line {}", number + 1))?;
+        let key = record.key().to_string();
+        if key.is_empty() {
+            anyhow::bail!("record without a key at line {}", number + 1);
+        }
+        if let Some(hash) = record.blob() {
+            if dedup::acquire(leveldb, hash).await?.is_none() {
+                dedup::register(leveldb, hash, record.read_volumes()).await?;
+            }
+        }
+        leveldb.put_record(&key, record).await?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+/// Struct counting the records handled by a restore.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct RestoreStats {
+    pub(crate) restored: u64,
+    pub(crate) failed: u64,
+    pub(crate) missing_blob: u64,
+    pub(crate) missing_metadata: u64,
+}
+
+/// Struct restoring a metadata dump and a blobs archive into a leveldb and the volumes of the current ring.
+pub(crate) struct Restore {
+    leveldb: Arc<record::LevelDb>,
+    hashring: hashring::Ring,
+    client: reqwest::Client,
+}
+
+impl Restore {
+    /// Creates a new restore writing records to the leveldb and values to the ring volumes.
+    pub(crate) fn new(leveldb: Arc<record::LevelDb>, hashring: hashring::Ring) -> Self {
+        Self {
+            leveldb,
+            hashring,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses the client to write the restored values to the volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Restores every blob of the tar archive that has a record in the metadata dump.
+    /// Values failing their checksums are not uploaded, records without a blob are not written.
+    /// Fails only if the archive cannot be read, records failing to restore are counted and logged.
+    pub(crate) async fn run(
+        &self,
+        mut metadata: HashMap<String, BackupRecord>,
+        blobs: impl Read,
+    ) -> anyhow::Result<RestoreStats> {
+        let mut stats = RestoreStats::default();
+        let mut archive = tar::Archive::new(blobs);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let key = entry.path()?.to_string_lossy().into_owned();
+            let Some(backup_record) = metadata.remove(&key) else {
+                debug!("restore: key: {} has no metadata, skipping blob", key);
+                stats.missing_metadata += 1;
+                continue;
+            };
+            let mut value = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut value)?;
+
+            match self.restore_record(&key, backup_record, value).await {
+                Ok(()) => stats.restored += 1,
+                Err(e) => {
+                    error!("restore: failed to restore key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        for key in metadata.keys() {
+            error!("restore: key: {} has no blob in the archive", key);
+        }
+        stats.missing_blob = metadata.len() as u64;
+
+        info!(
+            "restore: restored: {} failed: {} missing blob: {} missing metadata: {}",
+            stats.restored, stats.failed, stats.missing_blob, stats.missing_metadata
+        );
+        Ok(stats)
+    }
+
+    /// Verifies a value, uploads it to its replicas in the current ring and writes its record.
+    async fn restore_record(
+        &self,
+        key: &str,
+        backup_record: BackupRecord,
+        value: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        backup_record.verify(&value)?;
+
+        let placement = backup_record
+            .placement
+            .as_deref()
+            .filter(|group| self.hashring.has_group(group));
+        let replicas_volumes =
+            self.hashring
+                .get_volume_with_replicas(key, placement, backup_record.replicas);
+
+        let value = bytes::Bytes::from(value);
+        let mut futures = FuturesUnordered::new();
+        for volume in replicas_volumes.iter() {
+            let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+            futures.push(server::remote_put(
+                self.client.clone(),
+                remote_url,
+                value.clone().into(),
+                value.len() as u64,
+            ));
+        }
+        while let Some(result) = futures.next().await {
+            result?;
+        }
+
+        self.leveldb
+            .put_record(key, backup_record.into_record(replicas_volumes))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    fn archive(blobs: &[(&str, &[u8])]) -> anyhow::Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (key, value) in blobs {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(value.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, key, *value)?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    #[tokio::test]
+    async fn test_restore() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let hashring = hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+
+        let dump = format!(
+            "{}\n{}\n{}\n",
+            serde_json::json!({"key": "good", "hash": checksum::md5_hex(b"onyou"), "size": 5,
+                "content_disposition": "attachment", "content_type": "text/plain"}),
+            serde_json::json!({"key": "corrupt", "hash": checksum::md5_hex(b"onyou"), "size": 5}),
+            serde_json::json!({"key": "lost", "hash": "", "size": 1}),
+        );
+        let metadata = read_metadata(dump.as_bytes())?;
+        let blobs = archive(&[("good", b"onyou"), ("corrupt", b"onyov"), ("orphan", b"x")])?;
+
+        let stats = Restore::new(leveldb.clone(), hashring)
+            .run(metadata, blobs.as_slice())
+            .await?;
+        assert_eq!(
+            stats,
+            RestoreStats {
+                restored: 1,
+                failed: 1,
+                missing_blob: 1,
+                missing_metadata: 1,
+            }
+        );
+
+        let stored: usize = (0..3).map(|i| cluster.volume(i).len()).sum();
+        assert_eq!(stored, 2);
+        let remote_path = record::get_remote_path("good");
+        for i in 0..3 {
+            for path in cluster.volume(i).paths() {
+                assert!(path.ends_with(&remote_path));
+                assert_eq!(cluster.volume(i).get(&path).unwrap(), "onyou");
+            }
+        }
+
+        let record = leveldb.get_record("good").await?.unwrap();
+        assert_eq!(record.deleted(), record::Deleted::No);
+        assert_eq!(record.read_volumes().len(), 2);
+        assert_eq!(record.content_disposition(), Some("attachment"));
+        assert_eq!(record.content_type(), Some("text/plain"));
+        assert!(leveldb.get_record("corrupt").await?.is_none());
+        assert!(leveldb.get_record("lost").await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_import() -> anyhow::Result<()> {
+        let src_dir = tempfile::tempdir()?;
+        let src = record::LevelDb::with_backend(src_dir.path(), Default::default())?;
+        let volumes = vec!["localhost:3001/sv01".to_string()];
+        let part_key = record::part_key("upload", 1);
+        let records = [
+            (
+                "live",
+                record::Record::new(
+                    record::Deleted::No,
+                    checksum::md5_hex(b"v"),
+                    volumes.clone(),
+                )
+                .with_size(1)
+                .with_content_type(Some("text/plain".to_string()))
+                .with_timestamps(10, 20),
+            ),
+            (
+                "deleted",
+                record::Record::new(record::Deleted::Soft, String::new(), volumes.clone())
+                    .with_deleted_at(Some(30)),
+            ),
+            (
+                part_key.as_str(),
+                record::Record::new(record::Deleted::Init, String::new(), volumes.clone()),
+            ),
+        ];
+        for (key, record) in records.iter() {
+            src.put_record(key, record.clone()).await?;
+        }
+
+        let mut dump = Vec::new();
+        assert_eq!(export_records(&src, &mut dump)?, 3);
+        assert_eq!(dump.lines().count(), 3);
+
+        // Restore reads the live records of an export, with their timestamps
+        let mut metadata = read_metadata(dump.as_slice())?;
+        assert_eq!(metadata.len(), 1);
+        let restored = metadata
+            .remove("live")
+            .unwrap()
+            .into_record(volumes.clone());
+        assert_eq!(restored.created_at(), Some(10));
+        assert_eq!(restored.updated_at(), Some(20));
+        assert_eq!(restored.content_type(), Some("text/plain"));
+
+        let dst_dir = tempfile::tempdir()?;
+        let dst = record::LevelDb::with_backend(dst_dir.path(), Default::default())?;
+        assert_eq!(import_records(&dst, dump.as_slice()).await?, 3);
+        for (key, _) in records.iter() {
+            assert_eq!(
+                dst.get_record(key).await?,
+                src.get_record(key).await?,
+                "key: {}",
+                key
+            );
+        }
+        assert!(import_records(&dst, dump.as_slice()).await.is_err());
+
+        let invalid_dir = tempfile::tempdir()?;
+        let invalid = record::LevelDb::with_backend(invalid_dir.path(), Default::default())?;
+        let line = serde_json::json!({"key": "truncated"}).to_string();
+        assert!(import_records(&invalid, line.as_bytes()).await.is_err());
+
+        Ok(())
+    }
+}
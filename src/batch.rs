@@ -0,0 +1,287 @@
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use base64::Engine;
+use futures::StreamExt;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{admin, keys, server};
+
+/// Largest manifest of a batch, its values included.
+const MAX_BATCH_SIZE: usize = 64 * 1024 * 1024;
+
+/// Most operations of a batch.
+const MAX_OPERATIONS: usize = 1000;
+
+/// Operations of a batch running at once.
+const CONCURRENCY: usize = 16;
+
+/// Largest value returned by a get of a batch, larger values are fetched with a GET of their key.
+const MAX_GET_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Axum state for batch requests, the states of the key routes its operations map to.
+pub(crate) struct AppBatchState {
+    pub(crate) put: Arc<server::AppPutState>,
+    pub(crate) get: Arc<server::AppGetState>,
+    pub(crate) delete: Arc<server::AppDeleteState>,
+    pub(crate) key_policy: Arc<keys::KeyPolicy>,
+}
+
+/// Enum representing an operation of a batch manifest, values are base64 encoded.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum Operation {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        content_type: Option<String>,
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+/// Struct representing the outcome of an operation of a batch, the status of the matching request of the key.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Outcome {
+    op: String,
+    key: String,
+    status: u16,
+    /// Base64 encoded value of a successful get.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+/// Creates the router of batch requests.
+pub(crate) fn router(state: Arc<AppBatchState>) -> axum::Router {
+    axum::Router::new()
+        .route("/batch", axum::routing::post(handle_batch))
+        .with_state(state)
+}
+
+/// Handles POST requests running a JSON manifest of operations, e.g.
+/// `[{"op": "put", "key": "a", "value": "aGVsbG8="}, {"op": "get", "key": "b"}, {"op": "delete", "key": "c"}]`,
+/// in the namespace of an `X-Namespace` header, if any. Each operation runs like a PUT, proxied GET or DELETE of
+/// its key, up to 16 at once, so operations of the same key run in no particular order.
+/// Returns 200 with the outcome of each operation in manifest order as JSON,
+/// `[{"op": "get", "key": "b", "status": 200, "value": "d29ybGQ="}]`
+/// Returns 400 if the manifest is invalid or has more than 1000 operations, or the X-Namespace is not visible ASCII
+/// Returns 413 if the manifest exceeds 64 MiB
+async fn handle_batch(
+    axum::extract::State(state): axum::extract::State<Arc<AppBatchState>>,
+    headers: HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    let body = match axum::body::to_bytes(body, MAX_BATCH_SIZE).await {
+        Ok(body) => body,
+        Err(e) => {
+            debug!("batch: failed to read manifest: {}", e);
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+    };
+    let operations: Vec<Operation> = match serde_json::from_slice(&body) {
+        Ok(operations) => operations,
+        Err(e) => {
+            debug!("batch: invalid manifest: {}", e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    if operations.len() > MAX_OPERATIONS {
+        debug!("batch: {} operations", operations.len());
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let namespace = match server::namespace_header(&headers) {
+        Ok(namespace) => namespace,
+        Err(status) => return status.into_response(),
+    };
+    debug!("batch: {} operations", operations.len());
+
+    let outcomes: Vec<Outcome> = futures::stream::iter(operations)
+        .map(|operation| run(&state, namespace.clone(), operation))
+        .buffered(CONCURRENCY)
+        .collect()
+        .await;
+    axum::Json(outcomes).into_response()
+}
+
+/// Runs an operation of a batch through the handler of the matching request of its key.
+async fn run(state: &AppBatchState, namespace: Option<String>, operation: Operation) -> Outcome {
+    let (op, key) = match &operation {
+        Operation::Get { key } => ("get", key.clone()),
+        Operation::Put { key, .. } => ("put", key.clone()),
+        Operation::Delete { key } => ("delete", key.clone()),
+    };
+    let outcome = |status: StatusCode, value| Outcome {
+        op: op.to_string(),
+        key: key.clone(),
+        status: status.as_u16(),
+        value,
+    };
+    let record_key = match server::RecordKey::resolve(key.clone(), namespace) {
+        Ok(record_key) => record_key,
+        Err(status) => return outcome(status, None),
+    };
+    if let Err(e) = state.key_policy.check(&record_key.0) {
+        debug!("batch: key: {:?} rejected: {}", record_key.0, e);
+        return outcome(StatusCode::BAD_REQUEST, None);
+    }
+
+    match operation {
+        Operation::Get { .. } => {
+            let response = server::handle_get_record(
+                record_key,
+                axum::extract::State(state.get.clone()),
+                axum::extract::Query(server::GetParams::proxied()),
+                axum::extract::Query(admin::PageParams::default()),
+                HeaderMap::new(),
+            )
+            .await;
+            if response.status() != StatusCode::OK {
+                return outcome(response.status(), None);
+            }
+            let size = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            if size.is_some_and(|size| size > MAX_GET_SIZE) {
+                return outcome(StatusCode::PAYLOAD_TOO_LARGE, None);
+            }
+            match axum::body::to_bytes(response.into_body(), MAX_GET_SIZE as usize).await {
+                Ok(value) => outcome(
+                    StatusCode::OK,
+                    Some(base64::engine::general_purpose::STANDARD.encode(value)),
+                ),
+                Err(e) => {
+                    debug!("batch: failed to read value of key {}: {}", key, e);
+                    outcome(StatusCode::BAD_GATEWAY, None)
+                }
+            }
+        }
+        Operation::Put {
+            value,
+            content_type,
+            ttl,
+            ..
+        } => {
+            let Ok(value) = base64::engine::general_purpose::STANDARD.decode(value) else {
+                return outcome(StatusCode::BAD_REQUEST, None);
+            };
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(value.len()));
+            if let Some(content_type) = content_type {
+                let Ok(content_type) = HeaderValue::from_str(&content_type) else {
+                    return outcome(StatusCode::BAD_REQUEST, None);
+                };
+                headers.insert(header::CONTENT_TYPE, content_type);
+            }
+            if let Some(ttl) = ttl {
+                headers.insert(server::TTL, HeaderValue::from(ttl));
+            }
+            let status = server::handle_put_record(
+                record_key,
+                axum::extract::State(state.put.clone()),
+                axum::extract::Query(server::PutParams::default()),
+                headers,
+                axum::body::Body::from(value),
+            )
+            .await
+            .into_response()
+            .status();
+            outcome(status, None)
+        }
+        Operation::Delete { .. } => {
+            let response = server::handle_delete_record(
+                record_key,
+                axum::extract::State(state.delete.clone()),
+            )
+            .await;
+            outcome(response.status(), None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_batch() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in ["existing", "gone"] {
+            let res = client.put(cluster.key_url(key)).body("old").send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+
+        let url = format!("{}/batch", cluster.url());
+        let manifest = serde_json::json!([
+            {"op": "put", "key": "a", "value": "aGVsbG8=", "content_type": "text/plain"},
+            {"op": "put", "key": "existing", "value": "bmV3"},
+            {"op": "put", "key": "bad", "value": "not base64!"},
+            {"op": "get", "key": "existing"},
+            {"op": "get", "key": "missing"},
+            {"op": "get", "key": "a\nb"},
+            {"op": "delete", "key": "gone"},
+        ]);
+        let res = client.post(&url).json(&manifest).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let outcomes: Vec<Outcome> = res.json().await?;
+        let statuses: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| (outcome.op.as_str(), outcome.key.as_str(), outcome.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("put", "a", 201),
+                ("put", "existing", 409),
+                ("put", "bad", 400),
+                ("get", "existing", 200),
+                ("get", "missing", 404),
+                ("get", "a\nb", 400),
+                ("delete", "gone", 204),
+            ]
+        );
+        assert_eq!(outcomes[3].value.as_deref(), Some("b2xk"));
+
+        let res = client.head(cluster.key_url("a")).send().await?;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain");
+        let res = client.get(cluster.key_url("a")).send().await?;
+        assert_eq!(res.text().await?, "hello");
+        let res = client.get(cluster.key_url("existing")).send().await?;
+        assert_eq!(res.text().await?, "old");
+        let res = client.get(cluster.key_url("gone")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Keys of a namespace
+        let res = client
+            .post(&url)
+            .header(server::NAMESPACE, "app")
+            .json(&serde_json::json!([{"op": "put", "key": "b", "value": "aGk="}]))
+            .send()
+            .await?;
+        assert_eq!(res.json::<Vec<Outcome>>().await?[0].status, 201);
+        let res = client
+            .get(format!("{}/ns/app/b", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "hi");
+
+        let res = client.post(&url).body("{}").send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let manifest = vec![serde_json::json!({"op": "get", "key": "a"}); MAX_OPERATIONS + 1];
+        let res = client.post(&url).json(&manifest).send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = client.get(cluster.key_url("batch")).send().await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        Ok(())
+    }
+}
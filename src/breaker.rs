@@ -0,0 +1,271 @@
+use log::{info, warn};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use crate::server;
+
+/// Window the error rate of a volume is computed over, its counts restart every window.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// Struct representing when a circuit breaker opens and how long it stays open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    /// Fraction of failed requests in a window that opens the breaker, between 0 and 1.
+    pub error_rate: f64,
+    /// Requests in a window before the error rate is checked, 0 disables the breakers.
+    pub min_requests: u32,
+    /// Time an open breaker rejects requests before letting a trial request through.
+    pub open_for: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            error_rate: 0.5,
+            min_requests: 20,
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of the breaker of a volume.
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// Requests go through and their outcome is counted.
+    Closed,
+    /// Requests are rejected until the instant.
+    Open { until: Instant },
+    /// A single trial request went through at the instant, its outcome closes or opens the breaker.
+    HalfOpen { trial_at: Instant },
+}
+
+/// Struct representing the breaker of a volume server and the counts of its current window.
+#[derive(Debug)]
+struct Breaker {
+    state: State,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+}
+
+impl Breaker {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: State::Closed,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+        }
+    }
+}
+
+/// Struct representing the circuit breakers of the volume servers, keyed like the volumes of the ring,
+/// e.g. `localhost:3001` or `https://vol1:3001`.
+/// A volume failing at least the error rate of its requests is skipped by reads and writes while open.
+pub(crate) struct Breakers {
+    config: RwLock<BreakerConfig>,
+    volumes: Mutex<HashMap<String, Breaker>>,
+}
+
+/// Process wide circuit breakers, shared by the routes, background tasks and maintenance commands.
+pub(crate) static BREAKERS: LazyLock<Breakers> =
+    LazyLock::new(|| Breakers::new(BreakerConfig::default()));
+
+impl Breakers {
+    /// Creates the breakers, all closed.
+    fn new(config: BreakerConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            volumes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the configuration of the breakers.
+    pub(crate) fn configure(&self, config: BreakerConfig) {
+        *self.config.write() = config;
+    }
+
+    /// Returns an error if the breaker of the volume of the url rejects requests,
+    /// otherwise the request goes through and its outcome must be recorded.
+    pub(crate) fn allow(&self, remote_url: &str) -> anyhow::Result<()> {
+        let volume = volume_of(remote_url);
+        if self.try_acquire(volume, Instant::now()) {
+            Ok(())
+        } else {
+            anyhow::bail!("circuit breaker open for volume {}", volume)
+        }
+    }
+
+    /// Records the outcome of a request to the volume of the url.
+    /// Only connection errors, timeouts and 5xx count as failures, a 404 means the volume is fine.
+    pub(crate) fn record<T>(&self, remote_url: &str, result: &anyhow::Result<T>) {
+        let failed = result.as_ref().is_err_and(is_volume_failure);
+        self.record_outcome(volume_of(remote_url), failed, Instant::now());
+    }
+
+    /// Returns true if the breaker of the volume rejects requests.
+    /// An open breaker whose delay passed isn't, so the next request can be its trial.
+    pub(crate) fn is_open(&self, volume: &str) -> bool {
+        let open_for = self.config.read().open_for;
+        let now = Instant::now();
+        match self.volumes.lock().get(volume).map(|breaker| breaker.state) {
+            Some(State::Open { until }) => now < until,
+            Some(State::HalfOpen { trial_at }) => now < trial_at + open_for,
+            _ => false,
+        }
+    }
+
+    /// Returns true if a request to the volume may go through, moving an open breaker whose
+    /// delay passed to half-open. A trial that never reports back is replaced after the delay.
+    fn try_acquire(&self, volume: &str, now: Instant) -> bool {
+        let open_for = self.config.read().open_for;
+        let mut volumes = self.volumes.lock();
+        let Some(breaker) = volumes.get_mut(volume) else {
+            return true;
+        };
+        match breaker.state {
+            State::Closed => true,
+            State::Open { until } if now < until => false,
+            State::HalfOpen { trial_at } if now < trial_at + open_for => false,
+            _ => {
+                breaker.state = State::HalfOpen { trial_at: now };
+                true
+            }
+        }
+    }
+
+    /// Counts the outcome of a request in the window of the volume, and opens or closes its breaker.
+    fn record_outcome(&self, volume: &str, failed: bool, now: Instant) {
+        let config = *self.config.read();
+        if config.min_requests == 0 {
+            return;
+        }
+        let mut volumes = self.volumes.lock();
+        let breaker = volumes
+            .entry(volume.to_string())
+            .or_insert_with(|| Breaker::new(now));
+        match breaker.state {
+            State::HalfOpen { .. } if failed => {
+                warn!("breaker: volume {} still failing, reopening", volume);
+                breaker.state = State::Open {
+                    until: now + config.open_for,
+                };
+            }
+            State::HalfOpen { .. } => {
+                info!("breaker: volume {} recovered, closing", volume);
+                *breaker = Breaker::new(now);
+            }
+            // Requests sent before the breaker opened report late, they don't change it
+            State::Open { .. } => (),
+            State::Closed => {
+                if now.duration_since(breaker.window_start) >= WINDOW {
+                    *breaker = Breaker::new(now);
+                }
+                breaker.requests += 1;
+                if failed {
+                    breaker.failures += 1;
+                }
+                if breaker.failures > 0
+                    && breaker.requests >= config.min_requests
+                    && f64::from(breaker.failures)
+                        >= config.error_rate * f64::from(breaker.requests)
+                {
+                    warn!(
+                        "breaker: volume {} failed {} of {} requests, opening for {:?}",
+                        volume, breaker.failures, breaker.requests, config.open_for
+                    );
+                    breaker.state = State::Open {
+                        until: now + config.open_for,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if an error of a volume request means the volume is unhealthy.
+fn is_volume_failure(e: &anyhow::Error) -> bool {
+    server::is_transient(e)
+        || e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout())
+}
+
+/// Returns the volume of a url built by `record::volume_url`, the inverse of it.
+pub(crate) fn volume_of(remote_url: &str) -> &str {
+    // Volumes without a scheme are served over plain HTTP
+    let start = if remote_url.starts_with("http://") {
+        "http://".len()
+    } else {
+        0
+    };
+    let host_start = remote_url.find("://").map_or(0, |i| i + "://".len());
+    let end = remote_url[host_start..]
+        .find('/')
+        .map_or(remote_url.len(), |i| host_start + i);
+    &remote_url[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volume_of() {
+        assert_eq!(
+            volume_of("http://localhost:3001/sv01/5d/41/aGVsbG8="),
+            "localhost:3001"
+        );
+        assert_eq!(
+            volume_of("https://vol1:3001/5d/41/aGVsbG8="),
+            "https://vol1:3001"
+        );
+        assert_eq!(volume_of("http://localhost:3001"), "localhost:3001");
+    }
+
+    #[test]
+    fn test_breaker() {
+        let open_for = Duration::from_secs(30);
+        let breakers = Breakers::new(BreakerConfig {
+            error_rate: 0.5,
+            min_requests: 4,
+            open_for,
+        });
+        let volume = "localhost:3001";
+        let now = Instant::now();
+
+        // Below the minimum number of requests the breaker stays closed
+        for _ in 0..3 {
+            breakers.record_outcome(volume, true, now);
+        }
+        assert!(breakers.try_acquire(volume, now));
+        breakers.record_outcome(volume, false, now);
+        assert!(!breakers.try_acquire(volume, now));
+        assert!(breakers.is_open(volume));
+        assert!(!breakers.is_open("localhost:3002"));
+
+        // After the delay a single trial goes through, its failure reopens the breaker
+        let later = now + open_for;
+        assert!(breakers.try_acquire(volume, later));
+        assert!(!breakers.try_acquire(volume, later));
+        breakers.record_outcome(volume, true, later);
+        assert!(!breakers.try_acquire(volume, later));
+
+        let recovered = later + open_for;
+        assert!(breakers.try_acquire(volume, recovered));
+        breakers.record_outcome(volume, false, recovered);
+        assert!(breakers.try_acquire(volume, recovered));
+        assert!(!breakers.is_open(volume));
+
+        // Failures of an old window are forgotten
+        for _ in 0..3 {
+            breakers.record_outcome(volume, true, recovered);
+        }
+        let next_window = recovered + WINDOW;
+        breakers.record_outcome(volume, true, next_window);
+        assert!(breakers.try_acquire(volume, next_window));
+    }
+}
@@ -0,0 +1,136 @@
+use axum::http::StatusCode;
+use log::warn;
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Struct representing the probabilities of the injected faults.
+/// Every rate is a probability between 0.0 and 1.0, all faults are disabled by default.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct ChaosConfig {
+    /// Probability of failing a request to a volume server.
+    volume_error_rate: f64,
+    /// Probability of delaying a request to a volume server by `latency_spike_ms`.
+    latency_spike_rate: f64,
+    /// Duration of an injected latency spike in milliseconds.
+    latency_spike_ms: u64,
+    /// Probability of failing a leveldb read or write.
+    metadata_error_rate: f64,
+}
+
+impl ChaosConfig {
+    /// Checks that every rate is a probability.
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, rate) in [
+            ("volume_error_rate", self.volume_error_rate),
+            ("latency_spike_rate", self.latency_spike_rate),
+            ("metadata_error_rate", self.metadata_error_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                anyhow::bail!("{} must be between 0.0 and 1.0, got {}", name, rate);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Process wide chaos configuration.
+static CHAOS: RwLock<ChaosConfig> = parking_lot::const_rwlock(ChaosConfig {
+    volume_error_rate: 0.0,
+    latency_spike_rate: 0.0,
+    latency_spike_ms: 0,
+    metadata_error_rate: 0.0,
+});
+
+/// Returns true with the given probability.
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::thread_rng().gen_bool(rate.min(1.0))
+}
+
+/// Injects latency spikes and errors into a request to a volume server.
+pub(crate) async fn inject_volume_fault(remote_url: &str) -> anyhow::Result<()> {
+    let config = *CHAOS.read();
+
+    if roll(config.latency_spike_rate) {
+        warn!(
+            "chaos: injecting {}ms latency spike for {}",
+            config.latency_spike_ms, remote_url
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(config.latency_spike_ms)).await;
+    }
+
+    if roll(config.volume_error_rate) {
+        warn!("chaos: injecting volume error for {}", remote_url);
+        anyhow::bail!("chaos: injected volume error for {}", remote_url);
+    }
+
+    Ok(())
+}
+
+/// Injects errors into a leveldb operation.
+pub(crate) fn inject_metadata_fault(key: &str) -> anyhow::Result<()> {
+    if roll(CHAOS.read().metadata_error_rate) {
+        warn!("chaos: injecting metadata error for key {}", key);
+        anyhow::bail!("chaos: injected metadata error for key {}", key);
+    }
+    Ok(())
+}
+
+/// Handles GET requests returning the current chaos configuration.
+pub(crate) async fn handle_get_chaos() -> axum::Json<ChaosConfig> {
+    axum::Json(*CHAOS.read())
+}
+
+/// Handles PUT requests replacing the chaos configuration.
+/// Returns 200 with the new configuration
+/// Returns 400 if a rate is not a probability
+pub(crate) async fn handle_put_chaos(
+    axum::Json(config): axum::Json<ChaosConfig>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if let Err(e) = config.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    warn!("chaos: configuration set to {:?}", config);
+    *CHAOS.write() = config;
+    axum::Json(config).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chaos_config_validate() {
+        assert!(ChaosConfig::default().validate().is_ok());
+
+        let config = ChaosConfig {
+            volume_error_rate: 1.0,
+            latency_spike_rate: 0.5,
+            latency_spike_ms: 100,
+            metadata_error_rate: 0.0,
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ChaosConfig {
+            metadata_error_rate: 1.5,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ChaosConfig {
+            volume_error_rate: -0.1,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_roll() {
+        assert!(!roll(0.0));
+        assert!(roll(1.0));
+    }
+}
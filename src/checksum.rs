@@ -0,0 +1,380 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+/// Name of the header (or trailer) carrying the MD5 digest of a value.
+pub(crate) const CONTENT_MD5: &str = "content-md5";
+
+/// Computes the MD5 digest of a value as a lowercase hex string.
+pub(crate) fn md5_hex(value: &[u8]) -> String {
+    format!("{:x}", md5::compute(value))
+}
+
+/// Parses a client supplied MD5 digest into a lowercase hex string.
+/// Accepts the hex encoding used by our Content-Md5 responses and the base64 encoding of RFC 1864.
+/// Returns None if the value is not a valid MD5 digest in either encoding.
+pub(crate) fn parse_md5(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() == 32 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Some(value.to_ascii_lowercase());
+    }
+
+    let digest = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .ok()?;
+    if digest.len() != 16 {
+        return None;
+    }
+    Some(encode_hex(&digest))
+}
+
+/// Computes the MD5 of a multipart value like S3 ETags: the MD5 of the concatenated part MD5s,
+/// followed by a dash and the number of parts, e.g. `9b2cf535f27731c974343645a3985328-2`.
+pub(crate) fn multipart_md5_hex<'a>(part_hashes: impl ExactSizeIterator<Item = &'a str>) -> String {
+    let count = part_hashes.len();
+    let digests: Vec<u8> = part_hashes
+        .flat_map(|hash| decode_hex(hash).unwrap_or_default())
+        .collect();
+    format!("{}-{}", md5_hex(&digests), count)
+}
+
+/// Name of the header selecting the checksum algorithm verified and returned for a request.
+pub(crate) const X_CHECKSUM_ALGORITHM: &str = "x-checksum-algorithm";
+
+/// Enum representing the checksum algorithms clients can negotiate per request.
+/// Digests are exchanged base64 encoded in `X-Checksum-<Algorithm>` headers, like S3 SDKs do.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+    Crc32c,
+    Blake3,
+}
+
+impl Algorithm {
+    /// Every supported algorithm.
+    pub(crate) const ALL: [Algorithm; 4] = [
+        Algorithm::Md5,
+        Algorithm::Sha256,
+        Algorithm::Crc32c,
+        Algorithm::Blake3,
+    ];
+
+    /// Returns the name of the header (or trailer) carrying the digest.
+    pub(crate) fn header_name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "x-checksum-md5",
+            Algorithm::Sha256 => "x-checksum-sha256",
+            Algorithm::Crc32c => "x-checksum-crc32c",
+            Algorithm::Blake3 => "x-checksum-blake3",
+        }
+    }
+
+    /// Returns the name of the header of GET and HEAD responses carrying the hex digest of the value
+    /// a record is stored with, `Content-Md5` for MD5 like before the algorithm was configurable.
+    pub(crate) fn hash_header_name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "content-md5",
+            Algorithm::Sha256 => "x-content-sha256",
+            Algorithm::Crc32c => "x-content-crc32c",
+            Algorithm::Blake3 => "x-content-blake3",
+        }
+    }
+
+    /// Computes the digest of a value.
+    fn digest(&self, value: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Md5 => md5::compute(value).to_vec(),
+            Algorithm::Sha256 => sha2::Sha256::digest(value).to_vec(),
+            Algorithm::Crc32c => crc32c::crc32c(value).to_be_bytes().to_vec(),
+            Algorithm::Blake3 => blake3::hash(value).as_bytes().to_vec(),
+        }
+    }
+
+    /// Computes the hex encoded digest of a value, like the hash of the records.
+    pub(crate) fn compute_hex(&self, value: &[u8]) -> String {
+        encode_hex(&self.digest(value))
+    }
+
+    /// Computes the base64 encoded digest of a value.
+    pub(crate) fn compute(&self, value: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.digest(value))
+    }
+
+    /// Returns true if a client supplied digest, base64 or hex encoded, equals the base64 encoded digest.
+    pub(crate) fn matches(&self, expected: &str, digest: &str) -> bool {
+        let expected = expected.trim();
+        let Ok(digest) = base64::engine::general_purpose::STANDARD.decode(digest) else {
+            return false;
+        };
+        let decoded = if expected.len() == digest.len() * 2 {
+            decode_hex(expected)
+        } else {
+            base64::engine::general_purpose::STANDARD
+                .decode(expected)
+                .ok()
+        };
+        decoded.is_some_and(|expected| expected == digest)
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "md5" => Ok(Algorithm::Md5),
+            "sha256" => Ok(Algorithm::Sha256),
+            "crc32c" => Ok(Algorithm::Crc32c),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(anyhow::anyhow!("unsupported checksum algorithm: {}", value)),
+        }
+    }
+}
+
+/// Enum representing the algorithms the hash of the records can be computed with, or none to store
+/// values without a hash. MD5 is the default, for the clients comparing `Content-Md5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Checksum {
+    Md5,
+    Sha256,
+    Blake3,
+    None,
+}
+
+impl Checksum {
+    /// Returns the algorithm of the hash, None if values are stored without one.
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        match self {
+            Checksum::Md5 => Some(Algorithm::Md5),
+            Checksum::Sha256 => Some(Algorithm::Sha256),
+            Checksum::Blake3 => Some(Algorithm::Blake3),
+            Checksum::None => None,
+        }
+    }
+}
+
+/// Decodes a hex string of even length, None if it is not valid hex.
+pub(crate) fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.is_ascii() {
+        return None;
+    }
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Returns the algorithm selected by the `X-Checksum-Algorithm` header, None if the header is missing.
+pub(crate) fn requested_algorithm(
+    headers: &axum::http::HeaderMap,
+) -> anyhow::Result<Option<Algorithm>> {
+    match headers.get(X_CHECKSUM_ALGORITHM) {
+        Some(value) => Ok(Some(value.to_str()?.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Enum representing the running state of a digest.
+enum DigestState {
+    Md5(md5::Context),
+    Sha256(sha2::Sha256),
+    Crc32c(u32),
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Struct computing the digests of a value incrementally, as its chunks arrive.
+pub(crate) struct Hasher {
+    states: Vec<(Algorithm, DigestState)>,
+}
+
+impl Hasher {
+    /// Creates a new hasher computing the digest of every algorithm.
+    pub(crate) fn new(algorithms: &[Algorithm]) -> Self {
+        let states = algorithms
+            .iter()
+            .map(|algorithm| {
+                let state = match algorithm {
+                    Algorithm::Md5 => DigestState::Md5(md5::Context::new()),
+                    Algorithm::Sha256 => DigestState::Sha256(sha2::Sha256::new()),
+                    Algorithm::Crc32c => DigestState::Crc32c(0),
+                    Algorithm::Blake3 => DigestState::Blake3(Box::default()),
+                };
+                (*algorithm, state)
+            })
+            .collect();
+        Self { states }
+    }
+
+    /// Feeds the next chunk of the value to every digest.
+    pub(crate) fn update(&mut self, chunk: &[u8]) {
+        for (_, state) in self.states.iter_mut() {
+            match state {
+                DigestState::Md5(context) => context.consume(chunk),
+                DigestState::Sha256(hasher) => hasher.update(chunk),
+                DigestState::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, chunk),
+                DigestState::Blake3(hasher) => {
+                    hasher.update(chunk);
+                }
+            }
+        }
+    }
+
+    /// Returns the raw digest of every algorithm.
+    pub(crate) fn finalize(self) -> Vec<(Algorithm, Vec<u8>)> {
+        self.states
+            .into_iter()
+            .map(|(algorithm, state)| {
+                let digest = match state {
+                    DigestState::Md5(context) => context.compute().to_vec(),
+                    DigestState::Sha256(hasher) => hasher.finalize().to_vec(),
+                    DigestState::Crc32c(crc) => crc.to_be_bytes().to_vec(),
+                    DigestState::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+                };
+                (algorithm, digest)
+            })
+            .collect()
+    }
+}
+
+/// Encodes a raw digest as a lowercase hex string, like our Content-Md5 responses.
+pub(crate) fn encode_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encodes a raw digest in base64, like the `X-Checksum-<Algorithm>` headers.
+pub(crate) fn encode_base64(digest: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// Returns the algorithm and hex digest of the value of a GET or HEAD response of the index,
+/// None if the value was stored without one.
+pub(crate) fn response_hash(headers: &axum::http::HeaderMap) -> Option<(Algorithm, &str)> {
+    Algorithm::ALL.iter().find_map(|algorithm| {
+        let hash = headers.get(algorithm.hash_header_name())?.to_str().ok()?;
+        (!hash.is_empty()).then_some((*algorithm, hash))
+    })
+}
+
+/// Returns true if the request announces a trailer with the given name in its `Trailer` header.
+pub(crate) fn announces_trailer(headers: &axum::http::HeaderMap, name: &str) -> bool {
+    headers
+        .get_all(axum::http::header::TRAILER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|trailer| trailer.trim().eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multipart_md5_hex() {
+        let parts = [md5_hex(b"on"), md5_hex(b"you")];
+        let mut digests = md5::compute(b"on").to_vec();
+        digests.extend(md5::compute(b"you").to_vec());
+        assert_eq!(
+            multipart_md5_hex(parts.iter().map(String::as_str)),
+            format!("{}-2", md5_hex(&digests))
+        );
+    }
+
+    #[test]
+    fn test_md5_hex() {
+        assert_eq!(md5_hex(b"onyou"), format!("{:x}", md5::compute(b"onyou")));
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+    }
+
+    #[test]
+    fn test_parse_md5() {
+        let tests = vec![
+            (
+                "d41d8cd98f00b204e9800998ecf8427e",
+                Some("d41d8cd98f00b204e9800998ecf8427e"),
+            ),
+            (
+                "D41D8CD98F00B204E9800998ECF8427E",
+                Some("d41d8cd98f00b204e9800998ecf8427e"),
+            ),
+            (
+                "1B2M2Y8AsgTpgAmY7PhCfg==",
+                Some("d41d8cd98f00b204e9800998ecf8427e"),
+            ),
+            ("not a digest", None),
+            ("aGVsbG8=", None),
+        ];
+
+        for (value, expected) in tests {
+            assert_eq!(parse_md5(value).as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn test_algorithm_compute() {
+        assert_eq!(Algorithm::Md5.compute(b""), "1B2M2Y8AsgTpgAmY7PhCfg==");
+        assert_eq!(
+            Algorithm::Sha256.compute(b"hello"),
+            "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert_eq!(Algorithm::Crc32c.compute(b"hello"), "mnG7TA==");
+        assert_eq!(
+            Algorithm::Blake3.compute_hex(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[test]
+    fn test_algorithm_matches() {
+        let digest = Algorithm::Sha256.compute(b"hello");
+        assert!(Algorithm::Sha256.matches(&digest, &digest));
+        assert!(Algorithm::Sha256.matches(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            &digest
+        ));
+        assert!(!Algorithm::Sha256.matches(&Algorithm::Sha256.compute(b"world"), &digest));
+        assert!(!Algorithm::Sha256.matches("not a digest!", &digest));
+    }
+
+    #[test]
+    fn test_hasher() {
+        let mut hasher = Hasher::new(&Algorithm::ALL);
+        hasher.update(b"hel");
+        hasher.update(b"");
+        hasher.update(b"lo");
+        for (algorithm, digest) in hasher.finalize() {
+            assert_eq!(encode_base64(&digest), algorithm.compute(b"hello"));
+        }
+        let digest = Hasher::new(&[Algorithm::Md5]).finalize().remove(0).1;
+        assert_eq!(encode_hex(&digest), md5_hex(b""));
+    }
+
+    #[test]
+    fn test_requested_algorithm() -> anyhow::Result<()> {
+        let mut headers = axum::http::HeaderMap::new();
+        assert_eq!(requested_algorithm(&headers)?, None);
+
+        headers.insert(X_CHECKSUM_ALGORITHM, "SHA256".parse()?);
+        assert_eq!(requested_algorithm(&headers)?, Some(Algorithm::Sha256));
+
+        headers.insert(X_CHECKSUM_ALGORITHM, "crc32".parse()?);
+        assert!(requested_algorithm(&headers).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_announces_trailer() {
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(!announces_trailer(&headers, CONTENT_MD5));
+
+        headers.insert(
+            axum::http::header::TRAILER,
+            "Expires, Content-MD5".parse().unwrap(),
+        );
+        assert!(announces_trailer(&headers, CONTENT_MD5));
+        assert!(!announces_trailer(&headers, "x-checksum"));
+    }
+}
@@ -0,0 +1,317 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{checksum, server};
+
+/// Keys requested per page when listing a prefix.
+const LIST_LIMIT: usize = 1000;
+
+/// Page of keys returned by `GET /prefix?list`, listed as their paths.
+#[derive(Deserialize)]
+struct ListPage {
+    keys: Vec<String>,
+    next: String,
+}
+
+/// Struct representing an async client of an index server.
+/// GETs follow the redirect to the volume, requests failing to connect or with a 5xx are retried,
+/// and with checksum verification values are checked against the MD5 stored by the index.
+#[derive(Clone)]
+pub struct MiniKvClient {
+    url: reqwest::Url,
+    client: reqwest::Client,
+    token: Option<String>,
+    retry: server::RetryPolicy,
+    verify_checksums: bool,
+}
+
+impl MiniKvClient {
+    /// Creates a client of the index server at the url, e.g. `http://localhost:3000`.
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(url)?;
+        if url.cannot_be_a_base() {
+            anyhow::bail!("invalid index url: {}", url);
+        }
+        // Redirects are followed by hand, so the headers of the index are kept
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            url,
+            client,
+            token: None,
+            retry: server::RetryPolicy::default(),
+            verify_checksums: false,
+        })
+    }
+
+    /// Sends the bearer token to the index server, volumes are never sent it.
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Retries requests failing to connect or with a 5xx with the policy.
+    pub fn with_retry(mut self, retry: server::RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends the MD5 of PUT values for the index to verify, and verifies the hash of GET values.
+    pub fn with_checksum_verification(mut self, verify_checksums: bool) -> Self {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Puts the value of a key, replacing the previous one.
+    pub async fn put(&self, key: &str, value: impl Into<bytes::Bytes>) -> anyhow::Result<()> {
+        let value = value.into();
+        let md5 = self.verify_checksums.then(|| checksum::md5_hex(&value));
+        let res = self
+            .send(|| {
+                let mut request = self.index_request(reqwest::Method::PUT, key);
+                if let Some(md5) = &md5 {
+                    request = request.header(checksum::CONTENT_MD5, md5);
+                }
+                request.body(value.clone())
+            })
+            .await?;
+        match res.status() {
+            StatusCode::CREATED => Ok(()),
+            status => anyhow::bail!("put {}: {}", key, status),
+        }
+    }
+
+    /// Gets the value of a key, None if the key doesn't exist, is deleted or expired.
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<bytes::Bytes>> {
+        let res = self
+            .send(|| self.index_request(reqwest::Method::GET, key))
+            .await?;
+        let hash = checksum::response_hash(res.headers())
+            .map(|(algorithm, hash)| (algorithm, hash.to_ascii_lowercase()));
+        let res = match res.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if status.is_redirection() => {
+                let location = res
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or_else(|| anyhow::anyhow!("get {}: redirect without a location", key))?;
+                let location = res.url().join(location)?;
+                self.send(|| self.client.get(location.clone())).await?
+            }
+            _ => res,
+        };
+        if !res.status().is_success() {
+            anyhow::bail!("get {}: {}", key, res.status());
+        }
+
+        let value = res.bytes().await?;
+        // Multipart values are stored with an S3 style MD5 of their parts, which can't be verified
+        if let Some((algorithm, hash)) =
+            hash.filter(|(_, hash)| self.verify_checksums && !hash.contains('-'))
+        {
+            let actual = algorithm.compute_hex(&value);
+            if actual != hash {
+                anyhow::bail!(
+                    "get {}: {:?} mismatch, expected {} got {}",
+                    key,
+                    algorithm,
+                    hash,
+                    actual
+                );
+            }
+        }
+        Ok(Some(value))
+    }
+
+    /// Deletes a key, deleting a missing key succeeds.
+    pub async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let res = self
+            .send(|| self.index_request(reqwest::Method::DELETE, key))
+            .await?;
+        match res.status() {
+            StatusCode::NO_CONTENT => Ok(()),
+            status => anyhow::bail!("delete {}: {}", key, status),
+        }
+    }
+
+    /// Lists the live keys starting with the prefix, in key order.
+    /// The prefix can't be empty, like the prefix of `GET /prefix?list`.
+    pub async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        if prefix.is_empty() {
+            anyhow::bail!("list: the prefix can't be empty");
+        }
+        let mut keys = Vec::new();
+        let mut start = String::new();
+        loop {
+            let res = self
+                .send(|| {
+                    let mut request = self.index_request(reqwest::Method::GET, prefix);
+                    request = request.query(&[("list", ""), ("limit", &LIST_LIMIT.to_string())]);
+                    if !start.is_empty() {
+                        request = request.query(&[("start", &start)]);
+                    }
+                    request
+                })
+                .await?;
+            match res.status() {
+                StatusCode::OK => {}
+                StatusCode::NOT_FOUND => return Ok(keys),
+                status => anyhow::bail!("list {}: {}", prefix, status),
+            }
+            let page: ListPage = res.json().await?;
+            keys.extend(
+                page.keys
+                    .into_iter()
+                    .map(|key| key.strip_prefix('/').map(str::to_string).unwrap_or(key)),
+            );
+            if page.next.is_empty() {
+                return Ok(keys);
+            }
+            start = page.next;
+        }
+    }
+
+    /// Builds a request to the index server for a key, with the bearer token, if any.
+    /// The key is a single path segment, slashes included are percent-encoded.
+    fn index_request(&self, method: reqwest::Method, key: &str) -> reqwest::RequestBuilder {
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push(key);
+        }
+        let mut request = self.client.request(method, url);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+    }
+
+    /// Sends the request built by the closure, retrying it if it fails to connect or returns a 5xx.
+    /// The last response is returned if every attempt returns a 5xx.
+    async fn send(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut attempt = 1;
+        loop {
+            let result = request().send().await;
+            let retryable = match &result {
+                Ok(res) => res.status().is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retryable || attempt >= self.retry.attempts {
+                return Ok(result?);
+            }
+            tokio::time::sleep(self.retry.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Struct representing a blocking client of an index server, running a `MiniKvClient` on a
+/// runtime of its own. It must not be used from async code, like `reqwest::blocking`.
+pub struct BlockingMiniKvClient {
+    client: MiniKvClient,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl BlockingMiniKvClient {
+    /// Creates a blocking client of the index server at the url, e.g. `http://localhost:3000`.
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        Self::from_client(MiniKvClient::new(url)?)
+    }
+
+    /// Creates a blocking client sending the requests of the async client.
+    pub fn from_client(client: MiniKvClient) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self { client, runtime })
+    }
+
+    /// Puts the value of a key, see `MiniKvClient::put`.
+    pub fn put(&self, key: &str, value: impl Into<bytes::Bytes>) -> anyhow::Result<()> {
+        self.runtime.block_on(self.client.put(key, value))
+    }
+
+    /// Gets the value of a key, see `MiniKvClient::get`.
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<bytes::Bytes>> {
+        self.runtime.block_on(self.client.get(key))
+    }
+
+    /// Deletes a key, see `MiniKvClient::delete`.
+    pub fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.runtime.block_on(self.client.delete(key))
+    }
+
+    /// Lists the live keys starting with the prefix, see `MiniKvClient::list`.
+    pub fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        self.runtime.block_on(self.client.list(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_client() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = MiniKvClient::new(cluster.url())?.with_checksum_verification(true);
+
+        assert_eq!(client.get("photos/cat").await?, None);
+        client.put("photos/cat", "meow").await?;
+        client.put("photos/dog", "woof").await?;
+        client.put("videos/cat", "purr").await?;
+        assert_eq!(
+            client.get("photos/cat").await?,
+            Some(bytes::Bytes::from("meow"))
+        );
+        assert_eq!(client.list("photos/").await?, ["photos/cat", "photos/dog"]);
+
+        client.delete("photos/cat").await?;
+        assert_eq!(client.get("photos/cat").await?, None);
+        assert_eq!(client.list("photos/").await?, ["photos/dog"]);
+        assert!(client.list("missing/").await?.is_empty());
+        assert!(client.list("").await.is_err());
+
+        // The value on the volumes no longer matches the MD5 stored by the index
+        for (i, volume) in cluster.volume_addrs().iter().enumerate() {
+            for path in cluster.volume(i).paths() {
+                reqwest::Client::new()
+                    .put(format!("http://{}{}", volume, path))
+                    .body("bark")
+                    .send()
+                    .await?;
+            }
+        }
+        assert!(client.get("photos/dog").await.is_err());
+        let unverified = MiniKvClient::new(cluster.url())?;
+        assert_eq!(
+            unverified.get("photos/dog").await?,
+            Some(bytes::Bytes::from("bark"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocking_client() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(1, 1).await?;
+        let url = cluster.url().to_string();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let client = BlockingMiniKvClient::new(&url)?;
+            client.put("wehave", "bigswag")?;
+            assert_eq!(client.get("wehave")?, Some(bytes::Bytes::from("bigswag")));
+            assert_eq!(client.list("we")?, ["wehave"]);
+            client.delete("wehave")?;
+            assert_eq!(client.get("wehave")?, None);
+            Ok(())
+        })
+        .await??;
+
+        Ok(())
+    }
+}
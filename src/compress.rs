@@ -0,0 +1,161 @@
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use tokio::io::{AsyncBufRead, AsyncRead};
+
+/// Name of the zstd encoding in records and in `Content-Encoding` and `Accept-Encoding` headers.
+pub(crate) const ZSTD: &str = "zstd";
+
+/// Default zstd level of `--compress zstd`, the level of the zstd command line.
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Content types of values already compressed, written to the volumes as uploaded.
+const COMPRESSED_TYPES: [&str; 10] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+    "application/octet-stream+zstd",
+];
+
+/// Struct representing the zstd compression of values before they are written to the volumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub level: i32,
+}
+
+impl CompressionConfig {
+    /// Returns true if a value uploaded with the headers is compressed, false if the client already
+    /// encoded it or its Content-Type is a compressed format, e.g. images, video or archives.
+    pub(crate) fn applies_to(&self, headers: &axum::http::HeaderMap) -> bool {
+        if headers.contains_key(axum::http::header::CONTENT_ENCODING) {
+            return false;
+        }
+        let Some(content_type) = headers
+            .get(axum::http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return true;
+        };
+        !is_compressed(content_type)
+    }
+
+    /// Returns a reader compressing the bytes of the reader.
+    pub(crate) fn encoder(&self, reader: impl AsyncBufRead) -> impl AsyncRead {
+        ZstdEncoder::with_quality(reader, async_compression::Level::Precise(self.level))
+    }
+}
+
+/// Returns true if the media type is a compressed format, not worth compressing again.
+/// SVG images are text and compressed.
+fn is_compressed(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if media_type == "image/svg+xml" {
+        return false;
+    }
+    ["image/", "video/", "audio/", "font/"]
+        .iter()
+        .any(|prefix| media_type.starts_with(prefix))
+        || COMPRESSED_TYPES.contains(&media_type.as_str())
+}
+
+/// Returns a reader decompressing the bytes of the reader, a value stored with the encoding.
+pub(crate) fn decoder(reader: impl AsyncBufRead) -> impl AsyncRead {
+    ZstdDecoder::new(reader)
+}
+
+/// Returns true if the `Accept-Encoding` header lists the encoding, so the stored value is sent as is.
+/// Encodings with `q=0` are refused, and `*` is not taken as accepting zstd.
+pub(crate) fn accepts(headers: &axum::http::HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(axum::http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case(encoding) && !refused
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_applies_to() {
+        let config = CompressionConfig {
+            level: DEFAULT_LEVEL,
+        };
+        let headers = |pairs: &[(axum::http::HeaderName, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+                .collect::<axum::http::HeaderMap>()
+        };
+        let content_type = axum::http::header::CONTENT_TYPE;
+
+        assert!(config.applies_to(&headers(&[])));
+        assert!(config.applies_to(&headers(&[(
+            content_type.clone(),
+            "text/plain; charset=utf-8"
+        )])));
+        assert!(config.applies_to(&headers(&[(content_type.clone(), "image/svg+xml")])));
+        assert!(!config.applies_to(&headers(&[(content_type.clone(), "image/png")])));
+        assert!(!config.applies_to(&headers(&[(content_type.clone(), "Application/Zip")])));
+        assert!(!config.applies_to(&headers(&[(axum::http::header::CONTENT_ENCODING, "gzip")])));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let accept = |value: &str| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert(axum::http::header::ACCEPT_ENCODING, value.parse().unwrap());
+            accepts(&headers, ZSTD)
+        };
+
+        assert!(accept("zstd"));
+        assert!(accept("gzip, deflate, br, zstd"));
+        assert!(accept("gzip;q=1.0, ZSTD;q=0.5"));
+        assert!(!accept("zstd;q=0"));
+        assert!(!accept("gzip, *"));
+        assert!(!accepts(&axum::http::HeaderMap::new(), ZSTD));
+    }
+
+    #[tokio::test]
+    async fn test_encoder_decoder() -> anyhow::Result<()> {
+        let value = b"a value compressed with zstd ".repeat(100);
+        let config = CompressionConfig { level: 19 };
+
+        let mut compressed = Vec::new();
+        config
+            .encoder(value.as_slice())
+            .read_to_end(&mut compressed)
+            .await?;
+        assert!(compressed.len() < value.len());
+
+        let mut decompressed = Vec::new();
+        decoder(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .await?;
+        assert_eq!(decompressed, value);
+
+        Ok(())
+    }
+}
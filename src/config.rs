@@ -0,0 +1,593 @@
+use anyhow::Context;
+use clap::parser::ValueSource;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rust_minikeyvalue::{checksum, hashring};
+
+use crate::Cli;
+
+/// Struct representing a `--config` TOML file. The keys are the server flags, e.g.
+/// `volumes = ["localhost:3001"]`, with tables for the volume groups, placement rules and task schedules.
+/// Flags given on the command line override the values of the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct FileConfig {
+    port: Option<u16>,
+    internal_addr: Option<SocketAddr>,
+    s3_addr: Option<SocketAddr>,
+    resp_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    admin_port: Option<u16>,
+    admin_localhost: Option<bool>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    volume_ca: Option<PathBuf>,
+    volume_cert: Option<PathBuf>,
+    volume_key: Option<PathBuf>,
+    auth_token: Option<String>,
+    auth_token_file: Option<PathBuf>,
+    leveldb_path: Option<String>,
+    db_backend: Option<String>,
+    record_encoding: Option<String>,
+    record_cache: Option<usize>,
+    negative_cache: Option<usize>,
+    negative_cache_ttl_secs: Option<u64>,
+    value_cache: Option<u64>,
+    value_cache_max_size: Option<u64>,
+    value_cache_ttl_secs: Option<u64>,
+    hash_md5_checksum: Option<bool>,
+    checksum: Option<String>,
+    checksum_algorithms: Option<Vec<String>>,
+    volumes: Option<Vec<String>>,
+    default_proxy: Option<bool>,
+    redirect_status: Option<u16>,
+    redirect_cache_control: Option<String>,
+    concurrent_heads: Option<bool>,
+    replicas: Option<usize>,
+    max_replicas: Option<u64>,
+    namespace_replicas: Option<BTreeMap<String, usize>>,
+    max_key_length: Option<usize>,
+    key_charset: Option<String>,
+    max_value_size: Option<u64>,
+    write_quorum: Option<u64>,
+    write_retry_attempts: Option<u32>,
+    write_retry_backoff_ms: Option<u64>,
+    write_retry_max_backoff_ms: Option<u64>,
+    write_retry_jitter: Option<f64>,
+    two_phase_writes: Option<bool>,
+    erasure_coding: Option<String>,
+    erasure_min_size: Option<u64>,
+    dedup: Option<bool>,
+    compress: Option<String>,
+    encryption_key_file: Option<PathBuf>,
+    presign_key_file: Option<PathBuf>,
+    presign_volumes: Option<BTreeMap<String, String>>,
+    breaker_error_rate: Option<f64>,
+    breaker_min_requests: Option<u32>,
+    breaker_open_secs: Option<u64>,
+    max_replica_puts: Option<usize>,
+    max_replica_puts_per_volume: Option<usize>,
+    subvolumes: Option<u32>,
+    volume_groups: Option<BTreeMap<String, Vec<String>>>,
+    placement_rules: Option<BTreeMap<String, String>>,
+    task_schedules: Option<BTreeMap<String, u64>>,
+    lock_timeout_ms: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    ready_fraction: Option<f64>,
+    volume_connect_timeout: Option<u64>,
+    volume_request_timeout: Option<u64>,
+    volume_pool_max_idle: Option<usize>,
+    volume_pool_idle_timeout: Option<u64>,
+    volume_http2: Option<bool>,
+    volume_keep_alive: Option<u64>,
+    request_deadline: Option<u64>,
+    rate_limit: Option<u32>,
+    client_rate_limit: Option<u32>,
+    max_in_flight: Option<usize>,
+    cors_origins: Option<Vec<String>>,
+    cors_methods: Option<Vec<String>>,
+    cors_headers: Option<Vec<String>>,
+    cors_max_age: Option<u64>,
+    gc_grace_secs: Option<u64>,
+    auto_rebalance: Option<u32>,
+    otlp_endpoint: Option<String>,
+    access_log: Option<bool>,
+}
+
+impl FileConfig {
+    /// Reads and parses a config file.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config {}", path.display()))
+    }
+
+    /// Sets the flags of the cli that were not given on the command line to the values of the file.
+    pub(crate) fn apply(self, cli: &mut Cli, matches: &clap::ArgMatches) -> anyhow::Result<()> {
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        set(&mut cli.port, self.port, unset("port"));
+        set(
+            &mut cli.internal_addr,
+            self.internal_addr.map(Some),
+            unset("internal_addr"),
+        );
+        set(&mut cli.s3_addr, self.s3_addr.map(Some), unset("s3_addr"));
+        set(
+            &mut cli.resp_addr,
+            self.resp_addr.map(Some),
+            unset("resp_addr"),
+        );
+        set(
+            &mut cli.grpc_addr,
+            self.grpc_addr.map(Some),
+            unset("grpc_addr"),
+        );
+        set(
+            &mut cli.admin_port,
+            self.admin_port.map(Some),
+            unset("admin_port"),
+        );
+        set(
+            &mut cli.admin_localhost,
+            self.admin_localhost,
+            unset("admin_localhost"),
+        );
+        set(
+            &mut cli.tls_cert,
+            self.tls_cert.map(Some),
+            unset("tls_cert"),
+        );
+        set(&mut cli.tls_key, self.tls_key.map(Some), unset("tls_key"));
+        set(
+            &mut cli.volume_tls.volume_ca,
+            self.volume_ca.map(Some),
+            unset("volume_ca"),
+        );
+        set(
+            &mut cli.volume_tls.volume_cert,
+            self.volume_cert.map(Some),
+            unset("volume_cert"),
+        );
+        set(
+            &mut cli.volume_tls.volume_key,
+            self.volume_key.map(Some),
+            unset("volume_key"),
+        );
+        set(
+            &mut cli.auth_token,
+            self.auth_token.map(Some),
+            unset("auth_token"),
+        );
+        set(
+            &mut cli.auth_token_file,
+            self.auth_token_file.map(Some),
+            unset("auth_token_file"),
+        );
+        set(
+            &mut cli.leveldb_path,
+            self.leveldb_path.map(Some),
+            unset("leveldb_path"),
+        );
+        let db_backend = self
+            .db_backend
+            .map(|value| value_enum(&value, "db-backend"))
+            .transpose()?;
+        set(&mut cli.db_backend, db_backend, unset("db_backend"));
+        let record_encoding = self
+            .record_encoding
+            .map(|value| value_enum(&value, "record-encoding"))
+            .transpose()?;
+        set(
+            &mut cli.record_encoding,
+            record_encoding,
+            unset("record_encoding"),
+        );
+        set(
+            &mut cli.record_cache,
+            self.record_cache.map(Some),
+            unset("record_cache"),
+        );
+        set(
+            &mut cli.negative_cache,
+            self.negative_cache.map(Some),
+            unset("negative_cache"),
+        );
+        set(
+            &mut cli.negative_cache_ttl_secs,
+            self.negative_cache_ttl_secs,
+            unset("negative_cache_ttl_secs"),
+        );
+        set(
+            &mut cli.value_cache,
+            self.value_cache.map(Some),
+            unset("value_cache"),
+        );
+        set(
+            &mut cli.value_cache_max_size,
+            self.value_cache_max_size,
+            unset("value_cache_max_size"),
+        );
+        set(
+            &mut cli.value_cache_ttl_secs,
+            self.value_cache_ttl_secs,
+            unset("value_cache_ttl_secs"),
+        );
+        set(
+            &mut cli.hash_md5_checksum,
+            self.hash_md5_checksum,
+            unset("hash_md5_checksum"),
+        );
+        let checksum = self
+            .checksum
+            .map(|value| value_enum(&value, "checksum"))
+            .transpose()?;
+        set(&mut cli.checksum, checksum, unset("checksum"));
+        let checksum_algorithms = self
+            .checksum_algorithms
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|value| value.parse::<checksum::Algorithm>())
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        set(
+            &mut cli.checksum_algorithms,
+            checksum_algorithms,
+            unset("checksum_algorithms"),
+        );
+        set(&mut cli.volumes, self.volumes, unset("volumes"));
+        set(
+            &mut cli.default_proxy,
+            self.default_proxy,
+            unset("default_proxy"),
+        );
+        let redirect_status = self
+            .redirect_status
+            .map(|value| value_enum(&value.to_string(), "redirect-status"))
+            .transpose()?;
+        set(
+            &mut cli.redirect_status,
+            redirect_status,
+            unset("redirect_status"),
+        );
+        set(
+            &mut cli.redirect_cache_control,
+            self.redirect_cache_control,
+            unset("redirect_cache_control"),
+        );
+        set(
+            &mut cli.concurrent_heads,
+            self.concurrent_heads,
+            unset("concurrent_heads"),
+        );
+        set(&mut cli.replicas, self.replicas, unset("replicas"));
+        set(
+            &mut cli.max_replicas,
+            self.max_replicas.map(Some),
+            unset("max_replicas"),
+        );
+        set(
+            &mut cli.namespace_replicas,
+            self.namespace_replicas
+                .map(|replicas| replicas.into_iter().collect()),
+            unset("namespace_replicas"),
+        );
+        set(
+            &mut cli.max_key_length,
+            self.max_key_length,
+            unset("max_key_length"),
+        );
+        let key_charset = self
+            .key_charset
+            .map(|value| value_enum(&value, "key-charset"))
+            .transpose()?;
+        set(&mut cli.key_charset, key_charset, unset("key_charset"));
+        set(
+            &mut cli.max_value_size,
+            self.max_value_size.map(Some),
+            unset("max_value_size"),
+        );
+        set(
+            &mut cli.write_quorum,
+            self.write_quorum.map(Some),
+            unset("write_quorum"),
+        );
+        set(
+            &mut cli.write_retry_attempts,
+            self.write_retry_attempts,
+            unset("write_retry_attempts"),
+        );
+        set(
+            &mut cli.write_retry_backoff_ms,
+            self.write_retry_backoff_ms,
+            unset("write_retry_backoff_ms"),
+        );
+        set(
+            &mut cli.write_retry_max_backoff_ms,
+            self.write_retry_max_backoff_ms,
+            unset("write_retry_max_backoff_ms"),
+        );
+        set(
+            &mut cli.write_retry_jitter,
+            self.write_retry_jitter,
+            unset("write_retry_jitter"),
+        );
+        set(
+            &mut cli.two_phase_writes,
+            self.two_phase_writes,
+            unset("two_phase_writes"),
+        );
+        let erasure_coding = self
+            .erasure_coding
+            .map(|value| crate::parse_erasure_coding(&value).map_err(anyhow::Error::msg))
+            .transpose()?;
+        set(
+            &mut cli.erasure_coding,
+            erasure_coding.map(Some),
+            unset("erasure_coding"),
+        );
+        set(
+            &mut cli.erasure_min_size,
+            self.erasure_min_size,
+            unset("erasure_min_size"),
+        );
+        set(&mut cli.dedup, self.dedup, unset("dedup"));
+        let compress = self
+            .compress
+            .map(|value| crate::parse_compression(&value).map_err(anyhow::Error::msg))
+            .transpose()?;
+        set(&mut cli.compress, compress.map(Some), unset("compress"));
+        set(
+            &mut cli.encryption_key_file,
+            self.encryption_key_file.map(Some),
+            unset("encryption_key_file"),
+        );
+        set(
+            &mut cli.presign_key_file,
+            self.presign_key_file.map(Some),
+            unset("presign_key_file"),
+        );
+        set(
+            &mut cli.presign_volumes,
+            self.presign_volumes
+                .map(|volumes| volumes.into_iter().collect()),
+            unset("presign_volumes"),
+        );
+        set(
+            &mut cli.breaker_error_rate,
+            self.breaker_error_rate,
+            unset("breaker_error_rate"),
+        );
+        set(
+            &mut cli.breaker_min_requests,
+            self.breaker_min_requests,
+            unset("breaker_min_requests"),
+        );
+        set(
+            &mut cli.breaker_open_secs,
+            self.breaker_open_secs,
+            unset("breaker_open_secs"),
+        );
+        set(
+            &mut cli.max_replica_puts,
+            self.max_replica_puts.map(Some),
+            unset("max_replica_puts"),
+        );
+        set(
+            &mut cli.max_replica_puts_per_volume,
+            self.max_replica_puts_per_volume.map(Some),
+            unset("max_replica_puts_per_volume"),
+        );
+        set(&mut cli.subvolumes, self.subvolumes, unset("subvolumes"));
+        set(
+            &mut cli.volume_groups,
+            self.volume_groups
+                .map(|groups| groups.into_iter().collect()),
+            unset("volume_groups"),
+        );
+        set(
+            &mut cli.placement_rules,
+            self.placement_rules.map(|rules| {
+                rules
+                    .into_iter()
+                    .map(|(prefix, group)| hashring::PlacementRule { prefix, group })
+                    .collect()
+            }),
+            unset("placement_rules"),
+        );
+        set(
+            &mut cli.task_schedules,
+            self.task_schedules.map(|schedules| {
+                schedules
+                    .into_iter()
+                    .map(|(name, seconds)| (name, Duration::from_secs(seconds)))
+                    .collect()
+            }),
+            unset("task_schedules"),
+        );
+        set(
+            &mut cli.lock_timeout_ms,
+            self.lock_timeout_ms,
+            unset("lock_timeout_ms"),
+        );
+        set(
+            &mut cli.shutdown_timeout,
+            self.shutdown_timeout,
+            unset("shutdown_timeout"),
+        );
+        set(
+            &mut cli.ready_fraction,
+            self.ready_fraction,
+            unset("ready_fraction"),
+        );
+        set(
+            &mut cli.volume_connect_timeout,
+            self.volume_connect_timeout,
+            unset("volume_connect_timeout"),
+        );
+        set(
+            &mut cli.volume_request_timeout,
+            self.volume_request_timeout,
+            unset("volume_request_timeout"),
+        );
+        set(
+            &mut cli.volume_pool_max_idle,
+            self.volume_pool_max_idle.map(Some),
+            unset("volume_pool_max_idle"),
+        );
+        set(
+            &mut cli.volume_pool_idle_timeout,
+            self.volume_pool_idle_timeout,
+            unset("volume_pool_idle_timeout"),
+        );
+        set(
+            &mut cli.volume_http2,
+            self.volume_http2,
+            unset("volume_http2"),
+        );
+        set(
+            &mut cli.volume_keep_alive,
+            self.volume_keep_alive,
+            unset("volume_keep_alive"),
+        );
+        set(
+            &mut cli.request_deadline,
+            self.request_deadline,
+            unset("request_deadline"),
+        );
+        set(
+            &mut cli.rate_limit,
+            self.rate_limit.map(Some),
+            unset("rate_limit"),
+        );
+        set(
+            &mut cli.client_rate_limit,
+            self.client_rate_limit.map(Some),
+            unset("client_rate_limit"),
+        );
+        set(
+            &mut cli.max_in_flight,
+            self.max_in_flight.map(Some),
+            unset("max_in_flight"),
+        );
+        set(
+            &mut cli.cors_origins,
+            self.cors_origins,
+            unset("cors_origins"),
+        );
+        let cors_methods = self
+            .cors_methods
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|value| crate::parse_method(value).map_err(anyhow::Error::msg))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        set(&mut cli.cors_methods, cors_methods, unset("cors_methods"));
+        set(
+            &mut cli.cors_headers,
+            self.cors_headers,
+            unset("cors_headers"),
+        );
+        set(
+            &mut cli.cors_max_age,
+            self.cors_max_age,
+            unset("cors_max_age"),
+        );
+        set(
+            &mut cli.gc_grace_secs,
+            self.gc_grace_secs,
+            unset("gc_grace_secs"),
+        );
+        set(
+            &mut cli.auto_rebalance,
+            self.auto_rebalance.map(Some),
+            unset("auto_rebalance"),
+        );
+        set(
+            &mut cli.otlp_endpoint,
+            self.otlp_endpoint.map(Some),
+            unset("otlp_endpoint"),
+        );
+        set(&mut cli.access_log, self.access_log, unset("access_log"));
+        Ok(())
+    }
+}
+
+/// Sets a flag to the value of the file, unless it has no value in the file or is given on the command line.
+fn set<T>(flag: &mut T, value: Option<T>, unset: bool) {
+    if let (Some(value), true) = (value, unset) {
+        *flag = value;
+    }
+}
+
+/// Parses a value of a flag taking one of a fixed set of values, the same way the command line does.
+fn value_enum<T: clap::ValueEnum>(value: &str, name: &str) -> anyhow::Result<T> {
+    T::from_str(value, false).map_err(|e| anyhow::anyhow!("invalid {} in config: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[test]
+    fn test_apply_file_config() -> anyhow::Result<()> {
+        let file: FileConfig = toml::from_str(
+            r#"
+            port = 4000
+            leveldb-path = "/tmp/indexdb"
+            volumes = ["localhost:3001", "localhost:3002"]
+            replicas = 2
+            checksum-algorithms = ["sha256"]
+            lock-timeout-ms = 100
+
+            [volume-groups]
+            ssd = ["localhost:3006"]
+
+            [placement-rules]
+            "thumbnails/" = "ssd"
+
+            [task-schedules]
+            repair = 0
+            "#,
+        )?;
+        let matches =
+            Cli::command().try_get_matches_from(["mkv", "--port", "5000", "--replicas", "1"])?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        file.apply(&mut cli, &matches)?;
+
+        assert_eq!(cli.port, 5000);
+        assert_eq!(cli.replicas, 1);
+        assert_eq!(cli.leveldb_path.as_deref(), Some("/tmp/indexdb"));
+        assert_eq!(cli.volumes, vec!["localhost:3001", "localhost:3002"]);
+        assert_eq!(cli.checksum_algorithms, vec![checksum::Algorithm::Sha256]);
+        assert_eq!(cli.lock_timeout_ms, 100);
+        assert_eq!(cli.subvolumes, 10);
+        assert_eq!(
+            cli.volume_groups,
+            vec![("ssd".to_string(), vec!["localhost:3006".to_string()])]
+        );
+        assert_eq!(cli.placement_rules[0].group, "ssd");
+        assert_eq!(
+            cli.task_schedules,
+            vec![("repair".to_string(), Duration::ZERO)]
+        );
+
+        assert!(toml::from_str::<FileConfig>("unknown = 1").is_err());
+        let file: FileConfig = toml::from_str(r#"db-backend = "rocksdb""#)?;
+        assert!(file.apply(&mut cli, &matches).is_err());
+
+        Ok(())
+    }
+}
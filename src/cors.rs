@@ -0,0 +1,215 @@
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::IntoResponse;
+use std::{sync::Arc, time::Duration};
+
+/// Response headers browsers let the scripts of other origins read, besides the safelisted ones.
+const EXPOSED_HEADERS: &str =
+    "Content-Md5, ETag, Key-Volumes, Last-Modified, Location, X-Refcount, X-Value-Size";
+
+/// Struct representing the cross-origin requests browsers are allowed to make.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsConfig {
+    /// Origins allowed, e.g. `https://app.example.com`, or `*` for any origin.
+    pub origins: Vec<String>,
+    /// Methods allowed in preflight requests.
+    pub methods: Vec<Method>,
+    /// Request headers allowed in preflight requests, empty to allow the ones asked for.
+    pub headers: Vec<String>,
+    /// Time browsers cache the answer of a preflight request.
+    pub max_age: Duration,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: Vec::new(),
+            methods: vec![
+                Method::GET,
+                Method::HEAD,
+                Method::PUT,
+                Method::POST,
+                Method::DELETE,
+            ],
+            headers: Vec::new(),
+            max_age: Duration::from_secs(600),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Returns the value of `Access-Control-Allow-Origin` for the origin of a request, None if it isn't allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin_str = origin.to_str().ok()?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+            .then(|| origin.clone())
+    }
+
+    /// Returns the headers answering a preflight request, None if the method it asks for isn't allowed.
+    fn preflight(&self, allow_origin: HeaderValue, request: &HeaderMap) -> Option<HeaderMap> {
+        let method = request
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())?;
+        if !self.methods.contains(&method) {
+            return None;
+        }
+        let methods = self
+            .methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let allow_headers = match self.headers.is_empty() {
+            true => request
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned()
+                .unwrap_or_else(|| HeaderValue::from_static("")),
+            false => HeaderValue::from_str(&self.headers.join(", ")).ok()?,
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_str(&methods).ok()?,
+        );
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from(self.max_age.as_secs()),
+        );
+        Some(headers)
+    }
+}
+
+/// Middleware answering the CORS preflight requests of the allowed origins with 204, before the bearer
+/// tokens are checked since browsers send none, and adding the CORS headers to the responses of their
+/// other requests. Requests of other origins are served without them, so browsers don't let scripts read
+/// the responses, and their preflight requests get 403.
+pub(crate) async fn handle_cors(
+    axum::extract::State(cors): axum::extract::State<Arc<CorsConfig>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    let is_preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let allow_origin = origin.as_ref().and_then(|origin| cors.allow_origin(origin));
+
+    if is_preflight {
+        let preflight =
+            allow_origin.and_then(|allow_origin| cors.preflight(allow_origin, request.headers()));
+        let mut response = match preflight {
+            Some(headers) => (StatusCode::NO_CONTENT, headers).into_response(),
+            None => StatusCode::FORBIDDEN.into_response(),
+        };
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Origin"));
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    if origin.is_some() {
+        let headers = response.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        if let Some(allow_origin) = allow_origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_cors() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(3, 2, |config| {
+            config.cors = Some(CorsConfig {
+                origins: vec!["https://app.example.com".to_string()],
+                ..Default::default()
+            });
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("cors");
+
+        let res = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-md5")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, HEAD, PUT, POST, DELETE"
+        );
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-md5"
+        );
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        // Preflights of other origins or methods are refused
+        let res = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header(header::ORIGIN, "https://evil.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PATCH")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = client
+            .put(&url)
+            .header(header::ORIGIN, "https://app.example.com")
+            .body("value")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        let exposed = res.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS].to_str()?;
+        assert!(exposed.contains("Content-Md5") && exposed.contains("ETag"));
+
+        let res = client
+            .head(&url)
+            .header(header::ORIGIN, "https://evil.example.com")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(res.headers()[header::VARY], "Origin");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::record;
+
+/// Struct representing a deduplicated blob in the metadata store, the volumes holding it
+/// and the number of records sharing it, deleted ones not yet collected included.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Blob {
+    volumes: Vec<String>,
+    refcount: u64,
+}
+
+/// Gets the blob of a content, None if no record references it.
+fn get(leveldb: &record::LevelDb, hash: &str) -> anyhow::Result<Option<Blob>> {
+    leveldb
+        .store()
+        .get(&record::blob_key(hash))?
+        .map(|value| bincode::deserialize(&value))
+        .transpose()
+        .map_err(anyhow::Error::from)
+}
+
+/// Puts the blob of a content.
+fn put(leveldb: &record::LevelDb, hash: &str, blob: &Blob) -> anyhow::Result<()> {
+    leveldb
+        .store()
+        .put(&record::blob_key(hash), &bincode::serialize(blob)?)
+}
+
+/// Adds a reference to the blob of a content and returns the volumes holding it,
+/// None if no record references the content yet and it must be written.
+/// Callers lock the blob key, see `record::blob_key`.
+pub(crate) async fn acquire(
+    leveldb: &record::LevelDb,
+    hash: &str,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let hash = hash.to_string();
+    leveldb
+        .blocking(move |leveldb| {
+            let Some(mut blob) = get(leveldb, &hash)? else {
+                return Ok(None);
+            };
+            blob.refcount += 1;
+            put(leveldb, &hash, &blob)?;
+            Ok(Some(blob.volumes))
+        })
+        .await
+}
+
+/// Indexes the blob of a content just written to the volumes, referenced by a single record.
+pub(crate) async fn register(
+    leveldb: &record::LevelDb,
+    hash: &str,
+    volumes: &[String],
+) -> anyhow::Result<()> {
+    let hash = hash.to_string();
+    let blob = Blob {
+        volumes: volumes.to_vec(),
+        refcount: 1,
+    };
+    leveldb
+        .blocking(move |leveldb| put(leveldb, &hash, &blob))
+        .await
+}
+
+/// Returns true if other records reference the blob, so the record of a key can be collected without
+/// deleting the value. A blob missing from the index counts as shared: its value is left behind, never lost.
+pub(crate) async fn is_shared(leveldb: &record::LevelDb, hash: &str) -> anyhow::Result<bool> {
+    let hash = hash.to_string();
+    leveldb
+        .blocking(move |leveldb| Ok(get(leveldb, &hash)?.is_none_or(|blob| blob.refcount > 1)))
+        .await
+}
+
+/// Drops a reference to the blob of a content, removing it from the index with the last one.
+/// The value must be deleted from the volumes first if it was the last reference, see `is_shared`.
+pub(crate) async fn release(leveldb: &record::LevelDb, hash: &str) -> anyhow::Result<()> {
+    let hash = hash.to_string();
+    leveldb
+        .blocking(move |leveldb| match get(leveldb, &hash)? {
+            Some(blob) if blob.refcount > 1 => put(
+                leveldb,
+                &hash,
+                &Blob {
+                    refcount: blob.refcount - 1,
+                    ..blob
+                },
+            ),
+            Some(_) => leveldb.store().delete(&record::blob_key(&hash)),
+            None => Ok(()),
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refcount() -> anyhow::Result<()> {
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = record::LevelDb::with_backend(leveldb_dir.path(), Default::default())?;
+        let volumes = vec!["localhost:3001".to_string(), "localhost:3002".to_string()];
+
+        assert_eq!(acquire(&leveldb, "c0ffee").await?, None);
+        assert!(is_shared(&leveldb, "c0ffee").await?);
+        register(&leveldb, "c0ffee", &volumes).await?;
+        assert!(!is_shared(&leveldb, "c0ffee").await?);
+        assert_eq!(acquire(&leveldb, "c0ffee").await?, Some(volumes.clone()));
+        assert!(is_shared(&leveldb, "c0ffee").await?);
+
+        release(&leveldb, "c0ffee").await?;
+        assert!(!is_shared(&leveldb, "c0ffee").await?);
+        release(&leveldb, "c0ffee").await?;
+        assert_eq!(acquire(&leveldb, "c0ffee").await?, None);
+
+        // Blobs are not records
+        leveldb.for_each_record(|record| anyhow::bail!("unexpected record {}", record.key()))?;
+
+        Ok(())
+    }
+}
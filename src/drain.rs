@@ -0,0 +1,276 @@
+use anyhow::Context;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{hashring, locks, rebalance, record, server, volume_keys};
+
+/// Records moved concurrently by a drain.
+const CONCURRENCY: usize = 8;
+
+/// Enum representing the state of a drain.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DrainState {
+    Running,
+    /// Every indexed value was moved off the volume.
+    Drained,
+    /// Some values were skipped or failed to move, they are still listed by the volume key listing.
+    Incomplete,
+    /// The volume key index couldn't be read.
+    Failed,
+}
+
+/// Struct representing the progress of the drain of a volume as reported by the admin API.
+/// Keys counts the keys indexed on the volume when the drain started.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct DrainStatus {
+    pub(crate) volume: String,
+    pub(crate) state: DrainState,
+    pub(crate) keys: u64,
+    pub(crate) moved: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+    pub(crate) bytes: u64,
+    pub(crate) started_at: u64,
+    pub(crate) finished_at: Option<u64>,
+    pub(crate) last_error: Option<String>,
+}
+
+impl DrainStatus {
+    /// Returns the status of a drain of the volume starting now.
+    fn new(volume: &str) -> Self {
+        Self {
+            volume: volume.to_string(),
+            state: DrainState::Running,
+            keys: 0,
+            moved: 0,
+            skipped: 0,
+            failed: 0,
+            bytes: 0,
+            started_at: record::unix_now(),
+            finished_at: None,
+            last_error: None,
+        }
+    }
+}
+
+/// Outcome of the move of a single record off the drained volume.
+enum Outcome {
+    /// The replicas on the volume were moved, copying the number of bytes.
+    Moved(u64),
+    /// The record no longer lists the volume, e.g. it was rewritten or collected since the scan.
+    Gone,
+    Skipped,
+}
+
+/// Struct keeping the drains of the volumes, the last one of each volume, and running them in the background.
+pub(crate) struct Drains {
+    leveldb: Arc<record::LevelDb>,
+    hashring: Arc<RwLock<hashring::Ring>>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+    statuses: Mutex<BTreeMap<String, DrainStatus>>,
+}
+
+impl Drains {
+    /// Creates the drains of the volumes of the ring, moving values with the client.
+    /// Records are locked while moved, like PUT and DELETE lock them.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        hashring: Arc<RwLock<hashring::Ring>>,
+        key_locks: Arc<locks::KeyLocks>,
+        client: reqwest::Client,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            leveldb,
+            hashring,
+            key_locks,
+            client,
+            statuses: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Returns the status of the last drain of a volume, if any.
+    pub(crate) fn status(&self, volume: &str) -> Option<DrainStatus> {
+        self.statuses.lock().get(volume).cloned()
+    }
+
+    /// Starts draining a volume in the background, unless a drain of the volume is running.
+    /// Returns the status of the new drain, or None if one is already running.
+    pub(crate) fn start(self: &Arc<Self>, volume: &str) -> Option<DrainStatus> {
+        let status = DrainStatus::new(volume);
+        {
+            let mut statuses = self.statuses.lock();
+            if statuses
+                .get(volume)
+                .is_some_and(|status| status.state == DrainState::Running)
+            {
+                return None;
+            }
+            statuses.insert(volume.to_string(), status.clone());
+        }
+
+        let drains = self.clone();
+        let volume = volume.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = drains.run(&volume).await {
+                error!("drain: failed to drain {}: {:#}", volume, e);
+                drains.update(&volume, |status| {
+                    status.state = DrainState::Failed;
+                    status.last_error = Some(format!("{:#}", e));
+                });
+            }
+        });
+        Some(status)
+    }
+
+    /// Applies a change to the status of the drain of a volume.
+    fn update(&self, volume: &str, change: impl FnOnce(&mut DrainStatus)) {
+        if let Some(status) = self.statuses.lock().get_mut(volume) {
+            change(status);
+        }
+    }
+
+    /// Moves the values of every key indexed on the volume, updating its status as records are handled.
+    /// Fails only if the index cannot be read, records failing to move are counted and logged.
+    async fn run(&self, volume: &str) -> anyhow::Result<()> {
+        let keys = {
+            let (leveldb, volume) = (self.leveldb.clone(), volume.to_string());
+            tokio::task::spawn_blocking(move || volume_keys::keys(leveldb.store(), &volume))
+                .await??
+        };
+        info!("drain: draining {} keys of {}", keys.len(), volume);
+        self.update(volume, |status| status.keys = keys.len() as u64);
+
+        let mut outcomes = futures::stream::iter(keys)
+            .map(|key| async move {
+                let outcome = self.drain_record(volume, &key).await;
+                (key, outcome)
+            })
+            .buffer_unordered(CONCURRENCY);
+        while let Some((key, outcome)) = outcomes.next().await {
+            self.update(volume, |status| match outcome {
+                Ok(Outcome::Moved(bytes)) => {
+                    status.moved += 1;
+                    status.bytes += bytes;
+                }
+                Ok(Outcome::Gone) => status.moved += 1,
+                Ok(Outcome::Skipped) => status.skipped += 1,
+                Err(e) => {
+                    error!("drain: failed to move key {} off {}: {:#}", key, volume, e);
+                    status.failed += 1;
+                    status.last_error = Some(format!("{}: {:#}", key, e));
+                }
+            });
+        }
+
+        self.update(volume, |status| {
+            status.state = if status.skipped + status.failed == 0 {
+                DrainState::Drained
+            } else {
+                DrainState::Incomplete
+            };
+            status.finished_at = Some(record::unix_now());
+            info!(
+                "drain: {} {:?} keys: {} moved: {} skipped: {} failed: {} bytes: {}",
+                volume,
+                status.state,
+                status.keys,
+                status.moved,
+                status.skipped,
+                status.failed,
+                status.bytes
+            );
+        });
+        Ok(())
+    }
+
+    /// Copies the replicas of a record on the drained volume to volumes of the ring that don't hold it yet,
+    /// then writes the record with its new volumes and deletes the old replicas.
+    async fn drain_record(&self, volume: &str, key: &str) -> anyhow::Result<Outcome> {
+        let Some(_guard) = self.key_locks.lock(key).await else {
+            warn!("drain: key: {} stayed locked, skipping", key);
+            return Ok(Outcome::Skipped);
+        };
+        let record = match self.leveldb.get_record(key).await? {
+            Some(record) if record.deleted() != record::Deleted::Hard => record,
+            _ => return Ok(Outcome::Gone),
+        };
+        let drained: Vec<String> = record
+            .read_volumes()
+            .iter()
+            .filter(|read_volume| on_volume(read_volume, volume))
+            .cloned()
+            .collect();
+        // The parts of multipart values and the shards of erasure coded values keep their own volumes,
+        // deduplicated values the volumes of their blob
+        if !record.parts().is_empty() || record.erasure().is_some() || record.blob().is_some() {
+            warn!("drain: key: {} isn't a replicated value, skipping", key);
+            return Ok(Outcome::Skipped);
+        }
+        if drained.is_empty() {
+            return Ok(Outcome::Gone);
+        }
+
+        let mut targets = self.targets(&record, volume).into_iter();
+        let remote_path = record::get_remote_path(key);
+        let sources: Vec<String> = record
+            .read_volumes()
+            .iter()
+            .filter(|read_volume| !drained.contains(read_volume))
+            .chain(drained.iter())
+            .cloned()
+            .collect();
+        let mut read_volumes = record.read_volumes().to_vec();
+        let mut bytes = 0;
+        for old in drained.iter() {
+            let new = targets.next().with_context(|| {
+                format!("no volume of the ring left for the replica on {}", old)
+            })?;
+            bytes += rebalance::copy_value(&self.client, &sources, &new, &remote_path)
+                .await
+                .with_context(|| format!("failed to copy value to {}", new))?;
+            debug!("drain: key: {} copied from {} to {}", key, old, new);
+            if let Some(position) = read_volumes.iter().position(|v| v == old) {
+                read_volumes[position] = new;
+            }
+        }
+        self.leveldb
+            .put_record(key, record.with_read_volumes(read_volumes))
+            .await?;
+
+        // The record no longer points at the old replicas, a failed delete only leaks space
+        for old in drained {
+            let remote_url = record::volume_url(&old, &remote_path);
+            if let Err(e) = server::remote_delete(&self.client, &remote_url).await {
+                error!("drain: {}", e);
+            }
+        }
+        Ok(Outcome::Moved(bytes))
+    }
+
+    /// Returns the volumes a record can move to, the ones the ring places it on first, then the other
+    /// volumes of its ring, leaving out the drained volume and the volume servers already holding it.
+    fn targets(&self, record: &record::Record, volume: &str) -> Vec<String> {
+        let hashring = self.hashring.read();
+        let placement = record.placement().filter(|group| hashring.has_group(group));
+        let ring_size = hashring.all_volumes().len();
+        let mut targets =
+            hashring.get_volume_with_replicas(record.key(), placement, Some(ring_size));
+        targets.retain(|target| {
+            !on_volume(target, volume)
+                && record.read_volumes().iter().all(|read_volume| {
+                    hashring::volume_server(read_volume) != hashring::volume_server(target)
+                })
+        });
+        targets
+    }
+}
+
+/// Returns true if a replica volume is on the volume, with or without its subvolume.
+fn on_volume(read_volume: &str, volume: &str) -> bool {
+    read_volume == volume || hashring::volume_server(read_volume) == volume
+}
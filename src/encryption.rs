@@ -0,0 +1,256 @@
+use aes_gcm::{
+    aead::{generic_array::GenericArray, rand_core::RngCore, stream, Aead, AeadCore, OsRng},
+    Aes256Gcm, Key, KeyInit,
+};
+use anyhow::Context;
+use futures::{Stream, StreamExt};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{checksum, record};
+
+/// Size of the segments a value is encrypted in, each with its own authentication tag,
+/// so values are encrypted and decrypted as they stream.
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Size of the authentication tag of every segment and of wrapped data keys.
+const TAG_SIZE: usize = 16;
+
+/// Size of the nonce of a value. The STREAM construction fills the other 5 bytes of the
+/// AES-GCM nonce with the segment counter and the flag of the last segment.
+const NONCE_SIZE: usize = 7;
+
+/// Size of the AES-GCM nonce a data key is wrapped with, stored before the wrapped key.
+const WRAP_NONCE_SIZE: usize = 12;
+
+/// Size of an AES-256 key.
+const KEY_SIZE: usize = 32;
+
+/// Returns the size of the encryption of a value of the size, the value and a tag per segment.
+pub(crate) fn sealed_size(size: u64) -> u64 {
+    let segments = size.div_ceil(SEGMENT_SIZE as u64).max(1);
+    size + segments * TAG_SIZE as u64
+}
+
+/// Struct representing the master key wrapping the data key of every encrypted value.
+#[derive(Clone)]
+pub struct MasterKey {
+    key: Key<Aes256Gcm>,
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// Reads a master key file, 32 bytes raw or hex encoded, e.g. written by `openssl rand -hex 32`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("failed to read encryption key file {}", path.display()))?;
+        Self::from_bytes(&content)
+            .with_context(|| format!("invalid encryption key file {}", path.display()))
+    }
+
+    /// Returns the master key of the content of a key file.
+    fn from_bytes(content: &[u8]) -> anyhow::Result<Self> {
+        let key = match std::str::from_utf8(content) {
+            Ok(hex) if hex.trim().len() == KEY_SIZE * 2 => {
+                checksum::decode_hex(hex.trim()).context("expected 64 hex characters")?
+            }
+            _ => content.to_vec(),
+        };
+        if key.len() != KEY_SIZE {
+            anyhow::bail!("expected a {} bytes key, got {} bytes", KEY_SIZE, key.len());
+        }
+        Ok(Self {
+            key: *Key::<Aes256Gcm>::from_slice(&key),
+        })
+    }
+
+    /// Generates the data key of a new value, returned with the encryption of its record:
+    /// the nonce of the value and the data key wrapped by the master key.
+    pub(crate) fn new_data_key(&self) -> anyhow::Result<(DataKey, record::Encryption)> {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        let wrap_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped = Aes256Gcm::new(&self.key)
+            .encrypt(&wrap_nonce, key.as_slice())
+            .map_err(|_| anyhow::anyhow!("failed to wrap the data key"))?;
+        let encryption = record::Encryption {
+            nonce: nonce.to_vec(),
+            wrapped_key: [wrap_nonce.as_slice(), &wrapped].concat(),
+        };
+        Ok((DataKey { key, nonce }, encryption))
+    }
+
+    /// Unwraps the data key of the encryption of a record.
+    /// Fails if the record was encrypted with another master key.
+    pub(crate) fn open(&self, encryption: &record::Encryption) -> anyhow::Result<DataKey> {
+        if encryption.wrapped_key.len() != WRAP_NONCE_SIZE + KEY_SIZE + TAG_SIZE
+            || encryption.nonce.len() != NONCE_SIZE
+        {
+            anyhow::bail!("invalid encryption in the record");
+        }
+        let (wrap_nonce, wrapped) = encryption.wrapped_key.split_at(WRAP_NONCE_SIZE);
+        let key = Aes256Gcm::new(&self.key)
+            .decrypt(GenericArray::from_slice(wrap_nonce), wrapped)
+            .map_err(|_| anyhow::anyhow!("failed to unwrap the data key, wrong master key"))?;
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&encryption.nonce);
+        Ok(DataKey {
+            key: *Key::<Aes256Gcm>::from_slice(&key),
+            nonce,
+        })
+    }
+}
+
+/// Struct representing the key and nonce a single value is encrypted with.
+pub(crate) struct DataKey {
+    key: Key<Aes256Gcm>,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl DataKey {
+    /// Encrypts the bytes of the reader into the writer, segment by segment.
+    /// Returns the size of the encrypted value, see `sealed_size`.
+    pub(crate) async fn encrypt(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> anyhow::Result<u64> {
+        let mut encryptor = stream::EncryptorBE32::from_aead(
+            Aes256Gcm::new(&self.key),
+            GenericArray::from_slice(&self.nonce),
+        );
+        let failed = |_| anyhow::anyhow!("failed to encrypt a segment");
+        let mut size = 0;
+        let mut segment = read_segment(&mut reader).await?;
+        loop {
+            // The last segment is sealed differently, so a truncated value fails to decrypt
+            let next = read_segment(&mut reader).await?;
+            if next.is_empty() {
+                let sealed = encryptor.encrypt_last(segment.as_slice()).map_err(failed)?;
+                writer.write_all(&sealed).await?;
+                size += sealed.len() as u64;
+                break;
+            }
+            let sealed = encryptor.encrypt_next(segment.as_slice()).map_err(failed)?;
+            writer.write_all(&sealed).await?;
+            size += sealed.len() as u64;
+            segment = next;
+        }
+        writer.flush().await?;
+        Ok(size)
+    }
+
+    /// Decrypts a stream of the encrypted value as it arrives. A segment failing its authentication
+    /// tag, or a value cut short, ends the stream with an `InvalidData` error.
+    pub(crate) fn decrypt<S>(
+        self,
+        stored: S,
+    ) -> impl Stream<Item = std::io::Result<bytes::Bytes>> + Send
+    where
+        S: Stream<Item = std::io::Result<bytes::Bytes>> + Send + Unpin,
+    {
+        let decryptor = stream::DecryptorBE32::from_aead(
+            Aes256Gcm::new(&self.key),
+            GenericArray::from_slice(&self.nonce),
+        );
+        let failed = |_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "failed to decrypt a segment",
+            )
+        };
+        let sealed_segment = SEGMENT_SIZE + TAG_SIZE;
+        futures::stream::try_unfold(
+            (stored, bytes::BytesMut::new(), Some(decryptor)),
+            move |(mut stored, mut buffer, decryptor)| async move {
+                let Some(mut decryptor) = decryptor else {
+                    return Ok(None);
+                };
+                loop {
+                    // A full segment is only the last one if no byte follows it
+                    if buffer.len() > sealed_segment {
+                        let segment = buffer.split_to(sealed_segment);
+                        let value = decryptor.decrypt_next(segment.as_ref()).map_err(failed)?;
+                        return Ok(Some((value.into(), (stored, buffer, Some(decryptor)))));
+                    }
+                    match stored.next().await {
+                        Some(chunk) => buffer.extend_from_slice(&chunk?),
+                        None => {
+                            let value = decryptor.decrypt_last(buffer.as_ref()).map_err(failed)?;
+                            return Ok(Some((value.into(), (stored, buffer, None))));
+                        }
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// Reads the next segment of a value, shorter than a segment only at the end of the value.
+async fn read_segment(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+    let mut segment = Vec::with_capacity(SEGMENT_SIZE);
+    reader
+        .take(SEGMENT_SIZE as u64)
+        .read_to_end(&mut segment)
+        .await?;
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encrypt_decrypt() -> anyhow::Result<()> {
+        let master_key = MasterKey::from_bytes(&[7; KEY_SIZE])?;
+        assert!(MasterKey::from_bytes(b"short").is_err());
+        let hex = format!("{}\n", checksum::encode_hex(&[7; KEY_SIZE]));
+        assert_eq!(MasterKey::from_bytes(hex.as_bytes())?.key, master_key.key);
+
+        for size in [1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 5] {
+            let value: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let (data_key, encryption) = master_key.new_data_key()?;
+            let mut sealed = Vec::new();
+            let sealed_len = data_key.encrypt(value.as_slice(), &mut sealed).await?;
+            assert_eq!(sealed_len, sealed_size(size as u64));
+            assert_eq!(sealed.len() as u64, sealed_len);
+
+            // Decrypted whatever the chunks the volume sends it in
+            let chunks = sealed
+                .chunks(1000)
+                .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>();
+            let data_key = master_key.open(&encryption)?;
+            let decrypted: Vec<bytes::Bytes> = data_key
+                .decrypt(futures::stream::iter(chunks))
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<_, _>>()?;
+            assert_eq!(decrypted.concat(), value);
+
+            // Truncated values don't decrypt
+            let truncated = bytes::Bytes::copy_from_slice(&sealed[..sealed.len() - TAG_SIZE - 1]);
+            let data_key = master_key.open(&encryption)?;
+            let results: Vec<_> = data_key
+                .decrypt(futures::stream::iter([Ok(truncated)]))
+                .collect()
+                .await;
+            assert!(results.iter().any(Result::is_err));
+        }
+
+        // Another master key can't unwrap the data key
+        let (_, encryption) = master_key.new_data_key()?;
+        let other = MasterKey::from_bytes(&[8; KEY_SIZE])?;
+        assert!(other.open(&encryption).is_err());
+
+        Ok(())
+    }
+}
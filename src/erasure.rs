@@ -0,0 +1,190 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::record;
+
+/// Default smallest value erasure coded, smaller values are replicated.
+pub const DEFAULT_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Size of the stripe of every shard encoded at a time.
+const STRIPE_SIZE: u64 = 64 * 1024;
+
+/// Struct representing the Reed-Solomon layout of erasure coded values, e.g. 4+2 splits a value
+/// into 4 data shards and 2 parity shards on 6 volumes, surviving the loss of any 2 of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureConfig {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    /// Values smaller than this are replicated instead.
+    pub min_size: u64,
+}
+
+impl ErasureConfig {
+    /// Returns the number of shards of a value, each on its own volume.
+    pub fn shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// Returns true if a value of the size is erasure coded.
+    pub(crate) fn applies_to(&self, size: u64) -> bool {
+        size >= self.min_size
+    }
+}
+
+/// Returns the size of every shard of a value, the size of a data shard.
+pub(crate) fn shard_size(size: u64, data_shards: usize) -> u64 {
+    size.div_ceil(data_shards as u64).max(1)
+}
+
+/// Splits a value of the given size into data shards of equal size, the last one padded with zeros,
+/// and computes the parity shards, written to the data shards followed by the parity shards.
+/// The parity of every byte only depends on the bytes at the same offset of the data shards, so the
+/// shards are encoded a stripe at a time and memory use doesn't depend on the size of the value.
+/// Returns the MD5 of every shard.
+pub(crate) fn encode<W: Write>(
+    value: &mut (impl Read + Seek),
+    size: u64,
+    data_shards: usize,
+    parity_shards: usize,
+    shards: &mut [W],
+) -> anyhow::Result<Vec<String>> {
+    let reed_solomon = ReedSolomon::new(data_shards, parity_shards)?;
+    let shard_size = shard_size(size, data_shards);
+    let mut stripes = vec![Vec::new(); data_shards + parity_shards];
+    let mut hashes = vec![md5::Context::new(); data_shards + parity_shards];
+    let mut offset = 0;
+    while offset < shard_size {
+        let stripe_size = STRIPE_SIZE.min(shard_size - offset);
+        for (index, stripe) in stripes.iter_mut().enumerate() {
+            stripe.clear();
+            stripe.resize(stripe_size as usize, 0);
+            let start = index as u64 * shard_size + offset;
+            if index < data_shards && start < size {
+                let len = stripe_size.min(size - start) as usize;
+                value.seek(SeekFrom::Start(start))?;
+                value.read_exact(&mut stripe[..len])?;
+            }
+        }
+        reed_solomon.encode(&mut stripes)?;
+        for ((stripe, shard), hash) in stripes.iter().zip(shards.iter_mut()).zip(&mut hashes) {
+            shard.write_all(stripe)?;
+            hash.consume(stripe);
+        }
+        offset += stripe_size;
+    }
+    Ok(hashes
+        .into_iter()
+        .map(|hash| format!("{:x}", hash.compute()))
+        .collect())
+}
+
+/// Rebuilds a value of the given size from its shards, None for the missing ones.
+/// Missing data shards are reconstructed from the parity shards, which needs as many shards as data shards.
+pub(crate) fn decode(
+    mut shards: Vec<Option<Vec<u8>>>,
+    data_shards: usize,
+    size: u64,
+) -> anyhow::Result<Vec<u8>> {
+    if shards[..data_shards].iter().any(Option::is_none) {
+        let parity_shards = shards.len() - data_shards;
+        ReedSolomon::new(data_shards, parity_shards)?.reconstruct_data(&mut shards)?;
+    }
+    let mut value: Vec<u8> = shards
+        .into_iter()
+        .take(data_shards)
+        .flat_map(Option::unwrap_or_default)
+        .collect();
+    value.truncate(size as usize);
+    Ok(value)
+}
+
+/// Returns the shards of an erasure coded value to store in its record,
+/// with the MD5 of each shard so corrupted shards are read as missing.
+pub(crate) fn shards_of(hashes: Vec<String>, volumes: &[String]) -> Vec<record::Shard> {
+    hashes
+        .into_iter()
+        .zip(volumes)
+        .map(|(hash, volume)| record::Shard {
+            hash,
+            volume: volume.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+
+    fn encode_value(
+        value: &[u8],
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut shards = vec![Vec::new(); data_shards + parity_shards];
+        let hashes = encode(
+            &mut std::io::Cursor::new(value),
+            value.len() as u64,
+            data_shards,
+            parity_shards,
+            &mut shards,
+        )?;
+        for (shard, hash) in shards.iter().zip(hashes) {
+            assert_eq!(checksum::md5_hex(shard), hash);
+        }
+        Ok(shards)
+    }
+
+    #[test]
+    fn test_encode_decode() -> anyhow::Result<()> {
+        let value = b"an archival object split into shards".to_vec();
+        let shards = encode_value(&value, 4, 2)?;
+        assert_eq!(shards.len(), 6);
+        assert!(shards.iter().all(|shard| shard.len() == 9));
+        assert_eq!(&shards[0], b"an archiv");
+
+        let all = shards.iter().cloned().map(Some).collect();
+        assert_eq!(decode(all, 4, value.len() as u64)?, value);
+
+        // Any 2 of the 6 shards can be lost
+        let mut missing: Vec<_> = shards.iter().cloned().map(Some).collect();
+        missing[1] = None;
+        missing[3] = None;
+        assert_eq!(decode(missing, 4, value.len() as u64)?, value);
+
+        let mut lost: Vec<_> = shards.into_iter().map(Some).collect();
+        lost[0] = None;
+        lost[4] = None;
+        lost[5] = None;
+        assert!(decode(lost, 4, value.len() as u64).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_stripes() -> anyhow::Result<()> {
+        // Shards of several stripes, the last data shard padded
+        let value: Vec<u8> = (0..3 * STRIPE_SIZE * 3 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let shards = encode_value(&value, 3, 2)?;
+        let shard_size = shard_size(value.len() as u64, 3) as usize;
+        assert!(shards.iter().all(|shard| shard.len() == shard_size));
+        assert_eq!(&shards[1], &value[shard_size..2 * shard_size]);
+
+        // The parity is the one of the whole shards
+        let mut whole = shards.clone();
+        for parity in &mut whole[3..] {
+            parity.fill(0);
+        }
+        ReedSolomon::new(3, 2)?.encode(&mut whole)?;
+        assert_eq!(whole, shards);
+
+        let mut missing: Vec<_> = shards.into_iter().map(Some).collect();
+        missing[0] = None;
+        missing[2] = None;
+        assert_eq!(decode(missing, 3, value.len() as u64)?, value);
+
+        Ok(())
+    }
+}
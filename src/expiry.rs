@@ -0,0 +1,210 @@
+use futures::StreamExt;
+use log::{debug, error, info};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+use crate::{dedup, locks, record, server, tasks};
+
+/// Name of the expiry task in the scheduler.
+pub(crate) const TASK_NAME: &str = "expiry";
+
+/// Default interval between two expiry sweeps.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(300);
+
+/// Records purged concurrently by an expiry sweep.
+const CONCURRENCY: usize = 8;
+
+/// Struct counting the records handled by an expiry sweep.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct ExpiryStats {
+    pub(crate) purged: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+}
+
+/// Outcome of the expiry of a single record.
+enum Outcome {
+    Purged,
+    Skipped,
+}
+
+/// Struct deleting the values of the expired records from their volumes
+/// and purging the records from the leveldb.
+pub(crate) struct Expiry {
+    leveldb: Arc<record::LevelDb>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+}
+
+impl Expiry {
+    /// Creates a new expiry of the records of the leveldb.
+    /// Records are locked while purged, like PUT and DELETE lock them.
+    pub(crate) fn new(leveldb: Arc<record::LevelDb>, key_locks: Arc<locks::KeyLocks>) -> Self {
+        Self {
+            leveldb,
+            key_locks,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses the client to delete the expired values from the volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Scans every record and purges the expired ones.
+    /// Fails only if the leveldb cannot be scanned, records failing to purge are kept for the next sweep.
+    pub(crate) async fn run(&self) -> anyhow::Result<ExpiryStats> {
+        let now = record::unix_now();
+        let mut stats = ExpiryStats::default();
+        let records = self
+            .leveldb
+            .blocking(move |leveldb| {
+                let mut records = Vec::new();
+                leveldb.for_each_record(|record| {
+                    if record.deleted() == record::Deleted::No && record.is_expired(now) {
+                        records.push(record);
+                    }
+                    Ok(())
+                })?;
+                Ok(records)
+            })
+            .await?;
+
+        let mut outcomes = futures::stream::iter(records)
+            .map(|record| async move {
+                let key = record.key().to_string();
+                (key, self.purge_record(record, now).await)
+            })
+            .buffer_unordered(CONCURRENCY);
+        while let Some((key, outcome)) = outcomes.next().await {
+            match outcome {
+                Ok(Outcome::Purged) => stats.purged += 1,
+                Ok(Outcome::Skipped) => stats.skipped += 1,
+                Err(e) => {
+                    error!("expiry: failed to purge key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "expiry: purged: {} skipped: {} failed: {}",
+            stats.purged, stats.skipped, stats.failed
+        );
+        Ok(stats)
+    }
+
+    /// Deletes the value of an expired record from its volumes and purges the record,
+    /// unless the record is locked or was rewritten since it was scanned.
+    async fn purge_record(&self, record: record::Record, now: u64) -> anyhow::Result<Outcome> {
+        let key = record.key().to_string();
+        let Some(_guard) = self.key_locks.try_lock(&key) else {
+            debug!("expiry: key: {} locked, skipping", key);
+            return Ok(Outcome::Skipped);
+        };
+        match self.leveldb.get_record(&key).await? {
+            Some(current) if server::same_write(&current, &record) && current.is_expired(now) => {}
+            _ => {
+                debug!("expiry: key: {} changed since the scan, skipping", key);
+                return Ok(Outcome::Skipped);
+            }
+        }
+
+        let mut remote_urls = record.remote_urls(&key);
+        // A deduplicated value is only deleted with the last record referencing it
+        let _blob_guard = match record.blob() {
+            Some(hash) => {
+                let Some(guard) = self.key_locks.try_lock(&record::blob_key(hash)) else {
+                    debug!("expiry: key: {} blob {} locked, skipping", key, hash);
+                    return Ok(Outcome::Skipped);
+                };
+                if dedup::is_shared(&self.leveldb, hash).await? {
+                    remote_urls.clear();
+                }
+                Some(guard)
+            }
+            None => None,
+        };
+        // The record is kept until every blob is gone, so a failed delete is retried next sweep
+        let deletes = remote_urls
+            .iter()
+            .map(|remote_url| server::remote_delete(&self.client, remote_url));
+        for result in futures::future::join_all(deletes).await {
+            result?;
+        }
+
+        self.leveldb.delete_record(&key).await?;
+        if let Some(hash) = record.blob() {
+            dedup::release(&self.leveldb, hash).await?;
+        }
+        debug!("expiry: key: {} purged", key);
+        Ok(Outcome::Purged)
+    }
+}
+
+/// Returns the scheduler task running an expiry sweep, failing if records failed to purge.
+pub(crate) fn task(expiry: Arc<Expiry>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let expiry = expiry.clone();
+        Box::pin(async move {
+            let stats = expiry.run().await?;
+            if stats.failed > 0 {
+                anyhow::bail!("{} records failed to purge", stats.failed);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_expiry() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 2).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let volumes = cluster.volume_addrs().to_vec();
+
+        for (key, expires_at) in [("expired", Some(1)), ("live", None)] {
+            for volume in volumes.iter() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                client.put(remote_url).body(key).send().await?;
+            }
+            let record = record::Record::new(record::Deleted::No, String::new(), volumes.clone())
+                .with_expires_at(expires_at);
+            leveldb.put_record(key, record).await?;
+        }
+
+        let expiry = Expiry::new(
+            leveldb.clone(),
+            locks::KeyLocks::new(Duration::from_secs(1)),
+        );
+        let stats = expiry.run().await?;
+        assert_eq!(
+            stats,
+            ExpiryStats {
+                purged: 1,
+                skipped: 0,
+                failed: 0
+            }
+        );
+        assert!(leveldb.get_record("expired").await?.is_none());
+        assert!(leveldb.get_record("live").await?.is_some());
+        assert_eq!(cluster.volume(0).len(), 1);
+        assert_eq!(cluster.volume(1).len(), 1);
+
+        let stats = expiry.run().await?;
+        assert_eq!(stats.purged, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use parking_lot::{Mutex, RwLock};
+use std::{collections::HashMap, sync::Arc, sync::LazyLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{breaker, metrics};
+
+/// Struct representing the bounds of the PUTs of values to the volumes in flight at once, None for no bound.
+/// Every replica, part and shard written is a PUT, so a burst of large PUTs opens a connection per replica.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FanoutConfig {
+    /// PUTs to all the volumes together.
+    pub max_puts: Option<usize>,
+    /// PUTs to each volume server.
+    pub max_puts_per_volume: Option<usize>,
+}
+
+/// Struct representing the permits a PUT to a volume holds until it completes.
+pub(crate) struct Permits {
+    _volume: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+/// Struct bounding the PUTs to the volumes in flight, keyed like the volumes of the ring.
+/// PUTs over a bound wait for a PUT to complete instead of failing.
+pub(crate) struct Fanout {
+    config: RwLock<FanoutConfig>,
+    global: RwLock<Option<Arc<Semaphore>>>,
+    volumes: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Process wide bounds, shared by the routes, background tasks and maintenance commands.
+pub(crate) static FANOUT: LazyLock<Fanout> = LazyLock::new(|| Fanout::new(FanoutConfig::default()));
+
+impl Fanout {
+    fn new(config: FanoutConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            global: RwLock::new(config.max_puts.map(|max| Arc::new(Semaphore::new(max)))),
+            volumes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the bounds, PUTs in flight keep the permits of the previous ones.
+    pub(crate) fn configure(&self, config: FanoutConfig) {
+        *self.config.write() = config;
+        *self.global.write() = config.max_puts.map(|max| Arc::new(Semaphore::new(max)));
+        self.volumes.lock().clear();
+    }
+
+    /// Waits for a PUT to the volume of the url to be allowed, first by the bound of the volume then
+    /// by the global one, so PUTs waiting on a busy volume don't hold the slots of the other volumes.
+    #[tracing::instrument(name = "fanout_wait", skip_all)]
+    pub(crate) async fn acquire(&self, remote_url: &str) -> Permits {
+        let volume = self.config.read().max_puts_per_volume.map(|max| {
+            self.volumes
+                .lock()
+                .entry(breaker::volume_of(remote_url).to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        });
+        let global = self.global.read().clone();
+
+        let mut waited = false;
+        let volume = match volume {
+            Some(volume) => Some(acquire_owned(volume, &mut waited).await),
+            None => None,
+        };
+        let global = match global {
+            Some(global) => Some(acquire_owned(global, &mut waited).await),
+            None => None,
+        };
+        if waited {
+            metrics::METRICS.fanout_waits.inc();
+        }
+        Permits {
+            _volume: volume,
+            _global: global,
+        }
+    }
+}
+
+/// Acquires a permit of the semaphore, setting waited if none was free.
+async fn acquire_owned(semaphore: Arc<Semaphore>, waited: &mut bool) -> OwnedSemaphorePermit {
+    match semaphore.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            *waited = true;
+            semaphore
+                .acquire_owned()
+                .await
+                .expect("fan-out semaphores are never closed")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let fanout = Fanout::new(FanoutConfig {
+            max_puts: Some(2),
+            max_puts_per_volume: Some(1),
+        });
+
+        let first = fanout.acquire("http://vol1:3001/sv01/a").await;
+        // The volume is busy, its next PUT waits
+        assert!(fanout
+            .acquire("http://vol1:3001/sv02/b")
+            .now_or_never()
+            .is_none());
+        let second = fanout.acquire("http://vol2:3001/sv01/a").await;
+        // Every global slot is taken, PUTs to idle volumes wait too
+        assert!(fanout
+            .acquire("http://vol3:3001/sv01/a")
+            .now_or_never()
+            .is_none());
+
+        drop(first);
+        let third = fanout.acquire("http://vol3:3001/sv01/a").now_or_never();
+        assert!(third.is_some());
+        drop(second);
+        assert!(fanout
+            .acquire("http://vol1:3001/sv02/b")
+            .now_or_never()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unbounded() {
+        let fanout = Fanout::new(FanoutConfig::default());
+        let permits: Vec<Permits> =
+            futures::future::join_all((0..100).map(|_| fanout.acquire("http://vol1:3001/sv01/a")))
+                .await;
+        assert_eq!(permits.len(), 100);
+    }
+}
@@ -0,0 +1,463 @@
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+use crate::{checksum, compress, encryption, erasure, locks, rebalance, record, server, tasks};
+
+/// Name of the fsck task in the scheduler.
+pub(crate) const TASK_NAME: &str = "fsck";
+
+/// Default interval of the fsck task. Zero registers it paused, it reads every replica of every
+/// value so it only runs when triggered or given a schedule.
+pub(crate) const INTERVAL: Duration = Duration::ZERO;
+
+/// Default number of records checked concurrently.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Records checked between two progress logs.
+const PROGRESS_EVERY: u64 = 1000;
+
+/// Struct counting the records and replicas handled by a fsck scan.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct FsckStats {
+    /// Records checked, every replica of every blob of their value read.
+    pub(crate) checked: u64,
+    pub(crate) healthy: u64,
+    /// Replicas holding a value with another MD5 or size than their record.
+    pub(crate) corrupted: u64,
+    /// Replicas missing their value.
+    pub(crate) missing: u64,
+    /// Corrupted or missing replicas copied again from a good one.
+    pub(crate) repaired: u64,
+    /// Records with a blob on no good replica.
+    pub(crate) lost: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+}
+
+impl FsckStats {
+    /// Returns the corrupted or missing replicas left as they were found.
+    pub(crate) fn unrepaired(&self) -> u64 {
+        (self.corrupted + self.missing).saturating_sub(self.repaired)
+    }
+}
+
+/// State of a replica of a blob.
+#[derive(Debug, PartialEq, Eq)]
+enum Replica {
+    Good,
+    Corrupted,
+    Missing,
+}
+
+/// Struct representing a blob of a value, the whole value, a part of a multipart value or a shard.
+struct Blob {
+    remote_path: String,
+    hash: String,
+    algorithm: checksum::Algorithm,
+    size: u64,
+    volumes: Vec<String>,
+    /// True if the blob is compressed, the hash and size are the ones of the decompressed value.
+    encoded: bool,
+    /// Encryption of the blob, the hash and size are the ones of the decrypted value.
+    encryption: Option<record::Encryption>,
+}
+
+/// Struct counting the replicas of a single record.
+#[derive(Default)]
+struct Outcome {
+    corrupted: u64,
+    missing: u64,
+    repaired: u64,
+    lost: bool,
+    skipped: bool,
+}
+
+/// Struct reading every replica of the live records, recomputing the MD5 of their values and
+/// reporting the corrupted or missing ones. With repair, they are copied again from a good replica.
+pub(crate) struct Fsck {
+    leveldb: Arc<record::LevelDb>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+    concurrency: usize,
+    repair: bool,
+    encryption: Option<encryption::MasterKey>,
+}
+
+impl Fsck {
+    /// Creates a new fsck of the records of the leveldb, reporting only.
+    /// Records are locked while repaired, like PUT and DELETE lock them.
+    pub(crate) fn new(leveldb: Arc<record::LevelDb>, key_locks: Arc<locks::KeyLocks>) -> Self {
+        Self {
+            leveldb,
+            key_locks,
+            client: reqwest::Client::new(),
+            concurrency: DEFAULT_CONCURRENCY,
+            repair: false,
+            encryption: None,
+        }
+    }
+
+    /// Uses the client to read and copy the replicas, e.g. one configured for HTTPS volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Checks the number of records concurrently.
+    pub(crate) fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Decrypts encrypted values with the master key to check them, without it they fail to check.
+    pub(crate) fn with_encryption(mut self, encryption: Option<encryption::MasterKey>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Copies the value of a good replica over the corrupted and missing ones.
+    pub(crate) fn with_repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Scans every live record and checks the replicas of its value, logging the progress.
+    /// Fails only if the leveldb cannot be scanned, records failing to check are counted and logged.
+    pub(crate) async fn run(&self) -> anyhow::Result<FsckStats> {
+        let mut stats = FsckStats::default();
+        let records = self
+            .leveldb
+            .blocking(|leveldb| {
+                let mut records = Vec::new();
+                leveldb.for_each_record(|record| {
+                    if record.deleted() == record::Deleted::No {
+                        records.push(record);
+                    }
+                    Ok(())
+                })?;
+                Ok(records)
+            })
+            .await?;
+        let total = records.len();
+        info!("fsck: checking {} records", total);
+
+        let mut outcomes = futures::stream::iter(records)
+            .map(|record| async move {
+                let key = record.key().to_string();
+                (key, self.check_record(record).await)
+            })
+            .buffer_unordered(self.concurrency);
+        while let Some((key, outcome)) = outcomes.next().await {
+            stats.checked += 1;
+            match outcome {
+                Ok(outcome) => {
+                    stats.corrupted += outcome.corrupted;
+                    stats.missing += outcome.missing;
+                    stats.repaired += outcome.repaired;
+                    if outcome.lost {
+                        stats.lost += 1;
+                    } else if outcome.skipped {
+                        stats.skipped += 1;
+                    } else if outcome.corrupted + outcome.missing == 0 {
+                        stats.healthy += 1;
+                    }
+                }
+                Err(e) => {
+                    error!("fsck: failed to check key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+            if stats.checked % PROGRESS_EVERY == 0 {
+                info!("fsck: checked {}/{} records", stats.checked, total);
+            }
+        }
+
+        info!(
+            "fsck: checked: {} healthy: {} corrupted: {} missing: {} repaired: {} lost: {} skipped: {} failed: {}",
+            stats.checked,
+            stats.healthy,
+            stats.corrupted,
+            stats.missing,
+            stats.repaired,
+            stats.lost,
+            stats.skipped,
+            stats.failed
+        );
+        Ok(stats)
+    }
+
+    /// Checks every replica of every blob of a record, and repairs the bad ones if enabled.
+    async fn check_record(&self, record: record::Record) -> anyhow::Result<Outcome> {
+        let key = record.key().to_string();
+        let mut outcome = Outcome::default();
+        let mut repairs = Vec::new();
+        // An erasure coded value survives losing as many shards as it has parity shards
+        let tolerated = record.erasure().map_or(0, |erasure| {
+            erasure.shards.len() - erasure.data_shards as usize
+        });
+        let mut unreadable = 0;
+        for blob in blobs(&record) {
+            let reads = blob.volumes.iter().map(|volume| self.read(volume, &blob));
+            let replicas = futures::future::try_join_all(reads).await?;
+            let mut good = Vec::new();
+            let mut bad = Vec::new();
+            for (volume, replica) in blob.volumes.iter().zip(replicas) {
+                match replica {
+                    Replica::Good => good.push(volume.clone()),
+                    Replica::Corrupted => {
+                        warn!("fsck: key: {} replica {} corrupted", key, volume);
+                        outcome.corrupted += 1;
+                        bad.push(volume.clone());
+                    }
+                    Replica::Missing => {
+                        warn!("fsck: key: {} replica {} missing", key, volume);
+                        outcome.missing += 1;
+                        bad.push(volume.clone());
+                    }
+                }
+            }
+            if bad.is_empty() {
+                continue;
+            }
+            if good.is_empty() {
+                error!(
+                    "fsck: key: {} has no good replica of {}",
+                    key, blob.remote_path
+                );
+                unreadable += 1;
+                continue;
+            }
+            repairs.push((blob.remote_path, good, bad));
+        }
+        outcome.lost = unreadable > tolerated;
+
+        if !self.repair || repairs.is_empty() {
+            return Ok(outcome);
+        }
+        let Some(_guard) = self.key_locks.try_lock(&key) else {
+            debug!("fsck: key: {} locked, skipping the repair", key);
+            outcome.skipped = true;
+            return Ok(outcome);
+        };
+        match self.leveldb.get_record(&key).await? {
+            Some(current) if server::same_write(&current, &record) => {}
+            _ => {
+                debug!(
+                    "fsck: key: {} changed since the scan, skipping the repair",
+                    key
+                );
+                outcome.skipped = true;
+                return Ok(outcome);
+            }
+        }
+        for (remote_path, good, bad) in repairs {
+            for volume in bad {
+                rebalance::copy_value(&self.client, &good, &volume, &remote_path).await?;
+                debug!("fsck: key: {} copied to {}", key, volume);
+                outcome.repaired += 1;
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Reads a replica of a blob and compares its hash and size with the record.
+    /// Values stored without a hash are only checked for their size. Encrypted and compressed values are
+    /// decrypted and decompressed first, and corrupted if they fail to.
+    /// Fails if the volume cannot be read, a down volume is neither good nor bad.
+    async fn read(&self, volume: &str, blob: &Blob) -> anyhow::Result<Replica> {
+        let remote_url = record::volume_url(volume, &blob.remote_path);
+        let res = self.client.get(&remote_url).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Replica::Missing);
+        }
+        let res = res.error_for_status()?;
+
+        let mut hasher = checksum::Hasher::new(&[blob.algorithm]);
+        let mut size = 0;
+        let stored = res
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(std::io::Error::other));
+        let stored = match &blob.encryption {
+            Some(encryption) => {
+                let Some(master_key) = &self.encryption else {
+                    anyhow::bail!("encrypted value, no master key to check it");
+                };
+                master_key.open(encryption)?.decrypt(stored).boxed()
+            }
+            None => stored.boxed(),
+        };
+        let mut body = if blob.encoded {
+            let stored = tokio_util::io::StreamReader::new(stored);
+            tokio_util::io::ReaderStream::new(compress::decoder(stored)).boxed()
+        } else {
+            stored
+        };
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                // Errors of the volume are wrapped, any other one comes from the decoder
+                Err(e) if e.get_ref().is_some_and(|e| e.is::<reqwest::Error>()) => {
+                    return Err(e.into())
+                }
+                Err(_) => return Ok(Replica::Corrupted),
+            };
+            size += chunk.len() as u64;
+            hasher.update(&chunk);
+        }
+        let hash = checksum::encode_hex(&hasher.finalize().remove(0).1);
+        if size != blob.size || (!blob.hash.is_empty() && hash != blob.hash) {
+            return Ok(Replica::Corrupted);
+        }
+        Ok(Replica::Good)
+    }
+}
+
+/// Returns the blobs of the value of a record, its parts for a multipart value
+/// and its shards, each on a single volume, for an erasure coded value.
+fn blobs(record: &record::Record) -> Vec<Blob> {
+    if let Some(erasure) = record.erasure() {
+        let size = erasure::shard_size(record.stored_size(), erasure.data_shards as usize);
+        return erasure
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(index, shard)| Blob {
+                remote_path: record::get_remote_path(&record::shard_key(record.key(), index)),
+                hash: shard.hash.clone(),
+                algorithm: checksum::Algorithm::Md5,
+                size,
+                volumes: vec![shard.volume.clone()],
+                encoded: false,
+                encryption: None,
+            })
+            .collect();
+    }
+    if record.parts().is_empty() {
+        return vec![Blob {
+            remote_path: record.remote_path(record.key()),
+            hash: record.hash().to_string(),
+            algorithm: record.hash_algorithm(),
+            size: record.size(),
+            volumes: record.read_volumes().clone(),
+            encoded: record.encoding().is_some(),
+            encryption: record.encryption().cloned(),
+        }];
+    }
+    record
+        .parts()
+        .iter()
+        .map(|part| Blob {
+            remote_path: record::get_remote_path(&record::part_key(record.key(), part.number)),
+            hash: checksum::parse_md5(&part.hash).unwrap_or_default(),
+            algorithm: checksum::Algorithm::Md5,
+            size: part.size,
+            volumes: part.volumes.clone(),
+            encoded: false,
+            encryption: None,
+        })
+        .collect()
+}
+
+/// Returns the scheduler task running a repairing fsck scan, failing if replicas are left bad.
+pub(crate) fn task(fsck: Arc<Fsck>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let fsck = fsck.clone();
+        Box::pin(async move {
+            let stats = fsck.run().await?;
+            if stats.unrepaired() > 0 || stats.lost > 0 || stats.failed > 0 {
+                anyhow::bail!(
+                    "{} replicas unrepaired, {} records lost, {} records failed to check",
+                    stats.unrepaired(),
+                    stats.lost,
+                    stats.failed
+                );
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_fsck() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 2).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let volumes = cluster.volume_addrs().to_vec();
+        let remote_url =
+            |volume: &str, key: &str| record::volume_url(volume, &record::get_remote_path(key));
+
+        for key in ["healthy", "corrupted", "missing", "lost"] {
+            for volume in volumes.iter() {
+                client.put(remote_url(volume, key)).body(key).send().await?;
+            }
+            let record = record::Record::new(
+                record::Deleted::No,
+                checksum::md5_hex(key.as_bytes()),
+                volumes.clone(),
+            )
+            .with_size(key.len() as u64);
+            leveldb.put_record(key, record).await?;
+        }
+        client
+            .put(remote_url(&volumes[0], "corrupted"))
+            .body("rotten!!!")
+            .send()
+            .await?;
+        client
+            .delete(remote_url(&volumes[1], "missing"))
+            .send()
+            .await?;
+        for volume in volumes.iter() {
+            client.delete(remote_url(volume, "lost")).send().await?;
+        }
+
+        let key_locks = locks::KeyLocks::new(Duration::from_secs(1));
+        let stats = Fsck::new(leveldb.clone(), key_locks.clone()).run().await?;
+        assert_eq!(
+            stats,
+            FsckStats {
+                checked: 4,
+                healthy: 1,
+                corrupted: 1,
+                missing: 3,
+                repaired: 0,
+                lost: 1,
+                skipped: 0,
+                failed: 0,
+            }
+        );
+        let res = client
+            .get(remote_url(&volumes[0], "corrupted"))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "rotten!!!");
+
+        let fsck = Fsck::new(leveldb.clone(), key_locks)
+            .with_repair(true)
+            .with_concurrency(2);
+        let stats = fsck.run().await?;
+        assert_eq!((stats.repaired, stats.unrepaired(), stats.lost), (2, 2, 1));
+        for key in ["corrupted", "missing"] {
+            for volume in volumes.iter() {
+                let res = client.get(remote_url(volume, key)).send().await?;
+                assert_eq!(res.text().await?, key);
+            }
+        }
+
+        let stats = fsck.run().await?;
+        assert_eq!((stats.healthy, stats.lost), (3, 1));
+
+        Ok(())
+    }
+}
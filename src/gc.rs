@@ -0,0 +1,347 @@
+use futures::StreamExt;
+use log::{debug, error, info};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+use crate::{dedup, locks, record, server, tasks};
+
+/// Name of the garbage collection task in the scheduler.
+pub(crate) const TASK_NAME: &str = "gc";
+
+/// Default interval between two garbage collection sweeps.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Default time a deleted key can be recovered before its value is collected.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 3600);
+
+/// Records collected concurrently by a sweep.
+const CONCURRENCY: usize = 8;
+
+/// Struct counting the records handled by a garbage collection sweep.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct GcStats {
+    /// Soft deleted records purged with their values.
+    pub(crate) collected: u64,
+    /// Parts of multipart uploads never completed, purged with their values.
+    pub(crate) abandoned_parts: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+}
+
+/// Outcome of the collection of a single record.
+enum Outcome {
+    Collected,
+    Skipped,
+}
+
+/// Struct deleting the values of the records soft deleted for longer than the grace period
+/// from their volumes and purging the records from the leveldb.
+/// Parts of multipart uploads not written to for the grace period are collected the same way.
+pub(crate) struct Gc {
+    leveldb: Arc<record::LevelDb>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+    grace_period: Duration,
+}
+
+impl Gc {
+    /// Creates a new garbage collector of the records of the leveldb.
+    /// Records are locked while collected, like PUT and DELETE lock them.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        key_locks: Arc<locks::KeyLocks>,
+        grace_period: Duration,
+    ) -> Self {
+        Self {
+            leveldb,
+            key_locks,
+            client: reqwest::Client::new(),
+            grace_period,
+        }
+    }
+
+    /// Uses the client to delete the collected values from the volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Scans every record and collects the ones past the grace period.
+    /// Fails only if the leveldb cannot be scanned, records failing to collect are kept for the next sweep.
+    pub(crate) async fn run(&self) -> anyhow::Result<GcStats> {
+        let now = record::unix_now();
+        let mut stats = GcStats::default();
+        let grace_period = self.grace_period;
+        let records = self
+            .leveldb
+            .blocking(move |leveldb| {
+                let mut records = Vec::new();
+                leveldb.for_each_record(|record| {
+                    if is_collectable(&record, now, grace_period) {
+                        records.push(record);
+                    }
+                    Ok(())
+                })?;
+                Ok(records)
+            })
+            .await?;
+
+        let mut outcomes = futures::stream::iter(records)
+            .map(|record| async move {
+                let key = record.key().to_string();
+                let is_part = record.deleted() == record::Deleted::Init;
+                (key, is_part, self.collect_record(record, now).await)
+            })
+            .buffer_unordered(CONCURRENCY);
+        while let Some((key, is_part, outcome)) = outcomes.next().await {
+            match outcome {
+                Ok(Outcome::Collected) if is_part => stats.abandoned_parts += 1,
+                Ok(Outcome::Collected) => stats.collected += 1,
+                Ok(Outcome::Skipped) => stats.skipped += 1,
+                Err(e) => {
+                    error!("gc: failed to collect key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "gc: collected: {} abandoned parts: {} skipped: {} failed: {}",
+            stats.collected, stats.abandoned_parts, stats.skipped, stats.failed
+        );
+        Ok(stats)
+    }
+
+    /// Deletes the value of a record from its volumes and purges the record,
+    /// unless the key is locked or the record changed since it was scanned.
+    async fn collect_record(&self, record: record::Record, now: u64) -> anyhow::Result<Outcome> {
+        let key = record.key().to_string();
+        // Parts are locked through the key of their upload, like the upload completion locks them
+        let lock_key = match record.deleted() {
+            record::Deleted::Init => record::upload_key(&key).unwrap_or(key.as_str()),
+            _ => key.as_str(),
+        };
+        let Some(_guard) = self.key_locks.try_lock(lock_key) else {
+            debug!("gc: key: {} locked, skipping", key);
+            return Ok(Outcome::Skipped);
+        };
+        match self.leveldb.get_record(&key).await? {
+            Some(current)
+                if current == record && is_collectable(&current, now, self.grace_period) => {}
+            _ => {
+                debug!("gc: key: {} changed since the scan, skipping", key);
+                return Ok(Outcome::Skipped);
+            }
+        }
+
+        let mut remote_urls = record.remote_urls(&key);
+        // A deduplicated value is only deleted with the last record referencing it
+        let _blob_guard = match record.blob() {
+            Some(hash) => {
+                let Some(guard) = self.key_locks.try_lock(&record::blob_key(hash)) else {
+                    debug!("gc: key: {} blob {} locked, skipping", key, hash);
+                    return Ok(Outcome::Skipped);
+                };
+                if dedup::is_shared(&self.leveldb, hash).await? {
+                    remote_urls.clear();
+                }
+                Some(guard)
+            }
+            None => None,
+        };
+        // A new multipart upload of a deleted key writes its parts to the paths of the deleted parts
+        for part in record.parts() {
+            let part_key = record::part_key(&key, part.number);
+            if let Some(upload) = self.leveldb.get_record(&part_key).await? {
+                let kept = upload.remote_urls(&part_key);
+                remote_urls.retain(|remote_url| !kept.contains(remote_url));
+            }
+        }
+
+        // The record is kept until every blob is gone, so a failed delete is retried next sweep
+        let deletes = remote_urls
+            .iter()
+            .map(|remote_url| server::remote_delete(&self.client, remote_url));
+        for result in futures::future::join_all(deletes).await {
+            result?;
+        }
+
+        self.leveldb.delete_record(&key).await?;
+        if let Some(hash) = record.blob() {
+            dedup::release(&self.leveldb, hash).await?;
+        }
+        debug!("gc: key: {} collected", key);
+        Ok(Outcome::Collected)
+    }
+}
+
+/// Returns the scheduler task running a garbage collection sweep, failing if records failed to collect.
+pub(crate) fn task(gc: Arc<Gc>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let gc = gc.clone();
+        Box::pin(async move {
+            let stats = gc.run().await?;
+            if stats.failed > 0 {
+                anyhow::bail!("{} records failed to collect", stats.failed);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Returns true if the record is soft deleted, or a part record, since longer than the grace period.
+/// Records written before their times were recorded count as old.
+fn is_collectable(record: &record::Record, now: u64, grace_period: Duration) -> bool {
+    let since = match record.deleted() {
+        record::Deleted::Soft => record.deleted_at(),
+        // Only parts of multipart uploads are stored with the Init status
+        record::Deleted::Init => record.updated_at(),
+        record::Deleted::No | record::Deleted::Hard => return false,
+    };
+    since
+        .unwrap_or_default()
+        .saturating_add(grace_period.as_secs())
+        <= now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_gc() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 2).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let volumes = cluster.volume_addrs().to_vec();
+        let now = record::unix_now();
+        let grace_period = Duration::from_secs(3600);
+        let old = now - 2 * grace_period.as_secs();
+
+        let (abandoned, uploading) = (
+            record::part_key("abandoned", 1),
+            record::part_key("uploading", 1),
+        );
+        let records = [
+            (
+                "deleted-long-ago",
+                record::Record::new(record::Deleted::Soft, String::new(), volumes.clone())
+                    .with_deleted_at(Some(old)),
+            ),
+            (
+                "deleted-recently",
+                record::Record::new(record::Deleted::Soft, String::new(), volumes.clone())
+                    .with_deleted_at(Some(now)),
+            ),
+            (
+                "live",
+                record::Record::new(record::Deleted::No, String::new(), volumes.clone())
+                    .with_timestamps(old, old),
+            ),
+            (
+                abandoned.as_str(),
+                record::Record::new(record::Deleted::Init, String::new(), volumes.clone())
+                    .with_timestamps(old, old),
+            ),
+            (
+                uploading.as_str(),
+                record::Record::new(record::Deleted::Init, String::new(), volumes.clone())
+                    .with_timestamps(now, now),
+            ),
+        ];
+        for (key, record) in records {
+            for volume in volumes.iter() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                client.put(remote_url).body(key.to_string()).send().await?;
+            }
+            leveldb.put_record(key, record).await?;
+        }
+
+        let gc = Gc::new(
+            leveldb.clone(),
+            locks::KeyLocks::new(Duration::from_secs(1)),
+            grace_period,
+        );
+        let stats = gc.run().await?;
+        assert_eq!(
+            stats,
+            GcStats {
+                collected: 1,
+                abandoned_parts: 1,
+                skipped: 0,
+                failed: 0
+            }
+        );
+        assert!(leveldb.get_record("deleted-long-ago").await?.is_none());
+        assert!(leveldb.get_record(&abandoned).await?.is_none());
+        for key in ["deleted-recently", "live", uploading.as_str()] {
+            assert!(leveldb.get_record(key).await?.is_some(), "key: {}", key);
+        }
+        assert_eq!(cluster.volume(0).len(), 3);
+        assert_eq!(cluster.volume(1).len(), 3);
+
+        let stats = gc.run().await?;
+        assert_eq!(stats.collected + stats.abandoned_parts, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_keeps_parts_of_new_upload() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(1, 1).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let volumes = cluster.volume_addrs().to_vec();
+
+        // The deleted value had two parts, a new upload of the key rewrote the first one
+        let parts: Vec<record::Part> = (1..=2)
+            .map(|number| record::Part {
+                number,
+                hash: String::new(),
+                size: 1,
+                volumes: volumes.clone(),
+            })
+            .collect();
+        for number in 1..=2 {
+            let part_key = record::part_key("video", number);
+            let remote_url = format!(
+                "http://{}{}",
+                volumes[0],
+                record::get_remote_path(&part_key)
+            );
+            client.put(remote_url).body("p").send().await?;
+        }
+        let deleted = record::Record::new(record::Deleted::Soft, String::new(), volumes.clone())
+            .with_parts(parts)
+            .with_deleted_at(Some(1));
+        leveldb.put_record("video", deleted).await?;
+        let upload = record::Record::new(record::Deleted::Init, String::new(), volumes.clone())
+            .with_timestamps(record::unix_now(), record::unix_now());
+        leveldb
+            .put_record(&record::part_key("video", 1), upload)
+            .await?;
+
+        let gc = Gc::new(
+            leveldb.clone(),
+            locks::KeyLocks::new(Duration::from_secs(1)),
+            Duration::from_secs(60),
+        );
+        assert_eq!(gc.run().await?.collected, 1);
+        assert!(leveldb.get_record("video").await?.is_none());
+        assert_eq!(
+            cluster.volume(0).paths(),
+            [record::get_remote_path(&record::part_key("video", 1))]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use anyhow::Context;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::record;
+
+/// Prefix of the Go records of unlinked keys, whose values are still on the volumes.
+const DELETED_PREFIX: &str = "DELETED";
+
+/// Prefix of the MD5 of the value in the Go records, 32 hex characters.
+const HASH_PREFIX: &str = "HASH";
+
+/// Struct counting the keys handled by an import of a Go minikeyvalue index.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct GoImportStats {
+    pub(crate) imported: u64,
+    pub(crate) unlinked: u64,
+    pub(crate) existing: u64,
+    pub(crate) misplaced: u64,
+    pub(crate) invalid: u64,
+    pub(crate) failed: u64,
+}
+
+/// Struct representing a record of the Go minikeyvalue, stored as
+/// `[DELETED][HASH<md5>]volume1,volume2` under the raw bytes of its key.
+#[derive(Debug, PartialEq, Eq)]
+struct GoRecord {
+    unlinked: bool,
+    hash: String,
+    read_volumes: Vec<String>,
+}
+
+impl GoRecord {
+    /// Parses the value of a Go record.
+    fn parse(value: &[u8]) -> anyhow::Result<Self> {
+        let value = std::str::from_utf8(value).context("record is not UTF-8")?;
+        let (unlinked, value) = match value.strip_prefix(DELETED_PREFIX) {
+            Some(value) => (true, value),
+            None => (false, value),
+        };
+        let (hash, value) = match value.strip_prefix(HASH_PREFIX) {
+            Some(value) => {
+                let hash = value
+                    .get(..32)
+                    .context("hash is shorter than 32 characters")?;
+                (hash.to_string(), &value[32..])
+            }
+            None => (String::new(), value),
+        };
+        let read_volumes: Vec<String> = value
+            .split(',')
+            .filter(|volume| !volume.is_empty())
+            .map(String::from)
+            .collect();
+        if read_volumes.is_empty() {
+            anyhow::bail!("record has no volumes");
+        }
+        Ok(Self {
+            unlinked,
+            hash,
+            read_volumes,
+        })
+    }
+}
+
+/// Returns the path the Go minikeyvalue stores the value of a key at, like `record::get_remote_path`
+/// but with the standard base64 alphabet.
+fn go_remote_path(key: &str) -> String {
+    let md5_key = md5::compute(key);
+    let b64_key = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, key);
+
+    format!("/{:02x}/{:02x}/{}", md5_key[0], md5_key[1], b64_key)
+}
+
+/// Struct converting the index of a Go minikeyvalue into records of the leveldb, so the volumes
+/// of a Go cluster are served by this index server as they are.
+pub(crate) struct GoImport {
+    leveldb: Arc<record::LevelDb>,
+    concurrency: usize,
+    client: reqwest::Client,
+}
+
+impl GoImport {
+    /// Creates a new import writing records to the leveldb, sizing that many values at once.
+    pub(crate) fn new(leveldb: Arc<record::LevelDb>, concurrency: usize) -> Self {
+        Self {
+            leveldb,
+            concurrency: concurrency.max(1),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses the client to size the values on the volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Writes a record for every key of the Go index, live or unlinked, sized from a replica of its value.
+    /// Existing records are kept. Fails only if the Go index cannot be read, keys failing to import
+    /// are counted and logged.
+    pub(crate) async fn run(&self, go_path: &Path) -> anyhow::Result<GoImportStats> {
+        let mut stats = GoImportStats::default();
+        let entries = record::read_foreign_leveldb(go_path)?;
+        info!("import-go: importing {} keys", entries.len());
+
+        let mut records = Vec::new();
+        for (key, value) in entries {
+            let Ok(key) = String::from_utf8(key) else {
+                warn!("import-go: skipping key that is not UTF-8");
+                stats.invalid += 1;
+                continue;
+            };
+            if key.is_empty() || key.starts_with(record::RESERVED_PREFIX) {
+                warn!("import-go: skipping reserved key {:?}", key);
+                stats.invalid += 1;
+                continue;
+            }
+            match GoRecord::parse(&value) {
+                Ok(go_record) => records.push((key, go_record)),
+                Err(e) => {
+                    warn!("import-go: skipping key {}: {:#}", key, e);
+                    stats.invalid += 1;
+                }
+            }
+        }
+
+        let mut outcomes = futures::stream::iter(records)
+            .map(|(key, go_record)| async move {
+                let outcome = self.import_record(&key, &go_record).await;
+                (key, go_record.unlinked, outcome)
+            })
+            .buffer_unordered(self.concurrency);
+        while let Some((key, unlinked, outcome)) = outcomes.next().await {
+            match outcome {
+                Ok(false) => stats.existing += 1,
+                Ok(true) => {
+                    match unlinked {
+                        true => stats.unlinked += 1,
+                        false => stats.imported += 1,
+                    }
+                    if go_remote_path(&key) != record::get_remote_path(&key) {
+                        warn!(
+                            "import-go: value of key {} is at {} in the volumes, move it to {}",
+                            key,
+                            go_remote_path(&key),
+                            record::get_remote_path(&key)
+                        );
+                        stats.misplaced += 1;
+                    }
+                }
+                Err(e) => {
+                    error!("import-go: failed to import key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "import-go: imported: {} unlinked: {} existing: {} misplaced: {} invalid: {} failed: {}",
+            stats.imported,
+            stats.unlinked,
+            stats.existing,
+            stats.misplaced,
+            stats.invalid,
+            stats.failed
+        );
+        Ok(stats)
+    }
+
+    /// Writes the record of a key of the Go index, unless the leveldb already has one.
+    /// Returns true if the record is written.
+    async fn import_record(&self, key: &str, go_record: &GoRecord) -> anyhow::Result<bool> {
+        if self.leveldb.get_record(key).await?.is_some() {
+            debug!("import-go: key: {} already has a record", key);
+            return Ok(false);
+        }
+
+        let size = self.value_size(key, &go_record.read_volumes).await?;
+        let deleted = match go_record.unlinked {
+            true => record::Deleted::Soft,
+            false => record::Deleted::No,
+        };
+        let record = record::Record::new(
+            deleted,
+            go_record.hash.clone(),
+            go_record.read_volumes.clone(),
+        )
+        .with_size(size);
+        self.leveldb.put_record(key, record).await?;
+        Ok(true)
+    }
+
+    /// Returns the size of the value of a key, from the first of its volumes answering a HEAD.
+    async fn value_size(&self, key: &str, read_volumes: &[String]) -> anyhow::Result<u64> {
+        let remote_path = go_remote_path(key);
+        let mut last_error = None;
+        for volume in read_volumes {
+            let remote_url = record::volume_url(volume, &remote_path);
+            let size = self
+                .client
+                .head(&remote_url)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(anyhow::Error::from)
+                .and_then(|res| {
+                    res.headers()
+                        .get(reqwest::header::CONTENT_LENGTH)
+                        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+                        .context("missing Content-Length")
+                });
+            match size {
+                Ok(size) => return Ok(size),
+                Err(e) => {
+                    debug!("import-go: failed to size {}: {:#}", remote_url, e);
+                    last_error = Some(e.context(format!("failed to size {}", remote_url)));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no volumes")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            GoRecord::parse(b"HASH5d41402abc4b2a76b9719d911017c592localhost:3001,localhost:3002")
+                .unwrap(),
+            GoRecord {
+                unlinked: false,
+                hash: "5d41402abc4b2a76b9719d911017c592".to_string(),
+                read_volumes: vec!["localhost:3001".to_string(), "localhost:3002".to_string()],
+            }
+        );
+        assert_eq!(
+            GoRecord::parse(b"DELETEDlocalhost:3001/sv0A").unwrap(),
+            GoRecord {
+                unlinked: true,
+                hash: String::new(),
+                read_volumes: vec!["localhost:3001/sv0A".to_string()],
+            }
+        );
+        assert!(GoRecord::parse(b"HASH5d41").is_err());
+        assert!(GoRecord::parse(b"").is_err());
+    }
+
+    #[test]
+    fn test_go_remote_path() {
+        assert_eq!(go_remote_path("wehave"), record::get_remote_path("wehave"));
+        // Standard base64 where the keys differ from the URL-safe paths
+        assert!(go_remote_path("~~~").ends_with("/fn5+"));
+        assert!(record::get_remote_path("~~~").ends_with("/fn5-"));
+    }
+
+    #[cfg(feature = "leveldb")]
+    #[tokio::test]
+    async fn test_import() -> anyhow::Result<()> {
+        use leveldb::kv::KV;
+
+        struct GoKey(Vec<u8>);
+        impl db_key::Key for GoKey {
+            fn from_u8(key: &[u8]) -> Self {
+                Self(key.to_vec())
+            }
+
+            fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
+                f(&self.0)
+            }
+        }
+
+        let cluster = crate::testkit::TestCluster::start(2, 2).await?;
+        let volumes = cluster.volume_addrs();
+        let client = reqwest::Client::new();
+        for (key, value) in [("live", "hello"), ("unlinked", "bye"), ("~~~", "tilde")] {
+            for volume in volumes {
+                let url = record::volume_url(volume, &go_remote_path(key));
+                client
+                    .put(url)
+                    .body(value)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+
+        let go_dir = tempfile::tempdir()?;
+        {
+            let mut options = leveldb::options::Options::new();
+            options.create_if_missing = true;
+            let go_db: leveldb::database::Database<GoKey> =
+                leveldb::database::Database::open(go_dir.path(), options)?;
+            let volumes = volumes.join(",");
+            for (key, value) in [
+                (
+                    "live",
+                    format!("HASH5d41402abc4b2a76b9719d911017c592{}", volumes),
+                ),
+                ("unlinked", format!("DELETED{}", volumes)),
+                ("~~~", volumes.clone()),
+                ("lost", "localhost:1".to_string()),
+                ("broken", "HASH12".to_string()),
+            ] {
+                go_db.put(
+                    leveldb::options::WriteOptions::new(),
+                    GoKey(key.as_bytes().to_vec()),
+                    value.as_bytes(),
+                )?;
+            }
+        }
+
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            record::DbBackend::Sled,
+        )?);
+        let stats = GoImport::new(leveldb.clone(), 2).run(go_dir.path()).await?;
+        assert_eq!(
+            stats,
+            GoImportStats {
+                imported: 2,
+                unlinked: 1,
+                existing: 0,
+                misplaced: 1,
+                invalid: 1,
+                failed: 1,
+            }
+        );
+
+        let live = leveldb.get_record("live").await?.unwrap();
+        assert_eq!(live.deleted(), record::Deleted::No);
+        assert_eq!(live.hash(), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(live.read_volumes().as_slice(), volumes);
+        assert_eq!(live.size(), 5);
+        let unlinked = leveldb.get_record("unlinked").await?.unwrap();
+        assert_eq!(unlinked.deleted(), record::Deleted::Soft);
+        assert_eq!(unlinked.size(), 3);
+
+        // A second import keeps the records already imported
+        let stats = GoImport::new(leveldb, 2).run(go_dir.path()).await?;
+        assert_eq!(stats.existing, 3);
+
+        Ok(())
+    }
+}
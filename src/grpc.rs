@@ -0,0 +1,572 @@
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use futures::StreamExt;
+use log::{debug, error};
+use std::sync::Arc;
+
+use crate::{admin, auth, record, server};
+
+/// Messages and service of `proto/minikeyvalue.proto`.
+pub(crate) mod proto {
+    tonic::include_proto!("minikeyvalue.v1");
+}
+
+use proto::key_value_server::{KeyValue, KeyValueServer};
+
+/// Keys sent in a single message of a List stream.
+const LIST_BATCH: usize = 1000;
+
+/// State of the gRPC service, the states of the key routes its methods map to.
+pub(crate) struct AppGrpcState {
+    pub(crate) leveldb: Arc<record::LevelDb>,
+    pub(crate) put: Arc<server::AppPutState>,
+    pub(crate) get: Arc<server::AppGetState>,
+    pub(crate) delete: Arc<server::AppDeleteState>,
+}
+
+/// Struct implementing the `KeyValue` service over the records and volumes of the HTTP API.
+struct KeyValueService {
+    state: Arc<AppGrpcState>,
+}
+
+/// Creates the router serving the gRPC service, over HTTP/2.
+pub(crate) fn router(state: Arc<AppGrpcState>) -> axum::Router {
+    tonic::service::Routes::new(KeyValueServer::new(KeyValueService { state })).into_axum_router()
+}
+
+/// Middleware rejecting the calls without a bearer token of the scope they need, like `auth::require_token`.
+/// Put and Delete need write access, the other methods read access.
+pub(crate) async fn require_token(
+    axum::extract::State(tokens): axum::extract::State<Arc<auth::Tokens>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let required = match request.uri().path().rsplit('/').next() {
+        Some("Put" | "Delete") => auth::Scope::Write,
+        _ => auth::Scope::Read,
+    };
+    let status = match tokens.bearer_scope(request.headers()) {
+        None => tonic::Status::unauthenticated("missing or unknown bearer token"),
+        Some(scope) if scope < required => {
+            tonic::Status::permission_denied("the token only has read access")
+        }
+        Some(_) => return next.run(request).await,
+    };
+    status.into_http().map(axum::body::Body::new)
+}
+
+/// Middleware answering UNAVAILABLE to the Put and Delete calls arriving once shutdown started,
+/// like `server::reject_writes_while_draining`. The other methods are still served.
+pub(crate) async fn reject_writes_while_draining(
+    axum::extract::State(draining): axum::extract::State<Arc<std::sync::atomic::AtomicBool>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let method = request.uri().path().rsplit('/').next();
+    if draining.load(std::sync::atomic::Ordering::SeqCst)
+        && matches!(method, Some("Put" | "Delete"))
+    {
+        debug!("grpc: {} rejected while draining", request.uri().path());
+        return tonic::Status::unavailable("the server is shutting down")
+            .into_http()
+            .map(axum::body::Body::new);
+    }
+    next.run(request).await
+}
+
+/// Returns the gRPC status of an HTTP status of a key route.
+fn status_of(status: StatusCode, key: &str) -> tonic::Status {
+    let message = format!("key {}: {}", key, status);
+    match status {
+        StatusCode::BAD_REQUEST
+        | StatusCode::LENGTH_REQUIRED
+        | StatusCode::UNPROCESSABLE_ENTITY => tonic::Status::invalid_argument(message),
+        StatusCode::NOT_FOUND => tonic::Status::not_found(message),
+        // The key exists without a precondition, or stayed locked by another write
+        StatusCode::CONFLICT => tonic::Status::aborted(message),
+        StatusCode::PRECONDITION_FAILED => tonic::Status::failed_precondition(message),
+        StatusCode::GONE => tonic::Status::data_loss(message),
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => {
+            tonic::Status::unavailable(message)
+        }
+        StatusCode::GATEWAY_TIMEOUT => tonic::Status::deadline_exceeded(message),
+        StatusCode::PAYLOAD_TOO_LARGE => tonic::Status::out_of_range(message),
+        _ => tonic::Status::internal(message),
+    }
+}
+
+/// Returns an error unless the key is usable in the index.
+fn check_key(key: &str) -> Result<(), &'static str> {
+    if key.is_empty() {
+        return Err("key is required");
+    }
+    Ok(())
+}
+
+/// Returns the live record of a key.
+async fn live_record(
+    leveldb: &record::LevelDb,
+    key: &str,
+) -> Result<record::Record, tonic::Status> {
+    match leveldb.get_record(key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => Ok(record),
+        Ok(_) => Err(tonic::Status::not_found(format!("key {} not found", key))),
+        Err(e) => {
+            error!("grpc: failed to get record {} from leveldb: {}", key, e);
+            Err(tonic::Status::internal("failed to read the index"))
+        }
+    }
+}
+
+/// Returns the headers of the PUT of a Put header, or why they are invalid.
+fn put_headers(put: &proto::PutHeader) -> Result<HeaderMap, String> {
+    if put.size == 0 {
+        return Err("size is required".to_string());
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(put.size));
+    if put.ttl_secs > 0 {
+        headers.insert("X-Ttl", HeaderValue::from(put.ttl_secs));
+    }
+    let fields = [
+        ("Content-Md5", &put.content_md5),
+        ("Content-Type", &put.content_type),
+        ("Content-Disposition", &put.content_disposition),
+        ("If-Match", &put.if_match),
+        ("If-None-Match", &put.if_none_match),
+    ];
+    for (name, value) in fields {
+        if value.is_empty() {
+            continue;
+        }
+        let value = HeaderValue::from_str(value).map_err(|_| format!("invalid {}", name))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+#[tonic::async_trait]
+impl KeyValue for KeyValueService {
+    /// Stores a value like a PUT of its key, streamed from the chunks to the volumes.
+    async fn put(
+        &self,
+        request: tonic::Request<tonic::Streaming<proto::PutRequest>>,
+    ) -> Result<tonic::Response<proto::PutResponse>, tonic::Status> {
+        use proto::put_request::Request;
+
+        let mut messages = request.into_inner();
+        let Some(proto::PutRequest {
+            request: Some(Request::Header(put)),
+        }) = messages.message().await?
+        else {
+            return Err(tonic::Status::invalid_argument(
+                "the first message must be the header",
+            ));
+        };
+        check_key(&put.key).map_err(tonic::Status::invalid_argument)?;
+        debug!("grpc: put: {}", put.key);
+
+        let headers = put_headers(&put).map_err(tonic::Status::invalid_argument)?;
+        let chunks =
+            messages.map(
+                |message| match message.map_err(std::io::Error::other)?.request {
+                    Some(Request::Chunk(chunk)) => Ok(chunk),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "the messages after the header must be chunks",
+                    )),
+                },
+            );
+        let status = server::handle_put_record(
+            server::RecordKey(put.key.clone()),
+            axum::extract::State(self.state.put.clone()),
+            axum::extract::Query(server::PutParams::default()),
+            headers,
+            axum::body::Body::from_stream(chunks),
+        )
+        .await
+        .into_response()
+        .status();
+        if status != StatusCode::CREATED {
+            return Err(status_of(status, &put.key));
+        }
+
+        let record = live_record(&self.state.leveldb, &put.key).await?;
+        Ok(tonic::Response::new(proto::PutResponse {
+            etag: record.etag().unwrap_or_default(),
+        }))
+    }
+
+    /// Returns the volume urls of a value, the redirect of a GET or the url of every part.
+    /// Erasure coded values have no url to read them from, and compressed or encrypted values none to read
+    /// them as uploaded, they fail with FAILED_PRECONDITION.
+    async fn get(
+        &self,
+        request: tonic::Request<proto::GetRequest>,
+    ) -> Result<tonic::Response<proto::GetResponse>, tonic::Status> {
+        let key = request.into_inner().key;
+        check_key(&key).map_err(tonic::Status::invalid_argument)?;
+        debug!("grpc: get: {}", key);
+
+        let record = live_record(&self.state.leveldb, &key).await?;
+        if record.erasure().is_some() {
+            return Err(tonic::Status::failed_precondition(format!(
+                "key {}: erasure coded, read it through the index",
+                key
+            )));
+        }
+        if let Some(encoding) = record.encoding() {
+            return Err(tonic::Status::failed_precondition(format!(
+                "key {}: stored with {} encoding, read it through the index",
+                key, encoding.name
+            )));
+        }
+        if record.encryption().is_some() {
+            return Err(tonic::Status::failed_precondition(format!(
+                "key {}: encrypted, read it through the index",
+                key
+            )));
+        }
+        let urls = if record.parts().is_empty() {
+            let response = server::handle_get_record(
+                server::RecordKey(key.clone()),
+                axum::extract::State(self.state.get.clone()),
+                axum::extract::Query(server::GetParams::redirected()),
+                axum::extract::Query(admin::PageParams::default()),
+                HeaderMap::new(),
+            )
+            .await;
+            if !response.status().is_redirection() {
+                return Err(status_of(response.status(), &key));
+            }
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| tonic::Status::internal("redirect without a location"))?;
+            vec![location.to_string()]
+        } else {
+            server::locate_parts(&self.state.get, &key, &record)
+                .await
+                .map_err(|part| {
+                    tonic::Status::data_loss(format!(
+                        "key {}: part {} is on no volume",
+                        key, part.number
+                    ))
+                })?
+        };
+
+        Ok(tonic::Response::new(proto::GetResponse {
+            urls,
+            etag: record.etag().unwrap_or_default(),
+            size: record.size(),
+            content_type: record.content_type().unwrap_or_default().to_string(),
+            content_disposition: record.content_disposition().unwrap_or_default().to_string(),
+        }))
+    }
+
+    /// Deletes a key like a DELETE.
+    async fn delete(
+        &self,
+        request: tonic::Request<proto::DeleteRequest>,
+    ) -> Result<tonic::Response<proto::DeleteResponse>, tonic::Status> {
+        let key = request.into_inner().key;
+        check_key(&key).map_err(tonic::Status::invalid_argument)?;
+        debug!("grpc: delete: {}", key);
+
+        let response = server::handle_delete_record(
+            server::RecordKey(key.clone()),
+            axum::extract::State(self.state.delete.clone()),
+        )
+        .await;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(tonic::Response::new(proto::DeleteResponse {})),
+            status => Err(status_of(status, &key)),
+        }
+    }
+
+    type ListStream =
+        futures::stream::Iter<std::vec::IntoIter<Result<proto::ListResponse, tonic::Status>>>;
+
+    /// Streams the live keys starting with a prefix in batches, like `GET /prefix?list`.
+    async fn list(
+        &self,
+        request: tonic::Request<proto::ListRequest>,
+    ) -> Result<tonic::Response<Self::ListStream>, tonic::Status> {
+        let request = request.into_inner();
+        debug!("grpc: list: {}", request.prefix);
+
+        let leveldb = self.state.leveldb.clone();
+        let scan = tokio::task::spawn_blocking(move || {
+            let now = record::unix_now();
+            let mut keys = Vec::new();
+            leveldb.for_each_record(|record| {
+                if record.is_live(now) && record.key().starts_with(&request.prefix) {
+                    keys.push(record.key().to_string());
+                }
+                Ok(())
+            })?;
+            let limit = match request.limit {
+                0 => keys.len(),
+                limit => limit as usize,
+            };
+            let page = admin::PageParams {
+                start: Some(request.start),
+                limit: Some(limit),
+            };
+            anyhow::Ok(admin::paginate(keys, |key| key.as_str(), &page))
+        });
+        let (keys, next) = match scan.await {
+            Ok(Ok(page)) => page,
+            Ok(Err(e)) => {
+                error!("grpc: failed to scan leveldb: {}", e);
+                return Err(tonic::Status::internal("failed to scan the index"));
+            }
+            Err(e) => {
+                error!("grpc: leveldb scan panicked: {}", e);
+                return Err(tonic::Status::internal("failed to scan the index"));
+            }
+        };
+
+        let mut batches: Vec<proto::ListResponse> = keys
+            .chunks(LIST_BATCH)
+            .map(|keys| proto::ListResponse {
+                keys: keys.to_vec(),
+                next: String::new(),
+            })
+            .collect();
+        if batches.is_empty() {
+            batches.push(proto::ListResponse::default());
+        }
+        if let Some(last) = batches.last_mut() {
+            last.next = next;
+        }
+        let batches: Vec<_> = batches.into_iter().map(Ok).collect();
+        Ok(tonic::Response::new(futures::stream::iter(batches)))
+    }
+
+    /// Returns the metadata of a key from the index, like a HEAD.
+    async fn stat(
+        &self,
+        request: tonic::Request<proto::StatRequest>,
+    ) -> Result<tonic::Response<proto::StatResponse>, tonic::Status> {
+        let key = request.into_inner().key;
+        check_key(&key).map_err(tonic::Status::invalid_argument)?;
+        debug!("grpc: stat: {}", key);
+
+        let record = live_record(&self.state.leveldb, &key).await?;
+        Ok(tonic::Response::new(proto::StatResponse {
+            size: record.size(),
+            etag: record.etag().unwrap_or_default(),
+            content_type: record.content_type().unwrap_or_default().to_string(),
+            content_disposition: record.content_disposition().unwrap_or_default().to_string(),
+            created_at: record.created_at().unwrap_or_default(),
+            updated_at: record.updated_at().unwrap_or_default(),
+            expires_at: record.expires_at().unwrap_or_default(),
+            volumes: record.read_volumes().clone(),
+            parts: record.parts().len() as u32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+    use proto::key_value_client::KeyValueClient;
+
+    /// Returns the messages of a Put of the value in chunks of two bytes.
+    fn put_messages(key: &str, value: &'static str) -> Vec<proto::PutRequest> {
+        use proto::put_request::Request;
+
+        let header = proto::PutHeader {
+            key: key.to_string(),
+            size: value.len() as u64,
+            content_md5: format!("{:x}", md5::compute(value)),
+            content_type: "text/plain".to_string(),
+            ..Default::default()
+        };
+        let chunks = value.as_bytes().chunks(2).map(|chunk| proto::PutRequest {
+            request: Some(Request::Chunk(bytes::Bytes::copy_from_slice(chunk))),
+        });
+        std::iter::once(proto::PutRequest {
+            request: Some(Request::Header(header)),
+        })
+        .chain(chunks)
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_grpc() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let mut client = KeyValueClient::connect(cluster.grpc_url().to_string()).await?;
+        let http = reqwest::Client::new();
+
+        let put = futures::stream::iter(put_messages("greeting", "hello world"));
+        let etag = client.put(put).await?.into_inner().etag;
+        assert_eq!(etag, format!("\"{:x}\"", md5::compute("hello world")));
+
+        // Without a precondition an existing key isn't replaced, like a PUT
+        let put = futures::stream::iter(put_messages("greeting", "hi"));
+        let status = client.put(put).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        let put = futures::stream::iter(put_messages("greeting", "hi")[1..].to_vec());
+        let status = client.put(put).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let get = client
+            .get(proto::GetRequest {
+                key: "greeting".to_string(),
+            })
+            .await?
+            .into_inner();
+        assert_eq!(get.size, 11);
+        assert_eq!(get.content_type, "text/plain");
+        assert_eq!(get.urls.len(), 1);
+        assert_eq!(
+            http.get(&get.urls[0]).send().await?.text().await?,
+            "hello world"
+        );
+
+        let stat = client
+            .stat(proto::StatRequest {
+                key: "greeting".to_string(),
+            })
+            .await?
+            .into_inner();
+        assert_eq!(stat.etag, etag);
+        let head = http.head(cluster.key_url("greeting")).send().await?;
+        assert_eq!(stat.volumes.join(","), head.headers()["key-volumes"]);
+        assert!(stat.created_at > 0);
+        assert_eq!(stat.expires_at, 0);
+
+        // A multipart value is read from the url of every part
+        let url = cluster.key_url("video");
+        for (number, part) in [(1, "on"), (2, "you")] {
+            http.put(format!("{}?partNumber={}", url, number))
+                .body(part)
+                .send()
+                .await?;
+        }
+        http.post(format!("{}?uploads=complete", url))
+            .body("[1, 2]")
+            .send()
+            .await?;
+        let get = client
+            .get(proto::GetRequest {
+                key: "video".to_string(),
+            })
+            .await?
+            .into_inner();
+        let mut value = String::new();
+        for url in get.urls {
+            value.push_str(&http.get(url).send().await?.text().await?);
+        }
+        assert_eq!(value, "onyou");
+
+        let mut list = client
+            .list(proto::ListRequest {
+                limit: 1,
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let page = list.message().await?.unwrap();
+        assert_eq!(page.keys, ["greeting"]);
+        assert_eq!(page.next, "video");
+        assert!(list.message().await?.is_none());
+
+        client
+            .delete(proto::DeleteRequest {
+                key: "greeting".to_string(),
+            })
+            .await?;
+        let status = client
+            .get(proto::GetRequest {
+                key: "greeting".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let mut list = client
+            .list(proto::ListRequest::default())
+            .await?
+            .into_inner();
+        let page = list.message().await?.unwrap();
+        assert_eq!(page.keys, ["video"]);
+        assert_eq!(page.next, "");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grpc_auth() -> anyhow::Result<()> {
+        let mut tokens = auth::Tokens::default();
+        tokens.add("reader".to_string(), auth::Scope::Read);
+        tokens.add("writer".to_string(), auth::Scope::Write);
+        let cluster = TestCluster::start_with_auth(2, 1, tokens).await?;
+        let mut client = KeyValueClient::connect(cluster.grpc_url().to_string()).await?;
+        let with_token = |token: &str, put: Vec<proto::PutRequest>| {
+            let mut request = tonic::Request::new(futures::stream::iter(put));
+            request.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+            request
+        };
+
+        let status = client
+            .stat(proto::StatRequest {
+                key: "key".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = client
+            .put(with_token("reader", put_messages("key", "value")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        client
+            .put(with_token("writer", put_messages("key", "value")))
+            .await?;
+
+        let mut stat = tonic::Request::new(proto::StatRequest {
+            key: "key".to_string(),
+        });
+        stat.metadata_mut()
+            .insert("authorization", "Bearer reader".parse()?);
+        assert_eq!(client.stat(stat).await?.into_inner().size, 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grpc_reject_writes_while_draining() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 1).await?;
+        let mut client = KeyValueClient::connect(cluster.grpc_url().to_string()).await?;
+        let put = futures::stream::iter(put_messages("key", "value"));
+        client.put(put).await?;
+
+        cluster.set_draining(true);
+        let put = futures::stream::iter(put_messages("other", "value"));
+        let status = client.put(put).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let delete = proto::DeleteRequest {
+            key: "key".to_string(),
+        };
+        let status = client.delete(delete.clone()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let stat = proto::StatRequest {
+            key: "key".to_string(),
+        };
+        assert_eq!(client.stat(stat).await?.into_inner().size, 5);
+
+        cluster.set_draining(false);
+        client.delete(delete).await?;
+
+        Ok(())
+    }
+}
@@ -1,13 +1,44 @@
 use hashring::HashRing;
+use std::collections::HashMap;
+
+/// Returns the volume server of a replica volume, without its subvolume,
+/// e.g. `localhost:3001` for `localhost:3001/sv0A`.
+pub fn volume_server(volume: &str) -> &str {
+    match volume.rsplit_once('/') {
+        Some((server, subvolume)) if subvolume.starts_with("sv") && !server.ends_with('/') => {
+            server
+        }
+        _ => volume,
+    }
+}
+
+/// Struct representing a placement rule that pins keys starting with a prefix to a volume group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlacementRule {
+    pub prefix: String,
+    pub group: String,
+}
 
 /// Struct representing a hash ring for a set of volumes, replicas and subvolumes.
 /// The hash ring is used to determine which volumes contain a given record.
 /// The subvolumes are the subdirectories in each volume that contain the actual data.
 /// The replicas are the number of times the record is replicated in the hash ring.
+/// Named volume groups have their own hash ring, placement rules pin keys to a group.
+#[derive(Clone)]
 pub struct Ring {
     hashring: HashRing<String>,
+    volumes: Vec<String>,
     replicas: usize,
     subvolumes: u32,
+    groups: HashMap<String, Group>,
+    placement_rules: Vec<PlacementRule>,
+}
+
+/// Struct representing a named volume group and its own hash ring.
+#[derive(Clone)]
+struct Group {
+    hashring: HashRing<String>,
+    volumes: Vec<String>,
 }
 
 impl Ring {
@@ -17,29 +48,149 @@ impl Ring {
     /// The replicas are the number of times the record is replicated in the hash ring.
     pub fn new(volumes: Vec<String>, replicas: usize, subvolumes: u32) -> Self {
         let mut hashring: HashRing<String> = HashRing::new();
-        hashring.batch_add(volumes);
+        hashring.batch_add(volumes.clone());
         Self {
             hashring,
+            volumes,
             replicas,
             subvolumes,
+            groups: HashMap::new(),
+            placement_rules: Vec::new(),
+        }
+    }
+
+    /// Returns the volumes of the default hash ring, in the order they were added.
+    pub fn volumes(&self) -> &[String] {
+        &self.volumes
+    }
+
+    /// Returns the volumes of the default hash ring and of every volume group, without duplicates.
+    pub fn all_volumes(&self) -> Vec<String> {
+        let mut volumes = self.volumes.clone();
+        let mut names: Vec<&String> = self.groups.keys().collect();
+        names.sort_unstable();
+        for name in names {
+            for volume in self.groups[name].volumes.iter() {
+                if !volumes.contains(volume) {
+                    volumes.push(volume.clone());
+                }
+            }
+        }
+        volumes
+    }
+
+    /// Returns the number of replicas of every record.
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
+
+    /// Adds a volume to the default hash ring.
+    /// Returns false if the ring already has the volume.
+    pub fn add_volume(&mut self, volume: String) -> bool {
+        if self.volumes.contains(&volume) {
+            return false;
         }
+        self.hashring.add(volume.clone());
+        self.volumes.push(volume);
+        true
+    }
+
+    /// Removes a volume from the default hash ring.
+    /// Returns false if the ring doesn't have the volume.
+    pub fn remove_volume(&mut self, volume: &str) -> bool {
+        let Some(position) = self.volumes.iter().position(|v| v == volume) else {
+            return false;
+        };
+        let volume = self.volumes.remove(position);
+        self.hashring.remove(&volume);
+        true
+    }
+
+    /// Adds a named group of volumes with its own hash ring.
+    pub fn add_group(&mut self, name: String, volumes: Vec<String>) -> anyhow::Result<()> {
+        if volumes.is_empty() {
+            anyhow::bail!("Volume group {} has no volumes", name);
+        }
+        let mut hashring: HashRing<String> = HashRing::new();
+        hashring.batch_add(volumes.clone());
+        self.groups.insert(name, Group { hashring, volumes });
+        Ok(())
+    }
+
+    /// Adds a placement rule pinning keys that start with the prefix to a volume group.
+    /// Rules are matched by longest prefix.
+    pub fn add_placement_rule(&mut self, rule: PlacementRule) -> anyhow::Result<()> {
+        if !self.has_group(&rule.group) {
+            anyhow::bail!(
+                "Placement rule for prefix {} references unknown volume group {}",
+                rule.prefix,
+                rule.group
+            );
+        }
+        self.placement_rules.push(rule);
+        Ok(())
+    }
+
+    /// Returns true if a volume group with the given name exists.
+    pub fn has_group(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+    }
+
+    /// Returns the volume group a key is pinned to by the placement rules, if any.
+    pub fn placement_group(&self, key: &str) -> Option<&str> {
+        self.placement_rules
+            .iter()
+            .filter(|rule| key.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+            .map(|rule| rule.group.as_str())
     }
 
     /// Returns the subvolumes that contain a given record.
     /// The replicas are the number of times the record is replicated in the hash ring.
     /// The subvolumes are the subdirectories in each volume that contain the actual data.
     pub fn get_volume(&self, key: &str) -> Vec<String> {
-        let volumes = self
-            .hashring
-            .get_with_replicas(&key, self.replicas)
-            .unwrap();
+        self.get_volume_from(&self.hashring, key, self.replicas)
+    }
+
+    /// Returns the subvolumes that contain a given record placed in a volume group.
+    /// Uses the default hash ring if the group is None or unknown.
+    pub fn get_volume_in_group(&self, key: &str, group: Option<&str>) -> Vec<String> {
+        self.get_volume_with_replicas(key, group, None)
+    }
+
+    /// Returns the subvolumes that contain a given record placed in a volume group,
+    /// replicated the given number of times instead of the replicas of the ring if set.
+    pub fn get_volume_with_replicas(
+        &self,
+        key: &str,
+        group: Option<&str>,
+        replicas: Option<usize>,
+    ) -> Vec<String> {
+        let replicas = replicas.unwrap_or(self.replicas);
+        match group.and_then(|group| self.groups.get(group)) {
+            Some(group) => self.get_volume_from(&group.hashring, key, replicas),
+            None => self.get_volume_from(&self.hashring, key, replicas),
+        }
+    }
+
+    /// Returns the subvolumes that contain a given record in a hash ring, one per replica,
+    /// or every volume of a ring with fewer volumes than replicas.
+    fn get_volume_from(
+        &self,
+        hashring: &HashRing<String>,
+        key: &str,
+        replicas: usize,
+    ) -> Vec<String> {
+        let volumes = hashring.get_with_replicas(&key, replicas).unwrap();
 
         if volumes.len() == 1 {
             return volumes;
         }
 
+        // The ring returns a node more than the replicas, wrapping around to the first one if it has as many
         volumes
             .into_iter()
+            .take(replicas)
             .map(|volume| {
                 let volume_md5 = md5::compute(&volume);
                 let subvolume_hash = (u32::from(volume_md5[12]) << 24)
@@ -56,6 +207,22 @@ impl Ring {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_add_remove_volume() {
+        let volumes = vec!["foo".to_string(), "bar".to_string()];
+        let mut ring = Ring::new(volumes.clone(), 1, 10);
+        let before = ring.get_volume("key");
+
+        assert!(ring.add_volume("baz".to_string()));
+        assert!(!ring.add_volume("baz".to_string()));
+        assert_eq!(ring.volumes(), ["foo", "bar", "baz"]);
+
+        assert!(ring.remove_volume("baz"));
+        assert!(!ring.remove_volume("baz"));
+        assert_eq!(ring.volumes(), volumes);
+        assert_eq!(ring.get_volume("key"), before);
+    }
+
     #[test]
     fn test_new_ring() {
         let mut ring: HashRing<String> = HashRing::new();
@@ -115,10 +282,87 @@ mod tests {
 
         let key = "1";
         let volumes = ring.get_volume(key);
-        assert_eq!(volumes.len(), 4);
-        assert_eq!(volumes[0], "foo/sv00");
-        assert_eq!(volumes[1], "wow/sv05");
-        assert_eq!(volumes[2], "bar/sv02");
-        assert_eq!(volumes[3], "baz/sv06");
+        assert_eq!(volumes, ["foo/sv00", "wow/sv05", "bar/sv02"]);
+
+        // A ring with as many volumes as replicas places a replica on each
+        let ring = Ring::new(vec!["foo".to_string(), "bar".to_string()], 2, 10);
+        assert_eq!(ring.get_volume(key).len(), 2);
+        assert_eq!(ring.get_volume_with_replicas(key, None, Some(1)).len(), 1);
+        assert_eq!(ring.get_volume_with_replicas(key, None, Some(3)).len(), 2);
+    }
+
+    #[test]
+    fn test_placement_rules() -> anyhow::Result<()> {
+        let mut ring = Ring::new(
+            vec!["foo".to_string(), "bar".to_string(), "baz".to_string()],
+            1,
+            10,
+        );
+        ring.add_group(
+            "ssd".to_string(),
+            vec!["ssd1".to_string(), "ssd2".to_string()],
+        )?;
+        ring.add_group("tiny".to_string(), vec!["tiny1".to_string()])?;
+        ring.add_placement_rule(PlacementRule {
+            prefix: "thumbnails/".to_string(),
+            group: "ssd".to_string(),
+        })?;
+        ring.add_placement_rule(PlacementRule {
+            prefix: "thumbnails/tiny/".to_string(),
+            group: "tiny".to_string(),
+        })?;
+
+        assert!(ring
+            .add_placement_rule(PlacementRule {
+                prefix: "logs/".to_string(),
+                group: "hdd".to_string(),
+            })
+            .is_err());
+        assert!(ring.add_group("empty".to_string(), Vec::new()).is_err());
+        assert_eq!(
+            ring.all_volumes(),
+            ["foo", "bar", "baz", "ssd1", "ssd2", "tiny1"]
+        );
+
+        assert_eq!(ring.placement_group("thumbnails/cat.png"), Some("ssd"));
+        assert_eq!(
+            ring.placement_group("thumbnails/tiny/cat.png"),
+            Some("tiny")
+        );
+        assert_eq!(ring.placement_group("images/cat.png"), None);
+
+        let volumes = ring.get_volume_in_group("thumbnails/cat.png", Some("ssd"));
+        assert_eq!(volumes.len(), 1);
+        assert!(volumes
+            .iter()
+            .all(|volume| volume.starts_with("ssd1/") || volume.starts_with("ssd2/")));
+
+        let volumes = ring.get_volume_in_group("thumbnails/tiny/cat.png", Some("tiny"));
+        assert!(volumes.iter().all(|volume| volume.starts_with("tiny1")));
+
+        assert_eq!(
+            ring.get_volume_in_group("images/cat.png", None),
+            ring.get_volume("images/cat.png")
+        );
+        assert_eq!(
+            ring.get_volume_in_group("images/cat.png", Some("hdd")),
+            ring.get_volume("images/cat.png")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_server() {
+        assert_eq!(volume_server("localhost:3001/sv0A"), "localhost:3001");
+        assert_eq!(volume_server("localhost:3001"), "localhost:3001");
+        assert_eq!(
+            volume_server("https://sv1.example.com/sv01"),
+            "https://sv1.example.com"
+        );
+        assert_eq!(
+            volume_server("https://sv1.example.com"),
+            "https://sv1.example.com"
+        );
     }
 }
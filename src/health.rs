@@ -0,0 +1,381 @@
+use axum::http::StatusCode;
+use log::{debug, error, info, warn};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{breaker, hashring, record, tasks};
+
+/// Name of the volume health task in the scheduler.
+pub(crate) const TASK_NAME: &str = "health";
+
+/// Default interval between two probes of the volumes.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default fraction of the volumes that must respond for the server to be ready.
+pub const DEFAULT_READY_FRACTION: f64 = 0.5;
+
+/// Time a volume has to respond to a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Weight of the latest request in the latency and error averages of a volume server.
+const EWMA_ALPHA: f64 = 0.3;
+
+/// Latency a failing volume server is penalized with, at an error rate of 1.
+const ERROR_PENALTY: Duration = Duration::from_secs(1);
+
+/// Key read from the leveldb to check it is reachable, it doesn't need to exist.
+const PROBE_KEY: &str = "healthz";
+
+/// Axum state for health requests.
+pub(crate) struct AppHealthState {
+    pub(crate) leveldb: Arc<record::LevelDb>,
+    pub(crate) hashring: Arc<RwLock<hashring::Ring>>,
+    pub(crate) client: reqwest::Client,
+    pub(crate) ready_fraction: f64,
+}
+
+/// Struct representing the volumes of the default hash ring answering the readiness probe.
+#[derive(Debug, Serialize)]
+pub(crate) struct Readiness {
+    volumes: usize,
+    responding: Vec<String>,
+}
+
+/// Struct representing the exponentially weighted moving averages of the requests to a volume server,
+/// their latency in seconds and the fraction of them that failed.
+#[derive(Debug, Clone, Copy)]
+struct Latency {
+    seconds: f64,
+    errors: f64,
+}
+
+impl Latency {
+    /// Returns the score of the volume server, its latency plus the error penalty. Lower is better.
+    fn score(&self) -> f64 {
+        self.seconds + self.errors * ERROR_PENALTY.as_secs_f64()
+    }
+}
+
+/// Struct tracking the volume servers that failed their last probe, and the latency of the requests
+/// made to every volume server.
+/// Volumes are up until probed, so a server starts serving before the first probe.
+#[derive(Debug, Default)]
+pub(crate) struct VolumeHealth {
+    down: RwLock<HashSet<String>>,
+    latencies: RwLock<HashMap<String, Latency>>,
+}
+
+impl VolumeHealth {
+    /// Returns true unless the server of the volume failed its last probe or its circuit breaker is open.
+    /// Takes replica volumes too, e.g. `localhost:3001/sv0A`.
+    pub(crate) fn is_up(&self, volume: &str) -> bool {
+        let server = hashring::volume_server(volume);
+        !self.down.read().contains(server) && !breaker::BREAKERS.is_open(server)
+    }
+
+    /// Records the outcome of a probe of a volume server.
+    /// Returns true if the volume went up or down.
+    fn set_up(&self, volume: &str, up: bool) -> bool {
+        let mut down = self.down.write();
+        if up {
+            down.remove(volume)
+        } else {
+            down.insert(volume.to_string())
+        }
+    }
+
+    /// Returns the volumes with the up ones first, so down ones are only tried as a last resort.
+    pub(crate) fn up_first<'a>(&self, volumes: &'a [String]) -> Vec<&'a String> {
+        let (mut up, down): (Vec<_>, Vec<_>) = volumes.iter().partition(|v| self.is_up(v));
+        up.extend(down);
+        up
+    }
+
+    /// Records the latency of a request to a volume, and whether it failed, in the averages of its server.
+    /// Takes replica volumes too, e.g. `localhost:3001/sv0A`.
+    pub(crate) fn record_latency(&self, volume: &str, elapsed: Duration, failed: bool) {
+        let server = hashring::volume_server(volume);
+        let seconds = elapsed.as_secs_f64();
+        let errors = if failed { 1.0 } else { 0.0 };
+        let mut latencies = self.latencies.write();
+        match latencies.get_mut(server) {
+            Some(latency) => {
+                latency.seconds += EWMA_ALPHA * (seconds - latency.seconds);
+                latency.errors += EWMA_ALPHA * (errors - latency.errors);
+            }
+            None => {
+                latencies.insert(server.to_string(), Latency { seconds, errors });
+            }
+        }
+    }
+
+    /// Returns the up volumes from the fastest to the slowest, then the down ones in order.
+    /// Volumes without requests recorded yet come first, so they get measured.
+    /// The sort is stable, volumes shuffled beforehand spread the load between equally fast ones.
+    pub(crate) fn fastest_first<'a>(&self, volumes: &[&'a String]) -> Vec<&'a String> {
+        let (mut up, down): (Vec<&String>, Vec<&String>) =
+            volumes.iter().partition(|v| self.is_up(v));
+        let latencies = self.latencies.read();
+        let score = |volume: &str| {
+            latencies
+                .get(hashring::volume_server(volume))
+                .map_or(0.0, Latency::score)
+        };
+        up.sort_by(|a, b| score(a).total_cmp(&score(b)));
+        up.extend(down);
+        up
+    }
+}
+
+/// Struct probing every volume of the ring and updating their health.
+pub(crate) struct HealthChecker {
+    hashring: Arc<RwLock<hashring::Ring>>,
+    client: reqwest::Client,
+    health: Arc<VolumeHealth>,
+}
+
+impl HealthChecker {
+    /// Creates a new checker of the volumes of the ring and its volume groups.
+    pub(crate) fn new(
+        hashring: Arc<RwLock<hashring::Ring>>,
+        client: reqwest::Client,
+        health: Arc<VolumeHealth>,
+    ) -> Self {
+        Self {
+            hashring,
+            client,
+            health,
+        }
+    }
+
+    /// Probes every volume concurrently and marks the ones not responding as down.
+    /// The probe latencies are recorded, so volumes idle for GETs are ranked too.
+    /// Returns the number of volumes down.
+    pub(crate) async fn run(&self) -> usize {
+        let volumes = self.hashring.read().all_volumes();
+        let probes = volumes.iter().map(|volume| async {
+            let start = Instant::now();
+            let up = probe(&self.client, volume).await;
+            (up, start.elapsed())
+        });
+        let results = futures::future::join_all(probes).await;
+
+        let mut down = 0;
+        for (volume, (up, elapsed)) in volumes.iter().zip(results) {
+            self.health.record_latency(volume, elapsed, !up);
+            if !up {
+                down += 1;
+            }
+            match (self.health.set_up(volume, up), up) {
+                (true, true) => info!("health: volume {} is up", volume),
+                (true, false) => warn!("health: volume {} is down", volume),
+                _ => (),
+            }
+        }
+        down
+    }
+}
+
+/// Returns the scheduler task probing the volumes, failing while volumes are down.
+pub(crate) fn task(checker: Arc<HealthChecker>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let checker = checker.clone();
+        Box::pin(async move {
+            let down = checker.run().await;
+            if down > 0 {
+                anyhow::bail!("{} volumes down", down);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Creates the router for the health endpoints.
+pub(crate) fn router(state: Arc<AppHealthState>) -> axum::Router {
+    axum::Router::new()
+        .route("/healthz", axum::routing::get(handle_healthz))
+        .route("/readyz", axum::routing::get(handle_readyz))
+        .with_state(state)
+}
+
+/// Handles GET requests checking the process is up and its leveldb is reachable, for liveness probes.
+/// Returns 200 if a record can be read
+/// Returns 503 if the leveldb fails
+async fn handle_healthz(
+    axum::extract::State(state): axum::extract::State<Arc<AppHealthState>>,
+) -> StatusCode {
+    match state.leveldb.get_record(PROBE_KEY).await {
+        Ok(_) => StatusCode::OK,
+        Err(e) => {
+            error!("healthz: leveldb unreachable: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+/// Handles GET requests checking enough volumes respond to HEAD to serve traffic, for readiness probes.
+/// A volume responds if it answers within the probe timeout without a 5xx.
+/// Returns 200 with the responding volumes as JSON if at least the ready fraction of the volumes respond
+/// Returns 503 with the responding volumes as JSON otherwise
+async fn handle_readyz(
+    axum::extract::State(state): axum::extract::State<Arc<AppHealthState>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let volumes = state.hashring.read().volumes().to_vec();
+    let probes = volumes.iter().map(|volume| probe(&state.client, volume));
+    let responding: Vec<String> = futures::future::join_all(probes)
+        .await
+        .into_iter()
+        .zip(volumes.iter())
+        .filter(|(up, _)| *up)
+        .map(|(_, volume)| volume.clone())
+        .collect();
+
+    let status = if is_ready(responding.len(), volumes.len(), state.ready_fraction) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        volumes: volumes.len(),
+        responding,
+    };
+    (status, axum::Json(readiness)).into_response()
+}
+
+/// Returns true if the volume answers a HEAD of its root in time without a 5xx.
+async fn probe(client: &reqwest::Client, volume: &str) -> bool {
+    let res = client
+        .head(record::volume_url(volume, "/"))
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await;
+    match res {
+        Ok(res) => !res.status().is_server_error(),
+        Err(e) => {
+            debug!("health: volume {} not responding: {}", volume, e);
+            false
+        }
+    }
+}
+
+/// Returns true if enough of the volumes respond. A ring without volumes is never ready.
+fn is_ready(responding: usize, volumes: usize, ready_fraction: f64) -> bool {
+    volumes > 0 && responding as f64 >= ready_fraction * volumes as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[test]
+    fn test_is_ready() {
+        assert!(is_ready(2, 3, 0.5));
+        assert!(!is_ready(1, 3, 0.5));
+        assert!(is_ready(3, 3, 1.0));
+        assert!(!is_ready(2, 3, 1.0));
+        assert!(is_ready(0, 3, 0.0));
+        assert!(!is_ready(0, 0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_health_checker() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let volumes = cluster.volume_addrs().to_vec();
+        let health = Arc::new(VolumeHealth::default());
+        let checker = HealthChecker::new(
+            Arc::new(RwLock::new(hashring::Ring::new(volumes.clone(), 2, 10))),
+            reqwest::Client::new(),
+            health.clone(),
+        );
+
+        assert_eq!(checker.run().await, 0);
+        cluster.volume(1).set_unavailable(true);
+        assert_eq!(checker.run().await, 1);
+        assert!(health.is_up(&volumes[0]));
+        assert!(!health.is_up(&format!("{}/sv03", volumes[1])));
+        assert_eq!(
+            health.up_first(&volumes),
+            [&volumes[0], &volumes[2], &volumes[1]]
+        );
+
+        cluster.volume(1).set_unavailable(false);
+        assert_eq!(checker.run().await, 0);
+        assert!(health.is_up(&volumes[1]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fastest_first() {
+        let volumes: Vec<String> = (1..=4).map(|i| format!("localhost:300{}", i)).collect();
+        let replicas = [
+            format!("{}/sv01", volumes[0]),
+            format!("{}/sv02", volumes[1]),
+            format!("{}/sv03", volumes[2]),
+            format!("{}/sv04", volumes[3]),
+        ];
+        let replicas: Vec<&String> = replicas.iter().collect();
+        let health = VolumeHealth::default();
+        assert_eq!(health.fastest_first(&replicas), replicas);
+
+        health.record_latency(&volumes[0], Duration::from_millis(200), false);
+        health.record_latency(&volumes[1], Duration::from_millis(5), false);
+        health.record_latency(&volumes[2], Duration::from_millis(20), false);
+        // Unmeasured volumes are tried first, then the fastest
+        assert_eq!(
+            health.fastest_first(&replicas),
+            [replicas[3], replicas[1], replicas[2], replicas[0]]
+        );
+
+        // A fast volume failing falls behind slower ones, a down one is last
+        health.record_latency(&volumes[3], Duration::from_millis(1), false);
+        health.record_latency(&volumes[1], Duration::from_millis(5), true);
+        health.set_up(&volumes[3], false);
+        assert_eq!(
+            health.fastest_first(&replicas),
+            [replicas[2], replicas[0], replicas[1], replicas[3]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{}/healthz", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client
+            .get(format!("{}/readyz", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        cluster.volume(0).set_unavailable(true);
+        cluster.volume(1).set_unavailable(true);
+        let res = client
+            .get(format!("{}/readyz", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: serde_json::Value = res.json().await?;
+        assert_eq!(readiness["volumes"], 3);
+        assert_eq!(
+            readiness["responding"],
+            serde_json::json!([cluster.volume_addrs()[2]])
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use axum::http::StatusCode;
+use serde::Serialize;
+
+/// Longest key accepted by default, in bytes. The file name of a value on the volumes is the base64 of its key,
+/// 4/3 as long, which stays under the 255 bytes most file systems allow with the upload suffixes.
+pub const DEFAULT_MAX_KEY_LENGTH: usize = 180;
+
+/// Enum representing the characters allowed in keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyCharset {
+    /// Any character but control characters.
+    #[default]
+    Unicode,
+    /// Printable ASCII characters, space included.
+    Ascii,
+    /// ASCII letters, digits, `/` and `!-_.*'()`, the characters S3 calls safe, and the `:` of `cas:` and
+    /// namespaced keys. Rejects `%`, so keys percent-encoded twice by a client are caught instead of stored
+    /// with their escapes.
+    Safe,
+}
+
+impl KeyCharset {
+    /// Returns true if the character is allowed in keys.
+    fn allows(&self, c: char) -> bool {
+        match self {
+            KeyCharset::Unicode => !c.is_control(),
+            KeyCharset::Ascii => c.is_ascii() && !c.is_ascii_control(),
+            KeyCharset::Safe => {
+                c.is_ascii_alphanumeric()
+                    || matches!(
+                        c,
+                        '/' | ':' | '!' | '-' | '_' | '.' | '*' | '\'' | '(' | ')'
+                    )
+            }
+        }
+    }
+}
+
+/// Struct representing the keys the index accepts, checked once the path is percent-decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// Longest key in bytes, namespaced keys counting their `ns:<namespace>/` prefix.
+    pub max_length: usize,
+    pub charset: KeyCharset,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_length: DEFAULT_MAX_KEY_LENGTH,
+            charset: KeyCharset::default(),
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Checks a key, as stored in the index. Besides the length and the characters, `.` and `..` path segments
+    /// are rejected whatever the charset: HTTP clients resolve them, so such keys can't be reached by URL.
+    pub(crate) fn check(&self, key: &str) -> Result<(), KeyError> {
+        if key.len() > self.max_length {
+            return Err(KeyError(format!(
+                "key of {} bytes exceeds the max key length of {} bytes",
+                key.len(),
+                self.max_length
+            )));
+        }
+        if let Some(c) = key.chars().find(|c| !self.charset.allows(*c)) {
+            return Err(KeyError(format!(
+                "key contains {:?}, not allowed by the {:?} charset",
+                c, self.charset
+            )));
+        }
+        if key
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+        {
+            return Err(KeyError("key contains a . or .. path segment".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Struct representing why a key was rejected, returned as a 400 with a JSON body,
+/// `{"error": "invalid_key", "message": "..."}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KeyError(pub(crate) String);
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Struct representing the JSON body of a rejected key.
+#[derive(Debug, Serialize)]
+struct KeyErrorBody<'a> {
+    error: &'static str,
+    message: &'a str,
+}
+
+impl axum::response::IntoResponse for KeyError {
+    fn into_response(self) -> axum::response::Response {
+        let body = KeyErrorBody {
+            error: "invalid_key",
+            message: &self.0,
+        };
+        (StatusCode::BAD_REQUEST, axum::Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_policy() {
+        let policy = KeyPolicy::default();
+        for key in ["images/2024/cat.png", "ünïcode key", "a..b/.c", "ns:app/x"] {
+            assert_eq!(policy.check(key), Ok(()), "{}", key);
+        }
+        for key in ["a\nb", "tab\t", "./a", "a/../b", "..", &"a".repeat(181)] {
+            assert!(policy.check(key).is_err(), "{:?}", key);
+        }
+
+        let ascii = KeyPolicy {
+            charset: KeyCharset::Ascii,
+            ..KeyPolicy::default()
+        };
+        assert!(ascii.check("with space %20").is_ok());
+        assert!(ascii.check("ünïcode").is_err());
+
+        let safe = KeyPolicy {
+            max_length: 8,
+            charset: KeyCharset::Safe,
+        };
+        assert!(safe.check("a/b-c_(1)").is_err());
+        assert!(safe.check("a/b-c_1").is_ok());
+        assert!(safe.check("ns:a/b").is_ok());
+        assert!(safe.check("a%2Fb").is_err());
+        assert!(safe.check("a b").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! minikeyvalue, a distributed key value store with an index server placing the values on
+//! volume servers through a consistent hash ring.
+//!
+//! The index server can be embedded in another service, or started in-process by tests:
+//!
+//! ```no_run
+//! use rust_minikeyvalue::{Config, Listeners, Server};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let config = Config {
+//!     leveldb_path: "/tmp/indexdb".into(),
+//!     volumes: vec!["localhost:3001".to_string(), "localhost:3002".to_string()],
+//!     replicas: 2,
+//!     ..Default::default()
+//! };
+//! config.validate()?;
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+//! Server::new(config)
+//!     .serve(Listeners::new(listener), async {
+//!         let _ = tokio::signal::ctrl_c().await;
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The records can be kept in a store of the embedding service by implementing [`MetadataStore`]
+//! and passing it to [`Server::with_store`].
+//!
+//! [`MiniKvClient`] talks to a running index server, following the redirects to the volumes.
+
+mod access;
+mod admin;
+pub mod auth;
+mod backup;
+mod batch;
+pub mod breaker;
+#[cfg(feature = "chaos")]
+mod chaos;
+pub mod checksum;
+pub mod client;
+pub mod compress;
+pub mod cors;
+mod dedup;
+mod drain;
+pub mod encryption;
+pub mod erasure;
+mod expiry;
+pub mod fanout;
+pub mod fsck;
+pub mod gc;
+mod go_import;
+mod grpc;
+pub mod hashring;
+pub mod health;
+pub mod keys;
+pub mod limits;
+mod locks;
+/// Maintenance commands run offline against the metadata store and the volumes,
+/// printing their stats as JSON and failing if any record failed.
+pub mod maintenance;
+mod metrics;
+mod mirror;
+mod namespace;
+pub mod presign;
+mod rebalance;
+mod rebuild;
+pub mod record;
+mod reload;
+mod repair;
+mod replication;
+mod report;
+mod resp;
+mod s3;
+pub mod server;
+mod spool;
+mod tasks;
+pub mod telemetry;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod value_cache;
+pub mod volume;
+mod volume_keys;
+
+pub use client::{BlockingMiniKvClient, MiniKvClient};
+pub use hashring::Ring;
+pub use record::{MetadataStore, Record};
+pub use server::{Config, Listeners, Server};
@@ -0,0 +1,242 @@
+use axum::extract::ConnectInfo;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Paths served whatever the load, so probes don't fail a saturated server.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// Clients tracked before the ones that used none of their rate are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Struct representing the limits of the requests the server takes on, None for no limit.
+/// Rates are in requests per second, a second worth of requests can come in at once.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LimitsConfig {
+    /// Requests per second of all clients together, over it requests are answered 429.
+    pub rate: Option<u32>,
+    /// Requests per second of each client IP, over it its requests are answered 429.
+    pub client_rate: Option<u32>,
+    /// Requests served at once, over it requests are answered 503.
+    pub max_in_flight: Option<usize>,
+}
+
+impl LimitsConfig {
+    /// Returns true if any limit is set.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.rate.is_some() || self.client_rate.is_some() || self.max_in_flight.is_some()
+    }
+}
+
+/// Struct representing a token bucket refilled at a rate, holding a second worth of tokens.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self {
+            tokens: rate,
+            updated_at: now,
+        }
+    }
+
+    /// Refills the bucket up to now.
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated_at = now;
+    }
+
+    /// Takes a token, or returns the time until one is available.
+    fn take(&mut self, rate: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// Struct representing a request turned away, answered with its status and a `Retry-After` in seconds.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Rejection {
+    status: StatusCode,
+    retry_after: u64,
+}
+
+impl Rejection {
+    /// Returns the rejection of a rate limit, retried once a token is available.
+    fn rate_limited(wait: Duration) -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: wait.as_secs_f64().ceil().max(1.0) as u64,
+        }
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> axum::response::Response {
+        (self.status, [(header::RETRY_AFTER, self.retry_after)]).into_response()
+    }
+}
+
+/// Struct enforcing the limits of the config on the requests of every router of the server.
+pub(crate) struct Limiter {
+    config: LimitsConfig,
+    global: Mutex<Bucket>,
+    clients: Mutex<HashMap<IpAddr, Bucket>>,
+    in_flight: Option<Arc<tokio::sync::Semaphore>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(config: LimitsConfig) -> Arc<Self> {
+        let now = Instant::now();
+        Arc::new(Self {
+            config,
+            global: Mutex::new(Bucket::new(config.rate.unwrap_or(0) as f64, now)),
+            clients: Mutex::new(HashMap::new()),
+            in_flight: config
+                .max_in_flight
+                .map(|max| Arc::new(tokio::sync::Semaphore::new(max))),
+        })
+    }
+
+    /// Admits a request of a client, returning the permit to hold while it is served if requests in flight
+    /// are limited, or the rejection of the first limit it exceeds.
+    fn admit(
+        &self,
+        client: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, Rejection> {
+        let permit = match &self.in_flight {
+            Some(in_flight) => {
+                Some(
+                    in_flight
+                        .clone()
+                        .try_acquire_owned()
+                        .map_err(|_| Rejection {
+                            status: StatusCode::SERVICE_UNAVAILABLE,
+                            retry_after: 1,
+                        })?,
+                )
+            }
+            None => None,
+        };
+        if let (Some(rate), Some(client)) = (self.config.client_rate, client) {
+            let rate = rate as f64;
+            let mut clients = self.clients.lock();
+            if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+                // Clients whose bucket refilled are tracked again from a full bucket, like new ones
+                clients.retain(|_, bucket| {
+                    bucket.refill(rate, now);
+                    bucket.tokens < rate
+                });
+            }
+            clients
+                .entry(client)
+                .or_insert_with(|| Bucket::new(rate, now))
+                .take(rate, now)
+                .map_err(Rejection::rate_limited)?;
+        }
+        if let Some(rate) = self.config.rate {
+            self.global
+                .lock()
+                .take(rate as f64, now)
+                .map_err(Rejection::rate_limited)?;
+        }
+        Ok(permit)
+    }
+}
+
+/// Middleware answering 429 to the requests over the global or per client IP rate, and 503 to the
+/// requests over the max in flight, both with a `Retry-After`. Probes are always served.
+pub(crate) async fn limit_requests(
+    axum::extract::State(limiter): axum::extract::State<Arc<Limiter>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    match limiter.admit(client, Instant::now()) {
+        Ok(_permit) => next.run(request).await,
+        Err(rejection) => {
+            log::debug!(
+                "limits: {} {} rejected with {}",
+                request.method(),
+                request.uri().path(),
+                rejection.status
+            );
+            rejection.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates() {
+        let limiter = Limiter::new(LimitsConfig {
+            rate: Some(4),
+            client_rate: Some(2),
+            max_in_flight: None,
+        });
+        let now = Instant::now();
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        assert!(limiter.admit(Some(a), now).is_ok());
+        assert!(limiter.admit(Some(a), now).is_ok());
+        assert_eq!(
+            limiter.admit(Some(a), now).unwrap_err(),
+            Rejection {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                retry_after: 1
+            }
+        );
+        assert!(limiter.admit(Some(b), now).is_ok());
+        // Requests without a client address only count against the global rate
+        assert!(limiter.admit(None, now).is_ok());
+        assert!(limiter.admit(None, now).is_err());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.admit(Some(a), later).is_ok());
+        assert!(limiter.admit(Some(a), later).is_err());
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let limiter = Limiter::new(LimitsConfig {
+            max_in_flight: Some(1),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let permit = limiter.admit(None, now).unwrap();
+        assert_eq!(
+            limiter.admit(None, now).unwrap_err(),
+            Rejection {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                retry_after: 1
+            }
+        );
+        drop(permit);
+        assert!(limiter.admit(None, now).is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+/// Struct representing the per key locks of PUT, DELETE and the background repairs.
+/// Writers of the same key queue on an async mutex for up to the wait timeout,
+/// the mutex is removed from the map once its last holder and waiter are gone.
+pub(crate) struct KeyLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    timeout: Duration,
+}
+
+/// RAII guard of a locked key, unlocks the key when dropped, even on panic.
+pub(crate) struct KeyGuard {
+    locks: Arc<KeyLocks>,
+    key: String,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl KeyLocks {
+    /// Creates the locks, waiting up to the timeout for a locked key.
+    pub(crate) fn new(timeout: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            locks: Mutex::new(HashMap::new()),
+            timeout,
+        })
+    }
+
+    /// Locks a key, waiting up to the timeout for the current holder to unlock it.
+    /// Returns None if the key is still locked at the timeout.
+    #[tracing::instrument(name = "lock_wait", skip(self))]
+    pub(crate) async fn lock(self: &Arc<Self>, key: &str) -> Option<KeyGuard> {
+        let mutex = self.mutex(key);
+        let guard = tokio::time::timeout(self.timeout, mutex.lock_owned()).await;
+        self.guard(key, guard.ok())
+    }
+
+    /// Locks a key without waiting. Returns None if the key is locked.
+    pub(crate) fn try_lock(self: &Arc<Self>, key: &str) -> Option<KeyGuard> {
+        let mutex = self.mutex(key);
+        self.guard(key, mutex.try_lock_owned().ok())
+    }
+
+    /// Returns the mutex of a key, creating it if no one holds or waits for the key.
+    fn mutex(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Wraps the mutex guard of a key, or removes the unused mutex if the key wasn't locked.
+    fn guard(
+        self: &Arc<Self>,
+        key: &str,
+        guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    ) -> Option<KeyGuard> {
+        let guard = KeyGuard {
+            locks: self.clone(),
+            key: key.to_string(),
+            guard,
+        };
+        guard.guard.is_some().then_some(guard)
+    }
+
+    /// Returns the number of keys locked or waited for.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().len()
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock();
+        // Unlock before checking the holders, so only the map is left if nobody waits
+        drop(self.guard.take());
+        if let Some(mutex) = locks.get(&self.key) {
+            if Arc::strong_count(mutex) == 1 {
+                locks.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_key_locks() {
+        let locks = KeyLocks::new(Duration::from_millis(50));
+        let guard = locks.lock("a").await.unwrap();
+        assert!(locks.try_lock("a").is_none());
+        assert!(locks.lock("a").await.is_none());
+        assert!(locks.try_lock("b").is_some());
+        assert_eq!(locks.len(), 1);
+
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            async move { locks.lock("a").await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(guard);
+        assert!(waiter.await.unwrap());
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_key_locks_unlock_on_panic() {
+        let locks = KeyLocks::new(Duration::from_millis(50));
+        let task = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                let _guard = locks.lock("a").await;
+                panic!("writer panicked");
+            }
+        });
+        assert!(task.await.is_err());
+        assert!(locks.try_lock("a").is_some());
+        assert_eq!(locks.len(), 0);
+    }
+}
@@ -1,79 +1,1245 @@
-use clap::Parser;
-use std::path::Path;
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches, Subcommand};
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-mod hashring;
-mod record;
-mod server;
+use rust_minikeyvalue::{
+    auth, breaker, checksum, client, compress, cors, encryption, erasure, fanout, fsck, gc,
+    hashring, health, keys, limits, maintenance, presign, record, server, telemetry, value_cache,
+    volume,
+};
+
+mod config;
 
 /// minikeyvalue cli
-#[derive(Parser, Debug)]
+#[derive(clap::Parser, Debug)]
 #[clap(
     version = "0.1.0",
     author = "Arnau Diaz <arnaudiaz@duck.com>",
-    about = "minikeyvalue cli"
+    about = "minikeyvalue cli",
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
     /// Sets logging to "debug" level, defaults to "info"
     #[clap(short, long, global = true)]
     verbose: bool,
 
+    /// Runs a maintenance command instead of the server
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Reads the server flags from a TOML file, flags given on the command line override it
+    #[clap(long)]
+    config: Option<PathBuf>,
+
     /// Sets the port to listen on
     #[clap(short, long, default_value = "3000")]
     port: u16,
 
-    /// Sets the path to the leveldb
+    /// Serves PUT, DELETE and /admin on a separate address, e.g. "10.0.0.5:3100".
+    /// The port then only serves GET and HEAD of keys
+    #[clap(long)]
+    internal_addr: Option<std::net::SocketAddr>,
+
+    /// Serves the S3 compatible gateway on an address, e.g. "0.0.0.0:3200".
+    /// Signatures aren't checked, so it conflicts with the auth tokens
+    #[clap(long, conflicts_with_all = ["auth_token", "auth_token_file"])]
+    s3_addr: Option<std::net::SocketAddr>,
+
+    /// Serves GET, SET, DEL, EXISTS and TTL over the Redis protocol on an address, e.g. "0.0.0.0:6379".
+    /// Connections authenticate with AUTH and a bearer token when tokens are required
+    #[clap(long)]
+    resp_addr: Option<std::net::SocketAddr>,
+
+    /// Serves the gRPC service on an address, e.g. "0.0.0.0:3300"
+    #[clap(long)]
+    grpc_addr: Option<std::net::SocketAddr>,
+
+    /// Serves /healthz, /readyz, /metrics and /admin on a separate port instead of the other listeners
+    #[clap(long)]
+    admin_port: Option<u16>,
+
+    /// Binds the --admin-port to localhost only
+    #[clap(long, requires = "admin_port")]
+    admin_localhost: bool,
+
+    /// Serves HTTPS with the PEM certificate chain, reloaded on SIGHUP. Requires --tls-key
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Sets the PEM private key of the TLS certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    #[clap(flatten)]
+    volume_tls: VolumeTlsArgs,
+
+    /// Requires the bearer token with write access on every request but /healthz and /readyz
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Requires one of the bearer tokens of the file, one "<read|write> <token>" per line
+    #[clap(long)]
+    auth_token_file: Option<PathBuf>,
+
+    /// Sets the path to the leveldb, required unless set in the config file
     #[clap(short, long)]
-    leveldb_path: String,
+    leveldb_path: Option<String>,
+
+    /// Sets the metadata store backing the index
+    #[clap(long, value_enum, default_value_t)]
+    db_backend: record::DbBackend,
+
+    /// Sets the encoding the records are written with, records of both encodings are read
+    #[clap(long, value_enum, default_value_t)]
+    record_encoding: record::RecordEncoding,
+
+    /// Sets the records kept in memory in front of the metadata store, disabled if not set
+    #[clap(long)]
+    record_cache: Option<usize>,
+
+    /// Sets the keys found without a record remembered as missing, so that lookups of them skip the
+    /// metadata store, disabled if not set
+    #[clap(long)]
+    negative_cache: Option<usize>,
 
-    /// Calculate and store the MD5 checksum of values
+    /// Sets the seconds a key is remembered as missing by the negative cache
+    #[clap(long, default_value_t = record::DEFAULT_NEGATIVE_CACHE_TTL_SECS)]
+    negative_cache_ttl_secs: u64,
+
+    /// Sets the bytes of small values the index keeps in memory and serves with 200 instead of a redirect,
+    /// disabled if not set
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    value_cache: Option<u64>,
+
+    /// Sets the largest value of the value cache in bytes
+    #[clap(long, default_value_t = value_cache::DEFAULT_MAX_VALUE_SIZE, value_parser = clap::value_parser!(u64).range(1..))]
+    value_cache_max_size: u64,
+
+    /// Sets the seconds a cached value is served before it is read again from a volume
+    #[clap(long, default_value_t = value_cache::DEFAULT_TTL_SECS)]
+    value_cache_ttl_secs: u64,
+
+    /// Calculate and store the hash of values, false is the same as --checksum none
     #[clap(long, default_value = "true")]
     hash_md5_checksum: bool,
 
+    /// Sets the algorithm of the hash stored with values and returned as ETag
+    #[clap(long, value_enum, default_value = "md5")]
+    checksum: checksum::Checksum,
+
+    /// Calculate and store extra digests of values, e.g. "sha256,crc32c"
+    #[clap(long, value_delimiter = ',')]
+    checksum_algorithms: Vec<checksum::Algorithm>,
+
     /// Sets the volumes
     #[clap(long, value_delimiter = ',')]
     volumes: Vec<String>,
 
+    /// Streams values through the index on GET instead of redirecting to the volumes,
+    /// `?proxy=0` still redirects
+    #[clap(long)]
+    default_proxy: bool,
+
+    /// Sets the status of the redirects of GETs to the volumes
+    #[clap(long, value_enum, default_value_t)]
+    redirect_status: server::RedirectStatus,
+
+    /// Sets the Cache-Control of the redirects of GETs to the volumes, empty to send none
+    #[clap(long, default_value = server::DEFAULT_REDIRECT_CACHE_CONTROL)]
+    redirect_cache_control: String,
+
+    /// HEADs every replica of a GET concurrently and redirects to the first one holding the value,
+    /// instead of trying them one after the other from the fastest
+    #[clap(long)]
+    concurrent_heads: bool,
+
     /// Sets the number of replicas
     #[clap(long, default_value = "3")]
     replicas: usize,
 
+    /// Sets the highest number of replicas a PUT can ask for with X-Replicas, defaults to --replicas
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_replicas: Option<u64>,
+
+    /// Sets the number of replicas of the keys of a namespace written without X-Replicas, e.g. "logs=1"
+    #[clap(long = "namespace-replicas", value_parser = parse_namespace_replicas)]
+    namespace_replicas: Vec<(String, usize)>,
+
+    /// Sets the longest key accepted in bytes, longer keys return 400
+    #[clap(long, default_value_t = keys::DEFAULT_MAX_KEY_LENGTH, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_key_length: usize,
+
+    /// Sets the characters allowed in keys, others return 400
+    #[clap(long, value_enum, default_value_t)]
+    key_charset: keys::KeyCharset,
+
+    /// Sets the largest value a PUT can upload in bytes, larger bodies return 413 as soon as they exceed it
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_value_size: Option<u64>,
+
+    /// Sets the number of replica writes that must succeed for a PUT, defaults to every replica
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    write_quorum: Option<u64>,
+
+    /// Sets the number of attempts of a replica write failing to connect or with a 5xx, 1 disables retries
+    #[clap(long, default_value = "3")]
+    write_retry_attempts: u32,
+
+    /// Sets the milliseconds before the first retry of a replica write, doubled after every attempt
+    #[clap(long, default_value = "100")]
+    write_retry_backoff_ms: u64,
+
+    /// Sets the maximum milliseconds between two attempts of a replica write
+    #[clap(long, default_value = "2000")]
+    write_retry_max_backoff_ms: u64,
+
+    /// Sets the fraction of the retry delay that is randomized, between 0 and 1
+    #[clap(long, default_value = "0.5")]
+    write_retry_jitter: f64,
+
+    /// Uploads the replicas of a PUT to a temporary path and moves them into place once enough replicas
+    /// hold the whole value, the volumes must allow WebDAV MOVE
+    #[clap(long)]
+    two_phase_writes: bool,
+
+    /// Stores large values as Reed-Solomon data and parity shards on distinct volumes instead of replicas,
+    /// e.g. "4+2"
+    #[clap(long, value_parser = parse_erasure_coding)]
+    erasure_coding: Option<(usize, usize)>,
+
+    /// Sets the smallest value in bytes stored with --erasure-coding, smaller values are replicated
+    #[clap(long, default_value_t = erasure::DEFAULT_MIN_SIZE)]
+    erasure_min_size: u64,
+
+    /// Stores a value PUT under several keys once, the keys sharing it by the SHA-256 of the value
+    #[clap(long)]
+    dedup: bool,
+
+    /// Compresses values with zstd before writing them to the volumes, "zstd" or "zstd:level" from 1 to 22.
+    /// Values already compressed, e.g. images or archives, are stored as uploaded
+    #[clap(long, value_parser = parse_compression)]
+    compress: Option<i32>,
+
+    /// Encrypts values with AES-256-GCM before writing them to the volumes, each with a data key of its own
+    /// wrapped by the master key of the file, 32 bytes raw or hex encoded. Conflicts with --dedup
+    #[clap(long, conflicts_with = "dedup")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Returns volume URLs signed with the HMAC key of the file on `GET /key?presign&expires=N`, and upload
+    /// URLs on `POST /key?presign`, valid for N seconds. The volumes, or their nginx `auth_request` to
+    /// /presign/verify, check them
+    #[clap(long)]
+    presign_key_file: Option<PathBuf>,
+
+    /// Replaces a volume address in presigned URLs with its public address, e.g.
+    /// "localhost:3001=files1.example.com:8001". Requires --presign-key-file
+    #[clap(long = "presign-volume", value_parser = parse_presign_volume, requires = "presign_key_file")]
+    presign_volumes: Vec<(String, String)>,
+
+    /// Sets the fraction of failed requests to a volume in 10 seconds that opens its circuit breaker
+    #[clap(long, default_value = "0.5")]
+    breaker_error_rate: f64,
+
+    /// Sets the requests to a volume in 10 seconds before its error rate is checked, 0 disables the breakers
+    #[clap(long, default_value = "20")]
+    breaker_min_requests: u32,
+
+    /// Sets the seconds an open circuit breaker skips its volume before a trial request
+    #[clap(long, default_value = "30")]
+    breaker_open_secs: u64,
+
+    /// Sets the PUTs of replicas, parts and shards to all the volumes in flight at once,
+    /// the next ones wait for one to complete, unbounded if not set
+    #[clap(long)]
+    max_replica_puts: Option<usize>,
+
+    /// Sets the PUTs to each volume in flight at once, the next ones wait for one to complete, unbounded if not set
+    #[clap(long)]
+    max_replica_puts_per_volume: Option<usize>,
+
     /// Sets the number of subvolumes
     #[clap(long, default_value = "10")]
     subvolumes: u32,
+
+    /// Adds a named volume group, e.g. "ssd=localhost:3006,localhost:3007"
+    #[clap(long = "volume-group", value_parser = parse_volume_group)]
+    volume_groups: Vec<(String, Vec<String>)>,
+
+    /// Pins keys starting with a prefix to a volume group, e.g. "thumbnails/=ssd"
+    #[clap(long = "placement-rule", value_parser = parse_placement_rule)]
+    placement_rules: Vec<hashring::PlacementRule>,
+
+    /// Overrides the interval in seconds of a background task, e.g. "repair=600", 0 starts it paused
+    #[clap(long = "task-schedule", value_parser = parse_task_schedule)]
+    task_schedules: Vec<(String, Duration)>,
+
+    /// Sets the milliseconds a PUT or DELETE waits for another write of its key before returning 409
+    #[clap(long, default_value = "5000")]
+    lock_timeout_ms: u64,
+
+    /// Sets the seconds to wait on shutdown for open connections, in-flight writes and background tasks
+    #[clap(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// Sets the seconds to connect to a volume, 0 waits for the OS
+    #[clap(long, default_value = "5")]
+    volume_connect_timeout: u64,
+
+    /// Sets the seconds a request to a volume has to complete, body included, 0 waits forever
+    #[clap(long, default_value = "0")]
+    volume_request_timeout: u64,
+
+    /// Sets the idle connections kept open to each volume, unbounded if not set
+    #[clap(long)]
+    volume_pool_max_idle: Option<usize>,
+
+    /// Sets the seconds an idle connection to a volume is kept open, 0 keeps it until the volume closes it
+    #[clap(long, default_value = "90")]
+    volume_pool_idle_timeout: u64,
+
+    /// Talks HTTP/2 to the volumes without negotiating it first, every volume must support it
+    #[clap(long)]
+    volume_http2: bool,
+
+    /// Sets the seconds between TCP keep-alive probes, and HTTP/2 pings with --volume-http2,
+    /// on the connections to the volumes, 0 sends none
+    #[clap(long, default_value = "0")]
+    volume_keep_alive: u64,
+
+    /// Sets the seconds a request has to get its response before returning 504, 0 waits forever
+    #[clap(long, default_value = "0")]
+    request_deadline: u64,
+
+    /// Sets the requests per second of all clients together before returning 429, unlimited if not set
+    #[clap(long)]
+    rate_limit: Option<u32>,
+
+    /// Sets the requests per second of each client IP before returning 429, unlimited if not set
+    #[clap(long)]
+    client_rate_limit: Option<u32>,
+
+    /// Sets the requests served at once before returning 503, unlimited if not set
+    #[clap(long)]
+    max_in_flight: Option<usize>,
+
+    /// Lets browsers call the API from these origins, e.g. "https://app.example.com", or "*" for any origin.
+    /// CORS is disabled if not set
+    #[clap(long, value_delimiter = ',')]
+    cors_origins: Vec<String>,
+
+    /// Sets the methods the allowed origins may use
+    #[clap(long, value_delimiter = ',', value_parser = parse_method, default_value = "GET,HEAD,PUT,POST,DELETE")]
+    cors_methods: Vec<axum::http::Method>,
+
+    /// Sets the request headers the allowed origins may send, any header they ask for if not set
+    #[clap(long, value_delimiter = ',')]
+    cors_headers: Vec<String>,
+
+    /// Sets the seconds browsers cache the answer of a preflight request
+    #[clap(long, default_value = "600")]
+    cors_max_age: u64,
+
+    /// Sets the fraction of the volumes that must respond to HEAD for /readyz to report ready
+    #[clap(long, default_value_t = health::DEFAULT_READY_FRACTION)]
+    ready_fraction: f64,
+
+    /// Sets the seconds a deleted key can be recovered before the gc task deletes its value
+    #[clap(long, default_value_t = gc::DEFAULT_GRACE_PERIOD.as_secs())]
+    gc_grace_secs: u64,
+
+    /// Moves up to this many misplaced keys per second to their volumes in the ring in the background,
+    /// so the values converge after the volumes change without running rebalance
+    #[clap(long)]
+    auto_rebalance: Option<u32>,
+
+    /// Exports the spans of the requests, lock waits, volume requests and metadata store operations
+    /// to the OTLP gRPC endpoint of a collector, e.g. http://localhost:4317
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Logs a line per request with its client, method, path, status, bytes, duration and redirect target,
+    /// false to disable it
+    #[clap(long, default_value = "true")]
+    access_log: bool,
+}
+
+/// Flags of the TLS connections to `https://` volumes
+#[derive(clap::Args, Debug, Default)]
+struct VolumeTlsArgs {
+    /// Trusts only volume certificates signed by the PEM CA, instead of the system roots
+    #[clap(long)]
+    volume_ca: Option<PathBuf>,
+
+    /// Sends the PEM client certificate to volumes requiring mutual TLS. Requires --volume-key
+    #[clap(long, requires = "volume_key")]
+    volume_cert: Option<PathBuf>,
+
+    /// Sets the PEM private key of the volume client certificate, in PKCS#8
+    #[clap(long, requires = "volume_cert")]
+    volume_key: Option<PathBuf>,
+}
+
+/// Flags of the connection to a running index server
+#[derive(clap::Args, Debug)]
+struct ClientArgs {
+    /// Sets the url of the index server
+    #[clap(long, default_value = "http://localhost:3000")]
+    url: String,
+
+    /// Sends the bearer token to the index server
+    #[clap(long)]
+    token: Option<String>,
+}
+
+impl ClientArgs {
+    /// Returns a client of the index server, verifying the MD5 of the values.
+    fn client(self) -> anyhow::Result<client::MiniKvClient> {
+        let client = client::MiniKvClient::new(&self.url)?.with_checksum_verification(true);
+        Ok(match self.token {
+            Some(token) => client.with_token(token),
+            None => client,
+        })
+    }
+}
+
+impl VolumeTlsArgs {
+    /// Returns the TLS configuration of the connections to the volumes.
+    fn config(self) -> server::VolumeTlsConfig {
+        server::VolumeTlsConfig {
+            ca: self.volume_ca,
+            client_cert: self.volume_cert,
+            client_key: self.volume_key,
+        }
+    }
+}
+
+/// Maintenance and client commands
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Reports object counts and logical bytes per volume and subvolume.
+    /// Reads the leveldb directly, use GET /admin/report on a running server.
+    Report {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Prints the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Copies the live objects under a prefix from one cluster to another, streamed and
+    /// checksum verified. Re-runs only copy keys missing or changed in the destination.
+    Mirror {
+        /// Sets the url of the source index server, e.g. "http://clusterA:3000"
+        #[clap(long)]
+        src: String,
+
+        /// Sets the url of the destination index server, e.g. "http://clusterB:3000"
+        #[clap(long)]
+        dst: String,
+
+        /// Mirrors only the keys starting with a prefix
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Sets the number of objects copied concurrently
+        #[clap(long, default_value = "8")]
+        concurrency: usize,
+    },
+    /// Restores a metadata dump and a blobs archive after a full-cluster loss.
+    /// Writes the records to the leveldb directly and uploads the values to the current ring.
+    Restore {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the path to the metadata dump, one JSON record per line
+        #[clap(long)]
+        metadata: PathBuf,
+
+        /// Sets the path to the tar archive of values, one file per key
+        #[clap(long)]
+        blobs: PathBuf,
+
+        /// Sets the volumes
+        #[clap(long, value_delimiter = ',')]
+        volumes: Vec<String>,
+
+        /// Sets the number of replicas
+        #[clap(long, default_value = "3")]
+        replicas: usize,
+
+        /// Sets the number of subvolumes
+        #[clap(long, default_value = "10")]
+        subvolumes: u32,
+
+        /// Adds a named volume group, e.g. "ssd=localhost:3006,localhost:3007"
+        #[clap(long = "volume-group", value_parser = parse_volume_group)]
+        volume_groups: Vec<(String, Vec<String>)>,
+
+        #[clap(flatten)]
+        volume_tls: VolumeTlsArgs,
+    },
+
+    /// Rebuilds the leveldb from the blobs stored in the volumes after the leveldb is lost.
+    /// Walks the volume listings, keys that already have a record are kept.
+    Rebuild {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the volumes
+        #[clap(long, value_delimiter = ',')]
+        volumes: Vec<String>,
+
+        /// Sets the number of replicas
+        #[clap(long, default_value = "3")]
+        replicas: usize,
+
+        /// Sets the number of subvolumes
+        #[clap(long, default_value = "10")]
+        subvolumes: u32,
+
+        /// Adds a named volume group, e.g. "ssd=localhost:3006,localhost:3007"
+        #[clap(long = "volume-group", value_parser = parse_volume_group)]
+        volume_groups: Vec<(String, Vec<String>)>,
+
+        /// Pins keys starting with a prefix to a volume group, e.g. "thumbnails/=ssd"
+        #[clap(long = "placement-rule", value_parser = parse_placement_rule)]
+        placement_rules: Vec<hashring::PlacementRule>,
+
+        #[clap(flatten)]
+        volume_tls: VolumeTlsArgs,
+    },
+
+    /// Moves the values of the records to the volumes of the current ring after volumes are
+    /// added or removed, with the index server stopped.
+    Rebalance {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the volumes
+        #[clap(long, value_delimiter = ',')]
+        volumes: Vec<String>,
+
+        /// Sets the number of replicas
+        #[clap(long, default_value = "3")]
+        replicas: usize,
+
+        /// Sets the number of subvolumes
+        #[clap(long, default_value = "10")]
+        subvolumes: u32,
+
+        /// Adds a named volume group, e.g. "ssd=localhost:3006,localhost:3007"
+        #[clap(long = "volume-group", value_parser = parse_volume_group)]
+        volume_groups: Vec<(String, Vec<String>)>,
+
+        /// Sets the number of records moved concurrently
+        #[clap(long, default_value = "8")]
+        concurrency: usize,
+
+        /// Reports the records that would move without copying or deleting anything
+        #[clap(long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        volume_tls: VolumeTlsArgs,
+    },
+
+    /// Deletes the values of the keys deleted for longer than the grace period from the volumes
+    /// and purges their records, with the index server stopped. A running server does it in its gc task.
+    Gc {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the seconds a deleted key is kept before its value is deleted, 0 collects every deleted key
+        #[clap(long, default_value_t = gc::DEFAULT_GRACE_PERIOD.as_secs())]
+        grace_secs: u64,
+
+        #[clap(flatten)]
+        volume_tls: VolumeTlsArgs,
+    },
+    /// Reads every replica of the live values and checks their MD5 and size against the leveldb.
+    /// Reports the corrupted and missing replicas, or copies a good replica over them with --repair.
+    Fsck {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the number of records checked concurrently
+        #[clap(long, default_value_t = fsck::DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+
+        /// Copies a good replica over the corrupted and missing ones
+        #[clap(long)]
+        repair: bool,
+
+        /// Decrypts the encrypted values with the master key of the file to check them
+        #[clap(long)]
+        encryption_key_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        volume_tls: VolumeTlsArgs,
+    },
+
+    /// Exports, imports or migrates the records of the leveldb.
+    Db {
+        #[clap(subcommand)]
+        command: DbCommand,
+    },
+    /// Puts the content of a file as the value of a key on a running index server.
+    Put {
+        /// Sets the key
+        key: String,
+
+        /// Sets the file to upload, "-" reads stdin
+        file: PathBuf,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+    /// Gets the value of a key from a running index server, following the redirect to the volume.
+    Get {
+        /// Sets the key
+        key: String,
+
+        /// Writes the value to a file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+    /// Deletes a key on a running index server.
+    Del {
+        /// Sets the key
+        key: String,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+    /// Lists the keys starting with a prefix on a running index server, one per line.
+    Ls {
+        /// Sets the prefix of the keys
+        prefix: String,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+
+    /// Serves a volume storing raw blobs in a local directory, instead of an nginx volume.
+    Volume {
+        /// Sets the directory the blobs are stored in
+        #[clap(long)]
+        path: PathBuf,
+
+        /// Sets the port to listen on
+        #[clap(short, long, default_value = "3001")]
+        port: u16,
+
+        /// Serves the presigned URLs of the index on a second port, checked with the HMAC key of the
+        /// file. Requires --public-port
+        #[clap(long, requires = "public_port")]
+        presign_key_file: Option<PathBuf>,
+
+        /// Sets the port serving presigned URLs. Requires --presign-key-file
+        #[clap(long, requires = "presign_key_file")]
+        public_port: Option<u16>,
+    },
+}
+
+/// Commands on the records of the leveldb, with the index server stopped
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Writes every record, deleted ones and parts of multipart uploads included, as a JSON line.
+    /// The live records can be restored with `restore`.
+    Export {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Writes the records to a file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Writes the records of an export into an empty leveldb, e.g. of another backend.
+    Import {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the export to read, "-" reads stdin
+        input: PathBuf,
+    },
+    /// Imports the index of a Go minikeyvalue, whose volumes keep serving the values.
+    /// The volumes must be running, a replica of every value is sized with a HEAD.
+    ImportGo {
+        /// Sets the path to the LevelDB of the Go minikeyvalue
+        #[clap(long)]
+        path: PathBuf,
+
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the number of values sized concurrently
+        #[clap(long, default_value = "8")]
+        concurrency: usize,
+
+        #[clap(flatten)]
+        volume_tls: VolumeTlsArgs,
+    },
+    /// Rewrites the records written by older versions, or in the other encoding, in the latest record layout.
+    /// Older records are still read, migrating saves decoding them on every read.
+    Migrate {
+        /// Sets the path to the leveldb
+        #[clap(short, long)]
+        leveldb_path: String,
+
+        /// Sets the metadata store backing the index
+        #[clap(long, value_enum, default_value_t)]
+        db_backend: record::DbBackend,
+
+        /// Sets the encoding the records are rewritten in
+        #[clap(long, value_enum, default_value_t)]
+        record_encoding: record::RecordEncoding,
+    },
+}
+
+/// Parses a volume group in the form "name=volume1,volume2".
+fn parse_volume_group(value: &str) -> Result<(String, Vec<String>), String> {
+    let (name, volumes) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=volume1,volume2, got {}", value))?;
+    let volumes: Vec<String> = volumes
+        .split(',')
+        .filter(|volume| !volume.is_empty())
+        .map(String::from)
+        .collect();
+    if name.is_empty() || volumes.is_empty() {
+        return Err(format!("expected name=volume1,volume2, got {}", value));
+    }
+    Ok((name.to_string(), volumes))
+}
+
+/// Parses the public address of a volume in presigned URLs in the form "volume=public".
+fn parse_presign_volume(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .filter(|(volume, public)| !volume.is_empty() && !public.is_empty())
+        .map(|(volume, public)| (volume.to_string(), public.to_string()))
+        .ok_or_else(|| format!("expected volume=public, got {}", value))
+}
+
+/// Parses the replicas of a namespace in the form "namespace=replicas".
+fn parse_namespace_replicas(value: &str) -> Result<(String, usize), String> {
+    let (name, replicas) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected namespace=replicas, got {}", value))?;
+    let replicas: usize = replicas
+        .parse()
+        .map_err(|e| format!("invalid replicas in {}: {}", value, e))?;
+    Ok((name.to_string(), replicas))
+}
+
+/// Parses a task schedule in the form "name=seconds".
+fn parse_task_schedule(value: &str) -> Result<(String, Duration), String> {
+    let (name, seconds) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=seconds, got {}", value))?;
+    let seconds: u64 = seconds
+        .parse()
+        .map_err(|e| format!("invalid seconds in {}: {}", value, e))?;
+    Ok((name.to_string(), Duration::from_secs(seconds)))
+}
+
+/// Parses an erasure coding layout in the form "data+parity", e.g. "4+2".
+fn parse_erasure_coding(value: &str) -> Result<(usize, usize), String> {
+    value
+        .split_once('+')
+        .and_then(|(data, parity)| Some((data.parse().ok()?, parity.parse().ok()?)))
+        .filter(|(data, parity)| *data > 0 && *parity > 0)
+        .ok_or_else(|| format!("expected data+parity shards, got {}", value))
+}
+
+/// Parses an HTTP method, in any case, e.g. "put".
+fn parse_method(value: &str) -> Result<axum::http::Method, String> {
+    value
+        .to_ascii_uppercase()
+        .parse()
+        .map_err(|_| format!("invalid method {}", value))
+}
+
+/// Parses a compression in the form "zstd" or "zstd:level", e.g. "zstd:9", into its zstd level.
+fn parse_compression(value: &str) -> Result<i32, String> {
+    match value.split_once(':') {
+        None if value == "zstd" => Ok(compress::DEFAULT_LEVEL),
+        Some(("zstd", level)) => level
+            .parse()
+            .ok()
+            .filter(|level| (1..=22).contains(level))
+            .ok_or_else(|| format!("expected a zstd level from 1 to 22, got {}", level)),
+        _ => Err(format!("expected zstd or zstd:level, got {}", value)),
+    }
+}
+
+/// Parses a placement rule in the form "prefix=group".
+fn parse_placement_rule(value: &str) -> Result<hashring::PlacementRule, String> {
+    match value.split_once('=') {
+        Some((prefix, group)) if !group.is_empty() => Ok(hashring::PlacementRule {
+            prefix: prefix.to_string(),
+            group: group.to_string(),
+        }),
+        _ => Err(format!("expected prefix=group, got {}", value)),
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let cli = parse_cli(Cli::command().get_matches())?;
     if cli.verbose {
         std::env::set_var("RUST_LOG", "debug");
     } else {
         std::env::set_var("RUST_LOG", "info");
     }
     env_logger::init();
+    let _telemetry = cli
+        .otlp_endpoint
+        .as_deref()
+        .map(telemetry::Telemetry::init)
+        .transpose()?;
 
-    let port = cli.port;
-    let leveldb_path = Path::new(&cli.leveldb_path);
-    let verify_checksums = cli.hash_md5_checksum;
-    let volumes = cli.volumes;
-    let replicas = cli.replicas;
-    let subvolumes = cli.subvolumes;
-
-    if volumes.len() < replicas {
-        anyhow::bail!(
-            "Need at least as many volumes: {} as replicas: {}",
-            volumes.len(),
-            replicas
-        );
+    match cli.command {
+        Some(Command::Report {
+            leveldb_path,
+            db_backend,
+            json,
+        }) => maintenance::report(&leveldb_path, db_backend, json),
+        Some(Command::Mirror {
+            src,
+            dst,
+            prefix,
+            concurrency,
+        }) => maintenance::mirror(&src, &dst, &prefix, concurrency).await,
+        Some(Command::Restore {
+            leveldb_path,
+            db_backend,
+            metadata,
+            blobs,
+            volumes,
+            replicas,
+            subvolumes,
+            volume_groups,
+            volume_tls,
+        }) => {
+            let mut hashring = hashring::Ring::new(volumes, replicas, subvolumes);
+            for (name, volumes) in volume_groups {
+                hashring.add_group(name, volumes)?;
+            }
+            let client = volume_tls.config().client()?;
+            maintenance::restore(
+                &leveldb_path,
+                db_backend,
+                &metadata,
+                &blobs,
+                hashring,
+                client,
+            )
+            .await
+        }
+        Some(Command::Rebuild {
+            leveldb_path,
+            db_backend,
+            volumes,
+            replicas,
+            subvolumes,
+            volume_groups,
+            placement_rules,
+            volume_tls,
+        }) => {
+            let mut all_volumes = volumes.clone();
+            let mut hashring = hashring::Ring::new(volumes, replicas, subvolumes);
+            for (name, volumes) in volume_groups {
+                all_volumes.extend(volumes.iter().cloned());
+                hashring.add_group(name, volumes)?;
+            }
+            for rule in placement_rules {
+                hashring.add_placement_rule(rule)?;
+            }
+            let client = volume_tls.config().client()?;
+            maintenance::rebuild(&leveldb_path, db_backend, hashring, all_volumes, client).await
+        }
+        Some(Command::Rebalance {
+            leveldb_path,
+            db_backend,
+            volumes,
+            replicas,
+            subvolumes,
+            volume_groups,
+            concurrency,
+            dry_run,
+            volume_tls,
+        }) => {
+            let mut hashring = hashring::Ring::new(volumes, replicas, subvolumes);
+            for (name, volumes) in volume_groups {
+                hashring.add_group(name, volumes)?;
+            }
+            let client = volume_tls.config().client()?;
+            maintenance::rebalance(
+                &leveldb_path,
+                db_backend,
+                hashring,
+                concurrency,
+                dry_run,
+                client,
+            )
+            .await
+        }
+        Some(Command::Gc {
+            leveldb_path,
+            db_backend,
+            grace_secs,
+            volume_tls,
+        }) => {
+            let client = volume_tls.config().client()?;
+            maintenance::gc(
+                &leveldb_path,
+                db_backend,
+                Duration::from_secs(grace_secs),
+                client,
+            )
+            .await
+        }
+        Some(Command::Fsck {
+            leveldb_path,
+            db_backend,
+            concurrency,
+            repair,
+            encryption_key_file,
+            volume_tls,
+        }) => {
+            let client = volume_tls.config().client()?;
+            let encryption = encryption_key_file
+                .as_deref()
+                .map(encryption::MasterKey::load)
+                .transpose()?;
+            maintenance::fsck(
+                &leveldb_path,
+                db_backend,
+                concurrency,
+                repair,
+                encryption,
+                client,
+            )
+            .await
+        }
+        Some(Command::Db {
+            command:
+                DbCommand::Export {
+                    leveldb_path,
+                    db_backend,
+                    output,
+                },
+        }) => maintenance::export(&leveldb_path, db_backend, output.as_deref()),
+        Some(Command::Db {
+            command:
+                DbCommand::Import {
+                    leveldb_path,
+                    db_backend,
+                    input,
+                },
+        }) => maintenance::import(&leveldb_path, db_backend, &input).await,
+        Some(Command::Db {
+            command:
+                DbCommand::ImportGo {
+                    path,
+                    leveldb_path,
+                    db_backend,
+                    concurrency,
+                    volume_tls,
+                },
+        }) => {
+            let client = volume_tls.config().client()?;
+            maintenance::import_go(&leveldb_path, db_backend, &path, concurrency, client).await
+        }
+        Some(Command::Db {
+            command:
+                DbCommand::Migrate {
+                    leveldb_path,
+                    db_backend,
+                    record_encoding,
+                },
+        }) => maintenance::migrate(&leveldb_path, db_backend, record_encoding),
+        Some(Command::Put { key, file, client }) => {
+            let value = if file == Path::new("-") {
+                let mut value = Vec::new();
+                std::io::stdin().read_to_end(&mut value)?;
+                value
+            } else {
+                std::fs::read(&file)
+                    .with_context(|| format!("failed to read {}", file.display()))?
+            };
+            client.client()?.put(&key, value).await
+        }
+        Some(Command::Get {
+            key,
+            output,
+            client,
+        }) => {
+            let value = client
+                .client()?
+                .get(&key)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("key {} not found", key))?;
+            match output {
+                Some(output) => std::fs::write(&output, value)
+                    .with_context(|| format!("failed to write {}", output.display())),
+                None => Ok(std::io::stdout().write_all(&value)?),
+            }
+        }
+        Some(Command::Del { key, client }) => client.client()?.delete(&key).await,
+        Some(Command::Ls { prefix, client }) => {
+            let mut stdout = std::io::stdout().lock();
+            for key in client.client()?.list(&prefix).await? {
+                writeln!(stdout, "{}", key)?;
+            }
+            Ok(())
+        }
+        Some(Command::Volume {
+            path,
+            port,
+            presign_key_file,
+            public_port,
+        }) => {
+            let public = match (public_port, presign_key_file) {
+                (Some(public_port), Some(key_file)) => {
+                    Some((public_port, presign::PresignKey::load(&key_file)?))
+                }
+                _ => None,
+            };
+            volume::new_and_serve(port, path, public).await
+        }
+        None => serve(cli).await,
     }
+}
+
+/// Parses the cli from the command line matches, with the flags not given on the command line
+/// read from the config file, if any.
+fn parse_cli(matches: clap::ArgMatches) -> anyhow::Result<Cli> {
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    if let Some(path) = cli.config.clone() {
+        config::FileConfig::load(&path)?.apply(&mut cli, &matches)?;
+    }
+    Ok(cli)
+}
+
+/// Returns the server configuration of the cli, validated.
+fn server_config(cli: Cli) -> anyhow::Result<server::Config> {
+    let leveldb_path = cli
+        .leveldb_path
+        .ok_or_else(|| anyhow::anyhow!("--leveldb-path is required"))?;
+    let tls = match (cli.tls_cert, cli.tls_key) {
+        (Some(cert), Some(key)) => Some(server::TlsConfig { cert, key }),
+        (None, None) => None,
+        _ => anyhow::bail!("--tls-cert and --tls-key must be set together"),
+    };
+    let mut tokens = match cli.auth_token_file {
+        Some(path) => auth::Tokens::load(&path)?,
+        None => auth::Tokens::default(),
+    };
+    if let Some(token) = cli.auth_token {
+        tokens.add(token, auth::Scope::Write);
+    }
+    let config = server::Config {
+        leveldb_path: PathBuf::from(leveldb_path),
+        db_backend: cli.db_backend,
+        record_encoding: cli.record_encoding,
+        record_cache: cli.record_cache,
+        negative_cache: cli
+            .negative_cache
+            .map(|capacity| record::NegativeCacheConfig {
+                capacity,
+                ttl: Duration::from_secs(cli.negative_cache_ttl_secs),
+            }),
+        value_cache: cli
+            .value_cache
+            .map(|capacity| value_cache::ValueCacheConfig {
+                capacity,
+                max_value_size: cli.value_cache_max_size,
+                ttl: Duration::from_secs(cli.value_cache_ttl_secs),
+            }),
+        verify_checksums: cli.hash_md5_checksum && cli.checksum != checksum::Checksum::None,
+        hash_algorithm: cli.checksum.algorithm().unwrap_or(checksum::Algorithm::Md5),
+        checksum_algorithms: cli.checksum_algorithms,
+        volumes: cli.volumes,
+        default_proxy: cli.default_proxy,
+        redirect_status: cli.redirect_status,
+        redirect_cache_control: Some(cli.redirect_cache_control)
+            .filter(|cache_control| !cache_control.is_empty()),
+        concurrent_heads: cli.concurrent_heads,
+        replicas: cli.replicas,
+        max_replicas: cli.max_replicas.map(|max_replicas| max_replicas as usize),
+        namespace_replicas: cli.namespace_replicas.into_iter().collect(),
+        key_policy: keys::KeyPolicy {
+            max_length: cli.max_key_length,
+            charset: cli.key_charset,
+        },
+        max_value_size: cli.max_value_size,
+        write_quorum: cli.write_quorum.map(|quorum| quorum as usize),
+        subvolumes: cli.subvolumes,
+        volume_groups: cli.volume_groups,
+        placement_rules: cli.placement_rules,
+        task_schedules: cli.task_schedules.into_iter().collect(),
+        lock_timeout: Duration::from_millis(cli.lock_timeout_ms),
+        shutdown_timeout: Duration::from_secs(cli.shutdown_timeout),
+        reload_ring: None,
+        tls,
+        volume_tls: cli.volume_tls.config(),
+        auth: (!tokens.is_empty()).then_some(tokens),
+        ready_fraction: cli.ready_fraction,
+        write_retry: server::RetryPolicy {
+            attempts: cli.write_retry_attempts,
+            backoff: Duration::from_millis(cli.write_retry_backoff_ms),
+            max_backoff: Duration::from_millis(cli.write_retry_max_backoff_ms),
+            jitter: cli.write_retry_jitter,
+        },
+        two_phase_writes: cli.two_phase_writes,
+        erasure: cli
+            .erasure_coding
+            .map(|(data_shards, parity_shards)| erasure::ErasureConfig {
+                data_shards,
+                parity_shards,
+                min_size: cli.erasure_min_size,
+            }),
+        dedup: cli.dedup,
+        compression: cli
+            .compress
+            .map(|level| compress::CompressionConfig { level }),
+        encryption: cli
+            .encryption_key_file
+            .as_deref()
+            .map(encryption::MasterKey::load)
+            .transpose()?,
+        presign: cli
+            .presign_key_file
+            .as_deref()
+            .map(presign::PresignKey::load)
+            .transpose()?
+            .map(|key| presign::PresignConfig {
+                key,
+                public_volumes: cli.presign_volumes.into_iter().collect(),
+            }),
+        breaker: breaker::BreakerConfig {
+            error_rate: cli.breaker_error_rate,
+            min_requests: cli.breaker_min_requests,
+            open_for: Duration::from_secs(cli.breaker_open_secs),
+        },
+        fanout: fanout::FanoutConfig {
+            max_puts: cli.max_replica_puts,
+            max_puts_per_volume: cli.max_replica_puts_per_volume,
+        },
+        volume_connect_timeout: non_zero_secs(cli.volume_connect_timeout),
+        volume_request_timeout: non_zero_secs(cli.volume_request_timeout),
+        volume_client: server::VolumeClientConfig {
+            pool_max_idle_per_host: cli.volume_pool_max_idle,
+            pool_idle_timeout: non_zero_secs(cli.volume_pool_idle_timeout),
+            http2: cli.volume_http2,
+            keep_alive: non_zero_secs(cli.volume_keep_alive),
+        },
+        request_deadline: non_zero_secs(cli.request_deadline),
+        limits: limits::LimitsConfig {
+            rate: cli.rate_limit,
+            client_rate: cli.client_rate_limit,
+            max_in_flight: cli.max_in_flight,
+        },
+        cors: (!cli.cors_origins.is_empty()).then(|| cors::CorsConfig {
+            origins: cli.cors_origins,
+            methods: cli.cors_methods,
+            headers: cli.cors_headers,
+            max_age: Duration::from_secs(cli.cors_max_age),
+        }),
+        gc_grace_period: Duration::from_secs(cli.gc_grace_secs),
+        auto_rebalance: cli.auto_rebalance,
+        access_log: cli.access_log,
+    };
+    config.validate()?;
+    Ok(config)
+}
+
+/// Returns the duration of a number of seconds, None for 0.
+fn non_zero_secs(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Starts the server with the cli configuration.
+/// On SIGHUP the command line and config file are read again and the ring is rebuilt from them.
+async fn serve(cli: Cli) -> anyhow::Result<()> {
+    let port = cli.port;
+    let internal_addr = cli.internal_addr;
+    let s3_addr = cli.s3_addr;
+    let resp_addr = cli.resp_addr;
+    let grpc_addr = cli.grpc_addr;
+    let admin_addr = cli.admin_port.map(|port| match cli.admin_localhost {
+        true => std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port)),
+        false => std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port)),
+    });
+    let mut config = server_config(cli)?;
+    config.reload_ring = Some(Arc::new(|| {
+        let matches = Cli::command().try_get_matches()?;
+        server_config(parse_cli(matches)?)?.hashring()
+    }));
 
     server::new_and_serve(
         port,
-        leveldb_path,
-        verify_checksums,
-        volumes,
-        replicas,
-        subvolumes,
+        internal_addr,
+        s3_addr,
+        resp_addr,
+        grpc_addr,
+        admin_addr,
+        config,
     )
     .await?;
 
@@ -0,0 +1,254 @@
+use anyhow::Context;
+use log::info;
+use std::{path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    backup, encryption, fsck, gc, go_import, hashring, locks, mirror, rebalance, rebuild, record,
+    report,
+};
+
+/// Prints the distribution report of the leveldb.
+pub fn report(leveldb_path: &str, db_backend: record::DbBackend, json: bool) -> anyhow::Result<()> {
+    let leveldb = record::LevelDb::with_backend(Path::new(leveldb_path), db_backend)?;
+    let report = report::DistributionReport::scan(&leveldb)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
+/// Mirrors the objects under a prefix from the source to the destination cluster.
+pub async fn mirror(src: &str, dst: &str, prefix: &str, concurrency: usize) -> anyhow::Result<()> {
+    let stats = mirror::Mirror::new(src, dst, prefix, concurrency)?
+        .run()
+        .await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 {
+        anyhow::bail!("{} objects failed to mirror", stats.failed);
+    }
+    Ok(())
+}
+
+/// Restores the metadata dump and blobs archive into the leveldb and the ring volumes.
+pub async fn restore(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    metadata: &Path,
+    blobs: &Path,
+    hashring: hashring::Ring,
+    client: reqwest::Client,
+) -> anyhow::Result<()> {
+    let metadata = std::fs::File::open(metadata)
+        .with_context(|| format!("failed to open {}", metadata.display()))?;
+    let metadata = backup::read_metadata(std::io::BufReader::new(metadata))?;
+    let blobs = std::fs::File::open(blobs)
+        .with_context(|| format!("failed to open {}", blobs.display()))?;
+    let leveldb = Arc::new(record::LevelDb::with_backend(
+        Path::new(leveldb_path),
+        db_backend,
+    )?);
+
+    let stats = backup::Restore::new(leveldb, hashring)
+        .with_client(client)
+        .run(metadata, std::io::BufReader::new(blobs))
+        .await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 || stats.missing_blob > 0 {
+        anyhow::bail!(
+            "{} records failed and {} records have no blob",
+            stats.failed,
+            stats.missing_blob
+        );
+    }
+    Ok(())
+}
+
+/// Rebuilds the leveldb from the blobs stored in the volumes.
+pub async fn rebuild(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    hashring: hashring::Ring,
+    volumes: Vec<String>,
+    client: reqwest::Client,
+) -> anyhow::Result<()> {
+    let leveldb = Arc::new(record::LevelDb::with_backend(
+        Path::new(leveldb_path),
+        db_backend,
+    )?);
+
+    let stats = rebuild::Rebuild::new(leveldb, hashring, volumes)
+        .with_client(client)
+        .run()
+        .await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 {
+        anyhow::bail!("{} records failed to rebuild", stats.failed);
+    }
+    Ok(())
+}
+
+/// Moves the values of the records in the leveldb to the volumes of the ring.
+pub async fn rebalance(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    hashring: hashring::Ring,
+    concurrency: usize,
+    dry_run: bool,
+    client: reqwest::Client,
+) -> anyhow::Result<()> {
+    let leveldb = Arc::new(record::LevelDb::with_backend(
+        Path::new(leveldb_path),
+        db_backend,
+    )?);
+
+    let stats = rebalance::Rebalance::new(leveldb, hashring, concurrency, dry_run)
+        .with_client(client)
+        .run()
+        .await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 {
+        anyhow::bail!("{} records failed to rebalance", stats.failed);
+    }
+    Ok(())
+}
+
+/// Collects the values and records of the keys deleted for longer than the grace period.
+pub async fn gc(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    grace_period: Duration,
+    client: reqwest::Client,
+) -> anyhow::Result<()> {
+    let leveldb = Arc::new(record::LevelDb::with_backend(
+        Path::new(leveldb_path),
+        db_backend,
+    )?);
+
+    let key_locks = locks::KeyLocks::new(Duration::from_secs(1));
+    let stats = gc::Gc::new(leveldb, key_locks, grace_period)
+        .with_client(client)
+        .run()
+        .await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 {
+        anyhow::bail!("{} records failed to collect", stats.failed);
+    }
+    Ok(())
+}
+
+/// Checks the MD5 of every replica of the values in the leveldb, copying good replicas over the bad
+/// ones with repair. Encrypted values are decrypted with the master key, and fail to check without it.
+/// Fails if replicas are left bad.
+pub async fn fsck(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    concurrency: usize,
+    repair: bool,
+    encryption: Option<encryption::MasterKey>,
+    client: reqwest::Client,
+) -> anyhow::Result<()> {
+    let leveldb = Arc::new(record::LevelDb::with_backend(
+        Path::new(leveldb_path),
+        db_backend,
+    )?);
+
+    let key_locks = locks::KeyLocks::new(Duration::from_secs(1));
+    let stats = fsck::Fsck::new(leveldb, key_locks)
+        .with_client(client)
+        .with_concurrency(concurrency)
+        .with_repair(repair)
+        .with_encryption(encryption)
+        .run()
+        .await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.unrepaired() > 0 || stats.lost > 0 || stats.failed > 0 {
+        anyhow::bail!(
+            "{} replicas unrepaired, {} records lost, {} records failed to check",
+            stats.unrepaired(),
+            stats.lost,
+            stats.failed
+        );
+    }
+    Ok(())
+}
+
+/// Writes every record of the leveldb as a JSON line to the output file, or stdout without one.
+pub fn export(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    output: Option<&Path>,
+) -> anyhow::Result<()> {
+    let leveldb = record::LevelDb::with_backend(Path::new(leveldb_path), db_backend)?;
+    let exported = match output {
+        Some(output) => {
+            let file = std::fs::File::create(output)
+                .with_context(|| format!("failed to create {}", output.display()))?;
+            backup::export_records(&leveldb, std::io::BufWriter::new(file))?
+        }
+        None => backup::export_records(&leveldb, std::io::BufWriter::new(std::io::stdout()))?,
+    };
+    info!("export: exported {} records", exported);
+    Ok(())
+}
+
+/// Imports the index of a Go minikeyvalue into the leveldb, sizing the values on its volumes.
+pub async fn import_go(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    go_path: &Path,
+    concurrency: usize,
+    client: reqwest::Client,
+) -> anyhow::Result<()> {
+    let leveldb = Arc::new(record::LevelDb::with_backend(
+        Path::new(leveldb_path),
+        db_backend,
+    )?);
+    let stats = go_import::GoImport::new(leveldb.clone(), concurrency)
+        .with_client(client)
+        .run(go_path)
+        .await?;
+    leveldb.flush()?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 {
+        anyhow::bail!("{} keys failed to import", stats.failed);
+    }
+    Ok(())
+}
+
+/// Rewrites the records of the leveldb written by older versions, or in another encoding,
+/// in the latest record layout of the encoding.
+pub fn migrate(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    record_encoding: record::RecordEncoding,
+) -> anyhow::Result<()> {
+    let leveldb = record::LevelDb::with_backend(Path::new(leveldb_path), db_backend)?
+        .with_record_encoding(record_encoding);
+    let stats = leveldb.migrate_records()?;
+    leveldb.flush()?;
+    println!("{}", serde_json::to_string(&stats)?);
+    if stats.failed > 0 {
+        anyhow::bail!("{} records failed to migrate", stats.failed);
+    }
+    Ok(())
+}
+
+/// Writes the records of an export, read from the input file or stdin for "-", into an empty leveldb.
+pub async fn import(
+    leveldb_path: &str,
+    db_backend: record::DbBackend,
+    input: &Path,
+) -> anyhow::Result<()> {
+    let leveldb = record::LevelDb::with_backend(Path::new(leveldb_path), db_backend)?;
+    let imported = if input == Path::new("-") {
+        backup::import_records(&leveldb, std::io::stdin().lock()).await?
+    } else {
+        let file = std::fs::File::open(input)
+            .with_context(|| format!("failed to open {}", input.display()))?;
+        backup::import_records(&leveldb, std::io::BufReader::new(file)).await?
+    };
+    println!("{}", serde_json::json!({ "imported": imported }));
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use axum::http::Method;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// Struct representing the Prometheus metrics of the index server.
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Requests served by method and status code.
+    pub(crate) requests: IntCounterVec,
+    /// Duration of the requests served by method.
+    pub(crate) request_duration: HistogramVec,
+    /// Duration of the requests made to volume servers by method.
+    pub(crate) volume_request_duration: HistogramVec,
+    /// Values or parts that failed to be written to a replica.
+    pub(crate) replication_failures: IntCounter,
+    /// Replica writes retried after a transient volume error.
+    pub(crate) write_retries: IntCounter,
+    /// Failed leveldb operations by operation.
+    pub(crate) leveldb_errors: IntCounterVec,
+    /// Requests rejected because their key stayed locked by another PUT or DELETE past the lock timeout.
+    pub(crate) lock_conflicts: IntCounter,
+    /// Bytes of values proxied through the index, "in" for uploads and "out" for multipart reads.
+    pub(crate) proxied_bytes: IntCounterVec,
+    /// Repairs of the records a GET found a replica of missing or placed off the ring.
+    pub(crate) read_repairs: IntCounter,
+    /// GETs of erasure coded values that rebuilt missing data shards from the parity shards.
+    pub(crate) erasure_reconstructions: IntCounter,
+    /// PUTs of a value already stored under another key, referencing its blob instead of writing it.
+    pub(crate) dedup_hits: IntCounter,
+    /// PUTs to the volumes that waited for a PUT in flight to complete, see `fanout::FanoutConfig`.
+    pub(crate) fanout_waits: IntCounter,
+    /// Records read from the record cache, see `record::LevelDb::with_record_cache`.
+    pub(crate) record_cache_hits: IntCounter,
+    /// Records read from the metadata store with the record cache enabled.
+    pub(crate) record_cache_misses: IntCounter,
+    /// Lookups of keys without a record answered by the negative cache, see `record::LevelDb::with_negative_cache`.
+    pub(crate) negative_cache_hits: IntCounter,
+    /// GETs served from the value cache, see `value_cache::ValueCache`.
+    pub(crate) value_cache_hits: IntCounter,
+    /// GETs of cacheable values read from a volume.
+    pub(crate) value_cache_misses: IntCounter,
+}
+
+/// Process wide metrics, shared by every router and the maintenance commands.
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    /// Creates the metrics and registers them in a new registry.
+    fn new() -> Self {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("mkv_requests_total", "Requests served by method and status"),
+            &["method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "mkv_request_duration_seconds",
+                "Duration of the requests served by method",
+            ),
+            &["method"],
+        )
+        .unwrap();
+        let volume_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "mkv_volume_request_duration_seconds",
+                "Duration of the requests made to volume servers by method",
+            ),
+            &["method"],
+        )
+        .unwrap();
+        let replication_failures = IntCounter::new(
+            "mkv_replication_failures_total",
+            "Values or parts that failed to be written to a replica",
+        )
+        .unwrap();
+        let write_retries = IntCounter::new(
+            "mkv_write_retries_total",
+            "Replica writes retried after a transient volume error",
+        )
+        .unwrap();
+        let leveldb_errors = IntCounterVec::new(
+            Opts::new("mkv_leveldb_errors_total", "Failed leveldb operations"),
+            &["operation"],
+        )
+        .unwrap();
+        let lock_conflicts = IntCounter::new(
+            "mkv_lock_conflicts_total",
+            "Requests rejected because their key stayed locked by another PUT or DELETE",
+        )
+        .unwrap();
+        let proxied_bytes = IntCounterVec::new(
+            Opts::new(
+                "mkv_proxied_bytes_total",
+                "Bytes of values proxied through the index by direction",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(volume_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(replication_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(write_retries.clone())).unwrap();
+        registry.register(Box::new(leveldb_errors.clone())).unwrap();
+        registry.register(Box::new(lock_conflicts.clone())).unwrap();
+        let read_repairs = IntCounter::new(
+            "mkv_read_repairs_total",
+            "Repairs scheduled by GETs finding a replica missing or off the ring",
+        )
+        .unwrap();
+        let erasure_reconstructions = IntCounter::new(
+            "mkv_erasure_reconstructions_total",
+            "GETs of erasure coded values reconstructed from their parity shards",
+        )
+        .unwrap();
+        let dedup_hits = IntCounter::new(
+            "mkv_dedup_hits_total",
+            "PUTs of a value already stored under another key, sharing its blob",
+        )
+        .unwrap();
+
+        registry.register(Box::new(proxied_bytes.clone())).unwrap();
+        registry.register(Box::new(read_repairs.clone())).unwrap();
+        registry
+            .register(Box::new(erasure_reconstructions.clone()))
+            .unwrap();
+        registry.register(Box::new(dedup_hits.clone())).unwrap();
+        let fanout_waits = IntCounter::new(
+            "mkv_fanout_waits_total",
+            "PUTs to the volumes that waited for a PUT in flight to complete",
+        )
+        .unwrap();
+        registry.register(Box::new(fanout_waits.clone())).unwrap();
+        let record_cache_hits = IntCounter::new(
+            "mkv_record_cache_hits_total",
+            "Records read from the record cache",
+        )
+        .unwrap();
+        let record_cache_misses = IntCounter::new(
+            "mkv_record_cache_misses_total",
+            "Records read from the metadata store with the record cache enabled",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(record_cache_hits.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(record_cache_misses.clone()))
+            .unwrap();
+        let negative_cache_hits = IntCounter::new(
+            "mkv_negative_cache_hits_total",
+            "Lookups of keys without a record answered by the negative cache",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(negative_cache_hits.clone()))
+            .unwrap();
+        let value_cache_hits = IntCounter::new(
+            "mkv_value_cache_hits_total",
+            "GETs served from the value cache",
+        )
+        .unwrap();
+        let value_cache_misses = IntCounter::new(
+            "mkv_value_cache_misses_total",
+            "GETs of cacheable values read from a volume",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(value_cache_hits.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(value_cache_misses.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            volume_request_duration,
+            replication_failures,
+            write_retries,
+            leveldb_errors,
+            lock_conflicts,
+            proxied_bytes,
+            read_repairs,
+            erasure_reconstructions,
+            dedup_hits,
+            fanout_waits,
+            record_cache_hits,
+            record_cache_misses,
+            negative_cache_hits,
+            value_cache_hits,
+            value_cache_misses,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub(crate) fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// Times a request made to a volume server, in a span of the request.
+    pub(crate) async fn time_volume_request<T>(
+        &self,
+        method: &str,
+        request: impl std::future::Future<Output = T>,
+    ) -> T {
+        use tracing::Instrument;
+
+        let _timer = self
+            .volume_request_duration
+            .with_label_values(&[method])
+            .start_timer();
+        request
+            .instrument(tracing::info_span!(
+                "volume_request",
+                http.request.method = method
+            ))
+            .await
+    }
+}
+
+/// Returns the method label of a request, methods the server doesn't route share a label
+/// so clients can't grow the number of series.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::PUT => "PUT",
+        Method::POST => "POST",
+        Method::DELETE => "DELETE",
+        _ => "OTHER",
+    }
+}
+
+/// Middleware counting the requests served and their duration.
+pub(crate) async fn track_requests(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let method = method_label(request.method());
+    let timer = METRICS
+        .request_duration
+        .with_label_values(&[method])
+        .start_timer();
+    let response = next.run(request).await;
+    timer.observe_duration();
+    METRICS
+        .requests
+        .with_label_values(&[method, response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Creates the router serving the metrics.
+pub(crate) fn router() -> axum::Router {
+    axum::Router::new().route("/metrics", axum::routing::get(handle_metrics))
+}
+
+/// Handles GET requests for the metrics in the Prometheus text format.
+/// Returns 500 if the metrics can't be encoded
+async fn handle_metrics() -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    match METRICS.render() {
+        Ok(metrics) => (
+            [(axum::http::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            metrics,
+        )
+            .into_response(),
+        Err(e) => {
+            log::error!("metrics: failed to render metrics: {}", e);
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(method_label(&Method::PUT), "PUT");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PURGE").unwrap()),
+            "OTHER"
+        );
+    }
+
+    #[test]
+    fn test_render() -> anyhow::Result<()> {
+        METRICS.lock_conflicts.inc();
+        METRICS.leveldb_errors.with_label_values(&["put"]).inc();
+        let metrics = METRICS.render()?;
+        assert!(metrics.contains("# TYPE mkv_lock_conflicts_total counter"));
+        assert!(metrics.contains("mkv_leveldb_errors_total{operation=\"put\"}"));
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use anyhow::Context;
+use futures::StreamExt;
+use log::{debug, error, info};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::{admin, checksum};
+
+/// Number of objects listed per page of the source cluster.
+const LIST_PAGE_LIMIT: usize = 1000;
+
+/// Struct counting the objects handled by a mirror run.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct MirrorStats {
+    pub(crate) copied: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+    pub(crate) bytes: u64,
+}
+
+/// Enum representing what happened to a single object.
+enum Outcome {
+    Copied(u64),
+    Skipped,
+}
+
+/// Struct copying the live objects under a prefix from a source cluster to a destination cluster.
+/// Objects whose destination hash matches the source are skipped, so re-runs only copy what changed.
+pub(crate) struct Mirror {
+    client: reqwest::Client,
+    src: String,
+    dst: String,
+    prefix: String,
+    concurrency: usize,
+}
+
+impl Mirror {
+    /// Creates a new mirror between the base urls of two index servers, e.g. `http://localhost:3000`.
+    pub(crate) fn new(
+        src: &str,
+        dst: &str,
+        prefix: &str,
+        concurrency: usize,
+    ) -> anyhow::Result<Self> {
+        // Redirects are followed by hand to read the index headers before fetching the value.
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            client,
+            src: src.trim_end_matches('/').to_string(),
+            dst: dst.trim_end_matches('/').to_string(),
+            prefix: prefix.to_string(),
+            concurrency: concurrency.max(1),
+        })
+    }
+
+    /// Mirrors every live object under the prefix and returns the counts of the run.
+    /// Fails only if the source cannot be listed, objects failing to copy are counted and logged.
+    pub(crate) async fn run(&self) -> anyhow::Result<MirrorStats> {
+        let mut stats = MirrorStats::default();
+        let mut start = String::new();
+        loop {
+            let page = self.list_page(&start).await?;
+            let mut outcomes = futures::stream::iter(page.objects.iter())
+                .map(|object| async move { (object, self.mirror_object(object).await) })
+                .buffer_unordered(self.concurrency);
+            while let Some((object, outcome)) = outcomes.next().await {
+                match outcome {
+                    Ok(Outcome::Copied(bytes)) => {
+                        debug!("mirror: copied key: {} bytes: {}", object.key, bytes);
+                        stats.copied += 1;
+                        stats.bytes += bytes;
+                    }
+                    Ok(Outcome::Skipped) => stats.skipped += 1,
+                    Err(e) => {
+                        error!("mirror: failed to copy key {}: {:#}", object.key, e);
+                        stats.failed += 1;
+                    }
+                }
+            }
+
+            if page.next.is_empty() {
+                break;
+            }
+            start = page.next;
+        }
+
+        info!(
+            "mirror: {} -> {} prefix: {:?} copied: {} skipped: {} failed: {} bytes: {}",
+            self.src, self.dst, self.prefix, stats.copied, stats.skipped, stats.failed, stats.bytes
+        );
+        Ok(stats)
+    }
+
+    /// Lists a page of live objects under the prefix in the source cluster.
+    async fn list_page(&self, start: &str) -> anyhow::Result<admin::ObjectPage> {
+        let limit = LIST_PAGE_LIMIT.to_string();
+        let page = self
+            .client
+            .get(format!("{}/admin/objects", self.src))
+            .query(&[
+                ("prefix", self.prefix.as_str()),
+                ("start", start),
+                ("limit", limit.as_str()),
+            ])
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("failed to list objects in {}", self.src))?
+            .json::<admin::ObjectPage>()
+            .await?;
+        Ok(page)
+    }
+
+    /// Copies an object unless the destination already stores the same value.
+    /// The value is streamed from the source volume and the destination verifies it against the source hash.
+    async fn mirror_object(&self, object: &admin::LiveObject) -> anyhow::Result<Outcome> {
+        let dst_url = format!("{}/{}", self.dst, object.key);
+        let hash_algorithm = object.hash_algorithm.unwrap_or(checksum::Algorithm::Md5);
+        let existing = self.client.get(&dst_url).send().await?;
+        match existing.status() {
+            StatusCode::NOT_FOUND => {}
+            // Without a source hash an existing value can't be compared, it is assumed up to date
+            status if status.is_redirection() && object.hash.is_empty() => {
+                return Ok(Outcome::Skipped)
+            }
+            status
+                if status.is_redirection()
+                    && checksum::response_hash(existing.headers())
+                        == Some((hash_algorithm, object.hash.as_str())) =>
+            {
+                return Ok(Outcome::Skipped)
+            }
+            _ => {
+                let res = self.client.delete(&dst_url).send().await?;
+                if res.status() != StatusCode::NO_CONTENT {
+                    anyhow::bail!("failed to delete stale value, status: {}", res.status());
+                }
+            }
+        }
+
+        let src_url = format!("{}/{}", self.src, object.key);
+        let located = self.client.get(&src_url).send().await?;
+        let content_disposition = located
+            .headers()
+            .get(reqwest::header::CONTENT_DISPOSITION)
+            .cloned();
+        let content_type = located
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .cloned();
+        let value = match located.status() {
+            status if status.is_redirection() => {
+                let location = located
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .context("missing Location header")?;
+                self.client
+                    .get(location)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())?
+            }
+            // Multipart, erasure coded and compressed values are streamed by the index itself
+            StatusCode::OK => located,
+            // Deleted in the source since it was listed
+            StatusCode::NOT_FOUND => return Ok(Outcome::Skipped),
+            status => anyhow::bail!("failed to locate value, status: {}", status),
+        };
+        let size = value.content_length().unwrap_or(object.size);
+
+        let mut put = self
+            .client
+            .put(&dst_url)
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(reqwest::Body::wrap_stream(value.bytes_stream()));
+        // The MD5 of a multipart value is derived from its parts, it can't verify the whole value
+        if !object.hash.is_empty() && !object.hash.contains('-') {
+            put = put.header(hash_algorithm.header_name(), object.hash.as_str());
+        }
+        if let Some(content_disposition) = content_disposition {
+            put = put.header(reqwest::header::CONTENT_DISPOSITION, content_disposition);
+        }
+        if let Some(content_type) = content_type {
+            put = put.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+        let res = put.send().await?;
+        if res.status() != StatusCode::CREATED {
+            anyhow::bail!("failed to put value, status: {}", res.status());
+        }
+
+        Ok(Outcome::Copied(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_mirror_incremental() -> anyhow::Result<()> {
+        let src = TestCluster::start(3, 2).await?;
+        let dst = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in ["x-a", "x-b", "y-c"] {
+            client.put(src.key_url(key)).body(key).send().await?;
+        }
+
+        let mirror = Mirror::new(src.url(), dst.url(), "x-", 2)?;
+        let stats = mirror.run().await?;
+        assert_eq!(stats.copied, 2);
+        assert_eq!(stats.bytes, 6);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(
+            client.get(dst.key_url("x-a")).send().await?.text().await?,
+            "x-a"
+        );
+        assert_eq!(
+            client.get(dst.key_url("x-b")).send().await?.text().await?,
+            "x-b"
+        );
+        let res = client.get(dst.key_url("y-c")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        client.delete(src.key_url("x-a")).send().await?;
+        client
+            .put(src.key_url("x-a"))
+            .body("changed")
+            .send()
+            .await?;
+
+        let stats = mirror.run().await?;
+        assert_eq!(
+            stats,
+            MirrorStats {
+                copied: 1,
+                skipped: 1,
+                failed: 0,
+                bytes: 7,
+            }
+        );
+        let res = client.get(dst.key_url("x-a")).send().await?;
+        assert_eq!(res.text().await?, "changed");
+
+        Ok(())
+    }
+}
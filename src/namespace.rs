@@ -0,0 +1,543 @@
+use axum::http::StatusCode;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use crate::{admin, record, server};
+
+/// Prefix of the keys of a namespace, `ns:<namespace>/<key>`.
+/// Plain keys starting with it are rejected, namespaced keys are only reached through their namespace.
+pub(crate) const PREFIX: &str = "ns:";
+
+/// Longest namespace name.
+const MAX_NAME_SIZE: usize = 64;
+
+/// Prefix of the metadata store entries counting the keys and bytes of a namespace, under the reserved prefix.
+const USAGE_PREFIX: &str = "\0namespace-usage/";
+
+/// Prefix of the metadata store entries holding the quota of a namespace, under the reserved prefix.
+const QUOTA_PREFIX: &str = "\0namespace-quota/";
+
+/// Axum state for namespace admin requests.
+pub(crate) struct AppNamespaceState {
+    pub(crate) leveldb: Arc<record::LevelDb>,
+    pub(crate) delete: Arc<server::AppDeleteState>,
+}
+
+/// Struct representing the live keys of a namespace and the bytes of their values.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct NamespaceStats {
+    name: String,
+    keys: u64,
+    bytes: u64,
+}
+
+/// Struct representing the namespaces holding live keys.
+#[derive(Debug, Serialize)]
+struct NamespaceList {
+    namespaces: Vec<NamespaceStats>,
+}
+
+/// Struct representing the keys of a namespace that are not deleted and the bytes of their values,
+/// updated on every write of their records. Expired keys count until the expiry task deletes them.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+struct Usage {
+    keys: u64,
+    bytes: u64,
+}
+
+/// Struct representing the limits of a namespace, None for no limit.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+struct Quota {
+    #[serde(default)]
+    max_keys: Option<u64>,
+    #[serde(default)]
+    max_bytes: Option<u64>,
+}
+
+/// Struct representing the quota of a namespace along with its usage.
+#[derive(Debug, Serialize)]
+struct QuotaStatus {
+    max_keys: Option<u64>,
+    max_bytes: Option<u64>,
+    keys: u64,
+    bytes: u64,
+}
+
+/// Struct representing the keys deleted with their namespace.
+#[derive(Debug, Serialize)]
+struct DeletedKeys {
+    deleted: u64,
+    failed: u64,
+}
+
+/// Returns true if a namespace name is 1 to 64 ASCII letters, digits, dots, dashes or underscores.
+pub(crate) fn is_valid(name: &str) -> bool {
+    (1..=MAX_NAME_SIZE).contains(&name.len())
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_'))
+}
+
+/// Returns the leveldb key of a key in a namespace.
+pub(crate) fn key(namespace: &str, key: &str) -> String {
+    format!("{}{}/{}", PREFIX, namespace, key)
+}
+
+/// Returns the namespace and the key within it of a namespaced leveldb key, None for plain keys.
+pub(crate) fn split(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(PREFIX)?.split_once('/')
+}
+
+/// Gets an entry of the metadata store, the default if missing.
+fn get_entry<T: serde::de::DeserializeOwned + Default>(
+    store: &dyn record::MetadataStore,
+    key: &str,
+) -> anyhow::Result<T> {
+    match store.get(key)? {
+        Some(value) => Ok(bincode::deserialize(&value)?),
+        None => Ok(T::default()),
+    }
+}
+
+/// Returns the usage of a namespace.
+fn usage(store: &dyn record::MetadataStore, name: &str) -> anyhow::Result<Usage> {
+    get_entry(store, &format!("{}{}", USAGE_PREFIX, name))
+}
+
+/// Returns the quota of a namespace, without limits if none is set.
+fn quota(store: &dyn record::MetadataStore, name: &str) -> anyhow::Result<Quota> {
+    get_entry(store, &format!("{}{}", QUOTA_PREFIX, name))
+}
+
+/// Sets the quota of a namespace, a quota without limits removes it.
+fn put_quota(store: &dyn record::MetadataStore, name: &str, quota: &Quota) -> anyhow::Result<()> {
+    let key = format!("{}{}", QUOTA_PREFIX, name);
+    if *quota == Quota::default() {
+        store.delete(&key)
+    } else {
+        store.put(&key, &bincode::serialize(quota)?)
+    }
+}
+
+/// Updates the usage of the namespace of a key whose record is replaced, None for a missing record.
+/// Only records that are not deleted count. Callers serialize the updates, see `record::LevelDb::put_record`.
+pub(crate) fn track_usage(
+    store: &dyn record::MetadataStore,
+    key: &str,
+    current: Option<&record::Record>,
+    new: Option<&record::Record>,
+) -> anyhow::Result<()> {
+    let Some((name, _)) = split(key) else {
+        return Ok(());
+    };
+    let counted = |record: Option<&record::Record>| {
+        record
+            .filter(|record| record.deleted() == record::Deleted::No)
+            .map(record::Record::size)
+    };
+    let (current, new) = (counted(current), counted(new));
+    if current == new {
+        return Ok(());
+    }
+    let mut usage = usage(store, name)?;
+    if let Some(size) = current {
+        usage.keys = usage.keys.saturating_sub(1);
+        usage.bytes = usage.bytes.saturating_sub(size);
+    }
+    if let Some(size) = new {
+        usage.keys += 1;
+        usage.bytes += size;
+    }
+    store.put(
+        &format!("{}{}", USAGE_PREFIX, name),
+        &bincode::serialize(&usage)?,
+    )
+}
+
+/// Struct representing the usage reserved in the quotas of their namespaces by the writes in flight,
+/// by key with the id of the reservation, see `record::LevelDb::with_reservations`.
+#[derive(Debug, Default)]
+pub(crate) struct Reservations {
+    keys: HashMap<String, (u64, Usage)>,
+    next_id: u64,
+}
+
+impl Reservations {
+    /// Releases the usage reserved for a key, once its record is written.
+    pub(crate) fn release(&mut self, key: &str) {
+        self.keys.remove(key);
+    }
+
+    /// Releases a reservation once its write gave up, unless the key was reserved again since.
+    fn release_reservation(&mut self, key: &str, id: u64) {
+        if self
+            .keys
+            .get(key)
+            .is_some_and(|(reserved, _)| *reserved == id)
+        {
+            self.keys.remove(key);
+        }
+    }
+
+    /// Returns the usage reserved by the writes in flight to the keys of a namespace but one,
+    /// whose reservation is replaced by the write holding the lock of the key.
+    fn reserved(&self, name: &str, except: &str) -> Usage {
+        self.keys
+            .iter()
+            .filter(|(key, _)| {
+                key.as_str() != except && split(key).is_some_and(|(namespace, _)| namespace == name)
+            })
+            .fold(Usage::default(), |total, (_, (_, usage))| Usage {
+                keys: total.keys + usage.keys,
+                bytes: total.bytes + usage.bytes,
+            })
+    }
+}
+
+/// Struct representing the usage a write holds in the quota of the namespace of its key, see `reserve`.
+/// Writing the record of the key releases it, dropping it releases it too, e.g. when the write fails.
+pub(crate) struct Reservation {
+    leveldb: record::LevelDb,
+    /// Key and id of the reservation, None if nothing is reserved.
+    key: Option<(String, u64)>,
+}
+
+impl Drop for Reservation {
+    /// Releases the reservation on the blocking thread pool, like the writes of the records.
+    fn drop(&mut self) {
+        let Some((key, id)) = self.key.take() else {
+            return;
+        };
+        let leveldb = self.leveldb.clone();
+        let release = move |leveldb: &record::LevelDb| {
+            leveldb.with_reservations(|reservations| reservations.release_reservation(&key, id));
+            Ok(())
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move { leveldb.blocking(release).await });
+            }
+            Err(_) => {
+                let _ = release(&leveldb);
+            }
+        }
+    }
+}
+
+/// Reserves the usage of writing a value of a size to a key in the quota of its namespace, the value
+/// replacing the current one of the key. The quota is checked against the usage and the reservations of
+/// the writes in flight under the lock of the namespace the usage is updated under, so concurrent writes
+/// can't overshoot it. Only namespaces with a quota are locked.
+/// The caller holds the lock of the key until the reservation is dropped.
+/// Returns None if the write would exceed the quota. Plain keys and namespaces without a quota reserve nothing.
+pub(crate) async fn reserve(
+    leveldb: &record::LevelDb,
+    key: &str,
+    size: u64,
+) -> anyhow::Result<Option<Reservation>> {
+    let Some((name, _)) = split(key) else {
+        return Ok(Some(Reservation {
+            leveldb: leveldb.clone(),
+            key: None,
+        }));
+    };
+    let (name, reserved_key) = (name.to_string(), key.to_string());
+    let reserved = leveldb
+        .blocking(move |leveldb| {
+            let quota = quota(leveldb.store(), &name)?;
+            if quota == Quota::default() {
+                return Ok(Some(None));
+            }
+            leveldb.with_index_lock(&reserved_key, || {
+                let usage = usage(leveldb.store(), &name)?;
+                let held = leveldb
+                    .with_reservations(|reservations| reservations.reserved(&name, &reserved_key));
+                let replaced = leveldb
+                    .stored_record(&reserved_key)?
+                    .filter(|record| record.deleted() == record::Deleted::No)
+                    .map(|record| record.size());
+                let reservation = Usage {
+                    keys: u64::from(replaced.is_none()),
+                    bytes: size.saturating_sub(replaced.unwrap_or_default()),
+                };
+                let keys = usage.keys + held.keys + reservation.keys;
+                let bytes =
+                    (usage.bytes + held.bytes + size).saturating_sub(replaced.unwrap_or_default());
+                if quota.max_keys.is_some_and(|max_keys| keys > max_keys)
+                    || quota.max_bytes.is_some_and(|max_bytes| bytes > max_bytes)
+                {
+                    return Ok(None);
+                }
+                let id = leveldb.with_reservations(|reservations| {
+                    reservations.next_id += 1;
+                    let id = reservations.next_id;
+                    reservations
+                        .keys
+                        .insert(reserved_key.clone(), (id, reservation));
+                    id
+                });
+                Ok(Some(Some((reserved_key.clone(), id))))
+            })
+        })
+        .await?;
+    Ok(reserved.map(|key| Reservation {
+        leveldb: leveldb.clone(),
+        key,
+    }))
+}
+
+/// Returns the stats of the namespaces with live keys, or of a single one, scanning their keys only.
+fn stats(leveldb: &record::LevelDb, name: Option<&str>) -> anyhow::Result<Vec<NamespaceStats>> {
+    let prefix = match name {
+        Some(name) => key(name, ""),
+        None => PREFIX.to_string(),
+    };
+    let now = record::unix_now();
+    let mut namespaces = BTreeMap::<String, NamespaceStats>::new();
+    leveldb.for_each_record_with_prefix(&prefix, |record| {
+        let Some((namespace, _)) = split(record.key()) else {
+            return Ok(());
+        };
+        if !record.is_live(now) {
+            return Ok(());
+        }
+        let stats = namespaces
+            .entry(namespace.to_string())
+            .or_insert_with(|| NamespaceStats {
+                name: namespace.to_string(),
+                ..NamespaceStats::default()
+            });
+        stats.keys += 1;
+        stats.bytes += record.size();
+        Ok(())
+    })?;
+    Ok(namespaces.into_values().collect())
+}
+
+/// Returns the router of the namespace admin endpoints.
+pub(crate) fn router(state: Arc<AppNamespaceState>) -> axum::Router {
+    axum::Router::new()
+        .route(
+            "/admin/namespaces",
+            axum::routing::get(handle_list_namespaces),
+        )
+        .route(
+            "/admin/namespaces/:namespace",
+            axum::routing::get(handle_get_namespace).delete(handle_delete_namespace),
+        )
+        .route(
+            "/admin/namespaces/:namespace/quota",
+            axum::routing::get(handle_get_quota).put(handle_put_quota),
+        )
+        .with_state(state)
+}
+
+/// Handles GET requests listing the namespaces holding live keys with their key count and bytes.
+/// Returns 200 with the namespaces as JSON, `{"namespaces": [{"name": "", "keys": 0, "bytes": 0}]}`
+/// Returns 500 for internal server error
+async fn handle_list_namespaces(
+    axum::extract::State(state): axum::extract::State<Arc<AppNamespaceState>>,
+) -> axum::response::Response {
+    admin::scan_to_json("list_namespaces", move || {
+        let namespaces = stats(&state.leveldb, None)?;
+        Ok(NamespaceList { namespaces })
+    })
+    .await
+}
+
+/// Handles GET requests returning the key count and bytes of a namespace, zero if it holds no live key.
+/// Returns 200 with the stats as JSON
+/// Returns 400 if the namespace name is invalid
+/// Returns 500 for internal server error
+async fn handle_get_namespace(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppNamespaceState>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if !is_valid(&name) {
+        debug!("get_namespace: invalid namespace: {:?}", name);
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    admin::scan_to_json("get_namespace", move || {
+        let stats = stats(&state.leveldb, Some(&name))?;
+        Ok(stats.into_iter().next().unwrap_or(NamespaceStats {
+            name,
+            ..NamespaceStats::default()
+        }))
+    })
+    .await
+}
+
+/// Handles DELETE requests deleting every live key of a namespace, like a DELETE of each key:
+/// the values stay recoverable until the gc grace period passes.
+/// Returns 200 with the deleted keys and the keys that failed to delete as JSON, `{"deleted": 0, "failed": 0}`
+/// Returns 400 if the namespace name is invalid
+/// Returns 500 if the keys can't be listed
+async fn handle_delete_namespace(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppNamespaceState>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if !is_valid(&name) {
+        debug!("delete_namespace: invalid namespace: {:?}", name);
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let leveldb = state.leveldb.clone();
+    let prefix = key(&name, "");
+    let scan = tokio::task::spawn_blocking(move || {
+        let now = record::unix_now();
+        let mut keys = Vec::new();
+        leveldb.for_each_record_with_prefix(&prefix, |record| {
+            if record.is_live(now) {
+                keys.push(record.key().to_string());
+            }
+            Ok(())
+        })?;
+        anyhow::Ok(keys)
+    });
+    let keys = match scan.await {
+        Ok(Ok(keys)) => keys,
+        Ok(Err(e)) => {
+            error!("delete_namespace: failed to scan leveldb: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(e) => {
+            error!("delete_namespace: scan task failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut deleted = DeletedKeys {
+        deleted: 0,
+        failed: 0,
+    };
+    for key in keys {
+        let response = server::handle_delete_record(
+            server::RecordKey(key.clone()),
+            axum::extract::State(state.delete.clone()),
+        )
+        .await;
+        match response.status() {
+            StatusCode::NO_CONTENT => deleted.deleted += 1,
+            // Deleted or expired since the scan
+            StatusCode::NOT_FOUND => {}
+            status => {
+                error!("delete_namespace: failed to delete {}: {}", key, status);
+                deleted.failed += 1;
+            }
+        }
+    }
+    debug!(
+        "delete_namespace: namespace: {} deleted {} keys, {} failed",
+        name, deleted.deleted, deleted.failed
+    );
+    axum::Json(deleted).into_response()
+}
+
+/// Returns the quota of a namespace along with its usage.
+fn quota_status(store: &dyn record::MetadataStore, name: &str) -> anyhow::Result<QuotaStatus> {
+    let quota = quota(store, name)?;
+    let usage = usage(store, name)?;
+    Ok(QuotaStatus {
+        max_keys: quota.max_keys,
+        max_bytes: quota.max_bytes,
+        keys: usage.keys,
+        bytes: usage.bytes,
+    })
+}
+
+/// Handles GET requests returning the quota of a namespace and its usage, the keys that are not deleted
+/// and their bytes. A limit is null if unset.
+/// Returns 200 with the quota as JSON, `{"max_keys": 100, "max_bytes": null, "keys": 2, "bytes": 10}`
+/// Returns 400 if the namespace name is invalid
+/// Returns 500 for internal server error
+async fn handle_get_quota(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppNamespaceState>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if !is_valid(&name) {
+        debug!("get_quota: invalid namespace: {:?}", name);
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let status = {
+        let name = name.clone();
+        state
+            .leveldb
+            .blocking(move |leveldb| quota_status(leveldb.store(), &name))
+            .await
+    };
+    match status {
+        Ok(status) => axum::Json(status).into_response(),
+        Err(e) => {
+            error!("get_quota: failed to get quota of {}: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Handles PUT requests setting the quota of a namespace from a JSON body, `{"max_keys": 100, "max_bytes": 1048576}`.
+/// A missing or null limit is unset, a quota without limits is removed. PUTs exceeding it return 507.
+/// Returns 200 with the quota and the usage as JSON
+/// Returns 400 if the namespace name is invalid
+/// Returns 422 if the body is not a quota
+/// Returns 500 for internal server error
+async fn handle_put_quota(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppNamespaceState>>,
+    axum::Json(quota): axum::Json<Quota>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if !is_valid(&name) {
+        debug!("put_quota: invalid namespace: {:?}", name);
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let status = {
+        let name = name.clone();
+        state
+            .leveldb
+            .blocking(move |leveldb| {
+                put_quota(leveldb.store(), &name, &quota)?;
+                quota_status(leveldb.store(), &name)
+            })
+            .await
+    };
+    match status {
+        Ok(status) => {
+            info!("put_quota: namespace: {} quota: {:?}", name, quota);
+            axum::Json(status).into_response()
+        }
+        Err(e) => {
+            error!("put_quota: failed to set quota of {}: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        assert!(is_valid("app-1.prod_eu"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a/b"));
+        assert!(!is_valid("ns:app"));
+        assert!(!is_valid(&"a".repeat(MAX_NAME_SIZE + 1)));
+
+        let namespaced = key("app", "dir/file");
+        assert_eq!(namespaced, "ns:app/dir/file");
+        assert_eq!(split(&namespaced), Some(("app", "dir/file")));
+        assert_eq!(split("app/dir/file"), None);
+        assert_eq!(split("ns:app"), None);
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::Context;
+use axum::http::{HeaderMap, Method, StatusCode};
+use hmac::{Hmac, Mac};
+use log::debug;
+use sha2::Sha256;
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use crate::{checksum, record};
+
+/// Seconds a presigned URL is valid for when `?presign` comes without `expires`.
+pub const DEFAULT_EXPIRES: u64 = 300;
+
+/// Most seconds a presigned URL can be valid for, a week.
+pub const MAX_EXPIRES: u64 = 7 * 24 * 60 * 60;
+
+/// Shortest key accepted, so the signatures can't be brute forced.
+const MIN_KEY_SIZE: usize = 16;
+
+/// Header nginx sends the URI of the request being authorized in, set with
+/// `proxy_set_header X-Original-URI $request_uri`.
+const ORIGINAL_URI: &str = "X-Original-URI";
+
+/// Header nginx sends the method of the request being authorized in, set with
+/// `proxy_set_header X-Original-Method $request_method`. GET if missing.
+const ORIGINAL_METHOD: &str = "X-Original-Method";
+
+/// Returns the seconds a presigned URL is valid for, the requested `expires` or the default.
+/// Fails if `expires` is 0 or longer than `MAX_EXPIRES`.
+pub(crate) fn expires_in(requested: Option<u64>) -> anyhow::Result<u64> {
+    match requested.unwrap_or(DEFAULT_EXPIRES) {
+        0 => anyhow::bail!("expires must be at least 1 second"),
+        expires if expires > MAX_EXPIRES => anyhow::bail!(
+            "expires must be at most {} seconds, got {}",
+            MAX_EXPIRES,
+            expires
+        ),
+        expires => Ok(expires),
+    }
+}
+
+/// Struct representing the HMAC-SHA256 key the index signs presigned URLs with
+/// and the volumes validate them with.
+#[derive(Clone)]
+pub struct PresignKey {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for PresignKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PresignKey(..)")
+    }
+}
+
+impl PresignKey {
+    /// Reads a presign key file, at least 16 bytes, e.g. written by `openssl rand -hex 32`.
+    /// Trailing whitespace is not part of the key.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("failed to read presign key file {}", path.display()))?;
+        Self::from_bytes(content.trim_ascii_end())
+            .with_context(|| format!("invalid presign key file {}", path.display()))
+    }
+
+    /// Returns the presign key of the content of a key file.
+    fn from_bytes(key: &[u8]) -> anyhow::Result<Self> {
+        if key.len() < MIN_KEY_SIZE {
+            anyhow::bail!(
+                "expected a key of at least {} bytes, got {} bytes",
+                MIN_KEY_SIZE,
+                key.len()
+            );
+        }
+        Ok(Self { key: key.to_vec() })
+    }
+
+    /// Returns the HMAC of a request to a path valid until the unix time, the path as sent in the
+    /// request line. HEAD is signed like GET, so a download URL can be checked first.
+    fn mac(&self, method: &Method, path: &str, expires_at: u64) -> Hmac<Sha256> {
+        let method = match *method {
+            Method::HEAD => &Method::GET,
+            ref method => method,
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n{}\n{}", method, expires_at, path).as_bytes());
+        mac
+    }
+
+    /// Returns the URL with the `expires` and `signature` query parameters granting requests
+    /// of the method to its path until the unix time.
+    pub(crate) fn sign(
+        &self,
+        method: &Method,
+        url: &str,
+        expires_at: u64,
+    ) -> anyhow::Result<String> {
+        let mut url = reqwest::Url::parse(url)?;
+        let signature = checksum::encode_hex(
+            &self
+                .mac(method, url.path(), expires_at)
+                .finalize()
+                .into_bytes(),
+        );
+        url.set_query(Some(&format!(
+            "expires={}&signature={}",
+            expires_at, signature
+        )));
+        Ok(url.into())
+    }
+
+    /// Checks the `expires` and `signature` query parameters of a request to a path.
+    /// Fails if either is missing, the URL expired or the signature isn't the one of the method and path.
+    pub fn verify(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let param = |name: &str| {
+            query
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        };
+        let expires_at: u64 = param("expires")
+            .context("missing expires")?
+            .parse()
+            .context("invalid expires")?;
+        let signature = param("signature")
+            .and_then(checksum::decode_hex)
+            .context("missing or invalid signature")?;
+        if expires_at < now {
+            anyhow::bail!("expired {} seconds ago", now - expires_at);
+        }
+        self.mac(method, path, expires_at)
+            .verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("signature mismatch"))
+    }
+}
+
+/// Struct representing the presigned URLs of `GET /key?presign`: the key signing them and the
+/// public addresses of the volumes, for volumes whose signed downloads are served on another address.
+#[derive(Debug, Clone)]
+pub struct PresignConfig {
+    pub key: PresignKey,
+    /// Public address of a volume by its address in the ring, e.g. "localhost:3001" to
+    /// "files1.example.com:8001" or "https://files1.example.com".
+    pub public_volumes: HashMap<String, String>,
+}
+
+impl PresignConfig {
+    /// Returns the presigned URL of requests of the method to a volume URL, valid until the unix time.
+    pub(crate) fn presign(
+        &self,
+        method: &Method,
+        remote_url: &str,
+        expires_at: u64,
+    ) -> anyhow::Result<String> {
+        let url = reqwest::Url::parse(remote_url)?;
+        let host = url.host_str().unwrap_or_default();
+        let volume = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let public_url = match self.public_volumes.get(&volume) {
+            Some(public) if public.contains("://") => {
+                format!("{}{}", public.trim_end_matches('/'), url.path())
+            }
+            Some(public) => format!("{}://{}{}", url.scheme(), public, url.path()),
+            None => remote_url.to_string(),
+        };
+        self.key.sign(method, &public_url, expires_at)
+    }
+}
+
+/// Returns the router of the `auth_request` hook of nginx volumes serving presigned URLs.
+pub(crate) fn router(config: Arc<PresignConfig>) -> axum::Router {
+    axum::Router::new()
+        .route("/presign/verify", axum::routing::get(handle_verify))
+        .with_state(config)
+}
+
+/// Handles the `auth_request` subrequests of an nginx volume, checking the presigned URL of the
+/// `X-Original-URI` and `X-Original-Method` headers. Served without a token, nginx doesn't forward one.
+/// Returns 200 if the URL is signed and not expired
+/// Returns 400 if a header is missing or invalid
+/// Returns 403 if the signature is missing or wrong, or the URL expired
+async fn handle_verify(
+    axum::extract::State(config): axum::extract::State<Arc<PresignConfig>>,
+    headers: HeaderMap,
+) -> StatusCode {
+    let Some(uri) = headers
+        .get(ORIGINAL_URI)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<axum::http::Uri>().ok())
+    else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let method = match headers.get(ORIGINAL_METHOD) {
+        Some(value) => match Method::from_bytes(value.as_bytes()) {
+            Ok(method) => method,
+            Err(_) => return StatusCode::BAD_REQUEST,
+        },
+        None => Method::GET,
+    };
+    match config
+        .key
+        .verify(&method, uri.path(), uri.query(), record::unix_now())
+    {
+        Ok(()) => StatusCode::OK,
+        Err(e) => {
+            debug!("presign: rejected {} {}: {}", method, uri, e);
+            StatusCode::FORBIDDEN
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() -> anyhow::Result<()> {
+        let key = PresignKey::from_bytes(b"a key of 16 bytes or more")?;
+        assert!(PresignKey::from_bytes(b"short").is_err());
+
+        let url = key.sign(
+            &Method::GET,
+            "http://localhost:3001/sv01/ab/cd/b25Zb3U=",
+            1000,
+        )?;
+        let url = reqwest::Url::parse(&url)?;
+        assert_eq!(url.path(), "/sv01/ab/cd/b25Zb3U=");
+        let verify = |method: &Method, path: &str, query: Option<&str>, now: u64| {
+            key.verify(method, path, query, now)
+        };
+        verify(&Method::GET, url.path(), url.query(), 1000)?;
+        verify(&Method::HEAD, url.path(), url.query(), 1000)?;
+
+        // Expired, another method, another path, another expiry or another key
+        assert!(verify(&Method::GET, url.path(), url.query(), 1001).is_err());
+        assert!(verify(&Method::PUT, url.path(), url.query(), 0).is_err());
+        assert!(verify(&Method::GET, "/sv01/ab/cd/b3RoZXI=", url.query(), 0).is_err());
+        let query = url.query().unwrap_or_default().replace("1000", "2000");
+        assert!(verify(&Method::GET, url.path(), Some(&query), 0).is_err());
+        let other = PresignKey::from_bytes(b"another key of 16 bytes")?;
+        assert!(other
+            .verify(&Method::GET, url.path(), url.query(), 0)
+            .is_err());
+        assert!(verify(&Method::GET, url.path(), None, 0).is_err());
+        assert!(verify(&Method::GET, url.path(), Some("expires=1000"), 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_public_volumes() -> anyhow::Result<()> {
+        let config = PresignConfig {
+            key: PresignKey::from_bytes(b"a key of 16 bytes or more")?,
+            public_volumes: HashMap::from([
+                (
+                    "localhost:3001".to_string(),
+                    "files1.example.com:8001".to_string(),
+                ),
+                (
+                    "localhost:3002".to_string(),
+                    "https://files2.example.com/".to_string(),
+                ),
+            ]),
+        };
+        let path = "/sv01/ab/cd/b25Zb3U=";
+        for (remote_url, prefix) in [
+            ("http://localhost:3001", "http://files1.example.com:8001/"),
+            ("http://localhost:3002", "https://files2.example.com/"),
+            ("http://localhost:3003", "http://localhost:3003/"),
+        ] {
+            let expires_at = record::unix_now() + 60;
+            let url =
+                config.presign(&Method::PUT, &format!("{}{}", remote_url, path), expires_at)?;
+            assert!(url.starts_with(prefix), "{}", url);
+            let url = reqwest::Url::parse(&url)?;
+            config
+                .key
+                .verify(&Method::PUT, url.path(), url.query(), record::unix_now())?;
+        }
+
+        Ok(())
+    }
+}
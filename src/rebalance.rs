@@ -0,0 +1,499 @@
+use anyhow::Context;
+use futures::StreamExt;
+use log::{debug, error, info};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+use crate::{hashring, locks, record, server, tasks};
+
+/// Name of the background rebalance task in the scheduler.
+pub(crate) const TASK_NAME: &str = "rebalance";
+
+/// Default interval between two background rebalance scans.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Struct counting the records handled by a rebalance.
+/// In a dry run, rebalanced counts the records that would move.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct RebalanceStats {
+    pub(crate) balanced: u64,
+    pub(crate) rebalanced: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+    pub(crate) bytes: u64,
+}
+
+/// Struct moving the values of the live records to the volumes the current ring places them on,
+/// after volumes are added or removed.
+pub(crate) struct Rebalance {
+    leveldb: Arc<record::LevelDb>,
+    hashring: hashring::Ring,
+    client: reqwest::Client,
+    concurrency: usize,
+    dry_run: bool,
+}
+
+impl Rebalance {
+    /// Creates a new rebalance of the records of the leveldb to the volumes of the ring.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        hashring: hashring::Ring,
+        concurrency: usize,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            leveldb,
+            hashring,
+            client: reqwest::Client::new(),
+            concurrency: concurrency.max(1),
+            dry_run,
+        }
+    }
+
+    /// Uses the client to copy and delete the values moved between volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Rebalances every live record whose volumes differ from its replicas in the ring.
+    /// Fails only if the leveldb cannot be scanned, records failing to move are counted and logged.
+    pub(crate) async fn run(&self) -> anyhow::Result<RebalanceStats> {
+        let (unbalanced, mut stats) = scan(&self.leveldb, self.hashring.clone()).await?;
+
+        let mut outcomes = futures::stream::iter(unbalanced)
+            .map(|(record, expected)| async move {
+                let key = record.key().to_string();
+                (key, self.rebalance_record(record, expected).await)
+            })
+            .buffer_unordered(self.concurrency);
+        while let Some((key, outcome)) = outcomes.next().await {
+            match outcome {
+                Ok(bytes) => {
+                    stats.rebalanced += 1;
+                    stats.bytes += bytes;
+                }
+                Err(e) => {
+                    error!("rebalance: failed to rebalance key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "rebalance: balanced: {} rebalanced: {} skipped: {} failed: {} bytes: {} dry run: {}",
+            stats.balanced,
+            stats.rebalanced,
+            stats.skipped,
+            stats.failed,
+            stats.bytes,
+            self.dry_run
+        );
+        Ok(stats)
+    }
+
+    /// Copies the value of a record to its missing replicas, or in a dry run counts the bytes it would copy.
+    async fn rebalance_record(
+        &self,
+        record: record::Record,
+        expected: Vec<String>,
+    ) -> anyhow::Result<u64> {
+        debug!(
+            "rebalance: key: {} from: {:?} to: {:?} dry run: {}",
+            record.key(),
+            record.read_volumes(),
+            expected,
+            self.dry_run
+        );
+        if self.dry_run {
+            let missing = expected
+                .iter()
+                .filter(|volume| !record.read_volumes().contains(volume))
+                .count();
+            return Ok(record.stored_size() * missing as u64);
+        }
+        move_record(&self.leveldb, &self.client, record, expected).await
+    }
+}
+
+/// Struct moving the misplaced values of the live records in the background, a limited number of keys per
+/// second, so the values converge to the ring after volumes are added or removed on a running server.
+/// Records are locked while moved, like PUT and DELETE lock them.
+pub(crate) struct AutoRebalance {
+    leveldb: Arc<record::LevelDb>,
+    hashring: Arc<RwLock<hashring::Ring>>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+    keys_per_sec: u32,
+}
+
+impl AutoRebalance {
+    /// Creates a background rebalance of the records of the leveldb to the current ring, moving at most
+    /// the number of keys per second.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        hashring: Arc<RwLock<hashring::Ring>>,
+        key_locks: Arc<locks::KeyLocks>,
+        keys_per_sec: u32,
+    ) -> Self {
+        Self {
+            leveldb,
+            hashring,
+            key_locks,
+            client: reqwest::Client::new(),
+            keys_per_sec: keys_per_sec.max(1),
+        }
+    }
+
+    /// Uses the client to copy and delete the values moved between volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Scans the live records and moves the unbalanced ones one at a time at the rate.
+    /// Records locked or rewritten since the scan are skipped until the next run.
+    pub(crate) async fn run(&self) -> anyhow::Result<RebalanceStats> {
+        let ring = self.hashring.read().clone();
+        let (unbalanced, mut stats) = scan(&self.leveldb, ring).await?;
+
+        let mut ticks = tokio::time::interval(Duration::from_secs(1) / self.keys_per_sec);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        for (record, _) in unbalanced {
+            ticks.tick().await;
+            let key = record.key().to_string();
+            match self.rebalance_record(record).await {
+                Ok(Some(bytes)) => {
+                    stats.rebalanced += 1;
+                    stats.bytes += bytes;
+                }
+                Ok(None) => stats.skipped += 1,
+                Err(e) => {
+                    error!("rebalance: failed to rebalance key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "rebalance: balanced: {} rebalanced: {} skipped: {} failed: {} bytes: {}",
+            stats.balanced, stats.rebalanced, stats.skipped, stats.failed, stats.bytes
+        );
+        Ok(stats)
+    }
+
+    /// Locks a scanned record and moves it to the volumes of the current ring, unless it changed since
+    /// the scan. Returns the number of bytes copied, None if the record was skipped.
+    async fn rebalance_record(&self, record: record::Record) -> anyhow::Result<Option<u64>> {
+        let key = record.key().to_string();
+        let Some(_guard) = self.key_locks.try_lock(&key) else {
+            debug!("rebalance: key: {} locked, skipping", key);
+            return Ok(None);
+        };
+        let current = match self.leveldb.get_record(&key).await? {
+            Some(current) if server::same_write(&current, &record) => current,
+            _ => {
+                debug!("rebalance: key: {} changed since the scan, skipping", key);
+                return Ok(None);
+            }
+        };
+        // The ring may have changed again since the scan
+        let expected = expected_volumes(&self.hashring.read(), &current);
+        if same_volumes(current.read_volumes(), &expected) {
+            return Ok(Some(0));
+        }
+        debug!(
+            "rebalance: key: {} from: {:?} to: {:?}",
+            key,
+            current.read_volumes(),
+            expected
+        );
+        move_record(&self.leveldb, &self.client, current, expected)
+            .await
+            .map(Some)
+    }
+}
+
+/// Returns the scheduler task running a background rebalance, failing if records failed to move.
+pub(crate) fn task(rebalance: Arc<AutoRebalance>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let rebalance = rebalance.clone();
+        Box::pin(async move {
+            let stats = rebalance.run().await?;
+            if stats.failed > 0 {
+                anyhow::bail!("{} records failed to rebalance", stats.failed);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Scans the live records and returns the ones whose volumes differ from their replicas in the ring,
+/// with the volumes they belong to, and the stats counting the balanced and skipped records.
+async fn scan(
+    leveldb: &record::LevelDb,
+    hashring: hashring::Ring,
+) -> anyhow::Result<(Vec<(record::Record, Vec<String>)>, RebalanceStats)> {
+    leveldb
+        .blocking(move |leveldb| {
+            let mut stats = RebalanceStats::default();
+            let mut unbalanced = Vec::new();
+            leveldb.for_each_record(|record| {
+                if record.deleted() != record::Deleted::No {
+                    return Ok(());
+                }
+                // Parts, shards and shared blobs keep their own volumes, multipart, erasure coded
+                // and deduplicated values are left where they are
+                if !record.parts().is_empty()
+                    || record.erasure().is_some()
+                    || record.blob().is_some()
+                {
+                    stats.skipped += 1;
+                    return Ok(());
+                }
+                let expected = expected_volumes(&hashring, &record);
+                if same_volumes(record.read_volumes(), &expected) {
+                    stats.balanced += 1;
+                } else {
+                    unbalanced.push((record, expected));
+                }
+                Ok(())
+            })?;
+            Ok((unbalanced, stats))
+        })
+        .await
+}
+
+/// Returns the volumes the ring places a record on, in its group if the group still exists.
+fn expected_volumes(hashring: &hashring::Ring, record: &record::Record) -> Vec<String> {
+    let placement = record.placement().filter(|group| hashring.has_group(group));
+    hashring.get_volume_with_replicas(record.key(), placement, record.replicas())
+}
+
+/// Copies the value of a record to its missing replicas, updates its volumes in leveldb
+/// and deletes the value from the volumes it no longer belongs to.
+/// Returns the number of bytes copied.
+async fn move_record(
+    leveldb: &record::LevelDb,
+    client: &reqwest::Client,
+    record: record::Record,
+    expected: Vec<String>,
+) -> anyhow::Result<u64> {
+    let key = record.key().to_string();
+    let remote_path = record::get_remote_path(&key);
+    let missing: Vec<&String> = expected
+        .iter()
+        .filter(|volume| !record.read_volumes().contains(volume))
+        .collect();
+    let stale: Vec<String> = record
+        .read_volumes()
+        .iter()
+        .filter(|volume| !expected.contains(volume))
+        .cloned()
+        .collect();
+
+    let mut bytes = 0;
+    for volume in missing {
+        bytes += copy_value(client, record.read_volumes(), volume, &remote_path)
+            .await
+            .with_context(|| format!("failed to copy value to {}", volume))?;
+    }
+
+    leveldb
+        .put_record(&key, record.with_read_volumes(expected))
+        .await?;
+
+    // The record no longer points at the stale copies, a failed delete only leaks space
+    for volume in stale {
+        let remote_url = record::volume_url(&volume, &remote_path);
+        match client.delete(&remote_url).send().await {
+            Ok(res) if res.status().is_success() || res.status() == 404 => (),
+            Ok(res) => error!(
+                "rebalance: failed to delete {}: {}",
+                remote_url,
+                res.status()
+            ),
+            Err(e) => error!("rebalance: failed to delete {}: {}", remote_url, e),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Streams a value from the first source volume holding it to the destination volume.
+/// Returns the number of bytes copied.
+pub(crate) async fn copy_value(
+    client: &reqwest::Client,
+    sources: &[String],
+    destination: &str,
+    remote_path: &str,
+) -> anyhow::Result<u64> {
+    for source in sources {
+        let res = match client
+            .get(record::volume_url(source, remote_path))
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_success() => res,
+            Ok(res) => {
+                debug!(
+                    "copy_value: path: {} not in {}: {}",
+                    remote_path,
+                    source,
+                    res.status()
+                );
+                continue;
+            }
+            Err(e) => {
+                debug!(
+                    "copy_value: failed to get {} from {}: {}",
+                    remote_path, source, e
+                );
+                continue;
+            }
+        };
+        let size = res
+            .content_length()
+            .context("missing Content-Length in source volume")?;
+        let remote_url = record::volume_url(destination, remote_path);
+        let body = reqwest::Body::wrap_stream(res.bytes_stream());
+        server::remote_put(client.clone(), remote_url, body, size).await?;
+        return Ok(size);
+    }
+    anyhow::bail!("value not found in any of {:?}", sources)
+}
+
+/// Returns true if both lists hold the same volumes, in any order.
+fn same_volumes(read_volumes: &[String], expected: &[String]) -> bool {
+    read_volumes.len() == expected.len()
+        && expected.iter().all(|volume| read_volumes.contains(volume))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_rebalance() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(4, 2).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+
+        let old_ring = hashring::Ring::new(cluster.volume_addrs()[..3].to_vec(), 2, 10);
+        let keys: Vec<String> = (0..20).map(|i| format!("key-{}", i)).collect();
+        for key in keys.iter() {
+            let volumes = old_ring.get_volume(key);
+            for volume in volumes.iter() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                client.put(remote_url).body(key.clone()).send().await?;
+            }
+            let record = record::Record::new(record::Deleted::No, String::new(), volumes)
+                .with_size(key.len() as u64);
+            leveldb.put_record(key, record).await?;
+        }
+
+        let new_ring = || hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+        let moved = keys
+            .iter()
+            .filter(|key| !same_volumes(&old_ring.get_volume(key), &new_ring().get_volume(key)))
+            .count() as u64;
+        assert!(moved > 0);
+
+        let stats = Rebalance::new(leveldb.clone(), new_ring(), 4, true)
+            .run()
+            .await?;
+        assert_eq!(stats.balanced, keys.len() as u64 - moved);
+        assert_eq!(stats.rebalanced, moved);
+        assert_eq!(cluster.volume(3).len(), 0);
+
+        let stats = Rebalance::new(leveldb.clone(), new_ring(), 4, false)
+            .run()
+            .await?;
+        assert_eq!(stats.rebalanced, moved);
+        assert_eq!(stats.failed, 0);
+
+        let stored: usize = (0..4).map(|i| cluster.volume(i).len()).sum();
+        let expected: usize = keys
+            .iter()
+            .map(|key| new_ring().get_volume(key).len())
+            .sum();
+        assert_eq!(stored, expected);
+        for key in keys.iter() {
+            let record = leveldb.get_record(key).await?.unwrap();
+            assert_eq!(record.read_volumes(), &new_ring().get_volume(key));
+            for volume in record.read_volumes() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                assert_eq!(client.get(remote_url).send().await?.text().await?, *key);
+            }
+        }
+
+        let stats = Rebalance::new(leveldb, new_ring(), 4, false).run().await?;
+        assert_eq!(stats.balanced, keys.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_rebalance() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(4, 2).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+
+        let old_ring = hashring::Ring::new(cluster.volume_addrs()[..3].to_vec(), 2, 10);
+        let new_ring = hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+        let keys: Vec<String> = (0..20).map(|i| format!("key-{}", i)).collect();
+        for key in keys.iter() {
+            let volumes = old_ring.get_volume(key);
+            for volume in volumes.iter() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                client.put(remote_url).body(key.clone()).send().await?;
+            }
+            let record = record::Record::new(record::Deleted::No, String::new(), volumes)
+                .with_size(key.len() as u64);
+            leveldb.put_record(key, record).await?;
+        }
+        let moved: Vec<&String> = keys
+            .iter()
+            .filter(|key| !same_volumes(&old_ring.get_volume(key), &new_ring.get_volume(key)))
+            .collect();
+        assert!(moved.len() > 1);
+
+        // A key locked by a write is left for the next run
+        let key_locks = locks::KeyLocks::new(Duration::from_secs(1));
+        let rebalance = AutoRebalance::new(
+            leveldb.clone(),
+            Arc::new(RwLock::new(new_ring.clone())),
+            key_locks.clone(),
+            1000,
+        );
+        let guard = key_locks.try_lock(moved[0]);
+        let stats = rebalance.run().await?;
+        assert_eq!(stats.rebalanced, moved.len() as u64 - 1);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.failed, 0);
+        drop(guard);
+
+        let stats = rebalance.run().await?;
+        assert_eq!(stats.rebalanced, 1);
+        assert_eq!(stats.balanced, keys.len() as u64 - 1);
+        for key in keys.iter() {
+            let record = leveldb.get_record(key).await?.unwrap();
+            assert_eq!(record.read_volumes(), &new_ring.get_volume(key));
+            for volume in record.read_volumes() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                assert_eq!(client.get(remote_url).send().await?.text().await?, *key);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::{hashring, record};
+
+/// Depth of the directories walked in a volume: the subvolume, then the two md5 levels of the key.
+const MAX_DEPTH: usize = 3;
+
+/// Struct representing an entry of a volume directory listing, as served by nginx with
+/// `autoindex_format json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DirEntry {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    #[serde(default)]
+    pub(crate) size: u64,
+}
+
+/// Struct counting the keys handled by a rebuild.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct RebuildStats {
+    pub(crate) rebuilt: u64,
+    pub(crate) existing: u64,
+    pub(crate) parts: u64,
+    pub(crate) invalid: u64,
+    pub(crate) failed: u64,
+}
+
+/// Struct representing the replicas of a key found while walking the volumes.
+#[derive(Debug, Default)]
+struct Found {
+    size: u64,
+    read_volumes: Vec<String>,
+}
+
+/// Struct reconstructing the leveldb records from the blobs stored in the volumes,
+/// for when the leveldb directory is lost.
+pub(crate) struct Rebuild {
+    leveldb: Arc<record::LevelDb>,
+    hashring: hashring::Ring,
+    volumes: Vec<String>,
+    client: reqwest::Client,
+}
+
+impl Rebuild {
+    /// Creates a new rebuild walking the volumes and writing records to the leveldb.
+    /// The ring recomputes the placement group and the order of the replicas of every key.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        hashring: hashring::Ring,
+        volumes: Vec<String>,
+    ) -> Self {
+        Self {
+            leveldb,
+            hashring,
+            volumes,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses the client to list the blobs of the volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Walks every volume and writes a live record for every key found in them.
+    /// Existing records are kept, so a rebuild never resurrects a deleted key still in the leveldb.
+    /// Fails only if a volume cannot be listed, records failing to write are counted and logged.
+    pub(crate) async fn run(&self) -> anyhow::Result<RebuildStats> {
+        let mut stats = RebuildStats::default();
+        let mut found: BTreeMap<String, Found> = BTreeMap::new();
+        for volume in self.volumes.iter() {
+            self.walk_volume(volume, &mut found, &mut stats).await?;
+        }
+
+        for (key, found) in found {
+            // Parts of multipart uploads can't be stitched back without their part list
+            if key.starts_with(record::PART_PREFIX) {
+                debug!("rebuild: key: {:?} is a multipart part, skipping", key);
+                stats.parts += 1;
+                continue;
+            }
+            // Deduplicated blobs don't name the keys sharing them, shards can't be decoded without their record
+            if key.starts_with(record::RESERVED_PREFIX) {
+                debug!(
+                    "rebuild: key: {:?} is a deduplicated blob or a shard, skipping",
+                    key
+                );
+                stats.invalid += 1;
+                continue;
+            }
+            match self.rebuild_record(&key, found).await {
+                Ok(true) => stats.rebuilt += 1,
+                Ok(false) => stats.existing += 1,
+                Err(e) => {
+                    error!("rebuild: failed to rebuild key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "rebuild: rebuilt: {} existing: {} parts: {} invalid: {} failed: {}",
+            stats.rebuilt, stats.existing, stats.parts, stats.invalid, stats.failed
+        );
+        Ok(stats)
+    }
+
+    /// Walks the directories of a volume and collects the keys of the blobs stored in it.
+    async fn walk_volume(
+        &self,
+        volume: &str,
+        found: &mut BTreeMap<String, Found>,
+        stats: &mut RebuildStats,
+    ) -> anyhow::Result<()> {
+        let mut dirs = vec![(String::from("/"), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            for entry in self.list_dir(volume, &dir).await? {
+                let path = format!("{}{}", dir, entry.name);
+                match entry.kind.as_str() {
+                    "directory" if depth < MAX_DEPTH => dirs.push((path + "/", depth + 1)),
+                    "file" => match key_from_path(&path) {
+                        Some((key, subvolume)) => {
+                            let found = found.entry(key).or_default();
+                            if !found.read_volumes.is_empty() && found.size != entry.size {
+                                warn!(
+                                    "rebuild: path: {} in volume {} has size {}, expected: {}",
+                                    path, volume, entry.size, found.size
+                                );
+                            }
+                            found.size = entry.size;
+                            found.read_volumes.push(format!("{}{}", volume, subvolume));
+                        }
+                        None => {
+                            debug!("rebuild: path: {} in volume {} is not a key", path, volume);
+                            stats.invalid += 1;
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists a directory of a volume.
+    async fn list_dir(&self, volume: &str, dir: &str) -> anyhow::Result<Vec<DirEntry>> {
+        let entries = self
+            .client
+            .get(record::volume_url(volume, dir))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("failed to list {} in volume {}", dir, volume))?
+            .json::<Vec<DirEntry>>()
+            .await
+            .with_context(|| format!("invalid listing of {} in volume {}", dir, volume))?;
+        Ok(entries)
+    }
+
+    /// Writes the record of a key found in the volumes, unless the leveldb already has one.
+    /// Returns true if the record is written.
+    async fn rebuild_record(&self, key: &str, found: Found) -> anyhow::Result<bool> {
+        if self.leveldb.get_record(key).await?.is_some() {
+            debug!("rebuild: key: {} already has a record", key);
+            return Ok(false);
+        }
+
+        let placement = self.hashring.placement_group(key).map(String::from);
+        let expected = self.hashring.get_volume_in_group(key, placement.as_deref());
+        let mut read_volumes = found.read_volumes;
+        // Replicas in their ring order first, so reads and rebalance see the usual order
+        read_volumes.sort_by_key(|volume| {
+            expected
+                .iter()
+                .position(|expected| expected == volume)
+                .unwrap_or(expected.len())
+        });
+        read_volumes.dedup();
+
+        let record = record::Record::new(record::Deleted::No, String::new(), read_volumes)
+            .with_placement(placement)
+            .with_size(found.size);
+        self.leveldb.put_record(key, record).await?;
+        Ok(true)
+    }
+}
+
+/// Returns the key stored at a volume path and the subvolume prefix of the path, e.g. `/sv01`.
+/// Returns None if the path is not the remote path of its decoded key.
+fn key_from_path(path: &str) -> Option<(String, &str)> {
+    let (_, encoded) = path.rsplit_once('/')?;
+    let key = base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE, encoded).ok()?;
+    let key = String::from_utf8(key).ok()?;
+    let subvolume = path.strip_suffix(&record::get_remote_path(&key))?;
+    Some((key, subvolume))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[test]
+    fn test_key_from_path() {
+        let remote_path = record::get_remote_path("wehave");
+        assert_eq!(
+            key_from_path(&format!("/sv01{}", remote_path)),
+            Some(("wehave".to_string(), "/sv01"))
+        );
+        assert_eq!(
+            key_from_path(&remote_path),
+            Some(("wehave".to_string(), ""))
+        );
+        assert_eq!(key_from_path("/sv01/00/00/d2VoYXZl"), None);
+        assert_eq!(key_from_path("/nginx.pid"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in ["a", "b-c"] {
+            let res = client.put(cluster.key_url(key)).body(key).send().await?;
+            assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        }
+        let res = client
+            .put(cluster.key_url("m"))
+            .query(&[("partNumber", "1")])
+            .body("part")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let hashring = hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+        leveldb
+            .put_record(
+                "b-c",
+                record::Record::new(record::Deleted::Soft, String::new(), Vec::new()),
+            )
+            .await?;
+
+        let stats = Rebuild::new(leveldb.clone(), hashring, cluster.volume_addrs().to_vec())
+            .run()
+            .await?;
+        assert_eq!(
+            stats,
+            RebuildStats {
+                rebuilt: 1,
+                existing: 1,
+                parts: 1,
+                invalid: 0,
+                failed: 0,
+            }
+        );
+
+        let record = leveldb.get_record("a").await?.unwrap();
+        assert_eq!(record.deleted(), record::Deleted::No);
+        assert_eq!(record.size(), 1);
+        assert_eq!(
+            record.read_volumes(),
+            &hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10).get_volume("a")
+        );
+        let record = leveldb.get_record("b-c").await?.unwrap();
+        assert_eq!(record.deleted(), record::Deleted::Soft);
+
+        Ok(())
+    }
+}
@@ -1,11 +1,26 @@
 use anyhow::Context;
+#[cfg(feature = "leveldb")]
 use leveldb::database::Database;
+#[cfg(feature = "leveldb")]
+use leveldb::iterator::{Iterable, LevelDBIterator};
+#[cfg(feature = "leveldb")]
 use leveldb::kv::KV;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{checksum, encryption, namespace, volume_keys};
+
+/// First byte of a versioned record. Unversioned records start with the variant of `Deleted`,
+/// a little endian u32 below 4, so the two never clash.
+const RECORD_MAGIC: u8 = 0xFE;
+
+/// Version of the record layout written by `Record::to_bytes`, bumped on any change of the fields.
+pub const RECORD_VERSION: u8 = 1;
 
 /// Enum representing the deletion status of a record in leveldb.
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum Deleted {
+pub enum Deleted {
     No,
     Soft,
     Hard,
@@ -13,11 +28,87 @@ pub(crate) enum Deleted {
 }
 
 /// Struct representing a record in the leveldb database.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub(crate) struct Record {
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Record {
     deleted: Deleted,
     hash: String,
     read_volumes: Vec<String>,
+    placement: Option<String>,
+    key: String,
+    size: u64,
+    expires_at: Option<u64>,
+    deleted_at: Option<u64>,
+    checksums: Vec<(checksum::Algorithm, String)>,
+    content_disposition: Option<String>,
+    content_type: Option<String>,
+    parts: Vec<Part>,
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+    replicas: Option<u32>,
+    erasure: Option<Erasure>,
+    refcount: Option<u64>,
+    blob: Option<String>,
+    hash_algorithm: Option<checksum::Algorithm>,
+    encoding: Option<Encoding>,
+    encryption: Option<Encryption>,
+}
+
+/// Struct counting the records handled by a migration to the latest record layout.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct MigrationStats {
+    pub(crate) scanned: u64,
+    pub(crate) migrated: u64,
+    pub(crate) failed: u64,
+}
+
+/// Struct representing a part of a multipart upload, stored at the remote path of its part key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub number: u32,
+    pub hash: String,
+    pub size: u64,
+    pub volumes: Vec<String>,
+}
+
+/// Struct representing the Reed-Solomon shards of an erasure coded value,
+/// the data shards first and the parity shards after them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Erasure {
+    pub data_shards: u32,
+    pub shards: Vec<Shard>,
+}
+
+/// Struct representing a shard of an erasure coded value, stored at the remote path of its shard key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Shard {
+    pub hash: String,
+    pub volume: String,
+}
+
+/// Struct representing the compression of a value written to the volumes, e.g. `zstd`,
+/// and the size of the compressed value stored on them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Encoding {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Struct representing the encryption of a value written to the volumes: the nonce of the value
+/// and its data key, wrapped by the master key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+}
+
+impl Erasure {
+    /// Returns the volume of every shard, in shard order.
+    pub fn volumes(&self) -> Vec<&str> {
+        self.shards
+            .iter()
+            .map(|shard| shard.volume.as_str())
+            .collect()
+    }
 }
 
 impl Record {
@@ -27,105 +118,1164 @@ impl Record {
             deleted,
             hash,
             read_volumes,
+            placement: None,
+            key: String::new(),
+            size: 0,
+            expires_at: None,
+            deleted_at: None,
+            checksums: Vec::new(),
+            content_disposition: None,
+            content_type: None,
+            parts: Vec::new(),
+            created_at: None,
+            updated_at: None,
+            replicas: None,
+            erasure: None,
+            refcount: None,
+            blob: None,
+            hash_algorithm: None,
+            encoding: None,
+            encryption: None,
         }
     }
 
+    /// Sets the Content-Disposition returned with the value of the leveldb record, e.g. `attachment; filename="cat.png"`.
+    pub(crate) fn with_content_disposition(mut self, content_disposition: Option<String>) -> Self {
+        self.content_disposition = content_disposition;
+        self
+    }
+
+    /// Sets the Content-Type returned with the value of the leveldb record, e.g. `image/png`.
+    pub(crate) fn with_content_type(mut self, content_type: Option<String>) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Sets the parts the value of the leveldb record is stitched from, in order.
+    pub(crate) fn with_parts(mut self, parts: Vec<Part>) -> Self {
+        self.parts = parts;
+        self
+    }
+
+    /// Sets the base64 encoded digests of the value of the leveldb record.
+    pub(crate) fn with_checksums(mut self, checksums: Vec<(checksum::Algorithm, String)>) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Sets the deletion status of the leveldb record, keeping every other field.
+    pub(crate) fn with_deleted(mut self, deleted: Deleted) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    /// Sets the time the leveldb record was deleted as seconds since the unix epoch.
+    pub(crate) fn with_deleted_at(mut self, deleted_at: Option<u64>) -> Self {
+        self.deleted_at = deleted_at;
+        self
+    }
+
+    /// Sets the times the key was first written and its value last written, as seconds since the unix epoch.
+    pub(crate) fn with_timestamps(mut self, created_at: u64, updated_at: u64) -> Self {
+        self.created_at = Some(created_at);
+        self.updated_at = Some(updated_at);
+        self
+    }
+
+    /// Sets the expiry of the leveldb record as seconds since the unix epoch.
+    pub(crate) fn with_expires_at(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Sets the size in bytes of the value of the leveldb record.
+    pub(crate) fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Sets the volumes the value of the leveldb record is read from.
+    pub(crate) fn with_read_volumes(mut self, read_volumes: Vec<String>) -> Self {
+        self.read_volumes = read_volumes;
+        self
+    }
+
+    /// Sets the volume group the leveldb record is pinned to.
+    pub(crate) fn with_placement(mut self, placement: Option<String>) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Sets the number of replicas of the leveldb record, None to use the replicas of the ring.
+    pub(crate) fn with_replicas(mut self, replicas: Option<usize>) -> Self {
+        self.replicas = replicas.map(|replicas| replicas as u32);
+        self
+    }
+
+    /// Sets the shards the value of the leveldb record is erasure coded into, None if it is replicated.
+    pub(crate) fn with_erasure(mut self, erasure: Option<Erasure>) -> Self {
+        self.erasure = erasure;
+        self
+    }
+
+    /// Sets the number of uploads of a content addressed value that share the leveldb record.
+    pub(crate) fn with_refcount(mut self, refcount: Option<u64>) -> Self {
+        self.refcount = refcount;
+        self
+    }
+
+    /// Sets the SHA-256 of the deduplicated blob holding the value of the leveldb record,
+    /// None if the value is stored at the remote path of the key.
+    pub(crate) fn with_blob(mut self, blob: Option<String>) -> Self {
+        self.blob = blob;
+        self
+    }
+
+    /// Sets the algorithm of the hash of the leveldb record. MD5 is stored as None, like in the records
+    /// written before the algorithm was configurable.
+    pub(crate) fn with_hash_algorithm(mut self, hash_algorithm: checksum::Algorithm) -> Self {
+        self.hash_algorithm =
+            (hash_algorithm != checksum::Algorithm::Md5).then_some(hash_algorithm);
+        self
+    }
+
+    /// Sets the compression of the value of the leveldb record on the volumes, None if stored as uploaded.
+    pub(crate) fn with_encoding(mut self, encoding: Option<Encoding>) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the encryption of the value of the leveldb record on the volumes, None if stored in the clear.
+    pub(crate) fn with_encryption(mut self, encryption: Option<Encryption>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
     /// Returns the deletion status of the leveldb record.
-    pub(crate) fn deleted(&self) -> Deleted {
+    pub fn deleted(&self) -> Deleted {
         self.deleted
     }
 
     /// Returns the hash of the leveldb record.
-    pub(crate) fn hash(&self) -> &str {
+    pub fn hash(&self) -> &str {
         &self.hash
     }
 
+    /// Returns the algorithm of the hash of the leveldb record, MD5 unless written with another `--checksum`.
+    pub fn hash_algorithm(&self) -> checksum::Algorithm {
+        self.hash_algorithm.unwrap_or(checksum::Algorithm::Md5)
+    }
+
     /// Returns the read volumes of the leveldb record.
-    pub(crate) fn read_volumes(&self) -> &Vec<String> {
+    pub fn read_volumes(&self) -> &Vec<String> {
         &self.read_volumes
     }
 
-    /// Serializes the leveldb record to bytes.
-    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
-        bincode::serialize(self).map_err(|e| anyhow::anyhow!("Serialization error: {}", e))
+    /// Returns the volume group the leveldb record is pinned to, None if placed by the default ring.
+    pub fn placement(&self) -> Option<&str> {
+        self.placement.as_deref()
+    }
+
+    /// Returns the key of the leveldb record, set when the record is put into the database.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the base64 encoded digest of the value for an algorithm, None if it was not computed.
+    pub fn checksum(&self, algorithm: checksum::Algorithm) -> Option<&str> {
+        self.checksums
+            .iter()
+            .find(|(stored, _)| *stored == algorithm)
+            .map(|(_, digest)| digest.as_str())
+    }
+
+    /// Returns the strong ETag of the value of the leveldb record, its quoted hash.
+    /// None if the value was stored without a hash.
+    pub fn etag(&self) -> Option<String> {
+        (!self.hash.is_empty()).then(|| format!("\"{}\"", self.hash))
+    }
+
+    /// Returns the URLs of the blobs of the value of the leveldb record of a key on its volumes,
+    /// the blobs of the parts of multipart values and the shards of erasure coded values included.
+    pub(crate) fn remote_urls(&self, key: &str) -> Vec<String> {
+        let remote_path = self.remote_path(key);
+        let mut remote_urls: Vec<String> = self
+            .read_volumes
+            .iter()
+            .map(|volume| volume_url(volume, &remote_path))
+            .collect();
+        for part in self.parts.iter() {
+            let remote_path = get_remote_path(&part_key(key, part.number));
+            remote_urls.extend(
+                part.volumes
+                    .iter()
+                    .map(|volume| volume_url(volume, &remote_path)),
+            );
+        }
+        if let Some(erasure) = &self.erasure {
+            remote_urls.extend(erasure.shards.iter().enumerate().map(|(index, shard)| {
+                volume_url(&shard.volume, &get_remote_path(&shard_key(key, index)))
+            }));
+        }
+        remote_urls
+    }
+
+    /// Returns the Content-Disposition of the value of the leveldb record, None if not set.
+    pub fn content_disposition(&self) -> Option<&str> {
+        self.content_disposition.as_deref()
+    }
+
+    /// Returns the Content-Type of the value of the leveldb record, None if not set.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Returns the parts the value of the leveldb record is stitched from, empty if uploaded in one PUT.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// Returns the shards the value of the leveldb record is erasure coded into, None if it is replicated.
+    pub fn erasure(&self) -> Option<&Erasure> {
+        self.erasure.as_ref()
+    }
+
+    /// Returns the number of uploads sharing a content addressed leveldb record, None for other records.
+    /// A DELETE drops one of them and deletes the record with the last one.
+    pub fn refcount(&self) -> Option<u64> {
+        self.refcount
+    }
+
+    /// Returns the hex SHA-256 of the blob the value is shared through with every key of the same content,
+    /// None if the value is not deduplicated.
+    pub fn blob(&self) -> Option<&str> {
+        self.blob.as_deref()
+    }
+
+    /// Returns the remote path of the value of the leveldb record of a key,
+    /// the path of its blob for a deduplicated value.
+    pub(crate) fn remote_path(&self, key: &str) -> String {
+        match &self.blob {
+            Some(hash) => get_remote_path(&blob_key(hash)),
+            None => get_remote_path(key),
+        }
+    }
+
+    /// Returns the size in bytes of the value of the leveldb record.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the compression of the value on the volumes, None if it is stored as uploaded.
+    pub fn encoding(&self) -> Option<&Encoding> {
+        self.encoding.as_ref()
+    }
+
+    /// Returns the encryption of the value on the volumes, None if it is stored in the clear.
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    /// Returns the size in bytes of the value as stored on the volumes, smaller than `size` if compressed
+    /// and a little larger if encrypted.
+    pub fn stored_size(&self) -> u64 {
+        let size = self
+            .encoding
+            .as_ref()
+            .map_or(self.size, |encoding| encoding.size);
+        match self.encryption {
+            Some(_) => encryption::sealed_size(size),
+            None => size,
+        }
+    }
+
+    /// Returns the expiry of the leveldb record as seconds since the unix epoch, None if it never expires.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Returns the time the leveldb record was deleted as seconds since the unix epoch, None if it is not deleted.
+    pub fn deleted_at(&self) -> Option<u64> {
+        self.deleted_at
+    }
+
+    /// Returns the time the key was first written as seconds since the unix epoch,
+    /// None for records written before timestamps were recorded or rebuilt from the volumes.
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// Returns the time the value of the leveldb record was last written as seconds since the unix epoch,
+    /// None if unknown.
+    pub fn updated_at(&self) -> Option<u64> {
+        self.updated_at
+    }
+
+    /// Returns the number of replicas the leveldb record was written with, None if it uses the replicas of the ring.
+    pub fn replicas(&self) -> Option<usize> {
+        self.replicas.map(|replicas| replicas as usize)
+    }
+
+    /// Returns true if the leveldb record expired at the given time in seconds since the unix epoch.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns true if the value of the leveldb record can be read at the given time,
+    /// i.e. it is not deleted and not expired.
+    pub fn is_live(&self, now: u64) -> bool {
+        self.deleted == Deleted::No && !self.is_expired(now)
     }
 
-    /// Deserializes the leveldb record from bytes.
-    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
-        bincode::deserialize(bytes).map_err(|e| anyhow::anyhow!("Deserialization error: {}", e))
+    /// Serializes the leveldb record to bytes, the magic and version byte followed by the bincode fields.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![RECORD_MAGIC, RECORD_VERSION];
+        bincode::serialize_into(&mut bytes, self)
+            .map_err(|e| anyhow::anyhow!("Serialization error: {}", e))?;
+        Ok(bytes)
+    }
+
+    /// Deserializes the leveldb record from bytes, JSON or bincode, versioned or written before records had a version.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if RecordEncoding::Json.is_current(bytes) {
+            return serde_json::from_slice(bytes)
+                .map_err(|e| anyhow::anyhow!("Deserialization error: {}", e));
+        }
+        match Self::version(bytes) {
+            Some(RECORD_VERSION) => bincode::deserialize(&bytes[2..])
+                .map_err(|e| anyhow::anyhow!("Deserialization error: {}", e)),
+            Some(version) => anyhow::bail!(
+                "Deserialization error: record version {} is newer than {}",
+                version,
+                RECORD_VERSION
+            ),
+            None => Self::from_unversioned_bytes(bytes, false)
+                .or_else(|_| Self::from_unversioned_bytes(bytes, true))
+                .map_err(|e| anyhow::anyhow!("Deserialization error: {}", e)),
+        }
+    }
+
+    /// Returns the version of the serialized record, None for a record written before records had a version.
+    pub fn version(bytes: &[u8]) -> Option<u8> {
+        match bytes {
+            [RECORD_MAGIC, version, ..] => Some(*version),
+            _ => None,
+        }
+    }
+
+    /// Deserializes a record written before records had a version. Fields were appended over time,
+    /// so the bytes end after any field from `read_volumes` on and the missing ones take their default.
+    /// Records written before `content_type` have `parts` right after `content_disposition`.
+    fn from_unversioned_bytes(
+        bytes: &[u8],
+        parts_before_content_type: bool,
+    ) -> bincode::Result<Self> {
+        use bincode::Options;
+        // The layout of bincode::serialize, with lengths misread from another layout failing instead of allocating
+        let options = bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(bytes.len() as u64);
+        let mut reader = std::io::Cursor::new(bytes);
+        let mut record = Self {
+            deleted: options.deserialize_from(&mut reader)?,
+            hash: options.deserialize_from(&mut reader)?,
+            read_volumes: options.deserialize_from(&mut reader)?,
+            ..Self::default()
+        };
+        macro_rules! field {
+            ($field:ident) => {
+                if reader.position() == bytes.len() as u64 {
+                    return Ok(record);
+                }
+                record.$field = options.deserialize_from(&mut reader)?;
+            };
+        }
+        field!(placement);
+        field!(key);
+        field!(size);
+        field!(expires_at);
+        field!(deleted_at);
+        field!(checksums);
+        field!(content_disposition);
+        if parts_before_content_type {
+            field!(parts);
+        } else {
+            field!(content_type);
+            field!(parts);
+            field!(created_at);
+            field!(updated_at);
+            field!(replicas);
+            field!(erasure);
+            field!(refcount);
+            field!(blob);
+            field!(hash_algorithm);
+            field!(encoding);
+            field!(encryption);
+        }
+        if reader.position() != bytes.len() as u64 {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "{} trailing bytes",
+                bytes.len() as u64 - reader.position()
+            ))));
+        }
+        Ok(record)
     }
 }
 
-/// Default record for leveldb. Deleted is Init, hash is empty and read_volumes is empty.
+/// Default record for leveldb. Deleted is Init, hash is empty, read_volumes is empty, placement is None, key is empty,
+/// size is 0, expires_at is None, deleted_at is None, checksums is empty, content_disposition is None,
+/// content_type is None, parts is empty, created_at is None, updated_at is None,
+/// replicas is None, erasure is None, refcount is None, blob is None, hash_algorithm is None,
+/// encoding is None and encryption is None.
 impl Default for Record {
     fn default() -> Self {
         Self {
             deleted: Deleted::Init,
             hash: String::new(),
             read_volumes: Vec::new(),
+            placement: None,
+            key: String::new(),
+            size: 0,
+            expires_at: None,
+            deleted_at: None,
+            checksums: Vec::new(),
+            content_disposition: None,
+            content_type: None,
+            parts: Vec::new(),
+            created_at: None,
+            updated_at: None,
+            replicas: None,
+            erasure: None,
+            refcount: None,
+            blob: None,
+            hash_algorithm: None,
+            encoding: None,
+            encryption: None,
         }
     }
 }
 
-/// Type representing the key in the leveldb database. Must be i32.
-pub(crate) type LevelDbKey = i32;
+/// Returns the current time in seconds since the unix epoch.
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
 
-/// Converts a string key to a LevelDbKey.
-pub(crate) fn leveldb_key_from_str(key: &str) -> LevelDbKey {
-    // TODO make sure i32 is always positive and use only the lower 31 bits of the hash
-    let leveldb_key: i32 = (gxhash::gxhash32(key.as_bytes(), 0) & 0x7FFFFFFF) as i32;
-    leveldb_key
+/// Enum representing the metadata stores the index can be backed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DbBackend {
+    /// LevelDB through the C++ library, the default where it builds
+    #[cfg(feature = "leveldb")]
+    Leveldb,
+    /// sled, a pure-Rust embedded store that runs natively on every platform, e.g. Windows
+    Sled,
 }
 
-/// Struct representing a LevelDB database.
-pub(crate) struct LevelDb {
+impl Default for DbBackend {
+    fn default() -> Self {
+        #[cfg(feature = "leveldb")]
+        return DbBackend::Leveldb;
+        #[cfg(not(feature = "leveldb"))]
+        return DbBackend::Sled;
+    }
+}
+
+/// Enum representing how the records are serialized in the metadata store.
+/// Records of either encoding are read whatever the encoding records are written with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RecordEncoding {
+    /// Compact bincode behind a version byte
+    #[default]
+    Bincode,
+    /// Self-describing JSON objects, readable by other tools and languages
+    Json,
+}
+
+impl RecordEncoding {
+    /// Serializes a record in the encoding.
+    pub fn encode(self, record: &Record) -> anyhow::Result<Vec<u8>> {
+        match self {
+            RecordEncoding::Bincode => record.to_bytes(),
+            RecordEncoding::Json => serde_json::to_vec(record)
+                .map_err(|e| anyhow::anyhow!("Serialization error: {}", e)),
+        }
+    }
+
+    /// Returns true if the serialized record is in the encoding and the latest layout.
+    /// JSON records start with `{`, which is neither the magic of versioned records nor a variant of `Deleted`.
+    pub fn is_current(self, bytes: &[u8]) -> bool {
+        match self {
+            RecordEncoding::Bincode => Record::version(bytes) == Some(RECORD_VERSION),
+            RecordEncoding::Json => bytes.first() == Some(&b'{'),
+        }
+    }
+}
+
+/// Trait representing an embedded key-value store holding the serialized records.
+pub trait MetadataStore: Send + Sync {
+    /// Puts the serialized record of a key.
+    fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+
+    /// Gets the serialized record of a key.
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Removes the record of a key, if any.
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Calls the closure for every key starting with the prefix and its value, in key order.
+    /// An empty prefix visits the whole store. Stops at the first error returned by the closure.
+    fn for_each_entry(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()>;
+
+    /// Calls the closure for every key starting with the prefix from the start key on, and its value, in key order,
+    /// until the closure returns false. Stops at the first error returned by the closure.
+    /// The default visits the whole prefix with `for_each_entry`, stores that can seek to the start key override it.
+    fn for_each_entry_from(
+        &self,
+        prefix: &str,
+        start: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        let mut done = false;
+        self.for_each_entry(prefix, &mut |key, value| {
+            if !done && key >= start {
+                done = !f(key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Makes the writes so far durable, called once the server stopped writing on shutdown.
+    /// Stores writing through to disk have nothing to do.
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Type representing the key in the leveldb database, the bytes of the record key.
+#[cfg(feature = "leveldb")]
+struct LevelDbKey(Vec<u8>);
+
+#[cfg(feature = "leveldb")]
+impl db_key::Key for LevelDbKey {
+    fn from_u8(key: &[u8]) -> Self {
+        Self(key.to_vec())
+    }
+
+    fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
+        f(&self.0)
+    }
+}
+
+#[cfg(feature = "leveldb")]
+impl LevelDbKey {
+    /// Returns the leveldb key of a record key.
+    fn from_str(key: &str) -> Self {
+        Self(key.as_bytes().to_vec())
+    }
+}
+
+/// Key of the entry marking a LevelDB as keyed on the full record keys.
+/// Record keys are never empty, so the marker never collides with a record.
+#[cfg(feature = "leveldb")]
+const FULL_KEYS_MARKER: &[u8] = b"";
+
+/// Returns the key a record was stored under before LevelDB was keyed on the full record keys,
+/// the lower 31 bits of its gxhash as a big endian i32.
+/// Two record keys with the same hash overwrote each other's records.
+#[cfg(feature = "leveldb")]
+fn legacy_leveldb_key(key: &str) -> [u8; 4] {
+    let leveldb_key = (gxhash::gxhash32(key.as_bytes(), 0) & 0x7FFFFFFF) as i32;
+    leveldb_key.to_be_bytes()
+}
+
+/// Returns every key and value of a LevelDB written by another program, e.g. the index of the Go
+/// minikeyvalue, in key order. The LevelDB is only read, it must exist and not be open elsewhere.
+#[cfg(feature = "leveldb")]
+pub(crate) fn read_foreign_leveldb(
+    ldb_path: &std::path::Path,
+) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let leveldb: Database<LevelDbKey> =
+        leveldb::database::Database::open(ldb_path, leveldb::options::Options::new())
+            .with_context(|| format!("Failed to open LevelDB at path: {}", ldb_path.display()))?;
+    Ok(leveldb
+        .iter(leveldb::options::ReadOptions::new())
+        .map(|(key, value)| (key.0, value))
+        .collect())
+}
+
+/// Reading another program's LevelDB needs the LevelDB backend, built with the `leveldb` feature.
+#[cfg(not(feature = "leveldb"))]
+pub(crate) fn read_foreign_leveldb(
+    ldb_path: &std::path::Path,
+) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    anyhow::bail!(
+        "Reading the LevelDB at {} requires building with the leveldb feature",
+        ldb_path.display()
+    )
+}
+
+/// Struct representing a metadata store backed by LevelDB, keyed on the full key bytes.
+#[cfg(feature = "leveldb")]
+struct LevelDbStore {
     leveldb: Database<LevelDbKey>,
 }
 
-impl LevelDb {
-    /// Creates a new LevelDb instance.
-    pub(crate) fn new(ldb_path: &std::path::Path) -> anyhow::Result<Self> {
+#[cfg(feature = "leveldb")]
+impl LevelDbStore {
+    /// Opens or creates the LevelDB at the path, migrating the records of a LevelDB
+    /// keyed on hashes to their full keys.
+    fn open(ldb_path: &std::path::Path) -> anyhow::Result<Self> {
         let mut leveldb_options = leveldb::options::Options::new();
         leveldb_options.create_if_missing = true;
 
         let leveldb = leveldb::database::Database::open(ldb_path, leveldb_options)
             .with_context(|| format!("Failed to open LevelDB at path: {}", ldb_path.display()))?;
 
-        Ok(Self { leveldb })
+        let store = Self { leveldb };
+        store.migrate_legacy_keys()?;
+        Ok(store)
+    }
+
+    /// Moves every record stored under its hash to its full key, once per LevelDB.
+    /// Records are written under their full key before their hash key is removed,
+    /// so an interrupted migration is resumed on the next open.
+    /// Fails without changing the LevelDB if a record predates records holding their key,
+    /// its key can only be recovered from the volumes by `rebuild`.
+    fn migrate_legacy_keys(&self) -> anyhow::Result<()> {
+        let marker = LevelDbKey(FULL_KEYS_MARKER.to_vec());
+        if self
+            .leveldb
+            .get(leveldb::options::ReadOptions::new(), &marker)?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let mut legacy = Vec::new();
+        let mut keyless = 0;
+        for (leveldb_key, value) in self.leveldb.iter(leveldb::options::ReadOptions::new()) {
+            if leveldb_key.0.len() != 4 {
+                continue;
+            }
+            let key = match Record::from_bytes(&value) {
+                Ok(record) => record.key,
+                Err(e) => {
+                    log::warn!(
+                        "leveldb: skipping undecodable record at {:?}: {}",
+                        leveldb_key.0,
+                        e
+                    );
+                    continue;
+                }
+            };
+            // A record written before records held their key, only its hash is known
+            if key.is_empty() {
+                keyless += 1;
+                continue;
+            }
+            // A four byte key already stored under its full key
+            if key.as_bytes() == leveldb_key.0.as_slice() {
+                continue;
+            }
+            if legacy_leveldb_key(&key) != leveldb_key.0.as_slice() {
+                log::warn!(
+                    "leveldb: record of key {} stored at {:?} is not at its hash",
+                    key,
+                    leveldb_key.0
+                );
+                continue;
+            }
+            legacy.push((leveldb_key, key, value));
+        }
+        if keyless > 0 {
+            anyhow::bail!(
+                "{} records of the LevelDB were written before records held their key and can't be migrated, \
+                 move the LevelDB aside and run `rebuild` into a new --leveldb-path to restore them from the volumes",
+                keyless
+            );
+        }
+
+        for (leveldb_key, key, value) in legacy.iter() {
+            self.put(key, value)?;
+            self.leveldb
+                .delete(leveldb::options::WriteOptions::new(), leveldb_key)
+                .with_context(|| format!("Failed to delete the hash key of {}", key))?;
+        }
+        self.leveldb
+            .put(leveldb::options::WriteOptions::new(), &marker, &[])
+            .context("Failed to mark LevelDB as keyed on full keys")?;
+        if !legacy.is_empty() {
+            log::info!("leveldb: migrated {} records to full keys", legacy.len());
+        }
+        Ok(())
     }
+}
 
-    /// Puts a record into the database. Calls record.to_bytes() to serialize the record.
-    pub(crate) async fn put_record(&self, key: &str, record: Record) -> anyhow::Result<()> {
-        let leveldb_key = leveldb_key_from_str(key);
+#[cfg(feature = "leveldb")]
+impl MetadataStore for LevelDbStore {
+    fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
         let write_options = leveldb::options::WriteOptions::new();
         self.leveldb
-            .put(write_options, leveldb_key, &record.to_bytes()?)
-            .with_context(|| {
-                format!(
-                    "Failed to put record for key {} and leveldb_key {}",
-                    key, leveldb_key
-                )
-            })?;
+            .put(write_options, LevelDbKey::from_str(key), value)
+            .with_context(|| format!("Failed to put record for key {} in LevelDB", key))
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let read_options = leveldb::options::ReadOptions::new();
+        self.leveldb
+            .get(read_options, LevelDbKey::from_str(key))
+            .with_context(|| format!("Failed to get key {} from LevelDB", key))
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let write_options = leveldb::options::WriteOptions::new();
+        self.leveldb
+            .delete(write_options, LevelDbKey::from_str(key))
+            .with_context(|| format!("Failed to delete key {} from LevelDB", key))
+    }
+
+    fn for_each_entry(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let read_options = leveldb::options::ReadOptions::new();
+        let from = LevelDbKey::from_str(prefix);
+        for (key, value) in self.leveldb.iter(read_options).from(&from) {
+            if !key.0.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if key.0 == FULL_KEYS_MARKER {
+                continue;
+            }
+            f(&String::from_utf8_lossy(&key.0), &value)?;
+        }
         Ok(())
     }
 
-    /// Gets a record from the database. Calls Record::from_bytes() to deserialize the record.
-    pub(crate) async fn get_record(&self, key: &str) -> anyhow::Result<Option<Record>> {
+    fn for_each_entry_from(
+        &self,
+        prefix: &str,
+        start: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
         let read_options = leveldb::options::ReadOptions::new();
-        let leveldb_key = leveldb_key_from_str(key);
+        let from = LevelDbKey::from_str(start.max(prefix));
+        for (key, value) in self.leveldb.iter(read_options).from(&from) {
+            if !key.0.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if key.0 == FULL_KEYS_MARKER {
+                continue;
+            }
+            if !f(&String::from_utf8_lossy(&key.0), &value)? {
+                break;
+            }
+        }
+        Ok(())
+    }
 
-        let record = self
-            .leveldb
-            .get(read_options, leveldb_key)
-            .with_context(|| format!("Failed to get key {} from LevelDB", key))?;
+    fn flush(&self) -> anyhow::Result<()> {
+        // A synced write syncs the log of every write before it, the marker is rewritten unchanged
+        let mut write_options = leveldb::options::WriteOptions::new();
+        write_options.sync = true;
+        self.leveldb
+            .put(write_options, LevelDbKey(FULL_KEYS_MARKER.to_vec()), &[])
+            .context("Failed to sync LevelDB")
+    }
+}
 
-        if let Some(record) = record {
-            Ok(Some(Record::from_bytes(&record)?))
-        } else {
-            Ok(None)
+/// Struct representing a metadata store backed by sled, keyed on the full key bytes.
+struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    /// Opens or creates the sled database at the path.
+    fn open(path: &std::path::Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("Failed to open sled at path: {}", path.display()))?;
+        Ok(Self { db })
+    }
+}
+
+impl MetadataStore for SledStore {
+    fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.db
+            .insert(key.as_bytes(), value)
+            .with_context(|| format!("Failed to put record for key {} in sled", key))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let value = self
+            .db
+            .get(key.as_bytes())
+            .with_context(|| format!("Failed to get key {} from sled", key))?;
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.db
+            .remove(key.as_bytes())
+            .with_context(|| format!("Failed to delete key {} from sled", key))?;
+        Ok(())
+    }
+
+    fn for_each_entry(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for entry in self.db.scan_prefix(prefix.as_bytes()) {
+            let (key, value) = entry?;
+            f(&String::from_utf8_lossy(&key), &value)?;
+        }
+        Ok(())
+    }
+
+    fn for_each_entry_from(
+        &self,
+        prefix: &str,
+        start: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        for entry in self.db.range(start.max(prefix).as_bytes()..) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix.as_bytes()) || !f(&String::from_utf8_lossy(&key), &value)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.db.flush().context("Failed to flush sled")?;
+        Ok(())
+    }
+}
+
+/// Struct representing the locks serializing the writes of records with the updates of the entries indexing them,
+/// one per namespace for its keys, whose writes all update its usage, and one per key for the other keys.
+/// A lock is removed from the map once its last user is gone, like `locks::KeyLocks`.
+#[derive(Default)]
+struct IndexLocks {
+    locks: parking_lot::Mutex<std::collections::HashMap<String, Arc<parking_lot::Mutex<()>>>>,
+}
+
+impl IndexLocks {
+    /// Calls the closure holding the lock of the namespace of a key, or of the key itself, blocking.
+    fn with_lock<T>(&self, key: &str, f: impl FnOnce() -> T) -> T {
+        let name = match namespace::split(key) {
+            Some((name, _)) => namespace::key(name, ""),
+            None => key.to_string(),
+        };
+        let mutex = self.locks.lock().entry(name.clone()).or_default().clone();
+        let result = {
+            let _guard = mutex.lock();
+            f()
+        };
+        let mut locks = self.locks.lock();
+        // Only the map and this call hold the lock, no one else waits for it
+        if locks
+            .get(&name)
+            .is_some_and(|held| Arc::ptr_eq(held, &mutex) && Arc::strong_count(&mutex) == 2)
+        {
+            locks.remove(&name);
+        }
+        result
+    }
+
+    /// Returns the number of namespaces and keys locked or waited for.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().len()
+    }
+}
+
+/// Struct representing the records last read or written, least recently used first out.
+struct RecordCache {
+    records: lru::LruCache<String, Record>,
+    /// Writes so far, a read only caches the record it got from the store if none happened meanwhile.
+    writes: u64,
+}
+
+/// Default seconds a key is remembered as missing by `--negative-cache`.
+pub const DEFAULT_NEGATIVE_CACHE_TTL_SECS: u64 = 5;
+
+/// Struct representing the negative cache, remembering the keys found without a record for a while
+/// so that probes of keys that don't exist skip the metadata store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegativeCacheConfig {
+    /// Keys remembered, least recently used first out.
+    pub capacity: usize,
+    /// Time a key is remembered as missing.
+    pub ttl: Duration,
+}
+
+/// Struct representing the keys last found without a record, with the time they were.
+struct MissingKeys {
+    keys: lru::LruCache<String, Instant>,
+    ttl: Duration,
+    /// Writes so far, a read only remembers a missing key if none happened meanwhile.
+    writes: u64,
+}
+
+/// Struct representing the record database of the index, LevelDB or another metadata store.
+/// Clones share the store, so a clone runs the blocking operations of the async ones, see `LevelDb::blocking`.
+#[derive(Clone)]
+pub(crate) struct LevelDb {
+    store: Arc<dyn MetadataStore>,
+    /// Cache of the deserialized records, None if disabled.
+    cache: Option<Arc<parking_lot::Mutex<RecordCache>>>,
+    /// Negative cache of the keys without a record, None if disabled.
+    missing: Option<Arc<parking_lot::Mutex<MissingKeys>>>,
+    /// Serialize the writes of records with the updates of the entries indexing them,
+    /// the usage of their namespace and the keys of their volumes.
+    index_locks: Arc<IndexLocks>,
+    /// Usage reserved in the quotas of their namespaces by the writes in flight, only locked to read or update them.
+    reservations: Arc<parking_lot::Mutex<namespace::Reservations>>,
+    /// Encoding the records are written with.
+    encoding: RecordEncoding,
+}
+
+impl LevelDb {
+    /// Creates a new LevelDb instance storing the records in the metadata store, e.g. one embedded by another service.
+    pub(crate) fn with_store(store: Box<dyn MetadataStore>) -> Self {
+        Self {
+            store: Arc::from(store),
+            index_locks: Arc::default(),
+            reservations: Arc::default(),
+            encoding: RecordEncoding::default(),
+            cache: None,
+            missing: None,
+        }
+    }
+
+    /// Keeps up to that many records in memory, updated by every write of the index server.
+    /// The metadata store must not be written by another process meanwhile.
+    pub(crate) fn with_record_cache(mut self, capacity: Option<usize>) -> Self {
+        self.cache = capacity.map(|capacity| {
+            Arc::new(parking_lot::Mutex::new(RecordCache {
+                records: lru::LruCache::new(capacity),
+                writes: 0,
+            }))
+        });
+        self
+    }
+
+    /// Remembers the keys found without a record for a while, forgotten as soon as the index server writes them.
+    /// The metadata store must not be written by another process meanwhile.
+    pub(crate) fn with_negative_cache(mut self, config: Option<NegativeCacheConfig>) -> Self {
+        self.missing = config.map(|config| {
+            Arc::new(parking_lot::Mutex::new(MissingKeys {
+                keys: lru::LruCache::new(config.capacity),
+                ttl: config.ttl,
+                writes: 0,
+            }))
+        });
+        self
+    }
+
+    /// Runs blocking operations of the metadata store on the blocking thread pool, so disk reads,
+    /// syncs and compaction stalls hold up a pool thread instead of an async worker.
+    pub(crate) async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&LevelDb) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let leveldb = self.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || span.in_scope(|| f(&leveldb)))
+            .await
+            .context("Metadata store task failed")?
+    }
+
+    /// Replaces the cached record of a key with the one written, None for a deleted key.
+    fn cache_write(&self, key: &str, record: Option<&Record>) {
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock();
+            cache.writes += 1;
+            match record {
+                Some(record) => {
+                    cache.records.put(key.to_string(), record.clone());
+                }
+                None => {
+                    cache.records.pop(key);
+                }
+            }
+        }
+        if let Some(missing) = &self.missing {
+            let mut missing = missing.lock();
+            missing.writes += 1;
+            missing.keys.pop(key);
         }
     }
 
+    /// Writes the records with the encoding, records already written are read as they are.
+    pub(crate) fn with_record_encoding(mut self, encoding: RecordEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Creates a new LevelDb instance with the given backend.
+    pub(crate) fn with_backend(
+        ldb_path: &std::path::Path,
+        backend: DbBackend,
+    ) -> anyhow::Result<Self> {
+        let store: Box<dyn MetadataStore> = match backend {
+            #[cfg(feature = "leveldb")]
+            DbBackend::Leveldb => Box::new(LevelDbStore::open(ldb_path)?),
+            DbBackend::Sled => Box::new(SledStore::open(ldb_path)?),
+        };
+        Ok(Self::with_store(store))
+    }
+
+    /// Puts a record into the database, serialized in the encoding of the leveldb.
+    /// The key is stored in the record so the database can be iterated.
+    /// Records of a namespace update its usage, see `namespace::track_usage`,
+    /// and every record the index of the keys of its volumes, see `volume_keys::track`.
+    #[tracing::instrument(name = "metadata_put", skip(self, record))]
+    pub(crate) async fn put_record(&self, key: &str, mut record: Record) -> anyhow::Result<()> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::inject_metadata_fault(key)?;
+
+        record.key = key.to_string();
+        self.blocking(move |leveldb| leveldb.put_stored_record(record))
+            .await
+    }
+
+    /// Puts a record into the metadata store under its key, blocking.
+    /// The usage reserved for the key is released, the record now counts in place of it.
+    fn put_stored_record(&self, record: Record) -> anyhow::Result<()> {
+        let key = record.key.as_str();
+        self.with_index_lock(key, || {
+            let current = self.stored_record(key)?;
+            self.store
+                .put(key, &self.encoding.encode(&record)?)
+                .inspect_err(|_| count_error("put"))?;
+            self.with_reservations(|reservations| reservations.release(key));
+            self.cache_write(key, Some(&record));
+            self.track(key, current.as_ref(), Some(&record))
+        })
+    }
+
+    /// Calls the closure holding the lock the record of a key is written under, the lock of its namespace
+    /// for a namespaced key, blocking. See `namespace::reserve`.
+    pub(crate) fn with_index_lock<T>(&self, key: &str, f: impl FnOnce() -> T) -> T {
+        self.index_locks.with_lock(key, f)
+    }
+
+    /// Calls the closure with the quota reservations of the namespaces, blocking. See `namespace::reserve`.
+    pub(crate) fn with_reservations<T>(
+        &self,
+        f: impl FnOnce(&mut namespace::Reservations) -> T,
+    ) -> T {
+        f(&mut self.reservations.lock())
+    }
+
+    /// Updates the entries indexing a record that is replaced, None for a missing record.
+    fn track(
+        &self,
+        key: &str,
+        current: Option<&Record>,
+        new: Option<&Record>,
+    ) -> anyhow::Result<()> {
+        namespace::track_usage(self.store(), key, current, new)?;
+        volume_keys::track(self.store(), key, current, new)
+    }
+
+    /// Gets a record from the cache or the metadata store, without fault injection, blocking.
+    pub(crate) fn stored_record(&self, key: &str) -> anyhow::Result<Option<Record>> {
+        let writes = match &self.cache {
+            Some(cache) => {
+                let mut cache = cache.lock();
+                if let Some(record) = cache.records.get(key) {
+                    crate::metrics::METRICS.record_cache_hits.inc();
+                    return Ok(Some(record.clone()));
+                }
+                crate::metrics::METRICS.record_cache_misses.inc();
+                Some(cache.writes)
+            }
+            None => None,
+        };
+        let missing_writes = match &self.missing {
+            Some(missing) => {
+                let mut missing = missing.lock();
+                let ttl = missing.ttl;
+                match missing.keys.get(key) {
+                    Some(found_at) if found_at.elapsed() < ttl => {
+                        crate::metrics::METRICS.negative_cache_hits.inc();
+                        return Ok(None);
+                    }
+                    Some(_) => {
+                        missing.keys.pop(key);
+                    }
+                    None => {}
+                }
+                Some(missing.writes)
+            }
+            None => None,
+        };
+        let record = self
+            .store
+            .get(key)
+            .inspect_err(|_| count_error("get"))?
+            .map(|record| Record::from_bytes(&record))
+            .transpose()?;
+        if let (Some(cache), Some(writes), Some(record)) = (&self.cache, writes, &record) {
+            let mut cache = cache.lock();
+            if cache.writes == writes {
+                cache.records.put(key.to_string(), record.clone());
+            }
+        }
+        if let (Some(missing), Some(writes), None) = (&self.missing, missing_writes, &record) {
+            let mut missing = missing.lock();
+            if missing.writes == writes {
+                missing.keys.put(key.to_string(), Instant::now());
+            }
+        }
+        Ok(record)
+    }
+
+    /// Gets a record from the database. Calls Record::from_bytes() to deserialize the record.
+    #[tracing::instrument(name = "metadata_get", skip(self))]
+    pub(crate) async fn get_record(&self, key: &str) -> anyhow::Result<Option<Record>> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::inject_metadata_fault(key)?;
+
+        let key = key.to_string();
+        self.blocking(move |leveldb| leveldb.stored_record(&key))
+            .await
+    }
+
+    /// Removes a record from the database, e.g. the part records of a completed multipart upload.
+    #[tracing::instrument(name = "metadata_delete", skip(self))]
+    pub(crate) async fn delete_record(&self, key: &str) -> anyhow::Result<()> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::inject_metadata_fault(key)?;
+
+        let key = key.to_string();
+        self.blocking(move |leveldb| {
+            leveldb.with_index_lock(&key, || {
+                let current = leveldb.stored_record(&key)?;
+                leveldb
+                    .store
+                    .delete(&key)
+                    .inspect_err(|_| count_error("delete"))?;
+                leveldb.cache_write(&key, None);
+                leveldb.track(&key, current.as_ref(), None)
+            })
+        })
+        .await
+    }
+
     /// Gets a record from the database or returns a default record.
     /// Calls Record::from_bytes() to deserialize the record.
     /// A default record is returned if the record is not found.
@@ -133,6 +1283,152 @@ impl LevelDb {
         let record = self.get_record(key).await?;
         Ok(record.unwrap_or(Record::default()))
     }
+
+    /// Calls the closure for every record in the database, in the key order of the backend.
+    /// Entries under the reserved prefix are skipped, but the part records. Stops at the first error returned by the closure.
+    pub(crate) fn for_each_record(
+        &self,
+        f: impl FnMut(Record) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.for_each_record_with_prefix("", f)
+    }
+
+    /// Calls the closure for every record whose key starts with a prefix, like `for_each_record`
+    /// but seeking to the prefix instead of scanning the whole database.
+    pub(crate) fn for_each_record_with_prefix(
+        &self,
+        prefix: &str,
+        mut f: impl FnMut(Record) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.store.for_each_entry(prefix, &mut |key, value| {
+            if !is_record_key(key) {
+                return Ok(());
+            }
+            f(Record::from_bytes(value)?)
+        })
+    }
+
+    /// Calls the closure for every record whose key starts with a prefix from the start key on,
+    /// like `for_each_record_with_prefix` but seeking to the start key, until the closure returns false.
+    pub(crate) fn for_each_record_from(
+        &self,
+        prefix: &str,
+        start: &str,
+        mut f: impl FnMut(Record) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        self.store
+            .for_each_entry_from(prefix, start, &mut |key, value| {
+                if !is_record_key(key) {
+                    return Ok(true);
+                }
+                f(Record::from_bytes(value)?)
+            })
+    }
+
+    /// Rewrites the records not in the encoding of the leveldb, or written before records had a version,
+    /// in that encoding and the layout of `RECORD_VERSION`. Records that can't be decoded are logged, counted as failed and left as they are.
+    pub(crate) fn migrate_records(&self) -> anyhow::Result<MigrationStats> {
+        let mut stats = MigrationStats::default();
+        let mut outdated = Vec::new();
+        self.store.for_each_entry("", &mut |key, value| {
+            if !is_record_key(key) {
+                return Ok(());
+            }
+            stats.scanned += 1;
+            if self.encoding.is_current(value) {
+                return Ok(());
+            }
+            match Record::from_bytes(value) {
+                Ok(record) => outdated.push((key.to_string(), value.to_vec(), record)),
+                Err(e) => {
+                    log::warn!("migrate: skipping undecodable record {}: {}", key, e);
+                    stats.failed += 1;
+                }
+            }
+            Ok(())
+        })?;
+        for (key, value, record) in outdated {
+            // A record changed since the scan is left as it was written
+            let migrated = self.with_index_lock(&key, || {
+                if self.store.get(&key)?.as_deref() != Some(value.as_slice()) {
+                    return anyhow::Ok(false);
+                }
+                self.store
+                    .put(&key, &self.encoding.encode(&record)?)
+                    .inspect_err(|_| count_error("put"))?;
+                Ok(true)
+            })?;
+            if migrated {
+                stats.migrated += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Makes the records written so far durable, see `MetadataStore::flush`.
+    pub(crate) fn flush(&self) -> anyhow::Result<()> {
+        self.store.flush()
+    }
+
+    /// Returns the metadata store, for the entries kept under the reserved prefix, e.g. the replication queue.
+    pub(crate) fn store(&self) -> &dyn MetadataStore {
+        self.store.as_ref()
+    }
+}
+
+/// Counts a failed operation of the metadata store.
+fn count_error(operation: &str) {
+    crate::metrics::METRICS
+        .leveldb_errors
+        .with_label_values(&[operation])
+        .inc();
+}
+
+/// Prefix of the metadata store keys that are not records, e.g. the replication queue.
+/// Record keys starting with it are rejected on PUT.
+pub(crate) const RESERVED_PREFIX: &str = "\0";
+
+/// Prefix of the records of the parts of multipart uploads, under the reserved prefix
+/// so a part never shares its key, or its remote path, with a record key.
+pub(crate) const PART_PREFIX: &str = "\0part/";
+
+/// Returns the key of the leveldb record of a part of a multipart upload, under `PART_PREFIX`.
+/// Parts are stored as records of their own with the Init status until the upload is completed.
+pub(crate) fn part_key(key: &str, number: u32) -> String {
+    format!("{}{}/{}", PART_PREFIX, key, number)
+}
+
+/// Returns the key of the upload a part key belongs to, None for keys that are not part keys.
+pub(crate) fn upload_key(part_key: &str) -> Option<&str> {
+    let (key, _) = part_key.strip_prefix(PART_PREFIX)?.rsplit_once('/')?;
+    Some(key)
+}
+
+/// Returns true if the entry of the metadata store under the key is a record, the part records included.
+fn is_record_key(key: &str) -> bool {
+    !key.starts_with(RESERVED_PREFIX) || key.starts_with(PART_PREFIX)
+}
+
+/// Returns the key of the blob of a shard of an erasure coded value, under the reserved prefix
+/// so a shard is never stored at the remote path of a record key.
+pub(crate) fn shard_key(key: &str, index: usize) -> String {
+    format!("\0shard/{}/{}", key, index)
+}
+
+/// Returns the key of a deduplicated blob from the SHA-256 of its content, under the reserved prefix.
+/// The blob is stored at the remote path of this key and its references are counted in the metadata store under it.
+pub(crate) fn blob_key(hash: &str) -> String {
+    format!("\0blob/{}", hash)
+}
+
+/// Returns the URL of a remote path on a volume. Volumes are `host:port` served over HTTP,
+/// or URLs with a scheme, e.g. `https://host:port` for volumes serving HTTPS.
+pub(crate) fn volume_url(volume: &str, remote_path: &str) -> String {
+    if volume.starts_with("http://") || volume.starts_with("https://") {
+        format!("{}{}", volume, remote_path)
+    } else {
+        format!("http://{}{}", volume, remote_path)
+    }
 }
 
 /// Gets the remote path for a key.
@@ -153,6 +1449,36 @@ mod tests {
             deleted: Deleted::Hard,
             hash: "1234567890".to_string(),
             read_volumes: vec!["vol1".to_string(), "vol2".to_string()],
+            placement: Some("ssd".to_string()),
+            key: "key".to_string(),
+            size: 5,
+            expires_at: Some(1),
+            deleted_at: Some(2),
+            checksums: vec![(checksum::Algorithm::Crc32c, "mnG7TA==".to_string())],
+            content_disposition: Some("attachment".to_string()),
+            content_type: Some("text/plain".to_string()),
+            parts: Vec::new(),
+            created_at: Some(3),
+            updated_at: Some(4),
+            replicas: Some(5),
+            erasure: Some(Erasure {
+                data_shards: 1,
+                shards: vec![Shard {
+                    hash: "6".to_string(),
+                    volume: "vol1".to_string(),
+                }],
+            }),
+            refcount: Some(7),
+            blob: Some("a1b2".to_string()),
+            hash_algorithm: Some(checksum::Algorithm::Blake3),
+            encoding: Some(Encoding {
+                name: "zstd".to_string(),
+                size: 5,
+            }),
+            encryption: Some(Encryption {
+                nonce: vec![1; 7],
+                wrapped_key: vec![2; 60],
+            }),
         };
         let bytes = record.to_bytes()?;
         let deserialized_record = Record::from_bytes(&bytes)?;
@@ -166,7 +1492,8 @@ mod tests {
         let bytes = [
             2, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 49, 50, 51, 52, 53, 54, 55, 56, 57, 48, 2, 0, 0,
             0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 118, 111, 108, 49, 4, 0, 0, 0, 0, 0, 0, 0, 118,
-            111, 108, 50,
+            111, 108, 50, 0, 3, 0, 0, 0, 0, 0, 0, 0, 107, 101, 121, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
         let record = Record::from_bytes(&bytes)?;
 
@@ -174,6 +1501,24 @@ mod tests {
             deleted: Deleted::Hard,
             hash: "1234567890".to_string(),
             read_volumes: vec!["vol1".to_string(), "vol2".to_string()],
+            placement: None,
+            key: "key".to_string(),
+            size: 5,
+            expires_at: None,
+            deleted_at: None,
+            checksums: Vec::new(),
+            content_disposition: None,
+            content_type: None,
+            parts: Vec::new(),
+            created_at: None,
+            updated_at: None,
+            replicas: None,
+            erasure: None,
+            refcount: None,
+            blob: None,
+            hash_algorithm: None,
+            encoding: None,
+            encryption: None,
         };
 
         assert_eq!(record, expected_record);
@@ -181,6 +1526,166 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_record_versions() -> anyhow::Result<()> {
+        let record = Record::new(Deleted::No, "hash".to_string(), vec!["vol1".to_string()])
+            .with_content_disposition(Some("attachment".to_string()))
+            .with_parts(vec![Part {
+                number: 1,
+                hash: "part".to_string(),
+                size: 3,
+                volumes: vec!["vol2".to_string()],
+            }])
+            .with_size(3);
+        let bytes = record.to_bytes()?;
+        assert_eq!(Record::version(&bytes), Some(RECORD_VERSION));
+
+        // The current layout without a version, and the first one of three fields
+        let unversioned = bincode::serialize(&record)?;
+        assert_eq!(Record::version(&unversioned), None);
+        assert_eq!(Record::from_bytes(&unversioned)?, record);
+        let first = bincode::serialize(&(Deleted::Soft, "hash", vec!["vol1"]))?;
+        assert_eq!(
+            Record::from_bytes(&first)?,
+            Record::new(Deleted::Soft, "hash".to_string(), vec!["vol1".to_string()])
+        );
+
+        // The parts came right after the content disposition before the content type was stored
+        let before_content_type = bincode::serialize(&(
+            (Deleted::No, "hash", vec!["vol1"], None::<String>, ""),
+            (3u64, None::<u64>, None::<u64>),
+            (
+                Vec::<(checksum::Algorithm, String)>::new(),
+                Some("attachment"),
+            ),
+            record.parts(),
+        ))?;
+        assert_eq!(Record::from_bytes(&before_content_type)?, record);
+
+        let mut newer = bytes.clone();
+        newer[1] = RECORD_VERSION + 1;
+        assert!(Record::from_bytes(&newer).is_err());
+        let mut trailing = unversioned;
+        trailing.push(0);
+        assert!(Record::from_bytes(&trailing).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_records() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let leveldb = LevelDb::with_backend(dir.path(), DbBackend::Sled)?;
+        let record = Record::new(Deleted::No, "hash".to_string(), vec!["vol1".to_string()]);
+        leveldb.store().put("old", &bincode::serialize(&record)?)?;
+        leveldb.store().put("new", &record.to_bytes()?)?;
+        leveldb.store().put("broken", &[0, 0, 0, 0, 1])?;
+
+        let stats = leveldb.migrate_records()?;
+        assert_eq!(
+            stats,
+            MigrationStats {
+                scanned: 3,
+                migrated: 1,
+                failed: 1,
+            }
+        );
+        let migrated = leveldb.store().get("old")?.unwrap();
+        assert_eq!(Record::version(&migrated), Some(RECORD_VERSION));
+        assert_eq!(Record::from_bytes(&migrated)?, record);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record_encodings() -> anyhow::Result<()> {
+        let record = Record::new(Deleted::No, "hash".to_string(), vec!["vol1".to_string()])
+            .with_checksums(vec![(checksum::Algorithm::Sha256, "c2hh".to_string())])
+            .with_size(3);
+        let json = RecordEncoding::Json.encode(&record)?;
+        assert!(RecordEncoding::Json.is_current(&json));
+        assert!(!RecordEncoding::Bincode.is_current(&json));
+        assert_eq!(Record::from_bytes(&json)?, record);
+        assert!(Record::from_bytes(br#"{"deleted":"Soft","hash":"hash"}"#).is_err());
+
+        let dir = tempfile::tempdir()?;
+        let leveldb = LevelDb::with_backend(dir.path(), DbBackend::Sled)?
+            .with_record_encoding(RecordEncoding::Json);
+        leveldb.put_record("json", record.clone()).await?;
+        let stored = leveldb.store().get("json")?.unwrap();
+        assert!(stored.starts_with(b"{\"deleted\":\"No\""));
+        leveldb.store().put("bincode", &record.to_bytes()?)?;
+        assert_eq!(leveldb.migrate_records()?.migrated, 1);
+        assert!(RecordEncoding::Json.is_current(&leveldb.store().get("bincode")?.unwrap()));
+
+        let leveldb = leveldb.with_record_encoding(RecordEncoding::Bincode);
+        assert_eq!(leveldb.migrate_records()?.migrated, 2);
+        assert_eq!(leveldb.get_record("json").await?.unwrap().key(), "json");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record_cache() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let leveldb =
+            LevelDb::with_backend(dir.path(), DbBackend::Sled)?.with_record_cache(Some(2));
+        let record = |hash: &str| Record::new(Deleted::No, hash.to_string(), Vec::new());
+        for key in ["a", "b"] {
+            leveldb.put_record(key, record(key)).await?;
+        }
+
+        // Writes behind the back of the cache are not seen while the record is cached
+        leveldb.store().put("a", &record("behind").to_bytes()?)?;
+        assert_eq!(leveldb.get_record("a").await?.unwrap().hash(), "a");
+        leveldb.put_record("a", record("a2")).await?;
+        assert_eq!(leveldb.get_record("a").await?.unwrap().hash(), "a2");
+        leveldb.delete_record("b").await?;
+        assert_eq!(leveldb.get_record("b").await?, None);
+
+        // The least recently used record is evicted and read from the store again
+        leveldb.put_record("c", record("c")).await?;
+        leveldb.put_record("d", record("d")).await?;
+        leveldb.store().put("a", &record("behind").to_bytes()?)?;
+        assert_eq!(leveldb.get_record("a").await?.unwrap().hash(), "behind");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let leveldb = LevelDb::with_backend(dir.path(), DbBackend::Sled)?.with_negative_cache(
+            Some(NegativeCacheConfig {
+                capacity: 10,
+                ttl: Duration::from_secs(60),
+            }),
+        );
+        let record = |hash: &str| Record::new(Deleted::No, hash.to_string(), Vec::new());
+
+        // A missing key is remembered, writes behind the back of the cache are not seen
+        assert_eq!(leveldb.get_record("a").await?, None);
+        leveldb.store().put("a", &record("behind").to_bytes()?)?;
+        assert_eq!(leveldb.get_record("a").await?, None);
+
+        // A PUT forgets it
+        leveldb.put_record("a", record("a")).await?;
+        assert_eq!(leveldb.get_record("a").await?.unwrap().hash(), "a");
+        leveldb.delete_record("a").await?;
+        assert_eq!(leveldb.get_record("a").await?, None);
+
+        // Missing keys are forgotten after the TTL
+        let leveldb = leveldb.with_negative_cache(Some(NegativeCacheConfig {
+            capacity: 10,
+            ttl: Duration::ZERO,
+        }));
+        assert_eq!(leveldb.get_record("b").await?, None);
+        leveldb.store().put("b", &record("behind").to_bytes()?)?;
+        assert_eq!(leveldb.get_record("b").await?.unwrap().hash(), "behind");
+
+        Ok(())
+    }
+
     #[test]
     fn test_record_default() -> anyhow::Result<()> {
         let record = Record::default();
@@ -188,6 +1693,24 @@ mod tests {
             deleted: Deleted::Init,
             hash: String::new(),
             read_volumes: Vec::new(),
+            placement: None,
+            key: String::new(),
+            size: 0,
+            expires_at: None,
+            deleted_at: None,
+            checksums: Vec::new(),
+            content_disposition: None,
+            content_type: None,
+            parts: Vec::new(),
+            created_at: None,
+            updated_at: None,
+            replicas: None,
+            erasure: None,
+            refcount: None,
+            blob: None,
+            hash_algorithm: None,
+            encoding: None,
+            encryption: None,
         };
         assert_eq!(record, expected_record);
 
@@ -200,6 +1723,24 @@ mod tests {
             deleted: Deleted::Hard,
             hash: "1234567890".to_string(),
             read_volumes: Vec::new(),
+            placement: None,
+            key: String::new(),
+            size: 0,
+            expires_at: None,
+            deleted_at: None,
+            checksums: Vec::new(),
+            content_disposition: None,
+            content_type: None,
+            parts: Vec::new(),
+            created_at: None,
+            updated_at: None,
+            replicas: None,
+            erasure: None,
+            refcount: None,
+            blob: None,
+            hash_algorithm: None,
+            encoding: None,
+            encryption: None,
         };
         let bytes = record.to_bytes()?;
         let deserialized_record = Record::from_bytes(&bytes)?;
@@ -208,6 +1749,257 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_record_is_expired() {
+        let record = Record::default();
+        assert!(!record.is_expired(u64::MAX));
+
+        let record = Record::default().with_expires_at(Some(100));
+        assert!(!record.is_expired(99));
+        assert!(record.is_expired(100));
+        assert!(record.is_expired(101));
+    }
+
+    #[tokio::test]
+    async fn test_for_each_record() -> anyhow::Result<()> {
+        for backend in <DbBackend as clap::ValueEnum>::value_variants() {
+            let dir = tempfile::tempdir()?;
+            let leveldb = LevelDb::with_backend(dir.path(), *backend)?;
+            for key in ["a", "b", "c"] {
+                let record = Record::new(Deleted::No, String::new(), vec!["vol1".to_string()]);
+                leveldb.put_record(key, record).await?;
+            }
+
+            let mut keys = Vec::new();
+            leveldb.for_each_record(|record| {
+                keys.push(record.key().to_string());
+                Ok(())
+            })?;
+            keys.sort();
+            assert_eq!(keys, vec!["a", "b", "c"], "backend: {:?}", backend);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_for_each_record_from() -> anyhow::Result<()> {
+        /// Store seeking with the default `for_each_entry_from`.
+        struct Unseekable(SledStore);
+
+        impl MetadataStore for Unseekable {
+            fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+                self.0.put(key, value)
+            }
+
+            fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+                self.0.get(key)
+            }
+
+            fn delete(&self, key: &str) -> anyhow::Result<()> {
+                self.0.delete(key)
+            }
+
+            fn for_each_entry(
+                &self,
+                prefix: &str,
+                f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<()>,
+            ) -> anyhow::Result<()> {
+                self.0.for_each_entry(prefix, f)
+            }
+        }
+
+        let dirs = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let mut stores = vec![LevelDb::with_store(Box::new(Unseekable(SledStore::open(
+            dirs.0.path(),
+        )?)))];
+        for backend in <DbBackend as clap::ValueEnum>::value_variants() {
+            let dir = dirs.1.path().join(format!("{:?}", backend));
+            stores.push(LevelDb::with_backend(&dir, *backend)?);
+        }
+        for leveldb in stores {
+            for key in ["a", "b1", "b2", "b3", "c"] {
+                let record = Record::new(Deleted::No, String::new(), vec!["vol1".to_string()]);
+                leveldb.put_record(key, record).await?;
+            }
+
+            let keys_from = |prefix: &str, start: &str, limit: usize| {
+                let mut keys = Vec::new();
+                leveldb.for_each_record_from(prefix, start, |record| {
+                    keys.push(record.key().to_string());
+                    Ok(keys.len() < limit)
+                })?;
+                anyhow::Ok(keys)
+            };
+            assert_eq!(keys_from("b", "", 10)?, ["b1", "b2", "b3"]);
+            assert_eq!(keys_from("b", "b2", 10)?, ["b2", "b3"]);
+            assert_eq!(keys_from("b", "a", 2)?, ["b1", "b2"]);
+            assert_eq!(keys_from("", "b3", 10)?, ["b3", "c"]);
+            assert!(keys_from("b", "c", 10)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_get_record() -> anyhow::Result<()> {
+        for backend in <DbBackend as clap::ValueEnum>::value_variants() {
+            let dir = tempfile::tempdir()?;
+            let leveldb = LevelDb::with_backend(dir.path(), *backend)?;
+            assert!(leveldb.get_record("a").await?.is_none());
+
+            let record = Record::new(Deleted::No, "hash".to_string(), vec!["vol1".to_string()]);
+            leveldb.put_record("a", record).await?;
+            let record = leveldb
+                .get_record_or_default("a")
+                .await?
+                .with_deleted(Deleted::Soft);
+            leveldb.put_record("a", record).await?;
+
+            let record = leveldb.get_record("a").await?.unwrap();
+            assert_eq!(record.deleted(), Deleted::Soft, "backend: {:?}", backend);
+            assert_eq!(record.hash(), "hash");
+            assert_eq!(record.key(), "a");
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "leveldb")]
+    #[tokio::test]
+    async fn test_migrate_legacy_keys() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let store = LevelDbStore::open(dir.path())?;
+            // Records written under their hash once they held their key, in the unversioned layout
+            // up to the key, and a marker-less LevelDB
+            for key in ["legacy", "abcd"] {
+                let volumes = vec!["vol1".to_string()];
+                let bytes = bincode::serialize(&(Deleted::No, key, volumes, None::<String>, key))?;
+                store.leveldb.put(
+                    leveldb::options::WriteOptions::new(),
+                    LevelDbKey(legacy_leveldb_key(key).to_vec()),
+                    &bytes,
+                )?;
+            }
+            store.leveldb.delete(
+                leveldb::options::WriteOptions::new(),
+                LevelDbKey(FULL_KEYS_MARKER.to_vec()),
+            )?;
+        }
+
+        let leveldb = LevelDb::with_backend(dir.path(), DbBackend::Leveldb)?;
+        for key in ["legacy", "abcd"] {
+            let record = leveldb.get_record(key).await?.unwrap();
+            assert_eq!(record.hash(), key);
+            assert_eq!(record.key(), key);
+        }
+        let mut records = 0;
+        leveldb.for_each_record(|_| {
+            records += 1;
+            Ok(())
+        })?;
+        assert_eq!(records, 2);
+
+        leveldb.put_record("other", Record::default()).await?;
+        drop(leveldb);
+        let leveldb = LevelDb::with_backend(dir.path(), DbBackend::Leveldb)?;
+        assert!(leveldb.get_record("other").await?.is_some());
+        assert!(leveldb.get_record("legacy").await?.is_some());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "leveldb")]
+    #[test]
+    fn test_migrate_keyless_legacy_records() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let legacy = LevelDbKey(legacy_leveldb_key("legacy").to_vec());
+        {
+            let store = LevelDbStore::open(dir.path())?;
+            // A record of the original layout, `(deleted, hash, read_volumes)` under the hash of its key,
+            // next to one that holds its key
+            let bytes = bincode::serialize(&(Deleted::No, "hash", vec!["vol1".to_string()]))?;
+            store.leveldb.put(
+                leveldb::options::WriteOptions::new(),
+                LevelDbKey(legacy_leveldb_key("original").to_vec()),
+                &bytes,
+            )?;
+            let volumes = vec!["vol1".to_string()];
+            let bytes =
+                bincode::serialize(&(Deleted::No, "hash", volumes, None::<String>, "legacy"))?;
+            store
+                .leveldb
+                .put(leveldb::options::WriteOptions::new(), &legacy, &bytes)?;
+            store.leveldb.delete(
+                leveldb::options::WriteOptions::new(),
+                LevelDbKey(FULL_KEYS_MARKER.to_vec()),
+            )?;
+        }
+
+        // The open fails every time, nothing is migrated and the marker isn't written
+        for _ in 0..2 {
+            let Err(err) = LevelDbStore::open(dir.path()) else {
+                panic!("a LevelDB with keyless records was opened");
+            };
+            assert!(format!("{:#}", err).contains("rebuild"), "{:#}", err);
+        }
+        let leveldb: Database<LevelDbKey> =
+            Database::open(dir.path(), leveldb::options::Options::new())?;
+        for (key, present) in [
+            (legacy, true),
+            (LevelDbKey::from_str("legacy"), false),
+            (LevelDbKey(FULL_KEYS_MARKER.to_vec()), false),
+        ] {
+            let value = leveldb.get(leveldb::options::ReadOptions::new(), &key)?;
+            assert_eq!(value.is_some(), present, "{:?}", key.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_locks() {
+        let locks = Arc::new(IndexLocks::default());
+        let (locked, unlock) = (
+            Arc::new(std::sync::Barrier::new(2)),
+            Arc::new(std::sync::Barrier::new(2)),
+        );
+        let holder = std::thread::spawn({
+            let (locks, locked, unlock) = (locks.clone(), locked.clone(), unlock.clone());
+            move || {
+                locks.with_lock("ns:app/a", || {
+                    locked.wait();
+                    unlock.wait();
+                })
+            }
+        });
+        locked.wait();
+
+        // Other namespaces and plain keys don't wait for the lock of a namespace
+        locks.with_lock("ns:logs/a", || {});
+        locks.with_lock("a", || {});
+        assert_eq!(locks.len(), 1);
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let waiter = std::thread::spawn({
+            let (locks, done) = (locks.clone(), done.clone());
+            move || {
+                locks.with_lock("ns:app/b", || {
+                    done.store(true, std::sync::atomic::Ordering::SeqCst);
+                })
+            }
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(std::sync::atomic::Ordering::SeqCst));
+
+        unlock.wait();
+        holder.join().unwrap();
+        waiter.join().unwrap();
+        assert!(done.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(locks.len(), 0);
+    }
+
     #[test]
     fn test_get_remote_path() {
         let tests = vec![
@@ -220,4 +2012,24 @@ mod tests {
             assert_eq!(path, expected_path);
         }
     }
+
+    #[test]
+    fn test_part_key() {
+        assert_eq!(upload_key(&part_key("videos/a", 2)), Some("videos/a"));
+        assert_eq!(upload_key("videos/a?partNumber=2"), None);
+        assert_eq!(upload_key(&blob_key("ab12")), None);
+    }
+
+    #[test]
+    fn test_volume_url() {
+        let remote_path = get_remote_path("hello");
+        assert_eq!(
+            volume_url("localhost:3001", &remote_path),
+            "http://localhost:3001/5d/41/aGVsbG8="
+        );
+        assert_eq!(
+            volume_url("https://localhost:3001/sv01", &remote_path),
+            "https://localhost:3001/sv01/5d/41/aGVsbG8="
+        );
+    }
 }
@@ -0,0 +1,262 @@
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use log::{error, info, warn};
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+use crate::{hashring, record, server};
+
+/// Function reading the configured volumes again and building the ring they describe.
+pub type ReloadRing = Arc<dyn Fn() -> anyhow::Result<hashring::Ring> + Send + Sync>;
+
+/// Keys listed in the log when a reload unbalances keys, the others are only counted.
+const LOGGED_KEYS: usize = 100;
+
+/// Struct swapping the ring of a running server for the ring of the reloaded configuration,
+/// and reloading the TLS certificate after it is rotated.
+pub(crate) struct Reloader {
+    leveldb: Arc<record::LevelDb>,
+    hashring: Arc<RwLock<hashring::Ring>>,
+    reload_ring: Option<ReloadRing>,
+    tls: Option<(RustlsConfig, server::TlsConfig)>,
+}
+
+impl Reloader {
+    /// Creates a new reloader of the ring shared by the routes and background tasks,
+    /// the ring is kept if it cannot be reloaded.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        hashring: Arc<RwLock<hashring::Ring>>,
+        reload_ring: Option<ReloadRing>,
+    ) -> Self {
+        Self {
+            leveldb,
+            hashring,
+            reload_ring,
+            tls: None,
+        }
+    }
+
+    /// Reloads the certificate served by the TLS config from its files too.
+    pub(crate) fn with_tls(mut self, rustls: RustlsConfig, tls: server::TlsConfig) -> Self {
+        self.tls = Some((rustls, tls));
+        self
+    }
+
+    /// Returns true if there is a ring or a certificate to reload.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.reload_ring.is_some() || self.tls.is_some()
+    }
+
+    /// Reloads the TLS certificate, new connections are served with it.
+    /// The current certificate is kept if the files cannot be loaded, e.g. while they are rewritten.
+    pub(crate) async fn reload_tls(&self) -> anyhow::Result<()> {
+        if let Some((rustls, tls)) = &self.tls {
+            rustls
+                .reload_from_pem_file(&tls.cert, &tls.key)
+                .await
+                .with_context(|| {
+                    format!("failed to load TLS certificate {}", tls.cert.display())
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Builds the ring of the reloaded configuration and swaps it in at once, so every request
+    /// sees either the old or the new ring. Returns the keys the old ring placed on the volumes
+    /// holding them and the new ring places elsewhere, sorted.
+    /// The current ring is kept if the configuration cannot be reloaded.
+    pub(crate) async fn reload(&self) -> anyhow::Result<Vec<String>> {
+        let Some(reload_ring) = &self.reload_ring else {
+            return Ok(Vec::new());
+        };
+        let new_ring = reload_ring()?;
+        let old_ring = std::mem::replace(&mut *self.hashring.write(), new_ring.clone());
+
+        // The rings are compared on copies, so the scan doesn't block writers of the ring
+        let leveldb = self.leveldb.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut unbalanced = Vec::new();
+            leveldb.for_each_record(|record| {
+                // Parts, shards and shared blobs keep their own volumes, multipart, erasure coded
+                // and deduplicated values are never rebalanced
+                if record.deleted() != record::Deleted::No
+                    || !record.parts().is_empty()
+                    || record.erasure().is_some()
+                    || record.blob().is_some()
+                {
+                    return Ok(());
+                }
+                if is_balanced(&old_ring, &record) && !is_balanced(&new_ring, &record) {
+                    unbalanced.push(record.key().to_string());
+                }
+                Ok(())
+            })?;
+            unbalanced.sort_unstable();
+            Ok(unbalanced)
+        })
+        .await?
+    }
+}
+
+/// Returns true if the ring places a record on the volumes holding its value.
+fn is_balanced(hashring: &hashring::Ring, record: &record::Record) -> bool {
+    let placement = record.placement().filter(|group| hashring.has_group(group));
+    let expected = hashring.get_volume_with_replicas(record.key(), placement, record.replicas());
+    expected.len() == record.read_volumes().len()
+        && expected
+            .iter()
+            .all(|volume| record.read_volumes().contains(volume))
+}
+
+/// Reloads the TLS certificate and the ring on every SIGHUP and logs the keys that become unbalanced,
+/// until the server stops.
+/// Does nothing on platforms without SIGHUP.
+pub(crate) async fn reload_on_sighup(reloader: Reloader) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("reload: failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("reload: SIGHUP received, reloading");
+            if reloader.tls.is_some() {
+                match reloader.reload_tls().await {
+                    Ok(()) => info!("reload: TLS certificate reloaded"),
+                    Err(e) => error!("reload: keeping the current TLS certificate: {:#}", e),
+                }
+            }
+            if reloader.reload_ring.is_none() {
+                continue;
+            }
+            match reloader.reload().await {
+                Ok(unbalanced) if unbalanced.is_empty() => {
+                    info!("reload: ring swapped, no keys unbalanced");
+                }
+                Ok(unbalanced) => {
+                    warn!(
+                        "reload: ring swapped, {} keys unbalanced, run rebalance to move them: {:?}{}",
+                        unbalanced.len(),
+                        &unbalanced[..unbalanced.len().min(LOGGED_KEYS)],
+                        if unbalanced.len() > LOGGED_KEYS { " ..." } else { "" }
+                    );
+                }
+                Err(e) => error!(
+                    "reload: failed to reload, keeping the current ring: {:#}",
+                    e
+                ),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = reloader;
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload() -> anyhow::Result<()> {
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let volumes: Vec<String> = (1..=3).map(|i| format!("localhost:300{}", i)).collect();
+        let old_ring = hashring::Ring::new(volumes.clone(), 1, 10);
+
+        let keys: Vec<String> = (0..50).map(|i| format!("key-{}", i)).collect();
+        for key in keys.iter() {
+            let record =
+                record::Record::new(record::Deleted::No, String::new(), old_ring.get_volume(key));
+            leveldb.put_record(key, record).await?;
+        }
+
+        let mut new_volumes = volumes.clone();
+        new_volumes.push("localhost:3004".to_string());
+        let new_ring = hashring::Ring::new(new_volumes.clone(), 1, 10);
+        let sorted_volumes = |ring: &hashring::Ring, key: &str| {
+            let mut volumes = ring.get_volume(key);
+            volumes.sort_unstable();
+            volumes
+        };
+        let mut expected: Vec<String> = keys
+            .iter()
+            .filter(|key| sorted_volumes(&old_ring, key) != sorted_volumes(&new_ring, key))
+            .cloned()
+            .collect();
+        expected.sort_unstable();
+        assert!(!expected.is_empty());
+
+        let hashring = Arc::new(RwLock::new(old_ring));
+        let reloader = Reloader::new(
+            leveldb.clone(),
+            hashring.clone(),
+            Some(Arc::new(move || {
+                Ok(hashring::Ring::new(new_volumes.clone(), 1, 10))
+            })),
+        );
+        assert_eq!(reloader.reload().await?, expected);
+        assert_eq!(hashring.read().volumes().len(), 4);
+
+        // A second reload to the same ring unbalances nothing more
+        assert!(reloader.reload().await?.is_empty());
+
+        let failing = Reloader::new(
+            leveldb,
+            hashring.clone(),
+            Some(Arc::new(|| anyhow::bail!("invalid config"))),
+        );
+        assert!(failing.reload().await.is_err());
+        assert_eq!(hashring.read().volumes().len(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload_tls() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let tls = server::TlsConfig {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        let write_cert = |tls: &server::TlsConfig| -> anyhow::Result<()> {
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+            std::fs::write(&tls.cert, certified.cert.pem())?;
+            std::fs::write(&tls.key, certified.key_pair.serialize_pem())?;
+            Ok(())
+        };
+        write_cert(&tls)?;
+        let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?;
+        let served = rustls.get_inner();
+
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            &dir.path().join("indexdb"),
+            Default::default(),
+        )?);
+        let hashring = Arc::new(RwLock::new(hashring::Ring::new(Vec::new(), 1, 10)));
+        let reloader = Reloader::new(leveldb, hashring, None).with_tls(rustls.clone(), tls.clone());
+        assert!(reloader.is_enabled());
+
+        // A certificate half written during rotation keeps the current one
+        std::fs::write(&tls.cert, "-----BEGIN CERTIFICATE-----")?;
+        assert!(reloader.reload_tls().await.is_err());
+        assert!(Arc::ptr_eq(&served, &rustls.get_inner()));
+
+        write_cert(&tls)?;
+        reloader.reload_tls().await?;
+        assert!(!Arc::ptr_eq(&served, &rustls.get_inner()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio_util::task::TaskTracker;
+
+use crate::{hashring, locks, metrics, rebalance, record, server, tasks};
+
+/// Name of the repair task in the scheduler.
+pub(crate) const TASK_NAME: &str = "repair";
+
+/// Default interval between two repair scans.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Records checked concurrently by a repair scan.
+const CONCURRENCY: usize = 8;
+
+/// Read repairs running at once, GETs finding more unhealthy records leave them to the repair task.
+const MAX_READ_REPAIRS: usize = 64;
+
+/// Struct counting the records handled by a repair scan.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct RepairStats {
+    pub(crate) healthy: u64,
+    pub(crate) repaired: u64,
+    pub(crate) lost: u64,
+    pub(crate) skipped: u64,
+    pub(crate) failed: u64,
+}
+
+/// Outcome of the repair of a single record.
+enum Outcome {
+    Healthy,
+    Repaired,
+    Lost,
+    Skipped,
+}
+
+/// Struct checking that every replica of the live records holds its value,
+/// and copying the value from a healthy replica to the replicas missing it.
+pub(crate) struct Repair {
+    leveldb: Arc<record::LevelDb>,
+    hashring: Arc<RwLock<hashring::Ring>>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+}
+
+impl Repair {
+    /// Creates a new repair of the records of the leveldb against the volumes of the ring.
+    /// Records are locked while repaired, like PUT and DELETE lock them.
+    pub(crate) fn new(
+        leveldb: Arc<record::LevelDb>,
+        hashring: Arc<RwLock<hashring::Ring>>,
+        key_locks: Arc<locks::KeyLocks>,
+    ) -> Self {
+        Self {
+            leveldb,
+            hashring,
+            key_locks,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses the client to check and copy the replicas, e.g. one configured for HTTPS volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Scans every live record, HEADs its replicas and repairs the missing ones.
+    /// Fails only if the leveldb cannot be scanned, records failing to repair are counted and logged.
+    pub(crate) async fn run(&self) -> anyhow::Result<RepairStats> {
+        let (records, mut stats) = self
+            .leveldb
+            .blocking(|leveldb| {
+                let mut stats = RepairStats::default();
+                let mut records = Vec::new();
+                leveldb.for_each_record(|record| {
+                    if record.deleted() != record::Deleted::No {
+                        return Ok(());
+                    }
+                    // The parts of multipart values and the shards of erasure coded values keep their own volumes,
+                    // deduplicated values the volumes of their blob
+                    if !record.parts().is_empty()
+                        || record.erasure().is_some()
+                        || record.blob().is_some()
+                    {
+                        stats.skipped += 1;
+                        return Ok(());
+                    }
+                    records.push(record);
+                    Ok(())
+                })?;
+                Ok((records, stats))
+            })
+            .await?;
+
+        let mut outcomes = futures::stream::iter(records)
+            .map(|record| async move {
+                let key = record.key().to_string();
+                (key, self.repair_record(record).await)
+            })
+            .buffer_unordered(CONCURRENCY);
+        while let Some((key, outcome)) = outcomes.next().await {
+            match outcome {
+                Ok(Outcome::Healthy) => stats.healthy += 1,
+                Ok(Outcome::Repaired) => stats.repaired += 1,
+                Ok(Outcome::Lost) => stats.lost += 1,
+                Ok(Outcome::Skipped) => stats.skipped += 1,
+                Err(e) => {
+                    error!("repair: failed to repair key {}: {:#}", key, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "repair: healthy: {} repaired: {} lost: {} skipped: {} failed: {}",
+            stats.healthy, stats.repaired, stats.lost, stats.skipped, stats.failed
+        );
+        Ok(stats)
+    }
+
+    /// Returns the volumes the ring places a record on, in its group if the group still exists.
+    fn expected_volumes(&self, record: &record::Record) -> Vec<String> {
+        let hashring = self.hashring.read();
+        let placement = record.placement().filter(|group| hashring.has_group(group));
+        hashring.get_volume_with_replicas(record.key(), placement, record.replicas())
+    }
+
+    /// Checks the replicas of a record and repairs it if a replica misses its value
+    /// or the record lists a volume that lost it.
+    async fn repair_record(&self, record: record::Record) -> anyhow::Result<Outcome> {
+        let key = record.key().to_string();
+        let remote_path = record::get_remote_path(&key);
+        let expected = self.expected_volumes(&record);
+        let mut candidates = expected.clone();
+        for volume in record.read_volumes() {
+            if !candidates.contains(volume) {
+                candidates.push(volume.clone());
+            }
+        }
+
+        let heads = candidates
+            .iter()
+            .map(|volume| self.head(volume, &remote_path));
+        let healthy: Vec<String> = candidates
+            .iter()
+            .zip(futures::future::join_all(heads).await)
+            .filter(|(_, found)| *found)
+            .map(|(volume, _)| volume.clone())
+            .collect();
+
+        let missing: Vec<String> = expected
+            .iter()
+            .filter(|volume| !healthy.contains(volume))
+            .cloned()
+            .collect();
+        let unreadable = record
+            .read_volumes()
+            .iter()
+            .any(|volume| !healthy.contains(volume));
+        if missing.is_empty() && !unreadable {
+            return Ok(Outcome::Healthy);
+        }
+        if healthy.is_empty() {
+            error!("repair: key: {} is not stored in any volume", key);
+            return Ok(Outcome::Lost);
+        }
+
+        let Some(_guard) = self.key_locks.try_lock(&key) else {
+            debug!("repair: key: {} locked, skipping", key);
+            return Ok(Outcome::Skipped);
+        };
+        self.repair_locked(&key, &record, &remote_path, healthy, missing)
+            .await
+    }
+
+    /// Copies the value of a locked record to its missing replicas and writes the volumes
+    /// holding it, unless the record changed since it was scanned.
+    async fn repair_locked(
+        &self,
+        key: &str,
+        record: &record::Record,
+        remote_path: &str,
+        healthy: Vec<String>,
+        missing: Vec<String>,
+    ) -> anyhow::Result<Outcome> {
+        let current = match self.leveldb.get_record(key).await? {
+            Some(current) if server::same_write(&current, record) => current,
+            _ => {
+                debug!("repair: key: {} changed since the scan, skipping", key);
+                return Ok(Outcome::Skipped);
+            }
+        };
+
+        let mut read_volumes = healthy.clone();
+        let mut failed = Vec::new();
+        for volume in missing {
+            match rebalance::copy_value(&self.client, &healthy, &volume, remote_path).await {
+                Ok(_) => {
+                    debug!("repair: key: {} copied to {}", key, volume);
+                    read_volumes.push(volume);
+                }
+                Err(e) => {
+                    warn!("repair: failed to copy key {} to {}: {:#}", key, volume, e);
+                    failed.push(volume);
+                }
+            }
+        }
+
+        // Replicas in their ring order first, so reads and rebalance see the usual order
+        let expected = self.expected_volumes(&current);
+        read_volumes.sort_by_key(|volume| {
+            expected
+                .iter()
+                .position(|expected| expected == volume)
+                .unwrap_or(expected.len())
+        });
+        self.leveldb
+            .put_record(key, current.with_read_volumes(read_volumes))
+            .await?;
+
+        if !failed.is_empty() {
+            anyhow::bail!("replicas {:?} still miss the value", failed);
+        }
+        Ok(Outcome::Repaired)
+    }
+
+    /// Returns true if the volume holds the value at the remote path.
+    async fn head(&self, volume: &str, remote_path: &str) -> bool {
+        let remote_url = record::volume_url(volume, remote_path);
+        match server::remote_head(&self.client, &remote_url).await {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("repair: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Struct repairing in the background the records a GET found unhealthy, so hot keys heal
+/// before the next repair scan. A key is repaired by at most one read repair at a time.
+pub(crate) struct ReadRepair {
+    repair: Arc<Repair>,
+    pending: Mutex<HashSet<String>>,
+    tasks: TaskTracker,
+}
+
+impl ReadRepair {
+    /// Creates a read repair running the repairs on the tracker, e.g. the one shutdown waits for.
+    pub(crate) fn new(repair: Arc<Repair>, tasks: TaskTracker) -> Arc<Self> {
+        Arc::new(Self {
+            repair,
+            pending: Mutex::new(HashSet::new()),
+            tasks,
+        })
+    }
+
+    /// Spawns the repair of a key, unless it is already being repaired or too many repairs are running.
+    pub(crate) fn schedule(self: &Arc<Self>, key: &str) {
+        {
+            let mut pending = self.pending.lock();
+            if pending.len() >= MAX_READ_REPAIRS || !pending.insert(key.to_string()) {
+                return;
+            }
+        }
+        metrics::METRICS.read_repairs.inc();
+
+        let read_repair = self.clone();
+        let key = key.to_string();
+        self.tasks.spawn(async move {
+            match read_repair.repair_key(&key).await {
+                Ok(Outcome::Repaired) => info!("read_repair: key: {} repaired", key),
+                Ok(Outcome::Lost) => {
+                    error!("read_repair: key: {} is not stored in any volume", key)
+                }
+                Ok(_) => debug!("read_repair: key: {} left as is", key),
+                Err(e) => error!("read_repair: failed to repair key {}: {:#}", key, e),
+            }
+            read_repair.pending.lock().remove(&key);
+        });
+    }
+
+    /// Repairs the live record of a key, multipart, erasure coded and deduplicated values keep their own volumes
+    /// and are skipped.
+    async fn repair_key(&self, key: &str) -> anyhow::Result<Outcome> {
+        match self.repair.leveldb.get_record(key).await? {
+            Some(record)
+                if record.is_live(record::unix_now())
+                    && record.parts().is_empty()
+                    && record.erasure().is_none()
+                    && record.blob().is_none() =>
+            {
+                self.repair.repair_record(record).await
+            }
+            _ => Ok(Outcome::Skipped),
+        }
+    }
+}
+
+/// Returns the scheduler task running a repair scan, failing if records were lost or not repaired.
+pub(crate) fn task(repair: Arc<Repair>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let repair = repair.clone();
+        Box::pin(async move {
+            let stats = repair.run().await?;
+            if stats.lost > 0 || stats.failed > 0 {
+                anyhow::bail!(
+                    "{} records lost, {} records failed to repair",
+                    stats.lost,
+                    stats.failed
+                );
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_repair() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let hashring = hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+
+        let keys: Vec<String> = (0..10).map(|i| format!("key-{}", i)).collect();
+        for key in keys.iter() {
+            let volumes = hashring.get_volume(key);
+            for volume in volumes.iter() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                client.put(remote_url).body(key.clone()).send().await?;
+            }
+            let record = record::Record::new(record::Deleted::No, String::new(), volumes)
+                .with_size(key.len() as u64);
+            leveldb.put_record(key, record).await?;
+        }
+        leveldb
+            .put_record(
+                "lost",
+                record::Record::new(
+                    record::Deleted::No,
+                    String::new(),
+                    hashring.get_volume("lost"),
+                ),
+            )
+            .await?;
+
+        let before = cluster.volume(0).len();
+        cluster.volume(0).clear();
+        let repair = Repair::new(
+            leveldb.clone(),
+            Arc::new(RwLock::new(hashring)),
+            locks::KeyLocks::new(Duration::from_secs(1)),
+        );
+        let stats = repair.run().await?;
+        assert_eq!(stats.repaired, before as u64);
+        assert_eq!(stats.healthy, keys.len() as u64 - before as u64);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(cluster.volume(0).len(), before);
+
+        for key in keys.iter() {
+            let record = leveldb.get_record(key).await?.unwrap();
+            for volume in record.read_volumes() {
+                let remote_url = format!("http://{}{}", volume, record::get_remote_path(key));
+                assert_eq!(client.get(remote_url).send().await?.text().await?, *key);
+            }
+        }
+
+        let stats = repair.run().await?;
+        assert_eq!(stats.healthy, keys.len() as u64);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+use crate::{locks, rebalance, record, tasks};
+
+/// Name of the replication task in the scheduler.
+pub(crate) const TASK_NAME: &str = "replication";
+
+/// Default interval between two runs of the replication queue.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(30);
+
+/// Prefix of the metadata store keys of the replication queue, under the reserved prefix.
+const QUEUE_PREFIX: &str = "\0replication/";
+
+/// Struct representing a replication intent, the replicas a write missed.
+/// It is persisted before the record, so the replicas are retried after a restart of the index.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Intent {
+    key: String,
+    deleted: record::Deleted,
+    hash: String,
+    size: u64,
+    volumes: Vec<String>,
+    attempts: u32,
+}
+
+impl Intent {
+    /// Returns true if the record is still the write the intent was queued for.
+    /// The volumes of the record are not compared, they grow as the intent is replicated.
+    fn matches(&self, record: &record::Record) -> bool {
+        record.deleted() == self.deleted && record.hash() == self.hash && record.size() == self.size
+    }
+}
+
+/// Returns the metadata store key of the intent of a record key.
+fn queue_key(key: &str) -> String {
+    format!("{}{}", QUEUE_PREFIX, key)
+}
+
+/// Queues the replication of a record to the volumes its write missed, replacing any intent of the key.
+pub(crate) async fn enqueue(
+    leveldb: &record::LevelDb,
+    key: &str,
+    record: &record::Record,
+    volumes: &[String],
+) -> anyhow::Result<()> {
+    let intent = Intent {
+        key: key.to_string(),
+        deleted: record.deleted(),
+        hash: record.hash().to_string(),
+        size: record.size(),
+        volumes: volumes.to_vec(),
+        attempts: 0,
+    };
+    leveldb
+        .blocking(move |leveldb| {
+            leveldb
+                .store()
+                .put(&queue_key(&intent.key), &bincode::serialize(&intent)?)
+        })
+        .await
+}
+
+/// Removes the intent of a record once every replica of its write holds the value.
+/// An intent queued by another write of the key is kept.
+pub(crate) async fn complete(
+    leveldb: &record::LevelDb,
+    key: &str,
+    record: &record::Record,
+) -> anyhow::Result<()> {
+    let queue_key = queue_key(key);
+    let record = record.clone();
+    leveldb
+        .blocking(move |leveldb| {
+            let Some(value) = leveldb.store().get(&queue_key)? else {
+                return Ok(());
+            };
+            let intent: Intent = bincode::deserialize(&value)?;
+            if intent.matches(&record) {
+                leveldb.store().delete(&queue_key)?;
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Struct counting the intents handled by a run of the replication queue.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct ReplicationStats {
+    pub(crate) replicated: u64,
+    pub(crate) pending: u64,
+    pub(crate) dropped: u64,
+    pub(crate) skipped: u64,
+}
+
+/// Outcome of the replication of a single intent.
+enum Outcome {
+    Replicated,
+    Pending,
+    Dropped,
+    Skipped,
+}
+
+/// Struct retrying the queued replication intents, copying the value from the volumes of the
+/// record to the replicas its write missed until every replica holds it.
+pub(crate) struct Replicator {
+    leveldb: Arc<record::LevelDb>,
+    key_locks: Arc<locks::KeyLocks>,
+    client: reqwest::Client,
+}
+
+impl Replicator {
+    /// Creates a new replicator of the queue of the leveldb.
+    /// Records are locked while replicated, like PUT and DELETE lock them.
+    pub(crate) fn new(leveldb: Arc<record::LevelDb>, key_locks: Arc<locks::KeyLocks>) -> Self {
+        Self {
+            leveldb,
+            key_locks,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses the client to copy the values, e.g. one configured for HTTPS volumes.
+    pub(crate) fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Retries every queued intent once. Intents of records that changed since their write are dropped.
+    /// Fails only if the queue cannot be read, intents failing to replicate are kept for the next run.
+    pub(crate) async fn run(&self) -> anyhow::Result<ReplicationStats> {
+        let (intents, mut stats) = self
+            .leveldb
+            .blocking(|leveldb| {
+                let mut stats = ReplicationStats::default();
+                let mut intents = Vec::new();
+                leveldb
+                    .store()
+                    .for_each_entry(QUEUE_PREFIX, &mut |queue_key, value| {
+                        match bincode::deserialize::<Intent>(value) {
+                            Ok(intent) => intents.push(intent),
+                            Err(e) => {
+                                warn!("replication: undecodable intent at {:?}: {}", queue_key, e);
+                                stats.skipped += 1;
+                            }
+                        }
+                        Ok(())
+                    })?;
+                Ok((intents, stats))
+            })
+            .await?;
+
+        for intent in intents {
+            let key = intent.key.clone();
+            match self.replicate(intent).await {
+                Ok(Outcome::Replicated) => stats.replicated += 1,
+                Ok(Outcome::Pending) => stats.pending += 1,
+                Ok(Outcome::Dropped) => stats.dropped += 1,
+                Ok(Outcome::Skipped) => stats.skipped += 1,
+                Err(e) => {
+                    error!("replication: failed to replicate key {}: {:#}", key, e);
+                    stats.pending += 1;
+                }
+            }
+        }
+
+        info!(
+            "replication: replicated: {} pending: {} dropped: {} skipped: {}",
+            stats.replicated, stats.pending, stats.dropped, stats.skipped
+        );
+        Ok(stats)
+    }
+
+    /// Copies the value of a locked record to the replicas of the intent it misses,
+    /// adds them to the record and removes the intent once none is missing.
+    async fn replicate(&self, mut intent: Intent) -> anyhow::Result<Outcome> {
+        let key = intent.key.clone();
+        let Some(_guard) = self.key_locks.try_lock(&key) else {
+            debug!("replication: key: {} locked, retrying later", key);
+            return Ok(Outcome::Skipped);
+        };
+        let queue_key = queue_key(&key);
+        let current = match self.leveldb.get_record(&key).await? {
+            Some(current) if intent.matches(&current) => current,
+            _ => {
+                debug!("replication: key: {} changed, dropping intent", key);
+                self.leveldb
+                    .blocking(move |leveldb| leveldb.store().delete(&queue_key))
+                    .await?;
+                return Ok(Outcome::Dropped);
+            }
+        };
+
+        let remote_path = record::get_remote_path(&key);
+        let mut read_volumes = current.read_volumes().to_vec();
+        let mut failed = Vec::new();
+        for volume in intent.volumes.iter() {
+            if read_volumes.contains(volume) {
+                continue;
+            }
+            match rebalance::copy_value(&self.client, current.read_volumes(), volume, &remote_path)
+                .await
+            {
+                Ok(_) => {
+                    debug!("replication: key: {} copied to {}", key, volume);
+                    read_volumes.push(volume.clone());
+                }
+                Err(e) => {
+                    warn!(
+                        "replication: failed to copy key {} to {}: {:#}",
+                        key, volume, e
+                    );
+                    failed.push(volume.clone());
+                }
+            }
+        }
+        if read_volumes.len() > current.read_volumes().len() {
+            self.leveldb
+                .put_record(&key, current.with_read_volumes(read_volumes))
+                .await?;
+        }
+
+        if failed.is_empty() {
+            self.leveldb
+                .blocking(move |leveldb| leveldb.store().delete(&queue_key))
+                .await?;
+            return Ok(Outcome::Replicated);
+        }
+        intent.volumes = failed;
+        intent.attempts += 1;
+        let intent = bincode::serialize(&intent)?;
+        self.leveldb
+            .blocking(move |leveldb| leveldb.store().put(&queue_key, &intent))
+            .await?;
+        Ok(Outcome::Pending)
+    }
+}
+
+/// Returns the scheduler task running the replication queue, failing while intents stay pending.
+pub(crate) fn task(replicator: Arc<Replicator>) -> tasks::TaskFn {
+    Arc::new(move || {
+        let replicator = replicator.clone();
+        Box::pin(async move {
+            let stats = replicator.run().await?;
+            if stats.pending > 0 {
+                anyhow::bail!("{} replication intents pending", stats.pending);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_replication() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 1).await?;
+        let volumes = cluster.volume_addrs().to_vec();
+        let client = reqwest::Client::new();
+        let leveldb_dir = tempfile::tempdir()?;
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+
+        // Stored on the first volume only, the write missed the second one
+        for key in ["kept", "changed"] {
+            let remote_url = format!("http://{}{}", volumes[0], record::get_remote_path(key));
+            client.put(remote_url).body(key).send().await?;
+            let record = record::Record::new(
+                record::Deleted::No,
+                key.to_string(),
+                vec![volumes[0].clone()],
+            )
+            .with_size(key.len() as u64);
+            enqueue(&leveldb, key, &record, &volumes[1..]).await?;
+            leveldb.put_record(key, record).await?;
+        }
+        leveldb
+            .put_record(
+                "changed",
+                record::Record::new(record::Deleted::Soft, String::new(), Vec::new()),
+            )
+            .await?;
+        // Queued intents are not records
+        let mut records = 0;
+        leveldb.for_each_record(|_| {
+            records += 1;
+            Ok(())
+        })?;
+        assert_eq!(records, 2);
+
+        // Reopened like after a restart of the index
+        drop(leveldb);
+        let leveldb = Arc::new(record::LevelDb::with_backend(
+            leveldb_dir.path(),
+            Default::default(),
+        )?);
+        let replicator = Replicator::new(
+            leveldb.clone(),
+            locks::KeyLocks::new(Duration::from_secs(1)),
+        );
+        let stats = replicator.run().await?;
+        assert_eq!(stats.replicated, 1);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(cluster.volume(1).len(), 1);
+        let record = leveldb.get_record("kept").await?.unwrap();
+        assert_eq!(record.read_volumes(), volumes.as_slice());
+
+        let stats = replicator.run().await?;
+        assert_eq!(stats, ReplicationStats::default());
+
+        Ok(())
+    }
+}
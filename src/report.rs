@@ -0,0 +1,301 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::record;
+
+/// Struct representing the number of objects and logical bytes stored in a volume or subvolume.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct Usage {
+    objects: u64,
+    bytes: u64,
+}
+
+impl Usage {
+    /// Adds an object of the given size.
+    fn add(&mut self, size: u64) {
+        self.objects += 1;
+        self.bytes += size;
+    }
+}
+
+/// Struct representing the usage of a volume and each of its subvolumes.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct VolumeUsage {
+    #[serde(flatten)]
+    usage: Usage,
+    subvolumes: BTreeMap<String, Usage>,
+}
+
+/// Struct representing the distribution of the live records across volumes and subvolumes.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct DistributionReport {
+    total: Usage,
+    volumes: BTreeMap<String, VolumeUsage>,
+}
+
+impl DistributionReport {
+    /// Adds a record to the report. Only live records count towards the distribution,
+    /// the volumes with the size stored on them, smaller for compressed values.
+    pub(crate) fn add(&mut self, record: &record::Record) {
+        if record.deleted() != record::Deleted::No {
+            return;
+        }
+
+        self.total.add(record.size());
+        for read_volume in record.read_volumes() {
+            let (volume, subvolume) = match read_volume.split_once('/') {
+                Some((volume, subvolume)) => (volume, Some(subvolume)),
+                None => (read_volume.as_str(), None),
+            };
+
+            let volume_usage = self.volumes.entry(volume.to_string()).or_default();
+            volume_usage.usage.add(record.stored_size());
+            if let Some(subvolume) = subvolume {
+                volume_usage
+                    .subvolumes
+                    .entry(subvolume.to_string())
+                    .or_default()
+                    .add(record.stored_size());
+            }
+        }
+    }
+
+    /// Scans every record in the database and builds the report.
+    pub(crate) fn scan(leveldb: &record::LevelDb) -> anyhow::Result<Self> {
+        let mut report = Self::default();
+        leveldb.for_each_record(|record| {
+            report.add(&record);
+            Ok(())
+        })?;
+        Ok(report)
+    }
+}
+
+/// Formats the report as a table with one line per volume followed by its subvolumes.
+impl std::fmt::Display for DistributionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<32} {:>12} {:>16}", "VOLUME", "OBJECTS", "BYTES")?;
+        for (volume, volume_usage) in self.volumes.iter() {
+            writeln!(
+                f,
+                "{:<32} {:>12} {:>16}",
+                volume, volume_usage.usage.objects, volume_usage.usage.bytes
+            )?;
+            for (subvolume, usage) in volume_usage.subvolumes.iter() {
+                writeln!(
+                    f,
+                    "  {:<30} {:>12} {:>16}",
+                    subvolume, usage.objects, usage.bytes
+                )?;
+            }
+        }
+        write!(
+            f,
+            "{:<32} {:>12} {:>16}",
+            "TOTAL", self.total.objects, self.total.bytes
+        )
+    }
+}
+
+/// Struct representing a key with an expiry.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct ExpiringKey {
+    key: String,
+    expires_at: u64,
+    size: u64,
+}
+
+/// Struct representing a set of keys with an expiry.
+/// Count and bytes cover every key, the key list is truncated to the report limit.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct ExpiringKeys {
+    count: u64,
+    bytes: u64,
+    keys: Vec<ExpiringKey>,
+}
+
+impl ExpiringKeys {
+    /// Adds a record to the set, listing its key if the limit is not reached.
+    fn add(&mut self, record: &record::Record, expires_at: u64, limit: usize) {
+        self.count += 1;
+        self.bytes += record.size();
+        if self.keys.len() < limit {
+            self.keys.push(ExpiringKey {
+                key: record.key().to_string(),
+                expires_at,
+                size: record.size(),
+            });
+        }
+    }
+
+    /// Sorts the listed keys by expiry, soonest first.
+    fn sort(&mut self) {
+        self.keys
+            .sort_unstable_by(|a, b| a.expires_at.cmp(&b.expires_at).then(a.key.cmp(&b.key)));
+    }
+}
+
+/// Struct representing the live records that are past their expiry or expire within a window.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct ExpiryReport {
+    now: u64,
+    within: u64,
+    expired: ExpiringKeys,
+    expiring: ExpiringKeys,
+}
+
+impl ExpiryReport {
+    /// Creates an empty report for records expiring within the given seconds from now.
+    pub(crate) fn new(now: u64, within: u64) -> Self {
+        Self {
+            now,
+            within,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a record to the report if it is live and expired or expiring within the window.
+    pub(crate) fn add(&mut self, record: &record::Record, limit: usize) {
+        if record.deleted() != record::Deleted::No {
+            return;
+        }
+
+        match record.expires_at() {
+            Some(expires_at) if record.is_expired(self.now) => {
+                self.expired.add(record, expires_at, limit)
+            }
+            Some(expires_at) if expires_at <= self.now.saturating_add(self.within) => {
+                self.expiring.add(record, expires_at, limit)
+            }
+            _ => (),
+        }
+    }
+
+    /// Scans every record in the database and builds the report, listing at most limit keys per set.
+    pub(crate) fn scan(
+        leveldb: &record::LevelDb,
+        now: u64,
+        within: u64,
+        limit: usize,
+    ) -> anyhow::Result<Self> {
+        let mut report = Self::new(now, within);
+        leveldb.for_each_record(|record| {
+            report.add(&record, limit);
+            Ok(())
+        })?;
+        report.expired.sort();
+        report.expiring.sort();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution_report() {
+        let mut report = DistributionReport::default();
+        report.add(
+            &record::Record::new(
+                record::Deleted::No,
+                String::new(),
+                vec![
+                    "localhost:3001/sv01".to_string(),
+                    "localhost:3002/sv02".to_string(),
+                ],
+            )
+            .with_size(10),
+        );
+        report.add(
+            &record::Record::new(
+                record::Deleted::No,
+                String::new(),
+                vec!["localhost:3001/sv03".to_string()],
+            )
+            .with_size(5),
+        );
+        report.add(
+            &record::Record::new(
+                record::Deleted::Soft,
+                String::new(),
+                vec!["localhost:3001/sv01".to_string()],
+            )
+            .with_size(100),
+        );
+
+        assert_eq!(
+            report.total,
+            Usage {
+                objects: 2,
+                bytes: 15
+            }
+        );
+
+        let volume = &report.volumes["localhost:3001"];
+        assert_eq!(
+            volume.usage,
+            Usage {
+                objects: 2,
+                bytes: 15
+            }
+        );
+        assert_eq!(
+            volume.subvolumes["sv01"],
+            Usage {
+                objects: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(
+            volume.subvolumes["sv03"],
+            Usage {
+                objects: 1,
+                bytes: 5
+            }
+        );
+
+        let volume = &report.volumes["localhost:3002"];
+        assert_eq!(
+            volume.usage,
+            Usage {
+                objects: 1,
+                bytes: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_expiry_report() {
+        let record = |size, expires_at| {
+            record::Record::new(record::Deleted::No, String::new(), Vec::new())
+                .with_size(size)
+                .with_expires_at(expires_at)
+        };
+
+        let mut report = ExpiryReport::new(1000, 60);
+        report.add(&record(1, Some(900)), 10);
+        report.add(&record(2, Some(1000)), 10);
+        report.add(&record(4, Some(1030)), 10);
+        report.add(&record(8, Some(1061)), 10);
+        report.add(&record(16, None), 10);
+        report.add(
+            &record(32, Some(900)).with_deleted(record::Deleted::Soft),
+            10,
+        );
+
+        assert_eq!(report.expired.count, 2);
+        assert_eq!(report.expired.bytes, 3);
+        assert_eq!(report.expired.keys.len(), 2);
+        assert_eq!(report.expiring.count, 1);
+        assert_eq!(report.expiring.bytes, 4);
+        assert_eq!(report.expiring.keys[0].expires_at, 1030);
+
+        let mut report = ExpiryReport::new(1000, 60);
+        report.add(&record(1, Some(900)), 1);
+        report.add(&record(2, Some(950)), 1);
+        assert_eq!(report.expired.count, 2);
+        assert_eq!(report.expired.bytes, 3);
+        assert_eq!(report.expired.keys.len(), 1);
+    }
+}
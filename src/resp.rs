@@ -0,0 +1,841 @@
+use anyhow::Context;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use axum_server::tls_rustls::RustlsConfig;
+use bytes::Bytes;
+use futures::{FutureExt, StreamExt};
+use log::{debug, error};
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use tokio_util::task::TaskTracker;
+
+use crate::{admin, auth, record, server};
+
+/// Longest line of the protocol, an inline command or the header of an argument.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// Most arguments of a command.
+const MAX_ARGS: usize = 64 * 1024;
+
+/// Longest argument of a command, like the default `proto-max-bulk-len` of Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Attempts of a SET racing other writes of its key before giving up.
+const SET_ATTEMPTS: usize = 3;
+
+/// Axum state for the RESP listener, the states of the key routes its commands map to.
+pub(crate) struct AppRespState {
+    pub(crate) leveldb: Arc<record::LevelDb>,
+    pub(crate) put: Arc<server::AppPutState>,
+    pub(crate) get: Arc<server::AppGetState>,
+    pub(crate) delete: Arc<server::AppDeleteState>,
+    /// Tokens accepted by AUTH, None if connections don't need to authenticate.
+    pub(crate) auth: Option<Arc<auth::Tokens>>,
+    /// Set once shutdown started, SET and DEL are rejected from then on.
+    pub(crate) draining: Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// Reply to a command, written in RESP2.
+#[derive(Debug)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Reply>),
+    /// A value streamed from a volume with its length, written as a bulk string.
+    Value(axum::body::Body, u64),
+}
+
+impl Reply {
+    fn error(message: impl Into<String>) -> Self {
+        Self::Error(message.into())
+    }
+
+    fn wrong_arity(command: &str) -> Self {
+        Self::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command
+        ))
+    }
+}
+
+/// Condition of a SET on the existence of its key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    /// NX, the key is only created.
+    Absent,
+    /// XX, the key is only replaced.
+    Present,
+}
+
+/// Serves the Redis protocol on the listener until the shutdown future completes, over TLS if configured.
+/// On shutdown new connections are refused and open connections are closed after their current command.
+pub(crate) async fn serve(
+    listener: tokio::net::TcpListener,
+    state: Arc<AppRespState>,
+    rustls: Option<RustlsConfig>,
+    shutdown: impl std::future::Future<Output = ()> + Clone + Send + 'static,
+) -> std::io::Result<()> {
+    let connections = TaskTracker::new();
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Running out of file descriptors shouldn't stop the listener
+                    error!("resp: failed to accept connection: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.clone() => break,
+        };
+        debug!("resp: connection from {}", addr);
+        let state = state.clone();
+        let rustls = rustls.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let result = match rustls {
+                Some(rustls) => {
+                    let acceptor = tokio_rustls::TlsAcceptor::from(rustls.get_inner());
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => handle_connection(stream, state, shutdown).await,
+                        Err(e) => Err(e.into()),
+                    }
+                }
+                None => handle_connection(stream, state, shutdown).await,
+            };
+            if let Err(e) = result {
+                debug!("resp: connection from {} closed: {:#}", addr, e);
+            }
+        });
+    }
+
+    connections.close();
+    connections.wait().await;
+    Ok(())
+}
+
+/// Reads the commands of a connection and writes their replies, until the client quits
+/// or the shutdown future completes between two commands.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    state: Arc<AppRespState>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let mut stream = tokio::io::BufStream::new(stream);
+    let mut shutdown = std::pin::pin!(shutdown.fuse());
+    let mut scope = None;
+    loop {
+        let command = tokio::select! {
+            command = read_command(&mut stream) => command,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let args = match command {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // The rest of the stream can't be parsed, the connection is closed after the error
+                let reply = Reply::error(format!("ERR Protocol error: {:#}", e));
+                write_reply(&mut stream, reply).await?;
+                stream.flush().await?;
+                return Err(e);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        if name == "quit" {
+            write_reply(&mut stream, Reply::Status("OK")).await?;
+            stream.flush().await?;
+            return Ok(());
+        }
+        let reply = execute(&state, &mut scope, &name, &args[1..]).await;
+        write_reply(&mut stream, reply).await?;
+        stream.flush().await?;
+    }
+}
+
+/// Reads a command, an array of bulk strings or an inline command, e.g. `GET key` typed in telnet.
+/// Returns None if the connection is closed before the command starts.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> anyhow::Result<Option<Vec<Bytes>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(Bytes::copy_from_slice)
+            .collect();
+        return Ok(Some(args));
+    };
+
+    let count = parse_len(count, MAX_ARGS).context("invalid multibulk length")?;
+    let mut args = Vec::with_capacity(count.min(16));
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .context("connection closed in a command")?;
+        let len = line
+            .strip_prefix(b"$")
+            .with_context(|| format!("expected '$', got '{}'", String::from_utf8_lossy(&line)))?;
+        let len = parse_len(len, MAX_BULK_LEN).context("invalid bulk length")?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            anyhow::bail!("bulk string not terminated by CRLF");
+        }
+        arg.truncate(len);
+        args.push(Bytes::from(arg));
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line terminated by `\n`, without its `\r\n` or `\n`.
+/// Returns None if the connection is closed before the line starts.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if (&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        anyhow::bail!("line too long or truncated");
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parses a length of the protocol, up to the max.
+fn parse_len(digits: &[u8], max: usize) -> anyhow::Result<usize> {
+    let len: usize = std::str::from_utf8(digits)?.parse()?;
+    if len > max {
+        anyhow::bail!("{} is over the limit of {}", len, max);
+    }
+    Ok(len)
+}
+
+/// Writes a reply. A streamed value failing midway fails the connection,
+/// its announced length can't be honored anymore.
+async fn write_reply<W: AsyncWrite + Unpin>(writer: &mut W, reply: Reply) -> anyhow::Result<()> {
+    let Reply::Value(body, len) = reply else {
+        let mut buf = Vec::new();
+        encode(&reply, &mut buf);
+        writer.write_all(&buf).await?;
+        return Ok(());
+    };
+
+    writer.write_all(format!("${}\r\n", len).as_bytes()).await?;
+    let mut written = 0;
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        written += chunk.len() as u64;
+        if written > len {
+            anyhow::bail!("value longer than its length of {}", len);
+        }
+        writer.write_all(&chunk).await?;
+    }
+    if written != len {
+        anyhow::bail!(
+            "value of {} bytes shorter than its length of {}",
+            written,
+            len
+        );
+    }
+    writer.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// Encodes a reply that isn't streamed.
+fn encode(reply: &Reply, buf: &mut Vec<u8>) {
+    match reply {
+        Reply::Status(status) => buf.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+        Reply::Error(message) => {
+            // A line break would end the error early and desync the client
+            let message = message.replace(['\r', '\n'], " ");
+            buf.extend_from_slice(format!("-{}\r\n", message).as_bytes());
+        }
+        Reply::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+        Reply::Bulk(value) => {
+            buf.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+            buf.extend_from_slice(value);
+            buf.extend_from_slice(b"\r\n");
+        }
+        Reply::Null => buf.extend_from_slice(b"$-1\r\n"),
+        Reply::Array(replies) => {
+            buf.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+            for reply in replies {
+                encode(reply, buf);
+            }
+        }
+        Reply::Value(_, len) => unreachable!("value of {} bytes is streamed", len),
+    }
+}
+
+/// Executes a command of a connection whose token has the scope, None if it didn't authenticate.
+async fn execute(
+    state: &AppRespState,
+    scope: &mut Option<auth::Scope>,
+    name: &str,
+    args: &[Bytes],
+) -> Reply {
+    let required = match name {
+        "get" | "exists" | "ttl" => Some(auth::Scope::Read),
+        "set" | "del" => Some(auth::Scope::Write),
+        _ => None,
+    };
+    if let (Some(_), Some(required)) = (state.auth.as_ref(), required) {
+        match *scope {
+            None => return Reply::error("NOAUTH Authentication required."),
+            Some(scope) if scope < required => {
+                return Reply::error(format!(
+                    "NOPERM this token has no permissions to run the '{}' command",
+                    name
+                ))
+            }
+            Some(_) => (),
+        }
+    }
+    if required == Some(auth::Scope::Write)
+        && state.draining.load(std::sync::atomic::Ordering::SeqCst)
+    {
+        debug!("resp: {} rejected while draining", name);
+        return Reply::error("ERR server shutting down, try again");
+    }
+
+    match name {
+        "ping" => match args {
+            [] => Reply::Status("PONG"),
+            [message] => Reply::Bulk(message.clone()),
+            _ => Reply::wrong_arity(name),
+        },
+        "auth" => match args {
+            // The username of `AUTH username password` is ignored, tokens have no user
+            [.., token] if args.len() <= 2 => authenticate(state, scope, token),
+            _ => Reply::wrong_arity(name),
+        },
+        // Clients select the database 0 when connecting, the only one
+        "select" => match args {
+            [db] if db.as_ref() == b"0" => Reply::Status("OK"),
+            [_] => Reply::error("ERR DB index is out of range"),
+            _ => Reply::wrong_arity(name),
+        },
+        // redis-cli asks for the command docs when connecting
+        "command" => Reply::Array(Vec::new()),
+        "get" => match args {
+            [key] => match parse_key(key) {
+                Ok(key) => get(state, key).await,
+                Err(reply) => reply,
+            },
+            _ => Reply::wrong_arity(name),
+        },
+        "set" => match args {
+            [key, value, options @ ..] => match (parse_key(key), parse_set_options(options)) {
+                (Ok(key), Ok((ttl, condition))) => {
+                    set(state, key, value.clone(), ttl, condition).await
+                }
+                (Err(reply), _) | (_, Err(reply)) => reply,
+            },
+            _ => Reply::wrong_arity(name),
+        },
+        "del" if !args.is_empty() => del(state, args).await,
+        "exists" if !args.is_empty() => exists(state, args).await,
+        "ttl" => match args {
+            [key] => match parse_key(key) {
+                Ok(key) => ttl(state, &key).await,
+                Err(reply) => reply,
+            },
+            _ => Reply::wrong_arity(name),
+        },
+        "del" | "exists" => Reply::wrong_arity(name),
+        _ => Reply::error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+/// Handles AUTH, the connection gets the scope of the token.
+fn authenticate(state: &AppRespState, scope: &mut Option<auth::Scope>, token: &Bytes) -> Reply {
+    let Some(tokens) = state.auth.as_ref() else {
+        return Reply::error("ERR AUTH called without any token configured");
+    };
+    match std::str::from_utf8(token)
+        .ok()
+        .and_then(|token| tokens.scope(token))
+    {
+        Some(token_scope) => {
+            *scope = Some(token_scope);
+            Reply::Status("OK")
+        }
+        None => Reply::error("WRONGPASS invalid token"),
+    }
+}
+
+/// Returns the key of an argument, keys of the index are UTF-8 and not empty.
+fn parse_key(key: &Bytes) -> Result<String, Reply> {
+    match std::str::from_utf8(key) {
+        Ok(key) if !key.is_empty() => Ok(key.to_string()),
+        _ => Err(Reply::error("ERR keys must be non empty UTF-8")),
+    }
+}
+
+/// Parses the options of SET: `EX seconds` or `PX milliseconds`, and `NX` or `XX`.
+/// Returns the TTL in seconds, milliseconds are rounded up as the index expires keys by the second.
+fn parse_set_options(options: &[Bytes]) -> Result<(Option<u64>, Option<Condition>), Reply> {
+    let syntax_error = || Reply::error("ERR syntax error");
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let value = options.next().ok_or_else(syntax_error)?;
+                let value: u64 = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .filter(|value| *value > 0)
+                    .ok_or_else(|| Reply::error("ERR invalid expire time in 'set' command"))?;
+                ttl = Some(if unit == b"EX" {
+                    value
+                } else {
+                    value.div_ceil(1000)
+                });
+            }
+            b"NX" if condition.is_none() => condition = Some(Condition::Absent),
+            b"XX" if condition.is_none() => condition = Some(Condition::Present),
+            _ => return Err(syntax_error()),
+        }
+    }
+    Ok((ttl, condition))
+}
+
+/// Returns the reply of a status of a key route that isn't the outcome of the command.
+fn status_error(status: StatusCode) -> Reply {
+    match status {
+        StatusCode::CONFLICT => Reply::error("ERR key locked by another write, try again"),
+        status => Reply::error(format!("ERR {}", status)),
+    }
+}
+
+/// Handles GET by streaming the value from a volume, like a proxied GET of the key.
+async fn get(state: &AppRespState, key: String) -> Reply {
+    let response = server::handle_get_record(
+        server::RecordKey(key),
+        axum::extract::State(state.get.clone()),
+        axum::extract::Query(server::GetParams::proxied()),
+        axum::extract::Query(admin::PageParams::default()),
+        HeaderMap::new(),
+    )
+    .await;
+    match response.status() {
+        StatusCode::OK => (),
+        StatusCode::NOT_FOUND => return Reply::Null,
+        status => return status_error(status),
+    }
+
+    let len = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    match len {
+        Some(len) => Reply::Value(response.into_body(), len),
+        // Without a length the value is read whole, the bulk string needs it first
+        None => match axum::body::to_bytes(response.into_body(), MAX_BULK_LEN).await {
+            Ok(value) => Reply::Bulk(value),
+            Err(e) => Reply::error(format!("ERR failed to read the value: {}", e)),
+        },
+    }
+}
+
+/// Handles SET by storing the value like a PUT of the key, replacing the current value.
+/// NX and XX map to the `If-None-Match: *` and `If-Match: *` preconditions of the PUT.
+async fn set(
+    state: &AppRespState,
+    key: String,
+    value: Bytes,
+    ttl: Option<u64>,
+    condition: Option<Condition>,
+) -> Reply {
+    if value.is_empty() {
+        return Reply::error("ERR empty values are not supported");
+    }
+
+    // A PUT doesn't replace a value unconditionally, so SET guesses whether the key
+    // exists and retries with the other precondition if a write raced it
+    let mut expected = match condition {
+        Some(condition) => condition,
+        None => match state.leveldb.get_record(&key).await {
+            Ok(Some(record)) if record.is_live(record::unix_now()) => Condition::Present,
+            Ok(_) => Condition::Absent,
+            Err(e) => {
+                error!("resp: failed to get record {} from leveldb: {}", key, e);
+                return status_error(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        },
+    };
+    for _ in 0..SET_ATTEMPTS {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(value.len()));
+        let precondition = match expected {
+            Condition::Absent => header::IF_NONE_MATCH,
+            Condition::Present => header::IF_MATCH,
+        };
+        headers.insert(precondition, HeaderValue::from_static("*"));
+        if let Some(ttl) = ttl {
+            headers.insert("X-Ttl", HeaderValue::from(ttl));
+        }
+
+        let status = server::handle_put_record(
+            server::RecordKey(key.clone()),
+            axum::extract::State(state.put.clone()),
+            axum::extract::Query(server::PutParams::default()),
+            headers,
+            axum::body::Body::from(value.clone()),
+        )
+        .await
+        .into_response()
+        .status();
+        match status {
+            StatusCode::CREATED => return Reply::Status("OK"),
+            StatusCode::PRECONDITION_FAILED if condition.is_some() => return Reply::Null,
+            StatusCode::PRECONDITION_FAILED => {
+                expected = match expected {
+                    Condition::Absent => Condition::Present,
+                    Condition::Present => Condition::Absent,
+                };
+            }
+            status => return status_error(status),
+        }
+    }
+    status_error(StatusCode::CONFLICT)
+}
+
+/// Handles DEL by deleting the live keys like DELETE requests.
+/// Returns the number of keys deleted, or the first error.
+async fn del(state: &AppRespState, keys: &[Bytes]) -> Reply {
+    let mut deleted = 0;
+    for key in keys {
+        let key = match parse_key(key) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        // A DELETE of a missing key succeeds, it must not count
+        match state.leveldb.get_record(&key).await {
+            Ok(Some(record)) if record.is_live(record::unix_now()) => (),
+            Ok(_) => continue,
+            Err(e) => {
+                error!("resp: failed to get record {} from leveldb: {}", key, e);
+                return status_error(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        let response = server::handle_delete_record(
+            server::RecordKey(key),
+            axum::extract::State(state.delete.clone()),
+        )
+        .await;
+        match response.status() {
+            StatusCode::NO_CONTENT => deleted += 1,
+            StatusCode::NOT_FOUND => (),
+            status => return status_error(status),
+        }
+    }
+    Reply::Integer(deleted)
+}
+
+/// Handles EXISTS from the index, a key named twice counts twice like in Redis.
+async fn exists(state: &AppRespState, keys: &[Bytes]) -> Reply {
+    let now = record::unix_now();
+    let mut count = 0;
+    for key in keys {
+        let key = match parse_key(key) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        match state.leveldb.get_record(&key).await {
+            Ok(Some(record)) if record.is_live(now) => count += 1,
+            Ok(_) => (),
+            Err(e) => {
+                error!("resp: failed to get record {} from leveldb: {}", key, e);
+                return status_error(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    Reply::Integer(count)
+}
+
+/// Handles TTL from the index.
+/// Returns the seconds left before the key expires, -1 if it doesn't expire and -2 if it doesn't exist.
+async fn ttl(state: &AppRespState, key: &str) -> Reply {
+    let now = record::unix_now();
+    match state.leveldb.get_record(key).await {
+        Ok(Some(record)) if record.is_live(now) => match record.expires_at() {
+            Some(expires_at) => Reply::Integer(expires_at.saturating_sub(now) as i64),
+            None => Reply::Integer(-1),
+        },
+        Ok(_) => Reply::Integer(-2),
+        Err(e) => {
+            error!("resp: failed to get record {} from leveldb: {}", key, e);
+            status_error(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    async fn parse(input: &[u8]) -> anyhow::Result<Option<Vec<Bytes>>> {
+        read_command(&mut tokio::io::BufReader::new(input)).await
+    }
+
+    #[tokio::test]
+    async fn test_read_command() -> anyhow::Result<()> {
+        assert_eq!(
+            parse(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$7\r\nva\r\nlue\r\n").await?,
+            Some(vec![
+                Bytes::from("SET"),
+                Bytes::from("key"),
+                Bytes::from("va\r\nlue")
+            ])
+        );
+        assert_eq!(
+            parse(b"GET  key\r\n").await?,
+            Some(vec![Bytes::from("GET"), Bytes::from("key")])
+        );
+        assert_eq!(parse(b"\r\n").await?, Some(Vec::new()));
+        assert_eq!(parse(b"").await?, None);
+
+        assert!(parse(b"*1\r\n$3\r\nGET").await.is_err());
+        assert!(parse(b"*1\r\n$3\r\nGETX\r\n").await.is_err());
+        assert!(parse(b"*1\r\n:3\r\n").await.is_err());
+        assert!(parse(b"*-1\r\n").await.is_err());
+        assert!(parse(b"*1\r\n$536870913\r\n").await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_set_options() {
+        let options = |options: &[&'static str]| {
+            let options: Vec<Bytes> = options.iter().map(|o| Bytes::from(*o)).collect();
+            parse_set_options(&options)
+        };
+        assert!(matches!(options(&[]), Ok((None, None))));
+        assert!(matches!(
+            options(&["ex", "10", "NX"]),
+            Ok((Some(10), Some(Condition::Absent)))
+        ));
+        assert!(matches!(
+            options(&["XX", "PX", "1500"]),
+            Ok((Some(2), Some(Condition::Present)))
+        ));
+        assert!(options(&["EX"]).is_err());
+        assert!(options(&["EX", "0"]).is_err());
+        assert!(options(&["EX", "1", "PX", "1"]).is_err());
+        assert!(options(&["NX", "XX"]).is_err());
+        assert!(options(&["KEEPTTL"]).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        encode(
+            &Reply::Array(vec![
+                Reply::Status("OK"),
+                Reply::error("ERR bad\r\nline"),
+                Reply::Integer(-2),
+                Reply::Bulk(Bytes::from("abc")),
+                Reply::Null,
+            ]),
+            &mut buf,
+        );
+        assert_eq!(
+            buf,
+            b"*5\r\n+OK\r\n-ERR bad  line\r\n:-2\r\n$3\r\nabc\r\n$-1\r\n"
+        );
+    }
+
+    /// Connection of a test client, sending commands and reading their raw replies.
+    struct Client(tokio::io::BufStream<tokio::net::TcpStream>);
+
+    impl Client {
+        async fn connect(addr: std::net::SocketAddr) -> anyhow::Result<Self> {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+            Ok(Self(tokio::io::BufStream::new(stream)))
+        }
+
+        async fn call(&mut self, args: &[&str]) -> anyhow::Result<String> {
+            let mut command = format!("*{}\r\n", args.len());
+            for arg in args {
+                command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+            }
+            self.0.write_all(command.as_bytes()).await?;
+            self.0.flush().await?;
+
+            let mut reply = String::new();
+            self.0.read_line(&mut reply).await?;
+            if let Some(len) = reply.strip_prefix('$') {
+                if let Ok(len) = len.trim_end().parse::<usize>() {
+                    let mut value = vec![0; len + 2];
+                    self.0.read_exact(&mut value).await?;
+                    reply.push_str(&String::from_utf8(value)?);
+                }
+            }
+            Ok(reply)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resp() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let mut client = Client::connect(cluster.resp_addr()).await?;
+
+        assert_eq!(client.call(&["PING"]).await?, "+PONG\r\n");
+        assert_eq!(client.call(&["SELECT", "0"]).await?, "+OK\r\n");
+        assert_eq!(client.call(&["GET", "greeting"]).await?, "$-1\r\n");
+        assert_eq!(client.call(&["SET", "greeting", "hello"]).await?, "+OK\r\n");
+        assert_eq!(client.call(&["GET", "greeting"]).await?, "$5\r\nhello\r\n");
+        assert_eq!(
+            client.call(&["SET", "greeting", "hello world"]).await?,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            client.call(&["GET", "greeting"]).await?,
+            "$11\r\nhello world\r\n"
+        );
+
+        // The values are shared with the HTTP API
+        let res = reqwest::Client::new()
+            .get(cluster.key_url("greeting"))
+            .query(&[("proxy", "1")])
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "hello world");
+
+        assert_eq!(
+            client.call(&["SET", "greeting", "hi", "NX"]).await?,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            client.call(&["SET", "missing", "hi", "XX"]).await?,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            client
+                .call(&["SET", "session", "abc", "EX", "60", "NX"])
+                .await?,
+            "+OK\r\n"
+        );
+        let ttl = client.call(&["TTL", "session"]).await?;
+        assert!(ttl == ":60\r\n" || ttl == ":59\r\n", "{}", ttl);
+        assert_eq!(client.call(&["TTL", "greeting"]).await?, ":-1\r\n");
+        assert_eq!(client.call(&["TTL", "missing"]).await?, ":-2\r\n");
+
+        assert_eq!(
+            client
+                .call(&["EXISTS", "greeting", "session", "missing", "greeting"])
+                .await?,
+            ":3\r\n"
+        );
+        assert_eq!(
+            client.call(&["DEL", "greeting", "missing"]).await?,
+            ":1\r\n"
+        );
+        assert_eq!(client.call(&["GET", "greeting"]).await?, "$-1\r\n");
+        assert_eq!(client.call(&["EXISTS", "greeting"]).await?, ":0\r\n");
+
+        assert_eq!(
+            client.call(&["GET"]).await?,
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            client.call(&["SET", "key", "value", "KEEPTTL"]).await?,
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            client.call(&["FLUSHALL"]).await?,
+            "-ERR unknown command 'flushall'\r\n"
+        );
+        assert_eq!(client.call(&["QUIT"]).await?, "+OK\r\n");
+
+        // Inline commands, as typed in telnet
+        let mut stream = tokio::net::TcpStream::connect(cluster.resp_addr()).await?;
+        stream.write_all(b"EXISTS session\r\nQUIT\r\n").await?;
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).await?;
+        assert_eq!(replies, ":1\r\n+OK\r\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resp_auth() -> anyhow::Result<()> {
+        let mut tokens = auth::Tokens::default();
+        tokens.add("reader".to_string(), auth::Scope::Read);
+        tokens.add("writer".to_string(), auth::Scope::Write);
+        let cluster = TestCluster::start_with_auth(2, 1, tokens).await?;
+        let mut client = Client::connect(cluster.resp_addr()).await?;
+
+        assert_eq!(
+            client.call(&["GET", "key"]).await?,
+            "-NOAUTH Authentication required.\r\n"
+        );
+        assert_eq!(
+            client.call(&["AUTH", "wrong"]).await?,
+            "-WRONGPASS invalid token\r\n"
+        );
+        assert_eq!(client.call(&["AUTH", "reader"]).await?, "+OK\r\n");
+        assert_eq!(client.call(&["GET", "key"]).await?, "$-1\r\n");
+        assert_eq!(
+            client.call(&["SET", "key", "value"]).await?,
+            "-NOPERM this token has no permissions to run the 'set' command\r\n"
+        );
+        assert_eq!(
+            client.call(&["AUTH", "default", "writer"]).await?,
+            "+OK\r\n"
+        );
+        assert_eq!(client.call(&["SET", "key", "value"]).await?, "+OK\r\n");
+        assert_eq!(client.call(&["GET", "key"]).await?, "$5\r\nvalue\r\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resp_reject_writes_while_draining() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 1).await?;
+        let mut client = Client::connect(cluster.resp_addr()).await?;
+        assert_eq!(client.call(&["SET", "key", "value"]).await?, "+OK\r\n");
+
+        cluster.set_draining(true);
+        assert_eq!(
+            client.call(&["SET", "key", "other"]).await?,
+            "-ERR server shutting down, try again\r\n"
+        );
+        assert_eq!(
+            client.call(&["DEL", "key"]).await?,
+            "-ERR server shutting down, try again\r\n"
+        );
+        assert_eq!(client.call(&["GET", "key"]).await?, "$5\r\nvalue\r\n");
+
+        cluster.set_draining(false);
+        assert_eq!(client.call(&["DEL", "key"]).await?, ":1\r\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,830 @@
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use base64::Engine;
+use bytes::{Buf, BytesMut};
+use futures::StreamExt;
+use log::{debug, error};
+use serde::Deserialize;
+use std::{fmt::Write, sync::Arc};
+
+use crate::{admin, record, server};
+
+/// Namespace of the S3 XML documents.
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Default and highest number of keys in a ListObjectsV2 page, like S3.
+const MAX_KEYS: usize = 1000;
+
+/// Longest chunk header of an `aws-chunked` body, a hex size and a chunk signature.
+const MAX_CHUNK_HEADER: usize = 4096;
+
+/// Axum state for the S3 gateway, the states of the key routes its operations map to.
+pub(crate) struct AppS3State {
+    pub(crate) leveldb: Arc<record::LevelDb>,
+    pub(crate) put: Arc<server::AppPutState>,
+    pub(crate) get: Arc<server::AppGetState>,
+    pub(crate) delete: Arc<server::AppDeleteState>,
+}
+
+/// Query parameters of ListObjectsV2.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ListParams {
+    list_type: Option<String>,
+    #[serde(default)]
+    prefix: String,
+    delimiter: Option<String>,
+    continuation_token: Option<String>,
+    start_after: Option<String>,
+    max_keys: Option<usize>,
+    encoding_type: Option<String>,
+}
+
+/// Struct representing an object listed by ListObjectsV2.
+#[derive(Debug)]
+struct Object {
+    key: String,
+    etag: String,
+    size: u64,
+    modified_at: u64,
+}
+
+/// Creates the router of the S3 gateway, path-style only: `/bucket` and `/bucket/key`.
+/// The object `key` of `bucket` is the minikeyvalue key `bucket/key`, buckets exist as long as keys do.
+/// Signatures aren't checked.
+pub(crate) fn router(state: Arc<AppS3State>) -> axum::Router {
+    axum::Router::new()
+        .route(
+            "/:bucket",
+            axum::routing::get(handle_list_objects)
+                .head(handle_bucket)
+                .put(handle_bucket),
+        )
+        .route("/:bucket/", axum::routing::get(handle_list_objects))
+        .route(
+            "/:bucket/*key",
+            axum::routing::get(handle_get_object)
+                .head(handle_head_object)
+                .put(handle_put_object)
+                .delete(handle_delete_object),
+        )
+        .with_state(state)
+}
+
+/// Returns the minikeyvalue key of an object.
+fn object_key(bucket: &str, key: &str) -> String {
+    format!("{}/{}", bucket, key)
+}
+
+/// Handles HeadBucket and CreateBucket, buckets are implicit so every bucket exists.
+/// Returns 200
+async fn handle_bucket() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Handles PutObject by storing the object like a PUT of its key.
+/// A streaming SigV4 body, `aws-chunked`, is decoded first. CopyObject isn't supported.
+/// Returns 200 with the ETag of the object
+/// Returns an S3 error document otherwise, e.g. BadDigest if the Content-MD5 doesn't match
+async fn handle_put_object(
+    axum::extract::Path((bucket, key)): axum::extract::Path<(String, String)>,
+    axum::extract::State(state): axum::extract::State<Arc<AppS3State>>,
+    mut headers: HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    let resource = format!("/{}/{}", bucket, key);
+    let key = object_key(&bucket, &key);
+    debug!("s3: put object: {}", key);
+
+    if headers.contains_key("x-amz-copy-source") {
+        return s3_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "NotImplemented",
+            "CopyObject is not supported",
+            &resource,
+        );
+    }
+    let body = if is_aws_chunked(&headers) {
+        let Some(length) = headers.remove("x-amz-decoded-content-length") else {
+            return s3_error(
+                StatusCode::LENGTH_REQUIRED,
+                "MissingContentLength",
+                "x-amz-decoded-content-length is required with aws-chunked",
+                &resource,
+            );
+        };
+        headers.insert(header::CONTENT_LENGTH, length);
+        headers.remove(header::CONTENT_ENCODING);
+        decode_aws_chunked(body)
+    } else {
+        body
+    };
+
+    let status = server::handle_put_record(
+        server::RecordKey(key.clone()),
+        axum::extract::State(state.put.clone()),
+        axum::extract::Query(server::PutParams::default()),
+        headers,
+        body,
+    )
+    .await
+    .into_response()
+    .status();
+    if status != StatusCode::CREATED {
+        return status_error(status, &resource);
+    }
+
+    // The ETag is read back, the value may have been stored without an MD5
+    let etag = match state.leveldb.get_record(&key).await {
+        Ok(record) => record.and_then(|record| record.etag()),
+        Err(e) => {
+            error!("s3: failed to get record {} from leveldb: {}", key, e);
+            None
+        }
+    };
+    let mut response = axum::http::Response::builder().status(StatusCode::OK);
+    if let Some(etag) = etag {
+        response = response.header(header::ETAG, etag);
+    }
+    response.body(axum::body::Body::empty()).unwrap()
+}
+
+/// Handles GetObject by streaming the value through the index, a `Range` header included.
+/// Returns the response of a proxied GET of the key
+/// Returns NoSuchKey if the key is missing, deleted or expired
+async fn handle_get_object(
+    axum::extract::Path((bucket, key)): axum::extract::Path<(String, String)>,
+    axum::extract::State(state): axum::extract::State<Arc<AppS3State>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let resource = format!("/{}/{}", bucket, key);
+    let key = object_key(&bucket, &key);
+    debug!("s3: get object: {}", key);
+
+    let response = server::handle_get_record(
+        server::RecordKey(key),
+        axum::extract::State(state.get.clone()),
+        axum::extract::Query(server::GetParams::proxied()),
+        axum::extract::Query(admin::PageParams::default()),
+        headers,
+    )
+    .await;
+    match response.status() {
+        status if status.is_success() || status == StatusCode::NOT_MODIFIED => response,
+        status => status_error(status, &resource),
+    }
+}
+
+/// Handles HeadObject, answered from the index like a HEAD of the key.
+/// Returns the response of the HEAD of the key, without a body
+async fn handle_head_object(
+    axum::extract::Path((bucket, key)): axum::extract::Path<(String, String)>,
+    axum::extract::State(state): axum::extract::State<Arc<AppS3State>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let key = object_key(&bucket, &key);
+    debug!("s3: head object: {}", key);
+
+    server::handle_head_record(
+        server::RecordKey(key),
+        axum::extract::State(state.get.clone()),
+        headers,
+    )
+    .await
+}
+
+/// Handles DeleteObject like a DELETE of the key, the value can be undeleted until collected.
+/// Returns 204 whether the key existed or not, like S3
+/// Returns an S3 error document if the key cannot be deleted
+async fn handle_delete_object(
+    axum::extract::Path((bucket, key)): axum::extract::Path<(String, String)>,
+    axum::extract::State(state): axum::extract::State<Arc<AppS3State>>,
+) -> axum::response::Response {
+    let resource = format!("/{}/{}", bucket, key);
+    let key = object_key(&bucket, &key);
+    debug!("s3: delete object: {}", key);
+
+    let response = server::handle_delete_record(
+        server::RecordKey(key),
+        axum::extract::State(state.delete.clone()),
+    )
+    .await;
+    match response.status() {
+        StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => StatusCode::NO_CONTENT.into_response(),
+        status => status_error(status, &resource),
+    }
+}
+
+/// Handles ListObjectsV2 of the live objects of a bucket, in key order.
+/// A delimiter rolls the keys sharing the part of their name up to it into common prefixes,
+/// and `encoding-type=url` percent-encodes the keys like S3.
+/// Returns 200 with the ListBucketResult document
+/// Returns NotImplemented for ListObjects v1
+/// Returns InternalError if the leveldb fails
+async fn handle_list_objects(
+    axum::extract::Path(bucket): axum::extract::Path<String>,
+    axum::extract::State(state): axum::extract::State<Arc<AppS3State>>,
+    axum::extract::Query(params): axum::extract::Query<ListParams>,
+) -> axum::response::Response {
+    let resource = format!("/{}", bucket);
+    debug!("s3: list objects: {} params: {:?}", bucket, params);
+
+    if params.list_type.as_deref() != Some("2") {
+        return s3_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "NotImplemented",
+            "Only ListObjectsV2 is supported, use list-type=2",
+            &resource,
+        );
+    }
+    let continuation_token = match params.continuation_token.as_deref().map(decode_token) {
+        Some(Some(token)) => Some(token),
+        Some(None) => {
+            return s3_error(
+                StatusCode::BAD_REQUEST,
+                "InvalidArgument",
+                "The continuation token provided is incorrect",
+                &resource,
+            );
+        }
+        None => None,
+    };
+
+    let leveldb = state.leveldb.clone();
+    let prefix = object_key(&bucket, &params.prefix);
+    let bucket_len = bucket.len() + 1;
+    let scan = tokio::task::spawn_blocking(move || {
+        let now = record::unix_now();
+        let mut objects = Vec::new();
+        leveldb.for_each_record(|record| {
+            if record.is_live(now) && record.key().starts_with(&prefix) {
+                objects.push(Object {
+                    key: record.key()[bucket_len..].to_string(),
+                    etag: record.etag().unwrap_or_default(),
+                    size: record.size(),
+                    modified_at: record.updated_at().unwrap_or_default(),
+                });
+            }
+            Ok(())
+        })?;
+        objects.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok::<_, anyhow::Error>(objects)
+    });
+    let objects = match scan.await {
+        Ok(Ok(objects)) => objects,
+        Ok(Err(e)) => {
+            error!("s3: failed to scan leveldb: {}", e);
+            return s3_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalError",
+                "Failed to list the objects",
+                &resource,
+            );
+        }
+        Err(e) => {
+            error!("s3: scan task failed: {}", e);
+            return s3_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalError",
+                "Failed to list the objects",
+                &resource,
+            );
+        }
+    };
+
+    let after = continuation_token.or_else(|| params.start_after.clone());
+    let document = list_bucket_result(&bucket, &params, after.as_deref(), objects);
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        document,
+    )
+        .into_response()
+}
+
+/// Returns the ListBucketResult document of a page of the objects, sorted by key, listed after a key.
+fn list_bucket_result(
+    bucket: &str,
+    params: &ListParams,
+    after: Option<&str>,
+    objects: Vec<Object>,
+) -> String {
+    let max_keys = params.max_keys.unwrap_or(MAX_KEYS).min(MAX_KEYS);
+    let delimiter = params.delimiter.as_deref().filter(|d| !d.is_empty());
+    let url_encoded = params.encoding_type.as_deref() == Some("url");
+    let encode = |value: &str| {
+        if url_encoded {
+            url_encode(value)
+        } else {
+            xml_escape(value)
+        }
+    };
+
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut last = None;
+    let mut truncated = false;
+    for object in objects {
+        if let Some(after) = after {
+            // A common prefix token skips every key rolled up into it
+            let rolled_up =
+                delimiter.is_some_and(|d| after.ends_with(d)) && object.key.starts_with(after);
+            if object.key.as_str() <= after || rolled_up {
+                continue;
+            }
+        }
+        let common_prefix = delimiter.and_then(|d| {
+            object.key[params.prefix.len()..]
+                .find(d)
+                .map(|i| object.key[..params.prefix.len() + i + d.len()].to_string())
+        });
+        if common_prefix.is_some() && common_prefixes.last() == common_prefix.as_ref() {
+            continue;
+        }
+        if contents.len() + common_prefixes.len() == max_keys {
+            truncated = true;
+            break;
+        }
+        match common_prefix {
+            Some(common_prefix) => {
+                last = Some(common_prefix.clone());
+                common_prefixes.push(common_prefix);
+            }
+            None => {
+                last = Some(object.key.clone());
+                contents.push(object);
+            }
+        }
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    let _ = write!(xml, "<ListBucketResult xmlns=\"{}\">", XMLNS);
+    let _ = write!(xml, "<Name>{}</Name>", xml_escape(bucket));
+    let _ = write!(xml, "<Prefix>{}</Prefix>", encode(&params.prefix));
+    if let Some(delimiter) = delimiter {
+        let _ = write!(xml, "<Delimiter>{}</Delimiter>", encode(delimiter));
+    }
+    if url_encoded {
+        xml.push_str("<EncodingType>url</EncodingType>");
+    }
+    let _ = write!(xml, "<MaxKeys>{}</MaxKeys>", max_keys);
+    let _ = write!(
+        xml,
+        "<KeyCount>{}</KeyCount>",
+        contents.len() + common_prefixes.len()
+    );
+    let _ = write!(xml, "<IsTruncated>{}</IsTruncated>", truncated);
+    if let Some(token) = params.continuation_token.as_deref() {
+        let _ = write!(
+            xml,
+            "<ContinuationToken>{}</ContinuationToken>",
+            xml_escape(token)
+        );
+    }
+    if let Some(start_after) = params.start_after.as_deref() {
+        let _ = write!(xml, "<StartAfter>{}</StartAfter>", encode(start_after));
+    }
+    if let (true, Some(last)) = (truncated, last) {
+        let _ = write!(
+            xml,
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            encode_token(&last)
+        );
+    }
+    for object in contents {
+        let _ = write!(
+            xml,
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+            encode(&object.key),
+            iso8601(object.modified_at),
+            xml_escape(&object.etag),
+            object.size
+        );
+    }
+    for common_prefix in common_prefixes {
+        let _ = write!(
+            xml,
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            encode(&common_prefix)
+        );
+    }
+    xml.push_str("</ListBucketResult>");
+    xml
+}
+
+/// Returns the opaque continuation token resuming a listing after a key or common prefix.
+fn encode_token(after: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(after)
+}
+
+/// Returns the key or common prefix a continuation token resumes after, None if it is malformed.
+fn decode_token(token: &str) -> Option<String> {
+    let after = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(token)
+        .ok()?;
+    String::from_utf8(after).ok()
+}
+
+/// Returns the S3 error document of a status of the key routes.
+fn status_error(status: StatusCode, resource: &str) -> axum::response::Response {
+    let (status, code, message) = match status {
+        StatusCode::NOT_FOUND => (status, "NoSuchKey", "The specified key does not exist."),
+        StatusCode::BAD_REQUEST => (status, "InvalidRequest", "The request is invalid."),
+        StatusCode::CONFLICT => (
+            status,
+            "OperationAborted",
+            "A conflicting operation is in progress on the key.",
+        ),
+        StatusCode::LENGTH_REQUIRED => (
+            status,
+            "MissingContentLength",
+            "You must provide the Content-Length HTTP header.",
+        ),
+        StatusCode::PAYLOAD_TOO_LARGE => (
+            StatusCode::BAD_REQUEST,
+            "EntityTooLarge",
+            "Your proposed upload exceeds the maximum allowed object size.",
+        ),
+        StatusCode::PRECONDITION_FAILED => (
+            status,
+            "PreconditionFailed",
+            "At least one of the preconditions you specified did not hold.",
+        ),
+        StatusCode::UNPROCESSABLE_ENTITY => (
+            StatusCode::BAD_REQUEST,
+            "BadDigest",
+            "The Content-MD5 or checksum you specified did not match what was received.",
+        ),
+        StatusCode::SERVICE_UNAVAILABLE => (
+            status,
+            "ServiceUnavailable",
+            "Not enough volumes are available, retry later.",
+        ),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+            "We encountered an internal error. Please try again.",
+        ),
+    };
+    s3_error(status, code, message, resource)
+}
+
+/// Returns an S3 error document, e.g. `<Error><Code>NoSuchKey</Code>...</Error>`.
+fn s3_error(
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    resource: &str,
+) -> axum::response::Response {
+    let document = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource></Error>",
+        code,
+        xml_escape(message),
+        xml_escape(resource)
+    );
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml")],
+        document,
+    )
+        .into_response()
+}
+
+/// Returns the value escaped for XML text.
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the value percent-encoded like the `encoding-type=url` listings of S3, keeping `/`.
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+/// Returns the ISO 8601 UTC time of seconds since the unix epoch, e.g. `2023-11-14T22:13:20.000Z`.
+fn iso8601(secs: u64) -> String {
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Returns true if the body is framed in `aws-chunked`, the streaming uploads of the AWS SDKs.
+fn is_aws_chunked(headers: &HeaderMap) -> bool {
+    let has_value = |name: &str, pattern: &str| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .is_some_and(|value| value.contains(pattern))
+    };
+    has_value("content-encoding", "aws-chunked") || has_value("x-amz-content-sha256", "STREAMING-")
+}
+
+/// Phase of the decoding of an `aws-chunked` body.
+enum Chunked {
+    /// Reading the `<hex size>[;chunk-signature=...]` line of a chunk.
+    Header,
+    /// Reading the remaining bytes of the data of a chunk.
+    Data(usize),
+    /// Reading the CRLF ending the data of a chunk.
+    DataEnd,
+    /// The zero sized chunk was read, trailers are ignored.
+    Done,
+}
+
+/// Decodes an `aws-chunked` body into the value, chunks of `<hex size>[;chunk-signature=...]\r\n<data>\r\n`
+/// ending with a zero sized chunk. Chunk signatures and trailing checksums aren't checked.
+fn decode_aws_chunked(body: axum::body::Body) -> axum::body::Body {
+    let chunks = futures::stream::unfold(
+        (body.into_data_stream(), BytesMut::new(), Chunked::Header),
+        |(mut stream, mut buffer, mut phase)| async move {
+            loop {
+                match phase {
+                    Chunked::Header => {
+                        if let Some(end) = buffer.windows(2).position(|w| w == b"\r\n") {
+                            let line = buffer.split_to(end + 2);
+                            let size = std::str::from_utf8(&line[..end])
+                                .ok()
+                                .and_then(|line| line.split(';').next())
+                                .and_then(|size| usize::from_str_radix(size.trim(), 16).ok());
+                            phase = match size {
+                                Some(0) => Chunked::Done,
+                                Some(size) => Chunked::Data(size),
+                                None => {
+                                    return Some((
+                                        Err(invalid("invalid chunk size")),
+                                        (stream, buffer, Chunked::Done),
+                                    ))
+                                }
+                            };
+                            continue;
+                        }
+                        if buffer.len() > MAX_CHUNK_HEADER {
+                            return Some((
+                                Err(invalid("chunk header too long")),
+                                (stream, buffer, Chunked::Done),
+                            ));
+                        }
+                    }
+                    Chunked::Data(remaining) if !buffer.is_empty() => {
+                        let n = remaining.min(buffer.len());
+                        let data = buffer.split_to(n).freeze();
+                        phase = if n == remaining {
+                            Chunked::DataEnd
+                        } else {
+                            Chunked::Data(remaining - n)
+                        };
+                        return Some((Ok(data), (stream, buffer, phase)));
+                    }
+                    Chunked::Data(_) => (),
+                    Chunked::DataEnd if buffer.len() >= 2 => {
+                        if &buffer[..2] != b"\r\n" {
+                            return Some((
+                                Err(invalid("missing CRLF after chunk")),
+                                (stream, buffer, Chunked::Done),
+                            ));
+                        }
+                        buffer.advance(2);
+                        phase = Chunked::Header;
+                        continue;
+                    }
+                    Chunked::DataEnd => (),
+                    Chunked::Done => return None,
+                }
+                match stream.next().await {
+                    Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                    Some(Err(e)) => {
+                        return Some((
+                            Err(std::io::Error::other(e)),
+                            (stream, buffer, Chunked::Done),
+                        ))
+                    }
+                    None => {
+                        return Some((
+                            Err(invalid("body ended inside a chunk")),
+                            (stream, buffer, Chunked::Done),
+                        ))
+                    }
+                }
+            }
+        },
+    );
+    axum::body::Body::from_stream(chunks)
+}
+
+/// Returns the error of a malformed `aws-chunked` body.
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso8601(1_700_000_000), "2023-11-14T22:13:20.000Z");
+        assert_eq!(iso8601(951_782_400), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[tokio::test]
+    async fn test_decode_aws_chunked() -> anyhow::Result<()> {
+        let framed = "5;chunk-signature=abc\r\nhello\r\n6;chunk-signature=def\r\n world\r\n0;chunk-signature=0\r\nx-amz-checksum-crc32:AAAAAA==\r\n\r\n";
+        // Split inside the chunk headers and data to exercise the buffering
+        let parts: Vec<Result<bytes::Bytes, std::io::Error>> = framed
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+            .collect();
+        let body = axum::body::Body::from_stream(futures::stream::iter(parts));
+        let decoded = axum::body::to_bytes(decode_aws_chunked(body), usize::MAX).await?;
+        assert_eq!(decoded, "hello world");
+
+        let truncated = axum::body::Body::from("5\r\nhel");
+        assert!(
+            axum::body::to_bytes(decode_aws_chunked(truncated), usize::MAX)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_bucket_result() {
+        let objects = || {
+            ["a.txt", "dir/1", "dir/2", "z&z"]
+                .into_iter()
+                .map(|key| Object {
+                    key: key.to_string(),
+                    etag: "\"e\"".to_string(),
+                    size: 1,
+                    modified_at: 0,
+                })
+                .collect::<Vec<_>>()
+        };
+        let params = ListParams {
+            list_type: Some("2".to_string()),
+            delimiter: Some("/".to_string()),
+            max_keys: Some(2),
+            ..Default::default()
+        };
+
+        let page = list_bucket_result("b", &params, None, objects());
+        assert!(page.contains("<Contents><Key>a.txt</Key>"));
+        assert!(page.contains("<CommonPrefixes><Prefix>dir/</Prefix></CommonPrefixes>"));
+        assert!(page.contains("<KeyCount>2</KeyCount><IsTruncated>true</IsTruncated>"));
+        let token = encode_token("dir/");
+        assert!(page.contains(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            token
+        )));
+
+        // The next page skips the keys rolled up into the common prefix
+        let page = list_bucket_result("b", &params, Some("dir/"), objects());
+        assert!(page.contains("<Key>z&amp;z</Key>"));
+        assert!(!page.contains("dir/"));
+        assert!(page.contains("<IsTruncated>false</IsTruncated>"));
+
+        let params = ListParams {
+            encoding_type: Some("url".to_string()),
+            ..params
+        };
+        let page = list_bucket_result("b", &params, Some("dir/"), objects());
+        assert!(page.contains("<Key>z%26z</Key>"));
+    }
+
+    #[tokio::test]
+    async fn test_s3_gateway() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let s3_url = cluster.s3_url().unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("{}/photos/2024/cat.png", s3_url);
+
+        let res = client.put(format!("{}/photos", s3_url)).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .put(&url)
+            .header("Content-Type", "image/png")
+            .body("meow")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = format!("\"{:x}\"", md5::compute("meow"));
+        assert_eq!(res.headers()["etag"], etag.as_str());
+
+        // Objects are keys of the index prefixed with their bucket
+        let res = client
+            .get(cluster.key_url("photos%2F2024%2Fcat.png"))
+            .query(&[("proxy", "1")])
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "meow");
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "image/png");
+        assert_eq!(res.text().await?, "meow");
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["content-length"], "4");
+        assert!(res.headers().contains_key("last-modified"));
+
+        // A streaming SigV4 upload
+        let res = client
+            .put(format!("{}/photos/dog.png", s3_url))
+            .header("x-amz-content-sha256", "STREAMING-UNSIGNED-PAYLOAD-TRAILER")
+            .header("Content-Encoding", "aws-chunked")
+            .header("x-amz-decoded-content-length", "4")
+            .body("4\r\nwoof\r\n0\r\n\r\n")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .get(format!("{}/photos/dog.png", s3_url))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "woof");
+
+        let res = client
+            .get(format!("{}/photos", s3_url))
+            .query(&[("list-type", "2"), ("delimiter", "/")])
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let list = res.text().await?;
+        assert!(list.contains("<Key>dog.png</Key>"), "{}", list);
+        assert!(list.contains("<Size>4</Size>"), "{}", list);
+        assert!(
+            list.contains("<CommonPrefixes><Prefix>2024/</Prefix></CommonPrefixes>"),
+            "{}",
+            list
+        );
+        let res = client
+            .get(format!("{}/photos", s3_url))
+            .query(&[("list-type", "2"), ("prefix", "2024/")])
+            .send()
+            .await?;
+        let list = res.text().await?;
+        assert!(list.contains("<Key>2024/cat.png</Key>"), "{}", list);
+        assert!(!list.contains("dog.png"), "{}", list);
+
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.text().await?.contains("<Code>NoSuchKey</Code>"));
+
+        let res = client
+            .put(format!("{}/photos/bad.png", s3_url))
+            .header("Content-MD5", "1B2M2Y8AsgTpgAmY7PhCfg==")
+            .body("not empty")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.text().await?.contains("<Code>BadDigest</Code>"));
+
+        Ok(())
+    }
+}
@@ -1,440 +1,8624 @@
+use anyhow::Context;
 use axum::http::StatusCode;
-use futures::{stream::FuturesUnordered, StreamExt};
-use log::{debug, error};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt, TryStreamExt};
+use log::{debug, error, info, warn};
 use parking_lot::RwLock;
 use rand::{seq::SliceRandom, SeedableRng};
-use std::{collections::HashSet, path::Path, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 use tokio::signal;
+use tokio_util::task::TaskTracker;
 
-use crate::{hashring, record};
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::{
+    access, admin, auth, batch, breaker, checksum, compress, cors, dedup, drain, encryption,
+    erasure, expiry, fanout, fsck, gc, grpc, hashring, health, keys, limits, locks, metrics,
+    namespace, presign, rebalance, record, reload, repair, replication, resp, s3, spool, tasks,
+    telemetry, value_cache, volume_keys,
+};
 
 /// Axum state for PUT requests.
-struct AppPutState {
+pub(crate) struct AppPutState {
     leveldb: Arc<record::LevelDb>,
-    lock_keys: Arc<RwLock<HashSet<String>>>,
+    key_locks: Arc<locks::KeyLocks>,
     client: reqwest::Client,
-    hashring: Arc<hashring::Ring>,
+    hashring: Arc<RwLock<hashring::Ring>>,
     verify_checksums: bool,
+    hash_algorithm: checksum::Algorithm,
+    checksum_algorithms: Vec<checksum::Algorithm>,
+    write_quorum: usize,
+    max_replicas: usize,
+    namespace_replicas: HashMap<String, usize>,
+    key_policy: Arc<keys::KeyPolicy>,
+    max_value_size: Option<u64>,
+    write_retry: RetryPolicy,
+    two_phase_writes: bool,
+    erasure: Option<erasure::ErasureConfig>,
+    dedup: bool,
+    compression: Option<compress::CompressionConfig>,
+    encryption: Option<encryption::MasterKey>,
+    presign: Option<presign::PresignConfig>,
+    writes: TaskTracker,
+    health: Arc<health::VolumeHealth>,
 }
 
 /// Axum state for GET requests.
-struct AppGetState {
+pub(crate) struct AppGetState {
     leveldb: Arc<record::LevelDb>,
     client: reqwest::Client,
-    hashring: Arc<hashring::Ring>,
+    hashring: Arc<RwLock<hashring::Ring>>,
+    default_proxy: bool,
+    redirect_status: RedirectStatus,
+    redirect_cache_control: Option<axum::http::HeaderValue>,
+    concurrent_heads: bool,
+    health: Arc<health::VolumeHealth>,
+    read_repair: Arc<repair::ReadRepair>,
+    encryption: Option<encryption::MasterKey>,
+    presign: Option<presign::PresignConfig>,
+    value_cache: Option<value_cache::ValueCache>,
 }
 
 /// Axum state for DELETE requests.
-struct AppDeleteState {
+pub(crate) struct AppDeleteState {
     leveldb: Arc<record::LevelDb>,
-    lock_keys: Arc<RwLock<HashSet<String>>>,
+    key_locks: Arc<locks::KeyLocks>,
+    writes: TaskTracker,
 }
 
-/// Starts the server and listens for incoming requests.
-pub async fn new_and_serve(
-    port: u16,
-    leveldb_path: &Path,
-    verify_checksums: bool,
-    volumes: Vec<String>,
-    replicas: usize,
-    subvolumes: u32,
-) -> anyhow::Result<()> {
-    let leveldb = Arc::new(record::LevelDb::new(leveldb_path)?);
-    let lock_keys = Arc::new(RwLock::new(HashSet::<String>::new()));
+/// Axum state for COPY and MOVE requests, which read the source key, write the destination and delete the source.
+pub(crate) struct AppCopyState {
+    put: Arc<AppPutState>,
+    get: Arc<AppGetState>,
+    delete: Arc<AppDeleteState>,
+}
 
-    let hashring = {
-        let hashring = hashring::Ring::new(volumes, replicas, subvolumes);
-        Arc::new(hashring)
-    };
+impl AppPutState {
+    /// Returns the replica count configured for the namespace of a key, None to use the replicas of the ring.
+    fn namespace_replicas(&self, key: &str) -> Option<usize> {
+        let (name, _) = namespace::split(key)?;
+        self.namespace_replicas.get(name).copied()
+    }
+}
 
-    let client = reqwest::Client::new();
+/// Header naming the namespace of the key of a request to `/*key`, like the `/ns/:namespace/*key` routes.
+pub(crate) const NAMESPACE: &str = "X-Namespace";
 
-    let app_put_state = Arc::new(AppPutState {
-        leveldb: leveldb.clone(),
-        lock_keys: lock_keys.clone(),
-        client: client.clone(),
-        hashring: hashring.clone(),
-        verify_checksums,
-    });
+/// First path segments of the routes of the index, e.g. `/admin/volumes`. Plain keys starting with them
+/// are not served, they could be shadowed by the routes, e.g. on a listener without the admin routes.
+/// Namespaced keys can start with them, they are served under `/ns`.
+const ROUTE_PREFIXES: [&str; 3] = ["admin/", "ns/", "presign/"];
 
-    let app_get_state = Arc::new(AppGetState {
-        leveldb: leveldb.clone(),
-        client: client.clone(),
-        hashring: hashring.clone(),
-    });
+/// Paths of the routes of the index that are a single segment, e.g. `/batch`, not served as plain keys either.
+const ROUTE_KEYS: [&str; 1] = ["batch"];
 
-    let app_delete_state = Arc::new(AppDeleteState {
-        leveldb: leveldb.clone(),
-        lock_keys: lock_keys.clone(),
-    });
+/// Struct representing the leveldb key of a request: the path after the `/` of `/*key`, slashes included,
+/// in the namespace of the `/ns/:namespace/*key` routes or of an `X-Namespace` header, if any.
+/// Rejects invalid namespaces and plain keys starting with `ns:` with 400, and plain keys starting with a route
+/// prefix or naming a route with 404, like a missing route. Keys breaking the key policy of the server are rejected with 400 and
+/// a JSON body describing why, see `keys::KeyPolicy`.
+pub(crate) struct RecordKey(pub(crate) String);
 
-    let app = axum::Router::new()
-        .route(
-            "/:key",
-            axum::routing::put(handle_put_record).with_state(app_put_state),
-        )
-        .route(
-            "/:key",
-            axum::routing::get(handle_get_record).with_state(app_get_state),
-        )
-        .route(
-            "/:key",
-            axum::routing::delete(handle_delete_record).with_state(app_delete_state),
-        );
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for RecordKey {
+    type Rejection = axum::response::Response;
 
-    let listener = tokio::net::TcpListener::bind(format!("[::]:{}", port)).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        use axum::response::IntoResponse;
 
-    Ok(())
+        // Paths percent-decoding to invalid UTF-8 are rejected like the keys breaking the policy
+        let mut params = match axum::extract::Path::<HashMap<String, String>>::from_request_parts(
+            parts, state,
+        )
+        .await
+        {
+            Ok(axum::extract::Path(params)) => params,
+            Err(
+                rejection @ axum::extract::rejection::PathRejection::FailedToDeserializePathParams(
+                    _,
+                ),
+            ) => return Err(keys::KeyError(rejection.body_text()).into_response()),
+            Err(rejection) => return Err(rejection.into_response()),
+        };
+        let key = params.remove("key").unwrap_or_default();
+        let name = match params.remove("namespace") {
+            Some(name) => Some(name),
+            None => namespace_header(&parts.headers).map_err(IntoResponse::into_response)?,
+        };
+        let key = Self::resolve(key, name).map_err(IntoResponse::into_response)?;
+        // The routers of the server carry its key policy, see `serve`
+        if let Some(policy) = parts.extensions.get::<Arc<keys::KeyPolicy>>() {
+            if let Err(e) = policy.check(&key.0) {
+                debug!("record_key: key: {:?} rejected: {}", key.0, e);
+                return Err(e.into_response());
+            }
+        }
+        Ok(key)
+    }
 }
 
-/// Handles the shutdown signal.
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
+/// Returns the namespace of the `X-Namespace` header, if any.
+/// Returns 400 if the header is not visible ASCII.
+pub(crate) fn namespace_header(
+    headers: &axum::http::HeaderMap,
+) -> Result<Option<String>, StatusCode> {
+    match headers.get(NAMESPACE).map(|value| value.to_str()) {
+        Some(Ok(name)) => Ok(Some(name.to_string())),
+        Some(Err(_)) => Err(StatusCode::BAD_REQUEST),
+        None => Ok(None),
+    }
+}
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
+impl RecordKey {
+    /// Returns the leveldb key of a key of a request in a namespace, if any.
+    /// The key policy is left to the caller, see `keys::KeyPolicy::check`.
+    pub(crate) fn resolve(key: String, name: Option<String>) -> Result<Self, StatusCode> {
+        let key = match name {
+            Some(name) if namespace::is_valid(&name) => namespace::key(&name, &key),
+            Some(name) => {
+                debug!("record_key: invalid namespace: {:?}", name);
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            None if key.starts_with(namespace::PREFIX) => {
+                debug!("record_key: key: {} uses the namespace prefix", key);
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            None if ROUTE_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+                || ROUTE_KEYS.contains(&key.as_str()) =>
+            {
+                debug!("record_key: key: {} is shadowed by a route", key);
+                return Err(StatusCode::NOT_FOUND);
+            }
+            None => key,
+        };
+        Ok(Self(key))
+    }
+}
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+/// Struct representing the configuration of the server.
+pub struct Config {
+    pub leveldb_path: PathBuf,
+    pub db_backend: record::DbBackend,
+    /// Encoding the records are written with, bincode by default. Records of both encodings are read.
+    pub record_encoding: record::RecordEncoding,
+    /// Records kept in memory in front of the metadata store, least recently used first out, None to disable.
+    pub record_cache: Option<usize>,
+    /// Keys found without a record remembered as missing for a while, None to read the store for every miss.
+    pub negative_cache: Option<record::NegativeCacheConfig>,
+    /// Serves the small values from memory with 200 instead of redirecting to a volume, None to always redirect.
+    pub value_cache: Option<value_cache::ValueCacheConfig>,
+    pub verify_checksums: bool,
+    /// Algorithm of the hash stored in the records and returned as ETag, MD5 by default.
+    pub hash_algorithm: checksum::Algorithm,
+    pub checksum_algorithms: Vec<checksum::Algorithm>,
+    pub volumes: Vec<String>,
+    pub default_proxy: bool,
+    /// Status of the redirects of GETs to the volumes, 307 by default.
+    pub redirect_status: RedirectStatus,
+    /// Cache-Control of the redirects of GETs to the volumes, `no-store` by default as a rebalance or a
+    /// repair moves values to other volumes. None sends none.
+    pub redirect_cache_control: Option<String>,
+    /// HEADs every replica of a GET at once and redirects to the first holding the value,
+    /// instead of one after the other from the fastest.
+    pub concurrent_heads: bool,
+    pub replicas: usize,
+    /// Highest replica count a PUT can ask for with `X-Replicas`, None to allow up to the replicas.
+    pub max_replicas: Option<usize>,
+    /// Replica count of the keys of a namespace written without `X-Replicas`, instead of the replicas.
+    pub namespace_replicas: HashMap<String, usize>,
+    /// Longest key and characters allowed in keys, checked on every request to a key.
+    pub key_policy: keys::KeyPolicy,
+    /// Largest value a PUT can upload in bytes, the body is rejected as soon as it exceeds it. None for no limit.
+    pub max_value_size: Option<u64>,
+    pub write_quorum: Option<usize>,
+    pub subvolumes: u32,
+    pub volume_groups: Vec<(String, Vec<String>)>,
+    pub placement_rules: Vec<hashring::PlacementRule>,
+    pub task_schedules: HashMap<String, Duration>,
+    pub lock_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    /// Rebuilds the ring from the reloaded configuration on SIGHUP, None to keep the ring.
+    pub reload_ring: Option<reload::ReloadRing>,
+    /// Serves HTTPS with the certificate and key, None to serve plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// Certificates used to connect to volumes serving HTTPS.
+    pub volume_tls: VolumeTlsConfig,
+    /// Connection pool, HTTP/2 and keep-alive settings of the client making the requests to the volumes.
+    pub volume_client: VolumeClientConfig,
+    /// Requires a bearer token on every request but /healthz and /readyz, None to serve anyone.
+    pub auth: Option<auth::Tokens>,
+    /// Fraction of the volumes that must respond for /readyz to report ready.
+    pub ready_fraction: f64,
+    /// Retries of the replica writes failing with a transient volume error.
+    pub write_retry: RetryPolicy,
+    /// Uploads the replicas to a temporary path and moves them into place once enough of them hold the
+    /// value, the volumes must support WebDAV MOVE.
+    pub two_phase_writes: bool,
+    /// Stores values of at least a minimum size as Reed-Solomon shards instead of replicas, None to replicate every value.
+    pub erasure: Option<erasure::ErasureConfig>,
+    /// Stores a value written under several keys once, the records of the keys sharing its blob by SHA-256.
+    pub dedup: bool,
+    /// Compresses the values worth it with zstd before writing them to the volumes, None to store them as uploaded.
+    pub compression: Option<compress::CompressionConfig>,
+    /// Encrypts values with a data key of their own, wrapped by the master key, before writing them to
+    /// the volumes. None stores them in the clear. Conflicts with dedup, encrypted values share no blob.
+    pub encryption: Option<encryption::MasterKey>,
+    /// Signs the time-limited volume URLs of `GET /key?presign` and `POST /key?presign`, None to refuse them.
+    pub presign: Option<presign::PresignConfig>,
+    /// Stops sending requests to the volumes failing too many of them for a while.
+    pub breaker: breaker::BreakerConfig,
+    /// Bounds the PUTs of replicas, parts and shards to the volumes in flight at once.
+    pub fanout: fanout::FanoutConfig,
+    /// Time to connect to a volume, None to wait for the OS.
+    pub volume_connect_timeout: Option<Duration>,
+    /// Time a request to a volume has to complete, body included, None to wait forever.
+    pub volume_request_timeout: Option<Duration>,
+    /// Time a request to the server has to produce its response, None to wait forever.
+    pub request_deadline: Option<Duration>,
+    /// Turns away the requests over the global or per client IP rate with 429, and the ones over
+    /// the max in flight with 503, before they reach the metadata store or the volumes.
+    pub limits: limits::LimitsConfig,
+    /// Lets browsers call the routes of the public, internal and S3 listeners from the allowed origins,
+    /// None to answer no preflight.
+    pub cors: Option<cors::CorsConfig>,
+    /// Time a deleted key can be recovered before the gc task deletes its value from the volumes.
+    pub gc_grace_period: Duration,
+    /// Moves at most this many misplaced keys per second to the volumes of the ring in the background,
+    /// None to leave them until a rebalance.
+    pub auto_rebalance: Option<u32>,
+    /// Logs a line per request served under the `access` target, see `access::log_requests`.
+    pub access_log: bool,
+}
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+/// Default configuration of the server, the defaults of the command line flags.
+/// The leveldb path is empty and there are no volumes, both must be set.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            leveldb_path: PathBuf::new(),
+            db_backend: record::DbBackend::default(),
+            record_encoding: record::RecordEncoding::default(),
+            record_cache: None,
+            negative_cache: None,
+            value_cache: None,
+            verify_checksums: true,
+            hash_algorithm: checksum::Algorithm::Md5,
+            checksum_algorithms: Vec::new(),
+            volumes: Vec::new(),
+            default_proxy: false,
+            redirect_status: RedirectStatus::default(),
+            redirect_cache_control: Some(DEFAULT_REDIRECT_CACHE_CONTROL.to_string()),
+            concurrent_heads: false,
+            replicas: 3,
+            max_replicas: None,
+            namespace_replicas: HashMap::new(),
+            key_policy: keys::KeyPolicy::default(),
+            max_value_size: None,
+            write_quorum: None,
+            subvolumes: 10,
+            volume_groups: Vec::new(),
+            placement_rules: Vec::new(),
+            task_schedules: HashMap::new(),
+            lock_timeout: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(30),
+            reload_ring: None,
+            tls: None,
+            volume_tls: VolumeTlsConfig::default(),
+            volume_client: VolumeClientConfig::default(),
+            auth: None,
+            ready_fraction: health::DEFAULT_READY_FRACTION,
+            write_retry: RetryPolicy::default(),
+            two_phase_writes: false,
+            erasure: None,
+            dedup: false,
+            compression: None,
+            encryption: None,
+            presign: None,
+            breaker: breaker::BreakerConfig::default(),
+            fanout: fanout::FanoutConfig::default(),
+            volume_connect_timeout: Some(Duration::from_secs(5)),
+            volume_request_timeout: None,
+            request_deadline: None,
+            limits: limits::LimitsConfig::default(),
+            cors: None,
+            gc_grace_period: gc::DEFAULT_GRACE_PERIOD,
+            auto_rebalance: None,
+            access_log: true,
+        }
     }
 }
 
-/// Handles PUT requests to store a record.
-/// Returns 201 if the record is created
-/// Returns 409 if the record key is already locked for PUT/DELETE
-/// Returns 500 for internal server error
-async fn handle_put_record(
-    axum::extract::Path(key): axum::extract::Path<String>,
-    axum::extract::State(state): axum::extract::State<Arc<AppPutState>>,
-    headers: axum::http::HeaderMap,
-    body: axum::body::Bytes,
-) -> impl axum::response::IntoResponse {
-    debug!("put_record: key: {}", key);
+/// Struct representing the retries of a replica write after a connection error or a 5xx.
+/// The delay doubles after every attempt up to the max backoff, and the jitter fraction of it
+/// is randomized so replicas retried together spread out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts of a write, including the first one. 1 disables retries.
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction of the delay randomized, between 0 and 1.
+    pub jitter: f64,
+}
 
-    if headers.get(axum::http::header::CONTENT_LENGTH).is_none() || body.is_empty() {
-        return StatusCode::LENGTH_REQUIRED;
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.5,
+        }
     }
+}
 
-    if state.lock_keys.read().contains(&key) {
-        debug!("put_record: key: {} already locked", key);
-        return StatusCode::CONFLICT;
+impl RetryPolicy {
+    /// Returns the delay before the retry following an attempt, counted from 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter * rand::random::<f64>())
     }
+}
 
-    state.lock_keys.write().insert(key.clone());
+/// Struct representing the PEM files of the certificate chain and private key served over TLS.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
 
-    let record = match state.leveldb.get_record_or_default(&key).await {
-        Ok(record) => record,
-        Err(e) => {
-            error!(
-                "put_record: failed to get record {} from leveldb: {}",
-                key, e
-            );
-            state.lock_keys.write().remove(&key);
-            return StatusCode::INTERNAL_SERVER_ERROR;
-        }
-    };
+/// Struct representing the PEM files used to connect to `https://` volumes.
+/// The CA, if any, replaces the system roots, so only volumes with a certificate it signed are trusted.
+/// The client certificate and key, if any, are sent to volumes requiring mutual TLS.
+#[derive(Debug, Clone, Default)]
+pub struct VolumeTlsConfig {
+    pub ca: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
 
-    if let record::Deleted::No = record.deleted() {
-        state.lock_keys.write().remove(&key);
-        return StatusCode::CONFLICT;
+impl VolumeTlsConfig {
+    /// Returns the client making the requests to the volumes.
+    pub fn client(&self) -> anyhow::Result<reqwest::Client> {
+        Ok(self.client_builder()?.build()?)
     }
 
-    // TODO partNumber
-    let replicas_volumes = state.hashring.get_volume(&key);
-
-    let mut futures = FuturesUnordered::new();
-    for volume in replicas_volumes.iter() {
-        let remote_replica_volume_path = record::get_remote_path(&key);
-        let remote_url = format!("http://{}{}", volume, remote_replica_volume_path);
-        debug!("put_record key: {} remote_url: {}", key, remote_url);
-        let client_clone = state.client.clone();
-        let value_clone = body.clone();
-        futures.push(tokio::spawn(async move {
-            remote_put(client_clone, remote_url, value_clone).await
-        }));
+    /// Returns a builder of the client making the requests to the volumes, to set its timeouts.
+    pub fn client_builder(&self) -> anyhow::Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(ca) = &self.ca {
+            let pem = std::fs::read(ca)
+                .with_context(|| format!("failed to read volume CA {}", ca.display()))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("invalid volume CA {}", ca.display()))?;
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(certificate);
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let cert_pem = std::fs::read(cert).with_context(|| {
+                    format!(
+                        "failed to read volume client certificate {}",
+                        cert.display()
+                    )
+                })?;
+                let key_pem = std::fs::read(key).with_context(|| {
+                    format!("failed to read volume client key {}", key.display())
+                })?;
+                let identity = reqwest::Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+                    .with_context(|| {
+                        format!("invalid volume client certificate {}", cert.display())
+                    })?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("Need both a volume client certificate and key"),
+        }
+        Ok(builder)
     }
+}
 
-    while let Some(result) = futures.next().await {
-        match result {
-            Ok(_) => (),
-            Err(e) => {
-                error!(
-                    "put_record: failed to put record {} in remote replica: {}",
-                    key, e
-                );
+/// Default Cache-Control of the redirects of GETs to the volumes.
+pub const DEFAULT_REDIRECT_CACHE_CONTROL: &str = "no-store";
 
-                // In case of error we want to mark the record as Deleted::Soft in the local leveldb
-                let record =
-                    record::Record::new(record::Deleted::Soft, String::new(), replicas_volumes);
-                match state.leveldb.put_record(&key, record).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("put_record: failed to put record {} in leveldb: {}", key, e);
-                        state.lock_keys.write().remove(&key);
-                        return StatusCode::INTERNAL_SERVER_ERROR;
-                    }
-                }
-                state.lock_keys.write().remove(&key);
-                return StatusCode::INTERNAL_SERVER_ERROR;
-            }
+/// Enum representing the status of the redirects of GETs to the volumes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedirectStatus {
+    /// 302 Found, which some clients replay as a GET whatever the method, or mishandle for ranges.
+    #[value(name = "302")]
+    Found,
+    /// 307 Temporary Redirect, replayed with the same method and headers.
+    #[default]
+    #[value(name = "307")]
+    Temporary,
+    /// 308 Permanent Redirect, replayed like 307 but cacheable by default, set a Cache-Control that fits.
+    #[value(name = "308")]
+    Permanent,
+}
+
+impl RedirectStatus {
+    /// Returns the status code of the redirects.
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            RedirectStatus::Found => StatusCode::FOUND,
+            RedirectStatus::Temporary => StatusCode::TEMPORARY_REDIRECT,
+            RedirectStatus::Permanent => StatusCode::PERMANENT_REDIRECT,
         }
     }
+}
 
-    let value_md5_hash = if state.verify_checksums {
-        let body_clone = body.clone();
-        tokio::task::spawn_blocking(move || format!("{:x}", md5::compute(body_clone)))
-            .await
-            .unwrap_or_default()
-    } else {
-        String::new()
-    };
+/// Struct representing the connection pool and protocol of the client making the requests to the volumes.
+/// The defaults are the ones of reqwest, tuned for many hosts rather than a few volumes taking sustained writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeClientConfig {
+    /// Idle connections kept open to each volume, None for no bound.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Time an idle connection is kept open, None to keep it until the volume closes it.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Talks HTTP/2 to the volumes without negotiating it first, every volume must support it.
+    pub http2: bool,
+    /// Interval of the TCP keep-alive probes, and of the HTTP/2 pings with `http2`, None to send none.
+    pub keep_alive: Option<Duration>,
+}
 
-    let record = record::Record::new(record::Deleted::No, value_md5_hash, replicas_volumes);
-    match state.leveldb.put_record(&key, record).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!(
-                "put_record: failed to put record with value_md5_hash {} in leveldb: {}",
-                key, e
-            );
-            state.lock_keys.write().remove(&key);
-            return StatusCode::INTERNAL_SERVER_ERROR;
+impl Default for VolumeClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            http2: false,
+            keep_alive: None,
         }
     }
-
-    state.lock_keys.write().remove(&key);
-    StatusCode::CREATED
 }
 
-/// Puts a value in a remote volume using reqwest
-/// if the response status is not CREATED or NO_CONTENT, an error 500 is returned
-async fn remote_put(
-    client: reqwest::Client,
-    remote_url: String,
-    value: bytes::Bytes,
-) -> anyhow::Result<()> {
-    let res = client.put(remote_url.clone()).body(value).send().await?;
-    if res.status().is_success() {
-        if res.status().as_u16() != axum::http::StatusCode::CREATED.as_u16()
-            && res.status().as_u16() != axum::http::StatusCode::NO_CONTENT.as_u16()
-        {
-            return Err(anyhow::anyhow!(
-                "remote_put: invalid status code: {} for url: {}",
-                res.status(),
-                remote_url
-            ));
+impl VolumeClientConfig {
+    /// Applies the pool and protocol settings to a builder of the client making the requests to the volumes.
+    pub fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
         }
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!(
-            "remote_put: failed to put value at {}: {}",
-            remote_url,
-            res.status()
-        ))
+        builder = builder
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.keep_alive);
+        if self.http2 {
+            builder = builder.http2_prior_knowledge();
+            if let Some(interval) = self.keep_alive {
+                builder = builder
+                    .http2_keep_alive_interval(interval)
+                    .http2_keep_alive_while_idle(true);
+            }
+        }
+        builder
     }
 }
 
-/// Handles GET requests to retrieve a record.
-/// Returns FOUND if the record is found in a volume
-/// Returns NOT_FOUND if the record is not found
-/// Returns GONE if the record is not found in any volume
-/// Returns INTERNAL_SERVER_ERROR for internal server error
-async fn handle_get_record(
-    axum::extract::Path(key): axum::extract::Path<String>,
-    axum::extract::State(state): axum::extract::State<Arc<AppGetState>>,
-) -> axum::response::Response {
-    debug!("get_record: key: {}", key);
+impl Config {
+    /// Returns the ring of the configured volumes, volume groups and placement rules.
+    pub fn hashring(&self) -> anyhow::Result<hashring::Ring> {
+        let mut hashring =
+            hashring::Ring::new(self.volumes.clone(), self.replicas, self.subvolumes);
+        for (name, volumes) in self.volume_groups.iter() {
+            hashring.add_group(name.clone(), volumes.clone())?;
+        }
+        for rule in self.placement_rules.iter() {
+            hashring.add_placement_rule(rule.clone())?;
+        }
+        Ok(hashring)
+    }
 
-    let record = {
-        match state.leveldb.get_record(&key).await {
-            Ok(record) => record,
-            Err(e) => {
-                error!(
-                    "get_record: failed to get record {} from leveldb: {}",
-                    key, e
+    /// Checks the configuration merged from the command line and the config file before starting.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.volumes.len() < self.replicas {
+            anyhow::bail!(
+                "Need at least as many volumes: {} as replicas: {}",
+                self.volumes.len(),
+                self.replicas
+            );
+        }
+        if let Some(max_replicas) = self.max_replicas {
+            if max_replicas < self.replicas || max_replicas > self.volumes.len() {
+                anyhow::bail!(
+                    "Max replicas: {} must be between the replicas: {} and the volumes: {}",
+                    max_replicas,
+                    self.replicas,
+                    self.volumes.len()
                 );
-                return axum::http::Response::builder()
-                    .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(axum::body::Body::empty())
-                    .unwrap();
             }
         }
-    };
-
-    if record.is_none() {
-        return axum::http::Response::builder()
-            .status(axum::http::StatusCode::NOT_FOUND)
-            .header(axum::http::header::CONTENT_LENGTH, "0")
-            .header("Content-Md5", "")
-            .body(axum::body::Body::empty())
-            .unwrap();
+        for (name, replicas) in self.namespace_replicas.iter() {
+            if !namespace::is_valid(name) {
+                anyhow::bail!("Invalid namespace: {:?}", name);
+            }
+            if *replicas == 0 || *replicas > self.volumes.len() {
+                anyhow::bail!(
+                    "Replicas of namespace {}: {} must be between 1 and the volumes: {}",
+                    name,
+                    replicas,
+                    self.volumes.len()
+                );
+            }
+        }
+        if self.subvolumes == 0 {
+            anyhow::bail!("Need at least one subvolume");
+        }
+        if self.write_retry.attempts == 0 {
+            anyhow::bail!("Need at least one write attempt");
+        }
+        if !(0.0..=1.0).contains(&self.write_retry.jitter) {
+            anyhow::bail!(
+                "Write retry jitter: {} must be between 0 and 1",
+                self.write_retry.jitter
+            );
+        }
+        if !(0.0..=1.0).contains(&self.breaker.error_rate) {
+            anyhow::bail!(
+                "Breaker error rate: {} must be between 0 and 1",
+                self.breaker.error_rate
+            );
+        }
+        if self.limits.rate == Some(0) || self.limits.client_rate == Some(0) {
+            anyhow::bail!("Rate limits must allow at least 1 request per second");
+        }
+        if self.record_cache == Some(0) {
+            anyhow::bail!("Record cache must hold at least 1 record");
+        }
+        if self
+            .negative_cache
+            .is_some_and(|negative_cache| negative_cache.capacity == 0)
+        {
+            anyhow::bail!("Negative cache must hold at least 1 key");
+        }
+        if let Some(cache_control) = &self.redirect_cache_control {
+            axum::http::HeaderValue::from_str(cache_control)
+                .with_context(|| format!("Invalid redirect Cache-Control: {:?}", cache_control))?;
+        }
+        if self
+            .value_cache
+            .is_some_and(|value_cache| value_cache.capacity == 0 || value_cache.max_value_size == 0)
+        {
+            anyhow::bail!("Value cache must hold values of at least 1 byte");
+        }
+        if self.limits.max_in_flight == Some(0) {
+            anyhow::bail!("Max in flight must allow at least 1 request");
+        }
+        if self.fanout.max_puts == Some(0) || self.fanout.max_puts_per_volume == Some(0) {
+            anyhow::bail!("Fan-out bounds must allow at least 1 PUT to the volumes");
+        }
+        if self
+            .cors
+            .as_ref()
+            .is_some_and(|cors| cors.origins.is_empty())
+        {
+            anyhow::bail!("CORS needs at least one allowed origin");
+        }
+        if self.auto_rebalance == Some(0) {
+            anyhow::bail!("Auto rebalance must move at least 1 key per second");
+        }
+        if !(0.0..=1.0).contains(&self.ready_fraction) {
+            anyhow::bail!(
+                "Ready fraction: {} must be between 0 and 1",
+                self.ready_fraction
+            );
+        }
+        for (name, volumes) in self.volume_groups.iter() {
+            if volumes.is_empty() {
+                anyhow::bail!("Volume group {} has no volumes", name);
+            }
+        }
+        if let Some(erasure) = self.erasure {
+            if erasure.data_shards == 0 || erasure.parity_shards == 0 {
+                anyhow::bail!("Erasure coding needs at least one data shard and one parity shard");
+            }
+            let groups = self.volume_groups.iter().map(|(_, volumes)| volumes);
+            if let Some(volumes) = std::iter::once(&self.volumes)
+                .chain(groups)
+                .find(|volumes| volumes.len() < erasure.shards())
+            {
+                anyhow::bail!(
+                    "Need at least as many volumes: {} as erasure coded shards: {}",
+                    volumes.len(),
+                    erasure.shards()
+                );
+            }
+        }
+        for rule in self.placement_rules.iter() {
+            if !self
+                .volume_groups
+                .iter()
+                .any(|(name, _)| *name == rule.group)
+            {
+                anyhow::bail!(
+                    "Placement rule for prefix {} references unknown volume group {}",
+                    rule.prefix,
+                    rule.group
+                );
+            }
+        }
+        let tasks = [
+            repair::TASK_NAME,
+            expiry::TASK_NAME,
+            gc::TASK_NAME,
+            health::TASK_NAME,
+            fsck::TASK_NAME,
+            replication::TASK_NAME,
+            rebalance::TASK_NAME,
+        ];
+        for name in self.task_schedules.keys() {
+            if !tasks.contains(&name.as_str()) {
+                anyhow::bail!(
+                    "Unknown task {} in task schedules, expected one of {:?}",
+                    name,
+                    tasks
+                );
+            }
+        }
+        Ok(())
     }
+}
 
-    let record = record.unwrap();
+/// Query parameters of GET requests. `?list` lists the keys starting with the path instead,
+/// rolled up to their next `delimiter` with `?list&delimiter=/`.
+/// `?presign&expires=N` returns a volume URL valid for N seconds instead of redirecting to it,
+/// and `?meta` the metadata of the record.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct GetParams {
+    list: Option<String>,
+    meta: Option<String>,
+    delimiter: Option<String>,
+    proxy: Option<String>,
+    presign: Option<String>,
+    expires: Option<u64>,
+}
 
-    if record.deleted() != record::Deleted::No {
-        debug!(
-            "get_record: key: {} not found, record deleted: {:?}",
-            key,
-            record.deleted()
-        );
-        return axum::http::Response::builder()
-            .status(axum::http::StatusCode::NOT_FOUND)
-            .header(axum::http::header::CONTENT_LENGTH, "0")
-            .header("Content-Md5", record.hash().to_string())
-            .body(axum::body::Body::empty())
-            .unwrap();
+impl GetParams {
+    /// Returns the parameters of a GET streaming the value through the index.
+    pub(crate) fn proxied() -> Self {
+        Self {
+            proxy: Some("1".to_string()),
+            ..Default::default()
+        }
     }
 
-    let replicas_volumes = state.hashring.get_volume(&key);
-    let needs_rebalance_header = if needs_rebalance(&replicas_volumes, record.read_volumes()) {
-        "unbalanced"
-    } else {
-        "balanced"
-    };
-
-    let remote_url: Option<String> = {
-        let mut found_remote_url = None;
-        let mut rnd = rand::rngs::StdRng::from_entropy();
-        for volume in replicas_volumes.choose(&mut rnd).into_iter() {
-            let remote_replica_volume_path = record::get_remote_path(&key);
-            let remote_url = format!("http://{}{}", volume, remote_replica_volume_path);
-            if let Ok(()) = remote_head(&state.client, &remote_url).await {
-                found_remote_url = Some(remote_url);
-                break;
-            }
+    /// Returns the parameters of a GET redirecting to a volume, whatever the server default.
+    pub(crate) fn redirected() -> Self {
+        Self {
+            proxy: Some("0".to_string()),
+            ..Default::default()
         }
-        found_remote_url
-    };
+    }
 
-    match remote_url {
-        Some(remote_url) => {
-            debug!("get_record: key: {} from remote_url: {}", key, remote_url);
-            axum::http::Response::builder()
-                .status(axum::http::StatusCode::FOUND)
-                .header(axum::http::header::LOCATION, remote_url)
-                .header(axum::http::header::CONTENT_LENGTH, "0")
-                .header("Content-Md5", record.hash().to_string())
-                .body(axum::body::Body::empty())
-                .unwrap()
-        }
-        None => {
-            debug!("get_record: key: {} not found in any volume", key);
-            axum::http::Response::builder()
-                .status(axum::http::StatusCode::GONE)
-                .header(axum::http::header::CONTENT_LENGTH, "0")
-                .header("Key-Volumes", record.read_volumes().join(","))
-                .header("Key-Balance", needs_rebalance_header)
-                .body(axum::body::Body::empty())
-                .unwrap()
+    /// Returns true if the value is streamed through the index instead of redirected to.
+    /// `?proxy=1` proxies and `?proxy=0` redirects, whatever the server default.
+    fn proxy(&self, default_proxy: bool) -> bool {
+        match self.proxy.as_deref() {
+            Some(proxy) => !matches!(proxy, "0" | "false"),
+            None => default_proxy,
         }
     }
+
+    /// Returns true if `?proxy=0` asks for a redirect to a volume, even for a value the index could serve.
+    fn redirect(&self) -> bool {
+        matches!(self.proxy.as_deref(), Some("0" | "false"))
+    }
+
+    /// Returns the seconds the presigned URL of `?presign` is valid for, None without `?presign`.
+    fn presign_expires(&self) -> anyhow::Result<Option<u64>> {
+        self.presign
+            .as_ref()
+            .map(|_| presign::expires_in(self.expires))
+            .transpose()
+    }
 }
 
-/// Checks if the number of replicas volumes is different from the number of record read volumes
-/// and returns true if they are different, false otherwise
-fn needs_rebalance(replicas_volumes: &[String], record_read_volumes: &[String]) -> bool {
-    replicas_volumes.len() != record_read_volumes.len()
+/// Query parameters of PUT requests. `?partNumber=N` uploads a part of a multipart upload.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct PutParams {
+    #[serde(rename = "partNumber")]
+    part_number: Option<u32>,
 }
 
-/// Checks if a record exists in a remote volume using reqwest
-async fn remote_head(client: &reqwest::Client, remote_url: &str) -> anyhow::Result<()> {
-    let res = client.head(remote_url).send().await?;
-    if res.status().is_success() {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!(
-            "remote_head: failed to head {}: {}",
-            remote_url,
-            res.status()
-        ))
+/// Query parameters of POST requests. `?uploads=complete` completes a multipart upload,
+/// `?undelete` restores a deleted key, `?presign&expires=N` returns upload URLs valid for N seconds
+/// and `?presign=complete` completes their upload.
+#[derive(Debug, serde::Deserialize)]
+struct PostParams {
+    uploads: Option<String>,
+    undelete: Option<String>,
+    presign: Option<String>,
+    expires: Option<u64>,
+}
+
+/// Highest part number of a multipart upload, like S3.
+const MAX_PART_NUMBER: u32 = 10000;
+
+/// Header used on PUT to pin a key to a named volume group.
+const KEY_VOLUME_GROUP: &str = "Key-Volume-Group";
+
+/// Header used on PUT to expire a key after a number of seconds.
+pub(crate) const TTL: &str = "X-Ttl";
+
+/// Key of the POST storing a content addressed value, see `put_content_addressed`.
+const CAS_KEY: &str = "cas";
+
+/// Prefix of the keys of content addressed values, followed by the hex SHA-256 of the value.
+/// Only `POST /cas` writes them, so the key always names its content.
+pub(crate) const CAS_PREFIX: &str = "cas:";
+
+/// Header of HEAD responses counting the uploads sharing a content addressed value.
+const REFCOUNT: &str = "X-Refcount";
+
+/// Header of GET redirects with the size of the value in bytes, their own Content-Length being 0.
+const VALUE_SIZE: &str = "X-Value-Size";
+
+/// Largest body of a POST completing a multipart upload or undeleting a key, the default body limit of axum.
+const MAX_POST_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Header used on PUT to store a key on more or fewer volumes than the replicas of the ring.
+const REPLICAS: &str = "X-Replicas";
+
+/// Suffix of the path a value is uploaded to by two-phase writes before it is moved into place.
+/// The dot is not in the base64 alphabet of the remote paths, so it never names a key.
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Suffix of the path a value is uploaded to with presigned URLs, moved into place by the completion.
+/// Distinct from `TMP_SUFFIX`, so a PUT of the key doesn't clobber the upload.
+const UPLOAD_SUFFIX: &str = ".upload";
+
+/// WebDAV headers of the MOVE committing a two-phase write, also naming the key a COPY or MOVE of a key writes to.
+const DESTINATION: &str = "Destination";
+const OVERWRITE: &str = "Overwrite";
+
+/// Attempts to put a value in the replicas a quorum write missed before giving up.
+const REPAIR_ATTEMPTS: u32 = 4;
+
+/// Delay before the first repair attempt, doubled after every attempt.
+const REPAIR_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Struct representing the listeners the server accepts connections on.
+pub struct Listeners {
+    /// Serves every route, or only GET and HEAD of keys with an internal listener.
+    pub public: tokio::net::TcpListener,
+    /// Serves every route, so mutations and /admin can stay on a private network.
+    pub internal: Option<tokio::net::TcpListener>,
+    /// Serves the S3 compatible gateway, see `s3::router`.
+    pub s3: Option<tokio::net::TcpListener>,
+    /// Serves the Redis protocol, see `resp::serve`.
+    pub resp: Option<tokio::net::TcpListener>,
+    /// Serves the gRPC service, see `grpc::router`.
+    pub grpc: Option<tokio::net::TcpListener>,
+    /// Serves /healthz, /readyz, /metrics and /admin instead of the public and internal listeners.
+    pub admin: Option<tokio::net::TcpListener>,
+}
+
+impl Listeners {
+    /// Creates the listeners serving every route on the public listener.
+    pub fn new(public: tokio::net::TcpListener) -> Self {
+        Self {
+            public,
+            internal: None,
+            s3: None,
+            resp: None,
+            grpc: None,
+            admin: None,
+        }
+    }
+
+    /// Serves every route on the internal listener and only reads on the public one.
+    pub fn with_internal(mut self, internal: Option<tokio::net::TcpListener>) -> Self {
+        self.internal = internal;
+        self
+    }
+
+    /// Serves the S3 gateway on the listener.
+    pub fn with_s3(mut self, s3: Option<tokio::net::TcpListener>) -> Self {
+        self.s3 = s3;
+        self
+    }
+
+    /// Serves the Redis protocol on the listener.
+    pub fn with_resp(mut self, resp: Option<tokio::net::TcpListener>) -> Self {
+        self.resp = resp;
+        self
+    }
+
+    /// Serves the gRPC service on the listener.
+    pub fn with_grpc(mut self, grpc: Option<tokio::net::TcpListener>) -> Self {
+        self.grpc = grpc;
+        self
+    }
+
+    /// Serves the probes, /metrics and /admin on the listener only, e.g. one bound to localhost.
+    pub fn with_admin(mut self, admin: Option<tokio::net::TcpListener>) -> Self {
+        self.admin = admin;
+        self
     }
 }
 
-/// Handles DELETE requests to delete a record.
-/// Returns 204 if the record is deleted
-/// Returns 404 if the record is not found
-/// Returns 409 if the record key is already locked for PUT/DELETE
-/// Returns 500 for internal server error
-async fn handle_delete_record(
-    axum::extract::Path(key): axum::extract::Path<String>,
-    axum::extract::State(state): axum::extract::State<Arc<AppDeleteState>>,
-) -> axum::response::Response {
-    debug!("delete_record: key: {}", key);
+/// Binds a listener to the address, if any.
+async fn bind(
+    addr: Option<std::net::SocketAddr>,
+) -> std::io::Result<Option<tokio::net::TcpListener>> {
+    match addr {
+        Some(addr) => Ok(Some(tokio::net::TcpListener::bind(addr).await?)),
+        None => Ok(None),
+    }
+}
 
-    if state.lock_keys.read().contains(&key) {
-        debug!("delete_record: key: {} already locked", key);
-        return axum::http::Response::builder()
-            .status(axum::http::StatusCode::CONFLICT)
-            .body(axum::body::Body::empty())
-            .unwrap();
+/// Starts the server and listens for incoming requests.
+/// With an internal address the port only serves reads, mutations and /admin are served on the internal address.
+/// With an S3, RESP or gRPC address the S3 gateway, the Redis protocol or the gRPC service is served on it too.
+/// With an admin address the probes, /metrics and /admin are only served on it.
+pub async fn new_and_serve(
+    port: u16,
+    internal_addr: Option<std::net::SocketAddr>,
+    s3_addr: Option<std::net::SocketAddr>,
+    resp_addr: Option<std::net::SocketAddr>,
+    grpc_addr: Option<std::net::SocketAddr>,
+    admin_addr: Option<std::net::SocketAddr>,
+    config: Config,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(format!("[::]:{}", port)).await?;
+    let listeners = Listeners::new(listener)
+        .with_internal(bind(internal_addr).await?)
+        .with_s3(bind(s3_addr).await?)
+        .with_resp(bind(resp_addr).await?)
+        .with_grpc(bind(grpc_addr).await?)
+        .with_admin(bind(admin_addr).await?);
+    Server::new(config)
+        .serve(listeners, shutdown_signal())
+        .await
+}
+
+/// Struct representing the index server, the coordinator placing the values on the volumes of the ring
+/// and keeping their records in the metadata store.
+pub struct Server {
+    config: Config,
+    store: Option<Box<dyn record::MetadataStore>>,
+    /// Set once shutdown started, writes are rejected from then on.
+    draining: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Server {
+    /// Creates a server with the configuration, storing the records in the backend of the config.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            store: None,
+            draining: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        }
+    }
+
+    /// Stores the records in the metadata store instead of opening the leveldb path of the config.
+    pub fn with_store(mut self, store: Box<dyn record::MetadataStore>) -> Self {
+        self.store = Some(store);
+        self
     }
 
-    state.lock_keys.write().insert(key.clone());
+    /// Returns the flag set once shutdown started, set by the testkit to reject writes without shutting down.
+    #[cfg(any(test, feature = "testkit"))]
+    pub(crate) fn draining(&self) -> Arc<std::sync::atomic::AtomicBool> {
+        self.draining.clone()
+    }
 
-    let record = match state.leveldb.get_record_or_default(&key).await {
-        Ok(record) => record,
-        Err(e) => {
-            error!(
-                "delete_record: failed to get record {} from leveldb: {}",
-                key, e
+    /// Serves incoming requests on the listeners until the shutdown future completes.
+    /// Without an internal listener every route is served on the public listener.
+    /// With an internal listener the public listener only serves GET and HEAD of keys, and the internal
+    /// listener serves every route, so mutations and /admin can stay on a private network.
+    /// The S3 gateway doesn't check signatures, so it cannot be served with bearer tokens required.
+    /// RESP connections authenticate with the bearer tokens through AUTH.
+    /// On shutdown new connections are refused and writes on open connections answered 503, UNAVAILABLE
+    /// over gRPC or an error reply over RESP, then open
+    /// connections, in-flight writes and running background tasks are waited for until the shutdown timeout
+    /// of the config elapses, and the metadata store is flushed.
+    pub async fn serve(
+        self,
+        listeners: Listeners,
+        shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let Self {
+            config,
+            store,
+            draining,
+        } = self;
+        if listeners.s3.is_some() && config.auth.is_some() {
+            anyhow::bail!(
+                "The S3 gateway cannot be served with authentication, it doesn't check signatures"
             );
-            state.lock_keys.write().remove(&key);
-            return axum::http::Response::builder()
-                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(axum::body::Body::empty())
-                .unwrap();
         }
+        let shutdown_timeout = config.shutdown_timeout;
+        let tls = match config.tls.clone() {
+            Some(tls) => {
+                let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                    .await
+                    .with_context(|| {
+                        format!("failed to load TLS certificate {}", tls.cert.display())
+                    })?;
+                Some((rustls, tls))
+            }
+            None => None,
+        };
+        let app = new_app(config, store, listeners.admin.is_some(), draining)?;
+
+        let shutdown = shutdown.shared();
+        let mut reloader = app.reloader;
+        if let Some((rustls, tls)) = tls.clone() {
+            reloader = reloader.with_tls(rustls, tls);
+        }
+        if reloader.is_enabled() {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = reload::reload_on_sighup(reloader) => {},
+                    _ = shutdown => {},
+                }
+            });
+        }
+        tokio::spawn({
+            let (shutdown, draining) = (shutdown.clone(), app.draining.clone());
+            async move {
+                shutdown.await;
+                info!("shutdown: draining, new writes are rejected");
+                draining.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+        let rustls = tls.map(|(rustls, _)| rustls);
+        let deadline = {
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown.await;
+                tokio::time::Instant::now() + shutdown_timeout
+            }
+            .shared()
+        };
+
+        let serving = async {
+            let s3 = async {
+                match listeners.s3 {
+                    Some(s3) => serve_router(s3, app.s3, rustls.clone(), shutdown.clone()).await,
+                    None => Ok(()),
+                }
+            };
+            let keys = async {
+                match listeners.internal {
+                    None => {
+                        serve_router(listeners.public, app.full, rustls.clone(), shutdown.clone())
+                            .await
+                    }
+                    Some(internal) => {
+                        let (public, internal) = tokio::join!(
+                            serve_router(
+                                listeners.public,
+                                app.read,
+                                rustls.clone(),
+                                shutdown.clone()
+                            ),
+                            serve_router(internal, app.full, rustls.clone(), shutdown.clone()),
+                        );
+                        public.and(internal)
+                    }
+                }
+            };
+            let resp = async {
+                match listeners.resp {
+                    Some(resp) => {
+                        resp::serve(resp, app.resp.clone(), rustls.clone(), shutdown.clone()).await
+                    }
+                    None => Ok(()),
+                }
+            };
+            let grpc = async {
+                match listeners.grpc {
+                    Some(grpc) => {
+                        serve_router(grpc, app.grpc, rustls.clone(), shutdown.clone()).await
+                    }
+                    None => Ok(()),
+                }
+            };
+            let admin = async {
+                match listeners.admin {
+                    Some(admin) => {
+                        serve_router(admin, app.admin, rustls.clone(), shutdown.clone()).await
+                    }
+                    None => Ok(()),
+                }
+            };
+            let (keys, s3, resp, grpc, admin) = tokio::join!(keys, s3, resp, grpc, admin);
+            keys.and(s3).and(resp).and(grpc).and(admin)
+        };
+
+        let served = tokio::select! {
+            served = serving => served,
+            _ = async { tokio::time::sleep_until(deadline.clone().await).await } => {
+                warn!("shutdown: deadline of {:?} exceeded, closing open connections", shutdown_timeout);
+                Ok(())
+            }
+        };
+
+        // The deadline is unset if serving failed before a shutdown was requested
+        let deadline = deadline
+            .now_or_never()
+            .unwrap_or_else(|| tokio::time::Instant::now() + shutdown_timeout);
+
+        app.writes.close();
+        if tokio::time::timeout_at(deadline, app.writes.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "shutdown: deadline exceeded with {} writes in flight",
+                app.writes.len()
+            );
+        }
+        if tokio::time::timeout_at(deadline, app.scheduler.shutdown())
+            .await
+            .is_err()
+        {
+            warn!("shutdown: deadline exceeded with background tasks running");
+        }
+        // Writes still in flight past the deadline are lost with the process, the replicas
+        // they missed are in the replication queue if their record was written
+        let leveldb = app.leveldb.clone();
+        match tokio::task::spawn_blocking(move || leveldb.flush()).await? {
+            Ok(()) => info!("shutdown: metadata store flushed"),
+            Err(e) => error!("shutdown: failed to flush the metadata store: {:#}", e),
+        }
+        served?;
+
+        Ok(())
+    }
+}
+
+/// Serves a router on a listener until the shutdown future completes, over TLS if configured.
+async fn serve_router(
+    listener: tokio::net::TcpListener,
+    router: axum::Router,
+    rustls: Option<RustlsConfig>,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    // The access log reads the address of the client
+    let router = router.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let Some(rustls) = rustls else {
+        return axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown)
+            .await;
     };
 
-    if record.deleted() == record::Deleted::Hard || record.deleted() == record::Deleted::Soft {
-        debug!("delete_record: key: {} already deleted", key);
-        state.lock_keys.write().remove(&key);
-        return axum::http::Response::builder()
-            .status(axum::http::StatusCode::NOT_FOUND)
-            .body(axum::body::Body::empty())
-            .unwrap();
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    axum_server::from_tcp_rustls(listener.into_std()?, rustls)
+        .handle(handle)
+        .serve(router)
+        .await
+}
+
+/// Struct representing the routers of the server and the scheduler of the background tasks.
+struct App {
+    /// Serves GET and HEAD of keys.
+    read: axum::Router,
+    /// Serves every route: reads, PUT and DELETE of keys and /admin, but the admin routes with an admin listener.
+    full: axum::Router,
+    /// Serves the probes, /metrics and /admin with an admin listener, empty otherwise.
+    admin: axum::Router,
+    /// Serves the S3 gateway.
+    s3: axum::Router,
+    /// Executes the commands of the RESP listener.
+    resp: Arc<resp::AppRespState>,
+    /// Serves the gRPC service.
+    grpc: axum::Router,
+    scheduler: Arc<tasks::Scheduler>,
+    /// Tracks the in-flight replica uploads and metadata writes.
+    writes: TaskTracker,
+    /// Swaps the ring and the TLS certificate on SIGHUP.
+    reloader: reload::Reloader,
+    /// Set once shutdown started, writes are rejected from then on.
+    draining: Arc<std::sync::atomic::AtomicBool>,
+    /// Flushed once the writes and background tasks stopped on shutdown.
+    leveldb: Arc<record::LevelDb>,
+}
+
+/// Creates the axum routers with the state for every route and the scheduler of the background tasks.
+/// With a separate admin listener the probes, /metrics and /admin are left out of the read and full routers.
+fn new_app(
+    config: Config,
+    store: Option<Box<dyn record::MetadataStore>>,
+    separate_admin: bool,
+    draining: Arc<std::sync::atomic::AtomicBool>,
+) -> anyhow::Result<App> {
+    let leveldb = Arc::new(
+        match store {
+            Some(store) => record::LevelDb::with_store(store),
+            None => record::LevelDb::with_backend(&config.leveldb_path, config.db_backend)?,
+        }
+        .with_record_encoding(config.record_encoding)
+        .with_record_cache(config.record_cache)
+        .with_negative_cache(config.negative_cache),
+    );
+    volume_keys::build(&leveldb)?;
+    if config.dedup && config.encryption.is_some() {
+        anyhow::bail!("dedup can't share the blobs of encrypted values, use one or the other");
+    }
+    let hashring = Arc::new(RwLock::new(config.hashring()?));
+    breaker::BREAKERS.configure(config.breaker);
+    fanout::FANOUT.configure(config.fanout);
+    let scheduler = Arc::new(tasks::Scheduler::new(config.task_schedules));
+    let key_locks = locks::KeyLocks::new(config.lock_timeout);
+
+    let reloader = reload::Reloader::new(leveldb.clone(), hashring.clone(), config.reload_ring);
+
+    let mut client = config
+        .volume_client
+        .apply(config.volume_tls.client_builder()?);
+    if let Some(timeout) = config.volume_connect_timeout {
+        client = client.connect_timeout(timeout);
+    }
+    if let Some(timeout) = config.volume_request_timeout {
+        client = client.timeout(timeout);
+    }
+    let client = client.build()?;
+    let repair = Arc::new(
+        repair::Repair::new(leveldb.clone(), hashring.clone(), key_locks.clone())
+            .with_client(client.clone()),
+    );
+    scheduler.register(
+        repair::TASK_NAME,
+        repair::INTERVAL,
+        repair::task(repair.clone()),
+    );
+    let expiry =
+        expiry::Expiry::new(leveldb.clone(), key_locks.clone()).with_client(client.clone());
+    scheduler.register(
+        expiry::TASK_NAME,
+        expiry::INTERVAL,
+        expiry::task(Arc::new(expiry)),
+    );
+    let gc = gc::Gc::new(leveldb.clone(), key_locks.clone(), config.gc_grace_period)
+        .with_client(client.clone());
+    scheduler.register(gc::TASK_NAME, gc::INTERVAL, gc::task(Arc::new(gc)));
+    let fsck = fsck::Fsck::new(leveldb.clone(), key_locks.clone())
+        .with_client(client.clone())
+        .with_encryption(config.encryption.clone())
+        .with_repair(true);
+    scheduler.register(fsck::TASK_NAME, fsck::INTERVAL, fsck::task(Arc::new(fsck)));
+    let replicator = replication::Replicator::new(leveldb.clone(), key_locks.clone())
+        .with_client(client.clone());
+    scheduler.register(
+        replication::TASK_NAME,
+        replication::INTERVAL,
+        replication::task(Arc::new(replicator)),
+    );
+    if let Some(keys_per_sec) = config.auto_rebalance {
+        let rebalance = rebalance::AutoRebalance::new(
+            leveldb.clone(),
+            hashring.clone(),
+            key_locks.clone(),
+            keys_per_sec,
+        )
+        .with_client(client.clone());
+        scheduler.register(
+            rebalance::TASK_NAME,
+            rebalance::INTERVAL,
+            rebalance::task(Arc::new(rebalance)),
+        );
     }
+    let volume_health = Arc::new(health::VolumeHealth::default());
+    let checker =
+        health::HealthChecker::new(hashring.clone(), client.clone(), volume_health.clone());
+    scheduler.register(
+        health::TASK_NAME,
+        health::INTERVAL,
+        health::task(Arc::new(checker)),
+    );
+
+    let writes = TaskTracker::new();
+    let key_policy = Arc::new(config.key_policy);
+
+    let app_put_state = Arc::new(AppPutState {
+        leveldb: leveldb.clone(),
+        key_locks: key_locks.clone(),
+        client: client.clone(),
+        hashring: hashring.clone(),
+        verify_checksums: config.verify_checksums,
+        hash_algorithm: config.hash_algorithm,
+        checksum_algorithms: config.checksum_algorithms,
+        write_quorum: config.write_quorum.unwrap_or(usize::MAX),
+        max_replicas: config.max_replicas.unwrap_or(config.replicas),
+        namespace_replicas: config.namespace_replicas,
+        key_policy: key_policy.clone(),
+        max_value_size: config.max_value_size,
+        write_retry: config.write_retry,
+        two_phase_writes: config.two_phase_writes,
+        erasure: config.erasure,
+        dedup: config.dedup,
+        compression: config.compression,
+        encryption: config.encryption.clone(),
+        presign: config.presign.clone(),
+        writes: writes.clone(),
+        health: volume_health.clone(),
+    });
+
+    let app_get_state = Arc::new(AppGetState {
+        leveldb: leveldb.clone(),
+        client: client.clone(),
+        hashring: hashring.clone(),
+        default_proxy: config.default_proxy,
+        redirect_status: config.redirect_status,
+        redirect_cache_control: config
+            .redirect_cache_control
+            .as_deref()
+            .map(axum::http::HeaderValue::from_str)
+            .transpose()?,
+        concurrent_heads: config.concurrent_heads,
+        health: volume_health,
+        read_repair: repair::ReadRepair::new(repair, writes.clone()),
+        encryption: config.encryption.clone(),
+        presign: config.presign.clone(),
+        value_cache: config.value_cache.map(value_cache::ValueCache::new),
+    });
+
+    let app_delete_state = Arc::new(AppDeleteState {
+        leveldb: leveldb.clone(),
+        key_locks: key_locks.clone(),
+        writes: writes.clone(),
+    });
+
+    let app_admin_state = Arc::new(admin::AppAdminState {
+        leveldb: leveldb.clone(),
+        scheduler: scheduler.clone(),
+        hashring: hashring.clone(),
+        drains: drain::Drains::new(
+            leveldb.clone(),
+            hashring.clone(),
+            key_locks.clone(),
+            client.clone(),
+        ),
+    });
+
+    let app_namespace_state = Arc::new(namespace::AppNamespaceState {
+        leveldb: leveldb.clone(),
+        delete: app_delete_state.clone(),
+    });
+
+    let app_health_state = Arc::new(health::AppHealthState {
+        leveldb: leveldb.clone(),
+        hashring: hashring.clone(),
+        client: client.clone(),
+        ready_fraction: config.ready_fraction,
+    });
+
+    let app_copy_state = Arc::new(AppCopyState {
+        put: app_put_state.clone(),
+        get: app_get_state.clone(),
+        delete: app_delete_state.clone(),
+    });
+
+    let app_batch_state = Arc::new(batch::AppBatchState {
+        put: app_put_state.clone(),
+        get: app_get_state.clone(),
+        delete: app_delete_state.clone(),
+        key_policy: key_policy.clone(),
+    });
+
+    let app_s3_state = Arc::new(s3::AppS3State {
+        leveldb: leveldb.clone(),
+        put: app_put_state.clone(),
+        get: app_get_state.clone(),
+        delete: app_delete_state.clone(),
+    });
+
+    let app_grpc_state = Arc::new(grpc::AppGrpcState {
+        leveldb: leveldb.clone(),
+        put: app_put_state.clone(),
+        get: app_get_state.clone(),
+        delete: app_delete_state.clone(),
+    });
+
+    let auth = config.auth.map(Arc::new);
+    let app_resp_state = Arc::new(resp::AppRespState {
+        leveldb: leveldb.clone(),
+        put: app_put_state.clone(),
+        get: app_get_state.clone(),
+        delete: app_delete_state.clone(),
+        auth: auth.clone(),
+        draining: draining.clone(),
+    });
+
+    let read = axum::Router::new()
+        .route(
+            "/*key",
+            axum::routing::get(handle_get_record)
+                .head(handle_head_record)
+                .with_state(app_get_state.clone()),
+        )
+        .route(
+            "/ns/:namespace/*key",
+            axum::routing::get(handle_get_record)
+                .head(handle_head_record)
+                .with_state(app_get_state.clone()),
+        )
+        // Lists the whole namespace with `?list`
+        .route(
+            "/ns/:namespace/",
+            axum::routing::get(handle_get_record).with_state(app_get_state),
+        );
+    // The probes, /metrics and /admin are served on the admin listener, if any
+    let health = health::router(app_health_state);
+    let read = match separate_admin {
+        true => read,
+        false => read.merge(health.clone()),
+    };
+    let read = match config.presign {
+        Some(presign) => read.merge(presign::router(Arc::new(presign))),
+        None => read,
+    };
+
+    let full = read
+        .clone()
+        .route(
+            "/*key",
+            axum::routing::put(handle_put_record).with_state(app_put_state.clone()),
+        )
+        .route(
+            "/*key",
+            axum::routing::post(handle_post_record).with_state(app_put_state.clone()),
+        )
+        .route(
+            "/*key",
+            axum::routing::delete(handle_delete_record).with_state(app_delete_state.clone()),
+        )
+        // COPY and MOVE aren't methods axum routes, they reach the fallback of the key routes
+        .route(
+            "/*key",
+            axum::routing::MethodRouter::new()
+                .fallback(handle_copy_record)
+                .with_state(app_copy_state.clone()),
+        )
+        .route(
+            "/ns/:namespace/*key",
+            axum::routing::put(handle_put_record)
+                .post(handle_post_record)
+                .with_state(app_put_state.clone()),
+        )
+        .route(
+            "/",
+            axum::routing::post(handle_post_generated).with_state(app_put_state.clone()),
+        )
+        .route(
+            "/ns/:namespace/",
+            axum::routing::post(handle_post_generated).with_state(app_put_state),
+        )
+        .route(
+            "/ns/:namespace/*key",
+            axum::routing::delete(handle_delete_record).with_state(app_delete_state),
+        )
+        .route(
+            "/ns/:namespace/*key",
+            axum::routing::MethodRouter::new()
+                .fallback(handle_copy_record)
+                .with_state(app_copy_state),
+        )
+        .merge(batch::router(app_batch_state));
 
-    let deleted_record = record::Record::new(
-        record::Deleted::Soft,
-        record.hash().to_string(),
-        record.read_volumes().to_vec(),
+    let admin = admin::router(app_admin_state)
+        .merge(namespace::router(app_namespace_state))
+        .merge(metrics::router());
+    #[cfg(feature = "chaos")]
+    let admin = admin.route(
+        "/admin/chaos",
+        axum::routing::get(chaos::handle_get_chaos).put(chaos::handle_put_chaos),
     );
-    match state.leveldb.put_record(&key, deleted_record).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!(
-                "delete_record: failed to put deleted record {} in leveldb: {}",
-                key, e
+    let (full, admin) = match separate_admin {
+        true => (full, health.merge(admin)),
+        false => (full.merge(admin), axum::Router::new()),
+    };
+    // Read by the `RecordKey` extractor of the key routes
+    let read = read.layer(axum::Extension(key_policy.clone()));
+    let full = full.layer(axum::Extension(key_policy));
+    let grpc = grpc::router(app_grpc_state);
+    let (read, full, admin, grpc) = match auth {
+        Some(tokens) => (
+            read.layer(axum::middleware::from_fn_with_state(
+                tokens.clone(),
+                auth::require_token,
+            )),
+            full.layer(axum::middleware::from_fn_with_state(
+                tokens.clone(),
+                auth::require_token,
+            )),
+            admin.layer(axum::middleware::from_fn_with_state(
+                tokens.clone(),
+                auth::require_token,
+            )),
+            grpc.layer(axum::middleware::from_fn_with_state(
+                tokens,
+                grpc::require_token,
+            )),
+        ),
+        None => (read, full, admin, grpc),
+    };
+
+    let s3 = s3::router(app_s3_state);
+    let (read, full, admin, s3) = match config.request_deadline {
+        Some(deadline) => (
+            read.layer(axum::middleware::from_fn_with_state(
+                deadline,
+                enforce_deadline,
+            )),
+            full.layer(axum::middleware::from_fn_with_state(
+                deadline,
+                enforce_deadline,
+            )),
+            admin.layer(axum::middleware::from_fn_with_state(
+                deadline,
+                enforce_deadline,
+            )),
+            s3.layer(axum::middleware::from_fn_with_state(
+                deadline,
+                enforce_deadline,
+            )),
+        ),
+        None => (read, full, admin, s3),
+    };
+
+    // Writes arriving once shutdown started are turned away, the ones in flight are waited for
+    let full = full.layer(axum::middleware::from_fn_with_state(
+        draining.clone(),
+        reject_writes_while_draining,
+    ));
+    let s3 = s3.layer(axum::middleware::from_fn_with_state(
+        draining.clone(),
+        reject_writes_while_draining,
+    ));
+    let grpc = grpc.layer(axum::middleware::from_fn_with_state(
+        draining.clone(),
+        grpc::reject_writes_while_draining,
+    ));
+
+    // Requests turned away are still counted, logged and traced. The admin listener isn't limited,
+    // so an operator can still reach a saturated server
+    let (read, full, s3, grpc) = match config.limits.is_enabled() {
+        true => {
+            let limiter = limits::Limiter::new(config.limits);
+            (
+                read.layer(axum::middleware::from_fn_with_state(
+                    limiter.clone(),
+                    limits::limit_requests,
+                )),
+                full.layer(axum::middleware::from_fn_with_state(
+                    limiter.clone(),
+                    limits::limit_requests,
+                )),
+                s3.layer(axum::middleware::from_fn_with_state(
+                    limiter.clone(),
+                    limits::limit_requests,
+                )),
+                grpc.layer(axum::middleware::from_fn_with_state(
+                    limiter,
+                    limits::limit_requests,
+                )),
+            )
+        }
+        false => (read, full, s3, grpc),
+    };
+
+    // Preflights are answered before the tokens are checked and the limits applied,
+    // and the answers of both carry the CORS headers for the scripts to read them
+    let (read, full, s3) = match config.cors {
+        Some(cors) => {
+            let cors = Arc::new(cors);
+            (
+                read.layer(axum::middleware::from_fn_with_state(
+                    cors.clone(),
+                    cors::handle_cors,
+                )),
+                full.layer(axum::middleware::from_fn_with_state(
+                    cors.clone(),
+                    cors::handle_cors,
+                )),
+                s3.layer(axum::middleware::from_fn_with_state(
+                    cors,
+                    cors::handle_cors,
+                )),
+            )
+        }
+        None => (read, full, s3),
+    };
+
+    let read = read.layer(axum::middleware::from_fn(metrics::track_requests));
+    let full = full.layer(axum::middleware::from_fn(metrics::track_requests));
+    let admin = admin.layer(axum::middleware::from_fn(metrics::track_requests));
+    let s3 = s3.layer(axum::middleware::from_fn(metrics::track_requests));
+    let grpc = grpc.layer(axum::middleware::from_fn(metrics::track_requests));
+
+    let (read, full, admin, s3, grpc) = match config.access_log {
+        true => (
+            read.layer(axum::middleware::from_fn(access::log_requests)),
+            full.layer(axum::middleware::from_fn(access::log_requests)),
+            admin.layer(axum::middleware::from_fn(access::log_requests)),
+            s3.layer(axum::middleware::from_fn(access::log_requests)),
+            grpc.layer(axum::middleware::from_fn(access::log_requests)),
+        ),
+        false => (read, full, admin, s3, grpc),
+    };
+
+    let read = read.layer(axum::middleware::from_fn(telemetry::trace_requests));
+    let full = full.layer(axum::middleware::from_fn(telemetry::trace_requests));
+    let admin = admin.layer(axum::middleware::from_fn(telemetry::trace_requests));
+    let s3 = s3.layer(axum::middleware::from_fn(telemetry::trace_requests));
+    let grpc = grpc.layer(axum::middleware::from_fn(telemetry::trace_requests));
+
+    Ok(App {
+        read,
+        full,
+        admin,
+        s3,
+        resp: app_resp_state,
+        grpc,
+        scheduler,
+        writes,
+        reloader,
+        draining,
+        leveldb,
+    })
+}
+
+/// Middleware answering 503 with a `Retry-After` to the writes arriving once shutdown started,
+/// e.g. on a connection kept open by a request in flight, so no write starts that shutdown won't wait for.
+/// GET and HEAD are still served.
+async fn reject_writes_while_draining(
+    axum::extract::State(draining): axum::extract::State<Arc<std::sync::atomic::AtomicBool>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let method = request.method();
+    if draining.load(std::sync::atomic::Ordering::SeqCst)
+        && method != axum::http::Method::GET
+        && method != axum::http::Method::HEAD
+    {
+        debug!(
+            "shutdown: {} {} rejected while draining",
+            method,
+            request.uri().path()
+        );
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, "1")],
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Middleware answering 504 to the requests that don't produce a response before the deadline.
+/// Writes already spawned finish in the background, streamed response bodies aren't cut.
+async fn enforce_deadline(
+    axum::extract::State(deadline): axum::extract::State<Duration>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    match tokio::time::timeout(deadline, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(
+                "deadline: {} {} exceeded the deadline of {:?}",
+                method, uri, deadline
             );
-            state.lock_keys.write().remove(&key);
-            return axum::http::Response::builder()
-                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(axum::body::Body::empty())
-                .unwrap();
+            StatusCode::GATEWAY_TIMEOUT.into_response()
         }
     }
+}
 
-    state.lock_keys.write().remove(&key);
-    axum::http::Response::builder()
-        .status(axum::http::StatusCode::NO_CONTENT)
-        .body(axum::body::Body::empty())
-        .unwrap()
+/// Handles the shutdown signal.
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    // Ctrl+Break, closing the console window and system shutdown stop the server like SIGTERM
+    #[cfg(windows)]
+    let terminate = async {
+        let mut ctrl_break =
+            signal::windows::ctrl_break().expect("failed to install Ctrl+Break handler");
+        let mut ctrl_close =
+            signal::windows::ctrl_close().expect("failed to install Ctrl+Close handler");
+        let mut ctrl_shutdown =
+            signal::windows::ctrl_shutdown().expect("failed to install shutdown handler");
+        tokio::select! {
+            _ = ctrl_break.recv() => {},
+            _ = ctrl_close.recv() => {},
+            _ = ctrl_shutdown.recv() => {},
+        }
+    };
+
+    #[cfg(not(any(unix, windows)))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Handles PUT requests to store a record.
+/// A `Content-Md5` header (hex or base64) is verified against the body before any replica is written.
+/// A chunked upload can announce a `Trailer: Content-Md5` and send the digest after the body,
+/// in which case the Content-Length header is not required.
+/// A `Key-Volume-Group` header pins the key to a named volume group, overriding the placement rules.
+/// An `X-Checksum-Algorithm` header selects an extra digest to store, and `X-Checksum-<Algorithm>`
+/// headers or trailers are verified against the body.
+/// `Content-Disposition` and `Content-Type` headers are stored and returned on GET and HEAD.
+/// An `X-Ttl` header expires the key after a number of seconds, see `expiry`.
+/// An `X-Replicas` header stores the key on that many replicas instead of the replicas of the ring,
+/// up to the max replicas, rebalance and repair keep the count.
+/// With erasure coding, values of at least its minimum size are stored as shards instead of replicas,
+/// see `put_shards_and_record`.
+/// An `If-Match` header replaces the value only if its ETag is listed, `If-None-Match: *` creates the key
+/// only if absent, see `Precondition`.
+/// `?partNumber=N` (1 to 10000) uploads a part of a multipart upload instead, see `handle_post_record`.
+/// Returns 201 if the record or part is created
+/// Returns 400 if the Content-Md5 or an announced checksum trailer is missing or malformed, the checksum algorithm
+/// is unsupported, the Content-Disposition or Content-Type is not visible ASCII, the X-Ttl is not a positive
+/// number of seconds, the X-Replicas is not between 1 and the max replicas, the If-Match or If-None-Match
+/// is not visible ASCII, the volume group is unknown,
+/// the part number is out of range, the key starts with a NUL byte, reserved for the index,
+/// or with `cas:`, written by `POST /cas` only, or the namespace is invalid, see `RecordKey`
+/// Returns 409 if the record key stays locked by another PUT/DELETE past the lock timeout,
+/// or exists without an If-Match or If-None-Match precondition or when uploading a part
+/// Returns 412 if the If-Match or If-None-Match precondition fails
+/// Returns 411 if the Content-Length is missing or the body is empty
+/// Returns 413 if the Content-Length or the body exceeds the max value size
+/// Returns 422 if the Content-Md5 or a checksum header or trailer does not match the body
+/// Returns 507 if the value would exceed the quota of the namespace of the key
+/// Returns 500 for internal server error
+pub(crate) async fn handle_put_record(
+    RecordKey(key): RecordKey,
+    axum::extract::State(state): axum::extract::State<Arc<AppPutState>>,
+    axum::extract::Query(params): axum::extract::Query<PutParams>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> impl axum::response::IntoResponse {
+    debug!("put_record: key: {}", key);
+
+    if key.starts_with(record::RESERVED_PREFIX) {
+        debug!("put_record: key: {:?} uses the reserved prefix", key);
+        return StatusCode::BAD_REQUEST;
+    }
+    if key.starts_with(CAS_PREFIX) {
+        debug!("put_record: key: {} is content addressed", key);
+        return StatusCode::BAD_REQUEST;
+    }
+    // Checked by `RecordKey` already, again for the gateways calling the handler with their own keys
+    if let Err(e) = state.key_policy.check(&key) {
+        debug!("put_record: key: {:?} rejected: {}", key, e);
+        return StatusCode::BAD_REQUEST;
+    }
+    if params
+        .part_number
+        .is_some_and(|part_number| !(1..=MAX_PART_NUMBER).contains(&part_number))
+    {
+        debug!("put_record: key: {} invalid part number", key);
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let placement = match headers.get(KEY_VOLUME_GROUP) {
+        Some(value) => match value.to_str() {
+            Ok(group) if state.hashring.read().has_group(group) => Some(group.to_string()),
+            _ => {
+                debug!("put_record: key: {} unknown volume group: {:?}", key, value);
+                return StatusCode::BAD_REQUEST;
+            }
+        },
+        None => state
+            .hashring
+            .read()
+            .placement_group(&key)
+            .map(String::from),
+    };
+
+    let requested_algorithm = match checksum::requested_algorithm(&headers) {
+        Ok(algorithm) => algorithm,
+        Err(e) => {
+            debug!("put_record: key: {} {}", key, e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    let Ok(content_disposition) = stored_header(&headers, axum::http::header::CONTENT_DISPOSITION)
+    else {
+        debug!("put_record: key: {} invalid Content-Disposition", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(content_type) = stored_header(&headers, axum::http::header::CONTENT_TYPE) else {
+        debug!("put_record: key: {} invalid Content-Type", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(expires_at) = ttl_expires_at(&headers) else {
+        debug!("put_record: key: {} invalid X-Ttl", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(replicas) = requested_replicas(&headers, state.max_replicas) else {
+        debug!("put_record: key: {} invalid X-Replicas", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let replicas = replicas.or_else(|| state.namespace_replicas(&key));
+    let Ok(precondition) = Precondition::from_headers(&headers) else {
+        debug!("put_record: key: {} invalid If-Match or If-None-Match", key);
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let trailer_checksum = checksum::announces_trailer(&headers, checksum::CONTENT_MD5);
+    if headers.get(axum::http::header::CONTENT_LENGTH).is_none()
+        && !headers.contains_key(axum::http::header::TRAILER)
+    {
+        return StatusCode::LENGTH_REQUIRED;
+    }
+
+    // Checksums are computed while the body is spooled, so checksum trailers must be announced
+    let supplied_algorithms = checksum::Algorithm::ALL
+        .into_iter()
+        .filter(|algorithm| {
+            headers.contains_key(algorithm.header_name())
+                || checksum::announces_trailer(&headers, algorithm.header_name())
+        })
+        .collect::<Vec<_>>();
+
+    let mut algorithms = state.checksum_algorithms.clone();
+    algorithms.extend(requested_algorithm);
+    algorithms.extend(supplied_algorithms.iter().copied());
+    algorithms.sort_unstable();
+    algorithms.dedup();
+
+    // A Content-MD5 header or trailer is verified against the body before any replica is written
+    let content_md5 = trailer_checksum || headers.contains_key(checksum::CONTENT_MD5);
+
+    // Conditional PUTs get a hash too, so the stored value has an ETag for the next condition
+    let hash_value = state.verify_checksums
+        || content_md5
+        || params.part_number.is_some()
+        || precondition.is_some();
+    // Parts always get an MD5, the MD5 of a multipart value is computed from the MD5 of its parts
+    let hash_algorithm = match params.part_number {
+        Some(_) => checksum::Algorithm::Md5,
+        None => state.hash_algorithm,
+    };
+    let mut hashed_algorithms = algorithms.clone();
+    if content_md5 && !hashed_algorithms.contains(&checksum::Algorithm::Md5) {
+        hashed_algorithms.push(checksum::Algorithm::Md5);
+    }
+    if hash_value && !hashed_algorithms.contains(&hash_algorithm) {
+        hashed_algorithms.push(hash_algorithm);
+    }
+    // Deduplicated values are found by their SHA-256
+    if state.dedup && !hashed_algorithms.contains(&checksum::Algorithm::Sha256) {
+        hashed_algorithms.push(checksum::Algorithm::Sha256);
+    }
+
+    if exceeds_max_value_size(&headers, state.max_value_size) {
+        debug!(
+            "put_record: key: {} Content-Length exceeds the max value size",
+            key
+        );
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+    let value =
+        match spool::SpooledValue::spool(body, &hashed_algorithms, state.max_value_size).await {
+            Ok(value) => value,
+            Err(e) if e.downcast_ref::<spool::TooLarge>().is_some() => {
+                debug!("put_record: key: {} {}", key, e);
+                return StatusCode::PAYLOAD_TOO_LARGE;
+            }
+            Err(e) if e.downcast_ref::<axum::Error>().is_some() => {
+                error!("put_record: failed to read body for key {}: {}", key, e);
+                return StatusCode::BAD_REQUEST;
+            }
+            Err(e) => {
+                error!("put_record: failed to spool body for key {}: {}", key, e);
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        };
+
+    metrics::METRICS
+        .proxied_bytes
+        .with_label_values(&["in"])
+        .inc_by(value.size());
+
+    if value.size() == 0 {
+        return StatusCode::LENGTH_REQUIRED;
+    }
+
+    let expected_md5_hash = if content_md5 {
+        let md5_hash = header_or_trailer(&headers, value.trailers(), checksum::CONTENT_MD5)
+            .and_then(checksum::parse_md5);
+        match md5_hash {
+            Some(md5_hash) => Some(md5_hash),
+            None => {
+                debug!("put_record: key: {} missing or invalid Content-Md5", key);
+                return StatusCode::BAD_REQUEST;
+            }
+        }
+    } else {
+        None
+    };
+
+    let value_hash = if hash_value {
+        value
+            .digest(hash_algorithm)
+            .map(checksum::encode_hex)
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    if let Some(expected_md5_hash) = expected_md5_hash {
+        let value_md5_hash = value
+            .digest(checksum::Algorithm::Md5)
+            .map(checksum::encode_hex)
+            .unwrap_or_default();
+        if expected_md5_hash != value_md5_hash {
+            debug!(
+                "put_record: key: {} checksum mismatch, expected: {} computed: {}",
+                key, expected_md5_hash, value_md5_hash
+            );
+            return StatusCode::UNPROCESSABLE_ENTITY;
+        }
+    }
+
+    let checksums = algorithms
+        .iter()
+        .filter_map(|algorithm| {
+            value
+                .digest(*algorithm)
+                .map(|digest| (*algorithm, checksum::encode_base64(digest)))
+        })
+        .collect::<Vec<_>>();
+
+    for algorithm in supplied_algorithms {
+        let Some(expected) = header_or_trailer(&headers, value.trailers(), algorithm.header_name())
+        else {
+            debug!(
+                "put_record: key: {} missing {:?} checksum trailer",
+                key, algorithm
+            );
+            return StatusCode::BAD_REQUEST;
+        };
+        let matches = checksums.iter().any(|(computed, digest)| {
+            *computed == algorithm && algorithm.matches(expected, digest)
+        });
+        if !matches {
+            debug!(
+                "put_record: key: {} {:?} checksum mismatch, expected: {}",
+                key, algorithm, expected
+            );
+            return StatusCode::UNPROCESSABLE_ENTITY;
+        }
+    }
+
+    // Values are compressed once verified. Parts are stitched by the volumes as uploaded,
+    // and erasure coded or deduplicated values are stored as uploaded too
+    let compression = state.compression.filter(|compression| {
+        params.part_number.is_none()
+            && !state.dedup
+            && compression.applies_to(&headers)
+            && !state
+                .erasure
+                .is_some_and(|erasure| erasure.applies_to(value.size()))
+    });
+    let size = value.size();
+    let (value, encoding) = match compression {
+        Some(compression) => match value.compress(&compression).await {
+            Ok(compressed) if compressed.size() < size => {
+                let encoding = record::Encoding {
+                    name: compress::ZSTD.to_string(),
+                    size: compressed.size(),
+                };
+                (compressed, Some(encoding))
+            }
+            Ok(_) => (value, None),
+            Err(e) => {
+                error!("put_record: failed to compress value of key {}: {}", key, e);
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        },
+        None => (value, None),
+    };
+    // Encrypted after the compression, ciphertext doesn't compress
+    let (value, encryption) = match &state.encryption {
+        Some(master_key) if params.part_number.is_none() => {
+            match encrypt_value(master_key, &value).await {
+                Ok((encrypted, encryption)) => (encrypted, Some(encryption)),
+                Err(e) => {
+                    error!("put_record: failed to encrypt value of key {}: {}", key, e);
+                    return StatusCode::INTERNAL_SERVER_ERROR;
+                }
+            }
+        }
+        _ => (value, None),
+    };
+
+    let value = Arc::new(value);
+    if let Some(part_number) = params.part_number {
+        let record = record::Record::new(record::Deleted::Init, value_hash, Vec::new())
+            .with_content_type(content_type)
+            .with_placement(placement)
+            .with_replicas(replicas)
+            .with_size(value.size());
+        let write = put_part(state.clone(), key.clone(), part_number, value, record);
+        return match state.writes.spawn(write).await {
+            Ok(status) => status,
+            Err(e) => {
+                error!("put_record: write of part for key {} failed: {}", key, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+    }
+
+    // The replica uploads and the metadata write run detached from the connection and are
+    // tracked, so they finish if the client goes away and shutdown waits for them
+    let record = record::Record::new(record::Deleted::No, value_hash, Vec::new())
+        .with_hash_algorithm(hash_algorithm)
+        .with_checksums(checksums)
+        .with_content_disposition(content_disposition)
+        .with_content_type(content_type)
+        .with_expires_at(expires_at)
+        .with_placement(placement)
+        .with_replicas(replicas)
+        .with_size(size)
+        .with_encoding(encoding)
+        .with_encryption(encryption);
+    let write = put_replicas_and_record(state.clone(), key.clone(), value, record, precondition);
+    match state.writes.spawn(write).await {
+        Ok(status) => status,
+        Err(e) => {
+            error!("put_record: write for key {} failed: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Reserves the size of a value written to a key in the quota of its namespace, see `namespace::reserve`.
+/// The key must stay locked until the reservation is dropped.
+/// Returns the status of the write if the quota would be exceeded or can't be checked.
+async fn reserve_quota(
+    leveldb: &record::LevelDb,
+    key: &str,
+    size: u64,
+) -> Result<namespace::Reservation, StatusCode> {
+    match namespace::reserve(leveldb, key, size).await {
+        Ok(Some(reservation)) => Ok(reservation),
+        Ok(None) => {
+            debug!("quota: key: {} exceeds the quota of its namespace", key);
+            Err(StatusCode::INSUFFICIENT_STORAGE)
+        }
+        Err(e) => {
+            error!("quota: failed to check the quota of {}: {}", key, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Encrypts a spooled value with a new data key, returning it with the encryption of its record.
+async fn encrypt_value(
+    master_key: &encryption::MasterKey,
+    value: &spool::SpooledValue,
+) -> anyhow::Result<(spool::SpooledValue, record::Encryption)> {
+    let (data_key, encryption) = master_key.new_data_key()?;
+    Ok((value.encrypt(&data_key).await?, encryption))
+}
+
+/// Locks the key, puts the value in its replicas and stores the record in leveldb
+/// with the replicas that hold the value as its volumes.
+/// Returns the status of the PUT request.
+async fn put_replicas_and_record(
+    state: Arc<AppPutState>,
+    key: String,
+    value: Arc<spool::SpooledValue>,
+    new_record: record::Record,
+    precondition: Option<Precondition>,
+) -> StatusCode {
+    let Some(_guard) = state.key_locks.lock(&key).await else {
+        debug!("put_record: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return StatusCode::CONFLICT;
+    };
+
+    let record = match state.leveldb.get_record_or_default(&key).await {
+        Ok(record) => record,
+        Err(e) => {
+            error!(
+                "put_record: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+
+    // An expired value is replaced like a deleted one, the expiry task skips the new record
+    let current = record.is_live(record::unix_now()).then_some(&record);
+    match &precondition {
+        Some(precondition) if !precondition.allows(current) => {
+            debug!(
+                "put_record: key: {} precondition {:?} failed",
+                key, precondition
+            );
+            return StatusCode::PRECONDITION_FAILED;
+        }
+        Some(_) => (),
+        None if current.is_some() => return StatusCode::CONFLICT,
+        None => (),
+    }
+
+    // An overwrite keeps the creation time of the key, a deleted or expired key is created again
+    let now = record::unix_now();
+    let created_at = current
+        .and_then(|record| record.created_at())
+        .unwrap_or(now);
+    let new_record = new_record.with_timestamps(created_at, now);
+    let _reservation = match reserve_quota(&state.leveldb, &key, new_record.size()).await {
+        Ok(reservation) => reservation,
+        Err(status) => return status,
+    };
+
+    if let Some(erasure) = state
+        .erasure
+        .filter(|erasure| erasure.applies_to(value.size()))
+    {
+        return put_shards_and_record(&state, &key, &value, new_record, current, erasure).await;
+    }
+    if let Some(digest) = state
+        .dedup
+        .then(|| value.digest(checksum::Algorithm::Sha256))
+        .flatten()
+    {
+        let hash = checksum::encode_hex(digest);
+        return put_blob_and_record(&state, &key, &value, new_record, current, hash).await;
+    }
+
+    let replicas_volumes = state.hashring.read().get_volume_with_replicas(
+        &key,
+        new_record.placement(),
+        new_record.replicas(),
+    );
+
+    // Down volumes are skipped instead of waiting for them to time out, and repaired later
+    let (up, down): (Vec<String>, Vec<String>) = replicas_volumes
+        .iter()
+        .cloned()
+        .partition(|volume| state.health.is_up(volume));
+    if up.len() < state.write_quorum.min(replicas_volumes.len()) {
+        warn!(
+            "put_record: key: {} has {} of {} replicas up, down: {:?}",
+            key,
+            up.len(),
+            replicas_volumes.len(),
+            down
+        );
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    // An overwrite is staged whatever the two-phase writes, the live replicas stay untouched unless it commits
+    let quorum = state.write_quorum.min(replicas_volumes.len());
+    let (stored, mut failed, committed) =
+        put_replicas(&state, &key, &up, &value, quorum, current.is_some()).await;
+    failed.extend(down);
+    if !committed {
+        error!(
+            "put_record: key: {} stored in {} of {} replicas, failed: {:?}",
+            key,
+            stored.len(),
+            replicas_volumes.len(),
+            failed
+        );
+
+        // A failed overwrite keeps the live record, its uploads were staged and none was moved into place
+        if current.is_some() {
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+
+        // In case of error we want to mark the record as Deleted::Soft in the local leveldb
+        let record = record::Record::new(record::Deleted::Soft, String::new(), replicas_volumes)
+            .with_placement(new_record.placement().map(String::from))
+            .with_replicas(new_record.replicas())
+            .with_deleted_at(Some(record::unix_now()));
+        if let Err(e) = state.leveldb.put_record(&key, record).await {
+            error!("put_record: failed to put record {} in leveldb: {}", key, e);
+        }
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    let record = new_record.with_read_volumes(stored);
+    if !failed.is_empty() {
+        if let Err(e) = replication::enqueue(&state.leveldb, &key, &record, &failed).await {
+            error!(
+                "put_record: failed to queue the replication of {}: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    match state.leveldb.put_record(&key, record.clone()).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!(
+                "put_record: failed to put record with value_hash {} in leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    if let Some(replaced) = current {
+        delete_replaced(&state, &key, replaced, &record).await;
+    }
+
+    if !failed.is_empty() {
+        schedule_repair(state, key, record, value, failed);
+    }
+    StatusCode::CREATED
+}
+
+/// Deletes the blobs of a replaced value the new value is not written to, e.g. replicas on other volumes
+/// or the shards of a value now replicated. Failures are logged and the blobs left behind.
+/// A deduplicated value only loses the reference of the key, unless it was the last one.
+async fn delete_replaced(
+    state: &AppPutState,
+    key: &str,
+    replaced: &record::Record,
+    record: &record::Record,
+) {
+    let mut remote_urls = replaced.remote_urls(key);
+    let _blob_guard = match replaced.blob() {
+        Some(hash) => {
+            let Some(guard) = state.key_locks.lock(&record::blob_key(hash)).await else {
+                warn!(
+                    "put_record: blob {} of the replaced value of {} still locked, leaving it",
+                    hash, key
+                );
+                return;
+            };
+            match dedup::is_shared(&state.leveldb, hash).await {
+                Ok(true) => remote_urls.clear(),
+                Ok(false) => (),
+                Err(e) => {
+                    warn!("put_record: failed to get blob {} of {}: {}", hash, key, e);
+                    return;
+                }
+            }
+            Some(guard)
+        }
+        None => None,
+    };
+
+    let written = record.remote_urls(key);
+    for remote_url in remote_urls {
+        if written.contains(&remote_url) {
+            continue;
+        }
+        if let Err(e) = remote_delete(&state.client, &remote_url).await {
+            warn!(
+                "put_record: failed to delete replaced value of {}: {}",
+                key, e
+            );
+        }
+    }
+    if let Some(hash) = replaced.blob() {
+        if let Err(e) = dedup::release(&state.leveldb, hash).await {
+            warn!(
+                "put_record: failed to release blob {} of {}: {}",
+                hash, key, e
+            );
+        }
+    }
+}
+
+/// Stores the record of a key referencing the deduplicated blob of its value. The first key of a content
+/// writes the blob to every replica, the next ones only count one more reference to it.
+/// Returns the status of the PUT request.
+async fn put_blob_and_record(
+    state: &AppPutState,
+    key: &str,
+    value: &Arc<spool::SpooledValue>,
+    new_record: record::Record,
+    current: Option<&record::Record>,
+    hash: String,
+) -> StatusCode {
+    let blob_key = record::blob_key(&hash);
+    let volumes = {
+        let Some(_guard) = state.key_locks.lock(&blob_key).await else {
+            debug!(
+                "put_record: blob {} of key {} still locked, giving up",
+                hash, key
+            );
+            metrics::METRICS.lock_conflicts.inc();
+            return StatusCode::CONFLICT;
+        };
+        match dedup::acquire(&state.leveldb, &hash).await {
+            Ok(Some(volumes)) => {
+                debug!("put_record: key: {} shares blob {}", key, hash);
+                metrics::METRICS.dedup_hits.inc();
+                volumes
+            }
+            Ok(None) => match put_blob(state, key, &blob_key, value, &new_record).await {
+                Ok(volumes) => match dedup::register(&state.leveldb, &hash, &volumes).await {
+                    Ok(_) => volumes,
+                    Err(e) => {
+                        error!(
+                            "put_record: failed to register blob {} of {}: {}",
+                            hash, key, e
+                        );
+                        return StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                },
+                Err(status) => return status,
+            },
+            Err(e) => {
+                error!("put_record: failed to get blob {} of {}: {}", hash, key, e);
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        }
+    };
+
+    let record = new_record
+        .with_blob(Some(hash.clone()))
+        .with_read_volumes(volumes);
+    if let Err(e) = state.leveldb.put_record(key, record.clone()).await {
+        error!("put_record: failed to put record {} in leveldb: {}", key, e);
+        // The reference is dropped again, the blob stays in case the record was written after all
+        if let Some(_guard) = state.key_locks.lock(&blob_key).await {
+            if let Err(e) = dedup::release(&state.leveldb, &hash).await {
+                error!(
+                    "put_record: failed to release blob {} of {}: {}",
+                    hash, key, e
+                );
+            }
+        }
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    if let Some(replaced) = current {
+        delete_replaced(state, key, replaced, &record).await;
+    }
+    StatusCode::CREATED
+}
+
+/// Writes a new deduplicated blob to every replica of the key first storing it. The keys sharing
+/// the blob are not repaired one by one, so a write missing a replica fails and removes the others.
+/// Returns the volumes holding the blob, or the status of the failed PUT request.
+async fn put_blob(
+    state: &AppPutState,
+    key: &str,
+    blob_key: &str,
+    value: &Arc<spool::SpooledValue>,
+    new_record: &record::Record,
+) -> Result<Vec<String>, StatusCode> {
+    let volumes = state.hashring.read().get_volume_with_replicas(
+        key,
+        new_record.placement(),
+        new_record.replicas(),
+    );
+    if let Some(down) = volumes.iter().find(|volume| !state.health.is_up(volume)) {
+        warn!("put_record: key: {} blob replica {} is down", key, down);
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let (stored, failed, _) =
+        put_replicas(state, blob_key, &volumes, value, volumes.len(), false).await;
+    if failed.is_empty() {
+        return Ok(stored);
+    }
+    error!(
+        "put_record: key: {} blob stored in {} of {} replicas, failed: {:?}",
+        key,
+        stored.len(),
+        volumes.len(),
+        failed
+    );
+    let remote_path = record::get_remote_path(blob_key);
+    for volume in stored {
+        let remote_url = record::volume_url(&volume, &remote_path);
+        if let Err(e) = remote_delete(&state.client, &remote_url).await {
+            warn!(
+                "put_record: failed to delete blob of {} from {}: {}",
+                key, volume, e
+            );
+        }
+    }
+    Err(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Splits a value into the data and parity shards of the erasure coding, puts every shard on its own
+/// volume and stores the record listing the shards. Every shard must be written, the value can then
+/// lose any parity shards count of them. A failed PUT deletes the shards it wrote and keeps the record of the key,
+/// an overwrite is staged like with two-phase writes so the shards of the live value stay untouched.
+/// Returns the status of the PUT request.
+async fn put_shards_and_record(
+    state: &AppPutState,
+    key: &str,
+    value: &spool::SpooledValue,
+    new_record: record::Record,
+    current: Option<&record::Record>,
+    erasure: erasure::ErasureConfig,
+) -> StatusCode {
+    // The ring places a shard on each of its replicas
+    let volumes = state.hashring.read().get_volume_with_replicas(
+        key,
+        new_record.placement(),
+        Some(erasure.shards()),
+    );
+    if volumes.len() < erasure.shards() {
+        error!(
+            "put_record: key: {} has {} volumes for {} shards",
+            key,
+            volumes.len(),
+            erasure.shards()
+        );
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    let down: Vec<&String> = volumes
+        .iter()
+        .filter(|volume| !state.health.is_up(volume))
+        .collect();
+    if !down.is_empty() {
+        warn!(
+            "put_record: key: {} has shard volumes down: {:?}",
+            key, down
+        );
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    let (shards, hashes) = match value.encode(erasure).await {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            error!("put_record: failed to erasure code key {}: {}", key, e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    let erasure = Some(record::Erasure {
+        data_shards: erasure.data_shards as u32,
+        shards: erasure::shards_of(hashes, &volumes),
+    });
+
+    // Staged, the shards are uploaded next to their path and moved into place once all of them are
+    let staged = state.two_phase_writes || current.is_some();
+    let remote_urls: Vec<String> = volumes
+        .iter()
+        .enumerate()
+        .map(|(index, volume)| {
+            let remote_path = record::get_remote_path(&record::shard_key(key, index));
+            record::volume_url(volume, &remote_path)
+        })
+        .collect();
+    let upload_urls: Vec<String> = match staged {
+        true => remote_urls
+            .iter()
+            .map(|remote_url| format!("{}{}", remote_url, TMP_SUFFIX))
+            .collect(),
+        false => remote_urls.clone(),
+    };
+    let mut futures = FuturesUnordered::new();
+    for (index, (shard, upload_url)) in shards.iter().zip(&upload_urls).enumerate() {
+        debug!("put_record: key: {} shard remote_url: {}", key, upload_url);
+        let put = put_with_retry(
+            state.client.clone(),
+            upload_url.clone(),
+            || shard.body(),
+            shard.size(),
+            state.write_retry,
+        );
+        futures.push(put.map(move |result| (index, result)));
+    }
+    let mut written: Vec<Option<&String>> = vec![None; shards.len()];
+    while let Some((index, result)) = futures.next().await {
+        match result {
+            Ok(()) => written[index] = Some(&upload_urls[index]),
+            Err(e) => error!(
+                "put_record: failed to put shard {} of key {}: {}",
+                index, key, e
+            ),
+        }
+    }
+    drop(futures);
+
+    let mut failed = written.iter().filter(|url| url.is_none()).count();
+    if failed == 0 && staged {
+        let mut futures: FuturesUnordered<_> = upload_urls
+            .iter()
+            .zip(&remote_urls)
+            .enumerate()
+            .map(|(index, (upload_url, remote_url))| async move {
+                (
+                    index,
+                    remote_move(&state.client, upload_url, remote_url).await,
+                )
+            })
+            .collect();
+        while let Some((index, result)) = futures.next().await {
+            match result {
+                Ok(()) => written[index] = Some(&remote_urls[index]),
+                Err(e) => {
+                    error!(
+                        "put_record: failed to move shard {} of key {} into place: {}",
+                        index, key, e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        error!("put_record: key: {} failed to store {} shards", key, failed);
+        // The record of the key is kept as it is, only the shards of this PUT are removed
+        for remote_url in written.into_iter().flatten() {
+            if let Err(e) = remote_delete(&state.client, remote_url).await {
+                warn!(
+                    "put_record: failed to delete shard {} of the failed PUT of {}: {}",
+                    remote_url, key, e
+                );
+            }
+        }
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    let record = new_record.with_erasure(erasure);
+    if let Err(e) = state.leveldb.put_record(key, record.clone()).await {
+        error!("put_record: failed to put record {} in leveldb: {}", key, e);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    if let Some(replaced) = current {
+        delete_replaced(state, key, replaced, &record).await;
+    }
+    StatusCode::CREATED
+}
+
+/// Puts a value in the given replica volumes concurrently.
+/// Staged, or with two-phase writes, the value is uploaded to a temporary path on every volume first, and moved
+/// into place only once at least quorum volumes hold the full body, otherwise the uploads are deleted.
+/// Returns the volumes that stored the value and the volumes that failed, both in replica order,
+/// and whether the write committed: quorum volumes stored the value, or a staged value was moved into place
+/// on some of them, which replaced the value they held. The value must then be recorded and the failed volumes
+/// repaired, a MOVE can't be undone.
+#[tracing::instrument(name = "put_replicas", skip(state, value))]
+async fn put_replicas(
+    state: &AppPutState,
+    key: &str,
+    volumes: &[String],
+    value: &Arc<spool::SpooledValue>,
+    quorum: usize,
+    stage: bool,
+) -> (Vec<String>, Vec<String>, bool) {
+    let staged = stage || state.two_phase_writes;
+    let remote_path = record::get_remote_path(key);
+    let upload_path = if staged {
+        format!("{}{}", remote_path, TMP_SUFFIX)
+    } else {
+        remote_path.clone()
+    };
+    let mut futures = FuturesUnordered::new();
+    for (i, volume) in volumes.iter().enumerate() {
+        let remote_url = record::volume_url(volume, &upload_path);
+        debug!("put_replicas key: {} remote_url: {}", key, remote_url);
+        let client = state.client.clone();
+        let value = value.clone();
+        let policy = state.write_retry;
+        let put = tokio::spawn(async move {
+            let size = value.size();
+            let body = || value.body();
+            put_with_retry(client, remote_url, body, size, policy).await
+        });
+        futures.push(put.map(move |result| (i, result)));
+    }
+
+    let mut succeeded = vec![false; volumes.len()];
+    while let Some((i, result)) = futures.next().await {
+        match result.map_err(anyhow::Error::from).and_then(|put| put) {
+            Ok(()) => succeeded[i] = true,
+            Err(e) => error!(
+                "put_replicas: failed to put key {} in remote replica {}: {}",
+                key, volumes[i], e
+            ),
+        }
+    }
+
+    if staged {
+        let uploaded = succeeded.iter().filter(|succeeded| **succeeded).count();
+        let commit = uploaded >= quorum;
+        let mut futures = FuturesUnordered::new();
+        for (i, volume) in volumes.iter().enumerate() {
+            if !succeeded[i] {
+                continue;
+            }
+            let client = state.client.clone();
+            let tmp_url = record::volume_url(volume, &upload_path);
+            let remote_url = record::volume_url(volume, &remote_path);
+            futures.push(async move {
+                let result = match commit {
+                    true => remote_move(&client, &tmp_url, &remote_url).await,
+                    false => remote_delete(&client, &tmp_url).await,
+                };
+                (i, result)
+            });
+        }
+        let mut unmoved = Vec::new();
+        while let Some((i, result)) = futures.next().await {
+            if let Err(e) = result {
+                error!(
+                    "put_replicas: failed to move key {} into place in remote replica {}: {}",
+                    key, volumes[i], e
+                );
+                succeeded[i] = false;
+                if commit {
+                    unmoved.push(i);
+                }
+            }
+        }
+        drop(futures);
+        if !commit {
+            warn!(
+                "put_replicas: key: {} uploaded to {} of {} replicas, discarded",
+                key, uploaded, quorum
+            );
+            succeeded.fill(false);
+        }
+
+        // A failed MOVE leaves its upload behind, the replica is repaired with a new PUT
+        for i in unmoved {
+            let tmp_url = record::volume_url(&volumes[i], &upload_path);
+            if let Err(e) = remote_delete(&state.client, &tmp_url).await {
+                warn!(
+                    "put_replicas: failed to delete the upload of key {} from {}: {}",
+                    key, volumes[i], e
+                );
+            }
+        }
+    }
+
+    let (stored, failed): (Vec<_>, Vec<_>) = volumes
+        .iter()
+        .cloned()
+        .zip(succeeded)
+        .partition(|(_, succeeded)| *succeeded);
+    let stored: Vec<String> = stored.into_iter().map(|(volume, _)| volume).collect();
+    let committed = match staged {
+        true => !stored.is_empty(),
+        false => stored.len() >= quorum,
+    };
+    if committed && stored.len() < quorum {
+        warn!(
+            "put_replicas: key: {} moved into place in {} of {} replicas, committed",
+            key,
+            stored.len(),
+            quorum
+        );
+    }
+    (
+        stored,
+        failed.into_iter().map(|(volume, _)| volume).collect(),
+        committed,
+    )
+}
+
+/// Copies a value of a volume to another path of the same volume with a WebDAV COPY,
+/// replacing the value stored there, if any.
+async fn remote_copy(
+    client: &reqwest::Client,
+    remote_url: &str,
+    copy_url: &str,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "chaos")]
+    chaos::inject_volume_fault(remote_url).await?;
+
+    let request = client
+        .request(reqwest::Method::from_bytes(b"COPY")?, remote_url)
+        .header(DESTINATION, copy_url)
+        .header(OVERWRITE, "T")
+        .send();
+    let res = metrics::METRICS
+        .time_volume_request("COPY", request)
+        .await?;
+    match res.status() {
+        reqwest::StatusCode::CREATED | reqwest::StatusCode::NO_CONTENT => Ok(()),
+        status => Err(VolumeStatusError {
+            url: remote_url.to_string(),
+            status,
+        }
+        .into()),
+    }
+}
+
+/// Moves a value uploaded to a temporary url of a volume into place with a WebDAV MOVE,
+/// replacing the value stored there, if any.
+async fn remote_move(
+    client: &reqwest::Client,
+    tmp_url: &str,
+    remote_url: &str,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "chaos")]
+    chaos::inject_volume_fault(tmp_url).await?;
+
+    let request = client
+        .request(reqwest::Method::from_bytes(b"MOVE")?, tmp_url)
+        .header(DESTINATION, remote_url)
+        .header(OVERWRITE, "T")
+        .send();
+    let res = metrics::METRICS
+        .time_volume_request("MOVE", request)
+        .await?;
+    match res.status() {
+        reqwest::StatusCode::CREATED | reqwest::StatusCode::NO_CONTENT => Ok(()),
+        status => Err(VolumeStatusError {
+            url: tmp_url.to_string(),
+            status,
+        }
+        .into()),
+    }
+}
+
+/// Puts a value in a replica, retrying connection errors and 5xx of the volume with the policy.
+/// The body is opened again on every attempt, e.g. streamed again from the spool file of the value.
+async fn put_with_retry<F, Fut>(
+    client: reqwest::Client,
+    remote_url: String,
+    body: F,
+    size: u64,
+    policy: RetryPolicy,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<reqwest::Body>>,
+{
+    let mut attempt = 1;
+    loop {
+        match remote_put(client.clone(), remote_url.clone(), body().await?, size).await {
+            Err(e) if attempt < policy.attempts && is_transient(&e) => {
+                let delay = policy.delay(attempt);
+                warn!(
+                    "put_replicas: attempt {} to put {} failed, retrying in {:?}: {}",
+                    attempt, remote_url, delay, e
+                );
+                metrics::METRICS.write_retries.inc();
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns true if a volume request failed to connect or the volume answered 5xx,
+/// errors a later attempt may not hit.
+pub(crate) fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<VolumeStatusError>() {
+        return e.status.is_server_error();
+    }
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect())
+}
+
+/// Error of a volume answering a request with an unexpected status.
+#[derive(Debug)]
+struct VolumeStatusError {
+    url: String,
+    status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for VolumeStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unexpected status {} from {}", self.status, self.url)
+    }
+}
+
+impl std::error::Error for VolumeStatusError {}
+
+/// Spawns the repair of the replicas a quorum write missed, tracked like the other writes.
+fn schedule_repair(
+    state: Arc<AppPutState>,
+    key: String,
+    record: record::Record,
+    value: Arc<spool::SpooledValue>,
+    failed: Vec<String>,
+) {
+    warn!(
+        "put_record: key: {} missing replicas {:?}, scheduling repair",
+        key, failed
+    );
+    let writes = state.writes.clone();
+    writes.spawn(repair_replicas(state, key, record, value, failed));
+}
+
+/// Retries putting a value in the replicas a quorum write missed, with exponential backoff.
+/// The replicas are also queued by the write, see `replication`, which retries them once this gives up
+/// or the index restarts.
+/// Each attempt locks the key and gives up if the record changed since the write,
+/// so a repair never overwrites a newer value. The volumes that store the value are added
+/// to the record.
+async fn repair_replicas(
+    state: Arc<AppPutState>,
+    key: String,
+    mut record: record::Record,
+    value: Arc<spool::SpooledValue>,
+    mut failed: Vec<String>,
+) {
+    let mut backoff = REPAIR_BACKOFF;
+    for _ in 0..REPAIR_ATTEMPTS {
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+
+        let Some(_guard) = state.key_locks.try_lock(&key) else {
+            debug!("repair_replicas: key: {} locked, retrying later", key);
+            continue;
+        };
+        let current = match state.leveldb.get_record(&key).await {
+            Ok(Some(current)) if same_write(&current, &record) => current,
+            Ok(_) => {
+                debug!("repair_replicas: key: {} changed, dropping repair", key);
+                return;
+            }
+            Err(e) => {
+                error!("repair_replicas: failed to get record {}: {}", key, e);
+                continue;
+            }
+        };
+
+        let (stored, still_failed, _) = put_replicas(&state, &key, &failed, &value, 1, false).await;
+        if !stored.is_empty() {
+            let mut read_volumes = current.read_volumes().to_vec();
+            read_volumes.extend(stored);
+            let repaired = current.with_read_volumes(read_volumes);
+            match state.leveldb.put_record(&key, repaired.clone()).await {
+                Ok(()) => {
+                    record = repaired;
+                    failed = still_failed;
+                }
+                Err(e) => error!("repair_replicas: failed to put record {}: {}", key, e),
+            }
+        }
+        if failed.is_empty() {
+            debug!("repair_replicas: key: {} repaired", key);
+            if let Err(e) = replication::complete(&state.leveldb, &key, &record).await {
+                error!("repair_replicas: failed to dequeue {}: {}", key, e);
+            }
+            return;
+        }
+    }
+    error!(
+        "repair_replicas: key: {} still missing replicas {:?}, left to the replication queue",
+        key, failed
+    );
+}
+
+/// Returns true if the leveldb record is still the one written by a PUT, with the same volumes.
+pub(crate) fn same_write(current: &record::Record, written: &record::Record) -> bool {
+    current.deleted() == written.deleted()
+        && current.hash() == written.hash()
+        && current.size() == written.size()
+        && current.read_volumes() == written.read_volumes()
+}
+
+/// Returns the value of a header, or of a trailer with the same name if the header is missing.
+fn header_or_trailer<'a>(
+    headers: &'a axum::http::HeaderMap,
+    trailers: Option<&'a axum::http::HeaderMap>,
+    name: &str,
+) -> Option<&'a str> {
+    headers
+        .get(name)
+        .or_else(|| trailers.and_then(|trailers| trailers.get(name)))
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Returns true if an `If-None-Match` header matches the ETag, using the weak comparison of RFC 9110.
+/// A missing or malformed header never matches.
+fn if_none_match(headers: &axum::http::HeaderMap, etag: &str) -> bool {
+    let Some(Ok(value)) = headers
+        .get(axum::http::header::IF_NONE_MATCH)
+        .map(|value| value.to_str())
+    else {
+        return false;
+    };
+    etag_matches(value, etag, true)
+}
+
+/// Returns true if a list of ETags, e.g. `"a", W/"b"`, or `*` matches the ETag.
+/// The strong comparison of RFC 9110 never matches a weak ETag.
+fn etag_matches(list: &str, etag: &str, weak: bool) -> bool {
+    list.split(',').map(str::trim).any(|candidate| {
+        candidate == "*"
+            || match candidate.strip_prefix("W/") {
+                Some(candidate) => weak && candidate == etag,
+                None => candidate == etag,
+            }
+    })
+}
+
+/// Precondition of a PUT, from its `If-Match` or `If-None-Match` header, checked against
+/// the current record while the key is locked.
+#[derive(Debug)]
+enum Precondition {
+    /// Replaces the value only if it exists and its ETag is listed, `*` replaces any value.
+    IfMatch(String),
+    /// Stores the value only if no listed ETag matches, `*` creates the key only if absent.
+    IfNoneMatch(String),
+}
+
+impl Precondition {
+    /// Returns the precondition of the request, None if it is unconditional,
+    /// an error if the header is not visible ASCII.
+    fn from_headers(
+        headers: &axum::http::HeaderMap,
+    ) -> Result<Option<Self>, axum::http::header::ToStrError> {
+        if let Some(value) = headers.get(axum::http::header::IF_MATCH) {
+            return Ok(Some(Self::IfMatch(value.to_str()?.to_string())));
+        }
+        if let Some(value) = headers.get(axum::http::header::IF_NONE_MATCH) {
+            return Ok(Some(Self::IfNoneMatch(value.to_str()?.to_string())));
+        }
+        Ok(None)
+    }
+
+    /// Returns true if the value can be stored over the current live record, None if the key is absent.
+    fn allows(&self, current: Option<&record::Record>) -> bool {
+        let etag = current.map(|record| record.etag().unwrap_or_default());
+        match (self, etag) {
+            (Self::IfMatch(list), Some(etag)) => etag_matches(list, &etag, false),
+            (Self::IfMatch(_), None) => false,
+            (Self::IfNoneMatch(list), Some(etag)) => !etag_matches(list, &etag, true),
+            (Self::IfNoneMatch(_), None) => true,
+        }
+    }
+}
+
+/// Returns true if a conditional GET or HEAD of the record can be answered with 304.
+/// As in RFC 9110, `If-Modified-Since` is ignored when `If-None-Match` is present,
+/// and a malformed date or a record without a modification time never matches.
+fn is_not_modified(headers: &axum::http::HeaderMap, record: &record::Record) -> bool {
+    if headers.contains_key(axum::http::header::IF_NONE_MATCH) {
+        return record
+            .etag()
+            .is_some_and(|etag| if_none_match(headers, &etag));
+    }
+    let since = headers
+        .get(axum::http::header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    match (since, record.updated_at()) {
+        (Some(since), Some(updated_at)) => unix_time(updated_at) <= since,
+        _ => false,
+    }
+}
+
+/// Returns the `Last-Modified` HTTP date of the record, None if its modification time is unknown.
+fn last_modified(record: &record::Record) -> Option<String> {
+    record
+        .updated_at()
+        .map(|updated_at| httpdate::fmt_http_date(unix_time(updated_at)))
+}
+
+/// Returns the time of seconds since the unix epoch.
+fn unix_time(secs: u64) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Returns the 304 response of a conditional GET or HEAD of the record.
+fn not_modified(record: &record::Record) -> axum::response::Response {
+    let mut response = axum::http::Response::builder().status(axum::http::StatusCode::NOT_MODIFIED);
+    if let Some(etag) = record.etag() {
+        response = response.header(axum::http::header::ETAG, etag);
+    }
+    if let Some(last_modified) = last_modified(record) {
+        response = response.header(axum::http::header::LAST_MODIFIED, last_modified);
+    }
+    response.body(axum::body::Body::empty()).unwrap()
+}
+
+/// Returns the expiry time in seconds since the unix epoch of an `X-Ttl` header,
+/// an error if the header is not a positive number of seconds.
+fn ttl_expires_at(headers: &axum::http::HeaderMap) -> Result<Option<u64>, ()> {
+    let Some(value) = headers.get(TTL) else {
+        return Ok(None);
+    };
+    match value.to_str().ok().and_then(|ttl| ttl.parse::<u64>().ok()) {
+        Some(ttl) if ttl > 0 => Ok(Some(record::unix_now().saturating_add(ttl))),
+        _ => Err(()),
+    }
+}
+
+/// Returns true if the `Content-Length` announces a body larger than the max value size, so it is rejected
+/// before any of it is read. Bodies without one are counted while they are spooled.
+fn exceeds_max_value_size(headers: &axum::http::HeaderMap, max_size: Option<u64>) -> bool {
+    let Some(max_size) = max_size else {
+        return false;
+    };
+    headers
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|length| length > max_size)
+}
+
+/// Returns the replica count of an `X-Replicas` header, an error if it is not between 1 and the max replicas.
+fn requested_replicas(
+    headers: &axum::http::HeaderMap,
+    max_replicas: usize,
+) -> Result<Option<usize>, ()> {
+    let Some(value) = headers.get(REPLICAS) else {
+        return Ok(None);
+    };
+    match value
+        .to_str()
+        .ok()
+        .and_then(|replicas| replicas.parse::<usize>().ok())
+    {
+        Some(replicas) if (1..=max_replicas).contains(&replicas) => Ok(Some(replicas)),
+        _ => Err(()),
+    }
+}
+
+/// Returns a header stored with the value, e.g. `Content-Disposition`, an error if it is not visible ASCII.
+fn stored_header(
+    headers: &axum::http::HeaderMap,
+    name: axum::http::HeaderName,
+) -> Result<Option<String>, axum::http::header::ToStrError> {
+    headers
+        .get(name)
+        .map(|value| value.to_str().map(String::from))
+        .transpose()
+}
+
+/// Locks the key, puts a part of a multipart upload in the replicas of the key and stores its part record
+/// with the replicas that hold the value as its volumes. The lock keeps a PUT or the completion of the key
+/// from interleaving, e.g. a part rewritten once the upload is stitched.
+/// Returns the status of the PUT request.
+async fn put_part(
+    state: Arc<AppPutState>,
+    key: String,
+    part_number: u32,
+    value: Arc<spool::SpooledValue>,
+    new_record: record::Record,
+) -> StatusCode {
+    let Some(_guard) = state.key_locks.lock(&key).await else {
+        debug!("put_part: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return StatusCode::CONFLICT;
+    };
+
+    match state.leveldb.get_record(&key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => {
+            debug!("put_part: key: {} already exists", key);
+            return StatusCode::CONFLICT;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            error!("put_part: failed to get record {} from leveldb: {}", key, e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    let part_key = record::part_key(&key, part_number);
+    let replicas_volumes = state.hashring.read().get_volume_with_replicas(
+        &key,
+        new_record.placement(),
+        new_record.replicas(),
+    );
+
+    let quorum = state.write_quorum.min(replicas_volumes.len());
+    let (stored, failed, committed) =
+        put_replicas(&state, &part_key, &replicas_volumes, &value, quorum, false).await;
+    if !committed {
+        error!(
+            "put_part: part {} stored in {} of {} replicas",
+            part_key,
+            stored.len(),
+            replicas_volumes.len()
+        );
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    let now = record::unix_now();
+    let record = new_record
+        .with_read_volumes(stored)
+        .with_timestamps(now, now);
+    if !failed.is_empty() {
+        if let Err(e) = replication::enqueue(&state.leveldb, &part_key, &record, &failed).await {
+            error!(
+                "put_part: failed to queue the replication of {}: {}",
+                part_key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    match state.leveldb.put_record(&part_key, record.clone()).await {
+        Ok(_) => {
+            if !failed.is_empty() {
+                schedule_repair(state, part_key, record, value, failed);
+            }
+            StatusCode::CREATED
+        }
+        Err(e) => {
+            error!(
+                "put_part: failed to put part record {} in leveldb: {}",
+                part_key, e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Handles POST requests completing a multipart upload with `?uploads=complete`.
+/// The body is the JSON array of the part numbers to stitch in increasing order, e.g. `[1, 2, 3]`.
+/// The record gets the size and the S3 style MD5 of its parts, the part records are removed.
+/// `Content-Disposition` and `X-Ttl` headers are stored like on PUT, the Content-Type is the one of the first part.
+/// With `?undelete` a soft deleted key is restored instead, see `undelete_record`.
+/// With `?presign` the URLs to upload the value to are returned instead, see `presign_upload`,
+/// and `?presign=complete` creates the record of the uploaded value, see `complete_presigned`.
+/// `POST /cas` stores a content addressed value instead, see `put_content_addressed`.
+/// Returns 201 if the record is created
+/// Returns 400 if the query, the part list, the Content-Disposition, the Content-Type, the Content-Md5 or the X-Ttl
+/// is invalid or a part is not uploaded
+/// Returns 409 if the record key stays locked by another PUT/DELETE past the lock timeout or exists
+/// Returns 413 if the parts add up to more than the max value size
+/// Returns 507 if the value would exceed the quota of the namespace of the key
+/// Returns 500 for internal server error
+async fn handle_post_record(
+    RecordKey(key): RecordKey,
+    axum::extract::State(state): axum::extract::State<Arc<AppPutState>>,
+    axum::extract::Query(params): axum::extract::Query<PostParams>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if let Err(e) = state.key_policy.check(&key) {
+        debug!("post_record: key: {:?} rejected: {}", key, e);
+        return e.into_response();
+    }
+    if params
+        .presign
+        .as_deref()
+        .is_some_and(|presign| presign != "complete")
+    {
+        return presign_upload(&state, &key, params.expires).await;
+    }
+    if key == CAS_KEY && params.uploads.is_none() && params.undelete.is_none() {
+        return put_content_addressed(state, &headers, body).await;
+    }
+    let Ok(body) = axum::body::to_bytes(body, MAX_POST_BODY_SIZE).await else {
+        debug!("post_record: key: {} body too large or unreadable", key);
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    post_record(state, key, params, headers, body)
+        .await
+        .into_response()
+}
+
+/// Completes a multipart or presigned upload or undeletes a key, see `handle_post_record`.
+/// Returns the status of the POST request.
+async fn post_record(
+    state: Arc<AppPutState>,
+    key: String,
+    params: PostParams,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> StatusCode {
+    debug!("post_record: key: {}", key);
+
+    if params.undelete.is_some() {
+        return match state
+            .writes
+            .spawn(undelete_record(state.clone(), key.clone()))
+            .await
+        {
+            Ok(status) => status,
+            Err(e) => {
+                error!("post_record: undelete of key {} failed: {}", key, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+    }
+    if params.presign.is_some() {
+        return complete_presigned_upload(state, key, headers).await;
+    }
+    if params.uploads.as_deref() != Some("complete") {
+        return StatusCode::BAD_REQUEST;
+    }
+    let part_numbers = match serde_json::from_slice::<Vec<u32>>(&body) {
+        Ok(part_numbers)
+            if !part_numbers.is_empty()
+                && part_numbers.windows(2).all(|pair| pair[0] < pair[1]) =>
+        {
+            part_numbers
+        }
+        _ => {
+            debug!("post_record: key: {} invalid part list", key);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    let Ok(content_disposition) = stored_header(&headers, axum::http::header::CONTENT_DISPOSITION)
+    else {
+        debug!("post_record: key: {} invalid Content-Disposition", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(expires_at) = ttl_expires_at(&headers) else {
+        debug!("post_record: key: {} invalid X-Ttl", key);
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let write = complete_upload(
+        state.clone(),
+        key.clone(),
+        part_numbers,
+        content_disposition,
+        expires_at,
+    );
+    match state.writes.spawn(write).await {
+        Ok(status) => status,
+        Err(e) => {
+            error!("post_record: write for key {} failed: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Struct representing the response of `POST /key?presign`: the URLs the value is uploaded to,
+/// one per replica, until the unix time, and the path completing the upload.
+#[derive(Debug, serde::Serialize)]
+struct PresignedUpload {
+    urls: Vec<String>,
+    expires_at: u64,
+    complete: String,
+}
+
+/// Returns the presigned URLs of the replicas of a key, each to PUT the whole value to without going
+/// through the index, before `POST /key?presign=complete`. The URLs point next to the path of the value,
+/// the completion moves the uploads into place, so they never overwrite a live value.
+/// Values uploaded this way are stored as uploaded, on the replicas of the ring.
+/// Returns 200 with the URLs as JSON
+/// Returns 400 if presigned URLs are disabled or values are encrypted, `expires` is out of range,
+/// or the key starts with a NUL byte or `cas:`
+/// Returns 409 if the key exists
+/// Returns 500 for internal server error
+async fn presign_upload(
+    state: &AppPutState,
+    key: &str,
+    expires: Option<u64>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let Some(presign) = state
+        .presign
+        .as_ref()
+        .filter(|_| state.encryption.is_none())
+    else {
+        debug!(
+            "presign_upload: key: {} presigned uploads are disabled",
+            key
+        );
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if key.starts_with(record::RESERVED_PREFIX) || key.starts_with(CAS_PREFIX) {
+        debug!("presign_upload: key: {:?} is reserved", key);
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let expires = match presign::expires_in(expires) {
+        Ok(expires) => expires,
+        Err(e) => {
+            debug!("presign_upload: key: {} {}", key, e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    match state.leveldb.get_record(key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => {
+            debug!("presign_upload: key: {} exists", key);
+            return StatusCode::CONFLICT.into_response();
+        }
+        Ok(_) => (),
+        Err(e) => {
+            error!(
+                "presign_upload: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let volumes = presigned_volumes(state, key);
+    let upload_path = format!("{}{}", record::get_remote_path(key), UPLOAD_SUFFIX);
+    let expires_at = record::unix_now() + expires;
+    let urls = volumes
+        .iter()
+        .map(|volume| {
+            let remote_url = record::volume_url(volume, &upload_path);
+            presign.presign(&axum::http::Method::PUT, &remote_url, expires_at)
+        })
+        .collect::<anyhow::Result<Vec<_>>>();
+    let urls = match urls {
+        Ok(urls) => urls,
+        Err(e) => {
+            error!("presign_upload: failed to presign key {}: {}", key, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // The key is a single path segment, slashes included are percent-encoded
+    let mut complete = reqwest::Url::parse("http://index/").expect("valid URL");
+    if let Ok(mut segments) = complete.path_segments_mut() {
+        match namespace::split(key) {
+            Some((name, key)) => segments.extend(["ns", name, key]),
+            None => segments.push(key),
+        };
+    }
+    debug!("presign_upload: key: {} presigned for {}s", key, expires);
+    axum::Json(PresignedUpload {
+        urls,
+        expires_at,
+        complete: format!("{}?presign=complete", complete.path()),
+    })
+    .into_response()
+}
+
+/// Returns the volumes a presigned upload of a key is written to, the replicas of the ring
+/// in the volume group of its placement rule, if any.
+fn presigned_volumes(state: &AppPutState, key: &str) -> Vec<String> {
+    let hashring = state.hashring.read();
+    hashring.get_volume_with_replicas(
+        key,
+        hashring.placement_group(key),
+        state.namespace_replicas(key),
+    )
+}
+
+/// Parses the headers of `POST /key?presign=complete` and completes the upload, see `complete_presigned`.
+/// `Content-Disposition`, `Content-Type` and `X-Ttl` headers are stored like on PUT, and a `Content-Md5`
+/// header is verified against the uploaded value.
+/// Returns the status of the POST request.
+async fn complete_presigned_upload(
+    state: Arc<AppPutState>,
+    key: String,
+    headers: axum::http::HeaderMap,
+) -> StatusCode {
+    if state.presign.is_none() || state.encryption.is_some() {
+        debug!("post_record: key: {} presigned uploads are disabled", key);
+        return StatusCode::BAD_REQUEST;
+    }
+    let Ok(content_disposition) = stored_header(&headers, axum::http::header::CONTENT_DISPOSITION)
+    else {
+        debug!("post_record: key: {} invalid Content-Disposition", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(content_type) = stored_header(&headers, axum::http::header::CONTENT_TYPE) else {
+        debug!("post_record: key: {} invalid Content-Type", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(expires_at) = ttl_expires_at(&headers) else {
+        debug!("post_record: key: {} invalid X-Ttl", key);
+        return StatusCode::BAD_REQUEST;
+    };
+    let expected_md5 = match headers.get(checksum::CONTENT_MD5) {
+        Some(value) => match value.to_str().ok().and_then(checksum::parse_md5) {
+            Some(md5) => Some(md5),
+            None => {
+                debug!("post_record: key: {} invalid Content-Md5", key);
+                return StatusCode::BAD_REQUEST;
+            }
+        },
+        None => None,
+    };
+
+    let write = complete_presigned(
+        state.clone(),
+        key.clone(),
+        content_disposition,
+        content_type,
+        expires_at,
+        expected_md5,
+    );
+    match state.writes.spawn(write).await {
+        Ok(status) => status,
+        Err(e) => {
+            error!("post_record: presigned upload of key {} failed: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Locks the key and creates the record of a value uploaded with presigned URLs. The uploads are read
+/// from one replica to hash them, then moved into place on every replica holding one. At least quorum
+/// replicas must hold the upload, the others are queued for replication.
+/// Returns 201 if the record is created
+/// Returns 400 if fewer than quorum replicas hold the upload
+/// Returns 409 if the key stays locked by another write past the lock timeout, exists, or its replicas
+/// hold uploads of different sizes
+/// Returns 422 if the Content-Md5 does not match the upload, which is deleted
+/// Returns 507 if the value would exceed the quota of the namespace of the key, the uploads are kept
+/// Returns 500 for internal server error
+async fn complete_presigned(
+    state: Arc<AppPutState>,
+    key: String,
+    content_disposition: Option<String>,
+    content_type: Option<String>,
+    expires_at: Option<u64>,
+    expected_md5: Option<String>,
+) -> StatusCode {
+    let Some(_guard) = state.key_locks.lock(&key).await else {
+        debug!("complete_presigned: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return StatusCode::CONFLICT;
+    };
+
+    let current = match state.leveldb.get_record_or_default(&key).await {
+        Ok(record) => record,
+        Err(e) => {
+            error!(
+                "complete_presigned: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    let now = record::unix_now();
+    if current.is_live(now) {
+        debug!("complete_presigned: key: {} exists", key);
+        return StatusCode::CONFLICT;
+    }
+
+    let volumes = presigned_volumes(&state, &key);
+    let remote_path = record::get_remote_path(&key);
+    let upload_path = format!("{}{}", remote_path, UPLOAD_SUFFIX);
+    let mut uploaded = Vec::new();
+    for volume in volumes.iter() {
+        let upload_url = record::volume_url(volume, &upload_path);
+        match remote_size(&state.client, &upload_url).await {
+            Ok(size) => uploaded.push((volume.clone(), size)),
+            Err(e) => debug!("complete_presigned: key: {} not uploaded: {}", key, e),
+        }
+    }
+    let quorum = state.write_quorum.min(volumes.len());
+    if uploaded.is_empty() || uploaded.len() < quorum {
+        debug!(
+            "complete_presigned: key: {} uploaded to {} of {} replicas",
+            key,
+            uploaded.len(),
+            quorum
+        );
+        return StatusCode::BAD_REQUEST;
+    }
+    if uploaded.iter().any(|(_, size)| *size != uploaded[0].1) {
+        debug!("complete_presigned: key: {} uploads differ in size", key);
+        return StatusCode::CONFLICT;
+    }
+    // The uploads are kept, the upload can be completed again once the namespace has room
+    let _reservation = match reserve_quota(&state.leveldb, &key, uploaded[0].1).await {
+        Ok(reservation) => reservation,
+        Err(status) => return status,
+    };
+
+    let mut algorithms = state.checksum_algorithms.clone();
+    algorithms.push(state.hash_algorithm);
+    if expected_md5.is_some() {
+        algorithms.push(checksum::Algorithm::Md5);
+    }
+    algorithms.sort_unstable();
+    algorithms.dedup();
+    let upload_url = record::volume_url(&uploaded[0].0, &upload_path);
+    let (size, digests) = match hash_upload(&state.client, &upload_url, &algorithms).await {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            error!(
+                "complete_presigned: failed to read upload of {}: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    let digest = |algorithm| {
+        digests
+            .iter()
+            .find(|(computed, _)| *computed == algorithm)
+            .map(|(_, digest)| digest.as_slice())
+            .unwrap_or_default()
+    };
+    if let Some(expected_md5) = expected_md5 {
+        let md5 = checksum::encode_hex(digest(checksum::Algorithm::Md5));
+        if md5 != expected_md5 {
+            debug!(
+                "complete_presigned: key: {} checksum mismatch, expected: {} computed: {}",
+                key, expected_md5, md5
+            );
+            for (volume, _) in uploaded.iter() {
+                let upload_url = record::volume_url(volume, &upload_path);
+                if let Err(e) = remote_delete(&state.client, &upload_url).await {
+                    warn!("complete_presigned: failed to delete {}: {}", upload_url, e);
+                }
+            }
+            return StatusCode::UNPROCESSABLE_ENTITY;
+        }
+    }
+
+    let mut stored = Vec::new();
+    for (volume, _) in uploaded.iter() {
+        let upload_url = record::volume_url(volume, &upload_path);
+        let remote_url = record::volume_url(volume, &remote_path);
+        match remote_move(&state.client, &upload_url, &remote_url).await {
+            Ok(()) => stored.push(volume.clone()),
+            Err(e) => error!(
+                "complete_presigned: failed to move key {} into place in {}: {}",
+                key, volume, e
+            ),
+        }
+    }
+    if stored.len() < quorum {
+        error!(
+            "complete_presigned: key: {} moved into place on {} of {} replicas",
+            key,
+            stored.len(),
+            quorum
+        );
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    let failed = volumes
+        .iter()
+        .filter(|volume| !stored.contains(volume))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let checksums = state
+        .checksum_algorithms
+        .iter()
+        .map(|algorithm| (*algorithm, checksum::encode_base64(digest(*algorithm))))
+        .collect();
+    let value_hash = checksum::encode_hex(digest(state.hash_algorithm));
+    let placement = state
+        .hashring
+        .read()
+        .placement_group(&key)
+        .map(String::from);
+    let record = record::Record::new(record::Deleted::No, value_hash, Vec::new())
+        .with_hash_algorithm(state.hash_algorithm)
+        .with_checksums(checksums)
+        .with_content_disposition(content_disposition)
+        .with_content_type(content_type)
+        .with_expires_at(expires_at)
+        .with_placement(placement)
+        .with_replicas(state.namespace_replicas(&key))
+        .with_size(size)
+        .with_timestamps(now, now)
+        .with_read_volumes(stored);
+    if !failed.is_empty() {
+        if let Err(e) = replication::enqueue(&state.leveldb, &key, &record, &failed).await {
+            error!(
+                "complete_presigned: failed to queue the replication of {}: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    if let Err(e) = state.leveldb.put_record(&key, record).await {
+        error!(
+            "complete_presigned: failed to put record {} in leveldb: {}",
+            key, e
+        );
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    StatusCode::CREATED
+}
+
+/// Struct representing the response of `POST /cas`, the key of the value and the uploads sharing it.
+#[derive(Debug, serde::Serialize)]
+struct ContentAddressed {
+    key: String,
+    refcount: u64,
+}
+
+/// Stores a value under the key derived from its SHA-256, `cas:<hex>`, e.g. for build artifacts or blob caches.
+/// An upload of a value already stored adds a reference to its record instead of writing it again,
+/// and a DELETE of the key drops one, see `soft_delete_record`.
+/// `Content-Disposition` and `Content-Type` headers are stored like on PUT by the first upload.
+/// Returns 201 with the key and refcount as JSON and the path of the key in the Location header if the value is stored
+/// Returns 200 with the key and refcount as JSON if the value was already stored
+/// Returns 400 if the body can't be read or the Content-Disposition or Content-Type is not visible ASCII
+/// Returns 409 if the key stays locked by another write past the lock timeout
+/// Returns 411 if the body is empty
+/// Returns 413 if the Content-Length or the body exceeds the max value size
+/// Returns 500 for internal server error
+async fn put_content_addressed(
+    state: Arc<AppPutState>,
+    headers: &axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let Ok(content_disposition) = stored_header(headers, axum::http::header::CONTENT_DISPOSITION)
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let Ok(content_type) = stored_header(headers, axum::http::header::CONTENT_TYPE) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let mut algorithms = state.checksum_algorithms.clone();
+    algorithms.extend([state.hash_algorithm, checksum::Algorithm::Sha256]);
+    algorithms.sort_unstable();
+    algorithms.dedup();
+    if exceeds_max_value_size(headers, state.max_value_size) {
+        debug!("cas: Content-Length exceeds the max value size");
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let value = match spool::SpooledValue::spool(body, &algorithms, state.max_value_size).await {
+        Ok(value) => value,
+        Err(e) if e.downcast_ref::<spool::TooLarge>().is_some() => {
+            debug!("cas: {}", e);
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        Err(e) if e.downcast_ref::<axum::Error>().is_some() => {
+            error!("cas: failed to read body: {}", e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        Err(e) => {
+            error!("cas: failed to spool body: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    metrics::METRICS
+        .proxied_bytes
+        .with_label_values(&["in"])
+        .inc_by(value.size());
+    if value.size() == 0 {
+        return StatusCode::LENGTH_REQUIRED.into_response();
+    }
+
+    let digest = |algorithm| value.digest(algorithm).unwrap_or_default();
+    let key = format!(
+        "{}{}",
+        CAS_PREFIX,
+        checksum::encode_hex(digest(checksum::Algorithm::Sha256))
+    );
+    let value_hash = checksum::encode_hex(digest(state.hash_algorithm));
+    let checksums = state
+        .checksum_algorithms
+        .iter()
+        .map(|algorithm| (*algorithm, checksum::encode_base64(digest(*algorithm))))
+        .collect();
+    debug!("cas: key: {}", key);
+
+    // A value already stored only gains a reference. Two first uploads race to create it,
+    // the one losing the If-None-Match adds its reference to the record of the other
+    let status = match add_reference(&state, &key).await {
+        Ok(Some(refcount)) => return content_addressed(StatusCode::OK, key, refcount),
+        Ok(None) => {
+            let size = value.size();
+            let (value, encryption) = match &state.encryption {
+                Some(master_key) => match encrypt_value(master_key, &value).await {
+                    Ok((encrypted, encryption)) => (encrypted, Some(encryption)),
+                    Err(e) => {
+                        error!("cas: failed to encrypt value of key {}: {}", key, e);
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                },
+                None => (value, None),
+            };
+            let record = record::Record::new(record::Deleted::No, value_hash, Vec::new())
+                .with_hash_algorithm(state.hash_algorithm)
+                .with_checksums(checksums)
+                .with_content_disposition(content_disposition)
+                .with_content_type(content_type)
+                .with_placement(
+                    state
+                        .hashring
+                        .read()
+                        .placement_group(&key)
+                        .map(String::from),
+                )
+                .with_refcount(Some(1))
+                .with_size(size)
+                .with_encryption(encryption);
+            let precondition = Some(Precondition::IfNoneMatch("*".to_string()));
+            let write = put_replicas_and_record(
+                state.clone(),
+                key.clone(),
+                Arc::new(value),
+                record,
+                precondition,
+            );
+            match state.writes.spawn(write).await {
+                Ok(status) => status,
+                Err(e) => {
+                    error!("cas: write for key {} failed: {}", key, e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }
+        }
+        Err(status) => status,
+    };
+    match status {
+        StatusCode::CREATED => content_addressed(StatusCode::CREATED, key, 1),
+        StatusCode::PRECONDITION_FAILED => match add_reference(&state, &key).await {
+            Ok(Some(refcount)) => content_addressed(StatusCode::OK, key, refcount),
+            Ok(None) => StatusCode::CONFLICT.into_response(),
+            Err(status) => status.into_response(),
+        },
+        status => status.into_response(),
+    }
+}
+
+/// Locks a content addressed key and adds a reference to its live record.
+/// Returns the new refcount, None if the key has no live record, or the status of the failure.
+async fn add_reference(state: &AppPutState, key: &str) -> Result<Option<u64>, StatusCode> {
+    let Some(_guard) = state.key_locks.lock(key).await else {
+        debug!("cas: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return Err(StatusCode::CONFLICT);
+    };
+    let record = match state.leveldb.get_record(key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => record,
+        Ok(_) => return Ok(None),
+        Err(e) => {
+            error!("cas: failed to get record {} from leveldb: {}", key, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let refcount = record.refcount().unwrap_or(1) + 1;
+    match state
+        .leveldb
+        .put_record(key, record.with_refcount(Some(refcount)))
+        .await
+    {
+        Ok(_) => Ok(Some(refcount)),
+        Err(e) => {
+            error!("cas: failed to put record {} in leveldb: {}", key, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Returns the response of `POST /cas`.
+fn content_addressed(status: StatusCode, key: String, refcount: u64) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let location = format!("/{}", key);
+    (
+        status,
+        [(axum::http::header::LOCATION, location)],
+        axum::Json(ContentAddressed { key, refcount }),
+    )
+        .into_response()
+}
+
+/// Returns a random UUID, version 4, e.g. `3f2b6c1e-8d4a-4f0b-9c7e-2a5d8e1f6b3c`.
+fn generate_key() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = checksum::encode_hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Handles POST requests to `/` and `/ns/:namespace/`, storing the body under a generated key, a random UUID,
+/// for clients that don't need to name their keys. The key is in the namespace of the path or of an
+/// `X-Namespace` header, if any. Headers are handled like on a PUT of the key, see `handle_put_record`.
+/// Content addressed keys are generated by `POST /cas` instead, see `put_content_addressed`.
+/// Returns 201 with the path of the key in the Location header and the key as the body
+/// Returns 400 if the namespace is invalid
+/// Returns the status of the PUT of the key otherwise
+async fn handle_post_generated(
+    namespace: Option<axum::extract::Path<String>>,
+    axum::extract::State(state): axum::extract::State<Arc<AppPutState>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let name = match namespace {
+        Some(axum::extract::Path(name)) => Some(name),
+        None => match namespace_header(&headers) {
+            Ok(name) => name,
+            Err(status) => return status.into_response(),
+        },
+    };
+    let generated = generate_key();
+    let key = match RecordKey::resolve(generated.clone(), name.clone()) {
+        Ok(key) => key,
+        Err(status) => {
+            debug!("post_generated: invalid namespace: {:?}", name);
+            return status.into_response();
+        }
+    };
+    debug!("post_generated: key: {}", key.0);
+
+    let response = handle_put_record(
+        key,
+        axum::extract::State(state),
+        axum::extract::Query(PutParams::default()),
+        headers,
+        body,
+    )
+    .await
+    .into_response();
+    if response.status() != StatusCode::CREATED {
+        return response;
+    }
+    let location = match name {
+        Some(name) => format!("/ns/{}/{}", name, generated),
+        None => format!("/{}", generated),
+    };
+    (
+        StatusCode::CREATED,
+        [(axum::http::header::LOCATION, location)],
+        generated,
+    )
+        .into_response()
+}
+
+/// Locks the key and stitches the parts of a multipart upload into its record.
+/// Returns the status of the POST request.
+async fn complete_upload(
+    state: Arc<AppPutState>,
+    key: String,
+    part_numbers: Vec<u32>,
+    content_disposition: Option<String>,
+    expires_at: Option<u64>,
+) -> StatusCode {
+    let Some(_guard) = state.key_locks.lock(&key).await else {
+        debug!("complete_upload: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return StatusCode::CONFLICT;
+    };
+    stitch_parts(&state, &key, &part_numbers, content_disposition, expires_at).await
+}
+
+/// Locks the key and flips its soft deleted record back to live, keeping its volumes and metadata,
+/// once the value is found on the volumes. The gc task may already have deleted it.
+/// Returns 204 if the key is restored
+/// Returns 404 if the key has no soft deleted record
+/// Returns 409 if the key is live or stays locked by another PUT/DELETE past the lock timeout
+/// Returns 410 if no replica of the value, or of one of its parts, answers HEAD,
+/// or more shards of an erasure coded value than its parity shards are gone
+/// Returns 507 if the value would exceed the quota of the namespace of the key
+/// Returns 500 for internal server error
+async fn undelete_record(state: Arc<AppPutState>, key: String) -> StatusCode {
+    let Some(_guard) = state.key_locks.lock(&key).await else {
+        debug!("undelete_record: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return StatusCode::CONFLICT;
+    };
+
+    let record = match state.leveldb.get_record(&key).await {
+        Ok(Some(record)) if record.deleted() == record::Deleted::Soft => record,
+        Ok(Some(record)) if record.deleted() == record::Deleted::No => {
+            debug!("undelete_record: key: {} is not deleted", key);
+            return StatusCode::CONFLICT;
+        }
+        Ok(_) => return StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(
+                "undelete_record: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+
+    // Every blob the value is read from must still be on a volume, but the parity shards count of shards
+    let (blobs, tolerated): (Vec<(String, &[String])>, usize) = match record.erasure() {
+        Some(erasure) => (
+            erasure
+                .shards
+                .iter()
+                .enumerate()
+                .map(|(index, shard)| {
+                    (
+                        record::shard_key(&key, index),
+                        std::slice::from_ref(&shard.volume),
+                    )
+                })
+                .collect(),
+            erasure.shards.len() - erasure.data_shards as usize,
+        ),
+        None if record.parts().is_empty() => {
+            let blob_key = record.blob().map_or(key.clone(), record::blob_key);
+            (vec![(blob_key, record.read_volumes())], 0)
+        }
+        None => (
+            record
+                .parts()
+                .iter()
+                .map(|part| (record::part_key(&key, part.number), part.volumes.as_slice()))
+                .collect(),
+            0,
+        ),
+    };
+    let mut missing = 0;
+    for (blob_key, volumes) in blobs.iter() {
+        let remote_path = record::get_remote_path(blob_key);
+        let mut found = false;
+        for volume in state.health.up_first(volumes) {
+            let remote_url = record::volume_url(volume, &remote_path);
+            if remote_head(&state.client, &remote_url).await.is_ok() {
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            debug!(
+                "undelete_record: key: {} blob {} not found in any volume",
+                key, blob_key
+            );
+            missing += 1;
+        }
+    }
+    if missing > tolerated {
+        return StatusCode::GONE;
+    }
+    let _reservation = match reserve_quota(&state.leveldb, &key, record.size()).await {
+        Ok(reservation) => reservation,
+        Err(status) => return status,
+    };
+
+    let record = record
+        .with_deleted(record::Deleted::No)
+        .with_deleted_at(None);
+    match state.leveldb.put_record(&key, record).await {
+        Ok(_) => {
+            debug!("undelete_record: key: {} restored", key);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            error!(
+                "undelete_record: failed to put record {} in leveldb: {}",
+                key, e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Stores the record of a multipart upload from its part records and removes the part records.
+async fn stitch_parts(
+    state: &AppPutState,
+    key: &str,
+    part_numbers: &[u32],
+    content_disposition: Option<String>,
+    expires_at: Option<u64>,
+) -> StatusCode {
+    match state.leveldb.get_record(key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => {
+            debug!("complete_upload: key: {} already exists", key);
+            return StatusCode::CONFLICT;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            error!(
+                "complete_upload: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    let mut parts = Vec::with_capacity(part_numbers.len());
+    let mut placement = None;
+    let mut content_type = None;
+    for number in part_numbers.iter().copied() {
+        let part_record = match state
+            .leveldb
+            .get_record(&record::part_key(key, number))
+            .await
+        {
+            Ok(Some(part_record)) if part_record.deleted() == record::Deleted::Init => part_record,
+            Ok(_) => {
+                debug!("complete_upload: key: {} part {} not uploaded", key, number);
+                return StatusCode::BAD_REQUEST;
+            }
+            Err(e) => {
+                error!(
+                    "complete_upload: failed to get part {} of {} from leveldb: {}",
+                    number, key, e
+                );
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        };
+        placement = part_record.placement().map(String::from);
+        if parts.is_empty() {
+            content_type = part_record.content_type().map(String::from);
+        }
+        parts.push(record::Part {
+            number,
+            hash: part_record.hash().to_string(),
+            size: part_record.size(),
+            volumes: part_record.read_volumes().clone(),
+        });
+    }
+
+    let mut read_volumes: Vec<String> = parts
+        .iter()
+        .flat_map(|part| part.volumes.iter().cloned())
+        .collect();
+    read_volumes.sort_unstable();
+    read_volumes.dedup();
+    let size = parts.iter().map(|part| part.size).sum();
+    if state.max_value_size.is_some_and(|max_size| size > max_size) {
+        debug!(
+            "complete_upload: key: {} parts of {} bytes exceed the max value size",
+            key, size
+        );
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+    let _reservation = match reserve_quota(&state.leveldb, key, size).await {
+        Ok(reservation) => reservation,
+        Err(status) => return status,
+    };
+    let hash = checksum::multipart_md5_hex(parts.iter().map(|part| part.hash.as_str()));
+    // The key is absent or deleted, so the stitched value creates it again
+    let now = record::unix_now();
+
+    let record = record::Record::new(record::Deleted::No, hash, read_volumes)
+        .with_content_disposition(content_disposition)
+        .with_content_type(content_type)
+        .with_expires_at(expires_at)
+        .with_placement(placement)
+        .with_parts(parts)
+        .with_size(size)
+        .with_timestamps(now, now);
+    if let Err(e) = state.leveldb.put_record(key, record).await {
+        error!(
+            "complete_upload: failed to put record {} in leveldb: {}",
+            key, e
+        );
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    for number in part_numbers.iter().copied() {
+        if let Err(e) = state
+            .leveldb
+            .delete_record(&record::part_key(key, number))
+            .await
+        {
+            error!(
+                "complete_upload: failed to delete part {} of {} from leveldb: {}",
+                number, key, e
+            );
+        }
+    }
+
+    StatusCode::CREATED
+}
+
+/// Returns the presigned URL of the value of a key at a volume URL, valid for the seconds,
+/// so it can be downloaded without a token and without the index.
+/// Returns OK with the URL as the body
+/// Returns INTERNAL_SERVER_ERROR if the volume URL can't be signed
+fn presigned_url(
+    presign: &presign::PresignConfig,
+    key: &str,
+    remote_url: &str,
+    record: &record::Record,
+    expires: u64,
+) -> axum::response::Response {
+    let expires_at = record::unix_now() + expires;
+    match presign.presign(&axum::http::Method::GET, remote_url, expires_at) {
+        Ok(url) => {
+            debug!("get_record: key: {} presigned for {}s", key, expires);
+            axum::http::Response::builder()
+                .status(axum::http::StatusCode::OK)
+                .header(axum::http::header::CONTENT_TYPE, "text/plain")
+                .header(axum::http::header::CACHE_CONTROL, "no-store")
+                .header(record.hash_algorithm().hash_header_name(), record.hash())
+                .body(axum::body::Body::from(url))
+                .unwrap()
+        }
+        Err(e) => {
+            error!(
+                "get_record: failed to presign {} for {}: {}",
+                remote_url, key, e
+            );
+            axum::http::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+/// Puts a value of the given size in a remote volume using reqwest
+/// if the response status is not CREATED or NO_CONTENT, an error 500 is returned
+/// Failed puts are counted as replication failures.
+/// Waits for the fan-out bounds first, see `fanout::FanoutConfig`.
+pub(crate) async fn remote_put(
+    client: reqwest::Client,
+    remote_url: String,
+    value: reqwest::Body,
+    size: u64,
+) -> anyhow::Result<()> {
+    let _permits = fanout::FANOUT.acquire(&remote_url).await;
+    breaker::BREAKERS.allow(&remote_url)?;
+    let result = metrics::METRICS
+        .time_volume_request("PUT", send_remote_put(client, &remote_url, value, size))
+        .await;
+    breaker::BREAKERS.record(&remote_url, &result);
+    if result.is_err() {
+        metrics::METRICS.replication_failures.inc();
+    }
+    result
+}
+
+/// Sends the PUT request of a value to a remote volume.
+async fn send_remote_put(
+    client: reqwest::Client,
+    remote_url: &str,
+    value: reqwest::Body,
+    size: u64,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "chaos")]
+    chaos::inject_volume_fault(remote_url).await?;
+
+    let res = client
+        .put(remote_url)
+        .header(reqwest::header::CONTENT_LENGTH, size)
+        .body(value)
+        .send()
+        .await?;
+    match res.status() {
+        reqwest::StatusCode::CREATED | reqwest::StatusCode::NO_CONTENT => Ok(()),
+        status => Err(VolumeStatusError {
+            url: remote_url.to_string(),
+            status,
+        }
+        .into()),
+    }
+}
+
+/// Handles GET requests to retrieve a record.
+/// An `X-Checksum-Algorithm` header returns the stored digest in an `X-Checksum-<Algorithm>` header.
+/// The redirect carries the stored `Content-Disposition` and `Content-Type`, if any, and the `--redirect-cache-control`.
+/// With `?list` the key is a prefix and the matching keys are listed instead, rolled up to a `delimiter` if any,
+/// see `list_keys`.
+/// With `?proxy=1` or `--default-proxy` the value is streamed through the index, see `proxy_value`.
+/// With `--value-cache` small values are returned by the index from memory, see `get_cached`.
+/// Multipart, erasure coded, compressed and encrypted values are always returned through the index,
+/// see `get_erasure` and `get_encoded`.
+/// With `?presign&expires=N` a signed volume URL valid for N seconds is returned, see `presigned_url`.
+/// With `?meta` the metadata of the record is returned, deleted and expired records included, see `record_metadata`.
+/// Returns the `--redirect-status`, 307 by default, if the record is found in a volume
+/// Returns OK with the presigned URL of `?presign`, the metadata of `?meta` as JSON, or a cached value
+/// Returns NOT_MODIFIED if an `If-None-Match` header matches the ETag of the record,
+/// or without one if the record wasn't modified since an `If-Modified-Since` header
+/// Returns BAD_REQUEST if the checksum algorithm is unsupported, or `?presign` is disabled or its
+/// `expires` out of range
+/// Returns NOT_FOUND if the record is not found, deleted or expired
+/// Returns CONFLICT if `?presign` asks for a value only returned through the index
+/// Returns GONE if the record is not found in any volume
+/// Returns INTERNAL_SERVER_ERROR for internal server error
+pub(crate) async fn handle_get_record(
+    RecordKey(key): RecordKey,
+    axum::extract::State(state): axum::extract::State<Arc<AppGetState>>,
+    axum::extract::Query(params): axum::extract::Query<GetParams>,
+    axum::extract::Query(page): axum::extract::Query<admin::PageParams>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    if params.list.is_some() {
+        return list_keys(state.leveldb.clone(), key, params.delimiter, page).await;
+    }
+
+    debug!("get_record: key: {}", key);
+
+    let presign_expires = match params.presign_expires() {
+        Ok(Some(_)) if state.presign.is_none() => {
+            debug!("get_record: key: {} presigned URLs are disabled", key);
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_REQUEST)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+        Ok(expires) => expires,
+        Err(e) => {
+            debug!("get_record: key: {} {}", key, e);
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_REQUEST)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    let requested_algorithm = match checksum::requested_algorithm(&headers) {
+        Ok(algorithm) => algorithm,
+        Err(e) => {
+            debug!("get_record: key: {} {}", key, e);
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_REQUEST)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    let record = {
+        match state.leveldb.get_record(&key).await {
+            Ok(record) => record,
+            Err(e) => {
+                error!(
+                    "get_record: failed to get record {} from leveldb: {}",
+                    key, e
+                );
+                return axum::http::Response::builder()
+                    .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+            }
+        }
+    };
+
+    if record.is_none() {
+        return axum::http::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .header(axum::http::header::CONTENT_LENGTH, "0")
+            .header("Content-Md5", "")
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    let record = record.unwrap();
+
+    if params.meta.is_some() {
+        return record_metadata(&state, &key, &record);
+    }
+
+    if !record.is_live(record::unix_now()) {
+        debug!(
+            "get_record: key: {} not found, record deleted: {:?} expires at: {:?}",
+            key,
+            record.deleted(),
+            record.expires_at()
+        );
+        return axum::http::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .header(axum::http::header::CONTENT_LENGTH, "0")
+            .header(record.hash_algorithm().hash_header_name(), record.hash())
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    // Only values stored as uploaded can be downloaded from a volume
+    if presign_expires.is_some()
+        && (!record.parts().is_empty()
+            || record.erasure().is_some()
+            || record.encoding().is_some()
+            || record.encryption().is_some())
+    {
+        debug!("get_record: key: {} can't be presigned", key);
+        return axum::http::Response::builder()
+            .status(axum::http::StatusCode::CONFLICT)
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    if is_not_modified(&headers, &record) {
+        debug!("get_record: key: {} not modified", key);
+        return not_modified(&record);
+    }
+
+    if !record.parts().is_empty() {
+        return get_multipart(&state, &key, &record).await;
+    }
+    if let Some(erasure) = record.erasure() {
+        return get_erasure(&state, &key, &record, erasure).await;
+    }
+
+    let value_cache = state.value_cache.as_ref().filter(|value_cache| {
+        presign_expires.is_none()
+            && !params.redirect()
+            && !headers.contains_key(axum::http::header::RANGE)
+            && value_cache.applies_to(&record)
+    });
+    if let Some(value) = value_cache.and_then(|value_cache| value_cache.get(&key, &record)) {
+        debug!("get_record: key: {} from the value cache", key);
+        return cached_value(&record, value, requested_algorithm);
+    }
+
+    let replicas_volumes =
+        state
+            .hashring
+            .read()
+            .get_volume_with_replicas(&key, record.placement(), record.replicas());
+    // Deduplicated values stay on the volumes of the key first storing them
+    let needs_rebalance_header =
+        if record.blob().is_none() && needs_rebalance(&replicas_volumes, record.read_volumes()) {
+            state.read_repair.schedule(&key);
+            "unbalanced"
+        } else {
+            "balanced"
+        };
+
+    // Values are read from the volumes they were written to, so ring changes don't hide them
+    let read_volumes = if record.read_volumes().is_empty() {
+        &replicas_volumes
+    } else {
+        record.read_volumes()
+    };
+
+    let remote_path = record.remote_path(&key);
+    let remote_url = find_replica(&state, &key, &remote_path, read_volumes).await;
+
+    if let (Some(remote_url), Some(expires), Some(presign)) =
+        (&remote_url, presign_expires, &state.presign)
+    {
+        return presigned_url(presign, &key, remote_url, &record, expires);
+    }
+
+    match remote_url {
+        Some(remote_url) if record.encoding().is_some() || record.encryption().is_some() => {
+            debug!("get_record: key: {} decoded from: {}", key, remote_url);
+            get_encoded(
+                &state,
+                &key,
+                &remote_url,
+                &headers,
+                &record,
+                requested_algorithm,
+            )
+            .await
+        }
+        Some(remote_url) if value_cache.is_some() => {
+            debug!("get_record: key: {} cached from: {}", key, remote_url);
+            get_cached(&state, &key, &remote_url, &record, requested_algorithm).await
+        }
+        Some(remote_url) if params.proxy(state.default_proxy) => {
+            debug!("get_record: key: {} proxied from: {}", key, remote_url);
+            proxy_value(&state, &remote_url, &headers, &record, requested_algorithm).await
+        }
+        Some(remote_url) => {
+            debug!("get_record: key: {} from remote_url: {}", key, remote_url);
+            let mut response = axum::http::Response::builder()
+                .status(state.redirect_status.status())
+                .header(axum::http::header::LOCATION, remote_url)
+                .header(axum::http::header::CONTENT_LENGTH, "0")
+                .header(VALUE_SIZE, record.size())
+                .header(record.hash_algorithm().hash_header_name(), record.hash());
+            if let Some(etag) = record.etag() {
+                response = response.header(axum::http::header::ETAG, etag);
+            }
+            if let Some(last_modified) = last_modified(&record) {
+                response = response.header(axum::http::header::LAST_MODIFIED, last_modified);
+            }
+            if let Some(content_disposition) = record.content_disposition() {
+                response =
+                    response.header(axum::http::header::CONTENT_DISPOSITION, content_disposition);
+            }
+            if let Some(content_type) = record.content_type() {
+                response = response.header(axum::http::header::CONTENT_TYPE, content_type);
+            }
+            if let Some(cache_control) = &state.redirect_cache_control {
+                response = response.header(axum::http::header::CACHE_CONTROL, cache_control);
+            }
+            if let Some(algorithm) = requested_algorithm {
+                if let Some(digest) = record.checksum(algorithm) {
+                    response = response.header(algorithm.header_name(), digest);
+                }
+            }
+            response.body(axum::body::Body::empty()).unwrap()
+        }
+        None => {
+            debug!("get_record: key: {} not found in any volume", key);
+            axum::http::Response::builder()
+                .status(axum::http::StatusCode::GONE)
+                .header(axum::http::header::CONTENT_LENGTH, "0")
+                .header("Key-Volumes", record.read_volumes().join(","))
+                .header("Key-Balance", needs_rebalance_header)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+/// Returns the url of a replica holding the value of a key, trying every volume before giving up.
+/// Replicas are HEADed from the fastest, see `VolumeHealth::fastest_first`, or all at once with
+/// `--concurrent-heads`, the first one answering with the value winning.
+/// A replica missing the value schedules a read repair of the key.
+async fn find_replica(
+    state: &AppGetState,
+    key: &str,
+    remote_path: &str,
+    volumes: &[String],
+) -> Option<String> {
+    // Shuffled first so equally fast replicas share the load, down volumes are tried last
+    let mut candidates: Vec<&String> = volumes.iter().collect();
+    candidates.shuffle(&mut rand::rngs::StdRng::from_entropy());
+    let heads = state
+        .health
+        .fastest_first(&candidates)
+        .into_iter()
+        .map(|volume| {
+            let remote_url = record::volume_url(volume, remote_path);
+            async move {
+                let start = std::time::Instant::now();
+                let result = remote_head(&state.client, &remote_url).await;
+                // A replica missing the value answered in time, only the volume failing counts as an error
+                let failed = result.as_ref().is_err_and(|e| {
+                    e.downcast_ref::<VolumeStatusError>()
+                        .is_none_or(|e| !e.status.is_client_error())
+                });
+                state.health.record_latency(volume, start.elapsed(), failed);
+                result.map(|()| remote_url)
+            }
+        });
+
+    if state.concurrent_heads {
+        let mut heads: FuturesUnordered<_> = heads.collect();
+        while let Some(result) = heads.next().await {
+            match result {
+                Ok(remote_url) => return Some(remote_url),
+                Err(e) => {
+                    debug!("get_record: key: {} {}, scheduling read repair", key, e);
+                    state.read_repair.schedule(key);
+                }
+            }
+        }
+        return None;
+    }
+    for head in heads {
+        match head.await {
+            Ok(remote_url) => return Some(remote_url),
+            Err(e) => {
+                debug!("get_record: key: {} {}, scheduling read repair", key, e);
+                state.read_repair.schedule(key);
+            }
+        }
+    }
+    None
+}
+
+/// Streams the value of a record from a volume through the index, for clients that can't follow
+/// redirects to the volumes. A `Range` header is forwarded to the volume.
+/// Returns the status, Content-Length and Content-Range of the volume with the record headers
+/// Returns BAD_GATEWAY if the volume fails to serve the value
+async fn proxy_value(
+    state: &AppGetState,
+    remote_url: &str,
+    headers: &axum::http::HeaderMap,
+    record: &record::Record,
+    requested_algorithm: Option<checksum::Algorithm>,
+) -> axum::response::Response {
+    let mut request = state.client.get(remote_url);
+    if let Some(range) = headers.get(axum::http::header::RANGE) {
+        request = request.header(reqwest::header::RANGE, range.as_bytes());
+    }
+    let res = match metrics::METRICS
+        .time_volume_request("GET", request.send())
+        .await
+        .and_then(|res| res.error_for_status())
+    {
+        Ok(res) => res,
+        Err(e) => {
+            error!("get_record: failed to proxy {}: {}", remote_url, e);
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_GATEWAY)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    let mut response = axum::http::Response::builder().status(res.status().as_u16());
+    if let Some(etag) = record.etag() {
+        response = response.header(axum::http::header::ETAG, etag);
+    }
+    if let Some(last_modified) = last_modified(record) {
+        response = response.header(axum::http::header::LAST_MODIFIED, last_modified);
+    }
+    // The digest covers the whole value, not a range of it
+    if res.status() == reqwest::StatusCode::OK {
+        response = response.header(record.hash_algorithm().hash_header_name(), record.hash());
+    }
+    for name in [
+        reqwest::header::CONTENT_LENGTH,
+        reqwest::header::CONTENT_RANGE,
+    ] {
+        if let Some(value) = res.headers().get(&name) {
+            response = response.header(name.as_str(), value.as_bytes());
+        }
+    }
+    if let Some(content_disposition) = record.content_disposition() {
+        response = response.header(axum::http::header::CONTENT_DISPOSITION, content_disposition);
+    }
+    if let Some(content_type) = record.content_type() {
+        response = response.header(axum::http::header::CONTENT_TYPE, content_type);
+    }
+    if let Some(algorithm) = requested_algorithm {
+        if let Some(digest) = record.checksum(algorithm) {
+            response = response.header(algorithm.header_name(), digest);
+        }
+    }
+    let value = res.bytes_stream().inspect_ok(|chunk| {
+        metrics::METRICS
+            .proxied_bytes
+            .with_label_values(&["out"])
+            .inc_by(chunk.len() as u64)
+    });
+    response.body(axum::body::Body::from_stream(value)).unwrap()
+}
+
+/// Reads a small value from a volume into the value cache and returns it, so the next GETs of the key
+/// are served without a redirect or a request to a volume.
+/// Returns OK with the value and the record headers
+/// Returns BAD_GATEWAY if the volume fails to serve the value
+async fn get_cached(
+    state: &AppGetState,
+    key: &str,
+    remote_url: &str,
+    record: &record::Record,
+    requested_algorithm: Option<checksum::Algorithm>,
+) -> axum::response::Response {
+    let value = match metrics::METRICS
+        .time_volume_request("GET", state.client.get(remote_url).send())
+        .await
+        .and_then(|res| res.error_for_status())
+    {
+        Ok(res) => res.bytes().await,
+        Err(e) => Err(e),
+    };
+    let value = match value {
+        Ok(value) if value.len() as u64 == record.size() => value,
+        Ok(value) => {
+            error!(
+                "get_record: {} has {} bytes, the record {}",
+                remote_url,
+                value.len(),
+                record.size()
+            );
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_GATEWAY)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+        Err(e) => {
+            error!("get_record: failed to read {}: {}", remote_url, e);
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_GATEWAY)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+    if let Some(value_cache) = &state.value_cache {
+        value_cache.insert(key, record, value.clone());
+    }
+    cached_value(record, value, requested_algorithm)
+}
+
+/// Returns the response of a value returned from memory, with the record headers.
+fn cached_value(
+    record: &record::Record,
+    value: bytes::Bytes,
+    requested_algorithm: Option<checksum::Algorithm>,
+) -> axum::response::Response {
+    let mut response = value_response(record);
+    if let Some(algorithm) = requested_algorithm {
+        if let Some(digest) = record.checksum(algorithm) {
+            response = response.header(algorithm.header_name(), digest);
+        }
+    }
+    response.body(axum::body::Body::from(value)).unwrap()
+}
+
+/// Streams the compressed or encrypted value of a record from a volume through the index, decrypting it
+/// and decompressing it unless an `Accept-Encoding` header accepts the encoding, e.g. `zstd`, in which case
+/// it is sent compressed with a `Content-Encoding` header. A `Range` header is ignored, the whole value is returned.
+/// Returns OK with the value and the record headers
+/// Returns BAD_GATEWAY if the volume fails to serve the value
+/// Returns INTERNAL_SERVER_ERROR if the data key of an encrypted value can't be unwrapped
+async fn get_encoded(
+    state: &AppGetState,
+    key: &str,
+    remote_url: &str,
+    headers: &axum::http::HeaderMap,
+    record: &record::Record,
+    requested_algorithm: Option<checksum::Algorithm>,
+) -> axum::response::Response {
+    let data_key = match data_key(state, key, record) {
+        Ok(data_key) => data_key,
+        Err(status) => {
+            return axum::http::Response::builder()
+                .status(status)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    };
+    let res = match metrics::METRICS
+        .time_volume_request("GET", state.client.get(remote_url).send())
+        .await
+        .and_then(|res| res.error_for_status())
+    {
+        Ok(res) => res,
+        Err(e) => {
+            error!("get_record: failed to proxy {}: {}", remote_url, e);
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::BAD_GATEWAY)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    let mut response = value_response(record);
+    if let Some(algorithm) = requested_algorithm {
+        if let Some(digest) = record.checksum(algorithm) {
+            response = response.header(algorithm.header_name(), digest);
+        }
+    }
+    let stored = res.bytes_stream().map_err(std::io::Error::other);
+    let stored = match data_key {
+        Some(data_key) => data_key.decrypt(stored).boxed(),
+        None => stored.boxed(),
+    };
+    let value = match record.encoding() {
+        Some(encoding) => {
+            response = response.header(
+                axum::http::header::VARY,
+                axum::http::header::ACCEPT_ENCODING.as_str(),
+            );
+            if compress::accepts(headers, &encoding.name) {
+                if let Some(headers) = response.headers_mut() {
+                    headers.insert(axum::http::header::CONTENT_LENGTH, encoding.size.into());
+                }
+                response =
+                    response.header(axum::http::header::CONTENT_ENCODING, encoding.name.as_str());
+                stored
+            } else {
+                let stored = tokio_util::io::StreamReader::new(stored);
+                tokio_util::io::ReaderStream::new(compress::decoder(stored)).boxed()
+            }
+        }
+        None => stored,
+    };
+    let value = value.inspect_ok(|chunk| {
+        metrics::METRICS
+            .proxied_bytes
+            .with_label_values(&["out"])
+            .inc_by(chunk.len() as u64)
+    });
+    response.body(axum::body::Body::from_stream(value)).unwrap()
+}
+
+/// Unwraps the data key of an encrypted record with the master key, None for a value stored in the clear.
+/// Returns INTERNAL_SERVER_ERROR as the error if the index has no master key or another one.
+fn data_key(
+    state: &AppGetState,
+    key: &str,
+    record: &record::Record,
+) -> Result<Option<encryption::DataKey>, StatusCode> {
+    let Some(encryption) = record.encryption() else {
+        return Ok(None);
+    };
+    let data_key = match &state.encryption {
+        Some(master_key) => master_key.open(encryption),
+        None => Err(anyhow::anyhow!("encrypted value, no master key")),
+    };
+    data_key.map(Some).map_err(|e| {
+        error!("get_record: failed to decrypt {}: {}", key, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Returns the url of every part of a multipart record in order, on the first volume answering a HEAD
+/// with the up volumes tried first, or the first part found on no volume.
+pub(crate) async fn locate_parts<'a>(
+    state: &AppGetState,
+    key: &str,
+    record: &'a record::Record,
+) -> Result<Vec<String>, &'a record::Part> {
+    let mut part_urls = Vec::with_capacity(record.parts().len());
+    for part in record.parts() {
+        let remote_path = record::get_remote_path(&record::part_key(key, part.number));
+        let mut found_remote_url = None;
+        for volume in state.health.up_first(&part.volumes) {
+            let remote_url = record::volume_url(volume, &remote_path);
+            if let Ok(()) = remote_head(&state.client, &remote_url).await {
+                found_remote_url = Some(remote_url);
+                break;
+            }
+        }
+        part_urls.push(found_remote_url.ok_or(part)?);
+    }
+    Ok(part_urls)
+}
+
+/// Streams the value of a multipart record through the index, stitching its parts in order.
+/// Returns OK with the stitched value
+/// Returns GONE if a part is not found in any volume
+async fn get_multipart(
+    state: &AppGetState,
+    key: &str,
+    record: &record::Record,
+) -> axum::response::Response {
+    let part_urls = match locate_parts(state, key, record).await {
+        Ok(part_urls) => part_urls,
+        Err(part) => {
+            debug!(
+                "get_record: key: {} part {} not found in any volume",
+                key, part.number
+            );
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::GONE)
+                .header(axum::http::header::CONTENT_LENGTH, "0")
+                .header("Key-Volumes", part.volumes.join(","))
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    debug!(
+        "get_record: key: {} stitching {} parts",
+        key,
+        part_urls.len()
+    );
+    let client = state.client.clone();
+    let value = futures::stream::iter(part_urls)
+        .then(move |remote_url| {
+            let client = client.clone();
+            async move {
+                let res = metrics::METRICS
+                    .time_volume_request("GET", client.get(remote_url).send())
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, reqwest::Error>(res.bytes_stream())
+            }
+        })
+        .try_flatten()
+        .inspect_ok(|chunk| {
+            metrics::METRICS
+                .proxied_bytes
+                .with_label_values(&["out"])
+                .inc_by(chunk.len() as u64)
+        });
+
+    value_response(record)
+        .body(axum::body::Body::from_stream(value))
+        .unwrap()
+}
+
+/// Reads the shards of an erasure coded value and returns the value through the index.
+/// The data shards are read first, the parity shards only if a data shard is missing or corrupted,
+/// in which case the value is reconstructed from any data shards count of them.
+async fn get_erasure(
+    state: &AppGetState,
+    key: &str,
+    record: &record::Record,
+    erasure: &record::Erasure,
+) -> axum::response::Response {
+    let data_shards = erasure.data_shards as usize;
+    let mut shards = get_shards(state, key, erasure, 0..data_shards).await;
+    if shards.iter().any(Option::is_none) {
+        debug!("get_record: key: {} reading parity shards", key);
+        metrics::METRICS.erasure_reconstructions.inc();
+        shards.extend(get_shards(state, key, erasure, data_shards..erasure.shards.len()).await);
+    }
+
+    let available = shards.iter().filter(|shard| shard.is_some()).count();
+    if available < data_shards {
+        debug!(
+            "get_record: key: {} has {} of {} shards needed",
+            key, available, data_shards
+        );
+        return axum::http::Response::builder()
+            .status(axum::http::StatusCode::GONE)
+            .header(axum::http::header::CONTENT_LENGTH, "0")
+            .header("Key-Volumes", erasure.volumes().join(","))
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    let data_key = match data_key(state, key, record) {
+        Ok(data_key) => data_key,
+        Err(status) => {
+            return axum::http::Response::builder()
+                .status(status)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    };
+    shards.resize(erasure.shards.len(), None);
+    let size = record.stored_size();
+    let value = tokio::task::spawn_blocking(move || erasure::decode(shards, data_shards, size))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|value| value);
+    match value {
+        Ok(value) => {
+            metrics::METRICS
+                .proxied_bytes
+                .with_label_values(&["out"])
+                .inc_by(value.len() as u64);
+            let body = match data_key {
+                Some(data_key) => {
+                    let stored = futures::stream::iter([Ok(bytes::Bytes::from(value))]);
+                    axum::body::Body::from_stream(data_key.decrypt(stored))
+                }
+                None => axum::body::Body::from(value),
+            };
+            value_response(record).body(body).unwrap()
+        }
+        Err(e) => {
+            error!("get_record: failed to decode shards of {}: {}", key, e);
+            axum::http::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+/// Reads a range of the shards of an erasure coded value concurrently.
+/// Shards that can't be read or don't match their MD5 are None.
+async fn get_shards(
+    state: &AppGetState,
+    key: &str,
+    erasure: &record::Erasure,
+    indices: std::ops::Range<usize>,
+) -> Vec<Option<Vec<u8>>> {
+    let reads = indices.map(|index| {
+        let shard = &erasure.shards[index];
+        let remote_path = record::get_remote_path(&record::shard_key(key, index));
+        let remote_url = record::volume_url(&shard.volume, &remote_path);
+        async move {
+            let value = async {
+                let res = metrics::METRICS
+                    .time_volume_request("GET", state.client.get(&remote_url).send())
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, anyhow::Error>(res.bytes().await?)
+            };
+            match value.await {
+                Ok(value) if checksum::md5_hex(&value) == shard.hash => Some(value.to_vec()),
+                Ok(_) => {
+                    warn!(
+                        "get_record: shard {} of key {} is corrupted",
+                        remote_url, key
+                    );
+                    None
+                }
+                Err(e) => {
+                    warn!(
+                        "get_record: failed to read shard {} of key {}: {}",
+                        remote_url, key, e
+                    );
+                    None
+                }
+            }
+        }
+    });
+    futures::future::join_all(reads).await
+}
+
+/// Returns the builder of a 200 response with the headers describing the value of a record,
+/// its length, hash, ETag, Last-Modified, Content-Disposition and Content-Type.
+fn value_response(record: &record::Record) -> axum::http::response::Builder {
+    let mut response = axum::http::Response::builder()
+        .status(axum::http::StatusCode::OK)
+        .header(axum::http::header::CONTENT_LENGTH, record.size())
+        .header(record.hash_algorithm().hash_header_name(), record.hash());
+    if let Some(etag) = record.etag() {
+        response = response.header(axum::http::header::ETAG, etag);
+    }
+    if let Some(last_modified) = last_modified(record) {
+        response = response.header(axum::http::header::LAST_MODIFIED, last_modified);
+    }
+    if let Some(content_disposition) = record.content_disposition() {
+        response = response.header(axum::http::header::CONTENT_DISPOSITION, content_disposition);
+    }
+    if let Some(content_type) = record.content_type() {
+        response = response.header(axum::http::header::CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+/// Struct representing the metadata of a record returned by `GET /key?meta`.
+#[derive(Debug, serde::Serialize)]
+struct RecordMetadata<'a> {
+    key: &'a str,
+    hash: &'a str,
+    hash_algorithm: checksum::Algorithm,
+    size: u64,
+    /// Size of the value on the volumes, compressed or encrypted.
+    stored_size: u64,
+    content_type: Option<&'a str>,
+    content_disposition: Option<&'a str>,
+    /// Volumes the value is read from.
+    volumes: &'a [String],
+    /// Volumes the ring places the value on.
+    replica_volumes: Vec<String>,
+    /// Replica count of the key, None for the default of the server.
+    replicas: Option<usize>,
+    placement: Option<&'a str>,
+    /// `balanced` or `unbalanced` like the `Key-Balance` header, None for values rebalance skips.
+    balance: Option<&'static str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    parts: &'a [record::Part],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shards: Option<Vec<&'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blob: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refcount: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'a str>,
+    encrypted: bool,
+    deleted: record::Deleted,
+    deleted_at: Option<u64>,
+    expires_at: Option<u64>,
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+}
+
+/// Returns the metadata of the record of a key as JSON, whether it is live, deleted or expired, without
+/// reaching the volumes. See `RecordMetadata`.
+fn record_metadata(
+    state: &AppGetState,
+    key: &str,
+    record: &record::Record,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let replica_volumes =
+        state
+            .hashring
+            .read()
+            .get_volume_with_replicas(key, record.placement(), record.replicas());
+    // Like GET, multipart, erasure coded and deduplicated values aren't rebalanced
+    let rebalanced =
+        record.parts().is_empty() && record.erasure().is_none() && record.blob().is_none();
+    let balance =
+        rebalanced.then(
+            || match needs_rebalance(&replica_volumes, record.read_volumes()) {
+                true => "unbalanced",
+                false => "balanced",
+            },
+        );
+    let metadata = RecordMetadata {
+        key,
+        hash: record.hash(),
+        hash_algorithm: record.hash_algorithm(),
+        size: record.size(),
+        stored_size: record.stored_size(),
+        content_type: record.content_type(),
+        content_disposition: record.content_disposition(),
+        volumes: record.read_volumes(),
+        replica_volumes,
+        replicas: record.replicas(),
+        placement: record.placement(),
+        balance,
+        parts: record.parts(),
+        shards: record.erasure().map(|erasure| erasure.volumes()),
+        blob: record.blob(),
+        refcount: record.refcount(),
+        encoding: record.encoding().map(|encoding| encoding.name.as_str()),
+        encrypted: record.encryption().is_some(),
+        deleted: record.deleted(),
+        deleted_at: record.deleted_at(),
+        expires_at: record.expires_at(),
+        created_at: record.created_at(),
+        updated_at: record.updated_at(),
+    };
+    axum::Json(metadata).into_response()
+}
+
+/// Handles HEAD requests to check a record exists, answered from leveldb without contacting the volumes.
+/// Returns OK with the Content-Length, Content-Md5, ETag and Key-Volumes of the record,
+/// and the X-Refcount of content addressed values
+/// Returns NOT_MODIFIED if an `If-None-Match` header matches the ETag of the record,
+/// or without one if the record wasn't modified since an `If-Modified-Since` header
+/// Returns NOT_FOUND if the record is not found, deleted or expired
+/// Returns INTERNAL_SERVER_ERROR for internal server error
+pub(crate) async fn handle_head_record(
+    RecordKey(key): RecordKey,
+    axum::extract::State(state): axum::extract::State<Arc<AppGetState>>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    debug!("head_record: key: {}", key);
+
+    let record = match state.leveldb.get_record(&key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => record,
+        Ok(_) => {
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::NOT_FOUND)
+                .header(axum::http::header::CONTENT_LENGTH, "0")
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+        Err(e) => {
+            error!(
+                "head_record: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    if is_not_modified(&headers, &record) {
+        debug!("head_record: key: {} not modified", key);
+        return not_modified(&record);
+    }
+
+    // Erasure coded values list the volumes of their shards
+    let volumes = match record.erasure() {
+        Some(erasure) => erasure.volumes(),
+        None => record.read_volumes().iter().map(String::as_str).collect(),
+    };
+    let mut response = value_response(&record).header("Key-Volumes", volumes.join(","));
+    if let Some(refcount) = record.refcount() {
+        response = response.header(REFCOUNT, refcount);
+    }
+    response.body(axum::body::Body::empty()).unwrap()
+}
+
+/// Struct representing a page of keys, with a delimiter the keys containing it after the prefix are
+/// rolled up to their common prefixes, like the directories of a path. Next is empty on the last page.
+#[derive(Debug, serde::Serialize)]
+struct ListPage {
+    keys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefixes: Option<Vec<String>>,
+    next: String,
+}
+
+/// Lists the live keys starting with a prefix in sorted order, as their paths with a leading `/` like the original
+/// minikeyvalue. Keys of a namespace are listed without it, and only through their namespace.
+/// With a delimiter, e.g. `/`, the keys containing it after the prefix are listed once per common prefix
+/// up to the delimiter, e.g. `images/2024/` for `images/2024/cat.png` under `images/`.
+/// The `start` query parameter (inclusive), a path like `next`, and `limit` (default 1000) paginate the keys and prefixes.
+/// The scan seeks to the start and stops once the page is full, skipping the keys of a common prefix by seeking past them.
+/// Returns 200 with a page of keys as JSON, `{"keys": ["/key", ...], "next": ""}`, and `"prefixes": [...]` with a delimiter
+/// Returns 500 for internal server error
+async fn list_keys(
+    leveldb: Arc<record::LevelDb>,
+    prefix: String,
+    delimiter: Option<String>,
+    page: admin::PageParams,
+) -> axum::response::Response {
+    debug!("list_keys: prefix: {}", prefix);
+
+    admin::scan_to_json("list_keys", move || {
+        let limit = page.limit.unwrap_or(admin::DEFAULT_PAGE_LIMIT).max(1);
+        let start = page.start.unwrap_or_default();
+        let start = match start.strip_prefix('/') {
+            Some(key) => key.to_string(),
+            None => start,
+        };
+        let delimiter = delimiter.filter(|delimiter| !delimiter.is_empty());
+        let namespace = namespace::split(&prefix).map(|(name, _)| name.to_string());
+        let store_key = |key: &str| match &namespace {
+            Some(name) => namespace::key(name, key),
+            None => key.to_string(),
+        };
+        let listed = namespace::split(&prefix).map_or(prefix.as_str(), |(_, key)| key);
+
+        let now = record::unix_now();
+        let mut entries = Vec::new();
+        let mut from = store_key(&start);
+        loop {
+            let mut resume = None;
+            leveldb.for_each_record_from(&prefix, &from, |record| {
+                if !record.is_live(now) {
+                    return Ok(true);
+                }
+                let key = match namespace::split(record.key()) {
+                    Some((_, key)) if namespace.is_some() => key,
+                    None if namespace.is_none() => record.key(),
+                    _ => return Ok(true),
+                };
+                let common = delimiter.as_ref().and_then(|delimiter| {
+                    let at = key[listed.len()..].find(delimiter.as_str())?;
+                    Some(&key[..listed.len() + at + delimiter.len()])
+                });
+                // A common prefix before the start was listed by the previous page
+                if common.is_none_or(|common| *common >= *start) {
+                    let entry = common.unwrap_or(key);
+                    entries.push((format!("/{}", entry), common.is_some()));
+                }
+                if let Some(common) = common {
+                    resume = Some(successor(common));
+                    return Ok(false);
+                }
+                Ok(entries.len() <= limit)
+            })?;
+            match resume {
+                Some(Some(key)) if entries.len() <= limit => from = store_key(&key),
+                _ => break,
+            }
+        }
+
+        let next = match entries.len() > limit {
+            true => entries.pop().map(|(entry, _)| entry).unwrap_or_default(),
+            false => String::new(),
+        };
+        let (prefixes, keys): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|(_, is_prefix)| *is_prefix);
+        Ok(ListPage {
+            keys: keys.into_iter().map(|(key, _)| key).collect(),
+            prefixes: delimiter.map(|_| prefixes.into_iter().map(|(prefix, _)| prefix).collect()),
+            next,
+        })
+    })
+    .await
+}
+
+/// Returns the smallest string greater than every string starting with a prefix, ending the range of its keys.
+/// Returns None if no string is, e.g. for a prefix of `char::MAX` only.
+fn successor(prefix: &str) -> Option<String> {
+    let mut successor = prefix.to_string();
+    while let Some(last) = successor.pop() {
+        let next = match last {
+            '\u{D7FF}' => Some('\u{E000}'),
+            _ => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            successor.push(next);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+/// Checks if the number of replicas volumes is different from the number of record read volumes
+/// and returns true if they are different, false otherwise
+fn needs_rebalance(replicas_volumes: &[String], record_read_volumes: &[String]) -> bool {
+    replicas_volumes.len() != record_read_volumes.len()
+}
+
+/// Deletes a value from a remote volume using reqwest, a value already missing is not an error.
+pub(crate) async fn remote_delete(
+    client: &reqwest::Client,
+    remote_url: &str,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "chaos")]
+    chaos::inject_volume_fault(remote_url).await?;
+
+    let res = metrics::METRICS
+        .time_volume_request("DELETE", client.delete(remote_url).send())
+        .await?;
+    if res.status().is_success() || res.status() == reqwest::StatusCode::NOT_FOUND {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "remote_delete: failed to delete {}: {}",
+            remote_url,
+            res.status()
+        ))
+    }
+}
+
+/// Checks if a record exists in a remote volume using reqwest
+pub(crate) async fn remote_head(client: &reqwest::Client, remote_url: &str) -> anyhow::Result<()> {
+    breaker::BREAKERS.allow(remote_url)?;
+    let result = send_remote_head(client, remote_url).await;
+    breaker::BREAKERS.record(remote_url, &result);
+    result
+}
+
+/// Sends the HEAD request of a value to a remote volume.
+async fn send_remote_head(client: &reqwest::Client, remote_url: &str) -> anyhow::Result<()> {
+    #[cfg(feature = "chaos")]
+    chaos::inject_volume_fault(remote_url).await?;
+
+    let res = metrics::METRICS
+        .time_volume_request("HEAD", client.head(remote_url).send())
+        .await?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(VolumeStatusError {
+            url: remote_url.to_string(),
+            status: res.status(),
+        }
+        .into())
+    }
+}
+
+/// Returns the size of a value in a remote volume from the Content-Length of a HEAD.
+async fn remote_size(client: &reqwest::Client, remote_url: &str) -> anyhow::Result<u64> {
+    let res = metrics::METRICS
+        .time_volume_request("HEAD", client.head(remote_url).send())
+        .await?;
+    if !res.status().is_success() {
+        return Err(VolumeStatusError {
+            url: remote_url.to_string(),
+            status: res.status(),
+        }
+        .into());
+    }
+    res.headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("no Content-Length for {}", remote_url))
+}
+
+/// Reads a value from a remote volume as it streams, returning its size and digests.
+async fn hash_upload(
+    client: &reqwest::Client,
+    remote_url: &str,
+    algorithms: &[checksum::Algorithm],
+) -> anyhow::Result<(u64, Vec<(checksum::Algorithm, Vec<u8>)>)> {
+    let res = metrics::METRICS
+        .time_volume_request("GET", client.get(remote_url).send())
+        .await?;
+    if !res.status().is_success() {
+        return Err(VolumeStatusError {
+            url: remote_url.to_string(),
+            status: res.status(),
+        }
+        .into());
+    }
+    let mut hasher = checksum::Hasher::new(algorithms);
+    let mut size = 0;
+    let mut stream = res.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+    Ok((size, hasher.finalize()))
+}
+
+/// Handles COPY and MOVE requests of a key, WebDAV style: the `Destination` header names the key the value is
+/// copied to, as the path of its route or a URL of the index, e.g. `/dst` or `http://localhost:3000/ns/app/dst`.
+/// A plain destination key is in the namespace of the `X-Namespace` header, if any, like the source.
+/// The destination gets the value, Content-Type and Content-Disposition of the source, and expires after the
+/// seconds of an `X-Ttl` header, if any. MOVE then soft deletes the source, like a DELETE.
+/// A replicated value is copied by the volumes holding it, see `copy_on_volumes`. Multipart, erasure coded and
+/// deduplicated values, and values the volumes fail to copy, are streamed once through the index instead, like
+/// a GET of the source and a PUT of the destination.
+/// Returns 201 if the destination is created
+/// Returns 400 if the Destination is missing, invalid or the source, or the X-Ttl is invalid
+/// Returns 404 if the source is missing, deleted or expired
+/// Returns 405 for methods other than COPY and MOVE
+/// Returns 409 if the destination exists or either key stays locked by another write past the lock timeout
+/// Returns 413 if the value exceeds the max value size
+/// Returns 507 if the value would exceed the quota of the namespace of the destination
+/// Returns 500 for internal server error
+pub(crate) async fn handle_copy_record(
+    RecordKey(key): RecordKey,
+    axum::extract::State(state): axum::extract::State<Arc<AppCopyState>>,
+    method: axum::http::Method,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let delete_source = match method.as_str() {
+        "COPY" => false,
+        "MOVE" => true,
+        _ => return StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    };
+    let destination = match destination_key(&headers) {
+        Ok(destination) => destination,
+        Err(status) => {
+            debug!("copy_record: key: {} invalid Destination", key);
+            return status.into_response();
+        }
+    };
+    if let Err(e) = state.put.key_policy.check(&destination) {
+        debug!(
+            "copy_record: destination: {:?} rejected: {}",
+            destination, e
+        );
+        return e.into_response();
+    }
+    if destination == key
+        || destination.starts_with(record::RESERVED_PREFIX)
+        || destination.starts_with(CAS_PREFIX)
+    {
+        debug!(
+            "copy_record: key: {} invalid destination {}",
+            key, destination
+        );
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Ok(expires_at) = ttl_expires_at(&headers) else {
+        debug!("copy_record: key: {} invalid X-Ttl", key);
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    debug!(
+        "copy_record: {} key: {} destination: {}",
+        method, key, destination
+    );
+
+    let copy = copy_record(
+        state.clone(),
+        key.clone(),
+        destination,
+        expires_at,
+        delete_source,
+    );
+    match state.put.writes.spawn(copy).await {
+        Ok(status) => status.into_response(),
+        Err(e) => {
+            error!("copy_record: copy of key {} failed: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Returns the leveldb key named by the `Destination` header of a COPY or MOVE, percent-decoded.
+/// Returns 400 if the header is missing or doesn't name a key.
+fn destination_key(headers: &axum::http::HeaderMap) -> Result<String, StatusCode> {
+    let uri = headers
+        .get(DESTINATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<axum::http::Uri>().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let path = percent_encoding::percent_decode_str(uri.path())
+        .decode_utf8()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let path = path.strip_prefix('/').ok_or(StatusCode::BAD_REQUEST)?;
+    let (key, name) = match path.strip_prefix("ns/") {
+        Some(namespaced) => {
+            let (name, key) = namespaced.split_once('/').ok_or(StatusCode::BAD_REQUEST)?;
+            (key, Some(name.to_string()))
+        }
+        None => (path, namespace_header(headers)?),
+    };
+    if key.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    RecordKey::resolve(key.to_string(), name).map(|RecordKey(key)| key)
+}
+
+/// Copies the value of a key to the destination key, then soft deletes the key for a MOVE.
+/// Returns the status of the COPY or MOVE request.
+async fn copy_record(
+    state: Arc<AppCopyState>,
+    key: String,
+    destination: String,
+    expires_at: Option<u64>,
+    delete_source: bool,
+) -> StatusCode {
+    let source = match state.put.leveldb.get_record(&key).await {
+        Ok(Some(record)) if record.is_live(record::unix_now()) => record,
+        Ok(_) => return StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(
+                "copy_record: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    if state
+        .put
+        .max_value_size
+        .is_some_and(|max_size| source.size() > max_size)
+    {
+        debug!("copy_record: key: {} exceeds the max value size", key);
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+    let replicated = source.parts().is_empty()
+        && source.erasure().is_none()
+        && source.blob().is_none()
+        && source.refcount().is_none();
+    let copied = match replicated {
+        true => copy_on_volumes(&state.put, &key, &destination, expires_at).await,
+        false => None,
+    };
+    let status = match copied {
+        Some(status) => status,
+        None => proxy_copy(&state, &key, &destination, &source, expires_at).await,
+    };
+    if status != StatusCode::CREATED || !delete_source {
+        return status;
+    }
+
+    let response = soft_delete_record(state.delete.clone(), key.clone()).await;
+    match response.status() {
+        StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => StatusCode::CREATED,
+        status => {
+            error!(
+                "copy_record: key: {} copied to {} but not deleted: {}",
+                key, destination, status
+            );
+            status
+        }
+    }
+}
+
+/// Copies a replicated value with a WebDAV COPY on every volume holding it, so the value never leaves the
+/// volumes. The destination record lists the same volumes, rebalance moves it to its replicas in the ring.
+/// Returns the status of the copy, None if too few volumes copied the value and it must be streamed instead.
+async fn copy_on_volumes(
+    state: &AppPutState,
+    key: &str,
+    destination: &str,
+    expires_at: Option<u64>,
+) -> Option<StatusCode> {
+    let Some(_source_guard) = state.key_locks.lock(key).await else {
+        debug!("copy_record: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return Some(StatusCode::CONFLICT);
+    };
+    let Some(_guard) = state.key_locks.lock(destination).await else {
+        debug!("copy_record: key: {} still locked, giving up", destination);
+        metrics::METRICS.lock_conflicts.inc();
+        return Some(StatusCode::CONFLICT);
+    };
+
+    // The records are read again under the locks, the source may have been replaced since
+    let now = record::unix_now();
+    let (source, current) = match (
+        state.leveldb.get_record(key).await,
+        state.leveldb.get_record_or_default(destination).await,
+    ) {
+        (Ok(Some(source)), Ok(current)) => (source, current),
+        (Ok(None), _) => return Some(StatusCode::NOT_FOUND),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("copy_record: failed to get records from leveldb: {}", e);
+            return Some(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if !source.is_live(now) {
+        return Some(StatusCode::NOT_FOUND);
+    }
+    if current.is_live(now) {
+        debug!("copy_record: destination: {} exists", destination);
+        return Some(StatusCode::CONFLICT);
+    }
+    let _reservation = match reserve_quota(&state.leveldb, destination, source.size()).await {
+        Ok(reservation) => reservation,
+        Err(status) => return Some(status),
+    };
+
+    let remote_path = source.remote_path(key);
+    let copy_path = record::get_remote_path(destination);
+    let mut copied = Vec::new();
+    for volume in source.read_volumes().iter() {
+        let remote_url = record::volume_url(volume, &remote_path);
+        let copy_url = record::volume_url(volume, &copy_path);
+        match remote_copy(&state.client, &remote_url, &copy_url).await {
+            Ok(()) => copied.push(volume.clone()),
+            Err(e) => warn!("copy_record: failed to copy {}: {}", remote_url, e),
+        }
+    }
+    let quorum = state.write_quorum.min(source.read_volumes().len());
+    if copied.is_empty() || copied.len() < quorum {
+        debug!(
+            "copy_record: key: {} copied on {} of {} volumes, streaming it",
+            key,
+            copied.len(),
+            quorum
+        );
+        for volume in copied.iter() {
+            let copy_url = record::volume_url(volume, &copy_path);
+            if let Err(e) = remote_delete(&state.client, &copy_url).await {
+                warn!("copy_record: failed to delete {}: {}", copy_url, e);
+            }
+        }
+        return None;
+    }
+
+    let record = source
+        .clone()
+        .with_expires_at(expires_at)
+        .with_timestamps(now, now)
+        .with_read_volumes(copied);
+    if let Err(e) = state.leveldb.put_record(destination, record).await {
+        error!(
+            "copy_record: failed to put record {} in leveldb: {}",
+            destination, e
+        );
+        return Some(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Some(StatusCode::CREATED)
+}
+
+/// Copies a value by streaming it through the index, like a proxied GET of the key and a PUT of the destination.
+/// Returns the status of the PUT, or of the GET if it fails.
+async fn proxy_copy(
+    state: &AppCopyState,
+    key: &str,
+    destination: &str,
+    source: &record::Record,
+    expires_at: Option<u64>,
+) -> StatusCode {
+    use axum::response::IntoResponse;
+
+    let response = handle_get_record(
+        RecordKey(key.to_string()),
+        axum::extract::State(state.get.clone()),
+        axum::extract::Query(GetParams::proxied()),
+        axum::extract::Query(admin::PageParams::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    if response.status() != StatusCode::OK {
+        debug!(
+            "copy_record: key: {} failed to read: {}",
+            key,
+            response.status()
+        );
+        return response.status();
+    }
+
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_LENGTH,
+        axum::http::HeaderValue::from(source.size()),
+    );
+    let stored_headers = [
+        (axum::http::header::CONTENT_TYPE, source.content_type()),
+        (
+            axum::http::header::CONTENT_DISPOSITION,
+            source.content_disposition(),
+        ),
+    ];
+    for (name, value) in stored_headers {
+        if let Some(value) = value.and_then(|value| value.parse().ok()) {
+            headers.insert(name, value);
+        }
+    }
+    if let Some(replicas) = source.replicas() {
+        headers.insert(REPLICAS, axum::http::HeaderValue::from(replicas));
+    }
+    if let Some(expires_at) = expires_at {
+        let ttl = expires_at.saturating_sub(record::unix_now()).max(1);
+        headers.insert(TTL, axum::http::HeaderValue::from(ttl));
+    }
+    handle_put_record(
+        RecordKey(destination.to_string()),
+        axum::extract::State(state.put.clone()),
+        axum::extract::Query(PutParams::default()),
+        headers,
+        response.into_body(),
+    )
+    .await
+    .into_response()
+    .status()
+}
+
+/// Handles DELETE requests to delete a record.
+/// Returns 204 if the record is deleted
+/// Returns 404 if the record is not found
+/// Returns 409 if the record key stays locked by another PUT/DELETE past the lock timeout
+/// Returns 500 for internal server error
+pub(crate) async fn handle_delete_record(
+    RecordKey(key): RecordKey,
+    axum::extract::State(state): axum::extract::State<Arc<AppDeleteState>>,
+) -> axum::response::Response {
+    debug!("delete_record: key: {}", key);
+
+    let delete = soft_delete_record(state.clone(), key.clone());
+    match state.writes.spawn(delete).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("delete_record: write for key {} failed: {}", key, e);
+            axum::http::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+/// Locks the key and marks its record as soft deleted in leveldb.
+/// Returns the response of the DELETE request.
+async fn soft_delete_record(state: Arc<AppDeleteState>, key: String) -> axum::response::Response {
+    let Some(_guard) = state.key_locks.lock(&key).await else {
+        debug!("delete_record: key: {} still locked, giving up", key);
+        metrics::METRICS.lock_conflicts.inc();
+        return axum::http::Response::builder()
+            .status(axum::http::StatusCode::CONFLICT)
+            .body(axum::body::Body::empty())
+            .unwrap();
+    };
+
+    let record = match state.leveldb.get_record_or_default(&key).await {
+        Ok(record) => record,
+        Err(e) => {
+            error!(
+                "delete_record: failed to get record {} from leveldb: {}",
+                key, e
+            );
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
+
+    if record.deleted() == record::Deleted::Hard || record.deleted() == record::Deleted::Soft {
+        debug!("delete_record: key: {} already deleted", key);
+        return axum::http::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::empty())
+            .unwrap();
+    }
+
+    // A content addressed value uploaded several times stays until its last reference is deleted
+    let deleted_record = match record.refcount() {
+        Some(refcount) if refcount > 1 => {
+            debug!("delete_record: key: {} refcount: {}", key, refcount - 1);
+            record.with_refcount(Some(refcount - 1))
+        }
+        _ => record
+            .with_deleted(record::Deleted::Soft)
+            .with_deleted_at(Some(record::unix_now())),
+    };
+    match state.leveldb.put_record(&key, deleted_record).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!(
+                "delete_record: failed to put deleted record {} in leveldb: {}",
+                key, e
+            );
+            return axum::http::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    }
+
+    axum::http::Response::builder()
+        .status(axum::http::StatusCode::NO_CONTENT)
+        .body(axum::body::Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+
+    #[tokio::test]
+    async fn test_put_get_checksum_algorithm() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("checksummed");
+        let sha256 = checksum::Algorithm::Sha256.compute(b"onyou");
+
+        let res = client
+            .put(&url)
+            .header(
+                "X-Checksum-Sha256",
+                checksum::Algorithm::Sha256.compute(b"other"),
+            )
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let res = client
+            .put(&url)
+            .header(checksum::X_CHECKSUM_ALGORITHM, "crc32")
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = client
+            .put(&url)
+            .header("X-Checksum-Sha256", &sha256)
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client
+            .get(&url)
+            .header(checksum::X_CHECKSUM_ALGORITHM, "sha256")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(res.headers()["x-checksum-sha256"], sha256.as_str());
+        assert_eq!(res.headers()["content-length"], "0");
+        assert_eq!(res.headers()[VALUE_SIZE], "5");
+
+        let res = client.get(&url).send().await?;
+        assert!(res.headers().get("x-checksum-sha256").is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_streamed_body() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("streamed");
+        let chunks: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 64 * 1024]).collect();
+        let value = chunks.concat();
+
+        let body = futures::stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+        let res = client
+            .put(&url)
+            .header(reqwest::header::CONTENT_LENGTH, value.len())
+            .header(
+                "X-Checksum-Sha256",
+                checksum::Algorithm::Sha256.compute(&value),
+            )
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["content-length"], value.len().to_string());
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.bytes().await?, value);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_value_size() -> anyhow::Result<()> {
+        let cluster =
+            TestCluster::start_with_config(3, 2, |config| config.max_value_size = Some(8)).await?;
+        let client = reqwest::Client::new();
+        let res = client
+            .put(cluster.key_url("fits"))
+            .body("8 bytes!")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client
+            .put(cluster.key_url("large"))
+            .body("nine byte")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a Content-Length the body is cut off once it exceeds the max value size
+        let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(vec![b'x'; 4]));
+        let res = client
+            .put(cluster.key_url("streamed"))
+            .header(reqwest::header::TRAILER, checksum::CONTENT_MD5)
+            .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = client
+            .post(cluster.key_url(CAS_KEY))
+            .body("nine byte")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Parts fit on their own but not stitched together
+        let url = cluster.key_url("multipart");
+        for number in [1, 2] {
+            let res = client
+                .put(format!("{}?partNumber={}", url, number))
+                .body("5 byte")
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = client
+            .post(format!("{}?uploads=complete", url))
+            .body("[1, 2]")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        for key in ["large", "streamed", "multipart"] {
+            let res = client.get(cluster.key_url(key)).send().await?;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let res = client
+            .put(cluster.key_url("metered"))
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client
+            .get(format!("{}/metrics", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let metrics = res.text().await?;
+        assert!(metrics.contains("mkv_requests_total{method=\"PUT\",status=\"201\"}"));
+        assert!(metrics.contains("mkv_volume_request_duration_seconds_count{method=\"PUT\"}"));
+        assert!(metrics.contains("mkv_proxied_bytes_total{direction=\"in\"}"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_quorum() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_write_quorum(3, 3, 2).await?;
+        let client = reqwest::Client::new();
+        cluster.volume(0).set_unavailable(true);
+
+        let res = client
+            .put(cluster.key_url("quorum"))
+            .body("met")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(cluster.key_url("quorum")).send().await?;
+        assert_eq!(res.text().await?, "met");
+        assert!(cluster.volume(0).is_empty());
+
+        // The missed replica is repaired once its volume is back
+        cluster.volume(0).set_unavailable(false);
+        for _ in 0..50 {
+            if !cluster.volume(0).is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(cluster.volume(0).len(), 1);
+
+        // The replication queue is kept under a reserved prefix that keys can't use
+        let res = client
+            .put(cluster.key_url("%00replication%2Fquorum"))
+            .body("queued")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        cluster.volume(0).set_unavailable(true);
+        cluster.volume(1).set_unavailable(true);
+        let res = client
+            .put(cluster.key_url("missed"))
+            .body("no")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_quorum_failed_move() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_write_quorum(3, 3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("quorum");
+        let res = client.put(&url).body("old").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let etag = format!("\"{:x}\"", md5::compute("old"));
+
+        // The overwrite reached the quorum but moved none of its uploads into place
+        for index in 0..3 {
+            cluster.volume(index).set_failing_moves(true);
+        }
+        let res = client
+            .put(&url)
+            .header("If-Match", &etag)
+            .body("new")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.text().await?, "old");
+
+        // Moved into place on fewer replicas than the quorum, the overwrite commits anyway
+        cluster.volume(0).set_failing_moves(false);
+        let res = client
+            .put(&url)
+            .header("If-Match", &etag)
+            .body("new")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.text().await?, "new");
+
+        // The replicas whose move failed are repaired
+        cluster.volume(1).set_failing_moves(false);
+        cluster.volume(2).set_failing_moves(false);
+        let remote_path = record::get_remote_path("quorum");
+        let repaired = || {
+            (0..3).all(|index| {
+                let volume = cluster.volume(index);
+                let paths = volume.paths();
+                paths.len() == 1
+                    && paths[0].ends_with(&remote_path)
+                    && volume.get(&paths[0]).as_deref() == Some(&b"new"[..])
+            })
+        };
+        for _ in 0..50 {
+            if repaired() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(repaired());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replicas_header() -> anyhow::Result<()> {
+        let cluster =
+            TestCluster::start_with_config(5, 2, |config| config.max_replicas = Some(3)).await?;
+        let client = reqwest::Client::new();
+
+        for (key, replicas, copies) in [
+            ("bulky", Some("1"), 1),
+            ("default", None, 2),
+            ("important", Some("3"), 3),
+        ] {
+            let mut req = client.put(cluster.key_url(key)).body(key);
+            if let Some(replicas) = replicas {
+                req = req.header(REPLICAS, replicas);
+            }
+            assert_eq!(req.send().await?.status(), StatusCode::CREATED);
+            let remote_path = record::get_remote_path(key);
+            let stored = (0..5)
+                .flat_map(|index| cluster.volume(index).paths())
+                .filter(|path| path.ends_with(&remote_path))
+                .count();
+            assert_eq!(stored, copies, "key: {}", key);
+
+            let res = client.head(cluster.key_url(key)).send().await?;
+            assert_eq!(
+                res.headers()["Key-Volumes"].to_str()?.split(',').count(),
+                copies
+            );
+            let res = client.get(cluster.key_url(key)).send().await?;
+            assert_eq!(res.text().await?, key);
+        }
+
+        for replicas in ["0", "4", "many"] {
+            let res = client
+                .put(cluster.key_url("invalid"))
+                .header(REPLICAS, replicas)
+                .body("no")
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_erasure_coding() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(6, 1, |config| {
+            config.erasure = Some(erasure::ErasureConfig {
+                data_shards: 4,
+                parity_shards: 2,
+                min_size: 64,
+            })
+        })
+        .await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let value = "an archival object, large enough to be erasure coded".repeat(4);
+
+        let res = client
+            .put(cluster.key_url("archive"))
+            .body(value.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        // One shard on every volume
+        assert!((0..6).all(|index| cluster.volume(index).len() == 1));
+        let res = client.head(cluster.key_url("archive")).send().await?;
+        let volumes = res.headers()["Key-Volumes"].to_str()?.to_string();
+        let volumes: Vec<&str> = volumes.split(',').collect();
+        assert_eq!(volumes.len(), 6);
+
+        let res = client.get(cluster.key_url("archive")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, value);
+
+        // A corrupted data shard and a lost volume are reconstructed from the parity shards
+        let shard_url = record::volume_url(
+            volumes[0],
+            &record::get_remote_path(&record::shard_key("archive", 0)),
+        );
+        client.put(shard_url).body("corrupted").send().await?;
+        let lost = cluster
+            .volume_addrs()
+            .iter()
+            .position(|volume| volumes[1].starts_with(volume.as_str()))
+            .unwrap();
+        cluster.volume(lost).clear();
+        let res = client.get(cluster.key_url("archive")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, value);
+
+        let lost = cluster
+            .volume_addrs()
+            .iter()
+            .position(|volume| volumes[2].starts_with(volume.as_str()))
+            .unwrap();
+        cluster.volume(lost).clear();
+        let res = client.get(cluster.key_url("archive")).send().await?;
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        // Small values are replicated
+        let res = client
+            .put(cluster.key_url("small"))
+            .body("tiny")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(cluster.key_url("small")).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_erasure_coding_failed_overwrite() -> anyhow::Result<()> {
+        let large = "an archival object, large enough to be erasure coded".repeat(4);
+        // Overwrites are staged, the shards of an old value and the replica of a small one are kept
+        for (two_phase_writes, old) in [
+            (true, large.clone()),
+            (false, large.clone()),
+            (false, "tiny".to_string()),
+        ] {
+            let cluster = TestCluster::start_with_config(3, 1, |config| {
+                config.two_phase_writes = two_phase_writes;
+                config.write_retry.attempts = 1;
+                config.erasure = Some(erasure::ErasureConfig {
+                    data_shards: 2,
+                    parity_shards: 1,
+                    min_size: 64,
+                })
+            })
+            .await?;
+            let client = reqwest::Client::new();
+            let url = cluster.key_url("archive");
+            let res = client.put(&url).body(old.clone()).send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            let res = client.head(&url).send().await?;
+            let etag = res.headers()["etag"].clone();
+            let stored: usize = (0..3).map(|index| cluster.volume(index).len()).sum();
+
+            cluster.volume(1).set_unavailable(true);
+            let res = client
+                .put(&url)
+                .header("If-Match", &etag)
+                .body(large.replace("archival", "replaced"))
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            cluster.volume(1).set_unavailable(false);
+            // The shards of the failed PUT are deleted
+            let left: usize = (0..3).map(|index| cluster.volume(index).len()).sum();
+            assert_eq!(left, stored);
+
+            let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["etag"], etag);
+            assert_eq!(res.text().await?, old);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_erasure_coding_shard_key_collision() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(3, 3, |config| {
+            config.erasure = Some(erasure::ErasureConfig {
+                data_shards: 2,
+                parity_shards: 1,
+                min_size: 64,
+            })
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        // Keys spelling a shard of another key hold values of their own
+        for index in 0..3 {
+            let url = cluster.key_url(&format!("archive%3Fshard={}", index));
+            let res = client
+                .put(&url)
+                .body(format!("mine {}", index))
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let large = "an archival object, large enough to be erasure coded".repeat(4);
+        let res = client
+            .put(cluster.key_url("archive"))
+            .body(large.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client
+            .get(cluster.key_url("archive"))
+            .query(&[("proxy", "1")])
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, large);
+        for index in 0..3 {
+            let url = cluster.key_url(&format!("archive%3Fshard={}", index));
+            let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.text().await?, format!("mine {}", index));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_tries_every_replica() -> anyhow::Result<()> {
+        for concurrent_heads in [false, true] {
+            let cluster = TestCluster::start_with_config(4, 2, |config| {
+                config.concurrent_heads = concurrent_heads
+            })
+            .await?;
+            let client = reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?;
+            let res = client
+                .put(cluster.key_url("survivor"))
+                .body("last")
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+
+            // Every replica but one lost the value, the first GET finds it before read repair runs
+            let holding: Vec<usize> = (0..4).filter(|i| !cluster.volume(*i).is_empty()).collect();
+            for i in holding.iter().skip(1) {
+                cluster.volume(*i).clear();
+            }
+            let res = client.get(cluster.key_url("survivor")).send().await?;
+            assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+            let location = res.headers()[axum::http::header::LOCATION].to_str()?;
+            assert!(location.contains(&format!("{}/", cluster.volume_addrs()[holding[0]])));
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            for i in 0..4 {
+                cluster.volume(i).clear();
+            }
+            let res = client.get(cluster.key_url("survivor")).send().await?;
+            assert_eq!(res.status(), StatusCode::GONE);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_repair() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(4, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let res = client
+            .put(cluster.key_url("healed"))
+            .body("again")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let lost = (0..4).find(|i| !cluster.volume(*i).is_empty()).unwrap();
+        cluster.volume(lost).clear();
+
+        // GETs redirecting to a random replica find the lost one and repair it in the background
+        for _ in 0..50 {
+            if !cluster.volume(lost).is_empty() {
+                break;
+            }
+            client.get(cluster.key_url("healed")).send().await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(cluster.volume(lost).len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_two_phase_writes() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_two_phase_writes(3, 2).await?;
+        let client = reqwest::Client::new();
+        let remote_path = record::get_remote_path("committed");
+
+        let res = client
+            .put(cluster.key_url("committed"))
+            .body("moved")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(cluster.key_url("committed")).send().await?;
+        assert_eq!(res.text().await?, "moved");
+        let paths: Vec<String> = (0..3).flat_map(|i| cluster.volume(i).paths()).collect();
+        assert!(paths.len() >= 2);
+        assert!(paths.iter().all(|path| path.ends_with(&remote_path)));
+
+        // A replica failing leaves nothing on the other one, not even its upload
+        let hashring = hashring::Ring::new(cluster.volume_addrs().to_vec(), 2, 10);
+        let key = (0..)
+            .map(|i| format!("discarded-{}", i))
+            .find(|key| hashring.get_volume(key)[0].starts_with(&cluster.volume_addrs()[0]))
+            .unwrap();
+        cluster.volume(0).set_unavailable(true);
+        let res = client
+            .put(cluster.key_url(&key))
+            .body("never")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let paths: Vec<String> = (1..3).flat_map(|i| cluster.volume(i).paths()).collect();
+        assert!(paths.iter().all(|path| path.ends_with(&remote_path)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_proxy() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("proxied");
+        let res = client
+            .put(&url)
+            .header("Content-Disposition", "inline")
+            .body("through")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["Content-Disposition"], "inline");
+        assert_eq!(res.content_length(), Some(7));
+        assert_eq!(res.text().await?, "through");
+
+        let res = client.get(&url).query(&[("proxy", "0")]).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_redirect_status() -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        for (redirect_status, cache_control, status) in [
+            (RedirectStatus::default(), Some("no-store"), 307),
+            (RedirectStatus::Found, None, 302),
+            (RedirectStatus::Permanent, Some("max-age=60"), 308),
+        ] {
+            let cluster = TestCluster::start_with_config(3, 2, |config| {
+                config.redirect_status = redirect_status;
+                config.redirect_cache_control = cache_control.map(String::from);
+            })
+            .await?;
+            let url = cluster.key_url("redirected");
+            let res = client.put(&url).body("value").send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+
+            let res = client.get(&url).send().await?;
+            assert_eq!(res.status().as_u16(), status);
+            assert_eq!(
+                res.headers()
+                    .get("Cache-Control")
+                    .map(|value| value.to_str().unwrap()),
+                cache_control
+            );
+            // Clients following the redirect get the value whatever its status
+            let res = reqwest::get(&url).await?;
+            assert_eq!(res.text().await?, "value");
+        }
+
+        let mut config = Config {
+            volumes: vec!["localhost:3001".to_string()],
+            replicas: 1,
+            ..Default::default()
+        };
+        config.validate()?;
+        config.redirect_cache_control = Some("no\nstore".to_string());
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_value_cache() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(3, 2, |config| {
+            config.value_cache = Some(value_cache::ValueCacheConfig {
+                capacity: 1024,
+                max_value_size: 8,
+                ttl: Duration::from_secs(60),
+            })
+        })
+        .await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("thumbnail");
+        let res = client
+            .put(&url)
+            .header("Content-Type", "image/png")
+            .body("small")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["Content-Type"], "image/png");
+        assert_eq!(res.text().await?, "small");
+
+        // Redirected if asked to, or for a range
+        let res = client.get(&url).query(&[("proxy", "0")]).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let res = client.get(&url).header("Range", "bytes=0-1").send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        // Served from memory once cached
+        for index in 0..3 {
+            cluster.volume(index).clear();
+        }
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "small");
+
+        // A value written again is read again from the volumes
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.put(&url).body("smaller").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.text().await?, "smaller");
+
+        // Larger values are redirected
+        let url = cluster.key_url("large");
+        let res = client.put(&url).body("too large").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_get_content_disposition() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("download");
+
+        let res = client
+            .put(&url)
+            .header("Content-Disposition", "attachment; filename=\"cat.png\"")
+            .body("meow")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            res.headers()["content-disposition"],
+            "attachment; filename=\"cat.png\""
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_content_md5() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .put(cluster.key_url("corrupt"))
+            .header("Content-Md5", checksum::md5_hex(b"onyov"))
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!((0..3).all(|i| cluster.volume(i).is_empty()));
+
+        let res = client
+            .put(cluster.key_url("corrupt"))
+            .header("Content-Md5", "not-a-digest")
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let digest = md5::compute(b"onyou");
+        let res = client
+            .put(cluster.key_url("intact"))
+            .header(
+                "Content-Md5",
+                base64::Engine::encode(&base64::engine::general_purpose::STANDARD, digest.0),
+            )
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_get_content_type() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("typed");
+
+        let res = client
+            .put(&url)
+            .header("Content-Type", "image/png")
+            .body("png")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(res.headers()["content-type"], "image/png");
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["content-type"], "image/png");
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.headers()["content-type"], "image/png");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_if_none_match() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("cached");
+
+        let res = client.put(&url).body("cached").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let etag = format!("\"{:x}\"", md5::compute("cached"));
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(res.headers()["etag"], etag.as_str());
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["etag"], etag.as_str());
+
+        for if_none_match in [etag.clone(), format!("\"other\", W/{}", etag), "*".into()] {
+            let res = client
+                .get(&url)
+                .header("If-None-Match", &if_none_match)
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(res.headers()["etag"], etag.as_str());
+            assert!(res.headers().get("location").is_none());
+        }
+        let res = client
+            .head(&url)
+            .header("If-None-Match", &etag)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = client
+            .get(&url)
+            .header("If-None-Match", "\"other\"")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_if_modified_since() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("dated");
+
+        let res = client.put(&url).body("dated").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let last_modified = res.headers()["last-modified"].to_str()?.to_string();
+        let modified_at = httpdate::parse_http_date(&last_modified)?;
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["last-modified"], last_modified.as_str());
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.headers()["last-modified"], last_modified.as_str());
+
+        let later = httpdate::fmt_http_date(modified_at + Duration::from_secs(60));
+        for if_modified_since in [last_modified.clone(), later] {
+            let res = client
+                .get(&url)
+                .header("If-Modified-Since", &if_modified_since)
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(res.headers()["last-modified"], last_modified.as_str());
+        }
+        let res = client
+            .head(&url)
+            .header("If-Modified-Since", &last_modified)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let earlier = httpdate::fmt_http_date(modified_at - Duration::from_secs(60));
+        let res = client
+            .get(&url)
+            .header("If-Modified-Since", earlier)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        // If-None-Match takes precedence and a malformed date is ignored
+        let res = client
+            .get(&url)
+            .header("If-None-Match", "\"other\"")
+            .header("If-Modified-Since", &last_modified)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let res = client
+            .get(&url)
+            .header("If-Modified-Since", "yesterday")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_if_match() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("versioned");
+        let etag = |value: &str| format!("\"{:x}\"", md5::compute(value));
+
+        let res = client
+            .put(&url)
+            .header("If-Match", "*")
+            .body("v1")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let res = client
+            .put(&url)
+            .header("If-None-Match", "*")
+            .body("v1")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client
+            .put(&url)
+            .header("If-None-Match", "*")
+            .body("v1")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        for if_match in ["\"other\"".to_string(), format!("W/{}", etag("v1"))] {
+            let res = client
+                .put(&url)
+                .header("If-Match", if_match)
+                .body("v2")
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        }
+        let res = client
+            .put(&url)
+            .header("If-Match", format!("\"other\", {}", etag("v1")))
+            .body("v2")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.headers()["etag"], etag("v2").as_str());
+        assert_eq!(res.text().await?, "v2");
+
+        let res = client
+            .put(&url)
+            .header("If-Match", etag("v1"))
+            .body("v3")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let res = client.put(&url).body("v3").send().await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_if_match_failed_replica() -> anyhow::Result<()> {
+        for two_phase_writes in [true, false] {
+            let cluster = TestCluster::start_with_config(2, 2, |config| {
+                config.two_phase_writes = two_phase_writes;
+                config.write_retry.attempts = 1;
+            })
+            .await?;
+            let client = reqwest::Client::new();
+            let url = cluster.key_url("kept");
+            let res = client.put(&url).body("old").send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            let etag = format!("\"{:x}\"", md5::compute("old"));
+
+            cluster.volume(1).set_unavailable(true);
+            let res = client
+                .put(&url)
+                .header("If-Match", &etag)
+                .body("new")
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+            // The old value is still read from the replica the overwrite reached
+            let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["etag"], etag.as_str());
+            assert_eq!(res.text().await?, "old");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_if_match_failed_move() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 2).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("moved");
+        let remote_path = record::get_remote_path("moved");
+        let stored = |index: usize| {
+            let volume = cluster.volume(index);
+            let paths = volume.paths();
+            let path = paths.iter().find(|path| path.ends_with(&remote_path));
+            path.and_then(|path| volume.get(path))
+        };
+        let res = client.put(&url).body("old").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let etag = format!("\"{:x}\"", md5::compute("old"));
+
+        // No upload moved into place, the overwrite fails and every replica keeps the old value
+        cluster.volume(0).set_failing_moves(true);
+        cluster.volume(1).set_failing_moves(true);
+        let res = client
+            .put(&url)
+            .header("If-Match", &etag)
+            .body("new")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(res.text().await?, "old");
+        for index in 0..2 {
+            assert_eq!(stored(index).as_deref(), Some(&b"old"[..]));
+            assert_eq!(cluster.volume(index).len(), 1);
+        }
+
+        // One upload moved into place replaced the old value, the overwrite commits and repairs the other
+        cluster.volume(0).set_failing_moves(false);
+        let res = client
+            .put(&url)
+            .header("If-Match", &etag)
+            .body("new")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        cluster.volume(1).set_failing_moves(false);
+        let res = client.get(&url).query(&[("proxy", "1")]).send().await?;
+        assert_eq!(
+            res.headers()["etag"],
+            format!("\"{:x}\"", md5::compute("new"))
+        );
+        assert_eq!(res.text().await?, "new");
+        for _ in 0..50 {
+            if stored(1).as_deref() == Some(&b"new"[..]) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(stored(1).as_deref(), Some(&b"new"[..]));
+        assert_eq!(cluster.volume(1).len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_ttl() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("ttl");
+
+        for ttl in ["0", "abc"] {
+            let res = client.put(&url).header(TTL, ttl).body("v").send().await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let res = client.put(&url).header(TTL, "1").body("v").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = client.put(&url).body("v2").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_read_and_internal_listeners() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_split(3, 2).await?;
+        let internal_url = cluster.internal_url().unwrap();
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        let res = client
+            .put(cluster.key_url("split"))
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let res = client
+            .put(format!("{}/split", internal_url))
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(cluster.key_url("split")).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let res = client.head(cluster.key_url("split")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.delete(cluster.key_url("split")).send().await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let res = client
+            .get(format!("{}/admin/report", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client
+            .get(format!("{}/admin/report", internal_url))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    /// Metadata store keeping the records in memory, shared with the test through clones,
+    /// counting its flushes.
+    #[derive(Clone, Default)]
+    struct MemoryStore(
+        Arc<parking_lot::Mutex<std::collections::BTreeMap<String, Vec<u8>>>>,
+        Arc<std::sync::atomic::AtomicUsize>,
+    );
+
+    impl record::MetadataStore for MemoryStore {
+        fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+            self.0.lock().insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+
+        fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().get(key).cloned())
+        }
+
+        fn delete(&self, key: &str) -> anyhow::Result<()> {
+            self.0.lock().remove(key);
+            Ok(())
+        }
+
+        fn for_each_entry(
+            &self,
+            prefix: &str,
+            f: &mut dyn FnMut(&str, &[u8]) -> anyhow::Result<()>,
+        ) -> anyhow::Result<()> {
+            self.0
+                .lock()
+                .range(prefix.to_string()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .try_for_each(|(key, value)| f(key, value))
+        }
+
+        fn flush(&self) -> anyhow::Result<()> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_with_store() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(1, 1).await?;
+        let store = MemoryStore::default();
+        let config = Config {
+            volumes: cluster.volume_addrs().to_vec(),
+            replicas: 1,
+            ..Default::default()
+        };
+        config.validate()?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/embedded", listener.local_addr()?);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::new(config)
+                .with_store(Box::new(store.clone()))
+                .serve(Listeners::new(listener), async {
+                    let _ = shutdown_rx.await;
+                }),
+        );
+
+        let client = reqwest::Client::new();
+        let res = client.put(&url).body("value").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "value");
+
+        let bytes = record::MetadataStore::get(&store, "embedded")?.expect("record in the store");
+        let record = record::Record::from_bytes(&bytes)?;
+        assert!(record.is_live(record::unix_now()));
+        assert_eq!(record.size(), 5);
+        let volume = format!("{}/", cluster.volume_addrs()[0]);
+        assert!(record
+            .read_volumes()
+            .iter()
+            .all(|read_volume| read_volume.starts_with(&volume)));
+
+        shutdown_tx.send(()).ok();
+        server.await??;
+        assert_eq!(store.1.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_listener() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(1, 1).await?;
+        let config = Config {
+            volumes: cluster.volume_addrs().to_vec(),
+            replicas: 1,
+            ..Default::default()
+        };
+        let public = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let admin = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let public_url = format!("http://{}", public.local_addr()?);
+        let admin_url = format!("http://{}", admin.local_addr()?);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::new(config)
+                .with_store(Box::new(MemoryStore::default()))
+                .serve(Listeners::new(public).with_admin(Some(admin)), async {
+                    let _ = shutdown_rx.await;
+                }),
+        );
+
+        let client = reqwest::Client::new();
+        let res = client
+            .put(format!("{}/key", public_url))
+            .body("value")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        for path in ["/healthz", "/metrics", "/admin/report"] {
+            let res = client.get(format!("{}{}", admin_url, path)).send().await?;
+            assert_eq!(
+                res.status(),
+                StatusCode::OK,
+                "{} on the admin listener",
+                path
+            );
+            // The public listener takes them for keys
+            let res = client.get(format!("{}{}", public_url, path)).send().await?;
+            assert_eq!(
+                res.status(),
+                StatusCode::NOT_FOUND,
+                "{} on the public listener",
+                path
+            );
+        }
+        // Keys aren't served on the admin listener
+        let res = client.get(format!("{}/key", admin_url)).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        shutdown_tx.send(()).ok();
+        server.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_writes_while_draining() -> anyhow::Result<()> {
+        let draining = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let router = axum::Router::new()
+            .route(
+                "/key",
+                axum::routing::get(|| async { "value" }).put(|| async { StatusCode::CREATED }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                draining.clone(),
+                reject_writes_while_draining,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/key", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = reqwest::Client::new();
+        let res = client.put(&url).body("value").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        draining.store(true, std::sync::atomic::Ordering::SeqCst);
+        let res = client.put(&url).body("value").send().await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[reqwest::header::RETRY_AFTER], "1");
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_deadline_closes_stalled_uploads() -> anyhow::Result<()> {
+        let leveldb_dir = tempfile::tempdir()?;
+        let config = Config {
+            leveldb_path: leveldb_dir.path().to_path_buf(),
+            db_backend: Default::default(),
+            record_encoding: Default::default(),
+            record_cache: None,
+            negative_cache: None,
+            value_cache: None,
+            verify_checksums: true,
+            hash_algorithm: checksum::Algorithm::Md5,
+            checksum_algorithms: Vec::new(),
+            volumes: vec!["127.0.0.1:1".to_string()],
+            default_proxy: false,
+            redirect_status: RedirectStatus::default(),
+            redirect_cache_control: Some(DEFAULT_REDIRECT_CACHE_CONTROL.to_string()),
+            concurrent_heads: false,
+            replicas: 1,
+            max_replicas: None,
+            namespace_replicas: HashMap::new(),
+            key_policy: keys::KeyPolicy::default(),
+            max_value_size: None,
+            write_quorum: None,
+            subvolumes: 10,
+            volume_groups: Vec::new(),
+            placement_rules: Vec::new(),
+            task_schedules: Default::default(),
+            lock_timeout: Duration::from_secs(1),
+            shutdown_timeout: Duration::from_millis(200),
+            reload_ring: None,
+            tls: None,
+            volume_tls: Default::default(),
+            volume_client: Default::default(),
+            auth: None,
+            ready_fraction: health::DEFAULT_READY_FRACTION,
+            write_retry: Default::default(),
+            two_phase_writes: false,
+            erasure: None,
+            dedup: false,
+            compression: None,
+            encryption: None,
+            presign: None,
+            breaker: Default::default(),
+            fanout: Default::default(),
+            volume_connect_timeout: None,
+            volume_request_timeout: None,
+            request_deadline: None,
+            limits: Default::default(),
+            cors: None,
+            gc_grace_period: gc::DEFAULT_GRACE_PERIOD,
+            auto_rebalance: None,
+            access_log: true,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/stalled", listener.local_addr()?);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(Server::new(config).serve(Listeners::new(listener), async {
+            let _ = shutdown_rx.await;
+        }));
+
+        // The upload sends part of its body and never finishes
+        let body =
+            futures::stream::once(async { Ok::<_, std::io::Error>(bytes::Bytes::from("on")) })
+                .chain(futures::stream::pending());
+        let upload = tokio::spawn(
+            reqwest::Client::new()
+                .put(url)
+                .header(axum::http::header::CONTENT_LENGTH, 5)
+                .body(reqwest::Body::wrap_stream(body))
+                .send(),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let _ = shutdown_tx.send(());
+        let served = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert!(
+            served.is_ok(),
+            "server did not stop at the shutdown deadline"
+        );
+        served???;
+        upload.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let tls = TlsConfig {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        std::fs::write(&tls.cert, certified.cert.pem())?;
+        std::fs::write(&tls.key, certified.key_pair.serialize_pem())?;
+
+        let config = Config {
+            leveldb_path: dir.path().join("indexdb"),
+            db_backend: Default::default(),
+            record_encoding: Default::default(),
+            record_cache: None,
+            negative_cache: None,
+            value_cache: None,
+            verify_checksums: true,
+            hash_algorithm: checksum::Algorithm::Md5,
+            checksum_algorithms: Vec::new(),
+            volumes: vec!["127.0.0.1:1".to_string()],
+            default_proxy: false,
+            redirect_status: RedirectStatus::default(),
+            redirect_cache_control: Some(DEFAULT_REDIRECT_CACHE_CONTROL.to_string()),
+            concurrent_heads: false,
+            replicas: 1,
+            max_replicas: None,
+            namespace_replicas: HashMap::new(),
+            key_policy: keys::KeyPolicy::default(),
+            max_value_size: None,
+            write_quorum: None,
+            subvolumes: 10,
+            volume_groups: Vec::new(),
+            placement_rules: Vec::new(),
+            task_schedules: Default::default(),
+            lock_timeout: Duration::from_secs(1),
+            shutdown_timeout: Duration::from_millis(200),
+            reload_ring: None,
+            tls: Some(tls),
+            volume_tls: Default::default(),
+            volume_client: Default::default(),
+            auth: None,
+            ready_fraction: health::DEFAULT_READY_FRACTION,
+            write_retry: Default::default(),
+            two_phase_writes: false,
+            erasure: None,
+            dedup: false,
+            compression: None,
+            encryption: None,
+            presign: None,
+            breaker: Default::default(),
+            fanout: Default::default(),
+            volume_connect_timeout: None,
+            volume_request_timeout: None,
+            request_deadline: None,
+            limits: Default::default(),
+            cors: None,
+            gc_grace_period: gc::DEFAULT_GRACE_PERIOD,
+            auto_rebalance: None,
+            access_log: true,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(Server::new(config).serve(Listeners::new(listener), async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(
+                certified.cert.pem().as_bytes(),
+            )?)
+            .build()?;
+        let res = client
+            .get(format!("https://localhost:{}/missing", port))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = reqwest::Client::new()
+            .get(format!("http://localhost:{}/missing", port))
+            .send()
+            .await;
+        assert!(res.is_err() || !res?.status().is_success());
+
+        let _ = shutdown_tx.send(());
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_volume_tls_client() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        std::fs::write(&cert, certified.cert.pem())?;
+        std::fs::write(&key, certified.key_pair.serialize_pem())?;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("https://localhost:{}/", listener.local_addr()?.port());
+        let router = axum::Router::new().route("/", axum::routing::get(|| async { "volume" }));
+        let rustls = RustlsConfig::from_pem_file(&cert, &key).await?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let volume = tokio::spawn(serve_router(listener, router, Some(rustls), async {
+            let _ = shutdown_rx.await;
+        }));
+
+        // The volume certificate is only trusted with its CA pinned
+        assert!(VolumeTlsConfig::default()
+            .client()?
+            .get(&url)
+            .send()
+            .await
+            .is_err());
+        let volume_tls = VolumeTlsConfig {
+            ca: Some(cert.clone()),
+            client_cert: Some(cert.clone()),
+            client_key: Some(key),
+        };
+        let res = volume_tls.client()?.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let missing_key = VolumeTlsConfig {
+            client_key: None,
+            ..volume_tls
+        };
+        assert!(missing_key.client().is_err());
+
+        let _ = shutdown_tx.send(());
+        tokio::time::timeout(Duration::from_secs(5), volume).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_volume_http2() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/", listener.local_addr()?);
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::get(|request: axum::extract::Request| async move {
+                format!("{:?}", request.version())
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let volume_client = VolumeClientConfig {
+            http2: true,
+            keep_alive: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let client = volume_client.apply(reqwest::Client::builder()).build()?;
+        assert_eq!(client.get(&url).send().await?.text().await?, "HTTP/2.0");
+        let client = VolumeClientConfig::default()
+            .apply(reqwest::Client::builder())
+            .build()?;
+        assert_eq!(client.get(&url).send().await?.text().await?, "HTTP/1.1");
+
+        // The volumes of the cluster take the replicas and serve the values over HTTP/2
+        let cluster = TestCluster::start_with_config(3, 2, |config| {
+            config.volume_client = volume_client;
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        let res = client
+            .put(cluster.key_url("h2"))
+            .body("value")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client
+            .get(format!("{}?proxy=1", cluster.key_url("h2")))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "value");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> anyhow::Result<()> {
+        let mut tokens = auth::Tokens::default();
+        tokens.add("reader".to_string(), auth::Scope::Read);
+        tokens.add("writer".to_string(), auth::Scope::Write);
+        let cluster = TestCluster::start_with_auth(3, 2, tokens).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("guarded");
+
+        let res = client.put(&url).body("bigswag").send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()["www-authenticate"], "Bearer");
+        let res = client
+            .put(&url)
+            .bearer_auth("unknown")
+            .body("bigswag")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = client
+            .put(&url)
+            .bearer_auth("reader")
+            .body("bigswag")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = client
+            .put(&url)
+            .bearer_auth("writer")
+            .body("bigswag")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client
+            .get(format!("{}?proxy=1", url))
+            .bearer_auth("reader")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "bigswag");
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = client
+            .get(format!("{}/admin/volumes", cluster.url()))
+            .bearer_auth("reader")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_down_volumes_skipped() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 3).await?;
+        let client = reqwest::Client::new();
+        let down = cluster.volume_addrs()[0].clone();
+
+        let res = client
+            .put(cluster.key_url("before"))
+            .body("bigswag")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        cluster.volume(0).set_unavailable(true);
+        let res = client
+            .post(format!("{}/admin/tasks/health/run", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        for _ in 0..50 {
+            let tasks: serde_json::Value = client
+                .get(format!("{}/admin/tasks", cluster.url()))
+                .send()
+                .await?
+                .json()
+                .await?;
+            let health = tasks
+                .as_array()
+                .and_then(|tasks| tasks.iter().find(|task| task["name"] == "health"))
+                .cloned()
+                .unwrap_or_default();
+            if health["runs"].as_u64().unwrap_or_default() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Every replica is needed, the PUT fails without writing to the up volumes
+        let res = client
+            .put(cluster.key_url("after"))
+            .body("bigswag")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(cluster.volume(1).len(), 1);
+
+        let no_redirect = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        for _ in 0..10 {
+            let res = no_redirect.get(cluster.key_url("before")).send().await?;
+            assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+            assert!(!res.headers()["location"].to_str()?.contains(&down));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(300));
+        assert_eq!(policy.delay(40), Duration::from_millis(300));
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = jittered.delay(2);
+            assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_retries_transient_errors() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(2, 2).await?;
+        let client = reqwest::Client::new();
+
+        cluster.volume(1).set_unavailable(true);
+        let put = tokio::spawn(
+            client
+                .put(cluster.key_url("retried"))
+                .body("bigswag")
+                .send(),
+        );
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        cluster.volume(1).set_unavailable(false);
+
+        assert_eq!(put.await??.status(), StatusCode::CREATED);
+        assert_eq!(cluster.volume(0).len(), 1);
+        assert_eq!(cluster.volume(1).len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() -> anyhow::Result<()> {
+        let router = axum::Router::new()
+            .route(
+                "/slow",
+                axum::routing::get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "late"
+                }),
+            )
+            .route("/fast", axum::routing::get(|| async { "early" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Duration::from_millis(100),
+                enforce_deadline,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(serve_router(listener, router, None, std::future::pending()));
+
+        let client = reqwest::Client::new();
+        let res = client.get(format!("{}/slow", url)).send().await?;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        let res = client.get(format!("{}/fast", url)).send().await?;
+        assert_eq!(res.text().await?, "early");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_keys() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in ["list-b", "list-a", "list-c", "other"] {
+            client.put(cluster.key_url(key)).body(key).send().await?;
+        }
+        client.delete(cluster.key_url("list-c")).send().await?;
+
+        let res = client.get(cluster.key_url("list-?list")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page: serde_json::Value = res.json().await?;
+        assert_eq!(
+            page,
+            serde_json::json!({"keys": ["/list-a", "/list-b"], "next": ""})
+        );
+
+        let url = cluster.key_url("list-?list&limit=1");
+        let page: serde_json::Value = client.get(url).send().await?.json().await?;
+        assert_eq!(
+            page,
+            serde_json::json!({"keys": ["/list-a"], "next": "/list-b"})
+        );
+
+        let url = cluster.key_url("list-?list&limit=1&start=/list-b");
+        let page: serde_json::Value = client.get(url).send().await?.json().await?;
+        assert_eq!(page, serde_json::json!({"keys": ["/list-b"], "next": ""}));
+        // A key works as the start too
+        let url = cluster.key_url("list-?list&start=list-b");
+        let page: serde_json::Value = client.get(url).send().await?.json().await?;
+        assert_eq!(page, serde_json::json!({"keys": ["/list-b"], "next": ""}));
+
+        let url = cluster.key_url("missing?list");
+        let page: serde_json::Value = client.get(url).send().await?.json().await?;
+        assert_eq!(page, serde_json::json!({"keys": [], "next": ""}));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_successor() {
+        assert_eq!(successor("images/").as_deref(), Some("images0"));
+        assert_eq!(successor("a\u{D7FF}").as_deref(), Some("a\u{E000}"));
+        assert_eq!(successor("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(successor("\u{10FFFF}"), None);
+        assert_eq!(successor(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_nested_keys() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        for key in [
+            "images/2024/cat.png",
+            "images/2024/dog.png",
+            "images/logo.png",
+            "images2",
+            "ns/app/docs/readme",
+        ] {
+            let res = client.put(cluster.key_url(key)).body(key).send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            let res = client.get(cluster.key_url(key)).send().await?;
+            assert_eq!(res.text().await?, key);
+        }
+        // The remote path is a single file whatever the slashes of the key
+        let remote_path = record::get_remote_path("images/2024/cat.png");
+        assert_eq!(remote_path.matches('/').count(), 3);
+        assert!((0..3)
+            .flat_map(|i| cluster.volume(i).paths())
+            .any(|path| path.ends_with(&remote_path)));
+
+        let list = |url: &str| {
+            let request = client.get(cluster.key_url(url)).send();
+            async move { anyhow::Ok(request.await?.json::<serde_json::Value>().await?) }
+        };
+        assert_eq!(
+            list("images/?list").await?,
+            serde_json::json!({"keys": ["/images/2024/cat.png", "/images/2024/dog.png", "/images/logo.png"], "next": ""})
+        );
+        assert_eq!(
+            list("images/?list&delimiter=/").await?,
+            serde_json::json!({"keys": ["/images/logo.png"], "prefixes": ["/images/2024/"], "next": ""})
+        );
+        assert_eq!(
+            list("images?list&delimiter=/&limit=1").await?,
+            serde_json::json!({"keys": [], "prefixes": ["/images/"], "next": "/images2"})
+        );
+        assert_eq!(
+            list("images/?list&delimiter=/&limit=1").await?,
+            serde_json::json!({"keys": [], "prefixes": ["/images/2024/"], "next": "/images/logo.png"})
+        );
+        assert_eq!(
+            list("images/?list&delimiter=/&limit=1&start=/images/2024/").await?,
+            serde_json::json!({"keys": [], "prefixes": ["/images/2024/"], "next": "/images/logo.png"})
+        );
+        // A common prefix before the start is left out, even with keys after the start
+        assert_eq!(
+            list("images/?list&delimiter=/&start=/images/2024/d").await?,
+            serde_json::json!({"keys": ["/images/logo.png"], "prefixes": [], "next": ""})
+        );
+        assert_eq!(
+            list("ns/app/?list&delimiter=/").await?,
+            serde_json::json!({"keys": [], "prefixes": ["/docs/"], "next": ""})
+        );
+
+        // Plain keys can't start like a route
+        for key in ["admin/key", "ns%2Fapp", "presign/key"] {
+            let res = client.put(cluster.key_url(key)).body("no").send().await?;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", key);
+        }
+
+        let res = client
+            .delete(cluster.key_url("images/2024/cat.png"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client
+            .get(cluster.key_url("images/2024/cat.png"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_policy() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(3, 2, |config| {
+            config.key_policy = keys::KeyPolicy {
+                max_length: 16,
+                charset: keys::KeyCharset::Safe,
+            };
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        for key in ["ok/key", "ns/app/ok"] {
+            let res = client.put(cluster.key_url(key)).body(key).send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED, "{}", key);
+        }
+
+        // Percent-decoded once, so a double encoded slash keeps its %
+        for (key, message) in [
+            ("a%20b", "charset"),
+            ("a%252Fb", "charset"),
+            ("seventeen-bytes-k", "max key length"),
+            ("ns/app/sixteen-bytes", "max key length"),
+            ("%FF", "UTF-8"),
+        ] {
+            let res = client.put(cluster.key_url(key)).body("no").send().await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", key);
+            let error: serde_json::Value = res.json().await?;
+            assert_eq!(error["error"], "invalid_key");
+            let text = error["message"].as_str().unwrap_or_default();
+            assert!(text.contains(message), "{}: {}", key, text);
+        }
+        let res = client.get(cluster.key_url("a%20b")).send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record_metadata() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let res = client
+            .put(cluster.key_url("wehave"))
+            .header(reqwest::header::CONTENT_TYPE, "text/plain")
+            .body("bigswag")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let url = format!("{}?meta", cluster.key_url("wehave"));
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let meta: serde_json::Value = res.json().await?;
+        assert_eq!(meta["key"], "wehave");
+        assert_eq!(meta["hash"], checksum::md5_hex(b"bigswag"));
+        assert_eq!(meta["hash_algorithm"], "Md5");
+        assert_eq!(meta["size"], 7);
+        assert_eq!(meta["content_type"], "text/plain");
+        assert_eq!(meta["volumes"], meta["replica_volumes"]);
+        assert_eq!(meta["balance"], "balanced");
+        assert_eq!(meta["deleted"], "No");
+        assert!(meta["created_at"].as_u64().is_some());
+        assert!(meta.get("parts").is_none());
+
+        let res = client.delete(cluster.key_url("wehave")).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let meta: serde_json::Value = client.get(&url).send().await?.json().await?;
+        assert_eq!(meta["deleted"], "Soft");
+        assert!(meta["deleted_at"].as_u64().is_some());
+
+        let res = client
+            .get(format!("{}?meta", cluster.key_url("missing")))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generated_keys() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("{}/", cluster.url()))
+            .header(reqwest::header::CONTENT_TYPE, "text/plain")
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()[reqwest::header::LOCATION]
+            .to_str()?
+            .to_string();
+        let key = res.text().await?;
+        assert_eq!(location, format!("/{}", key));
+        assert_eq!(key.len(), 36);
+        assert_eq!(&key[14..15], "4");
+        let res = client
+            .get(format!("{}{}", cluster.url(), location))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "onyou");
+
+        let res = client
+            .post(format!("{}/", cluster.url()))
+            .body("onyou")
+            .send()
+            .await?;
+        assert_ne!(res.text().await?, key);
+
+        let res = client
+            .post(format!("{}/ns/app/", cluster.url()))
+            .body("inapp")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()[reqwest::header::LOCATION]
+            .to_str()?
+            .to_string();
+        assert!(location.starts_with("/ns/app/"), "{}", location);
+        let res = client
+            .get(format!("{}{}", cluster.url(), location))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "inapp");
+        let res = client
+            .post(format!("{}/", cluster.url()))
+            .header(NAMESPACE, "bad/name")
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = client.post(format!("{}/", cluster.url())).send().await?;
+        assert_eq!(res.status(), StatusCode::LENGTH_REQUIRED);
+
+        let res = client
+            .post(cluster.key_url(CAS_KEY))
+            .body("onyou")
+            .send()
+            .await?;
+        let location = res.headers()[reqwest::header::LOCATION]
+            .to_str()?
+            .to_string();
+        let res = client
+            .get(format!("{}{}", cluster.url(), location))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "onyou");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_and_move() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let copy = reqwest::Method::from_bytes(b"COPY")?;
+        let mv = reqwest::Method::from_bytes(b"MOVE")?;
+        let res = client
+            .put(cluster.key_url("src"))
+            .header(reqwest::header::CONTENT_TYPE, "text/plain")
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client
+            .request(copy.clone(), cluster.key_url("src"))
+            .header(DESTINATION, "/dir/dst")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let src = client.head(cluster.key_url("src")).send().await?;
+        let dst = client.head(cluster.key_url("dir/dst")).send().await?;
+        assert_eq!(dst.headers()[reqwest::header::CONTENT_TYPE], "text/plain");
+        // Copied by the volumes holding the source
+        assert_eq!(src.headers()["Key-Volumes"], dst.headers()["Key-Volumes"]);
+        let res = client.get(cluster.key_url("dir/dst")).send().await?;
+        assert_eq!(res.text().await?, "onyou");
+        let res = client.get(cluster.key_url("src")).send().await?;
+        assert_eq!(res.text().await?, "onyou");
+
+        for (key, destination, status) in [
+            ("src", Some("/dir/dst"), StatusCode::CONFLICT),
+            ("src", Some("/src"), StatusCode::BAD_REQUEST),
+            ("src", Some("/ns/bad:name/x"), StatusCode::BAD_REQUEST),
+            ("src", Some("/cas:x"), StatusCode::BAD_REQUEST),
+            ("src", None, StatusCode::BAD_REQUEST),
+            ("missing", Some("/other"), StatusCode::NOT_FOUND),
+        ] {
+            let mut request = client.request(copy.clone(), cluster.key_url(key));
+            if let Some(destination) = destination {
+                request = request.header(DESTINATION, destination);
+            }
+            assert_eq!(request.send().await?.status(), status, "{:?}", destination);
+        }
+        let res = client
+            .request(reqwest::Method::PATCH, cluster.key_url("src"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        // A MOVE tombstones the source, which can be undeleted
+        let res = client
+            .request(mv, cluster.key_url("dir/dst"))
+            .header(DESTINATION, format!("{}/ns/app/moved%20key", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(cluster.key_url("dir/dst")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client
+            .get(format!("{}/ns/app/moved%20key", cluster.url()))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "onyou");
+        let res = client
+            .post(format!("{}?undelete", cluster.key_url("dir/dst")))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // Multipart values are streamed through the index
+        let url = cluster.key_url("multipart");
+        for (number, part) in [(1, "on"), (2, "you")] {
+            let res = client
+                .put(format!("{}?partNumber={}", url, number))
+                .body(part)
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = client
+            .post(format!("{}?uploads=complete", url))
+            .body("[1, 2]")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client
+            .request(copy, &url)
+            .header(DESTINATION, "/stitched")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.get(cluster.key_url("stitched")).send().await?;
+        assert_eq!(res.text().await?, "onyou");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(5, 2, |config| {
+            config.namespace_replicas = HashMap::from([("logs".to_string(), 1)]);
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        for (key, value) in [
+            ("ns/app/photo", "a"),
+            ("ns/logs/photo", "bb"),
+            ("photo", "c"),
+        ] {
+            let res = client.put(cluster.key_url(key)).body(value).send().await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            let res = client.get(cluster.key_url(key)).send().await?;
+            assert_eq!(res.text().await?, value);
+        }
+        let res = client
+            .get(cluster.key_url("photo"))
+            .header(NAMESPACE, "app")
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "a");
+
+        // The namespace replicas apply without X-Replicas
+        let copies = |key: &str| {
+            let remote_path = record::get_remote_path(key);
+            (0..5)
+                .filter(|index| {
+                    cluster
+                        .volume(*index)
+                        .paths()
+                        .iter()
+                        .any(|path| path.ends_with(&remote_path))
+                })
+                .count()
+        };
+        assert!(copies("ns:logs/photo") < copies("ns:app/photo"));
+
+        let page: serde_json::Value = client
+            .get(cluster.key_url("ns/app/?list"))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(page, serde_json::json!({"keys": ["/photo"], "next": ""}));
+        let page: serde_json::Value = client
+            .get(cluster.key_url("ns?list"))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(page, serde_json::json!({"keys": [], "next": ""}));
+
+        for (key, namespace) in [
+            ("ns%3Aapp%2Fphoto", None),
+            ("ns/bad%20name/photo", None),
+            ("photo", Some("a/b")),
+        ] {
+            let mut req = client.put(cluster.key_url(key)).body("no");
+            if let Some(namespace) = namespace {
+                req = req.header(NAMESPACE, namespace);
+            }
+            assert_eq!(req.send().await?.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let url = format!("{}/admin/namespaces", cluster.url());
+        let list: serde_json::Value = client.get(&url).send().await?.json().await?;
+        assert_eq!(
+            list,
+            serde_json::json!({"namespaces": [
+                {"name": "app", "keys": 1, "bytes": 1},
+                {"name": "logs", "keys": 1, "bytes": 2},
+            ]})
+        );
+        let deleted: serde_json::Value = client
+            .delete(format!("{}/app", url))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(deleted, serde_json::json!({"deleted": 1, "failed": 0}));
+        let res = client.get(cluster.key_url("ns/app/photo")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.get(cluster.key_url("photo")).send().await?;
+        assert_eq!(res.text().await?, "c");
+        let stats: serde_json::Value = client
+            .get(format!("{}/app", url))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(
+            stats,
+            serde_json::json!({"name": "app", "keys": 0, "bytes": 0})
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespace_quotas() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = format!("{}/admin/namespaces/app/quota", cluster.url());
+        let quota: serde_json::Value = client
+            .put(&url)
+            .json(&serde_json::json!({"max_keys": 2, "max_bytes": 10}))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(
+            quota,
+            serde_json::json!({"max_keys": 2, "max_bytes": 10, "keys": 0, "bytes": 0})
+        );
+
+        for (key, value, status) in [
+            ("a", "123456", StatusCode::CREATED),
+            ("b", "12345", StatusCode::INSUFFICIENT_STORAGE),
+            ("b", "1234", StatusCode::CREATED),
+            ("c", "1", StatusCode::INSUFFICIENT_STORAGE),
+        ] {
+            let res = client
+                .put(cluster.key_url(&format!("ns/app/{}", key)))
+                .body(value)
+                .send()
+                .await?;
+            assert_eq!(res.status(), status, "{} {}", key, value);
+        }
+        let res = client
+            .put(cluster.key_url("c"))
+            .body("plain")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        client.delete(cluster.key_url("ns/app/b")).send().await?;
+        let quota: serde_json::Value = client.get(&url).send().await?.json().await?;
+        assert_eq!(
+            quota,
+            serde_json::json!({"max_keys": 2, "max_bytes": 10, "keys": 1, "bytes": 6})
+        );
+        let res = client
+            .put(cluster.key_url("ns/app/c"))
+            .body("1")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let quota: serde_json::Value = client
+            .put(&url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(
+            quota,
+            serde_json::json!({"max_keys": null, "max_bytes": null, "keys": 2, "bytes": 7})
+        );
+        let res = client
+            .put(cluster.key_url("ns/app/d"))
+            .body("unlimited")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespace_quota_concurrent_puts() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        client
+            .put(format!("{}/admin/namespaces/app/quota", cluster.url()))
+            .json(&serde_json::json!({"max_keys": 3}))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // Writes in flight hold their share of the quota, so racing PUTs never overshoot it
+        let puts = (0..12).map(|index| {
+            client
+                .put(cluster.key_url(&format!("ns/app/{}", index)))
+                .body("racing")
+                .send()
+        });
+        let mut statuses = Vec::new();
+        for res in futures::future::join_all(puts).await {
+            statuses.push(res?.status());
+        }
+        let created = statuses
+            .iter()
+            .filter(|status| **status == StatusCode::CREATED)
+            .count();
+        assert_eq!(created, 3, "{:?}", statuses);
+        assert!(statuses
+            .iter()
+            .all(|status| *status == StatusCode::CREATED
+                || *status == StatusCode::INSUFFICIENT_STORAGE));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespace_quota_completions() -> anyhow::Result<()> {
+        let key_file = tempfile::NamedTempFile::new()?;
+        std::fs::write(key_file.path(), "a presign key shared with the volumes")?;
+        let key = presign::PresignKey::load(key_file.path())?;
+        let cluster = TestCluster::start_with_config(3, 2, |config| {
+            config.presign = Some(presign::PresignConfig {
+                key,
+                public_volumes: HashMap::new(),
+            })
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        let quota_url = format!("{}/admin/namespaces/app/quota", cluster.url());
+        client
+            .put(&quota_url)
+            .json(&serde_json::json!({"max_bytes": 8}))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // Parts count once stitched, a completion taking the namespace past its quota fails
+        let url = cluster.key_url("ns/app/multipart");
+        for number in [1, 2] {
+            let res = client
+                .put(format!("{}?partNumber={}", url, number))
+                .body("12345")
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let complete = format!("{}?uploads=complete", url);
+        let res = client.post(&complete).body("[1, 2]").send().await?;
+        assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+        let res = client.post(&complete).body("[1]").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        // The uploads of a presigned value over the quota are kept for a later completion
+        let presign_url = format!("{}?presign", cluster.key_url("ns/app/presigned"));
+        let upload: serde_json::Value = client.post(&presign_url).send().await?.json().await?;
+        for url in upload["urls"].as_array().unwrap() {
+            let res = client
+                .put(url.as_str().unwrap())
+                .body("1234")
+                .send()
+                .await?;
+            assert!(res.status().is_success());
+        }
+        let complete_url = format!("{}{}", cluster.url(), upload["complete"].as_str().unwrap());
+        let res = client.post(&complete_url).send().await?;
+        assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+        client
+            .put(&quota_url)
+            .json(&serde_json::json!({"max_bytes": 9}))
+            .send()
+            .await?
+            .error_for_status()?;
+        let res = client.post(&complete_url).send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client
+            .get(cluster.key_url("ns/app/presigned"))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "1234");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_head_record() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        client
+            .put(cluster.key_url("head"))
+            .body("onyou")
+            .send()
+            .await?;
+
+        let res = client.head(cluster.key_url("head")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-length"], "5");
+        assert_eq!(res.headers()["content-md5"], checksum::md5_hex(b"onyou"));
+        assert_eq!(res.headers()["key-volumes"].to_str()?.split(',').count(), 2);
+
+        // Answered from leveldb even if every volume lost the value
+        for i in 0..3 {
+            cluster.volume(i).clear();
+        }
+        let res = client.head(cluster.key_url("head")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        client.delete(cluster.key_url("head")).send().await?;
+        let res = client.head(cluster.key_url("head")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.head(cluster.key_url("missing")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["content-length"], "0");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_undelete() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = cluster.key_url("regret");
+        let undelete = format!("{}?undelete", url);
+
+        let res = client.post(&undelete).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        client.put(&url).body("regret").send().await?;
+        let res = client.post(&undelete).send().await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = client.post(&undelete).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            res.headers()["etag"],
+            format!("\"{:x}\"", md5::compute("regret")).as_str()
+        );
+
+        // A value collected from the volumes cannot come back
+        client.delete(&url).send().await?;
+        for index in 0..3 {
+            cluster.volume(index).clear();
+        }
+        let res = client.post(&undelete).send().await?;
+        assert_eq!(res.status(), StatusCode::GONE);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cas() -> anyhow::Result<()> {
+        use sha2::Digest;
+
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let cas = cluster.key_url("cas");
+        let key = format!("cas:{:x}", sha2::Sha256::digest("artifact"));
+
+        let res = client.post(&cas).body("artifact").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created: serde_json::Value = res.json().await?;
+        assert_eq!(created["key"], key.as_str());
+        assert_eq!(created["refcount"], 1);
+
+        // The same content is stored once and referenced twice
+        let res = client.post(&cas).body("artifact").send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let existing: serde_json::Value = res.json().await?;
+        assert_eq!(existing["key"], key.as_str());
+        assert_eq!(existing["refcount"], 2);
+        let url = cluster.key_url(&key);
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["x-refcount"], "2");
+
+        let res = client.post(&cas).send().await?;
+        assert_eq!(res.status(), StatusCode::LENGTH_REQUIRED);
+        let res = client.put(&url).body("tampered").send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // The value stays until its last reference is deleted
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = client.post(&cas).body("artifact").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dedup() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(3, 2, |config| config.dedup = true).await?;
+        let client = reqwest::Client::new();
+        let blobs = || {
+            (0..3)
+                .map(|index| cluster.volume(index).len())
+                .sum::<usize>()
+        };
+
+        let res = client.put(cluster.key_url("a")).body("same").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let stored = blobs();
+        assert!(stored > 0);
+        let res = client.put(cluster.key_url("b")).body("same").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(blobs(), stored);
+        for key in ["a", "b"] {
+            let res = client.get(cluster.key_url(key)).send().await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.text().await?, "same");
+        }
+
+        // Replacing a key drops its reference, the blob goes with the last one
+        let res = client
+            .put(cluster.key_url("a"))
+            .header("If-Match", "*")
+            .body("other")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(blobs(), stored * 2);
+        let res = client.get(cluster.key_url("b")).send().await?;
+        assert_eq!(res.text().await?, "same");
+        let res = client
+            .put(cluster.key_url("b"))
+            .header("If-Match", "*")
+            .body("other")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(blobs(), stored);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hash_algorithm() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(2, 2, |config| {
+            config.hash_algorithm = checksum::Algorithm::Blake3
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("fast");
+        let blake3 = checksum::Algorithm::Blake3.compute_hex(b"hashed");
+
+        // A Content-Md5 is still verified, the record stores the BLAKE3 of the value
+        let res = client
+            .put(&url)
+            .header("Content-Md5", checksum::md5_hex(b"hashed"))
+            .body("hashed")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["x-content-blake3"], blake3.as_str());
+        assert_eq!(res.headers()["etag"], format!("\"{}\"", blake3).as_str());
+        assert!(!res.headers().contains_key("content-md5"));
+
+        let kv = crate::MiniKvClient::new(cluster.url())?.with_checksum_verification(true);
+        assert_eq!(kv.get("fast").await?.as_deref(), Some(&b"hashed"[..]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression() -> anyhow::Result<()> {
+        let cluster = TestCluster::start_with_config(1, 1, |config| {
+            config.compression = Some(compress::CompressionConfig {
+                level: compress::DEFAULT_LEVEL,
+            })
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        let value = "a log line compressed on its way to the volumes\n".repeat(100);
+        let stored = |key: &str| {
+            let volume = cluster.volume(0);
+            let remote_path = record::get_remote_path(key);
+            let path = volume
+                .paths()
+                .into_iter()
+                .find(|path| path.ends_with(&remote_path));
+            volume.get(&path.unwrap()).unwrap()
+        };
+
+        let res = client
+            .put(cluster.key_url("log"))
+            .body(value.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let compressed = stored("log");
+        assert!(compressed.len() < value.len());
+
+        // The record keeps the size and hash of the value as uploaded
+        let res = client.head(cluster.key_url("log")).send().await?;
+        assert_eq!(
+            res.headers()["content-length"],
+            value.len().to_string().as_str()
+        );
+        assert_eq!(
+            res.headers()["content-md5"],
+            checksum::md5_hex(value.as_bytes()).as_str()
+        );
+
+        let res = client.get(cluster.key_url("log")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["vary"], "accept-encoding");
+        assert!(!res.headers().contains_key("content-encoding"));
+        assert_eq!(res.text().await?, value);
+
+        let res = client
+            .get(cluster.key_url("log"))
+            .header("Accept-Encoding", "gzip, zstd")
+            .send()
+            .await?;
+        assert_eq!(res.headers()["content-encoding"], "zstd");
+        assert_eq!(
+            res.headers()["content-length"],
+            compressed.len().to_string().as_str()
+        );
+        assert_eq!(res.bytes().await?, compressed);
+
+        // Compressed formats and values the client encoded are stored as uploaded
+        for (key, name, header) in [
+            ("png", "Content-Type", "image/png"),
+            ("gz", "Content-Encoding", "gzip"),
+        ] {
+            let res = client
+                .put(cluster.key_url(key))
+                .header(name, header)
+                .body(value.clone())
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(stored(key), value.as_bytes());
+        }
+        let res = client.get(cluster.key_url("png")).send().await?;
+        assert!(!res.headers().contains_key("vary"));
+        assert_eq!(res.text().await?, value);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encryption() -> anyhow::Result<()> {
+        let key_file = tempfile::NamedTempFile::new()?;
+        std::fs::write(key_file.path(), checksum::encode_hex(&[42; 32]))?;
+        let master_key = encryption::MasterKey::load(key_file.path())?;
+        let cluster = TestCluster::start_with_config(1, 1, |config| {
+            config.encryption = Some(master_key.clone())
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        // Several segments, the last one shorter
+        let value = "a secret kept from the volumes ".repeat(5000);
+
+        let res = client
+            .put(cluster.key_url("secret"))
+            .body(value.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let volume = cluster.volume(0);
+        let stored = volume.get(&volume.paths()[0]).unwrap();
+        assert_eq!(
+            stored.len() as u64,
+            encryption::sealed_size(value.len() as u64)
+        );
+        assert!(!stored
+            .windows(16)
+            .any(|window| window == &value.as_bytes()[..16]));
+
+        let res = client.head(cluster.key_url("secret")).send().await?;
+        assert_eq!(
+            res.headers()["content-length"],
+            value.len().to_string().as_str()
+        );
+        let res = client.get(cluster.key_url("secret")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, value);
+
+        // Erasure coded values are encrypted before they are split into shards
+        let cluster = TestCluster::start_with_config(3, 1, |config| {
+            config.encryption = Some(master_key);
+            config.erasure = Some(erasure::ErasureConfig {
+                data_shards: 2,
+                parity_shards: 1,
+                min_size: 64,
+            })
+        })
+        .await?;
+        let res = client
+            .put(cluster.key_url("archive"))
+            .body(value.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!((0..3).all(|index| cluster.volume(index).len() == 1));
+        let res = client.get(cluster.key_url("archive")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, value);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_presign() -> anyhow::Result<()> {
+        let key_file = tempfile::NamedTempFile::new()?;
+        std::fs::write(key_file.path(), "a presign key shared with the volumes")?;
+        let key = presign::PresignKey::load(key_file.path())?;
+        let cluster = TestCluster::start_with_config(1, 1, |config| {
+            config.presign = Some(presign::PresignConfig {
+                key,
+                public_volumes: HashMap::new(),
+            });
+            config.compression = Some(compress::CompressionConfig {
+                level: compress::DEFAULT_LEVEL,
+            })
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        client
+            .put(cluster.key_url("onyou"))
+            .body("onyou")
+            .send()
+            .await?;
+
+        let res = client
+            .get(format!("{}?presign&expires=60", cluster.key_url("onyou")))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let url = res.text().await?;
+        assert!(url.starts_with(&format!("http://{}/", cluster.volume_addrs()[0])));
+        assert_eq!(client.get(&url).send().await?.text().await?, "onyou");
+
+        // The nginx auth_request hook checks the URI of the presigned URL
+        let url = reqwest::Url::parse(&url)?;
+        let original_uri = format!("{}?{}", url.path(), url.query().unwrap_or_default());
+        let verify_url = format!("{}/presign/verify", cluster.url());
+        let res = client
+            .get(&verify_url)
+            .header("X-Original-URI", &original_uri)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .get(&verify_url)
+            .header(
+                "X-Original-URI",
+                original_uri.replace("expires=", "expires=1"),
+            )
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = client.get(&verify_url).send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        for query in ["presign&expires=0", "presign&expires=604801"] {
+            let res = client
+                .get(format!("{}?{}", cluster.key_url("onyou"), query))
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // Compressed values are only returned through the index
+        client
+            .put(cluster.key_url("text"))
+            .body("a compressed value ".repeat(100))
+            .send()
+            .await?;
+        let res = client
+            .get(format!("{}?presign", cluster.key_url("text")))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let cluster = TestCluster::start(1, 1).await?;
+        let res = client
+            .get(format!("{}?presign", cluster.key_url("onyou")))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_presign_upload() -> anyhow::Result<()> {
+        let key_file = tempfile::NamedTempFile::new()?;
+        std::fs::write(key_file.path(), "a presign key shared with the volumes")?;
+        let key = presign::PresignKey::load(key_file.path())?;
+        let cluster = TestCluster::start_with_config(3, 2, |config| {
+            config.presign = Some(presign::PresignConfig {
+                key,
+                public_volumes: HashMap::new(),
+            })
+        })
+        .await?;
+        let client = reqwest::Client::new();
+        let presign_url = format!("{}?presign&expires=60", cluster.key_url("upload"));
+
+        let upload: serde_json::Value = client.post(&presign_url).send().await?.json().await?;
+        let urls = upload["urls"].as_array().unwrap();
+        assert!(!urls.is_empty());
+        assert_eq!(upload["complete"], "/upload?presign=complete");
+        for url in urls {
+            let res = client
+                .put(url.as_str().unwrap())
+                .body("onyou")
+                .send()
+                .await?;
+            assert!(res.status().is_success());
+        }
+        let res = client.get(cluster.key_url("upload")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let complete_url = format!("{}{}", cluster.url(), upload["complete"].as_str().unwrap());
+        let res = client
+            .post(&complete_url)
+            .header(reqwest::header::CONTENT_TYPE, "text/plain")
+            .header(checksum::CONTENT_MD5, checksum::md5_hex(b"onyou"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.head(cluster.key_url("upload")).send().await?;
+        assert_eq!(res.headers()[reqwest::header::CONTENT_TYPE], "text/plain");
+        assert_eq!(res.headers()[reqwest::header::CONTENT_LENGTH], "5");
+        let res = client.get(cluster.key_url("upload")).send().await?;
+        assert_eq!(res.text().await?, "onyou");
+        let remote_path = record::get_remote_path("upload");
+        let holding = (0..3)
+            .filter(|index| {
+                cluster
+                    .volume(*index)
+                    .paths()
+                    .iter()
+                    .any(|path| path.ends_with(&remote_path))
+            })
+            .count();
+        assert_eq!(holding, urls.len());
+        assert!((0..3).all(|index| cluster
+            .volume(index)
+            .paths()
+            .iter()
+            .all(|path| !path.ends_with(UPLOAD_SUFFIX))));
+
+        let res = client.post(&presign_url).send().await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = client.post(&complete_url).send().await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // Every replica must hold the upload, and the Content-Md5 must match it
+        let presign_url = format!("{}?presign", cluster.key_url("partial"));
+        let upload: serde_json::Value = client.post(&presign_url).send().await?.json().await?;
+        let urls = upload["urls"].as_array().unwrap();
+        let complete_url = format!("{}{}", cluster.url(), upload["complete"].as_str().unwrap());
+        client
+            .put(urls[0].as_str().unwrap())
+            .body("onme")
+            .send()
+            .await?;
+        let res = client.post(&complete_url).send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        for url in urls {
+            client
+                .put(url.as_str().unwrap())
+                .body("onme")
+                .send()
+                .await?;
+        }
+        let res = client
+            .post(&complete_url)
+            .header(checksum::CONTENT_MD5, checksum::md5_hex(b"onyou"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res = client.post(&complete_url).send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("multipart");
+
+        for (number, part) in [(2, "you"), (1, "on")] {
+            let res = client
+                .put(format!("{}?partNumber={}", url, number))
+                .body(part)
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = client
+            .put(format!("{}?partNumber=0", url))
+            .body("x")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // Parts are not readable or listed until the upload is completed
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let complete = format!("{}?uploads=complete", url);
+        let res = client.post(&complete).body("[1, 3]").send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = client.post(&complete).body("[2, 1]").send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = client.post(&complete).body("[1, 2]").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let expected_md5 = checksum::multipart_md5_hex(
+            [checksum::md5_hex(b"on"), checksum::md5_hex(b"you")]
+                .iter()
+                .map(String::as_str),
+        );
+        assert_eq!(res.headers()["content-md5"], expected_md5.as_str());
+        assert_eq!(res.text().await?, "onyou");
+
+        let res = client.head(&url).send().await?;
+        assert_eq!(res.headers()["content-length"], "5");
+
+        let page: serde_json::Value = client
+            .get(cluster.key_url("multipart?list"))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(page["keys"], serde_json::json!(["/multipart"]));
+
+        let res = client
+            .put(format!("{}?partNumber=3", url))
+            .body("x")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = client.post(&complete).body("[1, 2]").send().await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_multipart_upload_part_key_collision() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        // A key spelling the query of a part upload is a key like any other
+        let user_url = cluster.key_url("upload%3FpartNumber=1");
+        let res = client.put(&user_url).body("mine").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let url = cluster.key_url("upload");
+        let res = client
+            .put(format!("{}?partNumber=1", url))
+            .body("part")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client
+            .post(format!("{}?uploads=complete", url))
+            .body("[1]")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.text().await?, "part");
+        let res = client.get(&user_url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "mine");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,181 @@
+use http_body_util::BodyExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::{checksum, compress, encryption, erasure};
+
+/// Size of the chunks a spooled value is read back in when streamed to a volume.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Error of a request body larger than the max value size, returned by `SpooledValue::spool` as soon as
+/// the body exceeds it.
+#[derive(Debug)]
+pub(crate) struct TooLarge {
+    pub(crate) max_size: u64,
+}
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "body exceeds the max value size of {} bytes",
+            self.max_size
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Struct representing a request body spooled to a temporary file, so memory use doesn't depend
+/// on the size of the value. The file is removed when the value is dropped.
+pub(crate) struct SpooledValue {
+    path: tempfile::TempPath,
+    size: u64,
+    digests: Vec<(checksum::Algorithm, Vec<u8>)>,
+    trailers: Option<axum::http::HeaderMap>,
+}
+
+impl SpooledValue {
+    /// Writes a request body to a temporary file, computing the digest of every algorithm as the
+    /// chunks arrive. The file is created in the system temporary directory, `TMPDIR` on unix.
+    /// Stops reading with a `TooLarge` error once the body exceeds the max size, if any.
+    #[tracing::instrument(name = "spool", skip_all)]
+    pub(crate) async fn spool(
+        mut body: axum::body::Body,
+        algorithms: &[checksum::Algorithm],
+        max_size: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::from_std(file));
+        let mut hasher = checksum::Hasher::new(algorithms);
+        let mut size = 0;
+        let mut trailers = None;
+        while let Some(frame) = body.frame().await {
+            match frame?.into_data() {
+                Ok(data) => {
+                    size += data.len() as u64;
+                    if let Some(max_size) = max_size.filter(|max_size| size > *max_size) {
+                        return Err(TooLarge { max_size }.into());
+                    }
+                    hasher.update(&data);
+                    file.write_all(&data).await?;
+                }
+                Err(frame) => trailers = frame.into_trailers().ok(),
+            }
+        }
+        file.flush().await?;
+
+        Ok(Self {
+            path,
+            size,
+            digests: hasher.finalize(),
+            trailers,
+        })
+    }
+
+    /// Compresses the value into another temporary file. The digests are the ones of the uncompressed value,
+    /// so the record keeps the hash and checksums of the value as uploaded.
+    pub(crate) async fn compress(
+        &self,
+        compression: &compress::CompressionConfig,
+    ) -> anyhow::Result<Self> {
+        let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::from_std(file));
+        let reader = tokio::io::BufReader::new(tokio::fs::File::open(&self.path).await?);
+        let mut encoder = std::pin::pin!(compression.encoder(reader));
+        let size = tokio::io::copy(&mut encoder, &mut file).await?;
+        file.flush().await?;
+
+        Ok(Self {
+            path,
+            size,
+            digests: self.digests.clone(),
+            trailers: None,
+        })
+    }
+
+    /// Encrypts the value with the data key into another temporary file, keeping the digests of
+    /// the value in the clear like `compress`.
+    pub(crate) async fn encrypt(&self, data_key: &encryption::DataKey) -> anyhow::Result<Self> {
+        let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
+        let file = tokio::io::BufWriter::new(tokio::fs::File::from_std(file));
+        let reader = tokio::io::BufReader::new(tokio::fs::File::open(&self.path).await?);
+        let size = data_key.encrypt(reader, file).await?;
+
+        Ok(Self {
+            path,
+            size,
+            digests: self.digests.clone(),
+            trailers: None,
+        })
+    }
+
+    /// Returns the size of the value in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the raw digest of the value, None if the algorithm was not computed.
+    pub(crate) fn digest(&self, algorithm: checksum::Algorithm) -> Option<&[u8]> {
+        self.digests
+            .iter()
+            .find(|(computed, _)| *computed == algorithm)
+            .map(|(_, digest)| digest.as_slice())
+    }
+
+    /// Returns the trailers sent after the body, if any.
+    pub(crate) fn trailers(&self) -> Option<&axum::http::HeaderMap> {
+        self.trailers.as_ref()
+    }
+
+    /// Splits the value into the erasure coded shards, each in another temporary file, reading the
+    /// value a stripe at a time. Returns the data shards followed by the parity shards, and their MD5.
+    pub(crate) async fn encode(
+        &self,
+        erasure: erasure::ErasureConfig,
+    ) -> anyhow::Result<(Vec<Self>, Vec<String>)> {
+        let path = self.path.to_path_buf();
+        let size = self.size;
+        tokio::task::spawn_blocking(move || {
+            let mut value = std::fs::File::open(path)?;
+            let (mut files, paths): (Vec<_>, Vec<_>) = (0..erasure.shards())
+                .map(|_| {
+                    let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
+                    Ok((std::io::BufWriter::new(file), path))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .into_iter()
+                .unzip();
+            let hashes = erasure::encode(
+                &mut value,
+                size,
+                erasure.data_shards,
+                erasure.parity_shards,
+                &mut files,
+            )?;
+            for file in &mut files {
+                std::io::Write::flush(file)?;
+            }
+
+            let shard_size = erasure::shard_size(size, erasure.data_shards);
+            let shards = paths
+                .into_iter()
+                .map(|path| Self {
+                    path,
+                    size: shard_size,
+                    digests: Vec::new(),
+                    trailers: None,
+                })
+                .collect();
+            Ok((shards, hashes))
+        })
+        .await?
+    }
+
+    /// Opens the value as a request body streamed from the spool file.
+    pub(crate) async fn body(&self) -> anyhow::Result<reqwest::Body> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        Ok(reqwest::Body::wrap_stream(
+            tokio_util::io::ReaderStream::with_capacity(file, READ_CHUNK_SIZE),
+        ))
+    }
+}
@@ -0,0 +1,276 @@
+use futures::future::BoxFuture;
+use log::{debug, error, info};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::record;
+
+/// Type representing the body of a periodic task, called once per run.
+pub(crate) type TaskFn = Arc<dyn Fn() -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+/// Struct representing the status of a periodic task as reported by the admin API.
+#[derive(Debug, Default, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct TaskStatus {
+    name: String,
+    interval_secs: u64,
+    paused: bool,
+    running: bool,
+    runs: u64,
+    failures: u64,
+    last_run_at: Option<u64>,
+    last_duration_ms: Option<u64>,
+    last_error: Option<String>,
+}
+
+/// Struct representing a task registered in the scheduler.
+struct Task {
+    interval: Duration,
+    paused: AtomicBool,
+    trigger: Notify,
+    status: Mutex<TaskStatus>,
+    run: TaskFn,
+}
+
+impl Task {
+    /// Runs the task once and records the outcome in its status.
+    async fn run_once(&self, name: &str) {
+        self.status.lock().running = true;
+        let started_at = std::time::Instant::now();
+
+        let result = (self.run)().await;
+
+        let mut status = self.status.lock();
+        status.running = false;
+        status.runs += 1;
+        status.last_run_at = Some(record::unix_now());
+        status.last_duration_ms = Some(started_at.elapsed().as_millis() as u64);
+        match result {
+            Ok(()) => {
+                debug!("tasks: {} finished", name);
+                status.last_error = None;
+            }
+            Err(e) => {
+                error!("tasks: {} failed: {}", name, e);
+                status.failures += 1;
+                status.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Returns a snapshot of the status of the task.
+    fn status(&self) -> TaskStatus {
+        let mut status = self.status.lock().clone();
+        status.paused = self.paused.load(Ordering::Relaxed);
+        status
+    }
+}
+
+/// Struct representing the scheduler that owns every periodic background job.
+/// Each task runs in its own loop on a fixed interval, can be paused, resumed and triggered
+/// on demand, and reports its status. Intervals can be overridden per task, an interval of
+/// zero registers the task paused so it only runs when triggered.
+pub(crate) struct Scheduler {
+    schedules: HashMap<String, Duration>,
+    tasks: RwLock<BTreeMap<String, Arc<Task>>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    shutdown: tokio::sync::watch::Sender<bool>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler with the per task interval overrides.
+    pub(crate) fn new(schedules: HashMap<String, Duration>) -> Self {
+        Self {
+            schedules,
+            tasks: RwLock::new(BTreeMap::new()),
+            handles: Mutex::new(Vec::new()),
+            shutdown: tokio::sync::watch::channel(false).0,
+        }
+    }
+
+    /// Registers a task running every interval, unless overridden by the schedules,
+    /// and spawns its loop. The first run happens after one interval.
+    pub(crate) fn register(&self, name: &str, interval: Duration, run: TaskFn) {
+        let interval = self.schedules.get(name).copied().unwrap_or(interval);
+        let paused = interval.is_zero();
+        let task = Arc::new(Task {
+            interval,
+            paused: AtomicBool::new(paused),
+            trigger: Notify::new(),
+            status: Mutex::new(TaskStatus {
+                name: name.to_string(),
+                interval_secs: interval.as_secs(),
+                ..Default::default()
+            }),
+            run,
+        });
+        self.tasks.write().insert(name.to_string(), task.clone());
+        info!(
+            "tasks: registered {} every {:?}{}",
+            name,
+            interval,
+            if paused { " (paused)" } else { "" }
+        );
+
+        let name = name.to_string();
+        let mut shutdown = self.shutdown.subscribe();
+        let handle = tokio::spawn(async move {
+            loop {
+                let triggered = tokio::select! {
+                    _ = shutdown.changed() => return,
+                    _ = task.trigger.notified() => true,
+                    _ = Self::tick(task.interval) => false,
+                };
+                if triggered || !task.paused.load(Ordering::Relaxed) {
+                    task.run_once(&name).await;
+                }
+            }
+        });
+        self.handles.lock().push(handle);
+    }
+
+    /// Waits for the next scheduled run, forever if the interval is zero.
+    async fn tick(interval: Duration) {
+        if interval.is_zero() {
+            std::future::pending::<()>().await;
+        }
+        tokio::time::sleep(interval).await;
+    }
+
+    /// Pauses the scheduled runs of a task. Returns false if the task does not exist.
+    pub(crate) fn pause(&self, name: &str) -> bool {
+        self.with_task(name, |task| task.paused.store(true, Ordering::Relaxed))
+    }
+
+    /// Resumes the scheduled runs of a task. Returns false if the task does not exist.
+    pub(crate) fn resume(&self, name: &str) -> bool {
+        self.with_task(name, |task| task.paused.store(false, Ordering::Relaxed))
+    }
+
+    /// Triggers a run of a task as soon as possible, even if paused.
+    /// Returns false if the task does not exist.
+    pub(crate) fn trigger(&self, name: &str) -> bool {
+        self.with_task(name, |task| task.trigger.notify_one())
+    }
+
+    /// Returns the status of a task, None if the task does not exist.
+    pub(crate) fn status(&self, name: &str) -> Option<TaskStatus> {
+        self.tasks.read().get(name).map(|task| task.status())
+    }
+
+    /// Returns the status of every task sorted by name.
+    pub(crate) fn statuses(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .read()
+            .values()
+            .map(|task| task.status())
+            .collect()
+    }
+
+    /// Stops every task loop, waiting for runs in progress to finish.
+    pub(crate) async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        let handles = std::mem::take(&mut *self.handles.lock());
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+
+    /// Calls the closure with the task if it exists.
+    fn with_task(&self, name: &str, f: impl FnOnce(&Task)) -> bool {
+        match self.tasks.read().get(name) {
+            Some(task) => {
+                f(task);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+
+    fn counting_task(counter: Arc<AtomicU64>) -> TaskFn {
+        Arc::new(move || {
+            let counter = counter.clone();
+            Box::pin(async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    anyhow::bail!("first run fails");
+                }
+                Ok(())
+            })
+        })
+    }
+
+    async fn wait_for_runs(scheduler: &Scheduler, name: &str, runs: u64) -> TaskStatus {
+        for _ in 0..200 {
+            let status = scheduler.status(name).unwrap();
+            if status.runs >= runs {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("task {} did not reach {} runs", name, runs);
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_runs_on_interval() {
+        let scheduler = Scheduler::new(HashMap::new());
+        let counter = Arc::new(AtomicU64::new(0));
+        scheduler.register(
+            "count",
+            Duration::from_millis(10),
+            counting_task(counter.clone()),
+        );
+
+        let status = wait_for_runs(&scheduler, "count", 2).await;
+        assert!(status.failures >= 1);
+        assert!(status.last_run_at.is_some());
+
+        scheduler.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_pause_resume_trigger() {
+        let schedules = HashMap::from([("count".to_string(), Duration::ZERO)]);
+        let scheduler = Scheduler::new(schedules);
+        let counter = Arc::new(AtomicU64::new(0));
+        scheduler.register(
+            "count",
+            Duration::from_millis(10),
+            counting_task(counter.clone()),
+        );
+
+        let status = scheduler.status("count").unwrap();
+        assert!(status.paused);
+        assert_eq!(status.interval_secs, 0);
+        assert_eq!(status.runs, 0);
+
+        assert!(scheduler.trigger("count"));
+        let status = wait_for_runs(&scheduler, "count", 1).await;
+        assert_eq!(status.failures, 1);
+        assert_eq!(status.last_error.as_deref(), Some("first run fails"));
+
+        assert!(scheduler.resume("count"));
+        assert!(!scheduler.status("count").unwrap().paused);
+        assert!(scheduler.pause("count"));
+        assert!(scheduler.status("count").unwrap().paused);
+
+        assert!(!scheduler.pause("missing"));
+        assert!(!scheduler.trigger("missing"));
+        assert_eq!(scheduler.statuses().len(), 1);
+
+        scheduler.shutdown().await;
+    }
+}
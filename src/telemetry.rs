@@ -0,0 +1,121 @@
+use anyhow::Context;
+use log::{error, info};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Name of the service the spans are exported under.
+const SERVICE_NAME: &str = "rust-minikeyvalue";
+
+/// Struct representing the export of the `tracing` spans of the server to an OTLP collector.
+/// Spans are exported in batches, the pending ones are flushed when it is dropped.
+pub struct Telemetry {
+    provider: opentelemetry_sdk::trace::TracerProvider,
+}
+
+impl Telemetry {
+    /// Exports the spans to the OTLP gRPC endpoint of a collector, e.g. `http://localhost:4317`,
+    /// installing the global `tracing` subscriber. Logs are still written by the `log` logger.
+    /// Must be called from a tokio runtime, which runs the batch exports.
+    pub fn init(endpoint: &str) -> anyhow::Result<Self> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .with_context(|| format!("failed to create the OTLP exporter of {}", endpoint))?;
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+            .with_resource(opentelemetry_sdk::Resource::new([
+                opentelemetry::KeyValue::new("service.name", SERVICE_NAME),
+            ]))
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+            .context("failed to install the tracing subscriber")?;
+        info!("telemetry: exporting spans to {}", endpoint);
+        Ok(Self { provider })
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            error!("telemetry: failed to flush the spans: {}", e);
+        }
+    }
+}
+
+/// Middleware running each request in a span, the parent of the spans of its lock waits,
+/// volume requests and metadata store operations. Spans are free without a subscriber.
+pub(crate) async fn trace_requests(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", request.method(), request.uri().path()),
+        http.request.method = %request.method(),
+        url.path = %request.uri().path(),
+        http.response.status_code = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record(
+        "http.response.status_code",
+        response.status().as_u16() as u64,
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestCluster;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Layer collecting the names of the spans created.
+    #[derive(Clone, Default)]
+    struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanNames {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            self.0.lock().push(attrs.metadata().name());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_requests() -> anyhow::Result<()> {
+        // The test runtime runs the cluster on this thread, so the subscriber sees its spans
+        let names = SpanNames::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(names.clone()));
+
+        let cluster = TestCluster::start(3, 2).await?;
+        let res = reqwest::Client::new()
+            .put(cluster.key_url("traced"))
+            .body("value")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let names = names.0.lock();
+        for name in [
+            "request",
+            "lock_wait",
+            "spool",
+            "put_replicas",
+            "volume_request",
+            "metadata_put",
+        ] {
+            assert!(names.contains(&name), "{} not in {:?}", name, names);
+        }
+
+        Ok(())
+    }
+}
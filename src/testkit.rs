@@ -0,0 +1,478 @@
+use axum::http::{Method, StatusCode};
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::task::JoinHandle;
+
+use crate::{auth, checksum, gc, health, keys, rebuild, server};
+
+/// Struct representing an in-memory volume server.
+/// Stores values by request path and answers PUT, GET, HEAD and DELETE like the nginx volumes.
+#[derive(Clone, Default)]
+pub struct MemoryVolume {
+    values: Arc<RwLock<HashMap<String, bytes::Bytes>>>,
+    unavailable: Arc<AtomicBool>,
+    failing_moves: Arc<AtomicBool>,
+}
+
+impl MemoryVolume {
+    /// Returns the value stored at the given path of the volume.
+    pub fn get(&self, path: &str) -> Option<bytes::Bytes> {
+        self.values.read().get(path).cloned()
+    }
+
+    /// Returns the paths of the values stored in the volume.
+    pub fn paths(&self) -> Vec<String> {
+        self.values.read().keys().cloned().collect()
+    }
+
+    /// Returns the number of values stored in the volume.
+    pub fn len(&self) -> usize {
+        self.values.read().len()
+    }
+
+    /// Returns true if the volume stores no values.
+    pub fn is_empty(&self) -> bool {
+        self.values.read().is_empty()
+    }
+
+    /// Lists a directory of the volume like nginx `autoindex_format json`, sorted by name.
+    pub(crate) fn list_dir(&self, dir: &str) -> Vec<rebuild::DirEntry> {
+        let mut entries = std::collections::BTreeMap::new();
+        for (path, value) in self.values.read().iter() {
+            let Some(rest) = path.strip_prefix(dir) else {
+                continue;
+            };
+            let entry = match rest.split_once('/') {
+                Some((name, _)) => rebuild::DirEntry {
+                    name: name.to_string(),
+                    kind: "directory".to_string(),
+                    size: 0,
+                },
+                None => rebuild::DirEntry {
+                    name: rest.to_string(),
+                    kind: "file".to_string(),
+                    size: value.len() as u64,
+                },
+            };
+            entries.insert(entry.name.clone(), entry);
+        }
+        entries.into_values().collect()
+    }
+
+    /// Removes every value stored in the volume, simulating a lost disk.
+    pub fn clear(&self) {
+        self.values.write().clear();
+    }
+
+    /// Makes the volume answer every request with 503, simulating an outage, or serve again.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.unavailable.store(unavailable, Ordering::SeqCst);
+    }
+
+    /// Makes the volume answer every MOVE with 500, leaving the value where it is, or move again.
+    /// Simulates a staged upload the volume fails to move into place.
+    pub fn set_failing_moves(&self, failing: bool) {
+        self.failing_moves.store(failing, Ordering::SeqCst);
+    }
+
+    /// Creates the axum router serving the volume.
+    fn router(&self) -> axum::Router {
+        axum::Router::new()
+            .fallback(handle_volume_request)
+            .layer(axum::extract::DefaultBodyLimit::disable())
+            .with_state(self.clone())
+    }
+}
+
+/// Handles every request made to an in-memory volume.
+/// A WebDAV MOVE renames a value to the path of its `Destination` header, a COPY duplicates it there.
+async fn handle_volume_request(
+    axum::extract::State(volume): axum::extract::State<MemoryVolume>,
+    method: Method,
+    uri: axum::http::Uri,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if volume.unavailable.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    let path = uri.path().to_string();
+    match method {
+        Method::PUT => {
+            let created = volume.values.write().insert(path, body).is_none();
+            if created {
+                StatusCode::CREATED.into_response()
+            } else {
+                StatusCode::NO_CONTENT.into_response()
+            }
+        }
+        Method::GET if path.ends_with('/') => axum::Json(volume.list_dir(&path)).into_response(),
+        Method::GET | Method::HEAD => match volume.get(&path) {
+            Some(value) => value.into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        Method::DELETE => match volume.values.write().remove(&path) {
+            Some(_) => StatusCode::NO_CONTENT.into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        method if method.as_str() == "MOVE" && volume.failing_moves.load(Ordering::SeqCst) => {
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        method if method.as_str() == "MOVE" || method.as_str() == "COPY" => {
+            let Some(destination) = headers
+                .get("Destination")
+                .and_then(|destination| destination.to_str().ok())
+                .and_then(|destination| destination.parse::<axum::http::Uri>().ok())
+            else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            let mut values = volume.values.write();
+            let value = match method.as_str() {
+                "MOVE" => values.remove(&path),
+                _ => values.get(&path).cloned(),
+            };
+            let Some(value) = value else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            match values.insert(destination.path().to_string(), value) {
+                None => StatusCode::CREATED.into_response(),
+                Some(_) => StatusCode::NO_CONTENT.into_response(),
+            }
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+/// Struct representing a cluster running inside the current tokio runtime.
+/// The index server and every volume listen on ephemeral localhost ports.
+/// All servers are stopped when the cluster is dropped.
+///
+/// ```
+/// use rust_minikeyvalue::testkit::TestCluster;
+///
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let cluster = TestCluster::start(3, 2).await?;
+/// let client = reqwest::Client::new();
+/// client.put(cluster.key_url("wehave")).body("bigswag").send().await?;
+/// let value = client.get(cluster.key_url("wehave")).send().await?.text().await?;
+/// assert_eq!(value, "bigswag");
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestCluster {
+    url: String,
+    internal_url: Option<String>,
+    s3_url: Option<String>,
+    resp_addr: std::net::SocketAddr,
+    grpc_url: String,
+    volume_addrs: Vec<String>,
+    volumes: Vec<MemoryVolume>,
+    volume_handles: Vec<JoinHandle<()>>,
+    server_handle: JoinHandle<()>,
+    draining: Arc<AtomicBool>,
+    _leveldb_dir: tempfile::TempDir,
+}
+
+impl TestCluster {
+    /// Starts an index server backed by a fresh leveldb and the given number of in-memory volumes.
+    pub async fn start(volumes: usize, replicas: usize) -> anyhow::Result<Self> {
+        Self::start_with(volumes, replicas, false, |_| ()).await
+    }
+
+    /// Starts a cluster whose index server only serves reads on its url,
+    /// PUT, DELETE and /admin are served on the internal url.
+    pub async fn start_split(volumes: usize, replicas: usize) -> anyhow::Result<Self> {
+        Self::start_with(volumes, replicas, true, |_| ()).await
+    }
+
+    /// Starts a cluster whose PUTs succeed once the given number of replica writes succeed.
+    pub async fn start_with_write_quorum(
+        volumes: usize,
+        replicas: usize,
+        write_quorum: usize,
+    ) -> anyhow::Result<Self> {
+        Self::start_with_config(volumes, replicas, |config| {
+            config.write_quorum = Some(write_quorum)
+        })
+        .await
+    }
+
+    /// Starts a cluster whose PUTs upload the replicas to a temporary path before moving them into place.
+    pub async fn start_with_two_phase_writes(
+        volumes: usize,
+        replicas: usize,
+    ) -> anyhow::Result<Self> {
+        Self::start_with_config(volumes, replicas, |config| config.two_phase_writes = true).await
+    }
+
+    /// Starts a cluster whose index server requires one of the bearer tokens.
+    pub async fn start_with_auth(
+        volumes: usize,
+        replicas: usize,
+        tokens: auth::Tokens,
+    ) -> anyhow::Result<Self> {
+        Self::start_with_config(volumes, replicas, |config| config.auth = Some(tokens)).await
+    }
+
+    /// Starts a cluster whose index server configuration is changed by the closure first,
+    /// e.g. to enable a flag the other constructors leave to its default.
+    pub async fn start_with_config(
+        volumes: usize,
+        replicas: usize,
+        configure: impl FnOnce(&mut server::Config),
+    ) -> anyhow::Result<Self> {
+        Self::start_with(volumes, replicas, false, configure).await
+    }
+
+    /// Starts a cluster, with an internal listener if split.
+    /// The S3 gateway is served too, unless bearer tokens are required, the Redis protocol and the gRPC service.
+    async fn start_with(
+        volumes: usize,
+        replicas: usize,
+        split: bool,
+        configure: impl FnOnce(&mut server::Config),
+    ) -> anyhow::Result<Self> {
+        if volumes < replicas {
+            anyhow::bail!(
+                "Need at least as many volumes: {} as replicas: {}",
+                volumes,
+                replicas
+            );
+        }
+
+        let mut volume_addrs = Vec::with_capacity(volumes);
+        let mut memory_volumes = Vec::with_capacity(volumes);
+        let mut volume_handles = Vec::with_capacity(volumes);
+        for _ in 0..volumes {
+            let volume = MemoryVolume::default();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            volume_addrs.push(listener.local_addr()?.to_string());
+            let router = volume.router();
+            volume_handles.push(tokio::spawn(async move {
+                let _ = axum::serve(listener, router).await;
+            }));
+            memory_volumes.push(volume);
+        }
+
+        let leveldb_dir = tempfile::tempdir()?;
+        let mut config = server::Config {
+            leveldb_path: leveldb_dir.path().to_path_buf(),
+            db_backend: Default::default(),
+            record_encoding: Default::default(),
+            record_cache: None,
+            negative_cache: None,
+            value_cache: None,
+            verify_checksums: true,
+            hash_algorithm: checksum::Algorithm::Md5,
+            checksum_algorithms: Vec::new(),
+            volumes: volume_addrs.clone(),
+            default_proxy: false,
+            redirect_status: server::RedirectStatus::default(),
+            redirect_cache_control: Some(server::DEFAULT_REDIRECT_CACHE_CONTROL.to_string()),
+            concurrent_heads: false,
+            replicas,
+            max_replicas: None,
+            namespace_replicas: HashMap::new(),
+            key_policy: keys::KeyPolicy::default(),
+            max_value_size: None,
+            write_quorum: None,
+            subvolumes: 10,
+            volume_groups: Vec::new(),
+            placement_rules: Vec::new(),
+            task_schedules: Default::default(),
+            lock_timeout: std::time::Duration::from_secs(1),
+            shutdown_timeout: std::time::Duration::from_secs(1),
+            reload_ring: None,
+            tls: None,
+            volume_tls: Default::default(),
+            volume_client: Default::default(),
+            auth: None,
+            ready_fraction: health::DEFAULT_READY_FRACTION,
+            write_retry: Default::default(),
+            two_phase_writes: false,
+            erasure: None,
+            dedup: false,
+            compression: None,
+            encryption: None,
+            presign: None,
+            breaker: Default::default(),
+            fanout: Default::default(),
+            volume_connect_timeout: None,
+            volume_request_timeout: None,
+            request_deadline: None,
+            gc_grace_period: gc::DEFAULT_GRACE_PERIOD,
+            auto_rebalance: None,
+            access_log: true,
+            limits: Default::default(),
+            cors: None,
+        };
+        configure(&mut config);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let internal_listener = if split {
+            Some(tokio::net::TcpListener::bind("127.0.0.1:0").await?)
+        } else {
+            None
+        };
+        let internal_url = match internal_listener.as_ref() {
+            Some(internal_listener) => Some(format!("http://{}", internal_listener.local_addr()?)),
+            None => None,
+        };
+        let s3_listener = if config.auth.is_none() {
+            Some(tokio::net::TcpListener::bind("127.0.0.1:0").await?)
+        } else {
+            None
+        };
+        let s3_url = match s3_listener.as_ref() {
+            Some(s3_listener) => Some(format!("http://{}", s3_listener.local_addr()?)),
+            None => None,
+        };
+        let resp_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let resp_addr = resp_listener.local_addr()?;
+        let grpc_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let grpc_url = format!("http://{}", grpc_listener.local_addr()?);
+        let listeners = server::Listeners::new(listener)
+            .with_internal(internal_listener)
+            .with_s3(s3_listener)
+            .with_resp(Some(resp_listener))
+            .with_grpc(Some(grpc_listener));
+        let server = server::Server::new(config);
+        let draining = server.draining();
+        let server_handle = tokio::spawn(async move {
+            let shutdown = std::future::pending();
+            if let Err(e) = server.serve(listeners, shutdown).await {
+                log::error!("testkit: server failed: {}", e);
+            }
+        });
+
+        Ok(Self {
+            url,
+            internal_url,
+            s3_url,
+            resp_addr,
+            grpc_url,
+            volume_addrs,
+            volumes: memory_volumes,
+            volume_handles,
+            server_handle,
+            draining,
+            _leveldb_dir: leveldb_dir,
+        })
+    }
+
+    /// Returns the base url of the index server, e.g. `http://127.0.0.1:34567`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the base url of the internal listener of a split cluster, None otherwise.
+    pub fn internal_url(&self) -> Option<&str> {
+        self.internal_url.as_deref()
+    }
+
+    /// Returns the base url of the S3 gateway, None if the cluster requires bearer tokens.
+    pub fn s3_url(&self) -> Option<&str> {
+        self.s3_url.as_deref()
+    }
+
+    /// Returns the address of the RESP listener, for Redis clients.
+    pub fn resp_addr(&self) -> std::net::SocketAddr {
+        self.resp_addr
+    }
+
+    /// Returns the url of the gRPC listener, e.g. `http://127.0.0.1:34570`.
+    pub fn grpc_url(&self) -> &str {
+        &self.grpc_url
+    }
+
+    /// Returns the url of a key in the index server.
+    pub fn key_url(&self, key: &str) -> String {
+        format!("{}/{}", self.url, key)
+    }
+
+    /// Returns the addresses of the volumes as passed to the index server, e.g. `127.0.0.1:34568`.
+    pub fn volume_addrs(&self) -> &[String] {
+        &self.volume_addrs
+    }
+
+    /// Returns the in-memory volume at the given index.
+    pub fn volume(&self, index: usize) -> &MemoryVolume {
+        &self.volumes[index]
+    }
+
+    /// Stops the volume at the given index, simulating a volume server going down.
+    pub fn stop_volume(&self, index: usize) {
+        self.volume_handles[index].abort();
+    }
+
+    /// Makes the index server reject writes as if shutdown started, without closing connections, or accept them again.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        self.server_handle.abort();
+        for handle in self.volume_handles.iter() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_put_get_delete() -> anyhow::Result<()> {
+        let cluster = TestCluster::start(3, 2).await?;
+        let client = reqwest::Client::new();
+        let url = cluster.key_url("wehave");
+
+        let res = client.put(&url).body("bigswag").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "bigswag");
+
+        let res = client.delete(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_replicates_to_volumes() -> anyhow::Result<()> {
+        let replicas = 2;
+        let cluster = TestCluster::start(3, replicas).await?;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .put(cluster.key_url("replicated"))
+            .body("onyou")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let stored: usize = (0..3).map(|i| cluster.volume(i).len()).sum();
+        assert_eq!(stored, replicas);
+
+        Ok(())
+    }
+}
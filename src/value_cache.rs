@@ -0,0 +1,160 @@
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{metrics, record};
+
+/// Default largest value of `--value-cache`, in bytes.
+pub const DEFAULT_MAX_VALUE_SIZE: u64 = 64 * 1024;
+
+/// Default seconds a value of `--value-cache` is served from memory.
+pub const DEFAULT_TTL_SECS: u64 = 60;
+
+/// Struct representing the values the index server keeps in memory and serves itself with 200,
+/// instead of redirecting their GETs to a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueCacheConfig {
+    /// Bytes of values kept in memory, least recently used first out.
+    pub capacity: u64,
+    /// Largest value cached, in bytes.
+    pub max_value_size: u64,
+    /// Time a cached value is served before it is read again from a volume.
+    pub ttl: Duration,
+}
+
+/// Struct representing a cached value, with the hash of the record it was read for.
+struct CachedValue {
+    hash: String,
+    value: Bytes,
+    cached_at: Instant,
+}
+
+/// Struct representing the cached values and their total size.
+struct Values {
+    values: lru::LruCache<String, CachedValue>,
+    bytes: u64,
+}
+
+/// Struct caching the small values read from the volumes. A cached value is only served for a record
+/// with the hash it was read for, so an overwritten key is read again from its volumes.
+pub(crate) struct ValueCache {
+    config: ValueCacheConfig,
+    values: Mutex<Values>,
+}
+
+impl ValueCache {
+    pub(crate) fn new(config: ValueCacheConfig) -> Self {
+        Self {
+            config,
+            values: Mutex::new(Values {
+                values: lru::LruCache::unbounded(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Returns true if the value of the record can be cached: small, hashed, and stored as uploaded
+    /// in a single blob.
+    pub(crate) fn applies_to(&self, record: &record::Record) -> bool {
+        record.size() <= self.config.max_value_size
+            && record.size() <= self.config.capacity
+            && !record.hash().is_empty()
+            && record.parts().is_empty()
+            && record.erasure().is_none()
+            && record.encoding().is_none()
+            && record.encryption().is_none()
+    }
+
+    /// Returns the cached value of the record of a key, None if not cached, stale or read for another record.
+    pub(crate) fn get(&self, key: &str, record: &record::Record) -> Option<Bytes> {
+        let mut values = self.values.lock();
+        let value = match values.values.get(key) {
+            Some(cached)
+                if cached.hash == record.hash()
+                    && cached.value.len() as u64 == record.size()
+                    && cached.cached_at.elapsed() < self.config.ttl =>
+            {
+                Some(cached.value.clone())
+            }
+            Some(_) => {
+                if let Some(stale) = values.values.pop(key) {
+                    values.bytes -= stale.value.len() as u64;
+                }
+                None
+            }
+            None => None,
+        };
+        match value {
+            Some(_) => metrics::METRICS.value_cache_hits.inc(),
+            None => metrics::METRICS.value_cache_misses.inc(),
+        }
+        value
+    }
+
+    /// Caches the value read for the record of a key, evicting the least recently used values over the capacity.
+    pub(crate) fn insert(&self, key: &str, record: &record::Record, value: Bytes) {
+        if value.len() as u64 != record.size() || !self.applies_to(record) {
+            return;
+        }
+        let mut values = self.values.lock();
+        values.bytes += value.len() as u64;
+        let cached = CachedValue {
+            hash: record.hash().to_string(),
+            value,
+            cached_at: Instant::now(),
+        };
+        if let Some(replaced) = values.values.put(key.to_string(), cached) {
+            values.bytes -= replaced.value.len() as u64;
+        }
+        while values.bytes > self.config.capacity {
+            let Some((_, evicted)) = values.values.pop_lru() else {
+                break;
+            };
+            values.bytes -= evicted.value.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(hash: &str, size: u64) -> record::Record {
+        record::Record::new(record::Deleted::No, hash.to_string(), Vec::new()).with_size(size)
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let cache = ValueCache::new(ValueCacheConfig {
+            capacity: 10,
+            max_value_size: 5,
+            ttl: Duration::from_secs(60),
+        });
+        assert!(cache.applies_to(&record("a", 5)));
+        assert!(!cache.applies_to(&record("a", 6)));
+        assert!(!cache.applies_to(&record("", 5)));
+
+        cache.insert("a", &record("a", 4), Bytes::from_static(b"aaaa"));
+        assert_eq!(cache.get("a", &record("a", 4)).unwrap(), "aaaa");
+        // Another record of the key doesn't get the value of the previous one
+        assert!(cache.get("a", &record("b", 4)).is_none());
+        assert!(cache.get("a", &record("a", 4)).is_none());
+
+        // The least recently used values are evicted over the capacity
+        for key in ["a", "b", "c"] {
+            cache.insert(key, &record(key, 4), Bytes::from_static(b"xxxx"));
+        }
+        assert!(cache.get("a", &record("a", 4)).is_none());
+        assert!(cache.get("b", &record("b", 4)).is_some());
+        assert!(cache.get("c", &record("c", 4)).is_some());
+        assert_eq!(cache.values.lock().bytes, 8);
+
+        let cache = ValueCache::new(ValueCacheConfig {
+            capacity: 10,
+            max_value_size: 5,
+            ttl: Duration::ZERO,
+        });
+        cache.insert("a", &record("a", 1), Bytes::from_static(b"a"));
+        assert!(cache.get("a", &record("a", 1)).is_none());
+    }
+}
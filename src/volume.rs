@@ -0,0 +1,597 @@
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::IntoResponse;
+use http_body_util::BodyExt;
+use log::{debug, error, info};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{presign, rebuild, record, server};
+
+/// Directory of the volume where uploads are written before they are moved into place.
+const TMP_DIR: &str = ".tmp";
+
+/// Size of the chunks a blob is read in when served.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Struct representing a volume server storing raw blobs in a local directory,
+/// a built-in replacement for the nginx WebDAV volumes.
+struct Volume {
+    root: PathBuf,
+}
+
+/// Starts a volume server storing blobs in the directory and listens for incoming requests.
+/// With a public port and a presign key, the presigned URLs of the index are served on the public port too.
+pub async fn new_and_serve(
+    port: u16,
+    root: PathBuf,
+    public: Option<(u16, presign::PresignKey)>,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(format!("[::]:{}", port)).await?;
+    info!("volume: serving {} on port {}", root.display(), port);
+    let Some((public_port, key)) = public else {
+        return serve(listener, root, server::shutdown_signal()).await;
+    };
+    let public_listener = tokio::net::TcpListener::bind(format!("[::]:{}", public_port)).await?;
+    info!("volume: serving presigned URLs on port {}", public_port);
+    tokio::try_join!(
+        serve(listener, root.clone(), server::shutdown_signal()),
+        serve_public(public_listener, root, key, server::shutdown_signal()),
+    )?;
+    Ok(())
+}
+
+/// Serves PUT, GET, HEAD, DELETE and WebDAV COPY and MOVE of blobs on the listener until the shutdown future completes.
+/// GET of a directory lists it like nginx `autoindex_format json`, so volumes can be rebuilt.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    root: PathBuf,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(root.join(TMP_DIR)).await?;
+    let volume = Arc::new(Volume { root });
+    let router = axum::Router::new()
+        .fallback(handle_volume_request)
+        .with_state(volume);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+/// Serves GET, HEAD and PUT of the blobs of presigned URLs on the listener until the shutdown future
+/// completes, so the listener can be exposed while the volume itself is only reachable by the index.
+pub async fn serve_public(
+    listener: tokio::net::TcpListener,
+    root: PathBuf,
+    key: presign::PresignKey,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let volume = Arc::new(Volume { root });
+    let router = axum::Router::new()
+        .fallback(handle_public_request)
+        .with_state((volume, Arc::new(key)));
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+/// Handles every request made to the public listener of the volume.
+/// Returns 403 if the URL isn't signed by the presign key or expired
+/// Returns 404 if the path is a directory, directories aren't listed
+/// Returns 405 for methods other than GET, HEAD and PUT
+async fn handle_public_request(
+    axum::extract::State((volume, key)): axum::extract::State<(
+        Arc<Volume>,
+        Arc<presign::PresignKey>,
+    )>,
+    method: Method,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    if ![Method::GET, Method::HEAD, Method::PUT].contains(&method) {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+    if let Err(e) = key.verify(&method, uri.path(), uri.query(), record::unix_now()) {
+        debug!("volume: rejected {} {}: {}", method, uri, e);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(path) = volume.resolve(uri.path()) else {
+        debug!("volume: invalid path: {}", uri.path());
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if tokio::fs::metadata(&path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let result = match method {
+        Method::PUT => volume.put(&path, body).await,
+        _ => volume.get(&path, &headers, method == Method::HEAD).await,
+    };
+    result.unwrap_or_else(|e| {
+        error!("volume: failed to {} {}: {}", method, uri.path(), e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
+
+/// Handles every request made to the volume.
+/// Returns 400 if the path is not a valid blob path
+/// Returns 405 for methods other than PUT, GET, HEAD, DELETE, COPY and MOVE
+async fn handle_volume_request(
+    axum::extract::State(volume): axum::extract::State<Arc<Volume>>,
+    method: Method,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    let Some(path) = volume.resolve(uri.path()) else {
+        debug!("volume: invalid path: {}", uri.path());
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let result = match method {
+        Method::PUT => volume.put(&path, body).await,
+        Method::GET | Method::HEAD => volume.get(&path, &headers, method == Method::HEAD).await,
+        Method::DELETE => volume.delete(&path).await,
+        ref method if method.as_str() == "COPY" => volume.transfer(&path, &headers, true).await,
+        ref method if method.as_str() == "MOVE" => volume.transfer(&path, &headers, false).await,
+        _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    };
+    result.unwrap_or_else(|e| {
+        error!("volume: failed to {} {}: {}", method, uri.path(), e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
+
+impl Volume {
+    /// Returns the path on disk of a request path, None if it escapes the root of the volume.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.root.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            if segment == "." || segment == ".." || segment == TMP_DIR {
+                return None;
+            }
+            if segment.contains(['\\', ':']) {
+                return None;
+            }
+            resolved.push(disk_name(segment));
+        }
+        Some(resolved)
+    }
+
+    /// Writes a blob to a temporary file, then moves it into place so readers never see a partial blob.
+    /// Returns 201 if the blob is created, 204 if it replaced an existing blob
+    /// Returns 400 if the body can't be read
+    async fn put(
+        &self,
+        path: &Path,
+        mut body: axum::body::Body,
+    ) -> anyhow::Result<axum::response::Response> {
+        if path == self.root {
+            return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+        }
+        let (file, tmp_path) =
+            tempfile::NamedTempFile::new_in(self.root.join(TMP_DIR))?.into_parts();
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::from_std(file));
+        while let Some(frame) = body.frame().await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    debug!("volume: failed to read body for {}: {}", path.display(), e);
+                    return Ok(StatusCode::BAD_REQUEST.into_response());
+                }
+            };
+            if let Ok(data) = frame.into_data() {
+                file.write_all(&data).await?;
+            }
+        }
+        file.flush().await?;
+        file.into_inner().sync_all().await?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let existed = tokio::fs::try_exists(path).await?;
+        tmp_path.persist(path)?;
+        if existed {
+            Ok(StatusCode::NO_CONTENT.into_response())
+        } else {
+            Ok(StatusCode::CREATED.into_response())
+        }
+    }
+
+    /// Serves a blob, or the listing of a directory.
+    /// A single `Range: bytes=start-end` is honored with 206, other ranges serve the whole blob.
+    /// Returns 404 if the blob is not found
+    /// Returns 416 if the range starts past the end of the blob
+    async fn get(
+        &self,
+        path: &Path,
+        headers: &HeaderMap,
+        head: bool,
+    ) -> anyhow::Result<axum::response::Response> {
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(StatusCode::NOT_FOUND.into_response())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_dir() {
+            return Ok(axum::Json(self.list_dir(path).await?).into_response());
+        }
+
+        let size = metadata.len();
+        let range = headers
+            .get(header::RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_range);
+        let response = axum::http::Response::builder().header(header::ACCEPT_RANGES, "bytes");
+        let (response, start, length) = match range {
+            None => (response.status(StatusCode::OK), 0, size),
+            Some((start, _)) if start >= size => {
+                return Ok(axum::http::Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(axum::body::Body::empty())?);
+            }
+            Some((start, end)) => {
+                let end = end.unwrap_or(u64::MAX).min(size - 1);
+                let response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, size),
+                );
+                (response, start, end - start + 1)
+            }
+        };
+        let response = response.header(header::CONTENT_LENGTH, length);
+        if head {
+            return Ok(response.body(axum::body::Body::empty())?);
+        }
+
+        let mut file = tokio::fs::File::open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let stream =
+            tokio_util::io::ReaderStream::with_capacity(file.take(length), READ_CHUNK_SIZE);
+        Ok(response.body(axum::body::Body::from_stream(stream))?)
+    }
+
+    /// Lists the entries of a directory of the volume, sorted by name.
+    async fn list_dir(&self, path: &Path) -> anyhow::Result<Vec<rebuild::DirEntry>> {
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if path == self.root && name == TMP_DIR {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            entries.push(rebuild::DirEntry {
+                name: url_name(&name),
+                kind: if metadata.is_dir() {
+                    "directory"
+                } else {
+                    "file"
+                }
+                .to_string(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Moves a blob to the path of the `Destination` header, like a WebDAV MOVE, e.g. the upload of
+    /// a two-phase write, or copies it there like a WebDAV COPY, e.g. for a COPY of a key.
+    /// An existing blob is replaced unless the `Overwrite` header is `F`. A copy is written to a temporary
+    /// file first, like a PUT.
+    /// Returns 201 if the blob is moved or copied to a new path, 204 if it replaced an existing blob
+    /// Returns 400 if the destination is missing or not a valid blob path
+    /// Returns 404 if the blob is not found
+    /// Returns 412 if the destination exists and `Overwrite` is `F`
+    async fn transfer(
+        &self,
+        path: &Path,
+        headers: &HeaderMap,
+        copy: bool,
+    ) -> anyhow::Result<axum::response::Response> {
+        let Some(destination) = headers
+            .get("Destination")
+            .and_then(|destination| destination.to_str().ok())
+            .and_then(|destination| destination.parse::<axum::http::Uri>().ok())
+            .and_then(|destination| self.resolve(destination.path()))
+            .filter(|destination| *destination != self.root)
+        else {
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        };
+        if !tokio::fs::try_exists(path).await? {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+        let existed = tokio::fs::try_exists(&destination).await?;
+        let overwrite = headers.get("Overwrite").is_none_or(|value| value != "F");
+        if existed && !overwrite {
+            return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+        }
+
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if copy {
+            let tmp_path =
+                tempfile::NamedTempFile::new_in(self.root.join(TMP_DIR))?.into_temp_path();
+            tokio::fs::copy(path, &tmp_path).await?;
+            tmp_path.persist(&destination)?;
+        } else {
+            tokio::fs::rename(path, &destination).await?;
+        }
+        if existed {
+            Ok(StatusCode::NO_CONTENT.into_response())
+        } else {
+            Ok(StatusCode::CREATED.into_response())
+        }
+    }
+
+    /// Deletes a blob.
+    /// Returns 204 if the blob is deleted
+    /// Returns 404 if the blob is not found
+    async fn delete(&self, path: &Path) -> anyhow::Result<axum::response::Response> {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(StatusCode::NOT_FOUND.into_response())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Parses a single `bytes=start-end` range, where the end is optional.
+/// A suffix range `bytes=-n` or a list of ranges returns None and the whole blob is served.
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse::<u64>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Returns the name a path segment is stored under on disk.
+/// Base64 key names differ only in case, so they are escaped on case-insensitive filesystems.
+fn disk_name(segment: &str) -> String {
+    if cfg!(windows) {
+        escape_case(segment)
+    } else {
+        segment.to_string()
+    }
+}
+
+/// Returns the path segment stored on disk under a name, the inverse of `disk_name`.
+fn url_name(name: &str) -> String {
+    if cfg!(windows) {
+        unescape_case(name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Escapes uppercase letters as `!` followed by the lowercase letter, and `!` as `!!`.
+fn escape_case(segment: &str) -> String {
+    let mut escaped = String::with_capacity(segment.len());
+    for c in segment.chars() {
+        if c.is_ascii_uppercase() || c == '!' {
+            escaped.push('!');
+        }
+        escaped.push(c.to_ascii_lowercase());
+    }
+    escaped
+}
+
+/// Unescapes a name escaped by `escape_case`.
+fn unescape_case(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '!' => unescaped.extend(chars.next().map(|c| c.to_ascii_uppercase())),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_case() {
+        for segment in ["d2VoYXZl", "aGVsbG8=", "a!B-_", ""] {
+            assert_eq!(unescape_case(&escape_case(segment)), segment);
+        }
+        assert_eq!(escape_case("aB!"), "a!b!!");
+        assert_ne!(
+            escape_case("QUJD").to_lowercase(),
+            escape_case("qujd").to_lowercase()
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=2-5"), Some((2, Some(5))));
+        assert_eq!(parse_range("bytes=2-"), Some((2, None)));
+        assert_eq!(parse_range("bytes=-5"), None);
+        assert_eq!(parse_range("bytes=5-2"), None);
+        assert_eq!(parse_range("bytes=0-1,3-4"), None);
+        assert_eq!(parse_range("items=0-1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_volume_server() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(serve(
+            listener,
+            dir.path().to_path_buf(),
+            std::future::pending(),
+        ));
+        let client = reqwest::Client::new();
+        let blob_url = format!("{}/sv01/ab/cd/b25Zb3U=", url);
+
+        let res = client.get(&blob_url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.put(&blob_url).body("onyou").send().await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = client.put(&blob_url).body("onyou").send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = client.get(&blob_url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "onyou");
+        let res = client.head(&blob_url).send().await?;
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "5");
+        let res = client
+            .get(&blob_url)
+            .header(header::RANGE, "bytes=2-5")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 2-4/5");
+        assert_eq!(res.text().await?, "you");
+        let res = client
+            .get(&blob_url)
+            .header(header::RANGE, "bytes=5-")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let entries: Vec<rebuild::DirEntry> =
+            client.get(format!("{}/", url)).send().await?.json().await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "sv01");
+        assert_eq!(entries[0].kind, "directory");
+        let entries: Vec<rebuild::DirEntry> = client
+            .get(format!("{}/sv01/ab/cd/", url))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(entries[0].name, "b25Zb3U=");
+        assert_eq!(entries[0].size, 5);
+
+        let res = client.get(format!("{}/../secret", url)).send().await?;
+        assert_ne!(res.status(), StatusCode::OK);
+        let res = client
+            .put(format!("{}/.tmp/x", url))
+            .body("x")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let tmp_url = format!("{}.tmp", blob_url);
+        client.put(&tmp_url).body("onme").send().await?;
+        let res = client
+            .request(Method::from_bytes(b"MOVE")?, &tmp_url)
+            .header("Destination", &blob_url)
+            .header("Overwrite", "F")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let res = client
+            .request(Method::from_bytes(b"MOVE")?, &tmp_url)
+            .header("Destination", &blob_url)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(client.get(&blob_url).send().await?.text().await?, "onme");
+        let res = client.get(&tmp_url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let copy_url = format!("{}/copy", url);
+        let res = client
+            .request(Method::from_bytes(b"COPY")?, &blob_url)
+            .header("Destination", &copy_url)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(client.get(&copy_url).send().await?.text().await?, "onme");
+        assert_eq!(client.get(&blob_url).send().await?.text().await?, "onme");
+
+        let res = client.delete(&blob_url).send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.delete(&blob_url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_public_listener() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_file = tempfile::NamedTempFile::new()?;
+        std::fs::write(key_file.path(), "a presign key of the volume\n")?;
+        let key = presign::PresignKey::load(key_file.path())?;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(serve(
+            listener,
+            dir.path().to_path_buf(),
+            std::future::pending(),
+        ));
+        let public_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let public_url = format!("http://{}", public_listener.local_addr()?);
+        tokio::spawn(serve_public(
+            public_listener,
+            dir.path().to_path_buf(),
+            key.clone(),
+            std::future::pending(),
+        ));
+        let client = reqwest::Client::new();
+        client
+            .put(format!("{}/sv01/ab/cd/b25Zb3U=", url))
+            .body("onyou")
+            .send()
+            .await?;
+
+        let blob_url = format!("{}/sv01/ab/cd/b25Zb3U=", public_url);
+        let res = client.get(&blob_url).send().await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let signed_url = key.sign(&Method::GET, &blob_url, record::unix_now() + 60)?;
+        let res = client.get(&signed_url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await?, "onyou");
+        let res = client.put(&signed_url).body("onme").send().await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = client.delete(&signed_url).send().await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let upload_url = key.sign(&Method::PUT, &blob_url, record::unix_now() + 60)?;
+        let res = client.put(&upload_url).body("onme").send().await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(client.get(&signed_url).send().await?.text().await?, "onme");
+
+        let expired_url = key.sign(&Method::GET, &blob_url, record::unix_now() - 1)?;
+        let res = client.get(&expired_url).send().await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let dir_url = key.sign(
+            &Method::GET,
+            &format!("{}/sv01/", public_url),
+            record::unix_now() + 60,
+        )?;
+        let res = client.get(&dir_url).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use log::info;
+use std::collections::BTreeSet;
+
+use crate::record;
+
+/// Prefix of the metadata store entries indexing the keys with blobs on a volume, `<prefix><volume>\0<key>`,
+/// under the reserved prefix. Entries have no value.
+const PREFIX: &str = "\0volume-keys/";
+
+/// Key of the metadata store entry marking the index as built from every record.
+const BUILT_KEY: &str = "\0volume-keys-built";
+
+/// Separator of the volume and the key of an entry, neither contains it.
+const SEPARATOR: char = '\0';
+
+/// Returns the key of the entry of a key on a volume.
+fn entry_key(volume: &str, key: &str) -> String {
+    format!("{}{}{}{}", PREFIX, volume, SEPARATOR, key)
+}
+
+/// Returns the volumes holding blobs of a record, its replicas, parts and shards.
+/// Hard deleted records hold none, soft deleted records keep their blobs until collected.
+fn volumes(record: Option<&record::Record>) -> BTreeSet<&str> {
+    let Some(record) = record.filter(|record| record.deleted() != record::Deleted::Hard) else {
+        return BTreeSet::new();
+    };
+    let mut volumes: BTreeSet<&str> = record.read_volumes().iter().map(String::as_str).collect();
+    for part in record.parts() {
+        volumes.extend(part.volumes.iter().map(String::as_str));
+    }
+    if let Some(erasure) = record.erasure() {
+        volumes.extend(erasure.volumes());
+    }
+    volumes
+}
+
+/// Updates the entries of a key whose record is replaced, None for a missing record.
+/// Callers serialize the updates, see `record::LevelDb::put_record`.
+pub(crate) fn track(
+    store: &dyn record::MetadataStore,
+    key: &str,
+    current: Option<&record::Record>,
+    new: Option<&record::Record>,
+) -> anyhow::Result<()> {
+    let (current, new) = (volumes(current), volumes(new));
+    for volume in current.difference(&new) {
+        store.delete(&entry_key(volume, key))?;
+    }
+    for volume in new.difference(&current) {
+        store.put(&entry_key(volume, key), &[])?;
+    }
+    Ok(())
+}
+
+/// Builds the index from every record, unless built already, e.g. for a metadata store written by a version
+/// of the index without it. Entries left by writes made before are replaced.
+pub(crate) fn build(leveldb: &record::LevelDb) -> anyhow::Result<()> {
+    let store = leveldb.store();
+    if store.get(BUILT_KEY)?.is_some() {
+        return Ok(());
+    }
+    let mut stale = Vec::new();
+    store.for_each_entry(PREFIX, &mut |entry, _| {
+        stale.push(entry.to_string());
+        Ok(())
+    })?;
+    for entry in stale {
+        store.delete(&entry)?;
+    }
+
+    let mut records = 0;
+    leveldb.for_each_record(|record| {
+        records += 1;
+        track(store, record.key(), None, Some(&record))
+    })?;
+    store.put(BUILT_KEY, &[])?;
+    info!("volume_keys: indexed the volumes of {} records", records);
+    Ok(())
+}
+
+/// Returns the keys with blobs on a volume in key order, the volume matching with or without its subvolume,
+/// e.g. `localhost:3001` or `localhost:3001/sv01`.
+pub(crate) fn keys(store: &dyn record::MetadataStore, volume: &str) -> anyhow::Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let prefixes = [
+        format!("{}{}{}", PREFIX, volume, SEPARATOR),
+        format!("{}{}/", PREFIX, volume),
+    ];
+    for prefix in prefixes {
+        store.for_each_entry(&prefix, &mut |entry, _| {
+            if let Some((_, key)) = entry[PREFIX.len()..].split_once(SEPARATOR) {
+                keys.push(key.to_string());
+            }
+            Ok(())
+        })?;
+    }
+    keys.sort_unstable();
+    keys.dedup();
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_track() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let leveldb = record::LevelDb::with_backend(dir.path(), Default::default())?;
+        let volumes = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let record = |names: &[&str]| {
+            record::Record::new(record::Deleted::No, String::new(), volumes(names))
+        };
+
+        leveldb.put_record("a", record(&["v1", "v2/sv01"])).await?;
+        leveldb.put_record("b", record(&["v2/sv02"])).await?;
+        leveldb.put_record("c", record(&["v10"])).await?;
+        let store = leveldb.store();
+        assert_eq!(keys(store, "v1")?, ["a"]);
+        assert_eq!(keys(store, "v2")?, ["a", "b"]);
+        assert_eq!(keys(store, "v2/sv02")?, ["b"]);
+
+        // Moved to other volumes, then hard deleted
+        leveldb.put_record("a", record(&["v3"])).await?;
+        assert!(keys(store, "v1")?.is_empty());
+        assert_eq!(keys(store, "v3")?, ["a"]);
+        let deleted = record(&["v3"]).with_deleted(record::Deleted::Hard);
+        leveldb.put_record("a", deleted).await?;
+        assert!(keys(store, "v3")?.is_empty());
+        leveldb.delete_record("b").await?;
+        assert!(keys(store, "v2")?.is_empty());
+
+        // A store written without the index is indexed once
+        store.delete(BUILT_KEY)?;
+        store.delete(&entry_key("v10", "c"))?;
+        store.put(&entry_key("v1", "stale"), &[])?;
+        build(&leveldb)?;
+        assert_eq!(keys(store, "v10")?, ["c"]);
+        assert!(keys(store, "v1")?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Faults injected through `/admin/chaos`. The chaos configuration is process wide,
+//! so these tests run in their own binary instead of next to the unit tests.
+
+use reqwest::StatusCode;
+use rust_minikeyvalue::testkit::TestCluster;
+
+#[tokio::test]
+async fn test_admin_chaos() -> anyhow::Result<()> {
+    let cluster = TestCluster::start(2, 2).await?;
+    let client = reqwest::Client::new();
+    let chaos_url = format!("{}/admin/chaos", cluster.url());
+
+    let res = client
+        .put(&chaos_url)
+        .json(&serde_json::json!({"volume_error_rate": 1.5}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Every request to a volume fails, a PUT misses its replicas
+    let res = client
+        .put(&chaos_url)
+        .json(&serde_json::json!({"volume_error_rate": 1.0}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let config: serde_json::Value = client.get(&chaos_url).send().await?.json().await?;
+    assert_eq!(config["volume_error_rate"], 1.0);
+    let res = client
+        .put(cluster.key_url("chaos"))
+        .body("faulty")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(cluster.volume(0).is_empty());
+
+    // Cleared, the faults stop
+    let res = client
+        .put(&chaos_url)
+        .json(&serde_json::json!({}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let config: serde_json::Value = client.get(&chaos_url).send().await?.json().await?;
+    assert_eq!(config["volume_error_rate"], 0.0);
+    let res = client
+        .put(cluster.key_url("chaos2"))
+        .body("steady")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client.get(cluster.key_url("chaos2")).send().await?;
+    assert_eq!(res.text().await?, "steady");
+
+    Ok(())
+}
@@ -0,0 +1,9 @@
+#!/bin/bash
+
+cargo build --release
+
+for PORT in 3001 3002 3003 3004 3005; do
+  target/release/rust-minikeyvalue volume --path /tmp/volume$((PORT - 3000))/ --port $PORT &
+done
+
+target/release/rust-minikeyvalue --leveldb-path /tmp/indexdb/ --volumes localhost:3001,localhost:3002,localhost:3003,localhost:3004,localhost:3005
@@ -7,6 +7,7 @@ import unittest
 import requests
 import time
 import logging
+from urllib.parse import quote_plus
 
 logging.basicConfig(format='%(name)s %(levelname)s %(message)s')
 logger = logging.getLogger(__name__)
@@ -142,49 +143,46 @@ class TestMiniKeyValue(unittest.TestCase):
   #   r = requests.delete(key)
   #   self.assertEqual(r.status_code, 204)
 
-# TODO
-#   def test_json_list(self):
-#     key = self.get_fresh_key()
-#     data = "eh"
-#     r = requests.put(key+b"1", data=data)
-#     self.assertEqual(r.status_code, 201)
-#     r = requests.put(key+b"2", data=data)
-#     self.assertEqual(r.status_code, 201)
-
-#     r = requests.get(key+b"?list")
-#     self.assertEqual(r.status_code, 200)
-#     bkey = key.decode('utf-8')
-#     bkey = "/"+bkey.split("/")[-1]
-#     self.assertEqual(r.json(), {"next": "", "keys": [bkey+"1", bkey+"2"]})
-
-# TODO
-#   def test_json_list_null(self):
-#     r = requests.get(self.get_fresh_key()+b"/DOES_NOT_EXIST?list")
-#     self.assertEqual(r.status_code, 200)
-#     self.assertEqual(r.json(), {"next": "", "keys": []})
-
-# TODO
-#   def test_json_list_limit(self):
-#     prefix = self.get_fresh_key()
-#     keys = []
-#     data = "0"
-#     limit = 10
-#     for i in range(limit+2):
-#       key = prefix+str(i).encode()
-#       r = requests.put(key, data=data)
-#       self.assertEqual(r.status_code, 201)
-#       keys.append("/"+key.decode().split("/")[-1])
-#     # leveldb is sorted alphabetically
-#     keys = sorted(keys)
-#     # should return first page
-#     r = requests.get(prefix+b"?list&limit="+str(limit).encode())
-#     self.assertEqual(r.status_code, 200)
-#     self.assertEqual(r.json(), {"next": keys[limit], "keys": keys[:limit]})
-#     start = quote_plus(r.json()["next"]).encode()
-#     # should return last page
-#     r = requests.get(prefix+b"?list&limit="+str(limit).encode()+b"&start="+start)
-#     self.assertEqual(r.status_code, 200)
-#     self.assertEqual(r.json(), {"next": "", "keys": keys[limit:]})
+  def test_json_list(self):
+    key = self.get_fresh_key()
+    data = "eh"
+    r = requests.put(key+b"1", data=data)
+    self.assertEqual(r.status_code, 201)
+    r = requests.put(key+b"2", data=data)
+    self.assertEqual(r.status_code, 201)
+
+    r = requests.get(key+b"?list")
+    self.assertEqual(r.status_code, 200)
+    bkey = key.decode('utf-8')
+    bkey = "/"+bkey.split("/")[-1]
+    self.assertEqual(r.json(), {"next": "", "keys": [bkey+"1", bkey+"2"]})
+
+  def test_json_list_null(self):
+    r = requests.get(self.get_fresh_key()+b"/DOES_NOT_EXIST?list")
+    self.assertEqual(r.status_code, 200)
+    self.assertEqual(r.json(), {"next": "", "keys": []})
+
+  def test_json_list_limit(self):
+    prefix = self.get_fresh_key()
+    keys = []
+    data = "0"
+    limit = 10
+    for i in range(limit+2):
+      key = prefix+str(i).encode()
+      r = requests.put(key, data=data)
+      self.assertEqual(r.status_code, 201)
+      keys.append("/"+key.decode().split("/")[-1])
+    # leveldb is sorted alphabetically
+    keys = sorted(keys)
+    # should return first page
+    r = requests.get(prefix+b"?list&limit="+str(limit).encode())
+    self.assertEqual(r.status_code, 200)
+    self.assertEqual(r.json(), {"next": keys[limit], "keys": keys[:limit]})
+    start = quote_plus(r.json()["next"]).encode()
+    # should return last page
+    r = requests.get(prefix+b"?list&limit="+str(limit).encode()+b"&start="+start)
+    self.assertEqual(r.status_code, 200)
+    self.assertEqual(r.json(), {"next": "", "keys": keys[limit:]})
 
   def test_noemptykey(self):
     key = self.get_fresh_key()